@@ -0,0 +1,352 @@
+//! Explains how the permissions of a user apply to a call.
+
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, Utc};
+use futures::future::{BoxFuture, FutureExt};
+use serde_json::{json, Value};
+
+use crate::approvals::sent_amount;
+use crate::categories;
+use crate::client::{GenericRpcMethod, RpcRequest, RpcResponse};
+use crate::intercept::{InterceptResult, Interceptor, RequestContext, LOCAL_METHOD_PREFIX};
+use crate::state::State;
+use crate::users::User;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    /// The call may proceed
+    Pass,
+    /// The call is rejected
+    Deny,
+    /// The call waits for approval
+    Hold,
+}
+
+/// A single step of evaluating a call.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Rule {
+    pub rule: &'static str,
+    pub outcome: Outcome,
+    pub detail: String,
+}
+impl Rule {
+    fn new(rule: &'static str, outcome: Outcome, detail: impl Into<String>) -> Self {
+        Rule {
+            rule,
+            outcome,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// The rules a call of `user` is evaluated against, in the order the proxy applies them. The
+/// chain ends with the first rule that doesn't pass.
+pub fn explain(
+    state: &State,
+    user: &User,
+    req: &RpcRequest<GenericRpcMethod>,
+    now: DateTime<Utc>,
+) -> Vec<Rule> {
+    let mut rules = Vec::new();
+    let method = &*req.method.0;
+    match user.check_access(now) {
+        Ok(()) => rules.push(Rule::new(
+            "credentials",
+            Outcome::Pass,
+            "not expired and within allowed hours",
+        )),
+        Err(e) => {
+            rules.push(Rule::new("credentials", Outcome::Deny, e.message));
+            return rules;
+        }
+    }
+    if let Some(entry) = user.allowed_by(method) {
+        rules.push(Rule::new(
+            "allowed_calls",
+            Outcome::Pass,
+            if entry == method {
+                format!("{} is listed", method)
+            } else if entry.starts_with("role ") {
+                format!("{} is allowed by {}", method, entry)
+            } else {
+                format!("{} matches {}", method, entry)
+            },
+        ));
+    } else {
+        rules.push(Rule::new(
+            "allowed_calls",
+            Outcome::Deny,
+            format!("{} is not listed", method),
+        ));
+        return rules;
+    }
+    for rule in user.param_policy.iter().filter(|r| r.method == method) {
+        match rule.check(req) {
+            Ok(()) => rules.push(Rule::new(
+                "param_policy",
+                Outcome::Pass,
+                format!("parameter {} is within the policy", rule.param),
+            )),
+            Err(e) => {
+                rules.push(Rule::new("param_policy", Outcome::Deny, e.message));
+                return rules;
+            }
+        }
+    }
+    for limit in user.rate_limits().iter().filter(|l| l.applies_to(method)) {
+        rules.push(Rule::new(
+            "rate_limit",
+            Outcome::Pass,
+            format!("{} (current usage not checked)", limit),
+        ));
+    }
+    if categories::is_write(method) {
+        if state.read_only.load(Ordering::SeqCst) {
+            rules.push(Rule::new(
+                "read_only",
+                Outcome::Deny,
+                "state-changing methods are disabled",
+            ));
+            return rules;
+        }
+        rules.push(Rule::new(
+            "read_only",
+            Outcome::Pass,
+            "read-only mode is off",
+        ));
+    }
+    if let Some(approvals) = &state.approvals {
+        match sent_amount(req) {
+            Ok(Some(amount)) if amount > approvals.threshold => {
+                rules.push(Rule::new(
+                    "approval",
+                    Outcome::Hold,
+                    format!(
+                        "sending {} exceeds the threshold of {}",
+                        amount, approvals.threshold
+                    ),
+                ));
+                return rules;
+            }
+            Ok(Some(amount)) => rules.push(Rule::new(
+                "approval",
+                Outcome::Pass,
+                format!("sending {} is within the threshold", amount),
+            )),
+            Ok(None) => (),
+            Err(e) => {
+                rules.push(Rule::new("approval", Outcome::Deny, e.to_string()));
+                return rules;
+            }
+        }
+    }
+    if [
+        "proxy_adduser",
+        "proxy_removeuser",
+        "proxy_setpermissions",
+        "proxy_addapikey",
+        "proxy_revokeapikey",
+    ]
+    .contains(&method)
+        && !user.manage_users
+    {
+        rules.push(Rule::new(
+            "manage_users",
+            Outcome::Deny,
+            "managing users is not enabled for the user",
+        ));
+        return rules;
+    }
+    #[cfg(feature = "peer-fetch")]
+    if method == "proxy_getblocks" && !user.fetch_blocks {
+        rules.push(Rule::new(
+            "fetch_blocks",
+            Outcome::Deny,
+            "fetching blocks is not enabled for the user",
+        ));
+        return rules;
+    }
+    #[cfg(feature = "peer-fetch")]
+    if method == "getblock" {
+        rules.push(Rule::new(
+            "fetch_blocks",
+            Outcome::Pass,
+            if user.fetch_blocks {
+                "blocks missing from bitcoind are fetched elsewhere"
+            } else {
+                "blocks are only served by bitcoind"
+            },
+        ));
+    }
+    if let Err(e) = state.capabilities.check(req) {
+        rules.push(Rule::new("capabilities", Outcome::Deny, e.message));
+        return rules;
+    }
+    rules.push(if state.interceptors.get(method).is_some() {
+        Rule::new("method", Outcome::Pass, "served by the proxy")
+    } else if method.starts_with(LOCAL_METHOD_PREFIX) {
+        Rule::new("method", Outcome::Deny, "unknown proxy method")
+    } else {
+        Rule::new("method", Outcome::Pass, "forwarded to bitcoind")
+    });
+    rules
+}
+
+/// `proxy_explainacl <user> <method> [params]`: the rules that would allow or deny a call of
+/// `user`, without making it.
+pub struct ExplainAcl;
+impl Interceptor for ExplainAcl {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        _user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        _ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            let param = |i: usize| req.params.get(i).and_then(Value::as_str);
+            let name = param(0).ok_or_else(|| anyhow!("expected a user name"))?;
+            let method = param(1).ok_or_else(|| anyhow!("expected a method"))?;
+            let params = match req.params.get(2) {
+                Some(Value::Array(params)) => params.clone(),
+                None | Some(Value::Null) => Vec::new(),
+                Some(_) => return Err(anyhow!("expected an array of parameters").into()),
+            };
+            let user = state
+                .users
+                .by_name(name)
+                .ok_or_else(|| anyhow!("unknown user {}", name))?;
+            let call = RpcRequest {
+                id: None,
+                method: GenericRpcMethod(method.to_owned()),
+                params,
+            };
+            let rules = explain(&state, &user, &call, crate::clock::now());
+            let allowed = rules.iter().all(|r| r.outcome == Outcome::Pass);
+            Ok(Some(RpcResponse {
+                id: req.id.clone(),
+                result: Some(json!({
+                    "user": name,
+                    "method": method,
+                    "allowed": allowed,
+                    "rules": rules,
+                })),
+                error: None,
+            }))
+        }
+        .boxed()
+    }
+}
+
+/// Rules of `explain` that depend on the config of the user rather than the state of the proxy.
+const USER_RULES: &[&str] = &["credentials", "allowed_calls"];
+
+/// `proxy_auditacl <users> [since]`: replays the journal (optionally only the calls after an
+/// RFC 3339 time) against proposed configs of users, given like in the config file (`null`
+/// removes a user), and reports which of the recorded calls they would deny. Users not given
+/// keep their current config.
+pub struct AuditAcl;
+impl Interceptor for AuditAcl {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        _user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        _ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            let journal = state
+                .journal
+                .as_ref()
+                .ok_or_else(|| anyhow!("the journal is not enabled"))?;
+            let proposed = match req.params.first() {
+                Some(Value::Object(users)) => users
+                    .iter()
+                    .map(|(name, user)| match user {
+                        Value::Null => Ok((name.clone(), None)),
+                        user => User::from_value(user.clone())
+                            .map(|u| (name.clone(), Some(Arc::new(u))))
+                            .map_err(|e| anyhow!("invalid config of user {}: {}", name, e)),
+                    })
+                    .collect::<Result<HashMap<_, _>, _>>()?,
+                _ => return Err(anyhow!("expected an object of users").into()),
+            };
+            let since = match req.params.get(1) {
+                Some(Value::String(s)) => Some(
+                    DateTime::parse_from_rfc3339(s)
+                        .map_err(Error::from)?
+                        .with_timezone(&Utc),
+                ),
+                _ => None,
+            };
+            let mut checked = 0;
+            let mut denied = Vec::new();
+            let mut unchecked = Vec::new();
+            let entries = journal.entries().await?;
+            for entry in entries
+                .iter()
+                .filter(|e| since.is_none_or(|since| e.time > since))
+            {
+                let user = match proposed.get(&entry.user) {
+                    Some(user) => user.clone(),
+                    None => state.users.by_name(&entry.user),
+                };
+                checked += 1;
+                let call = json!({
+                    "time": entry.time,
+                    "user": entry.user,
+                    "path": entry.path,
+                    "method": entry.method,
+                });
+                let user = match user {
+                    Some(user) => user,
+                    None => {
+                        denied.push(
+                            json!({ "call": call, "rule": "user", "detail": "unknown user" }),
+                        );
+                        continue;
+                    }
+                };
+                let request = RpcRequest {
+                    id: None,
+                    method: GenericRpcMethod(entry.method.clone()),
+                    params: Vec::new(),
+                };
+                let deny = explain(&state, &user, &request, entry.time)
+                    .into_iter()
+                    .find(|r| r.outcome == Outcome::Deny && USER_RULES.contains(&r.rule))
+                    .map(|r| (r.rule, r.detail))
+                    .or_else(|| {
+                        user.check_wallet(&entry.path, &entry.method)
+                            .err()
+                            .map(|e| ("allowed_wallets", e.message))
+                    });
+                if let Some((rule, detail)) = deny {
+                    denied.push(json!({ "call": call, "rule": rule, "detail": detail }));
+                } else if user.param_policy.iter().any(|r| r.method == entry.method) {
+                    // only a hash of the parameters is journaled
+                    unchecked.push(json!({
+                        "call": call,
+                        "rule": "param_policy",
+                        "detail": "the journal doesn't record the parameters",
+                    }));
+                }
+            }
+            Ok(Some(RpcResponse {
+                id: req.id.clone(),
+                result: Some(json!({
+                    "checked": checked,
+                    "denied": denied,
+                    "unchecked": unchecked,
+                })),
+                error: None,
+            }))
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,251 @@
+//! Peers the proxy knows about, scored by how well they served blocks.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Error};
+use bitcoin::network::address::Address;
+use futures::future::{BoxFuture, FutureExt};
+use serde_json::{json, Value};
+
+use crate::client::{GenericRpcMethod, RpcRequest, RpcResponse};
+use crate::intercept::{InterceptResult, Interceptor, RequestContext};
+use crate::p2p::Features;
+use crate::state::State;
+use crate::storage::{Storage, PEERS};
+use crate::users::User;
+
+/// Entries beyond this many are dropped, worst first.
+const MAX_ENTRIES: usize = 1000;
+
+/// The `.onion` host name of a Tor v2 address encoded as an IPv6 address.
+pub fn onion_host(addr: &Address) -> String {
+    format!(
+        "{}.onion",
+        base32::encode(
+            base32::Alphabet::RFC4648 { padding: false },
+            &addr
+                .address
+                .iter()
+                .copied()
+                .flat_map(|n| u16::to_be_bytes(n).to_vec())
+                .collect::<Vec<_>>()
+        )
+        .to_lowercase()
+    )
+}
+
+/// Host name of a peer and the network it is on, as `getnodeaddresses` reports them.
+pub fn host(addr: &Address) -> (String, &'static str) {
+    match addr.socket_addr() {
+        Ok(SocketAddr::V4(a)) => (a.ip().to_string(), "ipv4"),
+        Ok(SocketAddr::V6(a)) => (a.ip().to_string(), "ipv6"),
+        Err(_) => (onion_host(addr), "onion"),
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Entry {
+    pub address: String,
+    pub port: u16,
+    pub network: String,
+    pub services: u64,
+    /// When the peer was last seen connected to bitcoind (seconds since epoch)
+    pub time: i64,
+    /// Blocks fetched from the peer
+    pub successes: u64,
+    /// Failed attempts to fetch blocks from the peer
+    pub failures: u64,
+    /// Negotiated during the last handshake of the proxy with the peer
+    #[serde(default)]
+    pub features: Option<Features>,
+}
+impl Entry {
+    /// Estimated probability of the next fetch succeeding, 0.5 for unknown peers.
+    pub fn score(&self) -> f64 {
+        (self.successes as f64 + 1.0) / ((self.successes + self.failures) as f64 + 2.0)
+    }
+}
+
+/// Peers of bitcoind the proxy has seen, kept in the storage across restarts.
+#[derive(Debug)]
+pub struct AddressBook {
+    storage: Arc<dyn Storage>,
+    entries: Mutex<HashMap<String, Entry>>,
+    dirty: AtomicBool,
+}
+impl AddressBook {
+    pub fn open(storage: Arc<dyn Storage>) -> Result<Self, Error> {
+        let entries = match storage.load(PEERS)? {
+            Some(peers) => serde_json::from_value::<Vec<Entry>>(peers)?
+                .into_iter()
+                .map(|e| (format!("{}:{}", e.address, e.port), e))
+                .collect(),
+            None => HashMap::new(),
+        };
+        Ok(AddressBook {
+            storage,
+            entries: Mutex::new(entries),
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    fn key(addr: &Address) -> String {
+        let (host, _) = host(addr);
+        format!("{}:{}", host, addr.port)
+    }
+
+    /// Records that bitcoind is connected to the peers at `addrs`.
+    pub fn observe<'a>(&self, addrs: impl IntoIterator<Item = &'a Address>) {
+        let now = chrono::Utc::now().timestamp();
+        let mut entries = self.entries.lock().unwrap();
+        for addr in addrs {
+            let (address, network) = host(addr);
+            let entry = entries.entry(Self::key(addr)).or_insert_with(|| Entry {
+                address,
+                port: addr.port,
+                network: network.to_owned(),
+                services: 0,
+                time: now,
+                successes: 0,
+                failures: 0,
+                features: None,
+            });
+            entry.services = addr.services.as_u64();
+            entry.time = now;
+        }
+        if entries.len() > MAX_ENTRIES {
+            let mut worst: Vec<_> = entries
+                .iter()
+                .map(|(k, e)| (e.score(), e.time, k.clone()))
+                .collect();
+            worst.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            for (_, _, key) in worst.into_iter().take(entries.len() - MAX_ENTRIES) {
+                entries.remove(&key);
+            }
+        }
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Records the outcome of fetching a block from the peer at `addr`.
+    pub fn record(&self, addr: &Address, success: bool) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(&Self::key(addr)) {
+            if success {
+                entry.successes += 1;
+            } else {
+                entry.failures += 1;
+            }
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Records what the peer at `addr` announced during a handshake.
+    pub fn set_features(&self, addr: &Address, features: Features) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(&Self::key(addr)) {
+            entry.features = Some(features);
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Score of the peer at `addr`, that of an unknown peer if it isn't in the book.
+    pub fn score(&self, addr: &Address) -> f64 {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(&Self::key(addr))
+            .map_or(0.5, Entry::score)
+    }
+
+    /// Sorts `peers` best scoring first.
+    pub fn sort_by_score<T>(&self, peers: &mut [T], addr: impl Fn(&T) -> Address) {
+        peers.sort_by(|a, b| {
+            self.score(&addr(b))
+                .partial_cmp(&self.score(&addr(a)))
+                .unwrap()
+        });
+    }
+
+    /// Known peers on `network` (all if `None`), best first.
+    pub fn list(&self, network: Option<&str>) -> Vec<Entry> {
+        let mut entries: Vec<_> = self
+            .entries
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|e| network.is_none_or(|n| e.network == n))
+            .cloned()
+            .collect();
+        entries.sort_by(|a, b| {
+            b.score()
+                .partial_cmp(&a.score())
+                .unwrap()
+                .then(b.time.cmp(&a.time))
+        });
+        entries
+    }
+
+    /// Stores the entries if they changed since the last call.
+    pub fn save(&self) -> Result<(), Error> {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.storage
+            .store(PEERS, &serde_json::to_value(self.list(None))?)
+    }
+}
+
+/// `proxy_getnodeaddresses [count] [network]`: like `getnodeaddresses`, but returning the peers of
+/// the proxy's address book, best scoring first.
+pub struct GetNodeAddresses;
+impl Interceptor for GetNodeAddresses {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        _user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        _ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            let count = match req.params.first() {
+                None | Some(Value::Null) => 1,
+                Some(count) => count.as_u64().ok_or_else(|| anyhow!("expected a count"))?,
+            };
+            let network = match req.params.get(1) {
+                None | Some(Value::Null) => None,
+                Some(Value::String(n)) if ["ipv4", "ipv6", "onion"].contains(&n.as_str()) => {
+                    Some(n.as_str())
+                }
+                Some(n) => return Err(anyhow!("unsupported network {}", n).into()),
+            };
+            let entries = state.address_book.list(network);
+            let count = if count == 0 {
+                entries.len()
+            } else {
+                count as usize
+            };
+            let result: Vec<_> = entries
+                .into_iter()
+                .take(count)
+                .map(|e| {
+                    json!({
+                        "time": e.time,
+                        "services": e.services,
+                        "address": e.address,
+                        "port": e.port,
+                        "network": e.network,
+                        "score": e.score(),
+                        "features": e.features,
+                    })
+                })
+                .collect();
+            Ok(Some(RpcResponse {
+                id: req.id.clone(),
+                result: Some(result.into()),
+                error: None,
+            }))
+        }
+        .boxed()
+    }
+}
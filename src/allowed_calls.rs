@@ -0,0 +1,158 @@
+//! The methods a user may call: names and glob patterns such as `get*` or `wallet/*`.
+
+use std::collections::HashSet;
+
+use crate::categories;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Literal(String),
+    /// `?`
+    AnyChar,
+    /// `*`
+    AnyString,
+}
+
+/// A compiled glob pattern.
+#[derive(Debug, Clone)]
+struct Glob(Vec<Token>);
+impl Glob {
+    fn compile(pattern: &str) -> Self {
+        let mut tokens = Vec::new();
+        for c in pattern.chars() {
+            match (c, tokens.last_mut()) {
+                ('*', Some(Token::AnyString)) => (),
+                ('*', _) => tokens.push(Token::AnyString),
+                ('?', _) => tokens.push(Token::AnyChar),
+                (c, Some(Token::Literal(s))) => s.push(c),
+                (c, _) => tokens.push(Token::Literal(c.to_string())),
+            }
+        }
+        Glob(tokens)
+    }
+
+    fn matches(&self, s: &str) -> bool {
+        fn matches(tokens: &[Token], s: &str) -> bool {
+            match tokens.split_first() {
+                None => s.is_empty(),
+                Some((Token::Literal(l), rest)) => {
+                    s.strip_prefix(l.as_str()).is_some_and(|s| matches(rest, s))
+                }
+                Some((Token::AnyChar, rest)) => {
+                    let mut chars = s.chars();
+                    chars.next().is_some() && matches(rest, chars.as_str())
+                }
+                Some((Token::AnyString, rest)) => s
+                    .char_indices()
+                    .map(|(i, _)| i)
+                    .chain(std::iter::once(s.len()))
+                    .any(|i| matches(rest, &s[i..])),
+            }
+        }
+        matches(&self.0, s)
+    }
+}
+
+/// A pattern of `allowed_calls`, matching method names or, if it contains `/`, the
+/// `<category>/<method>` of methods bitcoind lists under a category in `help`.
+#[derive(Debug, Clone)]
+struct Pattern {
+    source: String,
+    category: Option<Glob>,
+    method: Glob,
+}
+impl Pattern {
+    fn matches(&self, method: &str) -> bool {
+        match &self.category {
+            Some(category) => categories::category(method)
+                .is_some_and(|c| category.matches(c) && self.method.matches(method)),
+            None => self.method.matches(method),
+        }
+    }
+}
+
+/// `allowed_calls` of a user, with the patterns compiled when the config is loaded.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(from = "HashSet<String>")]
+pub struct AllowedCalls {
+    names: HashSet<String>,
+    patterns: Vec<Pattern>,
+}
+impl From<HashSet<String>> for AllowedCalls {
+    fn from(entries: HashSet<String>) -> Self {
+        let mut allowed = AllowedCalls::default();
+        for entry in entries {
+            if entry.contains(&['*', '?', '/'][..]) {
+                let (category, method) = match entry.split_once('/') {
+                    Some((category, method)) => (Some(Glob::compile(category)), method),
+                    None => (None, entry.as_str()),
+                };
+                allowed.patterns.push(Pattern {
+                    category,
+                    method: Glob::compile(method),
+                    source: entry.clone(),
+                });
+            } else {
+                allowed.names.insert(entry);
+            }
+        }
+        allowed.patterns.sort_by(|a, b| a.source.cmp(&b.source));
+        allowed
+    }
+}
+impl serde::Serialize for AllowedCalls {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut names: Vec<_> = self.names.iter().collect();
+        names.sort();
+        serializer.collect_seq(
+            names
+                .into_iter()
+                .chain(self.patterns.iter().map(|p| &p.source)),
+        )
+    }
+}
+impl AllowedCalls {
+    pub fn allows(&self, method: &str) -> bool {
+        self.matching_entry(method).is_some()
+    }
+
+    /// The entry allowing `method`, if any.
+    pub fn matching_entry(&self, method: &str) -> Option<&str> {
+        if let Some(name) = self.names.get(method) {
+            return Some(name);
+        }
+        self.patterns
+            .iter()
+            .find(|p| p.matches(method))
+            .map(|p| p.source.as_str())
+    }
+
+    /// Adds the names and patterns of `other`.
+    pub fn extend(&mut self, other: &AllowedCalls) {
+        self.names.extend(other.names.iter().cloned());
+        for pattern in &other.patterns {
+            if !self.patterns.iter().any(|p| p.source == pattern.source) {
+                self.patterns.push(pattern.clone());
+            }
+        }
+        self.patterns.sort_by(|a, b| a.source.cmp(&b.source));
+    }
+
+    /// The method names, without the patterns.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.names.iter().map(String::as_str)
+    }
+
+    pub fn has_patterns(&self) -> bool {
+        !self.patterns.is_empty()
+    }
+
+    /// Number of names and patterns.
+    pub fn len(&self) -> usize {
+        self.names.len() + self.patterns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,58 @@
+//! API keys: credentials of a user besides its password, e.g. one per client or per rotation,
+//! which can be revoked one by one.
+//!
+//! Clients send a key as the password of the user. Each key has an id naming it in logs and the
+//! journal, so that calls can be traced back to the key they were made with.
+
+use chrono::{DateTime, Utc};
+
+use crate::password::Password;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ApiKey {
+    /// Name of the key in logs and the journal
+    pub id: String,
+    /// The key, in plain text or hashed like passwords
+    pub key: Password,
+    /// Revoked keys are rejected, but still recognized in logs
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub revoked: bool,
+    /// The key is rejected after this time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<DateTime<Utc>>,
+}
+impl ApiKey {
+    /// Generates a random key, returning it along with the key storing its hash.
+    pub fn generate(
+        id: String,
+        expires: Option<DateTime<Utc>>,
+    ) -> Result<(String, Self), anyhow::Error> {
+        let key = hex::encode(rand::random::<[u8; 32]>());
+        let hashed = crate::password::hash(&key, crate::password::Scheme::Argon2)?;
+        Ok((
+            key,
+            ApiKey {
+                id,
+                key: Password::from(hashed),
+                revoked: false,
+                expires,
+            },
+        ))
+    }
+
+    /// Why the key is rejected, if it is.
+    pub fn rejected(&self, now: DateTime<Utc>) -> Option<&'static str> {
+        if self.revoked {
+            Some("revoked")
+        } else if self.expires.is_some_and(|expires| now > expires) {
+            Some("expired")
+        } else {
+            None
+        }
+    }
+}
+
+/// The key of `keys` matching the one sent by a client, whether or not it is still valid.
+pub fn find<'a>(keys: &'a [ApiKey], key: &str) -> Option<&'a ApiKey> {
+    keys.iter().find(|k| k.key.verify(key))
+}
@@ -0,0 +1,467 @@
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Error};
+use bitcoin::{consensus::deserialize, util::amount::Denomination, Amount, Transaction};
+use futures::future::{BoxFuture, FutureExt};
+use hyper::StatusCode;
+use serde_json::{json, Value};
+
+use crate::client::{
+    GenericRpcMethod, RpcError, RpcRequest, RpcResponse, ACCESS_DENIED_ERROR_CODE,
+    APPROVAL_REQUIRED_ERROR_CODE, READ_ONLY_ERROR_MESSAGE,
+};
+use crate::intercept::{InterceptResult, Interceptor, RequestContext};
+use crate::journal;
+use crate::state::State;
+use crate::tenants::{quota_exceeded, TenantStore};
+use crate::users::User;
+
+/// All the bitcoin there will ever be, in satoshis.
+const MAX_MONEY: u64 = 21_000_000 * 100_000_000;
+
+/// How long decided approvals are kept around so the requester can look up the outcome.
+const DECIDED_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ApprovalStatus {
+    Pending,
+    Approved,
+    Rejected,
+    Expired,
+    Failed,
+}
+
+#[derive(Debug)]
+pub struct Approval {
+    pub id: String,
+    pub user: String,
+    /// Who actually made the request, which differs from `user` under impersonation
+    pub requested_by: String,
+    pub path: String,
+    pub method: String,
+    pub params: Vec<Value>,
+    pub amount: Amount,
+    pub created: Instant,
+    pub status: ApprovalStatus,
+    pub decided_by: Option<String>,
+    pub result: Option<Value>,
+}
+impl Approval {
+    pub fn to_json(&self, timeout: Duration) -> Value {
+        json!({
+            "id": self.id,
+            "user": self.user,
+            "requested_by": self.requested_by,
+            "method": self.method,
+            "amount": self.amount.as_btc(),
+            "status": self.status,
+            "expires_in": timeout.checked_sub(self.created.elapsed()).unwrap_or_default().as_secs(),
+            "decided_by": self.decided_by,
+            "result": self.result,
+        })
+    }
+}
+
+/// Sends above a threshold wait for another user to approve them before they are forwarded.
+#[derive(Debug)]
+pub struct Approvals {
+    pub threshold: Amount,
+    pub timeout: Duration,
+    /// How many approvals a single user may have pending at once
+    pub quota: usize,
+    approvals: Mutex<HashMap<String, Approval>>,
+}
+impl Approvals {
+    pub fn new(threshold: Amount, timeout: Duration, quota: usize) -> Self {
+        Approvals {
+            threshold,
+            timeout,
+            quota,
+            approvals: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Forgets approvals decided long ago.
+    fn prune(&self, approvals: &mut HashMap<String, Approval>) {
+        let retention = self.timeout + DECIDED_RETENTION;
+        approvals.retain(|_, a| a.created.elapsed() < retention);
+    }
+
+    /// Marks pending approvals that timed out as expired, returning them.
+    pub fn expire(&self) -> Vec<(String, Value)> {
+        let mut approvals = self.approvals.lock().unwrap();
+        self.prune(&mut approvals);
+        approvals
+            .values_mut()
+            .filter(|a| a.status == ApprovalStatus::Pending && a.created.elapsed() > self.timeout)
+            .map(|a| {
+                a.status = ApprovalStatus::Expired;
+                (a.id.clone(), a.to_json(self.timeout))
+            })
+            .collect()
+    }
+
+    /// Parks the request if it sends more than the threshold, returning its id and the error
+    /// telling the client about it.
+    pub fn park(
+        &self,
+        path: &str,
+        req: &RpcRequest<GenericRpcMethod>,
+        ctx: &RequestContext,
+    ) -> Result<Option<(String, RpcError)>, RpcError> {
+        let amount = match sent_amount(req)? {
+            Some(amount) if amount > self.threshold => amount,
+            _ => return Ok(None),
+        };
+        let id = hex::encode(rand::random::<[u8; 16]>());
+        let mut approvals = self.approvals.lock().unwrap();
+        self.prune(&mut approvals);
+        let pending = approvals
+            .values()
+            .filter(|a| a.user == ctx.user_name && a.status == ApprovalStatus::Pending)
+            .count();
+        if pending >= self.quota {
+            return Err(quota_exceeded("approval", self.quota));
+        }
+        approvals.insert(
+            id.clone(),
+            Approval {
+                id: id.clone(),
+                user: ctx.user_name.clone(),
+                requested_by: ctx.principal().to_owned(),
+                path: path.to_owned(),
+                method: req.method.0.clone(),
+                params: req.params.clone(),
+                amount,
+                created: Instant::now(),
+                status: ApprovalStatus::Pending,
+                decided_by: None,
+                result: None,
+            },
+        );
+        Ok(Some((
+            id.clone(),
+            RpcError {
+                code: APPROVAL_REQUIRED_ERROR_CODE,
+                message: format!(
+                    "Sending {} requires approval, use proxy_getapproval to check its status",
+                    amount
+                ),
+                data: Some(json!({ "approval_id": id, "expires_in": self.timeout.as_secs() })),
+                status: Some(StatusCode::ACCEPTED),
+            },
+        )))
+    }
+
+    pub fn get(&self, id: &str) -> Option<Value> {
+        let mut approvals = self.approvals.lock().unwrap();
+        self.prune(&mut approvals);
+        approvals.get(id).map(|a| a.to_json(self.timeout))
+    }
+
+    pub fn list(&self) -> Vec<Value> {
+        let mut approvals = self.approvals.lock().unwrap();
+        self.prune(&mut approvals);
+        approvals
+            .values()
+            .filter(|a| a.status == ApprovalStatus::Pending)
+            .map(|a| a.to_json(self.timeout))
+            .collect()
+    }
+
+    /// Takes a pending approval out of the queue on behalf of the caller of `ctx`, who needs
+    /// `approve` and has to be someone else than who made the request.
+    fn decide(
+        &self,
+        id: &str,
+        ctx: &RequestContext,
+        user: &User,
+        status: ApprovalStatus,
+    ) -> Result<(String, RpcRequest<GenericRpcMethod>), Error> {
+        if !user.approve {
+            return Err(anyhow!("deciding on approvals requires approve"));
+        }
+        // an admin could otherwise approve its own requests as someone else
+        if ctx.impersonator.is_some() {
+            return Err(anyhow!("approvals can't be decided while impersonating"));
+        }
+        let approver = ctx.principal();
+        let mut approvals = self.approvals.lock().unwrap();
+        self.prune(&mut approvals);
+        let a = approvals
+            .get_mut(id)
+            .ok_or_else(|| anyhow!("unknown approval {}", id))?;
+        if a.status != ApprovalStatus::Pending {
+            return Err(anyhow!("approval {} is {:?}", id, a.status));
+        }
+        if a.created.elapsed() > self.timeout {
+            a.status = ApprovalStatus::Expired;
+            return Err(anyhow!("approval {} expired", id));
+        }
+        if a.requested_by == approver {
+            return Err(anyhow!(
+                "requests cannot be approved by the user who made them"
+            ));
+        }
+        a.status = status;
+        a.decided_by = Some(approver.to_owned());
+        Ok((
+            a.path.clone(),
+            RpcRequest {
+                id: None,
+                method: GenericRpcMethod(a.method.clone()),
+                params: a.params.clone(),
+            },
+        ))
+    }
+
+    fn finish(&self, id: &str, res: &Result<Value, RpcError>) {
+        if let Some(a) = self.approvals.lock().unwrap().get_mut(id) {
+            match res {
+                Ok(v) => a.result = Some(v.clone()),
+                Err(e) => {
+                    a.status = ApprovalStatus::Failed;
+                    a.result = Some(json!({ "code": e.code, "message": e.message }));
+                }
+            }
+        }
+    }
+}
+
+impl TenantStore for Approvals {
+    fn name(&self) -> &'static str {
+        "approvals"
+    }
+    fn inspect(&self, user: &str) -> Value {
+        let mut approvals = self.approvals.lock().unwrap();
+        self.prune(&mut approvals);
+        let entries: Vec<_> = approvals
+            .values()
+            .filter(|a| a.user == user)
+            .map(|a| a.to_json(self.timeout))
+            .collect();
+        json!({ "quota": self.quota, "entries": entries })
+    }
+    /// Drops the user's approvals, pending ones included.
+    fn purge(&self, user: &str) -> usize {
+        let mut approvals = self.approvals.lock().unwrap();
+        let before = approvals.len();
+        approvals.retain(|_, a| a.user != user);
+        before - approvals.len()
+    }
+}
+
+fn parse_amount(v: &Value) -> Result<Amount, Error> {
+    match v {
+        // the digits as sent, falling back to a float for exponents
+        Value::Number(n) => match Amount::from_str_in(&n.to_string(), Denomination::Bitcoin) {
+            Ok(amount) => Ok(amount),
+            Err(_) => Ok(Amount::from_btc(
+                n.as_f64().ok_or_else(|| anyhow!("invalid amount {}", n))?,
+            )?),
+        },
+        Value::String(s) => Ok(Amount::from_str_in(s, Denomination::Bitcoin)?),
+        _ => Err(anyhow!("invalid amount {}", v)),
+    }
+}
+
+fn sum_outputs(outputs: &Value) -> Result<Amount, Error> {
+    let mut sum = Amount::ZERO;
+    let mut add = |map: &serde_json::Map<String, Value>| -> Result<(), Error> {
+        for (k, v) in map {
+            if k != "data" {
+                sum += parse_amount(v)?;
+            }
+        }
+        Ok(())
+    };
+    match outputs {
+        Value::Object(map) => add(map)?,
+        Value::Array(outputs) => {
+            for o in outputs {
+                if let Value::Object(map) = o {
+                    add(map)?;
+                }
+            }
+        }
+        _ => return Err(anyhow!("invalid outputs {}", outputs)),
+    }
+    Ok(sum)
+}
+
+/// The amount leaving the wallet or node for methods that send funds. What `sendall` sends to
+/// recipients given without an amount is only known once the wallet is swept, so it counts as
+/// all the bitcoin there can be.
+pub fn sent_amount(req: &RpcRequest<GenericRpcMethod>) -> Result<Option<Amount>, Error> {
+    let param = |i: usize| req.params.get(i).unwrap_or(&Value::Null);
+    Ok(match &*req.method.0 {
+        "sendtoaddress" => Some(parse_amount(param(1))?),
+        "sendmany" => Some(sum_outputs(param(1))?),
+        "send" => Some(sum_outputs(param(0))?),
+        "sendall" => {
+            let recipients = param(0)
+                .as_array()
+                .ok_or_else(|| anyhow!("expected an array of recipients"))?;
+            if recipients.iter().any(Value::is_string) {
+                Some(Amount::from_sat(MAX_MONEY))
+            } else {
+                Some(sum_outputs(param(0))?)
+            }
+        }
+        "sendrawtransaction" => Some(Amount::from_sat(output_value(param(0))?)),
+        "submitpackage" => {
+            let package = param(0)
+                .as_array()
+                .ok_or_else(|| anyhow!("expected an array of transactions"))?;
+            let mut sum = 0u64;
+            for tx in package {
+                sum = sum.saturating_add(output_value(tx)?);
+            }
+            Some(Amount::from_sat(sum))
+        }
+        _ => None,
+    })
+}
+
+/// The value of all outputs of the raw transaction `hex`, in satoshis.
+fn output_value(hex: &Value) -> Result<u64, Error> {
+    let tx: Transaction = deserialize(&hex::decode(
+        hex.as_str()
+            .ok_or_else(|| anyhow!("expected a hex string"))?,
+    )?)?;
+    Ok(tx.output.iter().map(|o| o.value).sum())
+}
+
+fn approvals(state: &State) -> Result<&Approvals, Error> {
+    state
+        .approvals
+        .as_ref()
+        .ok_or_else(|| anyhow!("approvals are not enabled"))
+}
+
+fn id_param(req: &RpcRequest<GenericRpcMethod>) -> Result<&str, Error> {
+    req.params
+        .first()
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow!("expected an approval id"))
+}
+
+fn respond(req: &RpcRequest<GenericRpcMethod>, result: Value) -> InterceptResult {
+    Ok(Some(RpcResponse {
+        id: req.id.clone(),
+        result: Some(result),
+        error: None,
+    }))
+}
+
+/// `proxy_getapproval <id>`: status of a parked request.
+pub struct GetApproval;
+impl Interceptor for GetApproval {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        _user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        _ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            let id = id_param(req)?;
+            let approval = approvals(&state)?
+                .get(id)
+                .ok_or_else(|| anyhow!("unknown approval {}", id))?;
+            respond(req, approval)
+        }
+        .boxed()
+    }
+}
+
+/// `proxy_listapprovals`: all requests waiting for approval.
+pub struct ListApprovals;
+impl Interceptor for ListApprovals {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        _user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        _ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move { respond(req, approvals(&state)?.list().into()) }.boxed()
+    }
+}
+
+/// `proxy_approve <id>`: forwards a parked request, returning its result.
+pub struct Approve;
+impl Interceptor for Approve {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            let id = id_param(req)?;
+            let approvals = approvals(&state)?;
+            // checked before deciding, so that the send can still be approved later
+            if state.read_only.load(Ordering::SeqCst) {
+                return Err(RpcError {
+                    code: ACCESS_DENIED_ERROR_CODE,
+                    message: READ_ONLY_ERROR_MESSAGE.to_owned(),
+                    data: None,
+                    status: Some(StatusCode::SERVICE_UNAVAILABLE),
+                });
+            }
+            let (path, parked) = approvals.decide(id, ctx, user, ApprovalStatus::Approved)?;
+            info!(state.logger, "{} approved {}", ctx.user_name, id);
+            let res = match &state.journal {
+                Some(journal) => {
+                    journal::forward(state.clone(), journal, &path, &parked, ctx).await
+                }
+                None => state
+                    .rpc_client
+                    .call_at(&path, &parked)
+                    .await
+                    .map_err(RpcError::from),
+            }
+            .and_then(|res| res.into_result());
+            approvals.finish(id, &res);
+            Ok(Some(match res {
+                Ok(result) => RpcResponse {
+                    id: req.id.clone(),
+                    result: Some(result),
+                    error: None,
+                },
+                Err(e) => RpcResponse {
+                    id: req.id.clone(),
+                    result: None,
+                    error: Some(e),
+                },
+            }))
+        }
+        .boxed()
+    }
+}
+
+/// `proxy_reject <id>`: drops a parked request.
+pub struct Reject;
+impl Interceptor for Reject {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            let id = id_param(req)?;
+            approvals(&state)?.decide(id, ctx, user, ApprovalStatus::Rejected)?;
+            info!(state.logger, "{} rejected {}", ctx.user_name, id);
+            respond(req, Value::Null)
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,136 @@
+//! Audit events for denied and failed authentication, kept apart from the log so that they can
+//! be shipped to a SIEM. Each event is a JSON object, written as a line to a file or as the
+//! message of a syslog entry (facility authpriv).
+
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, Utc};
+
+use crate::state::State;
+
+/// Socket of the local syslog daemon.
+#[cfg(unix)]
+const SYSLOG_SOCKET: &str = "/dev/log";
+/// Facility authpriv, severity warning.
+#[cfg(unix)]
+const SYSLOG_PRIORITY: u8 = 10 * 8 + 4;
+
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    /// No valid credentials were sent
+    AuthenticationFailed,
+    /// The client address or user name is locked out after repeated failures
+    LockedOut,
+    /// The credentials are valid but may not be used now or from this address
+    AccessDenied,
+    ImpersonationDenied,
+    /// The user may not make the call
+    CallDenied,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Event<'a> {
+    pub time: DateTime<Utc>,
+    pub event: Kind,
+    /// The user authenticated or, if authentication failed, the user name tried
+    pub user: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub impersonator: Option<&'a str>,
+    pub ip: Option<IpAddr>,
+    pub method: Option<&'a str>,
+    pub reason: &'a str,
+}
+impl<'a> Event<'a> {
+    pub fn new(event: Kind, reason: &'a str) -> Self {
+        Event {
+            time: Utc::now(),
+            event,
+            user: None,
+            impersonator: None,
+            ip: None,
+            method: None,
+            reason,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Sink {
+    File(Mutex<std::fs::File>),
+    #[cfg(unix)]
+    Syslog(std::os::unix::net::UnixDatagram, PathBuf),
+}
+
+#[derive(Debug)]
+pub struct Audit {
+    sink: Sink,
+}
+impl Audit {
+    /// Opens the sink described by `spec`: `syslog` (or `syslog:<socket>`) or a file path,
+    /// optionally prefixed with `file:`.
+    pub fn open(spec: &str) -> Result<Self, Error> {
+        let sink = match spec.strip_prefix("syslog") {
+            #[cfg(unix)]
+            Some(socket) if socket.is_empty() || socket.starts_with(':') => {
+                let socket = match socket.strip_prefix(':') {
+                    Some(socket) => PathBuf::from(socket),
+                    None => PathBuf::from(SYSLOG_SOCKET),
+                };
+                let datagram = std::os::unix::net::UnixDatagram::unbound()?;
+                Sink::Syslog(datagram, socket)
+            }
+            #[cfg(not(unix))]
+            Some(socket) if socket.is_empty() || socket.starts_with(':') => {
+                return Err(anyhow!("syslog is only supported on unix"));
+            }
+            _ => {
+                let path = spec.strip_prefix("file:").unwrap_or(spec);
+                let file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| anyhow!("failed to open audit sink {}: {}", path, e))?;
+                Sink::File(Mutex::new(file))
+            }
+        };
+        Ok(Audit { sink })
+    }
+
+    fn write(&self, event: &Event) -> Result<(), Error> {
+        let json = serde_json::to_string(event)?;
+        match &self.sink {
+            Sink::File(file) => {
+                let line = json + "\n";
+                file.lock().unwrap().write_all(line.as_bytes())?;
+            }
+            #[cfg(unix)]
+            Sink::Syslog(datagram, socket) => {
+                let message = format!(
+                    "<{}>btc_rpc_proxy[{}]: {}",
+                    SYSLOG_PRIORITY,
+                    std::process::id(),
+                    json
+                );
+                datagram
+                    .send_to(message.as_bytes(), socket)
+                    .map_err(|e| anyhow!("{}: {}", socket.display(), e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Records `event` if an audit sink is configured, logging failures instead of failing the
+/// request.
+pub fn record(state: &State, event: Event) {
+    if let Some(audit) = &state.audit {
+        if let Err(e) = audit.write(&event) {
+            error!(state.logger, "Failed to record audit event: {:#}", e);
+        }
+    }
+}
@@ -0,0 +1,387 @@
+//! Validation of credentials. Users of the config are one backend, others validate credentials
+//! elsewhere and name the configured user whose permissions apply.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Error};
+use bitcoin::hashes::{sha256, Hash};
+use futures::future::{BoxFuture, FutureExt};
+use hyper::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use hyper::http::request::Parts;
+use hyper::{Body, Method, Request, Response, StatusCode, Uri};
+use serde_json::{json, Value};
+
+use crate::api_keys;
+use crate::audit::{self, Event, Kind};
+use crate::client::RpcResponse;
+use crate::clock;
+use crate::state::State;
+use crate::users::{ClientCert, User, Users};
+
+/// Validates user names and passwords sent with basic auth.
+pub trait AuthBackend: Send + Sync + std::fmt::Debug {
+    /// Short name for logs
+    fn name(&self) -> &str;
+
+    /// The configured user whose permissions apply, `None` if the credentials aren't valid.
+    fn authenticate<'a>(
+        &'a self,
+        user: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>, Error>>;
+}
+
+impl AuthBackend for Users {
+    fn name(&self) -> &str {
+        "config"
+    }
+
+    fn authenticate<'a>(
+        &'a self,
+        user: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>, Error>> {
+        // users without a password (client certificates, API keys, tokens, profiles) can't log
+        // in with one, not even an empty one
+        let valid = self
+            .by_name(user)
+            .is_some_and(|u| !u.password.is_empty() && u.password.verify(password));
+        futures::future::ready(Ok(if valid { Some(user.to_owned()) } else { None })).boxed()
+    }
+}
+
+/// Asks an HTTP endpoint, e.g. a bridge to LDAP or PAM, to validate credentials.
+///
+/// The endpoint receives `{"user": ..., "password": ...}` in a POST request and accepts the
+/// credentials with status 200, optionally naming the configured user whose permissions apply in
+/// `{"user": ...}` (the same name if not). Status 401 or 403 rejects them.
+#[derive(Debug)]
+pub struct HttpAuth {
+    uri: Uri,
+    client: hyper::Client<hyper::client::HttpConnector>,
+    /// Accepted credentials by their digest, so that the endpoint isn't asked on every request
+    cache: Mutex<HashMap<sha256::Hash, (String, Instant)>>,
+    cache_ttl: Duration,
+}
+impl HttpAuth {
+    pub fn new(uri: Uri, cache_ttl: Duration) -> Self {
+        HttpAuth {
+            uri,
+            client: hyper::Client::new(),
+            cache: Mutex::new(HashMap::new()),
+            cache_ttl,
+        }
+    }
+
+    async fn ask(&self, user: &str, password: &str) -> Result<Option<String>, Error> {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(self.uri.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                json!({ "user": user, "password": password }).to_string(),
+            ))?;
+        let response = self.client.request(request).await?;
+        match response.status() {
+            StatusCode::OK => (),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => return Ok(None),
+            status => return Err(anyhow!("authentication endpoint responded with {}", status)),
+        }
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        let profile = serde_json::from_slice::<Value>(&body)
+            .ok()
+            .and_then(|v| v.get("user")?.as_str().map(str::to_owned));
+        Ok(Some(profile.unwrap_or_else(|| user.to_owned())))
+    }
+}
+impl AuthBackend for HttpAuth {
+    fn name(&self) -> &str {
+        "http"
+    }
+
+    fn authenticate<'a>(
+        &'a self,
+        user: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>, Error>> {
+        async move {
+            let key = sha256::Hash::hash(format!("{}:{}", user, password).as_bytes());
+            if let Some((profile, at)) = self.cache.lock().unwrap().get(&key) {
+                if at.elapsed() < self.cache_ttl {
+                    return Ok(Some(profile.clone()));
+                }
+            }
+            let profile = self.ask(user, password).await?;
+            let mut cache = self.cache.lock().unwrap();
+            cache.retain(|_, (_, at)| at.elapsed() < self.cache_ttl);
+            if let Some(profile) = &profile {
+                cache.insert(key, (profile.clone(), Instant::now()));
+            }
+            Ok(profile)
+        }
+        .boxed()
+    }
+}
+
+/// User name and password sent with basic auth.
+fn basic_credentials(header: &str) -> Option<(String, String)> {
+    let auth = header.strip_prefix("Basic ")?;
+    let auth_decoded = base64::decode(auth).ok()?;
+    let auth_decoded_str = std::str::from_utf8(&auth_decoded).ok()?;
+    let mut auth_split = auth_decoded_str.split(":");
+    let name = auth_split.next()?;
+    let pass = auth_split.next()?;
+    Some((name.to_owned(), pass.to_owned()))
+}
+
+/// The user name a client claims with basic auth, whether or not the password is right.
+pub fn basic_user(auth: &HeaderValue) -> Option<String> {
+    basic_credentials(auth.to_str().ok()?).map(|(name, _)| name)
+}
+
+/// An authenticated client.
+#[derive(Debug)]
+pub struct Authenticated {
+    pub name: String,
+    /// The configured user whose permissions apply, which differs from `name` for clients of
+    /// other backends, OpenID Connect and anonymous clients
+    pub profile: String,
+    pub user: Arc<User>,
+    /// Id of the API key the client authenticated with
+    pub api_key: Option<String>,
+}
+impl From<(String, Arc<User>)> for Authenticated {
+    fn from((name, user): (String, Arc<User>)) -> Self {
+        Authenticated::mapped(name.clone(), name, user)
+    }
+}
+impl Authenticated {
+    /// A client known as `name` with the permissions of the configured user `profile`.
+    pub fn mapped(name: String, profile: String, user: Arc<User>) -> Self {
+        Authenticated {
+            name,
+            profile,
+            user,
+            api_key: None,
+        }
+    }
+}
+
+/// Authenticates the client with a bearer token issued by the proxy or, if configured, a JWT or
+/// an access token of an OpenID Connect provider, with basic auth against the API keys and users
+/// of the config and then the other backends otherwise.
+pub async fn authenticate(state: &State, auth: &HeaderValue) -> Option<Authenticated> {
+    let header_str = auth.to_str().ok()?;
+    if let Some(token) = header_str.strip_prefix("Bearer ") {
+        let token = token.trim();
+        if let Some(issued) = state.tokens.get(&state.users, token) {
+            return Some(issued);
+        }
+        if let Some(name) = state.jwt.as_ref().and_then(|jwt| jwt.verify(token).ok()) {
+            return state.users.by_name(&name).map(|u| (name, u).into());
+        }
+        return match state.oidc.as_ref()?.authenticate(token).await {
+            // distinct from configured users, which don't share state with subjects
+            Ok((subject, profile)) => match state.users.by_name(&profile) {
+                Some(user) => Some(Authenticated::mapped(
+                    format!("oidc:{}", subject),
+                    profile,
+                    user,
+                )),
+                None => {
+                    warn!(
+                        state.logger,
+                        "access token of {} maps to unknown user {}", subject, profile
+                    );
+                    None
+                }
+            },
+            Err(e) => {
+                debug!(state.logger, "rejected access token: {:#}", e);
+                None
+            }
+        };
+    }
+    let (name, pass) = basic_credentials(header_str)?;
+    let (name, pass) = (&*name, &*pass);
+    if let Some(user) = state.users.by_name(name) {
+        if let Some(key) = api_keys::find(&user.api_key, pass) {
+            if let Some(reason) = key.rejected(clock::now()) {
+                warn!(state.logger, "{} used {} API key {}", name, reason, key.id);
+                return None;
+            }
+            let api_key = Some(key.id.clone());
+            return Some(Authenticated {
+                name: name.to_owned(),
+                profile: name.to_owned(),
+                user,
+                api_key,
+            });
+        }
+    }
+    let backends = std::iter::once(&state.users as &dyn AuthBackend)
+        .chain(state.auth_backends.iter().map(|b| &**b));
+    for backend in backends {
+        match backend.authenticate(name, pass).await {
+            Ok(Some(profile)) => match state.users.by_name(&profile) {
+                Some(user) => return Some(Authenticated::mapped(name.to_owned(), profile, user)),
+                None => warn!(
+                    state.logger,
+                    "{} backend accepted {} as unknown user {}",
+                    backend.name(),
+                    name,
+                    profile
+                ),
+            },
+            Ok(None) => (),
+            Err(e) => warn!(
+                state.logger,
+                "{} backend failed to authenticate {}: {:#}",
+                backend.name(),
+                name,
+                e
+            ),
+        }
+    }
+    None
+}
+
+/// Prefix of the names of anonymous clients.
+const ANONYMOUS_PREFIX: &str = "anonymous:";
+
+/// Calls per second of all anonymous clients together if not configured.
+pub const DEFAULT_ANONYMOUS_RATE: f64 = 20.0;
+
+/// Whether `name` is the name of an anonymous client.
+pub fn is_anonymous(name: &str) -> bool {
+    name.starts_with(ANONYMOUS_PREFIX)
+}
+
+/// The network anonymous clients at `addr` are counted as one user: the address for IPv4 and
+/// the /64 prefix for IPv6, as clients usually get a whole prefix to pick addresses from.
+fn client_network(addr: IpAddr) -> String {
+    match addr {
+        IpAddr::V4(v4) => v4.to_string(),
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => v4.to_string(),
+            None => {
+                let s = v6.segments();
+                let prefix = Ipv6Addr::new(s[0], s[1], s[2], s[3], 0, 0, 0, 0);
+                format!("{}/64", prefix)
+            }
+        },
+    }
+}
+
+/// The permissions of `anonymous_user` for a client sending no credentials, if configured. Each
+/// network is a distinct user, so that rate limits apply per client.
+pub fn anonymous(state: &State, addr: Option<IpAddr>) -> Option<Authenticated> {
+    let profile = state.anonymous_user.as_ref()?;
+    let user = state.users.by_name(profile)?;
+    // the profile may have been changed at runtime
+    if let Err(e) = user.check_anonymous() {
+        warn!(
+            state.logger,
+            "anonymous_user {} denied to anonymous clients: {:#}", profile, e
+        );
+        return None;
+    }
+    let name = match addr {
+        Some(addr) => format!("{}{}", ANONYMOUS_PREFIX, client_network(addr)),
+        None => format!("{}unknown", ANONYMOUS_PREFIX),
+    };
+    Some(Authenticated::mapped(name, profile.clone(), user))
+}
+
+/// Authenticates the client of a request by its credentials, its client certificate or as
+/// anonymous, and checks when and from where the user may connect. Repeated failures lock the
+/// client out. The error is the response to send instead.
+pub(crate) async fn authenticate_request(
+    state: &State,
+    parts: &Parts,
+) -> Result<Authenticated, Response<Body>> {
+    let ip = parts.extensions.get::<SocketAddr>().map(|a| a.ip());
+    let claimed = parts.headers.get(AUTHORIZATION).and_then(basic_user);
+    if let Some(lockout) = &state.lockout {
+        if let Err(e) = lockout.check(ip, claimed.as_deref()) {
+            audit::record(
+                state,
+                Event {
+                    user: claimed.as_deref(),
+                    ip,
+                    ..Event::new(Kind::LockedOut, &e.message)
+                },
+            );
+            return Err(RpcResponse::from(e).into_response().unwrap_or_default());
+        }
+    }
+    let auth = match parts.headers.get(AUTHORIZATION) {
+        Some(auth) => authenticate(state, auth).await,
+        None => match parts.extensions.get::<ClientCert>() {
+            Some(cert) => state.users.by_client_cert(cert).map(Authenticated::from),
+            None => anonymous(state, ip),
+        },
+    };
+    if let Some(lockout) = &state.lockout {
+        if auth.is_some() {
+            lockout.succeed(claimed.as_deref());
+        } else if parts.headers.contains_key(AUTHORIZATION) && lockout.fail(ip, claimed.as_deref())
+        {
+            warn!(
+                state.logger,
+                "Locked out {} after repeated failed authentication attempts{}",
+                ip.map_or("unknown address".to_owned(), |ip| ip.to_string()),
+                claimed
+                    .as_deref()
+                    .map_or(String::new(), |user| format!(" for {}", user))
+            );
+        }
+    }
+    let auth = match auth {
+        Some(auth) => auth,
+        None => {
+            let reason = if parts.headers.contains_key(AUTHORIZATION) {
+                "invalid credentials"
+            } else if parts.extensions.get::<ClientCert>().is_some() {
+                "unknown client certificate"
+            } else {
+                "no credentials"
+            };
+            audit::record(
+                state,
+                Event {
+                    user: claimed.as_deref(),
+                    ip,
+                    ..Event::new(Kind::AuthenticationFailed, reason)
+                },
+            );
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::UNAUTHORIZED;
+            response.headers_mut().insert(
+                WWW_AUTHENTICATE,
+                HeaderValue::from_static("Basic realm=\"jsonrpc\""),
+            );
+            return Err(response);
+        }
+    };
+    if let Err(e) = auth
+        .user
+        .check_access(clock::now())
+        .and_then(|_| auth.user.check_address(ip))
+    {
+        warn!(state.logger, "{} denied: {}", auth.name, e.message);
+        audit::record(
+            state,
+            Event {
+                user: Some(&auth.name),
+                ip,
+                ..Event::new(Kind::AccessDenied, &e.message)
+            },
+        );
+        return Err(RpcResponse::from(e).into_response().unwrap_or_default());
+    }
+    Ok(auth)
+}
@@ -0,0 +1,168 @@
+//! Balance snapshots of configured wallets, polled in the background so that dashboards calling
+//! `proxy_getbalances` don't each make bitcoind compute the balances.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use futures::future::{BoxFuture, FutureExt};
+use hyper::StatusCode;
+use serde_json::{json, Map, Value};
+
+use crate::client::{
+    GenericRpcMethod, RpcError, RpcRequest, RpcResponse, ACCESS_DENIED_ERROR_CODE,
+};
+use crate::intercept::{InterceptResult, Interceptor, RequestContext};
+use crate::paths::wallet_path;
+use crate::state::State;
+use crate::users::{percent_decode, User};
+
+/// The last result of polling a wallet.
+#[derive(Debug, Default)]
+struct Snapshot {
+    /// Result of the last successful `getbalances`
+    balances: Option<Value>,
+    updated: Option<DateTime<Utc>>,
+    /// Why the last poll failed, if it did
+    error: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Balances {
+    pub wallets: Vec<String>,
+    pub interval: Duration,
+    snapshots: RwLock<HashMap<String, Snapshot>>,
+}
+impl Balances {
+    pub fn new(wallets: Vec<String>, interval: Duration) -> Self {
+        Balances {
+            wallets,
+            interval,
+            snapshots: RwLock::new(HashMap::new()),
+        }
+    }
+
+    async fn poll(&self, state: &State, wallet: &str) {
+        let req = RpcRequest {
+            id: None,
+            method: GenericRpcMethod("getbalances".to_owned()),
+            params: Vec::new(),
+        };
+        let result = match state.rpc_client.call_at(&wallet_path(wallet), &req).await {
+            Ok(response) => response.into_result().map_err(|e| e.message),
+            Err(e) => Err(format!("{:#}", e)),
+        };
+        let mut snapshots = self.snapshots.write().unwrap();
+        let snapshot = snapshots.entry(wallet.to_owned()).or_default();
+        match result {
+            Ok(balances) => {
+                snapshot.balances = Some(balances);
+                snapshot.updated = Some(Utc::now());
+                snapshot.error = None;
+            }
+            Err(e) => {
+                if snapshot.error.as_ref() != Some(&e) {
+                    warn!(
+                        state.logger,
+                        "Failed to poll the balances of wallet {}: {}", wallet, e
+                    );
+                }
+                snapshot.error = Some(e);
+            }
+        }
+    }
+
+    /// Polls every wallet independently, so that a slow wallet doesn't hold up the others.
+    pub async fn run(state: Arc<State>) {
+        let balances = match &state.balances {
+            Some(balances) => balances,
+            None => return,
+        };
+        futures::future::join_all(balances.wallets.iter().map(|wallet| {
+            let state = &state;
+            async move {
+                let mut interval = tokio::time::interval(balances.interval);
+                loop {
+                    interval.tick().await;
+                    balances.poll(state, wallet).await;
+                }
+            }
+        }))
+        .await;
+    }
+
+    /// The snapshot of `wallet`, `None` if it isn't polled.
+    fn get(&self, wallet: &str) -> Option<Value> {
+        if !self.wallets.iter().any(|w| w == wallet) {
+            return None;
+        }
+        let snapshots = self.snapshots.read().unwrap();
+        let snapshot = snapshots.get(wallet);
+        let updated = snapshot.and_then(|s| s.updated);
+        Some(json!({
+            "balances": snapshot.and_then(|s| s.balances.clone()),
+            "updated": updated,
+            "age": updated.map(|u| (Utc::now() - u).num_milliseconds() as f64 / 1000.0),
+            "error": snapshot.and_then(|s| s.error.clone()),
+        }))
+    }
+}
+
+/// `proxy_getbalances [wallet]`: the last polled `getbalances` result of a wallet (given as
+/// parameter or in the path like for wallet methods) with the time it was polled, or of all
+/// polled wallets the caller may use.
+pub struct GetBalances;
+impl Interceptor for GetBalances {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            let balances = state
+                .balances
+                .as_ref()
+                .ok_or_else(|| anyhow!("no wallets are polled, see balance_wallets"))?;
+            let wallet = match req.params.first() {
+                Some(Value::String(wallet)) => Some(wallet.clone()),
+                Some(Value::Null) | None => {
+                    ctx.path.strip_prefix("/wallet/").and_then(percent_decode)
+                }
+                Some(_) => return Err(anyhow!("expected a wallet name").into()),
+            };
+            let result = match wallet {
+                Some(wallet) => {
+                    if !user.may_use_wallet(&wallet) {
+                        return Err(RpcError {
+                            code: ACCESS_DENIED_ERROR_CODE,
+                            message: format!("Wallet {} is not allowed", wallet),
+                            data: None,
+                            status: Some(StatusCode::FORBIDDEN),
+                        });
+                    }
+                    balances
+                        .get(&wallet)
+                        .ok_or_else(|| anyhow!("wallet {} is not polled", wallet))?
+                }
+                None => Value::Object(
+                    balances
+                        .wallets
+                        .iter()
+                        .filter(|w| user.may_use_wallet(w))
+                        .filter_map(|w| Some((w.clone(), balances.get(w)?)))
+                        .collect::<Map<_, _>>(),
+                ),
+            };
+            Ok(Some(RpcResponse {
+                id: req.id.clone(),
+                result: Some(result),
+                error: None,
+            }))
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,111 @@
+//! Administers a running proxy through its control socket (`control_socket`), with the
+//! permissions of an admin and without credentials.
+
+use anyhow::{anyhow, Error};
+use serde_json::{json, Value};
+
+const USAGE: &str = "usage: btc-rpc-proxy-ctl [--socket <path>] <command>
+
+The socket is control_socket of the proxy, also read from BTC_RPC_PROXY_CONTROL_SOCKET.
+
+Commands:
+  status                        version and runtime state of the proxy
+  dump-state                    snapshot of the internal state of the proxy
+  list-users                    permissions of every user
+  maintenance on|off            deny or allow state-changing calls of all users
+  ban-peer <address> [seconds]  make bitcoind ban a peer (a day by default)
+  reload                        restart with the same arguments without downtime, reloading
+                                the config and an upgraded binary
+  call <method> [<param>...]    call any method, parameters are JSON or strings";
+
+/// A parameter given on the command line: JSON if it parses, a string otherwise.
+fn param(arg: String) -> Value {
+    serde_json::from_str(&arg).unwrap_or(Value::String(arg))
+}
+
+#[cfg(unix)]
+fn send(socket: &str, line: &str) -> Result<Value, Error> {
+    use std::io::{BufRead, BufReader, Write};
+
+    let mut stream = std::os::unix::net::UnixStream::connect(socket)
+        .map_err(|e| anyhow!("failed to connect to {}: {}", socket, e))?;
+    stream.write_all(line.as_bytes())?;
+    stream.write_all(b"\n")?;
+    let mut answer = String::new();
+    BufReader::new(stream).read_line(&mut answer)?;
+    if answer.is_empty() {
+        return Err(anyhow!("the proxy closed the connection"));
+    }
+    let response: Value = serde_json::from_str(&answer)?;
+    match response.get("error") {
+        Some(Value::Null) | None => Ok(response.get("result").cloned().unwrap_or(Value::Null)),
+        Some(error) => Err(anyhow!(
+            "{}",
+            error
+                .get("message")
+                .and_then(Value::as_str)
+                .map_or_else(|| error.to_string(), str::to_owned)
+        )),
+    }
+}
+
+#[cfg(not(unix))]
+fn send(_socket: &str, _line: &str) -> Result<Value, Error> {
+    Err(anyhow!("the control socket is only supported on unix"))
+}
+
+fn call(socket: &str, method: &str, params: Vec<Value>) -> Result<Value, Error> {
+    send(
+        socket,
+        &json!({ "jsonrpc": "1.0", "id": "ctl", "method": method, "params": params }).to_string(),
+    )
+}
+
+fn main() -> Result<(), Error> {
+    let mut args = std::env::args().skip(1).peekable();
+    let mut socket = std::env::var("BTC_RPC_PROXY_CONTROL_SOCKET").ok();
+    if args.peek().is_some_and(|a| a == "--socket") {
+        args.next();
+        socket = Some(args.next().ok_or_else(|| anyhow!(USAGE))?);
+    }
+    let socket = socket.ok_or_else(|| anyhow!("no control socket given\n\n{}", USAGE))?;
+    let command = args.next().ok_or_else(|| anyhow!(USAGE))?;
+    let mut args: Vec<String> = args.collect();
+    let result = match (command.as_str(), args.len()) {
+        ("status", 0) => call(&socket, "proxy_status", Vec::new())?,
+        ("dump-state", 0) => call(&socket, "proxy_dumpstate", Vec::new())?,
+        ("list-users", 0) => call(&socket, "proxy_listusers", Vec::new())?,
+        ("maintenance", 1) => {
+            let on = match args[0].as_str() {
+                "on" => true,
+                "off" => false,
+                _ => return Err(anyhow!(USAGE)),
+            };
+            call(&socket, "proxy_setreadonly", vec![on.into()])?
+        }
+        ("ban-peer", 1) | ("ban-peer", 2) => {
+            let seconds = match args.get(1) {
+                Some(seconds) => seconds
+                    .parse::<u64>()
+                    .map_err(|_| anyhow!("invalid number of seconds {}", seconds))?,
+                None => 86400,
+            };
+            let address = args.swap_remove(0);
+            call(
+                &socket,
+                "setban",
+                vec![address.into(), "add".into(), seconds.into()],
+            )?
+        }
+        ("reload", 0) => send(&socket, "reload")?,
+        ("call", n) if n > 0 => {
+            let method = args.remove(0);
+            call(&socket, &method, args.into_iter().map(param).collect())?
+        }
+        _ => return Err(anyhow!(USAGE)),
+    };
+    if !result.is_null() {
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    }
+    Ok(())
+}
@@ -0,0 +1,274 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Error};
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::hashes::{sha256d, Hash};
+use bitcoin::{Block, BlockHash};
+use memmap2::Mmap;
+
+use crate::cache::{CacheInfo, CacheStats, SizedCache};
+
+/// Marks the start of every record in a data file.
+const RECORD_MAGIC: &[u8; 4] = b"BPXB";
+/// Magic, block hash and length of the block.
+const RECORD_HEADER_LEN: u64 = 4 + 32 + 4;
+/// Size at which a new data file is started. Whole files are evicted at once.
+const MAX_FILE_LEN: u64 = 32 * 1024 * 1024;
+
+/// Blocks fetched from elsewhere than bitcoind.
+#[derive(Debug)]
+pub enum BlockCache {
+    Memory(SizedCache<BlockHash, Block>),
+    Disk(DiskBlockCache),
+}
+impl BlockCache {
+    pub fn get(&self, hash: &BlockHash) -> Result<Option<Block>, Error> {
+        match self {
+            BlockCache::Memory(cache) => Ok(cache.get(hash).map(|b| (*b).clone())),
+            BlockCache::Disk(cache) => cache.get(hash),
+        }
+    }
+    pub fn insert(&self, block: &Block) -> Result<(), Error> {
+        match self {
+            BlockCache::Memory(cache) => {
+                cache.insert(block.block_hash(), block.clone(), block.get_size());
+                Ok(())
+            }
+            BlockCache::Disk(cache) => cache.insert(block),
+        }
+    }
+    pub fn info(&self) -> &dyn CacheInfo {
+        match self {
+            BlockCache::Memory(cache) => cache,
+            BlockCache::Disk(cache) => cache,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct DataFile {
+    len: u64,
+    map: Option<Mmap>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Location {
+    file: u32,
+    offset: u64,
+    len: u32,
+}
+
+#[derive(Debug)]
+struct Inner {
+    files: BTreeMap<u32, DataFile>,
+    index: HashMap<BlockHash, Location>,
+}
+
+/// Blocks kept in append-only data files in a directory and read through memory maps, so that
+/// hundreds of them can be cached on devices with little RAM.
+///
+/// The index lives in memory only and is rebuilt by scanning the data files on startup, which
+/// also drops records left incomplete by a crash.
+#[derive(Debug)]
+pub struct DiskBlockCache {
+    dir: PathBuf,
+    /// Bytes the data files together may occupy
+    limit: u64,
+    inner: Mutex<Inner>,
+    stats: CacheStats,
+}
+impl DiskBlockCache {
+    fn file_path(dir: &Path, file: u32) -> PathBuf {
+        dir.join(format!("blocks-{:08}.dat", file))
+    }
+
+    pub fn open(dir: PathBuf, limit: u64) -> Result<Self, Error> {
+        std::fs::create_dir_all(&dir)?;
+        let mut inner = Inner {
+            files: BTreeMap::new(),
+            index: HashMap::new(),
+        };
+        for entry in std::fs::read_dir(&dir)? {
+            let name = entry?.file_name();
+            let file = match name
+                .to_str()
+                .and_then(|n| n.strip_prefix("blocks-"))
+                .and_then(|n| n.strip_suffix(".dat"))
+                .and_then(|n| n.parse().ok())
+            {
+                Some(file) => file,
+                None => continue,
+            };
+            let len = Self::scan(&Self::file_path(&dir, file), file, &mut inner.index)?;
+            inner.files.insert(file, DataFile { len, map: None });
+        }
+        Ok(DiskBlockCache {
+            dir,
+            limit,
+            inner: Mutex::new(inner),
+            stats: CacheStats::default(),
+        })
+    }
+
+    /// Indexes the valid records of a data file, truncating it after the last one.
+    fn scan(
+        path: &Path,
+        file: u32,
+        index: &mut HashMap<BlockHash, Location>,
+    ) -> Result<u64, Error> {
+        let data = std::fs::read(path)?;
+        let mut pos = 0;
+        while let Some(header) = data.get(pos..pos + RECORD_HEADER_LEN as usize) {
+            if &header[..4] != RECORD_MAGIC {
+                break;
+            }
+            let hash = BlockHash::from_slice(&header[4..36])?;
+            let mut len = [0; 4];
+            len.copy_from_slice(&header[36..40]);
+            let len = u32::from_le_bytes(len);
+            let start = pos + RECORD_HEADER_LEN as usize;
+            let block = match data.get(start..start + len as usize) {
+                Some(block) if block.len() >= 80 => block,
+                _ => break,
+            };
+            if sha256d::Hash::hash(&block[..80])[..] != hash[..] {
+                break;
+            }
+            index.insert(
+                hash,
+                Location {
+                    file,
+                    offset: start as u64,
+                    len,
+                },
+            );
+            pos = start + len as usize;
+        }
+        if pos < data.len() {
+            OpenOptions::new()
+                .write(true)
+                .open(path)?
+                .set_len(pos as u64)?;
+        }
+        Ok(pos as u64)
+    }
+
+    pub fn get(&self, hash: &BlockHash) -> Result<Option<Block>, Error> {
+        let mut inner = self.inner.lock().unwrap();
+        let loc = match inner.index.get(hash) {
+            Some(loc) => *loc,
+            None => {
+                self.stats.misses.fetch_add(1, Ordering::Relaxed);
+                return Ok(None);
+            }
+        };
+        let data_file = inner
+            .files
+            .get_mut(&loc.file)
+            .ok_or_else(|| anyhow!("block cache file {} missing", loc.file))?;
+        let end = (loc.offset + loc.len as u64) as usize;
+        if data_file.map.as_ref().is_none_or(|m| m.len() < end) {
+            let file = File::open(Self::file_path(&self.dir, loc.file))?;
+            // SAFETY: data files are only ever appended to by this process, so the mapped
+            // range never changes
+            data_file.map = Some(unsafe { Mmap::map(&file)? });
+        }
+        let map = data_file.map.as_ref().unwrap();
+        let data = map
+            .get(loc.offset as usize..end)
+            .ok_or_else(|| anyhow!("block cache file {} truncated", loc.file))?;
+        self.stats.hits.fetch_add(1, Ordering::Relaxed);
+        Ok(Some(deserialize(data)?))
+    }
+
+    pub fn insert(&self, block: &Block) -> Result<(), Error> {
+        let hash = block.block_hash();
+        let data = serialize(block);
+        let record_len = RECORD_HEADER_LEN + data.len() as u64;
+        let mut inner = self.inner.lock().unwrap();
+        if inner.index.contains_key(&hash) {
+            return Ok(());
+        }
+        let file = match inner.files.iter().next_back() {
+            Some((file, f)) if f.len + record_len <= MAX_FILE_LEN || f.len == 0 => *file,
+            Some((file, _)) => file + 1,
+            None => 0,
+        };
+        let mut out = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(Self::file_path(&self.dir, file))?;
+        let offset = out.seek(SeekFrom::End(0))?;
+        let mut record = Vec::with_capacity(record_len as usize);
+        record.extend_from_slice(RECORD_MAGIC);
+        record.extend_from_slice(&hash[..]);
+        record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        record.extend_from_slice(&data);
+        out.write_all(&record)?;
+        inner
+            .files
+            .entry(file)
+            .or_insert(DataFile { len: 0, map: None })
+            .len = offset + record_len;
+        inner.index.insert(
+            hash,
+            Location {
+                file,
+                offset: offset + RECORD_HEADER_LEN,
+                len: data.len() as u32,
+            },
+        );
+        self.evict(&mut inner)
+    }
+
+    /// Deletes the oldest data files until the rest fits into the limit.
+    fn evict(&self, inner: &mut Inner) -> Result<(), Error> {
+        while inner.files.len() > 1 && inner.files.values().map(|f| f.len).sum::<u64>() > self.limit
+        {
+            let oldest = *inner.files.keys().next().unwrap();
+            inner.files.remove(&oldest);
+            let before = inner.index.len();
+            inner.index.retain(|_, loc| loc.file != oldest);
+            self.stats
+                .evictions
+                .fetch_add((before - inner.index.len()) as u64, Ordering::Relaxed);
+            std::fs::remove_file(Self::file_path(&self.dir, oldest))?;
+        }
+        Ok(())
+    }
+}
+impl CacheInfo for DiskBlockCache {
+    fn name(&self) -> &'static str {
+        "blocks_disk"
+    }
+    fn stats(&self) -> &CacheStats {
+        &self.stats
+    }
+    fn bytes(&self) -> usize {
+        self.inner
+            .lock()
+            .unwrap()
+            .files
+            .values()
+            .map(|f| f.len as usize)
+            .sum()
+    }
+    fn entries(&self) -> usize {
+        self.inner.lock().unwrap().index.len()
+    }
+    fn clear(&self) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        for file in inner.files.keys() {
+            let _ = std::fs::remove_file(Self::file_path(&self.dir, *file));
+        }
+        inner.files.clear();
+        let cleared = inner.index.len();
+        inner.index.clear();
+        cleared
+    }
+}
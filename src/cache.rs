@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Memory shared by all caches of the proxy.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    /// Bytes all caches together may hold
+    pub limit: usize,
+    used: AtomicUsize,
+}
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Arc<Self> {
+        Arc::new(MemoryBudget {
+            limit,
+            used: AtomicUsize::new(0),
+        })
+    }
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::SeqCst)
+    }
+    pub(crate) fn try_reserve(&self, size: usize) -> bool {
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                Some(used + size).filter(|&total| total <= self.limit)
+            })
+            .is_ok()
+    }
+    pub(crate) fn release(&self, size: usize) {
+        self.used.fetch_sub(size, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CacheStats {
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+    pub evictions: AtomicU64,
+}
+
+/// What every cache reports for metrics.
+pub trait CacheInfo: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn stats(&self) -> &CacheStats;
+    /// Bytes currently held
+    fn bytes(&self) -> usize;
+    fn entries(&self) -> usize;
+    /// Drops all entries, returning how many there were.
+    fn clear(&self) -> usize;
+    /// Whether the entries become stale when the best block changes
+    fn depends_on_tip(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug)]
+struct Entry<V> {
+    value: Arc<V>,
+    size: usize,
+    last_used: u64,
+}
+
+#[derive(Debug)]
+struct Entries<K, V> {
+    map: HashMap<K, Entry<V>>,
+    bytes: usize,
+    tick: u64,
+}
+
+/// A least recently used cache accounting the size of its entries against a [`MemoryBudget`].
+///
+/// Eviction scans all entries, which is fine for the few hundred large entries (e.g. blocks)
+/// the proxy caches.
+#[derive(Debug)]
+pub struct SizedCache<K, V> {
+    name: &'static str,
+    budget: Arc<MemoryBudget>,
+    entries: Mutex<Entries<K, V>>,
+    stats: CacheStats,
+}
+impl<K: Eq + Hash + Clone, V> SizedCache<K, V> {
+    pub fn new(name: &'static str, budget: Arc<MemoryBudget>) -> Self {
+        SizedCache {
+            name,
+            budget,
+            entries: Mutex::new(Entries {
+                map: HashMap::new(),
+                bytes: 0,
+                tick: 0,
+            }),
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<Arc<V>> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.tick += 1;
+        let tick = entries.tick;
+        match entries.map.get_mut(key) {
+            Some(entry) => {
+                entry.last_used = tick;
+                self.stats.hits.fetch_add(1, Ordering::Relaxed);
+                Some(entry.value.clone())
+            }
+            None => {
+                self.stats.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Caches `value` of `size` bytes, evicting the least recently used entries of this cache to
+    /// make room for it. Nothing is cached if the budget is used up by other caches.
+    pub fn insert(&self, key: K, value: V, size: usize) {
+        if size > self.budget.limit {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(old) = entries.map.remove(&key) {
+            entries.bytes -= old.size;
+            self.budget.release(old.size);
+        }
+        while !self.budget.try_reserve(size) {
+            let lru = match entries.map.iter().min_by_key(|(_, e)| e.last_used) {
+                Some((k, _)) => k.clone(),
+                None => return,
+            };
+            if let Some(evicted) = entries.map.remove(&lru) {
+                entries.bytes -= evicted.size;
+                self.budget.release(evicted.size);
+                self.stats.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        entries.tick += 1;
+        let last_used = entries.tick;
+        entries.bytes += size;
+        entries.map.insert(
+            key,
+            Entry {
+                value: Arc::new(value),
+                size,
+                last_used,
+            },
+        );
+    }
+}
+impl<K: Send, V: Send + Sync> CacheInfo for SizedCache<K, V> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+    fn stats(&self) -> &CacheStats {
+        &self.stats
+    }
+    fn bytes(&self) -> usize {
+        self.entries.lock().unwrap().bytes
+    }
+    fn entries(&self) -> usize {
+        self.entries.lock().unwrap().map.len()
+    }
+    fn clear(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        self.budget.release(entries.bytes);
+        entries.bytes = 0;
+        let cleared = entries.map.len();
+        entries.map.clear();
+        cleared
+    }
+}
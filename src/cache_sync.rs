@@ -0,0 +1,214 @@
+//! Keeps the caches of several proxies in front of the same node consistent.
+//!
+//! Every proxy applies cache events locally and, if `cache_sync_redis` is configured, publishes
+//! them to the others, so that e.g. a new tip noticed by one proxy invalidates the caches of all
+//! of them within milliseconds.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+use bitcoin::BlockHash;
+use futures::future::{BoxFuture, FutureExt};
+use serde_json::Value;
+
+use crate::client::{GenericRpcMethod, RpcRequest, RpcResponse};
+use crate::intercept::{InterceptResult, Interceptor, RequestContext};
+use crate::redis::Redis;
+use crate::state::State;
+use crate::users::User;
+
+/// Redis channel the events are published on.
+pub const CHANNEL: &str = "btc-rpc-proxy:cache";
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CacheEvent {
+    /// The best block changed, so entries depending on it are stale
+    Tip { hash: BlockHash },
+    /// An operator invalidated a cache, all of them if `cache` is `None`
+    Invalidate { cache: Option<String> },
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct Message {
+    /// Instance that published the event
+    from: String,
+    event: CacheEvent,
+}
+
+#[derive(Debug)]
+pub struct CacheSync {
+    /// Identifies the messages of this instance
+    id: String,
+    redis: Option<Redis>,
+    /// How often to check for a new tip, if any cache or `on_tip` hook depends on it
+    pub tip_interval: Duration,
+    tip: Mutex<Option<BlockHash>>,
+}
+impl CacheSync {
+    pub fn new(redis: Option<Redis>, tip_interval: Duration) -> Self {
+        CacheSync {
+            id: hex::encode(rand::random::<[u8; 16]>()),
+            redis,
+            tip_interval,
+            tip: Mutex::new(None),
+        }
+    }
+
+    /// The best block when the tip was last checked.
+    pub fn tip(&self) -> Option<BlockHash> {
+        *self.tip.lock().unwrap()
+    }
+
+    pub fn is_shared(&self) -> bool {
+        self.redis.is_some()
+    }
+
+    /// Applies an event to the local caches, returning the number of invalidated entries.
+    pub fn apply(&self, state: &State, event: &CacheEvent) -> usize {
+        let caches = state.caches();
+        let cleared = match event {
+            CacheEvent::Tip { hash } => {
+                if self.tip.lock().unwrap().replace(*hash) == Some(*hash) {
+                    return 0;
+                }
+                state.hooks.tip(*hash);
+                caches
+                    .iter()
+                    .filter(|c| c.depends_on_tip())
+                    .map(|c| c.clear())
+                    .sum()
+            }
+            CacheEvent::Invalidate { cache } => caches
+                .iter()
+                .filter(|c| cache.as_ref().is_none_or(|name| c.name() == name))
+                .map(|c| c.clear())
+                .sum(),
+        };
+        debug!(
+            state.logger,
+            "Cache event {:?} invalidated {} entries", event, cleared
+        );
+        cleared
+    }
+
+    /// Applies an event locally and publishes it to the other proxies.
+    pub async fn publish(&self, state: &State, event: CacheEvent) -> usize {
+        let cleared = self.apply(state, &event);
+        if let Some(redis) = &self.redis {
+            let message = Message {
+                from: self.id.clone(),
+                event,
+            };
+            let res = match serde_json::to_vec(&message) {
+                Ok(message) => redis.publish(CHANNEL, &message).await,
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = res {
+                warn!(state.logger, "Failed to publish cache event: {:#}", e);
+            }
+        }
+        cleared
+    }
+
+    /// Applies the events published by other proxies.
+    async fn receive(&self, state: &State, redis: &Redis) -> Result<(), Error> {
+        let mut subscription = redis.subscribe(CHANNEL).await?;
+        loop {
+            let message: Message = serde_json::from_slice(&subscription.next_message().await?)?;
+            if message.from != self.id {
+                self.apply(state, &message.event);
+            }
+        }
+    }
+
+    async fn poll_tip(&self, state: &State) -> Result<(), Error> {
+        let hash = state
+            .rpc_client
+            .call(&RpcRequest {
+                id: None,
+                method: GenericRpcMethod("getbestblockhash".to_owned()),
+                params: Vec::new(),
+            })
+            .await?
+            .into_result()?;
+        let hash = hash
+            .as_str()
+            .ok_or_else(|| anyhow!("expected a block hash"))?
+            .parse()?;
+        if *self.tip.lock().unwrap() != Some(hash) {
+            self.publish(state, CacheEvent::Tip { hash }).await;
+        }
+        Ok(())
+    }
+
+    /// Watches the tip and events of other proxies, for as long as the proxy runs.
+    pub async fn run(state: Arc<State>) {
+        let sync = &state.cache_sync;
+        let receive = async {
+            let redis = match &sync.redis {
+                Some(redis) => redis,
+                None => return,
+            };
+            loop {
+                if let Err(e) = sync.receive(&state, redis).await {
+                    warn!(state.logger, "Cache event subscription failed: {:#}", e);
+                }
+                tokio::time::delay_for(Duration::from_secs(1)).await;
+            }
+        };
+        let poll = async {
+            let depends_on_tip =
+                state.hooks.wants_tip() || state.caches().iter().any(|c| c.depends_on_tip());
+            if sync.tip_interval.as_secs() == 0 || !depends_on_tip {
+                return;
+            }
+            let mut interval = tokio::time::interval(sync.tip_interval);
+            loop {
+                interval.tick().await;
+                if let Err(e) = sync.poll_tip(&state).await {
+                    debug!(state.logger, "Failed to check the tip: {:#}", e);
+                }
+            }
+        };
+        futures::future::join(receive, poll).await;
+    }
+}
+
+/// `proxy_invalidatecache [cache]`: drops the entries of a cache (all of them if not given) on
+/// every proxy, returning the number of entries dropped locally.
+pub struct InvalidateCache;
+impl Interceptor for InvalidateCache {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        _user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            let cache = req
+                .params
+                .first()
+                .and_then(Value::as_str)
+                .map(str::to_owned);
+            info!(
+                state.logger,
+                "{} invalidated cache {}",
+                ctx.caller(),
+                cache.as_deref().unwrap_or("*")
+            );
+            let cleared = state
+                .cache_sync
+                .publish(&state, CacheEvent::Invalidate { cache })
+                .await;
+            Ok(Some(RpcResponse {
+                id: req.id.clone(),
+                result: Some(cleared.into()),
+                error: None,
+            }))
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,173 @@
+//! What the backend supports, detected at startup, so that calls it can't serve are answered with
+//! a precise error instead of Core's sometimes vague one.
+
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use futures::future::{BoxFuture, FutureExt};
+use hyper::StatusCode;
+use serde_json::{json, Value};
+
+use crate::categories;
+use crate::client::{
+    GenericRpcMethod, RpcError, RpcRequest, METHOD_NOT_FOUND_ERROR_CODE, MISC_ERROR_CODE,
+};
+use crate::intercept::{InterceptResult, Interceptor, RequestContext};
+use crate::state::State;
+use crate::users::User;
+
+/// bitcoind's error code for unknown transactions, blocks and addresses.
+pub(crate) const INVALID_ADDRESS_OR_KEY_ERROR_CODE: i64 = -5;
+
+/// How often detection is retried while bitcoind can't be reached.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Indexes methods require, by the name `getindexinfo` reports them under. `gettxoutsetinfo`
+/// only requires its index to look up the set at a past block.
+const REQUIRED_INDEXES: &[(&str, &str, &str)] = &[
+    (
+        "getblockfilter",
+        "basic block filter index",
+        "-blockfilterindex",
+    ),
+    ("gettxoutsetinfo", "coinstatsindex", "-coinstatsindex"),
+];
+
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct Flags {
+    /// Indexes bitcoind maintains, unknown before 0.21
+    pub indexes: Option<HashSet<String>>,
+    /// Whether bitcoind was built and started with wallet support
+    pub wallet: bool,
+}
+impl Flags {
+    pub fn txindex(&self) -> Option<bool> {
+        self.indexes.as_ref().map(|i| i.contains("txindex"))
+    }
+}
+
+/// Capabilities of the backend, `None` until detected.
+#[derive(Debug, Default)]
+pub struct Capabilities(RwLock<Option<Flags>>);
+impl Capabilities {
+    pub fn get(&self) -> Option<Flags> {
+        self.0.read().unwrap().clone()
+    }
+
+    async fn detect(state: &State) -> Result<Flags, RpcError> {
+        let call = |method: &str| RpcRequest {
+            id: None,
+            method: GenericRpcMethod(method.to_owned()),
+            params: Vec::new(),
+        };
+        let rpc = &state.rpc_client;
+        let indexes = match rpc.call(&call("getindexinfo")).await?.into_result() {
+            Ok(Value::Object(indexes)) => Some(indexes.into_iter().map(|(k, _)| k).collect()),
+            Ok(_) => None,
+            Err(e) if e.code == METHOD_NOT_FOUND_ERROR_CODE => None,
+            Err(e) => return Err(e),
+        };
+        let wallet = match rpc.call(&call("listwallets")).await?.into_result() {
+            Ok(_) => true,
+            Err(e) if e.code == METHOD_NOT_FOUND_ERROR_CODE => false,
+            Err(e) => return Err(e),
+        };
+        Ok(Flags { indexes, wallet })
+    }
+
+    /// Detects the capabilities, retrying until bitcoind responds.
+    pub async fn run(state: Arc<State>) {
+        loop {
+            match Self::detect(&state).await {
+                Ok(flags) => {
+                    info!(
+                        state.logger,
+                        "bitcoind capabilities: txindex {}, wallet {}",
+                        flags
+                            .txindex()
+                            .map_or("unknown".to_owned(), |t| t.to_string()),
+                        flags.wallet
+                    );
+                    *state.capabilities.0.write().unwrap() = Some(flags);
+                    return;
+                }
+                Err(e) => {
+                    debug!(
+                        state.logger,
+                        "Failed to detect bitcoind capabilities: {}", e.message
+                    );
+                    tokio::time::delay_for(RETRY_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    /// Rejects calls bitcoind can't serve.
+    pub fn check(&self, req: &RpcRequest<GenericRpcMethod>) -> Result<(), RpcError> {
+        let flags = match self.get() {
+            Some(flags) => flags,
+            None => return Ok(()),
+        };
+        let method = &*req.method.0;
+        if !flags.wallet && categories::category(method) == Some("wallet") {
+            return Err(RpcError {
+                code: METHOD_NOT_FOUND_ERROR_CODE,
+                message: format!(
+                    "{} is a wallet method, but bitcoind runs without wallet support",
+                    method
+                ),
+                data: Some(json!({ "wallet": false })),
+                status: Some(StatusCode::NOT_FOUND),
+            });
+        }
+        let required = REQUIRED_INDEXES.iter().find(|(m, index, _)| {
+            *m == method
+                && (method != "gettxoutsetinfo" || req.params.get(1).is_some_and(|v| !v.is_null()))
+                && flags.indexes.as_ref().is_some_and(|i| !i.contains(*index))
+        });
+        if let Some((_, index, option)) = required {
+            return Err(RpcError {
+                code: MISC_ERROR_CODE,
+                message: format!("{} requires bitcoind to run with {}", method, option),
+                data: Some(json!({ "missing_index": index })),
+                status: None,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Serves `getrawtransaction` without a block hash when bitcoind has no txindex, which only
+/// finds transactions in the mempool, explaining why a transaction wasn't found.
+pub struct GetRawTransaction;
+impl Interceptor for GetRawTransaction {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        _user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        _ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            let txindex = state.capabilities.get().and_then(|f| f.txindex());
+            let block_hash = req.params.get(2).filter(|v| !v.is_null());
+            if txindex != Some(false) || block_hash.is_some() {
+                return Ok(None);
+            }
+            let mut response = state.rpc_client.call(req).await?;
+            if let Some(e) = response
+                .error
+                .as_mut()
+                .filter(|e| e.code == INVALID_ADDRESS_OR_KEY_ERROR_CODE)
+            {
+                e.message = "Transaction not in the mempool, and bitcoind has no txindex to \
+                             look it up: pass the hash of the block containing it"
+                    .to_owned();
+                e.data = Some(json!({ "txindex": false }));
+            }
+            Ok(Some(response))
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,288 @@
+//! Groupings of bitcoind's RPC methods used by proxy-wide policies.
+
+/// Methods that change the state of the node or of a wallet.
+pub const WRITE_METHODS: &[&str] = &[
+    // blockchain and mempool
+    "invalidateblock",
+    "reconsiderblock",
+    "preciousblock",
+    "pruneblockchain",
+    "savemempool",
+    "prioritisetransaction",
+    "sendrawtransaction",
+    "submitblock",
+    "submitheader",
+    "submitpackage",
+    // mining
+    "generateblock",
+    "generatetoaddress",
+    "generatetodescriptor",
+    // network
+    "addnode",
+    "clearbanned",
+    "disconnectnode",
+    "setban",
+    "setnetworkactive",
+    // control
+    "stop",
+    "setmocktime",
+    // wallet
+    "abandontransaction",
+    "abortrescan",
+    "backupwallet",
+    "bumpfee",
+    "createwallet",
+    "encryptwallet",
+    "getnewaddress",
+    "getrawchangeaddress",
+    "importaddress",
+    "importdescriptors",
+    "importmulti",
+    "importprivkey",
+    "importprunedfunds",
+    "importpubkey",
+    "importwallet",
+    "keypoolrefill",
+    "loadwallet",
+    "lockunspent",
+    "migratewallet",
+    "newkeypool",
+    "psbtbumpfee",
+    "removeprunedfunds",
+    "rescanblockchain",
+    "restorewallet",
+    "send",
+    "sendall",
+    "sendmany",
+    "sendtoaddress",
+    "sethdseed",
+    "setlabel",
+    "settxfee",
+    "setwalletflag",
+    "unloadwallet",
+    "upgradewallet",
+    "walletlock",
+    "walletpassphrase",
+    "walletpassphrasechange",
+];
+
+pub fn is_write(method: &str) -> bool {
+    WRITE_METHODS.contains(&method)
+}
+
+/// bitcoind's methods by the section of `help` listing them, for `allowed_calls` patterns such as
+/// `wallet/*`. Hidden methods are listed in the section of the source file defining them.
+pub const CATEGORIES: &[(&str, &[&str])] = &[
+    (
+        "blockchain",
+        &[
+            "dumptxoutset",
+            "getbestblockhash",
+            "getblock",
+            "getblockchaininfo",
+            "getblockcount",
+            "getblockfilter",
+            "getblockfrompeer",
+            "getblockhash",
+            "getblockheader",
+            "getblockstats",
+            "getchainstates",
+            "getchaintips",
+            "getchaintxstats",
+            "getdeploymentinfo",
+            "getdifficulty",
+            "getmempoolancestors",
+            "getmempooldescendants",
+            "getmempoolentry",
+            "getmempoolinfo",
+            "getrawmempool",
+            "gettxout",
+            "gettxoutproof",
+            "gettxoutsetinfo",
+            "gettxspendingprevout",
+            "importmempool",
+            "invalidateblock",
+            "loadtxoutset",
+            "preciousblock",
+            "pruneblockchain",
+            "reconsiderblock",
+            "savemempool",
+            "scanblocks",
+            "scantxoutset",
+            "verifychain",
+            "verifytxoutproof",
+            "waitforblock",
+            "waitforblockheight",
+            "waitfornewblock",
+        ],
+    ),
+    (
+        "control",
+        &[
+            "getmemoryinfo",
+            "getrpcinfo",
+            "help",
+            "logging",
+            "setmocktime",
+            "stop",
+            "uptime",
+        ],
+    ),
+    (
+        "mining",
+        &[
+            "generateblock",
+            "generatetoaddress",
+            "generatetodescriptor",
+            "getblocktemplate",
+            "getmininginfo",
+            "getnetworkhashps",
+            "getprioritisedtransactions",
+            "prioritisetransaction",
+            "submitblock",
+            "submitheader",
+        ],
+    ),
+    (
+        "network",
+        &[
+            "addnode",
+            "clearbanned",
+            "disconnectnode",
+            "getaddednodeinfo",
+            "getaddrmaninfo",
+            "getconnectioncount",
+            "getnettotals",
+            "getnetworkinfo",
+            "getnodeaddresses",
+            "getpeerinfo",
+            "listbanned",
+            "ping",
+            "setban",
+            "setnetworkactive",
+        ],
+    ),
+    (
+        "rawtransactions",
+        &[
+            "analyzepsbt",
+            "combinepsbt",
+            "combinerawtransaction",
+            "converttopsbt",
+            "createpsbt",
+            "createrawtransaction",
+            "decodepsbt",
+            "decoderawtransaction",
+            "decodescript",
+            "descriptorprocesspsbt",
+            "finalizepsbt",
+            "fundrawtransaction",
+            "getrawtransaction",
+            "joinpsbts",
+            "sendrawtransaction",
+            "signrawtransactionwithkey",
+            "submitpackage",
+            "testmempoolaccept",
+            "utxoupdatepsbt",
+        ],
+    ),
+    ("signer", &["enumeratesigners"]),
+    (
+        "util",
+        &[
+            "createmultisig",
+            "deriveaddresses",
+            "estimatesmartfee",
+            "getdescriptorinfo",
+            "getindexinfo",
+            "signmessagewithprivkey",
+            "validateaddress",
+            "verifymessage",
+        ],
+    ),
+    (
+        "wallet",
+        &[
+            "abandontransaction",
+            "abortrescan",
+            "addmultisigaddress",
+            "backupwallet",
+            "bumpfee",
+            "createwallet",
+            "createwalletdescriptor",
+            "dumpprivkey",
+            "dumpwallet",
+            "encryptwallet",
+            "getaddressesbylabel",
+            "getaddressinfo",
+            "getbalance",
+            "getbalances",
+            "gethdkeys",
+            "getnewaddress",
+            "getrawchangeaddress",
+            "getreceivedbyaddress",
+            "getreceivedbylabel",
+            "gettransaction",
+            "getunconfirmedbalance",
+            "getwalletinfo",
+            "importaddress",
+            "importdescriptors",
+            "importmulti",
+            "importprivkey",
+            "importprunedfunds",
+            "importpubkey",
+            "importwallet",
+            "keypoolrefill",
+            "listaddressgroupings",
+            "listdescriptors",
+            "listlabels",
+            "listlockunspent",
+            "listreceivedbyaddress",
+            "listreceivedbylabel",
+            "listsinceblock",
+            "listtransactions",
+            "listunspent",
+            "listwalletdir",
+            "listwallets",
+            "loadwallet",
+            "lockunspent",
+            "migratewallet",
+            "newkeypool",
+            "psbtbumpfee",
+            "removeprunedfunds",
+            "rescanblockchain",
+            "restorewallet",
+            "send",
+            "sendall",
+            "sendmany",
+            "sendtoaddress",
+            "sethdseed",
+            "setlabel",
+            "settxfee",
+            "setwalletflag",
+            "signmessage",
+            "signrawtransactionwithwallet",
+            "simulaterawtransaction",
+            "unloadwallet",
+            "upgradewallet",
+            "walletcreatefundedpsbt",
+            "walletdisplayaddress",
+            "walletlock",
+            "walletpassphrase",
+            "walletpassphrasechange",
+            "walletprocesspsbt",
+        ],
+    ),
+    ("zmq", &["getzmqnotifications"]),
+];
+
+/// The `help` section of a method, `proxy` for the methods served by the proxy itself.
+pub fn category(method: &str) -> Option<&'static str> {
+    if method.starts_with(crate::intercept::LOCAL_METHOD_PREFIX) {
+        return Some("proxy");
+    }
+    CATEGORIES
+        .iter()
+        .find(|(_, methods)| methods.contains(&method))
+        .map(|(category, _)| *category)
+}
@@ -0,0 +1,63 @@
+//! Fault injection for testing how clients cope with a misbehaving node or network.
+//!
+//! Only available with the `chaos` feature, which must never be enabled in production builds.
+
+use std::time::Duration;
+
+use anyhow::Error;
+use hyper::{body::Bytes, header::CONTENT_LENGTH, Body, Response};
+use tokio::stream::StreamExt;
+
+use crate::client::{RpcError, MISC_ERROR_CODE};
+
+/// Faults injected into requests, each rate being the probability of the fault happening.
+#[derive(Debug, Clone, Default)]
+pub struct Chaos {
+    /// Every request is delayed by up to this long
+    pub latency: Duration,
+    /// Connections closed without a response
+    pub drop_rate: f64,
+    /// Responses with their body cut in half
+    pub malformed_rate: f64,
+    /// Calls (including single calls of a batch) failing with an error
+    pub error_rate: f64,
+}
+impl Chaos {
+    fn roll(rate: f64) -> bool {
+        rate > 0.0 && rand::random::<f64>() < rate
+    }
+
+    pub async fn delay(&self) {
+        if self.latency > Duration::from_secs(0) {
+            tokio::time::delay_for(self.latency.mul_f64(rand::random())).await;
+        }
+    }
+
+    pub fn drop_connection(&self) -> bool {
+        Self::roll(self.drop_rate)
+    }
+
+    pub fn fail_call(&self) -> Option<RpcError> {
+        if Self::roll(self.error_rate) {
+            Some(RpcError {
+                code: MISC_ERROR_CODE,
+                message: "Injected fault".to_owned(),
+                data: None,
+                status: None,
+            })
+        } else {
+            None
+        }
+    }
+
+    pub async fn mangle(&self, response: Response<Body>) -> Result<Response<Body>, Error> {
+        if !Self::roll(self.malformed_rate) {
+            return Ok(response);
+        }
+        let (mut parts, body) = response.into_parts();
+        let body = body.collect::<Result<Bytes, _>>().await?;
+        let body = body.slice(..body.len() / 2);
+        parts.headers.insert(CONTENT_LENGTH, body.len().into());
+        Ok(Response::from_parts(parts, body.into()))
+    }
+}
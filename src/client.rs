@@ -1,13 +1,14 @@
 use std::future::Future;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 use anyhow::{anyhow, Context, Error};
-use futures::{channel::mpsc, StreamExt, TryStreamExt};
+use futures::{channel::mpsc, StreamExt};
 use hyper::{
     body::Bytes,
-    client::{Client, HttpConnector},
+    client::Client,
     header::{HeaderValue, AUTHORIZATION, CONTENT_LENGTH},
     Body, Method, Request, Response, StatusCode, Uri,
 };
@@ -16,16 +17,49 @@ use serde::{
     de::{Deserialize, Deserializer},
     ser::{Serialize, Serializer},
 };
+use serde_json::value::{to_raw_value, RawValue};
 use serde_json::Value;
+use slog::Logger;
 use tokio::sync::RwLock;
 
+use crate::headers::ResponseHeaders;
+use crate::upstream::Connector;
+
 pub const MISC_ERROR_CODE: i64 = -1;
+pub const METHOD_NOT_FOUND_ERROR_CODE: i64 = -32601;
 pub const METHOD_NOT_ALLOWED_ERROR_CODE: i64 = -32604;
+pub const ACCESS_DENIED_ERROR_CODE: i64 = -32605;
+pub const APPROVAL_REQUIRED_ERROR_CODE: i64 = -32606;
+pub const IDEMPOTENCY_CONFLICT_ERROR_CODE: i64 = -32607;
+pub const POLICY_VIOLATION_ERROR_CODE: i64 = -32608;
+pub const QUOTA_EXCEEDED_ERROR_CODE: i64 = -32609;
 pub const PARSE_ERROR_CODE: i64 = -32700;
+pub const METHOD_NOT_FOUND_ERROR_MESSAGE: &str = "Method not found";
 pub const METHOD_NOT_ALLOWED_ERROR_MESSAGE: &'static str = "Method not allowed";
 pub const PRUNE_ERROR_MESSAGE: &'static str = "Block not available (pruned data)";
+pub const READ_ONLY_ERROR_MESSAGE: &str = "Proxy is in read-only mode";
 
-type HttpClient = Client<HttpConnector>;
+type HttpClient = Client<Connector>;
+
+/// How connections to bitcoind are kept for reuse. Every request, whether made by `call` or
+/// forwarded by `send`, takes an idle connection from the same pool if there is one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pool {
+    /// How many idle connections are kept
+    pub max_idle: usize,
+    /// How long a connection may stay idle before it is closed, `None` to keep it until bitcoind
+    /// closes it
+    pub idle_timeout: Option<Duration>,
+}
+impl Default for Pool {
+    /// The defaults of hyper.
+    fn default() -> Self {
+        Pool {
+            max_idle: usize::MAX,
+            idle_timeout: Some(Duration::from_secs(90)),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum SingleOrBatchRpcRequest {
@@ -120,10 +154,13 @@ pub struct RpcRequest<T: RpcMethod> {
     pub params: T::Params,
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct RpcError {
     pub code: i64,
     pub message: String,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
     #[serde(skip)]
     pub status: Option<StatusCode>,
 }
@@ -132,6 +169,7 @@ impl From<Error> for RpcError {
         RpcError {
             code: MISC_ERROR_CODE,
             message: format!("{}", e),
+            data: None,
             status: None,
         }
     }
@@ -141,6 +179,7 @@ impl From<serde_json::Error> for RpcError {
         RpcError {
             code: PARSE_ERROR_CODE,
             message: format!("{}", e),
+            data: None,
             status: None,
         }
     }
@@ -195,13 +234,66 @@ pub struct RpcClient {
     authorization: AuthSource,
     uri: Uri,
     client: HttpClient,
+    logger: Logger,
+    /// Requests retried after reading a changed cookie because bitcoind rejected the old one
+    pub cookie_reloads: AtomicU64,
 }
 impl RpcClient {
-    pub fn new(auth: AuthSource, uri: Uri) -> Self {
+    pub fn new(auth: AuthSource, uri: Uri, connector: Connector, logger: Logger) -> Self {
+        Self::with_pool(auth, uri, connector, Pool::default(), logger)
+    }
+    pub fn with_pool(
+        auth: AuthSource,
+        uri: Uri,
+        connector: Connector,
+        pool: Pool,
+        logger: Logger,
+    ) -> Self {
         RpcClient {
             authorization: auth, // DO NOT try to eager evaluate this, it can change while the program is running
             uri,
-            client: HttpClient::new(),
+            client: Client::builder()
+                .pool_max_idle_per_host(pool.max_idle)
+                .pool_idle_timeout(pool.idle_timeout)
+                .build(connector),
+            logger,
+            cookie_reloads: AtomicU64::new(0),
+        }
+    }
+    /// Posts `body` to `path` of bitcoind. If bitcoind rejects the cookie, e.g. because it
+    /// restarted and wrote a new one since the cookie file was read, the cookie is read again and
+    /// the request retried once with it.
+    async fn post(&self, path: &str, body: String) -> Result<Response<Body>, Error> {
+        let mut parts = self.uri.clone().into_parts();
+        // under the base path bitcoind is mounted at, if any
+        let base = self.uri.path().trim_end_matches('/');
+        parts.path_and_query = Some(format!("{}{}", base, path).parse()?);
+        let uri = Uri::from_parts(parts)?;
+        let request = |authorization: HeaderValue, body: String| {
+            Request::builder()
+                .method(Method::POST)
+                .header(AUTHORIZATION, authorization)
+                .uri(uri.clone())
+                .body(body.into())
+        };
+        let authorization = self.authorization.try_load().await?;
+        let response = self
+            .client
+            .request(request(authorization.clone(), body.clone())?)
+            .await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+        match self.authorization.reload(&authorization).await? {
+            Some(reloaded) => {
+                self.cookie_reloads.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    self.logger,
+                    "bitcoind rejected the cookie, retrying with the cookie read again"
+                );
+                Ok(self.client.request(request(reloaded, body)?).await?)
+            }
+            None => Ok(response),
         }
     }
     pub async fn send<
@@ -212,6 +304,7 @@ impl RpcClient {
         &self,
         path: &'a str,
         req: &'a SingleOrBatchRpcRequest,
+        headers: &ResponseHeaders,
         intercept: F,
     ) -> Result<Response<Body>, Error> {
         match req {
@@ -224,17 +317,9 @@ impl RpcClient {
                     })
                     .into_response()?
                 } else {
-                    let mut parts = self.uri.clone().into_parts();
-                    parts.path_and_query = Some(path.parse()?);
-                    self.client
-                        .request(
-                            Request::builder()
-                                .method(Method::POST)
-                                .header(AUTHORIZATION, self.authorization.try_load().await?)
-                                .uri(Uri::from_parts(parts)?)
-                                .body(serde_json::to_string(req)?.into())?,
-                        )
-                        .await?
+                    let mut response = self.post(path, serde_json::to_string(req)?).await?;
+                    headers.filter(&mut response);
+                    response
                 })
             }
             SingleOrBatchRpcRequest::Batch(reqs) => {
@@ -247,51 +332,67 @@ impl RpcClient {
                         let forwarded_send = forwarded_send.clone();
                         async move {
                             match intercept_fn(path, req).await.transpose() {
-                                Some(res) => intercepted_send
-                                    .unbounded_send(res.map(|res| (idx, res)))
-                                    .unwrap(),
+                                // an error only fails its own call, others of the batch may have
+                                // executed already and their results must reach the client
+                                Some(res) => {
+                                    let res = res.unwrap_or_else(|e| RpcResponse {
+                                        id: req.id.clone(),
+                                        result: None,
+                                        error: Some(e),
+                                    });
+                                    intercepted_send.unbounded_send((idx, res)).unwrap()
+                                }
                                 None => forwarded_send.unbounded_send((idx, req)).unwrap(),
                             }
                         }
                     })
                     .await;
+                // forwarded responses are passed through as bitcoind wrote them, without parsing
+                // what may be megabytes of results
                 async fn send_batch(
                     client: &RpcClient,
                     path: &str,
-                    forwarded_recv: mpsc::UnboundedReceiver<(usize, &RpcRequest<GenericRpcMethod>)>,
-                ) -> Result<Vec<(usize, RpcResponse<GenericRpcMethod>)>, RpcError> {
-                    let (idxs, new_batch): (Vec<usize>, Vec<_>) =
-                        forwarded_recv.collect::<Vec<_>>().await.into_iter().unzip();
-                    let mut parts = client.uri.clone().into_parts();
-                    parts.path_and_query = Some(path.parse().map_err(Error::from)?);
+                    forwarded: &[(usize, &RpcRequest<GenericRpcMethod>)],
+                ) -> Result<Vec<(usize, Box<RawValue>)>, RpcError> {
+                    if forwarded.is_empty() {
+                        return Ok(Vec::new());
+                    }
+                    let new_batch: Vec<_> = forwarded.iter().map(|(_, req)| req).collect();
                     let response = client
-                        .client
-                        .request(
-                            Request::builder()
-                                .method(Method::POST)
-                                .header(AUTHORIZATION, client.authorization.try_load().await?)
-                                .uri(Uri::from_parts(parts).map_err(Error::from)?)
-                                .body(serde_json::to_string(&new_batch)?.into())
-                                .map_err(Error::from)?,
-                        )
-                        .await
-                        .map_err(Error::from)?;
+                        .post(path, serde_json::to_string(&new_batch)?)
+                        .await?;
                     let body: Bytes =
                         tokio::stream::StreamExt::collect::<Result<Bytes, _>>(response.into_body())
                             .await
                             .map_err(Error::from)?;
-                    let forwarded_res: Vec<RpcResponse<GenericRpcMethod>> =
-                        serde_json::from_slice(body.as_ref())?;
-                    Ok(idxs.into_iter().zip(forwarded_res).collect())
+                    let forwarded_res: Vec<Box<RawValue>> = serde_json::from_slice(body.as_ref())?;
+                    Ok(forwarded
+                        .iter()
+                        .map(|(idx, _)| *idx)
+                        .zip(forwarded_res)
+                        .collect())
                 }
-                let (forwarded, intercepted) = match futures::try_join!(
-                    send_batch(self, path, forwarded_recv),
-                    intercepted_recv.try_collect::<Vec<_>>()
-                ) {
-                    Ok(a) => a,
-                    Err(e) => return Ok(RpcResponse::from(e).into_response()?),
+                let forwarded_reqs = forwarded_recv.collect::<Vec<_>>().await;
+                let intercepted = intercepted_recv.collect::<Vec<_>>().await;
+                let forwarded = match send_batch(self, path, &forwarded_reqs).await {
+                    Ok(forwarded) => forwarded,
+                    Err(e) => forwarded_reqs
+                        .iter()
+                        .map(|(idx, req)| {
+                            let res = RpcResponse::<GenericRpcMethod> {
+                                id: req.id.clone(),
+                                result: None,
+                                error: Some(e.clone()),
+                            };
+                            Ok((*idx, to_raw_value(&res)?))
+                        })
+                        .collect::<Result<Vec<_>, serde_json::Error>>()?,
                 };
-                let res_vec: Vec<RpcResponse<GenericRpcMethod>> = forwarded
+                let intercepted = intercepted
+                    .into_iter()
+                    .map(|(idx, res)| Ok((idx, to_raw_value(&res)?)))
+                    .collect::<Result<Vec<_>, serde_json::Error>>()?;
+                let res_vec: Vec<Box<RawValue>> = forwarded
                     .into_iter()
                     .merge_by(intercepted, |(a, _), (b, _)| a < b)
                     .map(|(_, res)| res)
@@ -307,16 +408,15 @@ impl RpcClient {
         &self,
         req: &RpcRequest<T>,
     ) -> Result<RpcResponse<T>, Error> {
-        let response = self
-            .client
-            .request(
-                Request::builder()
-                    .method(Method::POST)
-                    .header(AUTHORIZATION, self.authorization.try_load().await?)
-                    .uri(&self.uri)
-                    .body(serde_json::to_string(req)?.into())?,
-            )
-            .await?;
+        self.call_at("/", req).await
+    }
+    /// Like `call`, but for endpoints other than the root, e.g. `/wallet/<name>`.
+    pub async fn call_at<T: RpcMethod + Serialize>(
+        &self,
+        path: &str,
+        req: &RpcRequest<T>,
+    ) -> Result<RpcResponse<T>, Error> {
+        let response = self.post(path, serde_json::to_string(req)?).await?;
         let status = response.status();
         let body: Bytes =
             tokio::stream::StreamExt::collect::<Result<Bytes, _>>(response.into_body()).await?;
@@ -331,6 +431,32 @@ impl RpcClient {
         }
         Ok(rpc_response)
     }
+    /// Sends `reqs` in a single batch, returning the responses in the same order.
+    pub async fn call_batch(
+        &self,
+        reqs: &[RpcRequest<GenericRpcMethod>],
+    ) -> Result<Vec<RpcResponse<GenericRpcMethod>>, Error> {
+        if reqs.is_empty() {
+            return Ok(Vec::new());
+        }
+        let response = self.post("/", serde_json::to_string(reqs)?).await?;
+        let status = response.status();
+        let body: Bytes =
+            tokio::stream::StreamExt::collect::<Result<Bytes, _>>(response.into_body()).await?;
+        let responses: Vec<RpcResponse<GenericRpcMethod>> = serde_json::from_slice(&body)
+            .with_context(|| match std::str::from_utf8(&body) {
+                Ok(s) => format!("Response: {}: {}", status, s),
+                Err(e) => format!("Response: {}: Could not parse body: {}", status, e),
+            })?;
+        if responses.len() != reqs.len() {
+            return Err(anyhow!(
+                "expected {} responses to a batch, got {}",
+                reqs.len(),
+                responses.len()
+            ));
+        }
+        Ok(responses)
+    }
 }
 
 #[derive(Debug)]
@@ -340,19 +466,45 @@ pub enum AuthSource {
         password: String,
         header: HeaderValue,
     },
+    /// Cookie files tried in order, the one that worked last being cached with its version
     CookieFile {
-        path: PathBuf,
-        cached: RwLock<Option<Arc<(SystemTime, HeaderValue)>>>,
+        paths: Vec<PathBuf>,
+        cached: RwLock<Option<Arc<(PathBuf, FileVersion, HeaderValue)>>>,
     },
 }
 
+/// What tells a cookie file bitcoind wrote again from the one read before. The mtime alone
+/// doesn't if the clock was stepped back in between, but bitcoind writes a new file, with a new
+/// inode, that it renames over the old one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileVersion {
+    modified: SystemTime,
+    len: u64,
+    #[cfg(unix)]
+    inode: u64,
+}
+impl FileVersion {
+    pub fn of(metadata: &std::fs::Metadata) -> std::io::Result<Self> {
+        #[cfg(unix)]
+        use std::os::unix::fs::MetadataExt;
+
+        Ok(FileVersion {
+            modified: metadata.modified()?,
+            len: metadata.len(),
+            #[cfg(unix)]
+            inode: metadata.ino(),
+        })
+    }
+}
+
 impl AuthSource {
     pub fn from_config(
         user: Option<String>,
         password: Option<String>,
-        file: Option<PathBuf>,
+        files: Vec<PathBuf>,
     ) -> Result<Self, Error> {
-        match (user, password, file) {
+        let files = Some(files).filter(|files| !files.is_empty());
+        match (user, password, files) {
             (Some(username), Some(password), None) => Ok(AuthSource::Const {
                 header: format!(
                     "Basic {}",
@@ -362,8 +514,8 @@ impl AuthSource {
                 username,
                 password,
             }),
-            (None, None, Some(cookie_file)) => Ok(AuthSource::CookieFile {
-                path: cookie_file,
+            (None, None, Some(cookie_files)) => Ok(AuthSource::CookieFile {
+                paths: cookie_files,
                 cached: RwLock::new(None),
             }),
             // It could pull it from bitcoin.conf, but I don't think it's worth my time.
@@ -384,25 +536,55 @@ impl AuthSource {
         })?)
     }
 
+    /// Reads the cookie again after bitcoind rejected `rejected`, returning it if it changed.
+    pub async fn reload(&self, rejected: &HeaderValue) -> Result<Option<HeaderValue>, Error> {
+        match self {
+            AuthSource::Const { .. } => Ok(None),
+            AuthSource::CookieFile { ref cached, .. } => {
+                *cached.write().await = None;
+                let header = self.try_load().await?;
+                Ok(Some(header).filter(|h| h != rejected))
+            }
+        }
+    }
+
     pub async fn try_load(&self) -> Result<HeaderValue, Error> {
         match self {
             AuthSource::Const { ref header, .. } => Ok(header.clone()),
             AuthSource::CookieFile {
-                ref path,
+                ref paths,
                 ref cached,
             } => {
-                let cache = cached.read().await.clone();
-                let modified = tokio::fs::metadata(&path).await?.modified()?;
-                match cache {
-                    Some(cache) if modified == cache.0 => Ok(cache.1.clone()),
-                    _ => {
+                if let Some(cache) = cached.read().await.clone() {
+                    let version = tokio::fs::metadata(&cache.0)
+                        .await
+                        .and_then(|m| FileVersion::of(&m));
+                    if version.ok() == Some(cache.1) {
+                        return Ok(cache.2.clone());
+                    }
+                }
+                let mut errors = Vec::new();
+                for path in paths {
+                    let loaded = async {
+                        let version = FileVersion::of(&tokio::fs::metadata(path).await?)?;
                         let header: HeaderValue =
                             format!("Basic {}", AuthSource::load_from_file(path).await?).parse()?;
-                        let new_cache = (modified, header.clone());
-                        *cached.write().await = Some(Arc::new(new_cache));
-                        Ok(header)
+                        Ok::<_, Error>((version, header))
+                    }
+                    .await;
+                    match loaded {
+                        Ok((version, header)) => {
+                            let new_cache = (path.clone(), version, header.clone());
+                            *cached.write().await = Some(Arc::new(new_cache));
+                            return Ok(header);
+                        }
+                        Err(e) => errors.push(format!("{}: {}", path.display(), e)),
                     }
                 }
+                Err(anyhow!(
+                    "failed to load a cookie file: {}",
+                    errors.join(", ")
+                ))
             }
         }
     }
@@ -0,0 +1,25 @@
+//! Time as the proxy sees it.
+//!
+//! Lifetimes, TTLs and rate windows are measured with the monotonic clock (`Instant`), which
+//! doesn't move when the system clock is stepped, e.g. by NTP on a device that booted with a
+//! wrong clock. Only what is calendar time by nature uses the wall clock of [`now`]: expiry dates
+//! and allowed hours of credentials, quota periods, block times and timestamps shown to people.
+//! Logic based on it copes with the clock jumping, but can't tell a jump from time passing.
+
+use std::sync::atomic::{AtomicI64, Ordering};
+
+use chrono::{DateTime, Duration, Utc};
+
+/// How far tests stepped the wall clock, in milliseconds.
+static STEPPED: AtomicI64 = AtomicI64::new(0);
+
+/// The wall clock.
+pub fn now() -> DateTime<Utc> {
+    Utc::now() + Duration::milliseconds(STEPPED.load(Ordering::Relaxed))
+}
+
+/// Steps the wall clock seen by the proxy by `by`, like NTP would the system clock. For tests.
+#[doc(hidden)]
+pub fn step(by: Duration) {
+    STEPPED.fetch_add(by.num_milliseconds(), Ordering::Relaxed);
+}
@@ -0,0 +1,61 @@
+use hyper::{
+    body::HttpBody,
+    header::{CONTENT_TYPE, EXPECT},
+    http::request::Parts,
+    Body, Method, StatusCode,
+};
+
+/// Toggles for accepting requests from client libraries that don't quite follow the spec.
+#[derive(Debug)]
+pub struct Compat {
+    /// Accept bodies sent as `Content-Type: text/plain` (python-bitcoinrpc, curl defaults)
+    pub text_plain: bool,
+    /// Accept requests without a `Content-Type` header (bitcoin-cli)
+    pub missing_content_type: bool,
+    /// Accept JSON-RPC sent as a GET request with a body
+    pub get_with_body: bool,
+    /// Answer `Expect: 100-continue` instead of rejecting the request
+    pub expect_continue: bool,
+}
+impl Default for Compat {
+    fn default() -> Self {
+        Compat {
+            text_plain: true,
+            missing_content_type: true,
+            get_with_body: false,
+            expect_continue: true,
+        }
+    }
+}
+impl Compat {
+    pub fn accepts_method(&self, parts: &Parts, body: &Body) -> bool {
+        parts.method == Method::POST
+            || (self.get_with_body && parts.method == Method::GET && !body.is_end_stream())
+    }
+
+    /// Returns the status to reject the request with, if any.
+    pub fn check(&self, parts: &Parts) -> Option<StatusCode> {
+        if let Some(expect) = parts.headers.get(EXPECT) {
+            if !self.expect_continue || !expect.as_bytes().eq_ignore_ascii_case(b"100-continue") {
+                return Some(StatusCode::EXPECTATION_FAILED);
+            }
+        }
+        match parts.headers.get(CONTENT_TYPE) {
+            None if !self.missing_content_type => Some(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+            Some(ct) if !self.text_plain && is_text_plain(ct.as_bytes()) => {
+                Some(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn is_text_plain(content_type: &[u8]) -> bool {
+    let mime = content_type
+        .split(|b| *b == b';')
+        .next()
+        .unwrap_or_default();
+    std::str::from_utf8(mime)
+        .map(|m| m.trim().eq_ignore_ascii_case("text/plain"))
+        .unwrap_or(false)
+}
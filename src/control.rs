@@ -0,0 +1,189 @@
+//! The control socket, used by `btc-rpc-proxy-ctl` and by new processes taking over from the
+//! running one (see `handoff`). Only the owner of the proxy may use it, so it needs no
+//! credentials: calls made through it have the permissions of an admin.
+//!
+//! Each connection sends a line with a command and gets a line with its JSON-RPC response:
+//!
+//! * `takeover` - hands the listening sockets over to the new process sending it
+//! * `reload` - starts a new process with the same arguments, which takes over, so that the
+//!   config is reloaded without downtime
+//! * a JSON-RPC request - calls a method of the proxy or of bitcoind
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, Error};
+use hyper::header::HeaderMap;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+
+use crate::client::{RpcError, RpcResponse, SingleOrBatchRpcRequest};
+use crate::handoff::{self, TIMEOUT};
+use crate::headers::ResponseHeaders;
+use crate::intercept::RequestContext;
+use crate::listen::Listener;
+use crate::state::State;
+use crate::users::User;
+
+/// Name calls through the control socket are logged with.
+const CONTROL_USER: &str = "control";
+
+type Connection = BufReader<UnixStream>;
+
+/// Writes the response to `result` as a line.
+async fn answer(mut conn: Connection, result: Result<Value, Error>) -> Result<(), Error> {
+    let response = match result {
+        Ok(result) => RpcResponse {
+            id: None,
+            result: Some(result),
+            error: None,
+        },
+        Err(e) => RpcResponse::from(RpcError::from(e)),
+    };
+    let mut line = serde_json::to_vec(&response)?;
+    line.push(b'\n');
+    conn.get_mut().write_all(&line).await?;
+    Ok(())
+}
+
+/// Makes the calls of `line` with the permissions of an admin.
+async fn call(state: Arc<State>, line: &str) -> Result<Value, Error> {
+    let req: SingleOrBatchRpcRequest = serde_json::from_str(line)?;
+    let user = User::from_value(json!({ "role": "admin", "manage_users": true, "approve": true }))?;
+    let ctx = RequestContext::new(CONTROL_USER.to_owned(), &HeaderMap::new());
+    let response = state
+        .rpc_client
+        // only the body is answered, so the headers of bitcoind don't matter
+        .send("/", &req, &ResponseHeaders::default(), |path, req| {
+            user.intercept(state.clone(), path, req, &ctx)
+        })
+        .await?;
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// Answers a JSON-RPC request with the response of the proxy or bitcoind.
+async fn respond(state: Arc<State>, mut conn: Connection, line: String) {
+    let result = match call(state.clone(), &line).await {
+        Ok(response) => {
+            let mut line = response.to_string();
+            line.push('\n');
+            conn.get_mut()
+                .write_all(line.as_bytes())
+                .await
+                .map_err(Error::from)
+        }
+        Err(e) => answer(conn, Err(e)).await,
+    };
+    if let Err(e) = result {
+        debug!(
+            state.logger,
+            "Failed to answer on the control socket: {:#}", e
+        );
+    }
+}
+
+/// Starts a new process with the arguments of this one. The binary is looked up again, so that
+/// an upgraded one is started.
+fn spawn() -> Result<tokio::process::Child, Error> {
+    let mut args = std::env::args_os();
+    let program = args
+        .next()
+        .ok_or_else(|| anyhow!("the path of the binary is unknown"))?;
+    tokio::process::Command::new(&program)
+        .args(args)
+        .spawn()
+        .map_err(|e| anyhow!("failed to start {}: {}", program.to_string_lossy(), e))
+}
+
+enum Next {
+    Accepted(UnixStream),
+    /// The process started by `reload` exited before taking over
+    Exited(std::io::Result<std::process::ExitStatus>),
+}
+
+/// Serves the control socket until `listeners` are handed over to a new process, which triggers
+/// `state.shutdown`.
+pub async fn serve(state: Arc<State>, listeners: Vec<Listener>) -> Result<(), Error> {
+    let path = match &state.control_socket {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    // left behind by a process that didn't hand over, or by the one that just did
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(anyhow!("failed to remove {}: {}", path.display(), e))
+        }
+        _ => (),
+    }
+    let mut control = tokio::net::UnixListener::bind(path)
+        .map_err(|e| anyhow!("failed to bind {}: {}", path.display(), e))?;
+    std::fs::set_permissions(path, std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    // the connection of `reload`, answered once the process it started took over
+    let mut reload: Option<(Connection, tokio::process::Child)> = None;
+    loop {
+        let next = match &mut reload {
+            Some((_, child)) => tokio::select! {
+                accepted = control.accept() => Next::Accepted(accepted?.0),
+                status = child => Next::Exited(status),
+            },
+            None => Next::Accepted(control.accept().await?.0),
+        };
+        let stream = match next {
+            Next::Accepted(stream) => stream,
+            Next::Exited(status) => {
+                if let Some((conn, _)) = reload.take() {
+                    let e = match status {
+                        Ok(status) => anyhow!("the new process exited with {}", status),
+                        Err(e) => anyhow!("failed to wait for the new process: {}", e),
+                    };
+                    warn!(state.logger, "Failed to reload: {:#}", e);
+                    let _ = answer(conn, Err(e)).await;
+                }
+                continue;
+            }
+        };
+        let mut conn = BufReader::new(stream);
+        let mut line = String::new();
+        match tokio::time::timeout(TIMEOUT, conn.read_line(&mut line)).await {
+            Ok(Ok(_)) => (),
+            _ => continue,
+        }
+        match line.trim() {
+            "takeover" => match handoff::hand_over(&mut conn, &listeners).await {
+                Ok(()) => {
+                    state.shutdown.trigger();
+                    info!(
+                        state.logger,
+                        "Handed the listening sockets over to a new process, finishing in-flight requests"
+                    );
+                    let _ = conn.get_mut().write_all(b"draining\n").await;
+                    if let Some((conn, child)) = reload {
+                        let _ = answer(conn, Ok(json!({ "pid": child.id() }))).await;
+                    }
+                    return Ok(());
+                }
+                Err(e) => warn!(state.logger, "Failed to hand over: {:#}", e),
+            },
+            "reload" if reload.is_some() => {
+                let _ = answer(conn, Err(anyhow!("already reloading"))).await;
+            }
+            "reload" => match spawn() {
+                Ok(child) => {
+                    info!(
+                        state.logger,
+                        "Reloading: started process {} to take over",
+                        child.id()
+                    );
+                    reload = Some((conn, child));
+                }
+                Err(e) => {
+                    let _ = answer(conn, Err(e)).await;
+                }
+            },
+            _ => {
+                tokio::spawn(respond(state.clone(), conn, line));
+            }
+        }
+    }
+}
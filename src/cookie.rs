@@ -0,0 +1,50 @@
+//! A cookie file like bitcoind's `.cookie`, with credentials generated on every start, for
+//! applications that authenticate with a cookie file instead of a configured password.
+
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Error};
+
+use crate::users::{User, CREDENTIAL_FIELDS};
+
+/// User name of the cookie credentials, the same as bitcoind's.
+pub const COOKIE_USER: &str = "__cookie__";
+
+#[derive(Debug)]
+pub struct Cookie {
+    pub path: PathBuf,
+    password: String,
+}
+impl Cookie {
+    pub fn new(path: PathBuf) -> Self {
+        Cookie {
+            path,
+            password: hex::encode(rand::random::<[u8; 32]>()),
+        }
+    }
+
+    /// A user with the permissions of `profile` and the password of the cookie.
+    pub fn user(&self, profile: &User) -> Result<User, Error> {
+        let mut value = serde_json::to_value(profile)?;
+        let fields = value.as_object_mut().unwrap();
+        for field in CREDENTIAL_FIELDS {
+            fields.remove(*field);
+        }
+        fields.insert("password".to_owned(), self.password.clone().into());
+        User::from_value(value)
+    }
+
+    /// Writes the file, readable by the owner only.
+    pub fn write(&self) -> Result<(), Error> {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options
+            .open(&self.path)
+            .map_err(|e| anyhow!("failed to write {}: {}", self.path.display(), e))?;
+        write!(file, "{}:{}", COOKIE_USER, self.password)?;
+        Ok(())
+    }
+}
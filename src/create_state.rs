@@ -1,55 +1,863 @@
+use std::ffi::OsString;
+use std::net::SocketAddr;
 use std::sync::Arc;
+#[cfg(feature = "peer-fetch")]
+use std::sync::Mutex;
 use std::time::Duration;
 
-use anyhow::Error;
-use btc_rpc_proxy::{AuthSource, Peers, RpcClient, State, TorState, Users};
+use anyhow::{anyhow, Error};
+use bitcoin::Amount;
+#[cfg(feature = "peer-fetch")]
+use btc_rpc_proxy::address_book::AddressBook;
+use btc_rpc_proxy::approvals::Approvals;
+use btc_rpc_proxy::audit::Audit;
+use btc_rpc_proxy::auth::{AuthBackend, HttpAuth};
+use btc_rpc_proxy::balances::Balances;
+#[cfg(feature = "peer-fetch")]
+use btc_rpc_proxy::block_cache::{BlockCache, DiskBlockCache};
+use btc_rpc_proxy::cache::MemoryBudget;
+#[cfg(feature = "peer-fetch")]
+use btc_rpc_proxy::cache::SizedCache;
+use btc_rpc_proxy::cache_sync::CacheSync;
+#[cfg(feature = "chaos")]
+use btc_rpc_proxy::chaos::Chaos;
+use btc_rpc_proxy::client::Pool;
+use btc_rpc_proxy::compat::Compat;
+use btc_rpc_proxy::cookie::{Cookie, COOKIE_USER};
+use btc_rpc_proxy::data_dir::{self, DataDir};
+use btc_rpc_proxy::deprecation::Deprecations;
+#[cfg(feature = "peer-fetch")]
+use btc_rpc_proxy::fetch_blocks::{Esplora, FetchPolicy};
+use btc_rpc_proxy::guardrails::{Action, Guardrails};
+use btc_rpc_proxy::headers::ResponseHeaders;
+#[cfg(feature = "tls")]
+use btc_rpc_proxy::http_client;
+use btc_rpc_proxy::idempotency::Idempotency;
+use btc_rpc_proxy::journal::Journal;
+use btc_rpc_proxy::jwt::JwtAuth;
+use btc_rpc_proxy::listen::{Address, Bind, Listen};
+use btc_rpc_proxy::lockout::Lockout;
+use btc_rpc_proxy::notify::{Notifier, SmtpConfig};
+use btc_rpc_proxy::oidc::Oidc;
+use btc_rpc_proxy::onion::Onion;
+#[cfg(feature = "peer-fetch")]
+use btc_rpc_proxy::p2p::Tracer;
+use btc_rpc_proxy::paths;
+use btc_rpc_proxy::priority::Load;
+use btc_rpc_proxy::quota::QuotaUsage;
+use btc_rpc_proxy::rate_limit::{Algorithm, RateLimit, RateLimiter};
+use btc_rpc_proxy::redis::Redis;
+use btc_rpc_proxy::resolver::Resolver;
+use btc_rpc_proxy::reuse::AddressReuse;
+use btc_rpc_proxy::secrets;
+use btc_rpc_proxy::signing::Signer;
+use btc_rpc_proxy::stats::Stats;
+use btc_rpc_proxy::storage::{self, Files, Storage};
+use btc_rpc_proxy::subscriptions::Subscriptions;
+#[cfg(feature = "peer-fetch")]
+use btc_rpc_proxy::timeouts::PeerTimeouts;
+#[cfg(feature = "tls")]
+use btc_rpc_proxy::tls::Tls;
+use btc_rpc_proxy::tunnel::Proxy;
+use btc_rpc_proxy::tx_status::TxStatusCache;
+#[cfg(feature = "cache")]
+use btc_rpc_proxy::txout_cache::TxOutCache;
+use btc_rpc_proxy::upstream::Connector;
+#[cfg(feature = "tor")]
+use btc_rpc_proxy::TorState;
+use btc_rpc_proxy::{AuthSource, RpcClient, State, Users};
 use slog::Drain;
-use tokio::sync::RwLock;
 
 #[allow(dead_code)]
 #[allow(unused_mut)]
 #[allow(unused_variables)]
+#[allow(unused_imports)]
+#[allow(clippy::all)]
 mod config {
     include!(concat!(env!("OUT_DIR"), "/configure_me_config.rs"));
 }
 use self::config::{Config, ResultExt};
 
-pub fn create_state() -> Result<State, Error> {
-    let (config, _) =
+/// Selects the profile if `--use-profile` isn't given.
+const PROFILE_ENV: &str = "BTC_RPC_PROXY_PROFILE";
+
+/// Index in `args` right after the last config file or directory. Settings of a profile are
+/// loaded there so that they override the config files but not the arguments following them.
+fn profile_position(args: &[OsString]) -> usize {
+    let mut position = args.len().min(1);
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].to_str() {
+            Some("--") => break,
+            Some("--conf") | Some("--conf-dir") => {
+                i += 2;
+                position = i.min(args.len());
+                continue;
+            }
+            Some(arg) if arg.starts_with("--conf=") || arg.starts_with("--conf-dir=") => {
+                position = i + 1
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+    position
+}
+
+fn write_private(path: &std::path::Path, contents: &[u8]) -> Result<(), Error> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents)?;
+    Ok(())
+}
+
+/// The Esplora server at `url`, reached like the rest of the outside world: through the
+/// configured resolver and, as peers, through Tor with `tor_only` or for an onion service, or
+/// through the egress proxy of bitcoind otherwise.
+#[cfg(feature = "peer-fetch")]
+fn esplora(
+    url: &str,
+    resolver: Resolver,
+    tor_proxy: Option<SocketAddr>,
+    tor_only: bool,
+    egress: Option<Proxy>,
+) -> Result<Esplora, Error> {
+    let uri: hyper::Uri = url.parse()?;
+    if uri.scheme_str() != Some("http") {
+        anyhow::bail!("only http:// URLs are supported");
+    }
+    let host = uri
+        .host()
+        .ok_or_else(|| anyhow!("{} has no host", url))?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_owned();
+    let onion = host.trim_end_matches('.').ends_with(".onion");
+    let connector = Connector::host(host, uri.port_u16().unwrap_or(80), resolver);
+    let connector = match (tor_proxy, egress) {
+        (Some(tor), _) if tor_only || onion => connector.via(Proxy::socks5(tor)),
+        (None, _) if onion => anyhow::bail!("reaching an onion service requires tor_proxy"),
+        (_, Some(egress)) => connector.via(egress),
+        (_, None) => connector,
+    };
+    Ok(Esplora::new(&uri, connector))
+}
+
+/// Parses the configuration, applying the selected profile if any.
+///
+/// configure_me can only load settings from files, so the profile is written to a temporary
+/// file (it may contain passwords, hence private) and the arguments are parsed again with it.
+fn load_config() -> Result<(Config, Vec<OsString>), Error> {
+    let (config, args) =
         Config::including_optional_config_files(std::iter::empty::<&str>()).unwrap_or_exit();
+    let name = match config
+        .use_profile
+        .clone()
+        .or_else(|| std::env::var(PROFILE_ENV).ok())
+    {
+        Some(name) => name,
+        None => return Ok((config, args.collect())),
+    };
+    let mut profile = config
+        .profile
+        .get(&name)
+        .cloned()
+        .ok_or_else(|| anyhow!("profile {} is not defined in the config files", name))?;
+    profile.remove("profile");
+    profile.remove("use_profile");
+
+    let path = std::env::temp_dir().join(format!(
+        "btc_rpc_proxy-profile-{}-{:x}.toml",
+        std::process::id(),
+        rand::random::<u64>()
+    ));
+    write_private(&path, configure_me::toml::to_string(&profile)?.as_bytes())?;
+    let mut all_args: Vec<OsString> = std::env::args_os().collect();
+    let position = profile_position(&all_args);
+    all_args.splice(
+        position..position,
+        vec![OsString::from("--conf"), path.clone().into_os_string()],
+    );
+    let result = Config::custom_args_and_optional_files(all_args, std::iter::empty::<&str>());
+    std::fs::remove_file(&path)?;
+    let (config, args) = result.unwrap_or_exit();
+    Ok((config, args.collect()))
+}
+
+/// Builds the state from the configuration, returning it along with the remaining arguments.
+pub fn create_state() -> Result<(State, Vec<OsString>), Error> {
+    let (mut config, args) = load_config()?;
 
     let auth = AuthSource::from_config(
         config.bitcoind_user,
-        config.bitcoind_password,
-        config.cookie_file,
+        secrets::resolve(
+            "bitcoind_password",
+            config.bitcoind_password,
+            config.bitcoind_password_file.as_deref(),
+        )?,
+        config
+            .cookie_file
+            .into_iter()
+            .chain(config.cookie_files.into_iter().flatten())
+            .collect(),
     )?;
-    let bitcoin_uri = format!(
-        "http://{}:{}/",
-        config.bitcoind_address, config.bitcoind_port
-    )
+    let bitcoind_addr = SocketAddr::new(config.bitcoind_address, config.bitcoind_port);
+    #[cfg(not(feature = "tls"))]
+    if config.bitcoind_tls {
+        anyhow::bail!("bitcoind_tls requires the proxy to be built with the `tls` feature");
+    }
+    #[cfg(feature = "tls")]
+    let connector = if config.bitcoind_tls {
+        Connector::tls(
+            bitcoind_addr,
+            http_client::tls_config(config.bitcoind_ca_file.as_deref())
+                .map_err(|e| anyhow!("bitcoind_ca_file: {:#}", e))?,
+        )
+    } else {
+        Connector::plain(bitcoind_addr)
+    };
+    #[cfg(not(feature = "tls"))]
+    let connector = Connector::plain(bitcoind_addr);
+    let connector = match config.bitcoind_host.clone() {
+        Some(host) => connector.resolving(
+            host,
+            config.bitcoind_port,
+            Resolver::from_config(
+                &config.resolver,
+                config.tor_proxy,
+                config.resolver_address,
+                config.resolver_ca_file.clone(),
+            )?,
+        ),
+        None => connector,
+    };
+    let base_path = paths::base_path(config.bitcoind_base_path.as_deref().unwrap_or(""))
+        .map_err(|e| anyhow!("bitcoind_base_path: {}", e))?;
+    // the connector always connects to bitcoind_addr or bitcoind_host, the host of the URI only
+    // names the certificate
+    let tls_name = config
+        .bitcoind_tls_name
+        .as_ref()
+        .or(config.bitcoind_host.as_ref());
+    let bitcoin_uri = match (tls_name, config.bitcoind_tls, &config.bitcoind_host) {
+        (Some(name), true, _) => {
+            format!("https://{}:{}{}/", name, config.bitcoind_port, base_path)
+        }
+        (None, true, _) => anyhow::bail!("bitcoind_tls requires bitcoind_tls_name"),
+        (_, false, Some(host)) => {
+            format!("http://{}:{}{}/", host, config.bitcoind_port, base_path)
+        }
+        (_, false, None) => format!("http://{}{}/", bitcoind_addr, base_path),
+    }
     .parse()?;
-    let rpc_client = RpcClient::new(auth, bitcoin_uri);
+    let bitcoind_proxy = match (config.bitcoind_proxy.take(), config.bitcoind_tor) {
+        (Some(_), true) => anyhow::bail!("bitcoind_tor can't be combined with bitcoind_proxy"),
+        (Some(proxy), false) => Some(
+            proxy
+                .parse::<Proxy>()
+                .map_err(|e| anyhow!("bitcoind_proxy: {}", e))?,
+        ),
+        (None, true) => Some(Proxy::socks5(
+            config
+                .tor_proxy
+                .ok_or_else(|| anyhow!("bitcoind_tor requires tor_proxy"))?,
+        )),
+        (None, false) => None,
+    };
+    #[cfg(feature = "peer-fetch")]
+    let esplora = match &config.esplora_url {
+        Some(url) => Some(
+            esplora(
+                url,
+                Resolver::from_config(
+                    &config.resolver,
+                    config.tor_proxy,
+                    config.resolver_address,
+                    config.resolver_ca_file.clone(),
+                )?,
+                config.tor_proxy,
+                config.tor_only,
+                bitcoind_proxy.clone(),
+            )
+            .map_err(|e| anyhow!("esplora_url: {:#}", e))?,
+        ),
+        None => None,
+    };
+    if let (Some(host), None) = (&config.bitcoind_host, &bitcoind_proxy) {
+        if host.trim_end_matches('.').ends_with(".onion") {
+            anyhow::bail!("reaching bitcoind at an onion address requires bitcoind_tor");
+        }
+    }
+    let (connector, bitcoin_uri) = match config.bitcoind_socket {
+        Some(_) if config.bitcoind_tls => {
+            anyhow::bail!("bitcoind_tls can't be combined with bitcoind_socket")
+        }
+        Some(_) if bitcoind_proxy.is_some() => {
+            anyhow::bail!("bitcoind_proxy and bitcoind_tor can't be combined with bitcoind_socket")
+        }
+        Some(_) if config.bitcoind_host.is_some() => {
+            anyhow::bail!("bitcoind_host can't be combined with bitcoind_socket")
+        }
+        #[cfg(unix)]
+        Some(path) => (
+            Connector::unix(path),
+            format!("http://localhost{}/", base_path).parse()?,
+        ),
+        #[cfg(not(unix))]
+        Some(_) => anyhow::bail!("bitcoind_socket is only supported on unix"),
+        None => (connector, bitcoin_uri),
+    };
+    let connector = match bitcoind_proxy {
+        Some(proxy) => connector.via(proxy),
+        None => connector,
+    };
+    let connector = match config.bitcoind_keepalive {
+        0 => connector,
+        secs => connector.keepalive(Duration::from_secs(secs)),
+    };
+    let pool = Pool {
+        max_idle: config.bitcoind_max_idle_connections.unwrap_or(usize::MAX),
+        idle_timeout: Some(config.bitcoind_idle_timeout)
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs),
+    };
 
+    #[cfg(feature = "tor")]
     let tor_only = config.tor_only;
+    #[cfg(feature = "tor")]
     let tor = config.tor_proxy.map(|proxy| TorState {
         proxy,
         only: tor_only,
     });
 
+    let approvals = match config.approval_threshold {
+        Some(threshold) => Some(Approvals::new(
+            Amount::from_btc(threshold)?,
+            Duration::from_secs(config.approval_timeout),
+            config.tenant_quota,
+        )),
+        None => None,
+    };
+
+    let mut jwt = JwtAuth::new(
+        config.jwt_user_claim,
+        config.jwt_audience,
+        config.jwt_issuer,
+    );
+    if let Some(secret) = secrets::resolve(
+        "jwt_secret",
+        config.jwt_secret,
+        config.jwt_secret_file.as_deref(),
+    )? {
+        jwt.add_secret(secret.as_bytes());
+    }
+    if let Some(path) = &config.jwt_public_key {
+        jwt.add_public_key(path)?;
+    }
+    if let Some(path) = &config.jwt_jwks_file {
+        jwt.add_jwks(path)?;
+    }
+    let jwt = if jwt.is_empty() { None } else { Some(jwt) };
+
+    let oidc = match config.oidc_issuer {
+        Some(issuer) => Some(Oidc::new(
+            issuer,
+            config
+                .oidc_audience
+                .ok_or_else(|| anyhow!("oidc_issuer requires oidc_audience"))?,
+            config.oidc_user_claim,
+            config.oidc_scope_claim,
+            config.oidc_scope.unwrap_or_default(),
+            config.oidc_ca_file,
+            Duration::from_secs(config.oidc_jwks_refresh),
+        )?),
+        None => None,
+    };
+
+    let mut auth_backends: Vec<Box<dyn AuthBackend>> = Vec::new();
+    if let Some(url) = &config.auth_http_url {
+        auth_backends.push(Box::new(HttpAuth::new(
+            url.parse()?,
+            Duration::from_secs(config.auth_cache_ttl),
+        )));
+    }
+
+    let notify_email_from = config.notify_email_from;
+    let notify_email_to = config.notify_email_to;
+    let tenant_quota = config.tenant_quota;
+    let address_reuse = config
+        .address_reuse
+        .map(|mode| mode.parse())
+        .transpose()?
+        .map(|mode| AddressReuse::new(mode, tenant_quota));
+    let notifier = Notifier {
+        webhook: config.notify_webhook.map(|w| w.parse()).transpose()?,
+        smtp: config.notify_smtp_server.map(|server| SmtpConfig {
+            server,
+            from: notify_email_from,
+            to: notify_email_to,
+        }),
+        secret: secrets::resolve(
+            "notify_secret",
+            config.notify_secret,
+            config.notify_secret_file.as_deref(),
+        )?
+        .map(String::into_bytes)
+        .unwrap_or_else(|| rand::random::<[u8; 32]>().to_vec()),
+        link_template: config.notify_link,
+    };
+
     let decorator = slog_term::TermDecorator::new().build();
     let drain = slog_term::FullFormat::new(decorator).build().fuse();
     let drain = slog_async::Async::new(drain).build().fuse();
     let logger = slog::Logger::root(drain, slog::o!());
 
-    Ok(State {
-        bind: (config.bind_address, config.bind_port).into(),
-        rpc_client,
-        tor,
-        users: Users(config.user),
-        logger,
-        peer_timeout: Duration::from_secs(config.peer_timeout),
-        peers: RwLock::new(Arc::new(Peers::new())),
-        max_peer_age: Duration::from_secs(config.max_peer_age),
-        max_peer_concurrency: config.max_peer_concurrency,
-    })
+    let rpc_client = RpcClient::with_pool(auth, bitcoin_uri, connector, pool, logger.clone());
+
+    let data_dir = config
+        .data_dir
+        .take()
+        .map(|dir| DataDir::open(dir, &logger))
+        .transpose()?;
+    if let Some(data_dir) = &data_dir {
+        config
+            .users_file
+            .get_or_insert_with(|| data_dir.artifact("users_file"));
+        config
+            .journal_file
+            .get_or_insert_with(|| data_dir.artifact("journal_file"));
+        config
+            .address_book_file
+            .get_or_insert_with(|| data_dir.artifact("address_book_file"));
+        config
+            .block_cache_dir
+            .get_or_insert_with(|| data_dir.artifact("block_cache_dir"));
+        config
+            .tor_onion_key_file
+            .get_or_insert_with(|| data_dir.artifact("tor_onion_key_file"));
+    }
+    data_dir::check(
+        &logger,
+        config.users_file.as_deref(),
+        config.journal_file.as_deref(),
+        config.address_book_file.as_deref(),
+    )?;
+    // in files, the journal is only kept if it has one
+    let journal = config.journal_file.is_some() || config.storage.is_some();
+    let storage: Arc<dyn Storage> = match config.storage.as_deref() {
+        None => Arc::new(
+            Files::default()
+                .with(storage::USERS, config.users_file.take())
+                .with(storage::JOURNAL, config.journal_file.take())
+                .with(storage::PEERS, config.address_book_file.take()),
+        ),
+        Some(spec) => storage::open(spec)?,
+    };
+
+    let chaos_enabled = config.chaos_latency > 0
+        || config.chaos_drop_rate > 0.0
+        || config.chaos_malformed_rate > 0.0
+        || config.chaos_error_rate > 0.0;
+    #[cfg(not(feature = "chaos"))]
+    if chaos_enabled {
+        anyhow::bail!("chaos_* options require the proxy to be built with the `chaos` feature");
+    }
+    #[cfg(feature = "chaos")]
+    let chaos = if chaos_enabled {
+        let chaos = Chaos {
+            latency: Duration::from_millis(config.chaos_latency),
+            drop_rate: config.chaos_drop_rate,
+            malformed_rate: config.chaos_malformed_rate,
+            error_rate: config.chaos_error_rate,
+        };
+        slog::warn!(logger, "Injecting faults: {:?}", chaos);
+        Some(chaos)
+    } else {
+        None
+    };
+
+    let binds = match config.bind {
+        Some(binds) => {
+            if config.tls_cert.is_some()
+                || config.tls_key.is_some()
+                || config.tls_client_ca.is_some()
+            {
+                anyhow::bail!("with bind, tls_cert, tls_key and tls_client_ca are set per entry");
+            }
+            if binds.is_empty() {
+                anyhow::bail!("bind lists no addresses to listen on");
+            }
+            binds
+        }
+        None => vec![Bind {
+            address: Address::Tcp((config.bind_address, config.bind_port).into()),
+            tls_cert: config.tls_cert,
+            tls_key: config.tls_key,
+            tls_client_ca: config.tls_client_ca,
+            forward_response_headers: None,
+            response_header: Default::default(),
+        }],
+    };
+    let mut listen: Vec<Listen> = Vec::new();
+    for bind in binds {
+        if listen.iter().any(|l| l.address == bind.address) {
+            anyhow::bail!("{} is listed twice in bind", bind.address);
+        }
+        #[cfg(unix)]
+        if bind.tls_cert.is_some() && matches!(bind.address, Address::Unix(_)) {
+            anyhow::bail!(
+                "{} is a Unix socket, which isn't served over TLS",
+                bind.address
+            );
+        }
+        #[cfg(not(feature = "tls"))]
+        if bind.tls_cert.is_some() || bind.tls_client_ca.is_some() {
+            anyhow::bail!("tls_* options require the proxy to be built with the `tls` feature");
+        }
+        #[cfg(feature = "tls")]
+        let tls = match (&bind.tls_cert, &bind.tls_key) {
+            (Some(cert), Some(key)) => Some(Arc::new(Tls::new(
+                cert,
+                key,
+                bind.tls_client_ca.as_deref(),
+                config.http2,
+            )?)),
+            (None, None) if bind.tls_client_ca.is_none() => None,
+            _ => anyhow::bail!(
+                "tls_cert and tls_key are required to serve {} over TLS",
+                bind.address
+            ),
+        };
+        let headers = if bind.has_headers() {
+            let mut inject = config.response_header.clone();
+            inject.extend(bind.response_header);
+            let forward = match bind.forward_response_headers {
+                Some(forward) => Some(forward),
+                None => config.forward_response_headers.clone(),
+            };
+            match ResponseHeaders::new(forward, inject) {
+                Ok(headers) => Some(Arc::new(headers)),
+                Err(e) => anyhow::bail!("response headers of {}: {}", bind.address, e),
+            }
+        } else {
+            None
+        };
+        listen.push(Listen {
+            address: bind.address,
+            #[cfg(feature = "tls")]
+            tls,
+            headers,
+        });
+    }
+    let onion = if config.tor_onion {
+        let control = config
+            .tor_control
+            .as_deref()
+            .ok_or_else(|| anyhow!("tor_onion requires tor_control"))?
+            .parse::<Address>()
+            .map_err(|e| anyhow!("tor_control: {}", e))?;
+        let port = config.tor_onion_port.unwrap_or(match listen[0].address {
+            Address::Tcp(addr) => addr.port(),
+            #[cfg(unix)]
+            Address::Unix(_) => 8331,
+        });
+        Some(Onion::new(
+            control,
+            config.tor_control_password,
+            port,
+            config.tor_onion_key_file,
+        )?)
+    } else {
+        None
+    };
+
+    let memory_budget = MemoryBudget::new(config.cache_memory_budget);
+    let tx_statuses = TxStatusCache::new(memory_budget.clone());
+    #[cfg(not(feature = "cache"))]
+    if config.txout_cache {
+        anyhow::bail!("txout_cache requires the proxy to be built with the `cache` feature");
+    }
+    #[cfg(not(feature = "metrics"))]
+    if config.serve_metrics {
+        anyhow::bail!("serve_metrics requires the proxy to be built with the `metrics` feature");
+    }
+    #[cfg(not(feature = "grpc"))]
+    if config.grpc {
+        anyhow::bail!("grpc requires the proxy to be built with the `grpc` feature");
+    }
+    #[cfg(feature = "cache")]
+    let txout_cache = if config.txout_cache {
+        Some(TxOutCache::new(memory_budget.clone()))
+    } else {
+        None
+    };
+    #[cfg(feature = "peer-fetch")]
+    let block_cache = match config.block_cache_dir {
+        Some(dir) => BlockCache::Disk(DiskBlockCache::open(dir, config.block_cache_size)?),
+        None => BlockCache::Memory(SizedCache::new("blocks", memory_budget.clone())),
+    };
+
+    let load = if config.load_check_interval > 0 {
+        Some(Load::new(
+            Duration::from_secs(config.load_check_interval),
+            Duration::from_secs(config.load_max_tip_age),
+            Duration::from_millis(config.load_max_latency),
+            config.batch_trickle,
+        ))
+    } else {
+        None
+    };
+    let balance_poll_interval = Duration::from_secs(config.balance_poll_interval.max(1));
+    let balances = config
+        .balance_wallets
+        .filter(|wallets| !wallets.is_empty())
+        .map(|wallets| Balances::new(wallets, balance_poll_interval));
+    let subscriptions = if config.subscriptions {
+        let mut subscriptions = Subscriptions::new(
+            config.subscription_queue,
+            config.subscription_overflow.parse()?,
+            Duration::from_secs(config.subscription_poll_interval.max(1)),
+        );
+        subscriptions.compression = config.subscription_compression;
+        Some(subscriptions)
+    } else {
+        None
+    };
+    let stats = if config.public_stats {
+        Some(Stats::new(
+            RateLimit {
+                algorithm: Algorithm::TokenBucket,
+                requests: config.stats_requests_per_minute.max(1),
+                period: 60.0,
+                burst: None,
+                methods: Default::default(),
+            },
+            Duration::from_secs(config.stats_cache),
+        ))
+    } else {
+        None
+    };
+
+    #[cfg(not(feature = "peer-fetch"))]
+    if config.p2p_trace || config.p2p_trace_file.is_some() {
+        anyhow::bail!(
+            "p2p_trace options require the proxy to be built with the `peer-fetch` feature"
+        );
+    }
+    #[cfg(feature = "peer-fetch")]
+    let p2p_tracer = if config.p2p_trace || config.p2p_trace_file.is_some() {
+        let file = config
+            .p2p_trace_file
+            .map(|path| {
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .map_err(|e| anyhow!("failed to open {}: {}", path.display(), e))
+            })
+            .transpose()?;
+        Some(Arc::new(Tracer {
+            logger: if config.p2p_trace {
+                Some(logger.clone())
+            } else {
+                None
+            },
+            file: file.map(Mutex::new),
+            max_payload: config.p2p_trace_payload,
+        }))
+    } else {
+        None
+    };
+
+    let cookie = match (config.proxy_cookie_file, &config.proxy_cookie_user) {
+        (Some(path), Some(profile)) => {
+            let cookie = Cookie::new(path);
+            let profile = config.user.get(profile).ok_or_else(|| {
+                anyhow!("proxy_cookie_user {} is not a user of the config", profile)
+            })?;
+            let user = cookie.user(profile)?;
+            config.user.insert(COOKIE_USER.to_owned(), user);
+            Some(cookie)
+        }
+        (None, None) => None,
+        _ => anyhow::bail!("proxy_cookie_file and proxy_cookie_user have to be set together"),
+    };
+
+    for (name, user) in &mut config.user {
+        user.resolve_password()
+            .and_then(|_| user.check_credentials())
+            .map_err(|e| anyhow!("credentials of user {}: {}", name, e))?;
+    }
+
+    let users = Users::open(config.user, config.group, storage.clone())?;
+    if let Some(name) = &config.anonymous_user {
+        users
+            .by_name(name)
+            .ok_or_else(|| anyhow!("anonymous_user {} is not a user of the config", name))?
+            .check_anonymous()
+            .map_err(|e| anyhow!("anonymous_user {}: {}", name, e))?;
+    }
+    let anonymous_rate = config.anonymous_max_requests_per_second;
+    if anonymous_rate.is_nan() || anonymous_rate <= 0.0 {
+        anyhow::bail!("anonymous_max_requests_per_second has to be positive");
+    }
+
+    #[cfg(feature = "peer-fetch")]
+    let network = {
+        let network = &config.network;
+        network
+            .parse()
+            .map_err(|_| anyhow!("invalid network {}", network))?
+    };
+
+    let builder = State::builder(rpc_client, logger)
+        .listen(listen)
+        .onion(onion)
+        .control_socket(config.control_socket)
+        .http2(config.http2)
+        .http2_max_streams(config.http2_max_streams)
+        .response_headers(ResponseHeaders::new(
+            config.forward_response_headers,
+            config.response_header,
+        )?)
+        .deprecations(Deprecations::new(config.deprecated_method))
+        .guardrails(Guardrails {
+            unconfirmed_inputs: config
+                .unconfirmed_inputs_policy
+                .map(|a| a.parse())
+                .transpose()?
+                .unwrap_or(Action::Allow),
+            subtract_fee: config
+                .subtract_fee_policy
+                .map(|a| a.parse())
+                .transpose()?
+                .unwrap_or(Action::Allow),
+            fee_rate: config
+                .fee_rate_policy
+                .map(|a| a.parse())
+                .transpose()?
+                .unwrap_or(Action::Deny),
+            min_fee_rate: config.min_fee_rate,
+            max_fee_rate: config.max_fee_rate,
+            replaceable: config
+                .replaceable_policy
+                .map(|a| a.parse())
+                .transpose()?
+                .unwrap_or(Action::Allow),
+            bump_max_fee: config.bumpstuck_max_fee,
+        })
+        .compat(Compat {
+            text_plain: config.compat_text_plain,
+            missing_content_type: config.compat_missing_content_type,
+            get_with_body: config.compat_get_with_body,
+            expect_continue: config.compat_expect_continue,
+        })
+        .users(users)
+        .anonymous_user(config.anonymous_user)
+        .anonymous_rate_limit(RateLimit::per_second(anonymous_rate, None))
+        .cookie(cookie)
+        .auth_backends(auth_backends)
+        .jwt(jwt)
+        .oidc(oidc)
+        .rate_limiter(match &config.rate_limit_redis {
+            Some(url) => RateLimiter::redis(url)?,
+            None => RateLimiter::memory(),
+        })
+        .jsonrpc2_strict(config.jsonrpc2_strict)
+        .quota_usage(QuotaUsage::open(storage.clone(), storage::QUOTA_USAGE)?)
+        .load(load)
+        .read_only(config.read_only)
+        .validate_responses(
+            config
+                .validate_responses
+                .map(|m| m.parse())
+                .transpose()?
+                .unwrap_or_default(),
+        )
+        .approvals(approvals)
+        .notifier(notifier)
+        .journal(journal.then(|| Journal::open(storage.clone())))
+        .response_signer(
+            config
+                .response_signing_key
+                .as_deref()
+                .map(Signer::from_file)
+                .transpose()?,
+        )
+        .data_dir(data_dir)
+        .idempotency(Idempotency::new(
+            Duration::from_secs(config.idempotency_window),
+            config.tenant_quota,
+        ))
+        .address_reuse(address_reuse)
+        .memory_budget(memory_budget)
+        .tx_statuses(tx_statuses)
+        .balances(balances)
+        .subscriptions(subscriptions)
+        .stats(stats)
+        .cache_sync(CacheSync::new(
+            config
+                .cache_sync_redis
+                .as_deref()
+                .map(Redis::new)
+                .transpose()?,
+            Duration::from_secs(config.tip_poll_interval),
+        ))
+        .lockout(if config.lockout_failures > 0 {
+            Some(Lockout::new(
+                config.lockout_failures,
+                Duration::from_secs(config.lockout_window),
+                Duration::from_secs(config.lockout_duration),
+            ))
+        } else {
+            None
+        })
+        .audit(config.audit_sink.as_deref().map(Audit::open).transpose()?);
+    #[cfg(feature = "tor")]
+    let builder = builder.tor(tor);
+    #[cfg(feature = "metrics")]
+    let builder = builder.serve_metrics(config.serve_metrics);
+    #[cfg(feature = "cache")]
+    let builder = builder.txout_cache(txout_cache);
+    #[cfg(feature = "grpc")]
+    let builder = builder.grpc(config.grpc);
+    #[cfg(feature = "peer-fetch")]
+    let builder = builder.fetch_usage(QuotaUsage::open(storage.clone(), storage::FETCH_USAGE)?);
+    #[cfg(feature = "chaos")]
+    let builder = builder.chaos(chaos);
+    #[cfg(feature = "peer-fetch")]
+    let builder = builder.network(network);
+    #[cfg(feature = "peer-fetch")]
+    let builder = builder.peer_timeout(Duration::from_secs(config.peer_timeout));
+    #[cfg(feature = "peer-fetch")]
+    let builder = builder.peer_timeouts(PeerTimeouts::new(
+        config.peer_timeout,
+        config.peer_timeouts,
+    )?);
+    #[cfg(feature = "peer-fetch")]
+    let builder = builder.max_peer_age(Duration::from_secs(config.max_peer_age));
+    #[cfg(feature = "peer-fetch")]
+    let builder = builder.warm_peers(config.warm_peers);
+    #[cfg(feature = "peer-fetch")]
+    let builder =
+        builder.peer_keepalive_interval(Duration::from_secs(config.peer_keepalive_interval));
+    #[cfg(feature = "peer-fetch")]
+    let builder = builder.p2p_tracer(p2p_tracer);
+    #[cfg(feature = "peer-fetch")]
+    let builder = builder.max_peer_concurrency(config.max_peer_concurrency);
+    #[cfg(feature = "peer-fetch")]
+    let builder = builder.fetch_budget(config.fetch_budget.map(Duration::from_secs));
+    #[cfg(feature = "peer-fetch")]
+    let builder = builder.fetch_policy(FetchPolicy::new(config.fetch_order, esplora)?);
+    #[cfg(feature = "peer-fetch")]
+    let builder = builder.block_cache(block_cache);
+    #[cfg(feature = "peer-fetch")]
+    let builder = builder.address_book(AddressBook::open(storage.clone())?);
+    let state = builder.build();
+
+    Ok((state, args))
 }
@@ -0,0 +1,177 @@
+//! The data directory, holding the files the proxy keeps across restarts unless they are put
+//! elsewhere with their own settings.
+//!
+//! The directory records the version of its layout, so that proxies can migrate directories
+//! written by older versions and refuse those written by newer ones.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Error};
+use serde_json::Value;
+use slog::Logger;
+
+/// Version of the layout written by this proxy.
+pub const VERSION: u32 = 1;
+
+const VERSION_FILE: &str = "VERSION";
+
+/// Files and directories kept in the data directory, by the setting putting them elsewhere.
+pub const ARTIFACTS: &[(&str, &str)] = &[
+    ("users_file", "users.json"),
+    ("journal_file", "journal.jsonl"),
+    ("address_book_file", "peers.json"),
+    ("block_cache_dir", "blocks"),
+    ("tor_onion_key_file", "onion_key"),
+];
+
+#[derive(Debug)]
+pub struct DataDir {
+    pub path: PathBuf,
+}
+impl DataDir {
+    /// Opens the directory, creating it readable by the owner only if needed and migrating it
+    /// to the current layout.
+    pub fn open(path: PathBuf, logger: &Logger) -> Result<Self, Error> {
+        if !path.exists() {
+            let mut builder = std::fs::DirBuilder::new();
+            builder.recursive(true);
+            #[cfg(unix)]
+            std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+            builder
+                .create(&path)
+                .map_err(|e| anyhow!("failed to create {}: {}", path.display(), e))?;
+        } else if !path.is_dir() {
+            return Err(anyhow!("data_dir {} is not a directory", path.display()));
+        }
+        let data_dir = DataDir { path };
+        let version = data_dir.version()?;
+        if version > VERSION {
+            return Err(anyhow!(
+                "data_dir {} has layout version {}, this proxy only knows up to {}",
+                data_dir.path.display(),
+                version,
+                VERSION
+            ));
+        }
+        if version < VERSION {
+            data_dir.migrate(version, logger)?;
+        }
+        Ok(data_dir)
+    }
+
+    fn version(&self) -> Result<u32, Error> {
+        let path = self.path.join(VERSION_FILE);
+        match std::fs::read_to_string(&path) {
+            Ok(version) => version
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("invalid layout version in {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(anyhow!("failed to read {}: {}", path.display(), e)),
+        }
+    }
+
+    /// Brings a directory written by an older version up to date, one version at a time.
+    fn migrate(&self, from: u32, logger: &Logger) -> Result<(), Error> {
+        for version in from..VERSION {
+            match version {
+                // unversioned directories only ever held the artifacts under their current names
+                0 => (),
+                _ => unreachable!(),
+            }
+            info!(
+                logger,
+                "Migrated data_dir {} to layout version {}",
+                self.path.display(),
+                version + 1
+            );
+        }
+        std::fs::write(self.path.join(VERSION_FILE), format!("{}\n", VERSION))
+            .map_err(|e| anyhow!("failed to write to data_dir {}: {}", self.path.display(), e))
+    }
+
+    /// Where the artifact of `setting` goes when the setting isn't set.
+    pub fn artifact(&self, setting: &str) -> PathBuf {
+        let (_, name) = ARTIFACTS
+            .iter()
+            .find(|(s, _)| *s == setting)
+            .expect("unknown artifact");
+        self.path.join(name)
+    }
+}
+
+fn read_json(path: &Path) -> Result<Option<Value>, Error> {
+    match std::fs::read(path) {
+        Ok(data) => {
+            Ok(Some(serde_json::from_slice(&data).map_err(|e| {
+                anyhow!("{} is corrupt: {}", path.display(), e)
+            })?))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(anyhow!("failed to read {}: {}", path.display(), e)),
+    }
+}
+
+/// Checks the artifacts before they are loaded, cutting off the last line of the journal if it
+/// was only partially written (e.g. because the proxy was killed while appending it).
+pub fn check(
+    logger: &Logger,
+    users_file: Option<&Path>,
+    journal_file: Option<&Path>,
+    address_book_file: Option<&Path>,
+) -> Result<(), Error> {
+    if let Some(path) = users_file {
+        if let Some(users) = read_json(path)? {
+            if !users.is_object() {
+                return Err(anyhow!(
+                    "{} is corrupt: expected an object of users",
+                    path.display()
+                ));
+            }
+        }
+    }
+    if let Some(path) = address_book_file {
+        if let Some(peers) = read_json(path)? {
+            if !peers.is_array() {
+                return Err(anyhow!(
+                    "{} is corrupt: expected an array of peers",
+                    path.display()
+                ));
+            }
+        }
+    }
+    if let Some(path) = journal_file {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(anyhow!("failed to read {}: {}", path.display(), e)),
+        };
+        let complete = data.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+        for (i, line) in data[..complete].split(|b| *b == b'\n').enumerate() {
+            if !line.iter().all(u8::is_ascii_whitespace) {
+                serde_json::from_slice::<Value>(line).map_err(|e| {
+                    anyhow!("{} is corrupt at line {}: {}", path.display(), i + 1, e)
+                })?;
+            }
+        }
+        if complete < data.len() && serde_json::from_slice::<Value>(&data[complete..]).is_ok() {
+            warn!(logger, "Terminating the last line of {}", path.display());
+            std::fs::OpenOptions::new()
+                .append(true)
+                .open(path)?
+                .write_all(b"\n")?;
+        } else if complete < data.len() {
+            warn!(
+                logger,
+                "Cutting off the partially written last line of {}",
+                path.display()
+            );
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(path)?
+                .set_len(complete as u64)?;
+        }
+    }
+    Ok(())
+}
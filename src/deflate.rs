@@ -0,0 +1,365 @@
+//! Raw DEFLATE (RFC 1951) as permessage-deflate (RFC 7692) uses it, each message compressed on
+//! its own.
+//!
+//! Compression finds repeated strings within the message and encodes them with the fixed Huffman
+//! codes, which gets most of the gain on repetitive JSON such as lists of txids without building
+//! code tables. Decompression understands all block types, as clients may compress with any.
+
+use anyhow::{anyhow, Error};
+
+/// Length of the shortest and longest repeated strings DEFLATE can refer to.
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// Candidates tried for each repeated string, trading speed for compression.
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// Order the lengths of the code length code are sent in.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u32,
+    count: u32,
+}
+impl BitWriter {
+    fn write(&mut self, value: u32, count: u32) {
+        self.bits |= value << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Writes a Huffman code, which goes most significant bit first.
+    fn write_code(&mut self, code: u32, len: u32) {
+        let reversed = code.reverse_bits() >> (32 - len);
+        self.write(reversed, len);
+    }
+
+    fn align(&mut self) {
+        if self.count > 0 {
+            self.write(0, 8 - self.count);
+        }
+    }
+
+    /// Writes a literal or length symbol with its fixed code.
+    fn write_literal(&mut self, symbol: u16) {
+        let symbol = symbol as u32;
+        match symbol {
+            0..=143 => self.write_code(0x30 + symbol, 8),
+            144..=255 => self.write_code(0x190 + symbol - 144, 9),
+            256..=279 => self.write_code(symbol - 256, 7),
+            _ => self.write_code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn write_match(&mut self, len: usize, dist: usize) {
+        let i = LENGTH_BASE
+            .iter()
+            .rposition(|b| *b as usize <= len)
+            .unwrap();
+        self.write_literal(257 + i as u16);
+        self.write(
+            (len - LENGTH_BASE[i] as usize) as u32,
+            LENGTH_EXTRA[i] as u32,
+        );
+        let i = DIST_BASE.iter().rposition(|b| *b as usize <= dist).unwrap();
+        self.write_code(i as u32, 5);
+        self.write((dist - DIST_BASE[i] as usize) as u32, DIST_EXTRA[i] as u32);
+    }
+}
+
+fn hash(data: &[u8]) -> usize {
+    let n = (data[0] as u32) << 16 | (data[1] as u32) << 8 | data[2] as u32;
+    (n.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+/// Makes the string at `pos` the first candidate for matches of its first bytes.
+fn insert(data: &[u8], pos: usize, head: &mut [usize], prev: &mut [usize]) {
+    if pos + MIN_MATCH <= data.len() {
+        let h = hash(&data[pos..]);
+        prev[pos] = head[h];
+        head[h] = pos;
+    }
+}
+
+/// Compresses `data` into a block with fixed codes, ending like after a sync flush but without
+/// the final `00 00 ff ff`, as permessage-deflate sends it. Repeated strings are looked for at
+/// most `2^window_bits` bytes back.
+pub fn compress(data: &[u8], window_bits: u8) -> Vec<u8> {
+    let window = 1usize << window_bits.clamp(8, 15);
+    let mut writer = BitWriter::default();
+    // not the final block, fixed codes
+    writer.write(0b010, 3);
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; data.len()];
+    let mut pos = 0;
+    while pos < data.len() {
+        let mut best = (0, 0);
+        if pos + MIN_MATCH <= data.len() {
+            let max = (data.len() - pos).min(MAX_MATCH);
+            let mut candidate = head[hash(&data[pos..])];
+            for _ in 0..MAX_CHAIN {
+                if candidate == usize::MAX || pos - candidate > window {
+                    break;
+                }
+                let len = data[candidate..]
+                    .iter()
+                    .zip(&data[pos..pos + max])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if len > best.0 {
+                    best = (len, pos - candidate);
+                    if len == max {
+                        break;
+                    }
+                }
+                candidate = prev[candidate];
+            }
+        }
+        if best.0 >= MIN_MATCH {
+            writer.write_match(best.0, best.1);
+            for p in pos..pos + best.0 {
+                insert(data, p, &mut head, &mut prev);
+            }
+            pos += best.0;
+        } else {
+            writer.write_literal(data[pos] as u16);
+            insert(data, pos, &mut head, &mut prev);
+            pos += 1;
+        }
+    }
+    writer.write_literal(256);
+    // an empty stored block, whose length fields are left for the receiver to append
+    writer.write(0, 3);
+    writer.align();
+    writer.out
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bits: u32,
+    count: u32,
+}
+impl BitReader<'_> {
+    fn bits(&mut self, count: u32) -> Result<u32, Error> {
+        while self.count < count {
+            let byte = *self
+                .data
+                .get(self.pos)
+                .ok_or_else(|| anyhow!("truncated compressed data"))?;
+            self.pos += 1;
+            self.bits |= (byte as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.bits & ((1u64 << count) - 1) as u32;
+        self.bits >>= count;
+        self.count -= count;
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        self.bits = 0;
+        self.count = 0;
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos == self.data.len() && self.count < 8
+    }
+}
+
+/// A canonical Huffman code, by the number of codes of each length and the symbols in code order.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self, Error> {
+        let mut counts = [0u16; 16];
+        for len in lengths {
+            counts[*len as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut left = 1i32;
+        for count in &counts[1..] {
+            left = (left << 1) - *count as i32;
+            if left < 0 {
+                return Err(anyhow!("oversubscribed Huffman code"));
+            }
+        }
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, len) in lengths.iter().enumerate() {
+            if *len != 0 {
+                symbols[offsets[*len as usize] as usize] = symbol as u16;
+                offsets[*len as usize] += 1;
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, Error> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..16 {
+            code |= reader.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(anyhow!("invalid Huffman code"))
+    }
+}
+
+fn fixed_codes() -> Result<(Huffman, Huffman), Error> {
+    let mut lengths = [0u8; 288];
+    for (symbol, len) in lengths.iter_mut().enumerate() {
+        *len = match symbol {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        };
+    }
+    Ok((Huffman::new(&lengths)?, Huffman::new(&[5; 30])?))
+}
+
+fn dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman), Error> {
+    let literals = reader.bits(5)? as usize + 257;
+    let distances = reader.bits(5)? as usize + 1;
+    let code_lengths = reader.bits(4)? as usize + 4;
+    if literals > 286 || distances > 30 {
+        return Err(anyhow!("too many Huffman codes"));
+    }
+    let mut lengths = [0u8; 19];
+    for i in &CODE_LENGTH_ORDER[..code_lengths] {
+        lengths[*i] = reader.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&lengths)?;
+    let mut lengths = Vec::with_capacity(literals + distances);
+    while lengths.len() < literals + distances {
+        let (len, repeat) = match code_length_code.decode(reader)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => (
+                *lengths
+                    .last()
+                    .ok_or_else(|| anyhow!("repeated length without a previous one"))?,
+                3 + reader.bits(2)? as usize,
+            ),
+            17 => (0, 3 + reader.bits(3)? as usize),
+            _ => (0, 11 + reader.bits(7)? as usize),
+        };
+        if lengths.len() + repeat > literals + distances {
+            return Err(anyhow!("too many code lengths"));
+        }
+        lengths.extend(std::iter::repeat_n(len, repeat));
+    }
+    if lengths[256] == 0 {
+        return Err(anyhow!("no code for the end of the block"));
+    }
+    Ok((
+        Huffman::new(&lengths[..literals])?,
+        Huffman::new(&lengths[literals..])?,
+    ))
+}
+
+/// Decompresses a message compressed with permessage-deflate, failing if it would take more than
+/// `limit` bytes.
+pub fn decompress(data: &[u8], limit: usize) -> Result<Vec<u8>, Error> {
+    let mut input = data.to_vec();
+    input.extend_from_slice(&[0, 0, 0xff, 0xff]);
+    let mut reader = BitReader {
+        data: &input,
+        pos: 0,
+        bits: 0,
+        count: 0,
+    };
+    let mut out = Vec::new();
+    loop {
+        let last = reader.bits(1)? == 1;
+        let (literal, distance) = match reader.bits(2)? {
+            0 => {
+                reader.align();
+                let len = reader.bits(16)?;
+                if len != !reader.bits(16)? & 0xffff {
+                    return Err(anyhow!("corrupt stored block"));
+                }
+                let start = reader.pos;
+                let block = input
+                    .get(start..start + len as usize)
+                    .ok_or_else(|| anyhow!("truncated compressed data"))?;
+                if out.len() + block.len() > limit {
+                    return Err(anyhow!("decompressed message larger than {} bytes", limit));
+                }
+                out.extend_from_slice(block);
+                reader.pos += len as usize;
+                if last || reader.at_end() {
+                    return Ok(out);
+                }
+                continue;
+            }
+            1 => fixed_codes()?,
+            2 => dynamic_codes(&mut reader)?,
+            _ => return Err(anyhow!("invalid block type")),
+        };
+        loop {
+            let symbol = literal.decode(&mut reader)? as usize;
+            if symbol < 256 {
+                out.push(symbol as u8);
+            } else if symbol == 256 {
+                break;
+            } else {
+                let i = symbol - 257;
+                if i >= LENGTH_BASE.len() {
+                    return Err(anyhow!("invalid length symbol"));
+                }
+                let len = LENGTH_BASE[i] as usize + reader.bits(LENGTH_EXTRA[i] as u32)? as usize;
+                let i = distance.decode(&mut reader)? as usize;
+                if i >= DIST_BASE.len() {
+                    return Err(anyhow!("invalid distance symbol"));
+                }
+                let dist = DIST_BASE[i] as usize + reader.bits(DIST_EXTRA[i] as u32)? as usize;
+                if dist > out.len() {
+                    return Err(anyhow!("distance too far back"));
+                }
+                for _ in 0..len {
+                    out.push(out[out.len() - dist]);
+                }
+            }
+            if out.len() > limit {
+                return Err(anyhow!("decompressed message larger than {} bytes", limit));
+            }
+        }
+        if last || reader.at_end() {
+            return Ok(out);
+        }
+    }
+}
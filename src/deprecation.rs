@@ -0,0 +1,76 @@
+//! Methods the operator marked as deprecated: calls still succeed, but clients are told with a
+//! `Warning` header and a `warning` field in the response, and the callers are logged, to find
+//! the clients that need migrating.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use slog::Logger;
+
+use crate::client::{GenericRpcMethod, RpcRequest, SingleOrBatchRpcRequest};
+use crate::intercept::RequestContext;
+
+#[derive(Debug, Default)]
+pub struct Deprecations {
+    /// Notes telling clients what to use instead, by deprecated method
+    methods: HashMap<String, String>,
+    /// Calls of deprecated methods by user and method
+    calls: Mutex<HashMap<(String, String), u64>>,
+}
+impl Deprecations {
+    pub fn new(methods: HashMap<String, String>) -> Self {
+        Deprecations {
+            methods,
+            calls: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn methods(&self) -> &HashMap<String, String> {
+        &self.methods
+    }
+
+    /// Calls of deprecated methods by user and method, for metrics.
+    pub fn calls(&self) -> HashMap<(String, String), u64> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    fn warning(&self, method: &str) -> Option<String> {
+        let note = self.methods.get(method)?;
+        Some(if note.is_empty() {
+            format!("{} is deprecated", method)
+        } else {
+            format!("{} is deprecated: {}", method, note)
+        })
+    }
+
+    /// Counts the call, logging the first call of `method` by `user` as a warning.
+    fn record(&self, logger: &Logger, user: &str, method: &str) {
+        let mut calls = self.calls.lock().unwrap();
+        let count = calls
+            .entry((user.to_owned(), method.to_owned()))
+            .or_insert(0);
+        *count += 1;
+        if *count == 1 {
+            warn!(logger, "{} called deprecated method {}", user, method);
+        } else {
+            debug!(
+                logger,
+                "{} called deprecated method {} ({} calls)", user, method, count
+            );
+        }
+    }
+
+    /// Warns about the deprecated methods called in `req`, counting the calls.
+    pub fn warn(&self, logger: &Logger, ctx: &RequestContext, req: &SingleOrBatchRpcRequest) {
+        let reqs: &[RpcRequest<GenericRpcMethod>] = match req {
+            SingleOrBatchRpcRequest::Single(req) => std::slice::from_ref(req),
+            SingleOrBatchRpcRequest::Batch(reqs) => reqs,
+        };
+        for req in reqs {
+            if let Some(warning) = self.warning(&req.method.0) {
+                self.record(logger, &ctx.caller(), &req.method.0);
+                ctx.warn(req, warning);
+            }
+        }
+    }
+}
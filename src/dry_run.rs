@@ -0,0 +1,81 @@
+use std::sync::Arc;
+
+use serde_json::{json, Value};
+
+use crate::client::{GenericRpcMethod, RpcRequest, RpcResponse};
+use crate::intercept::InterceptResult;
+use crate::state::State;
+
+/// Requests carrying this header with a value of `1` or `true` are simulated instead of
+/// being forwarded, if they would change the state of the node or wallet.
+pub const DRY_RUN_HEADER: &str = "x-dry-run";
+
+/// Translates a write method into a side-effect free call that checks whether it would
+/// succeed, if there is one.
+fn simulation(req: &RpcRequest<GenericRpcMethod>) -> Option<(&'static str, Vec<Value>)> {
+    let param = |i: usize| req.params.get(i).cloned().unwrap_or(Value::Null);
+    match &*req.method.0 {
+        "sendrawtransaction" => Some(("testmempoolaccept", vec![json!([param(0)])])),
+        "sendtoaddress" => {
+            let mut options = json!({});
+            if param(4) == Value::Bool(true) {
+                options["subtractFeeFromOutputs"] = json!([0]);
+            }
+            if let Value::Bool(replaceable) = param(5) {
+                options["replaceable"] = replaceable.into();
+            }
+            Some((
+                "walletcreatefundedpsbt",
+                vec![
+                    json!([]),
+                    json!([{ param(0).as_str().unwrap_or_default(): param(1) }]),
+                    json!(0),
+                    options,
+                ],
+            ))
+        }
+        "sendmany" => Some(("walletcreatefundedpsbt", vec![json!([]), param(1)])),
+        "send" => Some(("walletcreatefundedpsbt", vec![json!([]), param(0)])),
+        "bumpfee" => Some(("psbtbumpfee", req.params.iter().take(2).cloned().collect())),
+        _ => None,
+    }
+}
+
+pub async fn simulate(
+    state: Arc<State>,
+    path: &str,
+    req: &RpcRequest<GenericRpcMethod>,
+) -> InterceptResult {
+    let result = match simulation(req) {
+        Some((method, params)) => {
+            let check = state
+                .rpc_client
+                .call_at(
+                    path,
+                    &RpcRequest {
+                        id: None,
+                        method: GenericRpcMethod(method.to_owned()),
+                        params,
+                    },
+                )
+                .await?
+                .into_result()?;
+            json!({
+                "dry_run": true,
+                "method": req.method.0,
+                "simulated_with": method,
+                "result": check,
+            })
+        }
+        None => json!({
+            "dry_run": true,
+            "method": req.method.0,
+            "simulated_with": Value::Null,
+        }),
+    };
+    Ok(Some(RpcResponse {
+        id: req.id.clone(),
+        result: Some(result),
+        error: None,
+    }))
+}
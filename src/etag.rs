@@ -0,0 +1,74 @@
+use anyhow::Error;
+use bitcoin::hashes::{sha256, Hash};
+use hyper::{
+    body::Bytes,
+    header::{HeaderValue, CONTENT_LENGTH, ETAG},
+    Body, Response, StatusCode,
+};
+use serde_json::{json, Value};
+
+/// Methods whose result only depends on their parameters, so a client can safely reuse a
+/// previous response when the content hash matches.
+pub const CACHEABLE_METHODS: &[&str] = &[
+    "getblock",
+    "getblockhash",
+    "getblockheader",
+    "getblockfilter",
+    "getblockstats",
+    "getrawtransaction",
+    "gettxoutproof",
+    "decoderawtransaction",
+    "decodescript",
+    "decodepsbt",
+    "verifytxoutproof",
+];
+
+pub fn is_cacheable(method: &str) -> bool {
+    CACHEABLE_METHODS.contains(&method)
+}
+
+/// Whether the client has the tagged response. `*` isn't a match: it would answer a client that
+/// has no copy with an empty `304`, since RPC calls are always sent with `POST`.
+fn matches(if_none_match: &HeaderValue, etag: &str) -> bool {
+    match if_none_match.to_str() {
+        Ok(s) => s
+            .split(',')
+            .map(|t| t.trim())
+            .any(|t| t == etag || t.strip_prefix("W/") == Some(etag)),
+        Err(_) => false,
+    }
+}
+
+/// Hashes the result or error of a response, leaving out the id, which differs between calls.
+fn content_hash(body: &[u8]) -> sha256::Hash {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(Value::Object(response)) => {
+            let content = json!([response.get("result"), response.get("error")]);
+            sha256::Hash::hash(content.to_string().as_bytes())
+        }
+        _ => sha256::Hash::hash(body),
+    }
+}
+
+/// Tags a successful response with a content hash, replacing it with `304 Not Modified` if
+/// the client already has it.
+pub async fn tag(
+    response: Response<Body>,
+    if_none_match: Option<&HeaderValue>,
+) -> Result<Response<Body>, Error> {
+    if response.status() != StatusCode::OK {
+        return Ok(response);
+    }
+    let (mut parts, body) = response.into_parts();
+    let body: Bytes = tokio::stream::StreamExt::collect::<Result<Bytes, _>>(body).await?;
+    let etag = format!("\"{}\"", hex::encode(&content_hash(&body)[..]));
+    if if_none_match.is_some_and(|h| matches(h, &etag)) {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(ETAG, etag)
+            .body(Body::empty())?);
+    }
+    parts.headers.insert(ETAG, etag.parse()?);
+    parts.headers.insert(CONTENT_LENGTH, body.len().into());
+    Ok(Response::from_parts(parts, body.into()))
+}
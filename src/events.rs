@@ -0,0 +1,146 @@
+//! Notifications of new blocks and mempool transactions as Server-Sent Events on `/events`, for
+//! web clients that can't or don't want to use the WebSocket on `/ws`.
+//!
+//! Subscribers are registered with the same [`Subscriptions`](crate::subscriptions::Subscriptions)
+//! as WebSocket ones, so their queues and overflow policy are the same. The topics are given in
+//! the query, `/events?topics=block,tx`, defaulting to all the user may subscribe to.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Error;
+use hyper::{
+    header::{CACHE_CONTROL, CONTENT_TYPE},
+    http::request::Parts,
+    Body, Method, Response, StatusCode,
+};
+use serde_json::json;
+
+use crate::auth::Authenticated;
+use crate::state::State;
+use crate::subscriptions::{Delivery, Topic};
+use crate::users::User;
+
+/// How often a comment is sent on an idle stream, so that intermediaries don't time it out and
+/// disconnected clients are noticed.
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+const TOPICS: [Topic; 2] = [Topic::Block, Topic::Tx];
+
+/// The topics asked for in `query`, all those `user` is allowed if none are.
+pub fn topics(query: Option<&str>, user: &User) -> Result<Vec<Topic>, (StatusCode, String)> {
+    let asked = query
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == "topics")
+        .map(|(_, topics)| topics.replace("%2C", ",").replace("%2c", ","));
+    match asked {
+        Some(asked) => {
+            let topics = asked
+                .split(',')
+                .filter(|t| !t.is_empty())
+                .map(|t| {
+                    t.parse::<Topic>()
+                        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            if topics.is_empty() {
+                return Err((StatusCode::BAD_REQUEST, "no topics".to_owned()));
+            }
+            match topics
+                .iter()
+                .find(|t| user.allowed_by(t.method()).is_none())
+            {
+                Some(topic) => Err((
+                    StatusCode::FORBIDDEN,
+                    format!("subscribing requires being allowed {}", topic.method()),
+                )),
+                None => Ok(topics),
+            }
+        }
+        None => {
+            let topics: Vec<_> = TOPICS
+                .iter()
+                .copied()
+                .filter(|t| user.allowed_by(t.method()).is_some())
+                .collect();
+            if topics.is_empty() {
+                return Err((
+                    StatusCode::FORBIDDEN,
+                    "not allowed to subscribe to any topic".to_owned(),
+                ));
+            }
+            Ok(topics)
+        }
+    }
+}
+
+/// The event as sent on the stream, named like the `method` of WebSocket notifications with
+/// their `params` as data.
+pub fn encode(delivery: &Delivery) -> Option<String> {
+    let (name, data) = match delivery {
+        Delivery::Event(event) => {
+            let json = event.to_json();
+            (json["method"].as_str()?.to_owned(), json["params"].clone())
+        }
+        Delivery::Dropped(count) => ("dropped".to_owned(), json!({ "count": count })),
+        Delivery::Overflowed => return None,
+    };
+    Some(format!("event: {}\ndata: {}\n\n", name, data))
+}
+
+/// Answers a request for `/events` with a stream of the events the client asked for.
+pub async fn serve(state: Arc<State>, parts: Parts) -> Result<Response<Body>, Error> {
+    let subscriptions = match &state.subscriptions {
+        Some(subscriptions) => subscriptions,
+        None => {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())?)
+        }
+    };
+    if parts.method != Method::GET {
+        return Ok(Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .body(Body::empty())?);
+    }
+    let Authenticated { name, user, .. } =
+        match crate::auth::authenticate_request(&state, &parts).await {
+            Ok(auth) => auth,
+            Err(response) => return Ok(response),
+        };
+    let topics = match topics(parts.uri.query(), &user) {
+        Ok(topics) => topics,
+        Err((status, reason)) => {
+            return Ok(Response::builder().status(status).body(reason.into())?);
+        }
+    };
+    let outbox = subscriptions.subscribe();
+    for topic in topics {
+        outbox.set_subscribed(topic, true);
+    }
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        debug!(state.logger, "{} connected to /events", name);
+        // tells EventSource clients how long to wait before reconnecting
+        let mut sent = sender.send_data("retry: 5000\n\n".into()).await;
+        while sent.is_ok() {
+            let delivery = tokio::time::timeout(KEEPALIVE_INTERVAL, outbox.next()).await;
+            let chunk = match delivery {
+                Ok(delivery) => match encode(&delivery) {
+                    Some(chunk) => chunk,
+                    // the client reconnects and catches up with RPC calls
+                    None => break,
+                },
+                Err(_) => ": keepalive\n\n".to_owned(),
+            };
+            sent = sender.send_data(chunk.into()).await;
+        }
+        debug!(state.logger, "{} disconnected from /events", name);
+    });
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "text/event-stream")
+        .header(CACHE_CONTROL, "no-cache")
+        .body(body)?)
+}
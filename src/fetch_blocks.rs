@@ -1,56 +1,42 @@
+use std::collections::HashMap;
 use std::io::{Read, Write};
 use std::iter::FromIterator;
 use std::net::TcpStream;
-use std::sync::Arc;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 use anyhow::Error;
 use async_channel as mpmc;
 use bitcoin::{
-    consensus::{Decodable, Encodable},
+    consensus::Decodable,
     hash_types::BlockHash,
-    network::{
-        address::Address,
-        constants::{Network::Bitcoin, ServiceFlags},
-        message::{NetworkMessage, RawNetworkMessage},
-        message_blockdata::Inventory,
-        message_network::VersionMessage,
-    },
+    network::{address::Address, message::NetworkMessage, message_blockdata::Inventory},
     Block,
 };
-use futures::FutureExt;
+use futures::future::{BoxFuture, FutureExt};
+use hyper::{body::Bytes, StatusCode, Uri};
+use serde_json::{json, Value};
+#[cfg(feature = "tor")]
 use socks::Socks5Stream;
 
-use crate::client::{RpcClient, RpcError, RpcRequest, MISC_ERROR_CODE, PRUNE_ERROR_MESSAGE};
-use crate::rpc_methods::{GetBlock, GetBlockParams, GetPeerInfo};
-use crate::state::{State, TorState};
-
-type VersionMessageProducer = Box<dyn Fn(Address) -> RawNetworkMessage + Send + Sync>;
-
-lazy_static::lazy_static! {
-    static ref VER_ACK: RawNetworkMessage = RawNetworkMessage {
-        magic: Bitcoin.magic(),
-        payload: NetworkMessage::Verack,
-    };
-    static ref VERSION_MESSAGE: VersionMessageProducer = Box::new(|addr| {
-        use std::time::SystemTime;
-        RawNetworkMessage {
-            magic: Bitcoin.magic(),
-            payload: NetworkMessage::Version(VersionMessage::new(
-                ServiceFlags::NONE,
-                SystemTime::now()
-                    .duration_since(SystemTime::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs() as i64,
-                addr,
-                Address::new(&([127, 0, 0, 1], 8332).into(), ServiceFlags::NONE),
-                rand::random(),
-                format!("BTC RPC Proxy v{}", env!("CARGO_PKG_VERSION")),
-                0,
-            )),
-        }
-    });
-}
+use crate::address_book::host;
+#[cfg(feature = "tor")]
+use crate::address_book::onion_host;
+use crate::client::{
+    GenericRpcMethod, RpcClient, RpcError, RpcRequest, MISC_ERROR_CODE, PRUNE_ERROR_MESSAGE,
+};
+use crate::p2p::{self, Connection, Direction, Frame, Message, Tracer};
+use crate::quota::Quota;
+use crate::rpc_methods::{
+    GetBlock, GetBlockHeader, GetBlockHeaderParams, GetBlockParams, GetPeerInfo,
+};
+use crate::scheduler::{Missing, Outcome, PeerFailure, Scheduler, Sources};
+use crate::state::State;
+#[cfg(feature = "tor")]
+use crate::state::TorState;
+use crate::timeouts::Transport;
+use crate::upstream::Connector;
 
 #[derive(Debug)]
 pub struct Peers {
@@ -64,6 +50,16 @@ impl Peers {
             peers: Vec::new(),
         }
     }
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+    /// How long ago the list was fetched from bitcoind
+    pub fn age(&self) -> Option<Duration> {
+        self.fetched.map(|f| f.elapsed())
+    }
     pub fn stale(&self, max_peer_age: Duration) -> bool {
         self.fetched
             .map(|f| f.elapsed() > max_peer_age)
@@ -87,106 +83,226 @@ impl Peers {
             fetched: Some(Instant::now()),
         })
     }
-    pub fn handles<C: FromIterator<PeerHandle>>(&self) -> C {
+    /// The peers in the pool and whether an idle connection to them is kept, for state dumps.
+    pub fn dump(&self) -> Value {
+        json!({
+            "age": self.age().map(|a| a.as_secs_f64()),
+            "peers": self
+                .peers
+                .iter()
+                .map(|p| json!({
+                    "peer": peer_name(&p.addr),
+                    "idle_connection": !p.recv.is_empty(),
+                }))
+                .collect::<Vec<_>>(),
+        })
+    }
+
+    pub fn addrs(&self) -> impl Iterator<Item = &Address> {
+        self.peers.iter().map(|p| &p.addr)
+    }
+    /// Takes over the idle connections of `previous` to peers still in the list.
+    pub fn keep_connections(&mut self, previous: &Peers) {
+        for peer in &mut self.peers {
+            if let Some(old) = previous.peers.iter().find(|p| p.addr == peer.addr) {
+                *peer = old.clone();
+            }
+        }
+    }
+    pub(crate) fn handles<C: FromIterator<PeerHandle>>(&self) -> C {
         self.peers.iter().map(|p| p.handle()).collect()
     }
 }
 
-pub enum BitcoinPeerConnection {
+enum PeerStream {
     ClearNet(TcpStream),
+    #[cfg(feature = "tor")]
     Tor(Socks5Stream),
 }
-impl Read for BitcoinPeerConnection {
+impl Read for PeerStream {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         match self {
-            BitcoinPeerConnection::ClearNet(a) => a.read(buf),
-            BitcoinPeerConnection::Tor(a) => a.read(buf),
+            PeerStream::ClearNet(a) => a.read(buf),
+            #[cfg(feature = "tor")]
+            PeerStream::Tor(a) => a.read(buf),
         }
     }
 }
-impl Write for BitcoinPeerConnection {
+impl Write for PeerStream {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         match self {
-            BitcoinPeerConnection::ClearNet(a) => a.write(buf),
-            BitcoinPeerConnection::Tor(a) => a.write(buf),
+            PeerStream::ClearNet(a) => a.write(buf),
+            #[cfg(feature = "tor")]
+            PeerStream::Tor(a) => a.write(buf),
         }
     }
     fn flush(&mut self) -> std::io::Result<()> {
         match self {
-            BitcoinPeerConnection::ClearNet(a) => a.flush(),
-            BitcoinPeerConnection::Tor(a) => a.flush(),
+            PeerStream::ClearNet(a) => a.flush(),
+            #[cfg(feature = "tor")]
+            PeerStream::Tor(a) => a.flush(),
         }
     }
     fn write_vectored(&mut self, bufs: &[std::io::IoSlice<'_>]) -> std::io::Result<usize> {
         match self {
-            BitcoinPeerConnection::ClearNet(a) => a.write_vectored(bufs),
-            BitcoinPeerConnection::Tor(a) => a.write_vectored(bufs),
+            PeerStream::ClearNet(a) => a.write_vectored(bufs),
+            #[cfg(feature = "tor")]
+            PeerStream::Tor(a) => a.write_vectored(bufs),
         }
     }
     fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
         match self {
-            BitcoinPeerConnection::ClearNet(a) => a.write_all(buf),
-            BitcoinPeerConnection::Tor(a) => a.write_all(buf),
+            PeerStream::ClearNet(a) => a.write_all(buf),
+            #[cfg(feature = "tor")]
+            PeerStream::Tor(a) => a.write_all(buf),
         }
     }
     fn write_fmt(&mut self, fmt: std::fmt::Arguments<'_>) -> std::io::Result<()> {
         match self {
-            BitcoinPeerConnection::ClearNet(a) => a.write_fmt(fmt),
-            BitcoinPeerConnection::Tor(a) => a.write_fmt(fmt),
+            PeerStream::ClearNet(a) => a.write_fmt(fmt),
+            #[cfg(feature = "tor")]
+            PeerStream::Tor(a) => a.write_fmt(fmt),
+        }
+    }
+}
+impl PeerStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match self {
+            PeerStream::ClearNet(a) => a.set_read_timeout(timeout),
+            #[cfg(feature = "tor")]
+            PeerStream::Tor(a) => a.get_ref().set_read_timeout(timeout),
         }
     }
 }
+
+/// `host:port` of a peer.
+pub(crate) fn peer_name(addr: &Address) -> String {
+    format!("{}:{}", host(addr).0, addr.port)
+}
+
+/// How the proxy reaches `addr`.
+#[cfg_attr(not(feature = "tor"), allow(unused_variables))]
+fn transport(state: &State, addr: &Address) -> Transport {
+    let ip = match addr.socket_addr() {
+        Ok(addr) => addr.ip(),
+        Err(_) => return Transport::Tor,
+    };
+    #[cfg(feature = "tor")]
+    if state.tor.as_ref().is_some_and(|tor| tor.only) {
+        return Transport::Tor;
+    }
+    Transport::of_ip(ip)
+}
+
+pub(crate) struct BitcoinPeerConnection {
+    stream: PeerStream,
+    /// `host:port` of the peer, as traced
+    peer: String,
+    tracer: Option<Arc<Tracer>>,
+    /// How long to wait for the peer, depending on how it's reached
+    pub timeout: Duration,
+    /// Of the network of bitcoind
+    magic: u32,
+}
+impl Connection for BitcoinPeerConnection {
+    fn receive_frame(&mut self) -> Result<Frame, Error> {
+        let frame = Frame::read(&mut self.stream, self.magic)?;
+        if let Some(tracer) = &self.tracer {
+            tracer.record(&self.peer, Direction::Received, &frame);
+        }
+        Ok(frame)
+    }
+    fn send_frame(&mut self, frame: &Frame) -> Result<(), Error> {
+        if let Some(tracer) = &self.tracer {
+            tracer.record(&self.peer, Direction::Sent, frame);
+        }
+        frame.write(&mut self.stream, self.magic)
+    }
+}
 impl BitcoinPeerConnection {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+    /// Checks that the peer still responds, answering its pings and discarding other messages
+    /// received while the connection was idle.
+    fn ping(&mut self, timeout: Duration) -> Result<(), Error> {
+        let nonce = rand::random();
+        self.set_read_timeout(Some(timeout))?;
+        self.send(NetworkMessage::Ping(nonce))?;
+        loop {
+            match self.receive()? {
+                Message::Known(NetworkMessage::Pong(n)) if n == nonce => break,
+                Message::Known(NetworkMessage::Ping(n)) => self.send(NetworkMessage::Pong(n))?,
+                _ => (),
+            }
+        }
+        self.set_read_timeout(None)?;
+        Ok(())
+    }
+
+    /// Connects to `addr`, retrying as often as its timeout profile allows.
     pub async fn connect(state: Arc<State>, addr: Address) -> Result<Self, Error> {
+        let profile = state.peer_timeouts.get(transport(&state, &addr));
+        let mut attempt = 0;
+        loop {
+            match Self::connect_once(state.clone(), addr.clone(), profile.timeout()).await {
+                Err(e) if attempt < profile.retries => {
+                    attempt += 1;
+                    debug!(
+                        state.logger,
+                        "Failed to connect to peer {}, retrying: {}",
+                        peer_name(&addr),
+                        e
+                    );
+                }
+                res => return res,
+            }
+        }
+    }
+
+    async fn connect_once(
+        state: Arc<State>,
+        addr: Address,
+        timeout: Duration,
+    ) -> Result<Self, Error> {
         tokio::time::timeout(
-            state.peer_timeout,
+            timeout,
             tokio::task::spawn_blocking(move || {
-                let mut stream = match (addr.socket_addr(), &state.tor) {
+                #[cfg(not(feature = "tor"))]
+                let stream = PeerStream::ClearNet(TcpStream::connect(addr.socket_addr()?)?);
+                #[cfg(feature = "tor")]
+                let stream = match (addr.socket_addr(), &state.tor) {
                     (Ok(addr), Some(TorState { only: false, .. })) | (Ok(addr), None) => {
-                        BitcoinPeerConnection::ClearNet(TcpStream::connect(addr)?)
+                        PeerStream::ClearNet(TcpStream::connect(addr)?)
                     }
                     (Ok(addr), Some(tor)) => {
-                        BitcoinPeerConnection::Tor(Socks5Stream::connect(tor.proxy, addr)?)
+                        PeerStream::Tor(Socks5Stream::connect(tor.proxy, addr)?)
                     }
-                    (Err(_), Some(tor)) => BitcoinPeerConnection::Tor(Socks5Stream::connect(
+                    (Err(_), Some(tor)) => PeerStream::Tor(Socks5Stream::connect(
                         tor.proxy,
-                        (
-                            format!(
-                                "{}.onion",
-                                base32::encode(
-                                    base32::Alphabet::RFC4648 { padding: false },
-                                    &addr
-                                        .address
-                                        .iter()
-                                        .map(|n| *n)
-                                        .flat_map(|n| u16::to_be_bytes(n).to_vec())
-                                        .collect::<Vec<_>>()
-                                )
-                                .to_lowercase()
-                            )
-                            .as_str(),
-                            addr.port,
-                        ),
+                        (onion_host(&addr).as_str(), addr.port),
                     )?),
                     (Err(e), None) => return Err(e.into()),
                 };
-                VERSION_MESSAGE(addr).consensus_encode(&mut stream)?;
-                stream.flush()?;
-                let _ =
-                    bitcoin::network::message::RawNetworkMessage::consensus_decode(&mut stream)?; // version
-                let _ =
-                    bitcoin::network::message::RawNetworkMessage::consensus_decode(&mut stream)?; // verack
-                VER_ACK.consensus_encode(&mut stream)?;
-                stream.flush()?;
-
-                Ok(stream)
+                let mut conn = BitcoinPeerConnection {
+                    stream,
+                    peer: peer_name(&addr),
+                    tracer: state.p2p_tracer.clone(),
+                    timeout,
+                    magic: state.network.magic(),
+                };
+                let features = p2p::handshake(&mut conn, addr.clone())?;
+                state.address_book.set_features(&addr, features);
+
+                Ok(conn)
             }),
         )
         .await??
     }
 }
 
-pub struct Peer {
+#[derive(Clone)]
+pub(crate) struct Peer {
     addr: Address,
     send: mpmc::Sender<BitcoinPeerConnection>,
     recv: mpmc::Receiver<BitcoinPeerConnection>,
@@ -210,13 +326,20 @@ impl std::fmt::Debug for Peer {
     }
 }
 
+/// A peer of bitcoind, with its connection kept open between fetches if any.
 pub struct PeerHandle {
     addr: Address,
     conn: Option<BitcoinPeerConnection>,
     send: mpmc::Sender<BitcoinPeerConnection>,
 }
 impl PeerHandle {
-    pub async fn connect(&mut self, state: Arc<State>) -> Result<RecyclableConnection, Error> {
+    pub fn addr(&self) -> &Address {
+        &self.addr
+    }
+    pub(crate) async fn connect(
+        &mut self,
+        state: Arc<State>,
+    ) -> Result<RecyclableConnection, Error> {
         if let Some(conn) = self.conn.take() {
             Ok(RecyclableConnection {
                 conn,
@@ -231,12 +354,12 @@ impl PeerHandle {
     }
 }
 
-pub struct RecyclableConnection {
+pub(crate) struct RecyclableConnection {
     conn: BitcoinPeerConnection,
     send: mpmc::Sender<BitcoinPeerConnection>,
 }
 impl RecyclableConnection {
-    fn recycle(self) {
+    pub(crate) fn recycle(self) {
         self.send.try_send(self.conn).unwrap_or_default()
     }
 }
@@ -252,6 +375,56 @@ impl std::ops::DerefMut for RecyclableConnection {
     }
 }
 
+/// Keeps connections to the `warm_peers` best scoring peers open, pinging them every
+/// `peer_keepalive_interval`, so that fetching a block doesn't have to wait for a handshake.
+/// Idle connections to other peers are closed.
+pub async fn keep_peers_warm(state: Arc<State>) {
+    if state.warm_peers == 0 || state.peer_keepalive_interval.as_secs() == 0 {
+        return;
+    }
+    let mut interval = tokio::time::interval(state.peer_keepalive_interval);
+    loop {
+        interval.tick().await;
+        let peers = state.peers.read().await.clone();
+        let mut ranked: Vec<&Peer> = peers.peers.iter().collect();
+        state
+            .address_book
+            .sort_by_score(&mut ranked, |peer| peer.addr.clone());
+        for peer in ranked.iter().skip(state.warm_peers) {
+            drop(peer.recv.try_recv());
+        }
+        let warm = ranked.iter().take(state.warm_peers).map(|peer| {
+            let state = state.clone();
+            let mut handle = peer.handle();
+            async move {
+                let res = match handle.conn.take() {
+                    Some(mut conn) => {
+                        let timeout = conn.timeout;
+                        tokio::task::spawn_blocking(move || conn.ping(timeout).map(|_| conn))
+                            .await
+                            .map_err(Error::from)
+                            .and_then(|res| res)
+                    }
+                    None => {
+                        BitcoinPeerConnection::connect(state.clone(), handle.addr.clone()).await
+                    }
+                };
+                match res {
+                    Ok(conn) => handle.send.try_send(conn).unwrap_or_default(),
+                    Err(e) => debug!(
+                        state.logger,
+                        "Failed to keep connection to peer {}:{} open: {}",
+                        host(&handle.addr).0,
+                        handle.addr.port,
+                        e
+                    ),
+                }
+            }
+        });
+        futures::future::join_all(warm).await;
+    }
+}
+
 async fn fetch_block_from_self(state: &State, hash: BlockHash) -> Result<Option<Block>, RpcError> {
     match state
         .rpc_client
@@ -281,28 +454,20 @@ async fn fetch_block_from_peer<'a>(
     hash: BlockHash,
     mut conn: RecyclableConnection,
 ) -> Result<(Block, RecyclableConnection), Error> {
-    tokio::time::timeout(state.peer_timeout, async move {
+    tokio::time::timeout(conn.timeout, async move {
         conn = tokio::task::spawn_blocking(move || {
-            RawNetworkMessage {
-                magic: Bitcoin.magic(),
-                payload: NetworkMessage::GetData(vec![Inventory::Block(hash)]),
-            }
-            .consensus_encode(&mut *conn)
-            .map_err(Error::from)
-            .map(|_| conn)
+            conn.send(NetworkMessage::GetData(vec![Inventory::Block(hash)]))
+                .map(|_| conn)
         })
         .await??;
 
         loop {
-            let (msg, conn_) = tokio::task::spawn_blocking(move || {
-                RawNetworkMessage::consensus_decode(&mut *conn)
-                    .map_err(Error::from)
-                    .map(|msg| (msg, conn))
-            })
-            .await??;
+            let (msg, conn_) =
+                tokio::task::spawn_blocking(move || conn.receive().map(|msg| (msg, conn)))
+                    .await??;
             conn = conn_;
-            match msg.payload {
-                NetworkMessage::Block(b) => {
+            match msg {
+                Message::Known(NetworkMessage::Block(b)) => {
                     let returned_hash = b.block_hash();
                     let merkle_check = b.check_merkle_root();
                     let witness_check = b.check_witness_commitment();
@@ -321,83 +486,432 @@ async fn fetch_block_from_peer<'a>(
                         )),
                     };
                 }
-                NetworkMessage::Ping(p) => {
+                Message::Known(NetworkMessage::Ping(p)) => {
                     conn = tokio::task::spawn_blocking(move || {
-                        RawNetworkMessage {
-                            magic: Bitcoin.magic(),
-                            payload: NetworkMessage::Pong(p),
-                        }
-                        .consensus_encode(&mut *conn)
-                        .map_err(Error::from)
-                        .map(|_| conn)
+                        conn.send(NetworkMessage::Pong(p)).map(|_| conn)
                     })
                     .await??;
                 }
-                m => warn!(state.logger, "Invalid Message Received: {:?}", m),
+                // warm connections receive gossip (`inv`, `addr`, ...) while idle
+                m => debug!(state.logger, "Ignoring message from peer: {}", m.command()),
             }
         }
     })
     .await?
 }
 
-async fn fetch_block_from_peers(
-    state: Arc<State>,
-    peers: Vec<PeerHandle>,
+async fn fetch_block_via_backend_peers(
+    state: &State,
     hash: BlockHash,
-) -> Option<Block> {
-    use futures::stream::StreamExt;
-
-    let (send, mut recv) = futures::channel::mpsc::channel(1);
-    let fut_unordered: futures::stream::FuturesUnordered<_> =
-        peers.into_iter().map(futures::future::ready).collect();
-    let state_local = state.clone();
-    let runner = fut_unordered
-        .then(move |mut peer| {
-            let state_local = state_local.clone();
-            async move {
-                fetch_block_from_peer(
-                    state_local.clone(),
-                    hash.clone(),
-                    peer.connect(state_local).await?,
-                )
-                .await
-            }
+    failures: &Mutex<Vec<PeerFailure>>,
+) -> Result<Option<Block>, RpcError> {
+    let peers = state
+        .rpc_client
+        .call(&RpcRequest {
+            id: None,
+            method: GetPeerInfo,
+            params: [],
         })
-        .for_each_concurrent(state.max_peer_concurrency, |block_res| {
-            match block_res {
-                Ok((block, conn)) => {
-                    conn.recycle();
-                    send.clone().try_send(block).unwrap_or_default();
+        .await?
+        .into_result()?;
+    for peer in peers
+        .into_iter()
+        .filter(|p| !p.inbound && p.servicesnames.contains("NETWORK"))
+    {
+        let res = state
+            .rpc_client
+            .call(&RpcRequest {
+                id: None,
+                method: GenericRpcMethod("getblockfrompeer".to_owned()),
+                params: vec![json!(hash), json!(peer.id)],
+            })
+            .await?;
+        let failed = |reason: String| {
+            failures.lock().unwrap().push(PeerFailure {
+                peer: format!("bitcoind peer {}", peer.id),
+                reason,
+            })
+        };
+        if let Some(e) = res.error {
+            debug!(
+                state.logger,
+                "getblockfrompeer failed for peer {}: {}", peer.id, e.message
+            );
+            failed(e.message);
+            continue;
+        }
+        // bitcoind downloads the block in the background
+        let poll = async {
+            loop {
+                if let Some(block) = fetch_block_from_self(state, hash).await? {
+                    return Ok::<_, RpcError>(block);
                 }
-                Err(e) => warn!(state.logger, "Error fetching block from peer: {}", e),
+                tokio::time::delay_for(Duration::from_millis(250)).await;
+            }
+        };
+        // bitcoind's onion and I2P peers are slow like the proxy's Tor peers
+        let transport = peer
+            .addr
+            .parse::<std::net::SocketAddr>()
+            .map_or(Transport::Tor, |addr| Transport::of_ip(addr.ip()));
+        let timeout = state.peer_timeouts.get(transport).timeout();
+        match tokio::time::timeout(timeout, poll).await {
+            Ok(block) => return block.map(Some),
+            Err(_) => {
+                debug!(
+                    state.logger,
+                    "Peer {} did not deliver block {} in time", peer.id, hash
+                );
+                failed("did not deliver the block in time".to_owned());
             }
-            futures::future::ready(())
+        }
+    }
+    Ok(None)
+}
+
+async fn fetch_block_from_esplora(
+    state: &State,
+    hash: BlockHash,
+) -> Result<Option<Block>, RpcError> {
+    let esplora = match &state.fetch_policy.esplora {
+        Some(esplora) => esplora,
+        None => return Ok(None),
+    };
+    let body = match esplora.get(&format!("/block/{}/raw", hash)).await? {
+        Some(body) => body,
+        None => return Ok(None),
+    };
+    let block =
+        Block::consensus_decode(&mut std::io::Cursor::new(&body[..])).map_err(Error::from)?;
+    if block.block_hash() != hash || !block.check_merkle_root() || !block.check_witness_commitment()
+    {
+        return Err(anyhow::anyhow!("Esplora returned an invalid block for {}", hash).into());
+    }
+    Ok(Some(block))
+}
+
+/// Makes sure bitcoind knows the header of a block it can't serve itself and, unless
+/// `allow_forks`, that the block is part of its best chain. Blocks fetched elsewhere are checked
+/// against their hash only, so this keeps clients from being served unrelated forks.
+async fn check_best_chain(
+    state: &State,
+    hash: BlockHash,
+    allow_forks: bool,
+) -> Result<(), RpcError> {
+    let header = state
+        .rpc_client
+        .call(&RpcRequest {
+            id: None,
+            method: GetBlockHeader,
+            params: GetBlockHeaderParams(hash, Some(true)),
+        })
+        .await?
+        .into_result()?
+        .into_right()
+        .ok_or_else(|| anyhow::anyhow!("unexpected response for getblockheader"))?;
+    if header.confirmations < 0 && !allow_forks {
+        return Err(RpcError {
+            code: MISC_ERROR_CODE,
+            message: format!(
+                "Block {} is not in the best chain (send X-Allow-Forks: 1 to fetch it anyway)",
+                hash
+            ),
+            data: None,
+            status: None,
         });
-    let b = futures::select! {
-        b = recv.next().fuse() => b,
-        _ = runner.boxed().fuse() => None,
+    }
+    Ok(())
+}
+
+/// bitcoind's prune error, with what was tried in `data` and a hint when retrying may help:
+/// right away if the budget ran out, once the peer list was refreshed otherwise.
+async fn pruned(state: &State, hash: BlockHash, missing: Missing) -> RpcError {
+    let retry_after = if missing.budget_exhausted {
+        state.peer_timeout
+    } else {
+        match state.peers.read().await.age() {
+            Some(age) => state.max_peer_age.saturating_sub(age),
+            None => state.max_peer_age,
+        }
     };
-    b
+    RpcError {
+        code: MISC_ERROR_CODE,
+        message: PRUNE_ERROR_MESSAGE.to_owned(),
+        data: Some(json!({
+            "hash": hash,
+            "attempts": missing.attempts,
+            "peers": missing.peers,
+            "budget_exhausted": missing.budget_exhausted,
+            "retry_after": retry_after.as_secs().max(1),
+        })),
+        status: None,
+    }
 }
 
-pub async fn fetch_block(
+/// Looks for the block in the places configured for `method`, in order.
+pub(crate) async fn fetch_block(
     state: Arc<State>,
-    peers: Vec<PeerHandle>,
+    method: &str,
     hash: BlockHash,
-) -> Result<Option<Block>, RpcError> {
-    Ok(match fetch_block_from_self(&*state, hash).await? {
-        Some(block) => Some(block),
-        None => {
-            debug!(
-                state.logger,
-                "Block is pruned from Core, attempting fetch from peers."
-            );
-            if let Some(block) = fetch_block_from_peers(state.clone(), peers, hash).await {
-                Some(block)
-            } else {
-                error!(state.logger, "Could not fetch block from peers.");
-                None
+    allow_forks: bool,
+    fetcher: Fetcher<'_>,
+) -> Result<Block, RpcError> {
+    fetch_block_checked(state, method, hash, Some(allow_forks), fetcher).await
+}
+
+/// Like `fetch_block`, for a block whose header is already known to be in the best chain.
+pub(crate) async fn fetch_validated_block(
+    state: Arc<State>,
+    method: &str,
+    hash: BlockHash,
+    fetcher: Fetcher<'_>,
+) -> Result<Block, RpcError> {
+    fetch_block_checked(state, method, hash, None, fetcher).await
+}
+
+/// The user a block is fetched for, whose `fetch_quota` limits the blocks fetched from elsewhere
+/// than bitcoind and the cache.
+#[derive(Debug, Clone, Copy)]
+pub struct Fetcher<'a> {
+    pub user_name: &'a str,
+    pub quota: &'a [Quota],
+}
+
+/// Looks for the block as `Scheduler::fetch` does, failing with the prune error if it wasn't
+/// found.
+async fn fetch_block_checked(
+    state: Arc<State>,
+    method: &str,
+    hash: BlockHash,
+    best_chain_check: Option<bool>,
+    fetcher: Fetcher<'_>,
+) -> Result<Block, RpcError> {
+    let scheduler = Scheduler {
+        sources: &state,
+        policy: &state.fetch_policy,
+        address_book: &state.address_book,
+        fetch_usage: &state.fetch_usage,
+        budget: state.fetch_budget,
+        max_peer_concurrency: state.max_peer_concurrency,
+        logger: &state.logger,
+    };
+    match scheduler
+        .fetch(method, hash, best_chain_check, fetcher)
+        .await?
+    {
+        Outcome::Fetched(block) => Ok(block),
+        Outcome::Missing(missing) => Err(pruned(&state, hash, missing).await),
+    }
+}
+
+impl Sources for Arc<State> {
+    type Peer = PeerHandle;
+
+    fn peer_addr(peer: &PeerHandle) -> Address {
+        peer.addr().clone()
+    }
+
+    fn check_best_chain(
+        &self,
+        hash: BlockHash,
+        allow_forks: bool,
+    ) -> BoxFuture<'_, Result<(), RpcError>> {
+        check_best_chain(self, hash, allow_forks).boxed()
+    }
+
+    fn fetch<'a>(
+        &'a self,
+        stage: FetchStage,
+        hash: BlockHash,
+        failures: &'a Mutex<Vec<PeerFailure>>,
+    ) -> BoxFuture<'a, Result<Option<Block>, RpcError>> {
+        async move {
+            match stage {
+                FetchStage::Backend => fetch_block_from_self(self, hash).await,
+                FetchStage::Cache => Ok(self.block_cache.get(&hash)?),
+                FetchStage::GetBlockFromPeer => {
+                    fetch_block_via_backend_peers(self, hash, failures).await
+                }
+                // raced by the scheduler
+                FetchStage::P2p => Ok(None),
+                FetchStage::Esplora => fetch_block_from_esplora(self, hash).await,
             }
         }
-    })
+        .boxed()
+    }
+
+    fn peers(&self) -> BoxFuture<'_, Result<Vec<PeerHandle>, Error>> {
+        self.clone().get_peers().boxed()
+    }
+
+    fn fetch_from_peer(
+        &self,
+        mut peer: PeerHandle,
+        hash: BlockHash,
+    ) -> BoxFuture<'_, Result<Block, Error>> {
+        async move {
+            let conn = peer.connect(self.clone()).await?;
+            let (block, conn) = fetch_block_from_peer(self.clone(), hash, conn).await?;
+            conn.recycle();
+            Ok(block)
+        }
+        .boxed()
+    }
+
+    fn cache(&self, block: &Block) {
+        if let Err(e) = self.block_cache.insert(block) {
+            warn!(self.logger, "{}", e.context("caching block"));
+        }
+    }
+}
+
+/// A place blocks pruned from bitcoind can be fetched from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FetchStage {
+    /// bitcoind itself
+    Backend,
+    /// Blocks fetched earlier, kept in memory
+    Cache,
+    /// bitcoind, after asking it to download the block from one of its peers
+    GetBlockFromPeer,
+    /// bitcoind's peers, connected to directly
+    P2p,
+    /// An Esplora server
+    Esplora,
+}
+impl FromStr for FetchStage {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "backend" => Ok(FetchStage::Backend),
+            "cache" => Ok(FetchStage::Cache),
+            "getblockfrompeer" => Ok(FetchStage::GetBlockFromPeer),
+            "p2p" => Ok(FetchStage::P2p),
+            "esplora" => Ok(FetchStage::Esplora),
+            _ => Err(anyhow::anyhow!(
+                "unknown fetch stage {:?}, expected backend, cache, getblockfrompeer, p2p or esplora",
+                s
+            )),
+        }
+    }
+}
+impl std::fmt::Display for FetchStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            FetchStage::Backend => "backend",
+            FetchStage::Cache => "cache",
+            FetchStage::GetBlockFromPeer => "getblockfrompeer",
+            FetchStage::P2p => "p2p",
+            FetchStage::Esplora => "esplora",
+        })
+    }
+}
+
+/// A stage of a fallback chain, written as `stage` or `stage:timeout_in_seconds`.
+#[derive(Debug, Clone, Copy)]
+pub struct FetchStep {
+    pub stage: FetchStage,
+    pub timeout: Option<Duration>,
+}
+impl FromStr for FetchStep {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut split = s.splitn(2, ':');
+        let stage = split.next().unwrap_or_default().trim().parse()?;
+        let timeout = split
+            .next()
+            .map(|t| t.trim().parse().map(Duration::from_secs))
+            .transpose()?;
+        Ok(FetchStep { stage, timeout })
+    }
+}
+
+/// An Esplora server blocks and transactions bitcoind can't serve are looked up on.
+#[derive(Debug)]
+pub struct Esplora {
+    base: String,
+    client: hyper::Client<Connector>,
+}
+impl Esplora {
+    /// The server at `uri`, reached with `connector`, which should connect like the proxy does
+    /// elsewhere, through the configured resolver and proxies.
+    pub fn new(uri: &Uri, connector: Connector) -> Self {
+        Esplora {
+            base: uri.to_string().trim_end_matches('/').to_owned(),
+            client: hyper::Client::builder().build(connector),
+        }
+    }
+
+    /// Fetches `path` below the base URL, `None` if the server doesn't have it.
+    pub async fn get(&self, path: &str) -> Result<Option<Bytes>, Error> {
+        let uri: Uri = format!("{}{}", self.base, path).parse()?;
+        let response = self.client.get(uri).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!(
+                "Esplora responded with {}",
+                response.status()
+            ));
+        }
+        Ok(Some(hyper::body::to_bytes(response.into_body()).await?))
+    }
+}
+
+/// Where and in which order blocks are looked for, per method.
+#[derive(Debug, Default)]
+pub struct FetchPolicy {
+    order: HashMap<String, Vec<FetchStep>>,
+    pub esplora: Option<Esplora>,
+}
+impl FetchPolicy {
+    /// Methods that fetch pruned blocks.
+    pub const METHODS: &'static [&'static str] = &["getblock"];
+
+    pub fn new(
+        order: HashMap<String, Vec<String>>,
+        esplora: Option<Esplora>,
+    ) -> Result<Self, Error> {
+        let mut policy = FetchPolicy {
+            order: HashMap::new(),
+            esplora,
+        };
+        for (method, steps) in order {
+            if !Self::METHODS.contains(&&*method) {
+                return Err(anyhow::anyhow!("{} doesn't fetch blocks", method));
+            }
+            let steps = steps
+                .iter()
+                .map(|s| s.parse())
+                .collect::<Result<Vec<FetchStep>, _>>()?;
+            if policy.esplora.is_none() && steps.iter().any(|s| s.stage == FetchStage::Esplora) {
+                return Err(anyhow::anyhow!(
+                    "the esplora fetch stage requires esplora_url"
+                ));
+            }
+            policy.order.insert(method, steps);
+        }
+        Ok(policy)
+    }
+
+    pub fn steps(&self, method: &str) -> Vec<FetchStep> {
+        match self.order.get(method) {
+            Some(steps) => steps.clone(),
+            None => vec![
+                FetchStep {
+                    stage: FetchStage::Backend,
+                    timeout: None,
+                },
+                FetchStep {
+                    stage: FetchStage::Cache,
+                    timeout: None,
+                },
+                FetchStep {
+                    stage: FetchStage::P2p,
+                    timeout: None,
+                },
+            ],
+        }
+    }
 }
@@ -0,0 +1,189 @@
+//! Filters subscribers attach to the `tx` topic, so that only the transactions they care about
+//! are sent to them: those paying addresses of their descriptors, or paying at least a fee rate.
+//!
+//! Descriptors are expanded to addresses by bitcoind when the filter is set. The details needed
+//! to evaluate filters are only fetched for new transactions while any subscriber has one.
+
+use std::collections::HashSet;
+
+use anyhow::{anyhow, Error};
+use bitcoin::Txid;
+use serde_json::{json, Value};
+
+use crate::state::State;
+use crate::subscriptions::call;
+
+/// Addresses a filter watches at most, over all of its descriptors.
+pub const MAX_FILTER_ADDRESSES: usize = 10_000;
+/// Addresses derived from a ranged descriptor without a `range`.
+pub const DEFAULT_RANGE: u64 = 1000;
+
+/// What a transaction has to do to be sent to a subscriber.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Filter {
+    /// Pays one of these addresses
+    pub addresses: Option<HashSet<String>>,
+    /// Pays at least this fee rate in sat/vB
+    pub min_fee_rate: Option<f64>,
+}
+impl Filter {
+    /// Parses `{"descriptors": [...], "min_fee_rate": ...}`, expanding the descriptors with
+    /// bitcoind. A descriptor is given as a string or as `{"desc": ..., "range": n}`, ranged ones
+    /// deriving `n` addresses (`DEFAULT_RANGE` by default).
+    pub async fn parse(state: &State, params: &Value) -> Result<Self, Error> {
+        let params = params
+            .as_object()
+            .ok_or_else(|| anyhow!("expected a filter object"))?;
+        if let Some(key) = params
+            .keys()
+            .find(|k| *k != "descriptors" && *k != "min_fee_rate")
+        {
+            return Err(anyhow!(
+                "unknown filter {}, expected descriptors or min_fee_rate",
+                key
+            ));
+        }
+        let min_fee_rate = match params.get("min_fee_rate") {
+            None | Some(Value::Null) => None,
+            Some(rate) => Some(
+                rate.as_f64()
+                    .filter(|rate| *rate >= 0.0)
+                    .ok_or_else(|| anyhow!("expected min_fee_rate in sat/vB"))?,
+            ),
+        };
+        let addresses = match params.get("descriptors") {
+            None | Some(Value::Null) => None,
+            Some(Value::Array(descriptors)) => {
+                let mut addresses = HashSet::new();
+                for descriptor in descriptors {
+                    addresses.extend(derive(state, descriptor).await?);
+                    if addresses.len() > MAX_FILTER_ADDRESSES {
+                        return Err(anyhow!(
+                            "descriptors derive more than {} addresses",
+                            MAX_FILTER_ADDRESSES
+                        ));
+                    }
+                }
+                Some(addresses)
+            }
+            Some(_) => return Err(anyhow!("expected an array of descriptors")),
+        };
+        Ok(Filter {
+            addresses,
+            min_fee_rate,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_none() && self.min_fee_rate.is_none()
+    }
+
+    /// Whether a transaction with `info` passes, transactions without details only pass an empty
+    /// filter.
+    pub fn matches(&self, info: Option<&TxInfo>) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        let info = match info {
+            Some(info) => info,
+            None => return false,
+        };
+        let pays = match &self.addresses {
+            Some(addresses) => info.addresses.iter().any(|a| addresses.contains(a)),
+            None => true,
+        };
+        pays && self.min_fee_rate.is_none_or(|min| info.fee_rate >= min)
+    }
+
+    /// The filter as answered to the client.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "addresses": self.addresses.as_ref().map(HashSet::len),
+            "min_fee_rate": self.min_fee_rate,
+        })
+    }
+}
+
+/// The addresses of a descriptor given as a string or as `{"desc": ..., "range": n}`.
+async fn derive(state: &State, descriptor: &Value) -> Result<Vec<String>, Error> {
+    let (desc, range) = match descriptor {
+        Value::String(desc) => (desc.as_str(), None),
+        Value::Object(descriptor) => (
+            descriptor["desc"]
+                .as_str()
+                .ok_or_else(|| anyhow!("expected desc"))?,
+            match descriptor.get("range") {
+                None => None,
+                Some(range) => Some(
+                    range
+                        .as_u64()
+                        .filter(|n| (1..=MAX_FILTER_ADDRESSES as u64).contains(n))
+                        .ok_or_else(|| {
+                            anyhow!("expected a range of 1 to {}", MAX_FILTER_ADDRESSES)
+                        })?,
+                ),
+            },
+        ),
+        _ => return Err(anyhow!("expected a descriptor")),
+    };
+    // adds the checksum deriveaddresses requires
+    let info = call(state, "getdescriptorinfo", vec![json!(desc)]).await?;
+    let mut params = vec![info["descriptor"].clone()];
+    if info["isrange"].as_bool() == Some(true) {
+        params.push(json!([0, range.unwrap_or(DEFAULT_RANGE) - 1]));
+    }
+    Ok(serde_json::from_value(
+        call(state, "deriveaddresses", params).await?,
+    )?)
+}
+
+/// What filters look at of a mempool transaction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxInfo {
+    /// sat/vB
+    pub fee_rate: f64,
+    /// Addresses paid by its outputs
+    pub addresses: Vec<String>,
+}
+impl TxInfo {
+    /// Fetches the details of a transaction in the mempool.
+    pub async fn fetch(state: &State, txid: Txid) -> Result<Self, Error> {
+        let (entry, tx) = futures::future::try_join(
+            call(state, "getmempoolentry", vec![json!(txid)]),
+            call(state, "getrawtransaction", vec![json!(txid), json!(true)]),
+        )
+        .await?;
+        let fee = entry["fees"]["base"]
+            .as_f64()
+            .ok_or_else(|| anyhow!("expected the fee of {}", txid))?;
+        let vsize = entry["vsize"]
+            .as_f64()
+            .filter(|vsize| *vsize > 0.0)
+            .ok_or_else(|| anyhow!("expected the size of {}", txid))?;
+        let addresses = tx["vout"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .flat_map(|output| {
+                let script = &output["scriptPubKey"];
+                // bitcoind before 22.0 lists `addresses`
+                script["address"]
+                    .as_str()
+                    .into_iter()
+                    .chain(
+                        script["addresses"]
+                            .as_array()
+                            .into_iter()
+                            .flatten()
+                            .filter_map(Value::as_str),
+                    )
+                    .map(str::to_owned)
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        Ok(TxInfo {
+            fee_rate: fee * 100_000_000.0 / vsize,
+            addresses,
+        })
+    }
+}
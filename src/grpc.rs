@@ -0,0 +1,564 @@
+//! A gRPC facade of the JSON-RPC API, described by `proto/btc_rpc_proxy.proto`.
+//!
+//! Each gRPC call is turned into a JSON-RPC call sent through the same path as calls over HTTP,
+//! with the metadata of the call as headers, so that authentication, permissions, limits and
+//! everything else apply to it alike. Protobuf messages are encoded and decoded by hand, they
+//! are few and flat.
+
+use std::convert::{TryFrom, TryInto};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use anyhow::{anyhow, Error};
+use futures::future::{BoxFuture, FutureExt};
+use hyper::{
+    body::{Bytes, HttpBody},
+    header::{HeaderMap, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE},
+    http::request::Parts,
+    Body, Request, Response, StatusCode,
+};
+use serde_json::{json, Value};
+use tokio::stream::StreamExt;
+
+use crate::client::{
+    RpcError, ACCESS_DENIED_ERROR_CODE, METHOD_NOT_ALLOWED_ERROR_CODE, METHOD_NOT_FOUND_ERROR_CODE,
+    PARSE_ERROR_CODE, QUOTA_EXCEEDED_ERROR_CODE,
+};
+use crate::state::State;
+
+/// Path prefix of the methods of the service.
+pub const SERVICE_PATH: &str = "/btc_rpc_proxy.v1.BitcoinRpc/";
+
+/// Messages received or sent at most, like most gRPC implementations.
+pub const MAX_MESSAGE_SIZE: usize = 4 << 20;
+
+/// bitcoind's error for unknown blocks, transactions and the like
+const RPC_INVALID_ADDRESS_OR_KEY: i64 = -5;
+const RPC_INVALID_PARAMETER: i64 = -8;
+
+/// A gRPC status code and message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    pub code: u32,
+    pub message: String,
+    /// Code of the JSON-RPC error the status stands for
+    pub rpc_code: Option<i64>,
+}
+impl Status {
+    pub const INVALID_ARGUMENT: u32 = 3;
+    pub const NOT_FOUND: u32 = 5;
+    pub const PERMISSION_DENIED: u32 = 7;
+    pub const RESOURCE_EXHAUSTED: u32 = 8;
+    pub const UNIMPLEMENTED: u32 = 12;
+    pub const INTERNAL: u32 = 13;
+    pub const UNAVAILABLE: u32 = 14;
+    pub const UNAUTHENTICATED: u32 = 16;
+    pub const UNKNOWN: u32 = 2;
+
+    pub fn new(code: u32, message: impl Into<String>) -> Self {
+        Status {
+            code,
+            message: message.into(),
+            rpc_code: None,
+        }
+    }
+}
+impl From<RpcError> for Status {
+    fn from(e: RpcError) -> Self {
+        let code = match (e.code, e.status) {
+            (_, Some(StatusCode::TOO_MANY_REQUESTS)) | (QUOTA_EXCEEDED_ERROR_CODE, _) => {
+                Status::RESOURCE_EXHAUSTED
+            }
+            (_, Some(StatusCode::BAD_GATEWAY)) | (_, Some(StatusCode::SERVICE_UNAVAILABLE)) => {
+                Status::UNAVAILABLE
+            }
+            (METHOD_NOT_ALLOWED_ERROR_CODE, _) | (ACCESS_DENIED_ERROR_CODE, _) => {
+                Status::PERMISSION_DENIED
+            }
+            (METHOD_NOT_FOUND_ERROR_CODE, _) => Status::UNIMPLEMENTED,
+            (RPC_INVALID_ADDRESS_OR_KEY, _) => Status::NOT_FOUND,
+            (RPC_INVALID_PARAMETER, _) | (PARSE_ERROR_CODE, _) => Status::INVALID_ARGUMENT,
+            _ => Status::UNKNOWN,
+        };
+        Status {
+            code,
+            message: e.message,
+            rpc_code: Some(e.code),
+        }
+    }
+}
+impl From<Error> for Status {
+    fn from(e: Error) -> Self {
+        Status::new(Status::INTERNAL, format!("{:#}", e))
+    }
+}
+
+/// A field of a protobuf message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Field {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(Vec<u8>),
+    Fixed32(u32),
+}
+
+/// The fields of a decoded protobuf message, by number.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Message(pub Vec<(u32, Field)>);
+impl Message {
+    pub fn decode(mut data: &[u8]) -> Result<Self, Error> {
+        fn varint(data: &mut &[u8]) -> Result<u64, Error> {
+            let mut value = 0u64;
+            for shift in (0..64).step_by(7) {
+                let (byte, rest) = data
+                    .split_first()
+                    .ok_or_else(|| anyhow!("truncated varint"))?;
+                *data = rest;
+                value |= u64::from(byte & 0x7f) << shift;
+                if byte & 0x80 == 0 {
+                    return Ok(value);
+                }
+            }
+            Err(anyhow!("varint too long"))
+        }
+        fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8], Error> {
+            if data.len() < len {
+                return Err(anyhow!("truncated field"));
+            }
+            let (taken, rest) = data.split_at(len);
+            *data = rest;
+            Ok(taken)
+        }
+        let mut fields = Vec::new();
+        while !data.is_empty() {
+            let key = varint(&mut data)?;
+            let number = u32::try_from(key >> 3).map_err(|_| anyhow!("invalid field number"))?;
+            let field = match key & 7 {
+                0 => Field::Varint(varint(&mut data)?),
+                1 => Field::Fixed64(u64::from_le_bytes(take(&mut data, 8)?.try_into().unwrap())),
+                2 => {
+                    let len = varint(&mut data)?;
+                    Field::Bytes(take(&mut data, len as usize)?.to_vec())
+                }
+                5 => Field::Fixed32(u32::from_le_bytes(take(&mut data, 4)?.try_into().unwrap())),
+                wire_type => return Err(anyhow!("unsupported wire type {}", wire_type)),
+            };
+            fields.push((number, field));
+        }
+        Ok(Message(fields))
+    }
+
+    /// The last occurrence of field `number`, which wins in protobuf.
+    fn get(&self, number: u32) -> Option<&Field> {
+        self.0
+            .iter()
+            .rev()
+            .find(|(n, _)| *n == number)
+            .map(|(_, f)| f)
+    }
+
+    pub fn uint64(&self, number: u32) -> Result<u64, Status> {
+        match self.get(number) {
+            None => Ok(0),
+            Some(Field::Varint(value)) => Ok(*value),
+            Some(_) => Err(invalid_field(number)),
+        }
+    }
+
+    pub fn bytes(&self, number: u32) -> Result<&[u8], Status> {
+        match self.get(number) {
+            None => Ok(&[]),
+            Some(Field::Bytes(value)) => Ok(value),
+            Some(_) => Err(invalid_field(number)),
+        }
+    }
+
+    pub fn string(&self, number: u32) -> Result<&str, Status> {
+        std::str::from_utf8(self.bytes(number)?).map_err(|_| invalid_field(number))
+    }
+}
+
+fn invalid_field(number: u32) -> Status {
+    Status::new(
+        Status::INVALID_ARGUMENT,
+        format!("invalid field {}", number),
+    )
+}
+
+/// Encodes a protobuf message, leaving out fields with default values like proto3 does.
+#[derive(Debug, Default)]
+pub struct Encoder(pub Vec<u8>);
+impl Encoder {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    pub fn uint64(mut self, number: u32, value: u64) -> Self {
+        if value != 0 {
+            self.varint(u64::from(number) << 3);
+            self.varint(value);
+        }
+        self
+    }
+
+    /// `int32` and `int64`, negative values taking ten bytes.
+    pub fn int64(self, number: u32, value: i64) -> Self {
+        self.uint64(number, value as u64)
+    }
+
+    pub fn double(mut self, number: u32, value: f64) -> Self {
+        if value != 0.0 {
+            self.varint(u64::from(number) << 3 | 1);
+            self.0.extend_from_slice(&value.to_le_bytes());
+        }
+        self
+    }
+
+    pub fn bytes(mut self, number: u32, value: &[u8]) -> Self {
+        if !value.is_empty() {
+            self.varint(u64::from(number) << 3 | 2);
+            self.varint(value.len() as u64);
+            self.0.extend_from_slice(value);
+        }
+        self
+    }
+
+    pub fn string(self, number: u32, value: &str) -> Self {
+        self.bytes(number, value.as_bytes())
+    }
+}
+
+/// A JSON-RPC call standing for a gRPC call.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Call {
+    pub method: &'static str,
+    pub params: Value,
+    pub wallet: Option<String>,
+    /// For the generic call, the method given by the client
+    pub generic: Option<String>,
+}
+impl Call {
+    fn new(method: &'static str, params: Value) -> Self {
+        Call {
+            method,
+            params,
+            wallet: None,
+            generic: None,
+        }
+    }
+
+    /// The call of gRPC method `name` with the request `message`.
+    pub fn from_grpc(name: &str, message: &Message) -> Result<Self, Status> {
+        Ok(match name {
+            "Call" => {
+                let params = match message.string(2)? {
+                    "" => json!([]),
+                    params => serde_json::from_str(params)
+                        .ok()
+                        .filter(Value::is_array)
+                        .ok_or_else(|| {
+                            Status::new(Status::INVALID_ARGUMENT, "params have to be a JSON array")
+                        })?,
+                };
+                Call {
+                    generic: Some(message.string(1)?.to_owned()),
+                    wallet: Some(message.string(3)?.to_owned()).filter(|w| !w.is_empty()),
+                    ..Call::new("", params)
+                }
+            }
+            "GetBlockCount" => Call::new("getblockcount", json!([])),
+            "GetBestBlockHash" => Call::new("getbestblockhash", json!([])),
+            "GetBlockHash" => Call::new("getblockhash", json!([message.uint64(1)?])),
+            "GetBlockHeader" => Call::new("getblockheader", json!([message.string(1)?, true])),
+            "GetRawTransaction" => Call::new("getrawtransaction", json!([message.string(1)?])),
+            "SendRawTransaction" => Call::new(
+                "sendrawtransaction",
+                json!([hex::encode(message.bytes(1)?)]),
+            ),
+            "EstimateSmartFee" => Call::new("estimatesmartfee", json!([message.uint64(1)?])),
+            "GetBalances" => Call {
+                wallet: Some(message.string(1)?.to_owned()).filter(|w| !w.is_empty()),
+                ..Call::new("getbalances", json!([]))
+            },
+            name => {
+                return Err(Status::new(
+                    Status::UNIMPLEMENTED,
+                    format!("unknown method {}", name),
+                ))
+            }
+        })
+    }
+
+    pub fn method(&self) -> &str {
+        self.generic.as_deref().unwrap_or(self.method)
+    }
+
+    /// The response message for the `result` of the call.
+    pub fn encode_result(&self, result: &Value) -> Result<Vec<u8>, Status> {
+        let unexpected = || Status::new(Status::INTERNAL, format!("unexpected result {}", result));
+        let str_of = |value: &Value| value.as_str().map(str::to_owned).unwrap_or_default();
+        let encoder = match self.method {
+            "" => Encoder::default().string(1, &result.to_string()),
+            "getblockcount" => {
+                Encoder::default().uint64(1, result.as_u64().ok_or_else(unexpected)?)
+            }
+            "getbestblockhash" | "getblockhash" => {
+                Encoder::default().string(1, result.as_str().ok_or_else(unexpected)?)
+            }
+            "getblockheader" => Encoder::default()
+                .string(1, &str_of(&result["hash"]))
+                .uint64(2, result["height"].as_u64().unwrap_or_default())
+                .int64(3, result["version"].as_i64().unwrap_or_default())
+                .string(4, &str_of(&result["previousblockhash"]))
+                .string(5, &str_of(&result["merkleroot"]))
+                .uint64(6, result["time"].as_u64().unwrap_or_default())
+                .uint64(7, result["mediantime"].as_u64().unwrap_or_default())
+                .uint64(8, result["nonce"].as_u64().unwrap_or_default())
+                .string(9, &str_of(&result["bits"]))
+                .double(10, result["difficulty"].as_f64().unwrap_or_default())
+                .int64(11, result["confirmations"].as_i64().unwrap_or_default())
+                .uint64(12, result["nTx"].as_u64().unwrap_or_default()),
+            "getrawtransaction" => Encoder::default().bytes(
+                1,
+                &hex::decode(result.as_str().ok_or_else(unexpected)?).map_err(|_| unexpected())?,
+            ),
+            "sendrawtransaction" => {
+                Encoder::default().string(1, result.as_str().ok_or_else(unexpected)?)
+            }
+            "estimatesmartfee" => Encoder::default()
+                .uint64(
+                    1,
+                    crate::sats::to_sats(&result["feerate"]).unwrap_or_default() as u64,
+                )
+                .uint64(2, result["blocks"].as_u64().unwrap_or_default()),
+            "getbalances" => {
+                let sats =
+                    |key: &str| crate::sats::to_sats(&result["mine"][key]).unwrap_or_default();
+                Encoder::default()
+                    .int64(1, sats("trusted"))
+                    .int64(2, sats("untrusted_pending"))
+                    .int64(3, sats("immature"))
+            }
+            _ => return Err(unexpected()),
+        };
+        Ok(encoder.0)
+    }
+}
+
+/// The message of a request body: a compression flag, the length and the message.
+pub fn unframe(body: &[u8]) -> Result<&[u8], Status> {
+    match body {
+        [0, len @ ..] if len.len() >= 4 => {
+            let (len, message) = len.split_at(4);
+            if u32::from_be_bytes(len.try_into().unwrap()) as usize != message.len() {
+                return Err(Status::new(
+                    Status::INVALID_ARGUMENT,
+                    "expected a single message",
+                ));
+            }
+            Ok(message)
+        }
+        [1, ..] => Err(Status::new(
+            Status::UNIMPLEMENTED,
+            "compressed messages aren't supported",
+        )),
+        _ => Err(Status::new(
+            Status::INVALID_ARGUMENT,
+            "invalid message frame",
+        )),
+    }
+}
+
+pub fn frame(message: &[u8]) -> Bytes {
+    let mut framed = Vec::with_capacity(message.len() + 5);
+    framed.push(0);
+    framed.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    framed.extend_from_slice(message);
+    framed.into()
+}
+
+/// The `grpc-status` and `grpc-message` of a call.
+fn status_headers(status: Option<&Status>) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    let (code, message) = status.map_or((0, ""), |s| (s.code, s.message.as_str()));
+    headers.insert("grpc-status", code.into());
+    if !message.is_empty() {
+        // percent-encoded as required
+        let encoded: String = message
+            .bytes()
+            .map(|b| match b {
+                b' '..=b'~' if b != b'%' => (b as char).to_string(),
+                b => format!("%{:02X}", b),
+            })
+            .collect();
+        if let Ok(value) = HeaderValue::from_str(&encoded) {
+            headers.insert("grpc-message", value);
+        }
+    }
+    if let Some(code) = status.and_then(|s| s.rpc_code) {
+        headers.insert("rpc-error-code", code.into());
+    }
+    headers
+}
+
+async fn read_body(mut body: Body) -> Result<Vec<u8>, Status> {
+    let mut data = Vec::new();
+    while let Some(chunk) = body.next().await {
+        data.extend_from_slice(&chunk.map_err(|e| Status::from(Error::from(e)))?);
+        if data.len() > MAX_MESSAGE_SIZE + 5 {
+            return Err(Status::new(Status::RESOURCE_EXHAUSTED, "message too large"));
+        }
+    }
+    Ok(data)
+}
+
+/// Boxed with a named type, as the handler of requests awaits it in turn.
+fn forward(
+    state: Arc<State>,
+    request: Request<Body>,
+) -> BoxFuture<'static, Result<Response<Body>, Error>> {
+    crate::proxy::handle_request(state, request).boxed()
+}
+
+/// Forwards `call` through the JSON-RPC path with the headers of the gRPC call.
+async fn dispatch(state: Arc<State>, parts: &Parts, call: &Call) -> Result<Value, Status> {
+    let path = match &call.wallet {
+        Some(wallet) => crate::paths::wallet_path(wallet),
+        None => "/".to_owned(),
+    };
+    let body = serde_json::to_vec(&json!({
+        "jsonrpc": "1.0",
+        "id": 0,
+        "method": call.method(),
+        "params": call.params,
+    }))
+    .map_err(Error::from)?;
+    let mut request = Request::post(path)
+        .body(Body::from(body))
+        .map_err(Error::from)?;
+    for (name, value) in parts.headers.iter() {
+        if !name.as_str().starts_with("grpc-") && !name.as_str().starts_with(':') {
+            request.headers_mut().append(name, value.clone());
+        }
+    }
+    request
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    request.headers_mut().remove(CONTENT_LENGTH);
+    if let Some(remote_addr) = parts.extensions.get::<std::net::SocketAddr>() {
+        request.extensions_mut().insert(*remote_addr);
+    }
+    if let Some(client_cert) = parts.extensions.get::<crate::users::ClientCert>() {
+        request.extensions_mut().insert(client_cert.clone());
+    }
+    let response = forward(state, request).await.map_err(Status::from)?;
+    if response.status() == StatusCode::UNAUTHORIZED {
+        return Err(Status::new(
+            Status::UNAUTHENTICATED,
+            "invalid or no credentials",
+        ));
+    }
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(|e| Status::from(Error::from(e)))?;
+    let response: Value = serde_json::from_slice(&body).map_err(|_| {
+        Status::new(
+            Status::UNKNOWN,
+            format!("unexpected response with status {}", status),
+        )
+    })?;
+    match response.get("error").filter(|e| !e.is_null()) {
+        Some(error) => {
+            let mut error: RpcError = serde_json::from_value(error.clone()).map_err(Error::from)?;
+            error.status = Some(status);
+            Err(error.into())
+        }
+        None => Ok(response["result"].clone()),
+    }
+}
+
+/// Answers a gRPC call to `/btc_rpc_proxy.v1.BitcoinRpc/<method>`.
+pub async fn serve(state: Arc<State>, parts: Parts, body: Body) -> Result<Response<Body>, Error> {
+    if !state.grpc {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())?);
+    }
+    let name = parts.uri.path()[SERVICE_PATH.len()..].to_owned();
+    let res = async {
+        let data = read_body(body).await?;
+        let message = Message::decode(unframe(&data)?)
+            .map_err(|e| Status::new(Status::INVALID_ARGUMENT, format!("{:#}", e)))?;
+        let call = Call::from_grpc(&name, &message)?;
+        let result = dispatch(state.clone(), &parts, &call).await?;
+        call.encode_result(&result)
+    }
+    .await;
+    let mut response = Response::builder().header(CONTENT_TYPE, "application/grpc");
+    match res {
+        Ok(message) => {
+            let mut response = response.body(Body::from(frame(&message)))?;
+            response
+                .extensions_mut()
+                .insert(Trailers(status_headers(None)));
+            Ok(response)
+        }
+        // trailers-only
+        Err(status) => {
+            debug!(state.logger, "gRPC call {} failed: {:?}", name, status);
+            for (name, value) in status_headers(Some(&status)).iter() {
+                response = response.header(name, value);
+            }
+            Ok(response.body(Body::empty())?)
+        }
+    }
+}
+
+/// Trailers sent after the body of a response, which `Body` can't carry by itself.
+#[derive(Debug, Clone)]
+pub struct Trailers(pub HeaderMap);
+
+/// The body of responses of the server: a `Body` followed by the [`Trailers`] of the response.
+#[derive(Debug)]
+pub struct TrailersBody {
+    body: Body,
+    trailers: Option<HeaderMap>,
+}
+impl TrailersBody {
+    pub fn wrap(response: Response<Body>) -> Response<Self> {
+        let (mut parts, body) = response.into_parts();
+        let trailers = parts.extensions.remove::<Trailers>().map(|t| t.0);
+        Response::from_parts(parts, TrailersBody { body, trailers })
+    }
+}
+impl HttpBody for TrailersBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, hyper::Error>>> {
+        Pin::new(&mut self.body).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, hyper::Error>> {
+        match self.trailers.take() {
+            Some(trailers) => Poll::Ready(Ok(Some(trailers))),
+            None => Pin::new(&mut self.body).poll_trailers(cx),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.trailers.is_none() && self.body.is_end_stream()
+    }
+}
@@ -0,0 +1,415 @@
+//! Operator policies on how funding calls select coins and pay fees, enforced by rejecting the
+//! calls violating them or by rewriting their parameters.
+
+use bitcoin::consensus::encode::deserialize;
+use bitcoin::{Transaction, Txid};
+use hyper::StatusCode;
+use serde_json::{json, Map, Value};
+
+use crate::client::{GenericRpcMethod, RpcError, RpcRequest, POLICY_VIOLATION_ERROR_CODE};
+use crate::state::State;
+
+/// BTC/kvB in sat/vB, the unit of `feeRate` and `fee_rate` respectively.
+const SAT_PER_VB_PER_BTC_PER_KVB: f64 = 100_000.0;
+
+/// What to do with calls violating a guardrail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Allow,
+    Deny,
+    /// Rewrite the parameters to comply, denying calls that can't be rewritten
+    Rewrite,
+}
+impl std::str::FromStr for Action {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(Action::Allow),
+            "deny" => Ok(Action::Deny),
+            "rewrite" => Ok(Action::Rewrite),
+            _ => Err(anyhow::anyhow!(
+                "unknown action {}, expected allow, deny or rewrite",
+                s
+            )),
+        }
+    }
+}
+
+/// Where a funding method takes the guarded parameters.
+struct Funding {
+    /// Position of the options object
+    options: Option<usize>,
+    /// Position of the fee rate in sat/vB
+    fee_rate: Option<usize>,
+    /// Position of the parameter subtracting the fee from outputs
+    subtract_fee: Option<usize>,
+    /// Name of the option subtracting the fee from outputs
+    subtract_fee_option: Option<&'static str>,
+    /// Whether the options accept `minconf`
+    minconf: bool,
+    /// Position of the `replaceable` parameter, if it isn't an option
+    replaceable: Option<usize>,
+}
+
+fn funding(method: &str) -> Option<Funding> {
+    Some(match method {
+        "fundrawtransaction" | "walletcreatefundedpsbt" => Funding {
+            options: Some(if method == "fundrawtransaction" { 1 } else { 3 }),
+            fee_rate: None,
+            subtract_fee: None,
+            subtract_fee_option: Some("subtractFeeFromOutputs"),
+            minconf: true,
+            replaceable: None,
+        },
+        "send" | "sendall" => Funding {
+            options: Some(4),
+            fee_rate: Some(3),
+            subtract_fee: None,
+            subtract_fee_option: Some("subtract_fee_from_outputs").filter(|_| method == "send"),
+            minconf: true,
+            replaceable: None,
+        },
+        "sendtoaddress" | "sendmany" => Funding {
+            options: None,
+            fee_rate: Some(if method == "sendtoaddress" { 9 } else { 8 }),
+            subtract_fee: Some(4),
+            subtract_fee_option: None,
+            minconf: false,
+            replaceable: Some(5),
+        },
+        _ => return None,
+    })
+}
+
+fn violation(req: &RpcRequest<GenericRpcMethod>, guardrail: &str, message: String) -> RpcError {
+    RpcError {
+        code: POLICY_VIOLATION_ERROR_CODE,
+        message,
+        data: Some(json!({ "method": req.method.0, "guardrail": guardrail })),
+        status: Some(StatusCode::FORBIDDEN),
+    }
+}
+
+/// Whether a `subtractfeefromamount` flag or list of outputs subtracts the fee from any output.
+fn subtracts(value: &Value) -> bool {
+    match value {
+        Value::Bool(b) => *b,
+        Value::Array(outputs) => !outputs.is_empty(),
+        _ => false,
+    }
+}
+
+/// The options object of a call, created if missing. `fundrawtransaction` also accepts a bare
+/// `include_watching` flag instead.
+fn options_mut(params: &mut Vec<Value>, i: usize) -> &mut Map<String, Value> {
+    if params.len() <= i {
+        params.resize(i + 1, Value::Null);
+    }
+    let options = &mut params[i];
+    match options {
+        Value::Object(_) => (),
+        Value::Bool(include_watching) => {
+            *options = json!({ "includeWatching": *include_watching });
+        }
+        _ => *options = Value::Object(Map::new()),
+    }
+    options.as_object_mut().unwrap()
+}
+
+fn options(params: &[Value], funding: &Funding) -> Option<Map<String, Value>> {
+    params
+        .get(funding.options?)
+        .and_then(Value::as_object)
+        .cloned()
+}
+
+/// Inputs chosen by the caller instead of by coin selection.
+fn preselected_inputs(req: &RpcRequest<GenericRpcMethod>, funding: &Funding) -> Vec<(Txid, u32)> {
+    let from_json = |inputs: Option<&Value>| -> Vec<(Txid, u32)> {
+        inputs
+            .and_then(Value::as_array)
+            .map(|inputs| {
+                inputs
+                    .iter()
+                    .filter_map(|i| {
+                        Some((
+                            i.get("txid")?.as_str()?.parse().ok()?,
+                            i.get("vout")?.as_u64()? as u32,
+                        ))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    match req.method.0.as_str() {
+        "fundrawtransaction" => req
+            .params
+            .first()
+            .and_then(Value::as_str)
+            .and_then(|tx| hex::decode(tx).ok())
+            .and_then(|tx| deserialize::<Transaction>(&tx).ok())
+            .map(|tx| {
+                tx.input
+                    .iter()
+                    .map(|i| (i.previous_output.txid, i.previous_output.vout))
+                    .collect()
+            })
+            .unwrap_or_default(),
+        "walletcreatefundedpsbt" => from_json(req.params.first()),
+        _ => from_json(
+            options(&req.params, funding)
+                .as_ref()
+                .and_then(|o| o.get("inputs")),
+        ),
+    }
+}
+
+/// Coin selection and fee policies applying to the funding calls of all users.
+#[derive(Debug)]
+pub struct Guardrails {
+    /// Calls that may spend unconfirmed outputs
+    pub unconfirmed_inputs: Action,
+    /// Calls subtracting the fee from the amounts sent
+    pub subtract_fee: Action,
+    /// Explicit fee rates below `min_fee_rate` or above `max_fee_rate` (sat/vB)
+    pub fee_rate: Action,
+    pub min_fee_rate: Option<f64>,
+    pub max_fee_rate: Option<f64>,
+    /// Calls opting out of replace-by-fee
+    pub replaceable: Action,
+    /// Most BTC of fee `proxy_bumpstuck` adds to a single transaction
+    pub bump_max_fee: Option<f64>,
+}
+impl Default for Guardrails {
+    fn default() -> Self {
+        Guardrails {
+            unconfirmed_inputs: Action::Allow,
+            subtract_fee: Action::Allow,
+            fee_rate: Action::Deny,
+            min_fee_rate: None,
+            max_fee_rate: None,
+            replaceable: Action::Allow,
+            bump_max_fee: None,
+        }
+    }
+}
+impl Guardrails {
+    fn is_active(&self) -> bool {
+        self.unconfirmed_inputs != Action::Allow
+            || self.subtract_fee != Action::Allow
+            || self.replaceable != Action::Allow
+            || (self.fee_rate != Action::Allow
+                && (self.min_fee_rate.is_some() || self.max_fee_rate.is_some()))
+    }
+
+    /// Checks a fee rate given in sat/vB times `scale`, clamping it if rewriting.
+    fn check_fee_rate(
+        &self,
+        req: &RpcRequest<GenericRpcMethod>,
+        value: &mut Value,
+        scale: f64,
+    ) -> Result<bool, RpcError> {
+        let rate = match value {
+            Value::Number(n) => n.as_f64(),
+            Value::String(s) => s.parse().ok(),
+            _ => None,
+        };
+        let rate = match rate {
+            Some(rate) => rate * scale,
+            None => return Ok(false),
+        };
+        let clamped = match (self.min_fee_rate, self.max_fee_rate) {
+            (Some(min), _) if rate < min => min,
+            (_, Some(max)) if rate > max => max,
+            _ => return Ok(false),
+        };
+        match self.fee_rate {
+            Action::Allow => Ok(false),
+            Action::Deny => Err(violation(
+                req,
+                "fee_rate",
+                format!(
+                    "Fee rate {} sat/vB is outside of the allowed range {} to {} sat/vB",
+                    rate,
+                    self.min_fee_rate.unwrap_or(0.0),
+                    self.max_fee_rate
+                        .map_or_else(|| "unlimited".to_owned(), |max| max.to_string())
+                ),
+            )),
+            Action::Rewrite => {
+                *value = (clamped / scale).into();
+                Ok(true)
+            }
+        }
+    }
+
+    /// Checks a funding call against the guardrails, returning the call with rewritten
+    /// parameters if they had to be changed.
+    pub async fn apply(
+        &self,
+        state: &State,
+        req: &RpcRequest<GenericRpcMethod>,
+    ) -> Result<Option<RpcRequest<GenericRpcMethod>>, RpcError> {
+        let funding = match funding(&req.method) {
+            Some(funding) if self.is_active() => funding,
+            _ => return Ok(None),
+        };
+        let mut params = req.params.clone();
+        let mut rewritten = false;
+
+        if self.subtract_fee != Action::Allow {
+            if req.method.0 == "sendall" {
+                return Err(violation(
+                    req,
+                    "subtract_fee",
+                    "sendall always subtracts the fee from the amount sent".to_owned(),
+                ));
+            }
+            let deny = |what: &str| {
+                violation(
+                    req,
+                    "subtract_fee",
+                    format!(
+                        "Subtracting the fee from the amount sent ({}) is not allowed",
+                        what
+                    ),
+                )
+            };
+            if let Some(value) = funding.subtract_fee.and_then(|i| params.get_mut(i)) {
+                if subtracts(value) {
+                    if self.subtract_fee == Action::Deny {
+                        return Err(deny(if req.method.0 == "sendtoaddress" {
+                            "subtractfeefromamount"
+                        } else {
+                            "subtractfeefrom"
+                        }));
+                    }
+                    *value = match value {
+                        Value::Array(_) => json!([]),
+                        _ => false.into(),
+                    };
+                    rewritten = true;
+                }
+            }
+            if let Some(name) = funding.subtract_fee_option {
+                if options(&params, &funding)
+                    .and_then(|o| o.get(name).cloned())
+                    .is_some_and(|v| subtracts(&v))
+                {
+                    if self.subtract_fee == Action::Deny {
+                        return Err(deny(name));
+                    }
+                    options_mut(&mut params, funding.options.unwrap()).remove(name);
+                    rewritten = true;
+                }
+            }
+        }
+
+        if self.fee_rate != Action::Allow {
+            if let Some(value) = funding.fee_rate.and_then(|i| params.get_mut(i)) {
+                rewritten |= self.check_fee_rate(req, value, 1.0)?;
+            }
+            if let Some(i) = funding.options {
+                if let Some(Value::Object(options)) = params.get_mut(i) {
+                    if let Some(value) = options.get_mut("fee_rate") {
+                        rewritten |= self.check_fee_rate(req, value, 1.0)?;
+                    }
+                    if let Some(value) = options.get_mut("feeRate") {
+                        rewritten |= self.check_fee_rate(req, value, SAT_PER_VB_PER_BTC_PER_KVB)?;
+                    }
+                }
+            }
+        }
+
+        if self.replaceable != Action::Allow {
+            let replaceable = match funding.replaceable {
+                Some(i) => params.get(i).cloned(),
+                None => options(&params, &funding).and_then(|o| o.get("replaceable").cloned()),
+            };
+            match self.replaceable {
+                Action::Deny if replaceable == Some(Value::Bool(false)) => {
+                    return Err(violation(
+                        req,
+                        "replaceable",
+                        format!("{} must not opt out of replace-by-fee", req.method.0),
+                    ));
+                }
+                // null parameters are left to bitcoind's defaults
+                Action::Rewrite if replaceable != Some(Value::Bool(true)) => {
+                    match funding.replaceable {
+                        Some(i) => {
+                            if params.len() <= i {
+                                params.resize(i + 1, Value::Null);
+                            }
+                            params[i] = true.into();
+                        }
+                        None => {
+                            options_mut(&mut params, funding.options.unwrap())
+                                .insert("replaceable".into(), true.into());
+                        }
+                    }
+                    rewritten = true;
+                }
+                _ => (),
+            }
+        }
+
+        if self.unconfirmed_inputs != Action::Allow {
+            if !funding.minconf {
+                return Err(violation(
+                    req,
+                    "unconfirmed_inputs",
+                    format!(
+                        "{} can't be restricted to confirmed inputs, use send with the minconf \
+                         option",
+                        req.method.0
+                    ),
+                ));
+            }
+            let minconf = options(&params, &funding)
+                .and_then(|o| o.get("minconf").and_then(Value::as_u64))
+                .unwrap_or(0);
+            if minconf < 1 {
+                if self.unconfirmed_inputs == Action::Deny {
+                    return Err(violation(
+                        req,
+                        "unconfirmed_inputs",
+                        format!("{} must set the minconf option to at least 1", req.method.0),
+                    ));
+                }
+                options_mut(&mut params, funding.options.unwrap())
+                    .insert("minconf".into(), 1.into());
+                rewritten = true;
+            }
+            for (txid, vout) in preselected_inputs(req, &funding) {
+                let txout = state
+                    .rpc_client
+                    .call(&RpcRequest {
+                        id: None,
+                        method: GenericRpcMethod("gettxout".to_owned()),
+                        params: vec![json!(txid), vout.into(), false.into()],
+                    })
+                    .await?
+                    .into_result()?;
+                if txout.is_null() {
+                    return Err(violation(
+                        req,
+                        "unconfirmed_inputs",
+                        format!("Input {}:{} is unconfirmed or spent", txid, vout),
+                    ));
+                }
+            }
+        }
+
+        Ok(if rewritten {
+            Some(RpcRequest {
+                id: req.id.clone(),
+                method: GenericRpcMethod(req.method.0.clone()),
+                params,
+            })
+        } else {
+            None
+        })
+    }
+}
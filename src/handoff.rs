@@ -0,0 +1,207 @@
+//! Zero-downtime restarts: a new proxy process takes over the listening sockets of the running
+//! one through its control socket, after which the old process stops accepting connections and
+//! exits once its in-flight requests are answered. Since the sockets themselves stay open, clients
+//! connecting in between wait in their backlog instead of being refused.
+//!
+//! The processes exchange lines over the control socket (see `control`): the new one sends
+//! `takeover` and the old one answers `listeners`, passing the sockets along (`SCM_RIGHTS`). The
+//! new process keeps those it is configured to listen on, matched by address, and binds the
+//! others. Once it serves, it sends `ready`, the old one stops accepting and answers `draining`,
+//! and the new process binds the control socket itself.
+
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+use tokio::io::AsyncBufReadExt;
+
+use crate::listen::{Address, Listener};
+use crate::state::State;
+
+/// How long either process waits for the other to answer.
+pub const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The most listening sockets that are handed over.
+const MAX_LISTENERS: usize = 64;
+
+fn fds_len(n: usize) -> u32 {
+    (n * std::mem::size_of::<RawFd>()) as u32
+}
+
+/// Sends `message` along with the file descriptors `fds`.
+fn send_fds(socket: RawFd, fds: &[RawFd], message: &[u8]) -> std::io::Result<()> {
+    let mut iov = libc::iovec {
+        iov_base: message.as_ptr() as *mut libc::c_void,
+        iov_len: message.len(),
+    };
+    let space = unsafe { libc::CMSG_SPACE(fds_len(fds.len())) } as usize;
+    let mut control = vec![0u8; space];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len(fds.len())) as _;
+        let data = libc::CMSG_DATA(cmsg) as *mut RawFd;
+        for (i, fd) in fds.iter().enumerate() {
+            std::ptr::write_unaligned(data.add(i), *fd);
+        }
+        if libc::sendmsg(socket, &msg, 0) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Receives a message along with the file descriptors sent with it.
+fn recv_fds(socket: RawFd) -> std::io::Result<(Vec<u8>, Vec<RawFd>)> {
+    let mut buf = vec![0u8; 64];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let space = unsafe { libc::CMSG_SPACE(fds_len(MAX_LISTENERS)) } as usize;
+    let mut control = vec![0u8; space];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+    let (len, fds) = unsafe {
+        let len = libc::recvmsg(socket, &mut msg, 0);
+        if len < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        let mut fds = Vec::new();
+        if !cmsg.is_null()
+            && (*cmsg).cmsg_level == libc::SOL_SOCKET
+            && (*cmsg).cmsg_type == libc::SCM_RIGHTS
+        {
+            let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+            let n = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize)
+                / std::mem::size_of::<RawFd>();
+            for i in 0..n {
+                fds.push(std::ptr::read_unaligned(data.add(i)));
+            }
+        }
+        (len as usize, fds)
+    };
+    buf.truncate(len);
+    Ok((buf, fds))
+}
+
+/// The connection to the process whose listening socket was taken over.
+pub struct Takeover {
+    stream: UnixStream,
+}
+impl Takeover {
+    /// Tells the previous process to stop accepting connections, returning once it has.
+    pub fn finish(self) -> Result<(), Error> {
+        (&self.stream).write_all(b"ready\n")?;
+        let mut line = String::new();
+        BufReader::new(&self.stream).read_line(&mut line)?;
+        match line.trim() {
+            "draining" => Ok(()),
+            answer => Err(anyhow!(
+                "unexpected answer of the previous process: {:?}",
+                answer
+            )),
+        }
+    }
+}
+
+/// Asks the process serving the control socket at `stream` for its listening sockets.
+fn take_over(stream: UnixStream) -> Result<(Vec<Listener>, Takeover), Error> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    (&stream).write_all(b"takeover\n")?;
+    let (message, fds) = recv_fds(stream.as_raw_fd())?;
+    // owned right away, so that they are closed if the takeover fails
+    let listeners = fds
+        .into_iter()
+        .map(|fd| unsafe { Listener::from_raw_fd(fd) })
+        .collect();
+    match message.as_slice() {
+        b"listeners\n" => Ok((listeners, Takeover { stream })),
+        _ => Err(anyhow!(
+            "the previous process refused the takeover: {}",
+            String::from_utf8_lossy(&message).trim()
+        )),
+    }
+}
+
+/// The sockets to listen on, in the order of `state.listen`: those of the process serving the
+/// control socket if it is running and listens on the same addresses, newly bound ones otherwise.
+pub fn listen(state: &State) -> Result<(Vec<Listener>, Option<Takeover>), Error> {
+    let mut taken = Vec::new();
+    let mut takeover = None;
+    if let Some(path) = &state.control_socket {
+        match UnixStream::connect(path) {
+            Ok(stream) => {
+                let (listeners, t) = take_over(stream)
+                    .map_err(|e| anyhow!("failed to take over from {}: {:#}", path.display(), e))?;
+                for listener in listeners {
+                    taken.push((listener.address()?, listener));
+                }
+                takeover = Some(t);
+            }
+            // nothing is running, a stale socket is replaced when serving
+            Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => {}
+            Err(e) => return Err(anyhow!("failed to connect to {}: {}", path.display(), e)),
+        }
+    }
+    let mut listeners = Vec::new();
+    for listen in &state.listen {
+        match taken
+            .iter()
+            .position(|(address, _)| *address == listen.address)
+        {
+            Some(i) => {
+                info!(
+                    state.logger,
+                    "Took over listening on {} from the running process", listen.address
+                );
+                listeners.push(taken.swap_remove(i).1);
+            }
+            None => listeners.push(
+                Listener::bind(&listen.address)
+                    .map_err(|e| anyhow!("failed to listen on {}: {}", listen.address, e))?,
+            ),
+        }
+    }
+    for (address, _) in taken {
+        info!(
+            state.logger,
+            "Stopped listening on {}, which is no longer configured", address
+        );
+        if let Address::Unix(path) = address {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+    Ok((listeners, takeover))
+}
+
+/// Passes `listeners` to the new process connected to the control socket with `stream`, which
+/// asked for them with `takeover`, returning once it serves.
+pub async fn hand_over(
+    stream: &mut tokio::io::BufReader<tokio::net::UnixStream>,
+    listeners: &[Listener],
+) -> Result<(), Error> {
+    let fds: Vec<RawFd> = listeners.iter().map(|l| l.as_raw_fd()).collect();
+    send_fds(stream.get_ref().as_raw_fd(), &fds, b"listeners\n")?;
+    let mut line = String::new();
+    tokio::time::timeout(TIMEOUT, stream.read_line(&mut line))
+        .await
+        .map_err(|_| anyhow!("the new process didn't get ready in time"))??;
+    if line.trim() != "ready" {
+        return Err(anyhow!("the new process failed to start"));
+    }
+    Ok(())
+}
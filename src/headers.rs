@@ -0,0 +1,55 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Error;
+use hyper::{
+    header::{HeaderName, HeaderValue, CONTENT_LENGTH, TRANSFER_ENCODING},
+    Body, Response,
+};
+
+/// Controls which headers the proxy sends back to its clients.
+#[derive(Debug, Default)]
+pub struct ResponseHeaders {
+    /// Headers forwarded from bitcoind, `None` forwards all of them
+    forward: Option<HashSet<HeaderName>>,
+    /// Headers added to every response, replacing any forwarded value
+    inject: Vec<(HeaderName, HeaderValue)>,
+}
+impl ResponseHeaders {
+    pub fn new(
+        forward: Option<Vec<String>>,
+        inject: HashMap<String, String>,
+    ) -> Result<Self, Error> {
+        Ok(ResponseHeaders {
+            forward: forward
+                .map(|names| names.iter().map(|n| n.parse()).collect())
+                .transpose()?,
+            inject: inject
+                .into_iter()
+                .map(|(n, v)| Ok((n.parse()?, v.parse()?)))
+                .collect::<Result<_, Error>>()?,
+        })
+    }
+
+    /// Drops the headers of a response of bitcoind that aren't forwarded. Responses written by the
+    /// proxy itself are left alone.
+    pub fn filter(&self, response: &mut Response<Body>) {
+        let headers = response.headers_mut();
+        if let Some(forward) = &self.forward {
+            let names: Vec<HeaderName> = headers.keys().cloned().collect();
+            for name in names {
+                // the body is passed on as bitcoind framed it
+                if name != CONTENT_LENGTH && name != TRANSFER_ENCODING && !forward.contains(&name) {
+                    headers.remove(&name);
+                }
+            }
+        }
+    }
+
+    /// Adds the configured headers to a response about to be sent.
+    pub fn inject(&self, response: &mut Response<Body>) {
+        let headers = response.headers_mut();
+        for (name, value) in &self.inject {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+}
@@ -0,0 +1,213 @@
+//! Fetching ranges of blocks headers-first, like bitcoind's initial block download at small
+//! scale: the headers of the range are downloaded from a peer and checked to form a chain with
+//! valid proof of work connecting bitcoind's best chain at both ends, then the bodies are
+//! downloaded in parallel and checked against these headers.
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, Error};
+use bitcoin::{
+    blockdata::block::BlockHeader,
+    consensus::Encodable,
+    hash_types::BlockHash,
+    network::{message::NetworkMessage, message_blockdata::GetHeadersMessage},
+    Block,
+};
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{StreamExt, TryStreamExt};
+use hyper::StatusCode;
+use serde_json::Value;
+
+use crate::client::{
+    GenericRpcMethod, RpcError, RpcRequest, RpcResponse, ACCESS_DENIED_ERROR_CODE,
+};
+use crate::fetch_blocks::{fetch_validated_block, BitcoinPeerConnection, Fetcher};
+use crate::intercept::{InterceptResult, Interceptor, RequestContext};
+use crate::p2p::{Connection, Message};
+use crate::state::State;
+use crate::users::User;
+
+/// Most blocks fetched by a single call.
+pub const MAX_BLOCKS: u64 = 100;
+
+/// Bodies downloaded at once.
+const PARALLEL_DOWNLOADS: usize = 8;
+
+async fn block_hash(state: &State, height: u64) -> Result<BlockHash, RpcError> {
+    let hash = state
+        .rpc_client
+        .call(&RpcRequest {
+            id: None,
+            method: GenericRpcMethod("getblockhash".to_owned()),
+            params: vec![height.into()],
+        })
+        .await?
+        .into_result()?;
+    Ok(serde_json::from_value(hash).map_err(Error::from)?)
+}
+
+/// Asks a peer for the headers following `prev`, answering its pings meanwhile.
+fn request_headers(
+    conn: &mut BitcoinPeerConnection,
+    prev: BlockHash,
+) -> Result<Vec<BlockHeader>, Error> {
+    conn.send(NetworkMessage::GetHeaders(GetHeadersMessage::new(
+        vec![prev],
+        BlockHash::default(),
+    )))?;
+    loop {
+        match conn.receive()? {
+            Message::Known(NetworkMessage::Headers(headers)) => return Ok(headers),
+            Message::Known(NetworkMessage::Ping(n)) => conn.send(NetworkMessage::Pong(n))?,
+            _ => (),
+        }
+    }
+}
+
+/// Checks that `headers` form a chain with valid proof of work from `prev` to `last`.
+fn validate_headers(
+    headers: &[BlockHeader],
+    prev: BlockHash,
+    last: BlockHash,
+) -> Result<Vec<BlockHash>, Error> {
+    let mut hashes = Vec::with_capacity(headers.len());
+    let mut expected_prev = prev;
+    for header in headers {
+        if header.prev_blockhash != expected_prev {
+            return Err(anyhow!(
+                "header {} doesn't follow {}",
+                header.block_hash(),
+                expected_prev
+            ));
+        }
+        header
+            .validate_pow(&header.target())
+            .map_err(|e| anyhow!("header {}: {}", header.block_hash(), e))?;
+        expected_prev = header.block_hash();
+        hashes.push(expected_prev);
+    }
+    if expected_prev != last {
+        return Err(anyhow!(
+            "headers end at {} instead of {}",
+            expected_prev,
+            last
+        ));
+    }
+    Ok(hashes)
+}
+
+/// Hashes of the `count` blocks of bitcoind's best chain starting at `start`, synced from the best
+/// scoring peer delivering valid headers. Falls back to asking bitcoind for every hash.
+async fn sync_headers(
+    state: Arc<State>,
+    start: u64,
+    count: u64,
+) -> Result<Vec<BlockHash>, RpcError> {
+    let last = block_hash(&state, start + count - 1).await?;
+    if start > 0 {
+        let prev = block_hash(&state, start - 1).await?;
+        for mut peer in state.clone().get_peers().await? {
+            let addr = peer.addr().clone();
+            let res = async {
+                let mut conn = peer.connect(state.clone()).await?;
+                let (headers, conn) = tokio::time::timeout(
+                    conn.timeout,
+                    tokio::task::spawn_blocking(move || {
+                        request_headers(&mut conn, prev).map(|headers| (headers, conn))
+                    }),
+                )
+                .await???;
+                let hashes =
+                    validate_headers(&headers[..headers.len().min(count as usize)], prev, last)?;
+                conn.recycle();
+                Ok::<_, Error>(hashes)
+            }
+            .await;
+            match res {
+                Ok(hashes) => return Ok(hashes),
+                Err(e) => {
+                    state.address_book.record(&addr, false);
+                    debug!(state.logger, "Failed to sync headers from peer: {:#}", e);
+                }
+            }
+        }
+    }
+    debug!(
+        state.logger,
+        "No peer delivered headers {}-{}, asking bitcoind",
+        start,
+        start + count - 1
+    );
+    futures::stream::iter(start..start + count)
+        .map(|height| block_hash(&state, height))
+        .buffered(PARALLEL_DOWNLOADS)
+        .try_collect()
+        .await
+}
+
+/// Fetches the `count` blocks of bitcoind's best chain starting at height `start`.
+pub async fn fetch_range(
+    state: Arc<State>,
+    method: &str,
+    start: u64,
+    count: u64,
+    fetcher: Fetcher<'_>,
+) -> Result<Vec<Block>, RpcError> {
+    let hashes = sync_headers(state.clone(), start, count).await?;
+    futures::stream::iter(hashes)
+        .map(|hash| fetch_validated_block(state.clone(), method, hash, fetcher))
+        .buffered(PARALLEL_DOWNLOADS)
+        .try_collect()
+        .await
+}
+
+/// `proxy_getblocks <height> <count>`: the serialized blocks of bitcoind's best chain from
+/// `height` on, fetched headers-first if they are pruned.
+pub struct GetBlocks;
+impl Interceptor for GetBlocks {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            if !user.fetch_blocks {
+                return Err(RpcError {
+                    code: ACCESS_DENIED_ERROR_CODE,
+                    message: "Fetching blocks is not enabled for this user".to_owned(),
+                    data: None,
+                    status: Some(StatusCode::FORBIDDEN),
+                });
+            }
+            let start = req
+                .params
+                .first()
+                .and_then(Value::as_u64)
+                .ok_or_else(|| anyhow!("expected a height"))?;
+            let count = req
+                .params
+                .get(1)
+                .and_then(Value::as_u64)
+                .filter(|c| (1..=MAX_BLOCKS).contains(c))
+                .ok_or_else(|| anyhow!("expected a count between 1 and {}", MAX_BLOCKS))?;
+            let fetcher = Fetcher {
+                user_name: &ctx.user_name,
+                quota: &user.fetch_quota,
+            };
+            let mut result = Vec::with_capacity(count as usize);
+            for block in fetch_range(state, &req.method, start, count, fetcher).await? {
+                let mut data = Vec::new();
+                block.consensus_encode(&mut data).map_err(Error::from)?;
+                result.push(Value::String(hex::encode(&data)));
+            }
+            Ok(Some(RpcResponse {
+                id: req.id.clone(),
+                result: Some(result.into()),
+                error: None,
+            }))
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,91 @@
+//! Fetching documents from web servers other than bitcoind, e.g. the keys of an OpenID Connect
+//! provider. HTTPS requires the `tls` feature and verifies servers against a CA bundle.
+
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+use hyper::body::Bytes;
+use hyper::{Body, Request, StatusCode, Uri};
+
+/// Requests taking longer than this fail.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where distributions usually keep the bundle of trusted CA certificates.
+#[cfg(feature = "tls")]
+const CA_BUNDLES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/ca-bundle.pem",
+    "/etc/ssl/cert.pem",
+];
+
+/// Reads the certificates of `ca_file`, or of the CA bundle of the system if `None`.
+#[cfg(feature = "tls")]
+pub fn tls_config(ca_file: Option<&Path>) -> Result<rustls::ClientConfig, Error> {
+    let path = match ca_file {
+        Some(path) => path,
+        None => CA_BUNDLES
+            .iter()
+            .map(Path::new)
+            .find(|p| p.exists())
+            .ok_or_else(|| anyhow!("no CA bundle found, set one explicitly"))?,
+    };
+    let mut config = rustls::ClientConfig::new();
+    let file = std::fs::File::open(path).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    match config
+        .root_store
+        .add_pem_file(&mut std::io::BufReader::new(file))
+    {
+        Ok((added, _)) if added > 0 => Ok(config),
+        _ => Err(anyhow!("{} contains no certificates", path.display())),
+    }
+}
+
+#[cfg(feature = "tls")]
+async fn get_https(uri: &Uri, ca_file: Option<&Path>) -> Result<hyper::Response<Body>, Error> {
+    let host = uri.host().ok_or_else(|| anyhow!("{} has no host", uri))?;
+    let port = uri.port_u16().unwrap_or(443);
+    let name = webpki::DNSNameRef::try_from_ascii_str(host)
+        .map_err(|_| anyhow!("{} is not a valid DNS name", host))?;
+    let connector = tokio_rustls::TlsConnector::from(std::sync::Arc::new(tls_config(ca_file)?));
+    let stream = tokio::net::TcpStream::connect((host, port)).await?;
+    let stream = connector.connect(name, stream).await?;
+    let (mut sender, connection) = hyper::client::conn::handshake(stream).await?;
+    tokio::spawn(connection);
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
+    Ok(sender
+        .send_request(
+            Request::get(path)
+                .header(hyper::header::HOST, host)
+                .body(Body::empty())?,
+        )
+        .await?)
+}
+
+async fn get_response(uri: &Uri, ca_file: Option<&Path>) -> Result<hyper::Response<Body>, Error> {
+    match uri.scheme_str() {
+        Some("http") => Ok(hyper::Client::new()
+            .request(Request::get(uri.clone()).body(Body::empty())?)
+            .await?),
+        #[cfg(feature = "tls")]
+        Some("https") => get_https(uri, ca_file).await,
+        #[cfg(not(feature = "tls"))]
+        Some("https") => {
+            let _ = ca_file;
+            Err(anyhow!("fetching {} requires the tls feature", uri))
+        }
+        _ => Err(anyhow!("unsupported URL {}", uri)),
+    }
+}
+
+/// Fetches `uri`, trusting the certificates of `ca_file` for HTTPS if given.
+pub async fn get(uri: &Uri, ca_file: Option<&Path>) -> Result<Bytes, Error> {
+    let response = tokio::time::timeout(TIMEOUT, get_response(uri, ca_file))
+        .await
+        .map_err(|_| anyhow!("timed out fetching {}", uri))??;
+    if response.status() != StatusCode::OK {
+        return Err(anyhow!("{} responded with {}", uri, response.status()));
+    }
+    Ok(hyper::body::to_bytes(response.into_body()).await?)
+}
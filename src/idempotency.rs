@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use bitcoin::hashes::{sha256, Hash};
+use hyper::StatusCode;
+use serde_json::{json, Value};
+
+use crate::client::{
+    GenericRpcMethod, RpcError, RpcRequest, RpcResponse, IDEMPOTENCY_CONFLICT_ERROR_CODE,
+};
+use crate::tenants::{quota_exceeded, TenantStore};
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+#[derive(Debug)]
+enum Outcome {
+    InFlight,
+    /// The call may have been executed, but its result was lost
+    Unknown,
+    Done {
+        result: Option<Value>,
+        error: Option<RpcError>,
+    },
+}
+
+#[derive(Debug)]
+struct Record {
+    created: Instant,
+    method: String,
+    params_hash: sha256::Hash,
+    outcome: Outcome,
+}
+
+/// What to do with a state-changing call carrying an idempotency key.
+pub enum Claim {
+    /// First time the key is seen, the call has to be executed
+    New,
+    /// The call was already executed, this is its response
+    Replay(RpcResponse<GenericRpcMethod>),
+}
+
+/// Results of state-changing calls made with an `Idempotency-Key` header, so that retries of
+/// the same call don't execute it again.
+#[derive(Debug)]
+pub struct Idempotency {
+    /// How long a key is remembered
+    pub window: Duration,
+    /// How many keys a single user may have remembered at once
+    pub quota: usize,
+    /// Records by user and key
+    records: Mutex<HashMap<String, HashMap<String, Record>>>,
+}
+impl Idempotency {
+    pub fn new(window: Duration, quota: usize) -> Self {
+        Idempotency {
+            window,
+            quota,
+            records: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn conflict(message: &str, status: StatusCode) -> RpcError {
+        RpcError {
+            code: IDEMPOTENCY_CONFLICT_ERROR_CODE,
+            message: message.to_owned(),
+            data: None,
+            status: Some(status),
+        }
+    }
+
+    /// Claims `key` for `req`, unless a call with the same key was already made by the user.
+    pub fn claim(
+        &self,
+        user_name: &str,
+        key: &str,
+        req: &RpcRequest<GenericRpcMethod>,
+    ) -> Result<Claim, RpcError> {
+        let params_hash = sha256::Hash::hash(&serde_json::to_vec(&req.params)?);
+        let mut records = self.records.lock().unwrap();
+        let window = self.window;
+        records.retain(|_, records| {
+            records.retain(|_, r| r.created.elapsed() < window);
+            !records.is_empty()
+        });
+        let records = records.entry(user_name.to_owned()).or_default();
+        match records.get(key) {
+            Some(record) if record.method != req.method.0 || record.params_hash != params_hash => {
+                Err(Self::conflict(
+                    "Idempotency key was already used for a different call",
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                ))
+            }
+            Some(Record {
+                outcome: Outcome::InFlight,
+                ..
+            }) => Err(Self::conflict(
+                "A call with this idempotency key is still in progress",
+                StatusCode::CONFLICT,
+            )),
+            Some(Record {
+                outcome: Outcome::Unknown,
+                ..
+            }) => Err(Self::conflict(
+                "The outcome of the call with this idempotency key is unknown, check whether it \
+                 was executed before making it again with another key",
+                StatusCode::CONFLICT,
+            )),
+            Some(Record {
+                outcome: Outcome::Done { result, error },
+                ..
+            }) => Ok(Claim::Replay(RpcResponse {
+                id: req.id.clone(),
+                result: result.clone(),
+                error: error.clone(),
+            })),
+            None if records.len() >= self.quota => Err(quota_exceeded("idempotency", self.quota)),
+            None => {
+                records.insert(
+                    key.to_owned(),
+                    Record {
+                        created: Instant::now(),
+                        method: req.method.0.clone(),
+                        params_hash,
+                        outcome: Outcome::InFlight,
+                    },
+                );
+                Ok(Claim::New)
+            }
+        }
+    }
+
+    /// Remembers the response to the call `key` was claimed for.
+    pub fn complete(&self, user_name: &str, key: &str, response: &RpcResponse<GenericRpcMethod>) {
+        if let Some(record) = self
+            .records
+            .lock()
+            .unwrap()
+            .get_mut(user_name)
+            .and_then(|records| records.get_mut(key))
+        {
+            record.outcome = Outcome::Done {
+                result: response.result.clone(),
+                error: response.error.clone(),
+            };
+        }
+    }
+
+    /// Keeps a claimed key whose call may have reached bitcoind without its result coming back,
+    /// so that retries don't execute it again.
+    pub fn abandon(&self, user_name: &str, key: &str) {
+        if let Some(record) = self
+            .records
+            .lock()
+            .unwrap()
+            .get_mut(user_name)
+            .and_then(|records| records.get_mut(key))
+        {
+            record.outcome = Outcome::Unknown;
+        }
+    }
+
+    /// Forgets a claimed key whose call didn't reach bitcoind, so that it can be retried.
+    pub fn release(&self, user_name: &str, key: &str) {
+        if let Some(records) = self.records.lock().unwrap().get_mut(user_name) {
+            records.remove(key);
+        }
+    }
+}
+impl TenantStore for Idempotency {
+    fn name(&self) -> &'static str {
+        "idempotency"
+    }
+    fn inspect(&self, user: &str) -> Value {
+        let records = self.records.lock().unwrap();
+        let keys: Vec<_> = records
+            .get(user)
+            .into_iter()
+            .flat_map(|records| records.iter())
+            .filter(|(_, r)| r.created.elapsed() < self.window)
+            .map(|(key, r)| {
+                json!({
+                    "key": key,
+                    "method": r.method,
+                    "age": r.created.elapsed().as_secs(),
+                    "done": matches!(r.outcome, Outcome::Done { .. }),
+                })
+            })
+            .collect();
+        json!({ "quota": self.quota, "entries": keys })
+    }
+    fn purge(&self, user: &str) -> usize {
+        self.records
+            .lock()
+            .unwrap()
+            .remove(user)
+            .map_or(0, |records| records.len())
+    }
+}
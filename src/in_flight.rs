@@ -0,0 +1,85 @@
+//! Requests the proxy is still handling, so that hanging calls show up in state dumps.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use serde_json::{json, Value};
+
+use crate::client::SingleOrBatchRpcRequest;
+
+#[derive(Debug)]
+struct Request {
+    caller: String,
+    path: String,
+    methods: Vec<String>,
+    started: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct InFlight {
+    next: AtomicU64,
+    requests: Mutex<HashMap<u64, Request>>,
+}
+impl InFlight {
+    /// Tracks `req` until the returned guard is dropped.
+    pub fn start(&self, caller: &str, path: &str, req: &SingleOrBatchRpcRequest) -> Guard<'_> {
+        let methods = match req {
+            SingleOrBatchRpcRequest::Single(req) => vec![req.method.0.clone()],
+            SingleOrBatchRpcRequest::Batch(reqs) => {
+                reqs.iter().map(|req| req.method.0.clone()).collect()
+            }
+        };
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        self.requests.lock().unwrap().insert(
+            id,
+            Request {
+                caller: caller.to_owned(),
+                path: path.to_owned(),
+                methods,
+                started: Instant::now(),
+            },
+        );
+        Guard {
+            in_flight: self,
+            id,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The requests being handled, oldest first.
+    pub fn dump(&self) -> Value {
+        let requests = self.requests.lock().unwrap();
+        let mut requests: Vec<_> = requests.values().collect();
+        requests.sort_by_key(|r| r.started);
+        requests
+            .into_iter()
+            .map(|r| {
+                json!({
+                    "caller": r.caller,
+                    "path": r.path,
+                    "methods": r.methods,
+                    "elapsed": r.started.elapsed().as_secs_f64(),
+                })
+            })
+            .collect()
+    }
+}
+
+pub struct Guard<'a> {
+    in_flight: &'a InFlight,
+    id: u64,
+}
+impl Drop for Guard<'_> {
+    fn drop(&mut self) {
+        self.in_flight.requests.lock().unwrap().remove(&self.id);
+    }
+}
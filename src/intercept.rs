@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use futures::future::BoxFuture;
+use hyper::HeaderMap;
+
+use crate::acl;
+use crate::approvals;
+use crate::cache_sync;
+use crate::client::{GenericRpcMethod, RpcError, RpcRequest, RpcResponse};
+use crate::dry_run::DRY_RUN_HEADER;
+use crate::idempotency::IDEMPOTENCY_KEY_HEADER;
+use crate::journal;
+use crate::report;
+use crate::sats::SATS_HEADER;
+use crate::state::State;
+use crate::tenants;
+use crate::users::User;
+use crate::warnings::Warnings;
+
+pub mod admin;
+#[cfg(feature = "peer-fetch")]
+pub mod getblock;
+#[cfg(feature = "peer-fetch")]
+pub mod getblockchaininfo;
+#[cfg(feature = "peer-fetch")]
+pub mod getrawtransaction;
+
+/// Methods with this prefix are served by the proxy itself and never forwarded to bitcoind.
+pub const LOCAL_METHOD_PREFIX: &str = "proxy_";
+
+/// Header allowing blocks not in bitcoind's best chain to be fetched from elsewhere.
+pub const ALLOW_FORKS_HEADER: &str = "x-allow-forks";
+
+fn flag(headers: &HeaderMap, name: &str) -> bool {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+}
+
+/// Information about a request that isn't part of the JSON-RPC call itself.
+#[derive(Debug, Default, Clone)]
+pub struct RequestContext {
+    /// Name of the authenticated user
+    pub user_name: String,
+    /// The configured user whose permissions apply, usually `user_name`
+    pub profile: String,
+    /// Simulate state-changing methods instead of forwarding them
+    pub dry_run: bool,
+    /// Key identifying retries of the same state-changing call
+    pub idempotency_key: Option<String>,
+    /// Serve blocks fetched from elsewhere even if they aren't in bitcoind's best chain
+    pub allow_forks: bool,
+    /// Annotate amounts with satoshis and accept them in params
+    pub sats: bool,
+    /// Address of the client, if known
+    pub remote_addr: Option<SocketAddr>,
+    /// Name of the authenticated user if it made the request as `user_name`
+    pub impersonator: Option<String>,
+    /// Id of the API key the authenticated user made the request with
+    pub api_key: Option<String>,
+    /// Path the request was sent to, `/wallet/<name>` for calls of a specific wallet
+    pub path: String,
+    /// Warnings for the client about the calls of the request
+    pub warnings: Arc<Mutex<Warnings>>,
+}
+impl RequestContext {
+    pub fn new(user_name: String, headers: &HeaderMap) -> Self {
+        RequestContext {
+            profile: user_name.clone(),
+            user_name,
+            dry_run: flag(headers, DRY_RUN_HEADER),
+            allow_forks: flag(headers, ALLOW_FORKS_HEADER),
+            sats: flag(headers, SATS_HEADER),
+            remote_addr: None,
+            impersonator: None,
+            api_key: None,
+            path: "/".to_owned(),
+            warnings: Default::default(),
+            idempotency_key: headers
+                .get(IDEMPOTENCY_KEY_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.trim().to_owned())
+                .filter(|v| !v.is_empty()),
+        }
+    }
+
+    /// The user actually making the request: the impersonating admin, if any.
+    pub fn principal(&self) -> &str {
+        self.impersonator.as_deref().unwrap_or(&self.user_name)
+    }
+
+    /// Who made the request, for logs.
+    pub fn caller(&self) -> String {
+        let key = match &self.api_key {
+            Some(id) => format!(" with API key {}", id),
+            None => String::new(),
+        };
+        match &self.impersonator {
+            Some(admin) => format!("{} (impersonated by {}{})", self.user_name, admin, key),
+            None => format!("{}{}", self.user_name, key),
+        }
+    }
+
+    /// Adds a warning about `req` to its response.
+    pub fn warn(&self, req: &RpcRequest<GenericRpcMethod>, warning: String) {
+        self.warnings
+            .lock()
+            .unwrap()
+            .push((req.id.clone(), warning));
+    }
+
+    /// The warnings added so far, to be sent to the client.
+    pub fn take_warnings(&self) -> Warnings {
+        std::mem::take(&mut *self.warnings.lock().unwrap())
+    }
+}
+
+pub type InterceptResult = Result<Option<RpcResponse<GenericRpcMethod>>, RpcError>;
+
+/// Handles a single RPC method on behalf of an already authorized user.
+///
+/// Returning `Ok(None)` forwards the request to bitcoind unchanged.
+pub trait Interceptor: Send + Sync {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult>;
+}
+
+pub struct Interceptors(HashMap<String, Box<dyn Interceptor>>);
+impl Interceptors {
+    pub fn empty() -> Self {
+        Interceptors(HashMap::new())
+    }
+    pub fn register<I: Interceptor + 'static>(&mut self, method: &str, interceptor: I) {
+        self.0.insert(method.to_owned(), Box::new(interceptor));
+    }
+    pub fn get(&self, method: &str) -> Option<&dyn Interceptor> {
+        self.0.get(method).map(|i| &**i)
+    }
+}
+impl Default for Interceptors {
+    fn default() -> Self {
+        let mut res = Interceptors::empty();
+        res.register("proxy_status", admin::Status);
+        res.register("proxy_setreadonly", admin::SetReadOnly);
+        res.register("proxy_dumpstate", admin::DumpState);
+        res.register("proxy_adduser", admin::AddUser);
+        res.register("proxy_listusers", admin::ListUsers);
+        res.register("proxy_removeuser", admin::RemoveUser);
+        res.register("proxy_setpermissions", admin::SetPermissions);
+        res.register("proxy_addapikey", admin::AddApiKey);
+        res.register("proxy_revokeapikey", admin::RevokeApiKey);
+        res.register("proxy_getapproval", approvals::GetApproval);
+        res.register("proxy_listapprovals", approvals::ListApprovals);
+        res.register("proxy_approve", approvals::Approve);
+        res.register("proxy_reject", approvals::Reject);
+        res.register("proxy_exportjournal", journal::ExportJournal);
+        res.register("proxy_gettenant", tenants::GetTenant);
+        res.register("proxy_purgetenant", tenants::PurgeTenant);
+        res.register("proxy_generatereport", report::GenerateReport);
+        res.register("proxy_explainacl", acl::ExplainAcl);
+        res.register("proxy_auditacl", acl::AuditAcl);
+        res.register("proxy_issuetoken", crate::tokens::IssueToken);
+        res.register("proxy_getquota", crate::quota::GetQuota);
+        res.register("proxy_bumpstuck", crate::rbf::BumpStuck);
+        res.register("proxy_invalidatecache", cache_sync::InvalidateCache);
+        // with peer-fetch, the Esplora fallback also does what GetRawTransaction does
+        #[cfg(not(feature = "peer-fetch"))]
+        res.register("getrawtransaction", crate::capabilities::GetRawTransaction);
+        #[cfg(feature = "cache")]
+        res.register("gettxout", crate::txout_cache::GetTxOut);
+        res.register("proxy_gettxstatuses", crate::tx_status::GetTxStatuses);
+        res.register("proxy_getbalances", crate::balances::GetBalances);
+        for method in crate::labels::NAMESPACED_METHODS {
+            res.register(method, crate::labels::LabelNamespace);
+        }
+        #[cfg(feature = "peer-fetch")]
+        {
+            use crate::client::RpcMethod;
+            use crate::rpc_methods::{GetBlock, GetBlockchainInfo};
+
+            res.register(GetBlock.as_str(), getblock::PeerFetch);
+            res.register(
+                "proxy_getnodeaddresses",
+                crate::address_book::GetNodeAddresses,
+            );
+            res.register(GetBlockchainInfo.as_str(), getblockchaininfo::Unpruned);
+            res.register("getrawtransaction", getrawtransaction::EsploraFallback);
+            res.register("proxy_getblocks", crate::headers_first::GetBlocks);
+        }
+        res
+    }
+}
+impl std::fmt::Debug for Interceptors {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
@@ -0,0 +1,438 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, Utc};
+use futures::future::{BoxFuture, FutureExt};
+use hyper::StatusCode;
+use serde_json::{Map, Value};
+
+use crate::api_keys::ApiKey;
+use crate::client::{
+    GenericRpcMethod, RpcError, RpcRequest, RpcResponse, ACCESS_DENIED_ERROR_CODE,
+};
+use crate::intercept::{InterceptResult, Interceptor, RequestContext};
+use crate::password::{self, Password, Scheme};
+use crate::state::State;
+use crate::users::{User, HOST_FIELDS};
+
+fn respond(req: &RpcRequest<GenericRpcMethod>, result: Value) -> InterceptResult {
+    Ok(Some(RpcResponse {
+        id: req.id.clone(),
+        result: Some(result),
+        error: None,
+    }))
+}
+
+/// `proxy_status`: reports the runtime state of the proxy.
+pub struct Status;
+impl Interceptor for Status {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        _user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        _ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            respond(
+                req,
+                serde_json::json!({
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "read_only": state.read_only.load(Ordering::SeqCst),
+                    "capabilities": state.capabilities.get(),
+                    "load": state.load.as_ref().map(|l| l.status()),
+                    "response_signing_key": state
+                        .response_signer
+                        .as_ref()
+                        .map(|s| hex::encode(s.public_key())),
+                }),
+            )
+        }
+        .boxed()
+    }
+}
+
+/// The usage of the quotas of every user that has some.
+fn quota_usage(state: &State) -> Value {
+    let mut usage = Map::new();
+    for (name, user) in state.users.list() {
+        #[allow(unused_mut)]
+        let mut quotas = Map::new();
+        if !user.quota.is_empty() {
+            quotas.insert(
+                "calls".to_owned(),
+                state.quota_usage.report(&name, &user.quota),
+            );
+        }
+        #[cfg(feature = "peer-fetch")]
+        if !user.fetch_quota.is_empty() {
+            quotas.insert(
+                "fetch".to_owned(),
+                state.fetch_usage.report(&name, &user.fetch_quota),
+            );
+        }
+        if !quotas.is_empty() {
+            usage.insert(name, Value::Object(quotas));
+        }
+    }
+    Value::Object(usage)
+}
+
+/// `proxy_dumpstate`: a snapshot of the internal state of the proxy for debugging, without
+/// credentials: requests being handled, caches, rate limiter buckets, quotas, lockouts and, with
+/// `peer-fetch`, the peer pool and peers that failed to serve blocks.
+pub struct DumpState;
+impl Interceptor for DumpState {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        _user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        _ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            #[allow(unused_mut)]
+            let mut dump = serde_json::json!({
+                "time": Utc::now(),
+                "version": env!("CARGO_PKG_VERSION"),
+                "read_only": state.read_only.load(Ordering::SeqCst),
+                "in_flight": state.in_flight.dump(),
+                "caches": {
+                    "budget": state.memory_budget.limit,
+                    "used": state.memory_budget.used(),
+                    "caches": state
+                        .caches()
+                        .into_iter()
+                        .map(|c| serde_json::json!({
+                            "name": c.name(),
+                            "entries": c.entries(),
+                            "bytes": c.bytes(),
+                            "hits": c.stats().hits.load(Ordering::Relaxed),
+                            "misses": c.stats().misses.load(Ordering::Relaxed),
+                            "evictions": c.stats().evictions.load(Ordering::Relaxed),
+                        }))
+                        .collect::<Vec<_>>(),
+                },
+                "rate_limits": state.rate_limiter.dump(),
+                "quotas": quota_usage(&state),
+                "lockout": state.lockout.as_ref().map(|l| l.dump()),
+            });
+            #[cfg(feature = "peer-fetch")]
+            {
+                dump["peers"] = state.peers.read().await.dump();
+                dump["failing_peers"] = state
+                    .address_book
+                    .list(None)
+                    .into_iter()
+                    .filter(|e| e.failures > 0)
+                    .map(|e| {
+                        serde_json::json!({
+                            "address": e.address,
+                            "port": e.port,
+                            "network": e.network,
+                            "successes": e.successes,
+                            "failures": e.failures,
+                            "score": e.score(),
+                        })
+                    })
+                    .collect::<Vec<_>>()
+                    .into();
+            }
+            respond(req, dump)
+        }
+        .boxed()
+    }
+}
+
+/// `proxy_setreadonly <bool>`: turns the emergency read-only mode on or off.
+pub struct SetReadOnly;
+impl Interceptor for SetReadOnly {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        _user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        _ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            let read_only = req
+                .params
+                .first()
+                .and_then(|v| v.as_bool())
+                .ok_or_else(|| anyhow!("expected a boolean parameter"))?;
+            state.set_read_only(read_only);
+            respond(req, Value::Bool(read_only))
+        }
+        .boxed()
+    }
+}
+
+fn check_manage_users(user: &User) -> Result<(), RpcError> {
+    if user.manage_users {
+        Ok(())
+    } else {
+        Err(RpcError {
+            code: ACCESS_DENIED_ERROR_CODE,
+            message: "Managing users is not enabled for this user".to_owned(),
+            data: None,
+            status: Some(StatusCode::FORBIDDEN),
+        })
+    }
+}
+
+fn name_param(req: &RpcRequest<GenericRpcMethod>) -> Result<&str, Error> {
+    req.params
+        .first()
+        .and_then(Value::as_str)
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| anyhow!("expected a user name"))
+}
+
+fn object_param(req: &RpcRequest<GenericRpcMethod>) -> Result<Map<String, Value>, Error> {
+    match req.params.get(1) {
+        Some(Value::Object(fields)) => Ok(fields.clone()),
+        _ => Err(anyhow!("expected an object in the format of the config")),
+    }
+}
+
+/// The config of `user` without its password and API keys.
+fn permissions(user: &User) -> Result<Value, Error> {
+    let mut value = serde_json::to_value(user)?;
+    let fields = value.as_object_mut().unwrap();
+    fields.remove("password");
+    for key in fields
+        .get_mut("api_key")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_object_mut)
+    {
+        key.remove("key");
+    }
+    Ok(value)
+}
+
+fn key_id_param(req: &RpcRequest<GenericRpcMethod>) -> Result<&str, Error> {
+    req.params
+        .get(1)
+        .and_then(Value::as_str)
+        .filter(|id| !id.is_empty())
+        .ok_or_else(|| anyhow!("expected an API key id"))
+}
+
+/// `name` with its API keys changed by `change`.
+fn with_api_keys(
+    state: &State,
+    name: &str,
+    change: impl FnOnce(&mut Vec<Value>) -> Result<(), Error>,
+) -> Result<User, Error> {
+    let user = state
+        .users
+        .definition(name)
+        .ok_or_else(|| anyhow!("unknown user {}", name))?;
+    let mut value = serde_json::to_value(&*user)?;
+    let keys = value
+        .as_object_mut()
+        .unwrap()
+        .entry("api_key")
+        .or_insert_with(|| Value::Array(Vec::new()));
+    change(keys.as_array_mut().unwrap())?;
+    User::from_value(value)
+}
+
+/// `proxy_adduser <name> <user>`: adds a user configured like in the config file. Passwords in
+/// plain text are stored hashed with argon2.
+pub struct AddUser;
+impl Interceptor for AddUser {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            check_manage_users(user)?;
+            let name = name_param(req)?;
+            let mut fields = object_param(req)?;
+            if state.users.by_name(name).is_some() {
+                return Err(anyhow!("user {} already exists", name).into());
+            }
+            // they would let the caller read files and the environment of the proxy
+            if let Some(field) = HOST_FIELDS.iter().find(|f| fields.contains_key(**f)) {
+                return Err(anyhow!("{} can only be set in the config", field).into());
+            }
+            if let Some(Value::String(password)) = fields.get("password") {
+                if !password.is_empty() && !Password::from(password.clone()).is_hashed() {
+                    let hashed = password::hash(password, Scheme::Argon2)?;
+                    fields.insert("password".to_owned(), hashed.into());
+                }
+            }
+            let new_user = User::from_value(fields.into())?;
+            if new_user.password.is_empty() && !new_user.has_client_cert() {
+                return Err(anyhow!("expected a password or a client certificate").into());
+            }
+            let result = permissions(&new_user)?;
+            state.users.set(name, Some(new_user))?;
+            info!(state.logger, "{} added user {}", ctx.user_name, name);
+            respond(req, result)
+        }
+        .boxed()
+    }
+}
+
+/// `proxy_listusers`: the permissions of every user, including those inherited from groups,
+/// without credentials.
+pub struct ListUsers;
+impl Interceptor for ListUsers {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        _ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            check_manage_users(user)?;
+            let mut users = Map::new();
+            for (name, user) in state.users.list() {
+                users.insert(name, permissions(&user)?);
+            }
+            respond(req, Value::Object(users))
+        }
+        .boxed()
+    }
+}
+
+/// `proxy_removeuser <name>`: removes a user, rejecting its next request.
+pub struct RemoveUser;
+impl Interceptor for RemoveUser {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            check_manage_users(user)?;
+            let name = name_param(req)?;
+            if name == ctx.user_name {
+                return Err(anyhow!("users can't remove themselves").into());
+            }
+            if state.users.by_name(name).is_none() {
+                return Err(anyhow!("unknown user {}", name).into());
+            }
+            state.users.set(name, None)?;
+            info!(state.logger, "{} removed user {}", ctx.user_name, name);
+            respond(req, Value::Bool(true))
+        }
+        .boxed()
+    }
+}
+
+/// `proxy_setpermissions <name> <fields>`: changes the fields of a user other than its
+/// credentials, e.g. `{"allowed_calls": [...], "fetch_blocks": true}`.
+pub struct SetPermissions;
+impl Interceptor for SetPermissions {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            check_manage_users(user)?;
+            let name = name_param(req)?;
+            let changed = state.users.with_permissions(name, object_param(req)?)?;
+            let result = permissions(&changed)?;
+            state.users.set(name, Some(changed))?;
+            info!(
+                state.logger,
+                "{} changed the permissions of {}", ctx.user_name, name
+            );
+            respond(req, result)
+        }
+        .boxed()
+    }
+}
+
+/// `proxy_addapikey <name> <id> [expires]`: generates an API key of a user, optionally expiring
+/// at an RFC 3339 time. The key is only returned once, the proxy keeps its hash.
+pub struct AddApiKey;
+impl Interceptor for AddApiKey {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            check_manage_users(user)?;
+            let name = name_param(req)?;
+            let id = key_id_param(req)?;
+            let expires = match req.params.get(2) {
+                None | Some(Value::Null) => None,
+                Some(expires) => Some(
+                    serde_json::from_value::<DateTime<Utc>>(expires.clone())
+                        .map_err(|_| anyhow!("expected an RFC 3339 expiry time"))?,
+                ),
+            };
+            let (key, api_key) = ApiKey::generate(id.to_owned(), expires)?;
+            let changed = with_api_keys(&state, name, |keys| {
+                if keys.iter().any(|k| k["id"] == id) {
+                    return Err(anyhow!("user {} already has an API key {}", name, id));
+                }
+                keys.push(serde_json::to_value(&api_key)?);
+                Ok(())
+            })?;
+            state.users.set(name, Some(changed))?;
+            info!(
+                state.logger,
+                "{} added API key {} of {}", ctx.user_name, id, name
+            );
+            respond(
+                req,
+                serde_json::json!({ "id": id, "key": key, "expires": expires }),
+            )
+        }
+        .boxed()
+    }
+}
+
+/// `proxy_revokeapikey <name> <id>`: revokes an API key of a user, rejecting its next request.
+/// Revoked keys are kept, so that clients still using them are recognized in logs.
+pub struct RevokeApiKey;
+impl Interceptor for RevokeApiKey {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            check_manage_users(user)?;
+            let name = name_param(req)?;
+            let id = key_id_param(req)?;
+            let changed = with_api_keys(&state, name, |keys| {
+                let key = keys
+                    .iter_mut()
+                    .find(|k| k["id"] == id)
+                    .ok_or_else(|| anyhow!("user {} has no API key {}", name, id))?;
+                key["revoked"] = Value::Bool(true);
+                Ok(())
+            })?;
+            state.users.set(name, Some(changed))?;
+            info!(
+                state.logger,
+                "{} revoked API key {} of {}", ctx.user_name, id, name
+            );
+            respond(req, Value::Bool(true))
+        }
+        .boxed()
+    }
+}
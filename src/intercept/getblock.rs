@@ -0,0 +1,113 @@
+use std::sync::Arc;
+
+use anyhow::Error;
+use bitcoin::consensus::Encodable;
+use futures::future::{BoxFuture, FutureExt};
+use serde_json::Value;
+
+use crate::client::{GenericRpcMethod, RpcRequest, RpcResponse};
+use crate::fetch_blocks::{fetch_block, Fetcher};
+use crate::intercept::{InterceptResult, Interceptor, RequestContext};
+use crate::rpc_methods::{GetBlockHeader, GetBlockHeaderParams, GetBlockResult};
+use crate::state::State;
+use crate::users::User;
+
+/// Serves `getblock` for blocks pruned from bitcoind by fetching them from peers.
+pub struct PeerFetch;
+impl Interceptor for PeerFetch {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            if !user.fetch_blocks {
+                return Ok(None);
+            }
+            let fetcher = Fetcher {
+                user_name: &ctx.user_name,
+                quota: &user.fetch_quota,
+            };
+            // only non-verbose for now
+            match req.params.get(1).unwrap_or(&1_u64.into()) {
+                Value::Number(ref n) if n.as_u64() == Some(0) => {
+                    match fetch_block(
+                        state.clone(),
+                        &req.method,
+                        serde_json::from_value(req.params[0].clone()).map_err(Error::from)?,
+                        ctx.allow_forks,
+                        fetcher,
+                    )
+                    .await
+                    {
+                        Ok(block) => {
+                            let mut block_data = Vec::new();
+                            block
+                                .consensus_encode(&mut block_data)
+                                .map_err(Error::from)?;
+                            let block_data = hex::encode(&block_data);
+                            Ok(Some(RpcResponse {
+                                id: req.id.clone(),
+                                result: Some(Value::String(block_data)),
+                                error: None,
+                            }))
+                        }
+                        Err(e) => Ok(Some(e.into())),
+                    }
+                }
+                Value::Number(ref n) if n.as_u64() == Some(1) => {
+                    let hash =
+                        serde_json::from_value(req.params[0].clone()).map_err(Error::from)?;
+                    let fetch_header_req = RpcRequest {
+                        id: None,
+                        method: GetBlockHeader,
+                        params: GetBlockHeaderParams(hash, Some(true)),
+                    };
+                    match futures::try_join!(
+                        async {
+                            state
+                                .rpc_client
+                                .call(&fetch_header_req)
+                                .await?
+                                .into_result()
+                        },
+                        fetch_block(state.clone(), &req.method, hash, ctx.allow_forks, fetcher)
+                    ) {
+                        Ok((header, block)) => Ok(Some(RpcResponse {
+                            id: req.id.clone(),
+                            result: {
+                                let size = block.get_size();
+                                let witness = block
+                                    .txdata
+                                    .iter()
+                                    .flat_map(|tx| tx.input.iter())
+                                    .flat_map(|input| input.witness.iter())
+                                    .map(|witness| witness.len())
+                                    .sum::<usize>();
+                                Some(serde_json::to_value(GetBlockResult {
+                                    header: header.into_right().ok_or_else(|| {
+                                        anyhow::anyhow!("unexpected response for getblockheader")
+                                    })?,
+                                    size,
+                                    strippedsize: if witness > 0 {
+                                        Some(size - witness)
+                                    } else {
+                                        None
+                                    },
+                                    weight: block.get_weight(),
+                                    tx: block.txdata.into_iter().map(|tx| tx.txid()).collect(),
+                                })?)
+                            },
+                            error: None,
+                        })),
+                        Err(e) => Ok(Some(e.into())),
+                    }
+                }
+                _ => Ok(None), // TODO
+            }
+        }
+        .boxed()
+    }
+}
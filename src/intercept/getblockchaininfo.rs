@@ -0,0 +1,35 @@
+use std::sync::Arc;
+
+use futures::future::{BoxFuture, FutureExt};
+use serde_json::Value;
+
+use crate::client::{GenericRpcMethod, RpcRequest};
+use crate::intercept::{InterceptResult, Interceptor, RequestContext};
+use crate::state::State;
+use crate::users::User;
+
+/// Reports the node as unpruned to users that can fetch pruned blocks through the proxy.
+pub struct Unpruned;
+impl Interceptor for Unpruned {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        _ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            if !user.fetch_blocks {
+                return Ok(None);
+            }
+            let mut res = state.rpc_client.call(req).await?;
+            if let Some(Value::Object(o)) = res.result.as_mut() {
+                if let Some(p) = o.get_mut("pruned") {
+                    *p = Value::Bool(false);
+                }
+            }
+            Ok(Some(res))
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,91 @@
+use std::sync::Arc;
+
+use anyhow::Error;
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::{Transaction, Txid};
+use futures::future::{BoxFuture, FutureExt};
+use serde_json::Value;
+
+use crate::capabilities::{GetRawTransaction, INVALID_ADDRESS_OR_KEY_ERROR_CODE};
+use crate::client::{GenericRpcMethod, RpcRequest, RpcResponse};
+use crate::fetch_blocks::Esplora;
+use crate::intercept::{InterceptResult, Interceptor, RequestContext};
+use crate::state::State;
+use crate::users::User;
+
+/// Serves `getrawtransaction` like [`GetRawTransaction`] and, for transactions bitcoind can't
+/// find, which is all confirmed transactions without txindex, looks them up on the Esplora
+/// server of `esplora_url`.
+pub struct EsploraFallback;
+impl Interceptor for EsploraFallback {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            let esplora = match &state.fetch_policy.esplora {
+                Some(esplora) if user.fetch_blocks => esplora,
+                _ => return GetRawTransaction.intercept(state, user, req, ctx).await,
+            };
+            let response = match GetRawTransaction
+                .intercept(state.clone(), user, req, ctx)
+                .await?
+            {
+                Some(response) => response,
+                None => state.rpc_client.call_at(&ctx.path, req).await?,
+            };
+            // only non-verbose for now
+            let verbose = match req.params.get(1) {
+                None | Some(Value::Null) => false,
+                Some(Value::Bool(verbose)) => *verbose,
+                Some(Value::Number(n)) => n.as_u64() != Some(0),
+                Some(_) => return Ok(Some(response)),
+            };
+            let not_found = response
+                .error
+                .as_ref()
+                .is_some_and(|e| e.code == INVALID_ADDRESS_OR_KEY_ERROR_CODE);
+            if verbose || !not_found {
+                return Ok(Some(response));
+            }
+            let txid = match req.params.first().cloned().map(serde_json::from_value) {
+                Some(Ok(txid)) => txid,
+                _ => return Ok(Some(response)),
+            };
+            match fetch_transaction(esplora, txid).await {
+                Ok(Some(tx)) => Ok(Some(RpcResponse {
+                    id: req.id.clone(),
+                    result: Some(Value::String(hex::encode(serialize(&tx)))),
+                    error: None,
+                })),
+                Ok(None) => Ok(Some(response)),
+                Err(e) => {
+                    warn!(
+                        state.logger,
+                        "Failed to look up {} on Esplora: {:#}", txid, e
+                    );
+                    Ok(Some(response))
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+async fn fetch_transaction(esplora: &Esplora, txid: Txid) -> Result<Option<Transaction>, Error> {
+    let body = match esplora.get(&format!("/tx/{}/raw", txid)).await? {
+        Some(body) => body,
+        None => return Ok(None),
+    };
+    let tx: Transaction = deserialize(&body)?;
+    if tx.txid() != txid {
+        return Err(anyhow::anyhow!(
+            "Esplora returned another transaction for {}",
+            txid
+        ));
+    }
+    Ok(Some(tx))
+}
@@ -0,0 +1,155 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Error};
+use bitcoin::hashes::{sha256, Hash};
+use chrono::{DateTime, Utc};
+use futures::future::{BoxFuture, FutureExt};
+use serde_json::Value;
+
+use crate::client::{GenericRpcMethod, RpcError, RpcRequest, RpcResponse};
+use crate::intercept::{InterceptResult, Interceptor, RequestContext};
+use crate::state::State;
+use crate::storage::{Storage, JOURNAL};
+use crate::users::User;
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct JournalEntry {
+    pub time: DateTime<Utc>,
+    pub user: String,
+    /// Id of the API key the call was made with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    pub path: String,
+    pub method: String,
+    /// SHA256 of the JSON encoded parameters
+    pub params_hash: String,
+    #[serde(default)]
+    pub result: Option<Value>,
+    #[serde(default)]
+    pub error: Option<RpcError>,
+    /// The transaction broadcast by the call, if any
+    #[serde(default)]
+    pub txid: Option<String>,
+}
+
+/// Append-only record of every state-changing call forwarded to bitcoind, kept in the log
+/// `storage::JOURNAL`.
+#[derive(Debug)]
+pub struct Journal {
+    storage: Arc<dyn Storage>,
+}
+impl Journal {
+    pub fn open(storage: Arc<dyn Storage>) -> Self {
+        Journal { storage }
+    }
+
+    pub async fn append(&self, entry: &JournalEntry) -> Result<(), Error> {
+        let record = serde_json::to_value(entry)?;
+        let storage = self.storage.clone();
+        tokio::task::spawn_blocking(move || storage.append(JOURNAL, &record)).await?
+    }
+
+    pub async fn entries(&self) -> Result<Vec<JournalEntry>, Error> {
+        let storage = self.storage.clone();
+        tokio::task::spawn_blocking(move || storage.records(JOURNAL))
+            .await??
+            .into_iter()
+            .map(|r| Ok(serde_json::from_value(r)?))
+            .collect()
+    }
+}
+
+fn txid(method: &str, result: &Value) -> Option<String> {
+    match (method, result) {
+        ("sendrawtransaction", Value::String(s))
+        | ("sendtoaddress", Value::String(s))
+        | ("sendmany", Value::String(s))
+        | ("bumpfee", Value::String(s)) => Some(s.clone()),
+        (_, Value::Object(o)) => o.get("txid").and_then(|t| t.as_str()).map(|t| t.to_owned()),
+        _ => None,
+    }
+}
+
+/// Forwards `req` to bitcoind, recording the outcome in the journal.
+pub async fn forward(
+    state: Arc<State>,
+    journal: &Journal,
+    path: &str,
+    req: &RpcRequest<GenericRpcMethod>,
+    ctx: &RequestContext,
+) -> Result<RpcResponse<GenericRpcMethod>, RpcError> {
+    let mut response = state
+        .rpc_client
+        .call_at(
+            path,
+            &RpcRequest {
+                id: None,
+                method: GenericRpcMethod(req.method.0.clone()),
+                params: req.params.clone(),
+            },
+        )
+        .await?;
+    let entry = JournalEntry {
+        time: Utc::now(),
+        user: ctx.user_name.clone(),
+        api_key: ctx.api_key.clone(),
+        path: path.to_owned(),
+        method: req.method.0.clone(),
+        params_hash: hex::encode(
+            &sha256::Hash::hash(&serde_json::to_vec(&req.params).map_err(Error::from)?)[..],
+        ),
+        txid: response.result.as_ref().and_then(|r| txid(&req.method, r)),
+        result: response.result.clone(),
+        error: response.error.as_ref().map(|e| RpcError {
+            code: e.code,
+            message: e.message.clone(),
+            data: e.data.clone(),
+            status: None,
+        }),
+    };
+    if let Err(e) = journal.append(&entry).await {
+        // the call already went through, so the client must learn about its result anyway
+        error!(state.logger, "{}", e.context("writing to the journal"));
+    }
+    response.id = req.id.clone();
+    Ok(response)
+}
+
+/// `proxy_exportjournal [since]`: journal entries, optionally only those after an RFC 3339 time.
+pub struct ExportJournal;
+impl Interceptor for ExportJournal {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        _user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        _ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            let journal = state
+                .journal
+                .as_ref()
+                .ok_or_else(|| anyhow!("the journal is not enabled"))?;
+            let since = match req.params.first() {
+                Some(Value::String(s)) => Some(
+                    DateTime::parse_from_rfc3339(s)
+                        .map_err(Error::from)?
+                        .with_timezone(&Utc),
+                ),
+                _ => None,
+            };
+            let entries: Vec<_> = journal
+                .entries()
+                .await?
+                .into_iter()
+                .filter(|e| since.is_none_or(|since| e.time > since))
+                .collect();
+            Ok(Some(RpcResponse {
+                id: req.id.clone(),
+                result: Some(serde_json::to_value(entries)?),
+                error: None,
+            }))
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,268 @@
+//! Strict JSON-RPC 2.0 framing for clients, while bitcoind is still spoken to in its 1.x
+//! dialect.
+//!
+//! Requests have to carry `"jsonrpc": "2.0"`, calls without an `id` are notifications that get
+//! no response, and responses carry either a `result` or an `error` as the specification wants.
+
+use std::collections::BTreeMap;
+
+use anyhow::Error;
+use hyper::{
+    body::Bytes,
+    header::{CONTENT_LENGTH, CONTENT_TYPE},
+    Body, Response, StatusCode,
+};
+use serde_json::value::{to_raw_value, RawValue};
+use serde_json::{json, Map, Value};
+use tokio::stream::StreamExt;
+
+use crate::client::PARSE_ERROR_CODE;
+
+pub const INVALID_REQUEST_ERROR_CODE: i64 = -32600;
+
+/// The fields of a response, with their values as they were written.
+type Fields = BTreeMap<String, Box<RawValue>>;
+
+/// A call of a request, in order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Slot {
+    /// Forwarded, answered unless it is a notification
+    Call { id: Value, notification: bool },
+    /// Answered with an Invalid Request error with this id
+    Invalid { id: Value },
+}
+
+/// How the responses of bitcoind map back to the calls of a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Plan {
+    pub batch: bool,
+    pub slots: Vec<Slot>,
+}
+impl Plan {
+    fn forwards(&self) -> bool {
+        self.slots.iter().any(|s| matches!(s, Slot::Call { .. }))
+    }
+}
+
+fn error(id: Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": { "code": code, "message": message },
+        "id": id,
+    })
+}
+
+pub fn json_response(body: &impl serde::Serialize) -> Result<Response<Body>, Error> {
+    let body = serde_json::to_vec(body)?;
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .header(CONTENT_LENGTH, body.len())
+        .body(body.into())?)
+}
+
+fn no_content() -> Result<Response<Body>, Error> {
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())?)
+}
+
+/// The call as bitcoind takes it, or the id to answer an Invalid Request error with.
+fn check(call: Value) -> Result<(Value, bool), Value> {
+    let mut call = match call {
+        Value::Object(call) => call,
+        _ => return Err(Value::Null),
+    };
+    let id = call.get("id").cloned();
+    let valid_id = matches!(
+        id,
+        None | Some(Value::Null) | Some(Value::String(_)) | Some(Value::Number(_))
+    );
+    let id_or_null = id.clone().filter(|_| valid_id).unwrap_or(Value::Null);
+    if !valid_id
+        || call.get("jsonrpc").and_then(Value::as_str) != Some("2.0")
+        || !call.get("method").is_some_and(Value::is_string)
+    {
+        return Err(id_or_null);
+    }
+    match call.get("params") {
+        None => {
+            call.insert("params".to_owned(), json!([]));
+        }
+        Some(Value::Array(_)) => (),
+        // named params aren't supported by the proxy
+        Some(_) => return Err(id_or_null),
+    }
+    call.remove("jsonrpc");
+    Ok((Value::Object(call), id.is_none()))
+}
+
+/// Checks the framing of `body`, returning the request to forward in bitcoind's dialect and the
+/// plan to answer it with, or the answer if there is nothing to forward.
+pub fn plan(body: &[u8]) -> Result<(Bytes, Plan), Value> {
+    let request: Value = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(_) => return Err(error(Value::Null, PARSE_ERROR_CODE, "Parse error")),
+    };
+    let (calls, batch) = match request {
+        Value::Array(calls) if !calls.is_empty() => (calls, true),
+        Value::Array(_) => {
+            return Err(error(
+                Value::Null,
+                INVALID_REQUEST_ERROR_CODE,
+                "Invalid Request",
+            ))
+        }
+        call => (vec![call], false),
+    };
+    let mut forwarded = Vec::with_capacity(calls.len());
+    let mut slots = Vec::with_capacity(calls.len());
+    for call in calls {
+        match check(call) {
+            Ok((call, notification)) => {
+                let id = call["id"].clone();
+                forwarded.push(call);
+                slots.push(Slot::Call { id, notification });
+            }
+            Err(id) => slots.push(Slot::Invalid { id }),
+        }
+    }
+    let plan = Plan { batch, slots };
+    if !plan.forwards() {
+        let mut errors: Vec<Value> = plan
+            .slots
+            .iter()
+            .filter_map(|slot| match slot {
+                Slot::Invalid { id } => Some(error(
+                    id.clone(),
+                    INVALID_REQUEST_ERROR_CODE,
+                    "Invalid Request",
+                )),
+                Slot::Call { .. } => None,
+            })
+            .collect();
+        return Err(if batch {
+            Value::Array(errors)
+        } else {
+            errors.swap_remove(0)
+        });
+    }
+    let forwarded = if batch {
+        serde_json::to_vec(&forwarded)
+    } else {
+        serde_json::to_vec(&forwarded[0])
+    };
+    match forwarded {
+        Ok(forwarded) => Ok((forwarded.into(), plan)),
+        Err(_) => Err(error(Value::Null, PARSE_ERROR_CODE, "Parse error")),
+    }
+}
+
+/// Codes of the errors of the proxy itself, in the range the specification reserves for its
+/// own errors, moved to the range of server errors.
+pub fn error_code(code: i64) -> i64 {
+    match code {
+        -32699..=-32604 => code + 600,
+        code => code,
+    }
+}
+
+/// Turns a response of bitcoind into a JSON-RPC 2.0 one.
+fn translate_fields(mut fields: Fields) -> Result<Fields, Error> {
+    let error = fields
+        .remove("error")
+        .filter(|e| e.get() != "null")
+        .map(|e| serde_json::from_str::<Map<String, Value>>(e.get()))
+        .transpose()?;
+    match error {
+        Some(mut error) => {
+            if let Some(code) = error.get("code").and_then(Value::as_i64) {
+                error.insert("code".to_owned(), error_code(code).into());
+            }
+            if error.get("data").is_some_and(Value::is_null) {
+                error.remove("data");
+            }
+            fields.remove("result");
+            fields.insert("error".to_owned(), to_raw_value(&error)?);
+        }
+        None if !fields.contains_key("result") => {
+            fields.insert("result".to_owned(), to_raw_value(&Value::Null)?);
+        }
+        None => (),
+    }
+    if !fields.contains_key("id") {
+        fields.insert("id".to_owned(), to_raw_value(&Value::Null)?);
+    }
+    fields.insert("jsonrpc".to_owned(), to_raw_value(&"2.0")?);
+    Ok(fields)
+}
+
+/// Answers the request `plan` was made for with `response`, the response of the proxy to the
+/// forwarded calls. Responses that aren't JSON-RPC, like authentication failures, are returned
+/// as they are.
+pub async fn translate(plan: &Plan, response: Response<Body>) -> Result<Response<Body>, Error> {
+    let (mut parts, body) = response.into_parts();
+    let body: Bytes = body.collect::<Result<Bytes, _>>().await?;
+    let passthrough = |parts, body: Bytes| Ok(Response::from_parts(parts, body.into()));
+    if !plan.batch {
+        let fields = match serde_json::from_slice::<Fields>(&body) {
+            Ok(fields) => fields,
+            Err(_) => return passthrough(parts, body),
+        };
+        if let Slot::Call {
+            notification: true, ..
+        } = plan.slots[0]
+        {
+            return no_content();
+        }
+        let body = serde_json::to_vec(&translate_fields(fields)?)?;
+        parts.status = StatusCode::OK;
+        parts.headers.insert(CONTENT_LENGTH, body.len().into());
+        return Ok(Response::from_parts(parts, body.into()));
+    }
+    let mut responses = match serde_json::from_slice::<Vec<Fields>>(&body) {
+        Ok(responses) => responses,
+        // an error for the whole batch, e.g. a call that isn't allowed, is the error of each call
+        Err(_) => match serde_json::from_slice::<Fields>(&body) {
+            Ok(response) => plan
+                .slots
+                .iter()
+                .filter_map(|slot| match slot {
+                    Slot::Call { id, .. } => {
+                        let mut response = response.clone();
+                        response.insert("id".to_owned(), to_raw_value(id).ok()?);
+                        Some(response)
+                    }
+                    Slot::Invalid { .. } => None,
+                })
+                .collect(),
+            Err(_) => return passthrough(parts, body),
+        },
+    }
+    .into_iter();
+    let mut answered = Vec::new();
+    for slot in &plan.slots {
+        match slot {
+            Slot::Invalid { id } => answered.push(to_raw_value(&error(
+                id.clone(),
+                INVALID_REQUEST_ERROR_CODE,
+                "Invalid Request",
+            ))?),
+            Slot::Call { notification, .. } => {
+                let response = match responses.next() {
+                    Some(response) => response,
+                    None => break,
+                };
+                if !notification {
+                    answered.push(to_raw_value(&translate_fields(response)?)?);
+                }
+            }
+        }
+    }
+    if answered.is_empty() {
+        return no_content();
+    }
+    let body = serde_json::to_vec(&answered)?;
+    parts.status = StatusCode::OK;
+    parts.headers.insert(CONTENT_LENGTH, body.len().into());
+    Ok(Response::from_parts(parts, body.into()))
+}
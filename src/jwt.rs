@@ -0,0 +1,187 @@
+//! Authentication of users with `Authorization: Bearer <jwt>`.
+
+use std::path::Path;
+
+use anyhow::{anyhow, Error};
+use jsonwebtoken::jwk::{AlgorithmParameters, JwkSet};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde_json::{Map, Value};
+
+const HMAC: &[Algorithm] = &[Algorithm::HS256, Algorithm::HS384, Algorithm::HS512];
+const RSA: &[Algorithm] = &[
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+];
+const EC: &[Algorithm] = &[Algorithm::ES256, Algorithm::ES384];
+const ED: &[Algorithm] = &[Algorithm::EdDSA];
+
+/// Seconds `exp` and `nbf` may be off by, for clocks that differ a bit.
+const LEEWAY: i64 = 60;
+
+/// A key tokens may be signed with.
+struct Key {
+    /// Identifies the key in the token header, any key is tried if `None`
+    id: Option<String>,
+    key: DecodingKey,
+    /// Algorithms the key is used with, so that a token can't pick a weaker one
+    algorithms: Vec<Algorithm>,
+}
+
+/// Verifies bearer tokens and maps them to configured users.
+pub struct JwtAuth {
+    keys: Vec<Key>,
+    /// Claim containing the name of the user whose permissions apply
+    pub user_claim: String,
+    audience: Option<String>,
+    issuer: Option<String>,
+}
+impl JwtAuth {
+    pub fn new(user_claim: String, audience: Option<String>, issuer: Option<String>) -> Self {
+        JwtAuth {
+            keys: Vec::new(),
+            user_claim,
+            audience,
+            issuer,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Accepts tokens signed with HMAC using `secret`.
+    pub fn add_secret(&mut self, secret: &[u8]) {
+        self.keys.push(Key {
+            id: None,
+            key: DecodingKey::from_secret(secret),
+            algorithms: HMAC.to_vec(),
+        });
+    }
+
+    /// Accepts tokens signed with the RSA, EC or Ed25519 public key in the PEM file at `path`.
+    pub fn add_public_key(&mut self, path: &Path) -> Result<(), Error> {
+        let pem = std::fs::read(path)?;
+        let (key, algorithms) = DecodingKey::from_rsa_pem(&pem)
+            .map(|k| (k, RSA))
+            .or_else(|_| DecodingKey::from_ec_pem(&pem).map(|k| (k, EC)))
+            .or_else(|_| DecodingKey::from_ed_pem(&pem).map(|k| (k, ED)))
+            .map_err(|_| anyhow!("{} doesn't contain a public key", path.display()))?;
+        self.keys.push(Key {
+            id: None,
+            key,
+            algorithms: algorithms.to_vec(),
+        });
+        Ok(())
+    }
+
+    /// Accepts tokens signed with the keys of the JWKS file at `path`.
+    pub fn add_jwks(&mut self, path: &Path) -> Result<(), Error> {
+        self.add_jwk_set(&serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    /// Accepts tokens signed with the keys of `jwks`.
+    pub fn add_jwk_set(&mut self, jwks: &JwkSet) -> Result<(), Error> {
+        for jwk in &jwks.keys {
+            let algorithms = match (jwk.common.algorithm, &jwk.algorithm) {
+                (Some(alg), _) => vec![alg],
+                (None, AlgorithmParameters::RSA(_)) => RSA.to_vec(),
+                (None, AlgorithmParameters::EllipticCurve(_)) => EC.to_vec(),
+                (None, AlgorithmParameters::OctetKeyPair(_)) => ED.to_vec(),
+                (None, AlgorithmParameters::OctetKey(_)) => HMAC.to_vec(),
+            };
+            let key = match &jwk.algorithm {
+                // `k` is base64url, which `from_jwk` decodes as standard base64
+                AlgorithmParameters::OctetKey(params) => DecodingKey::from_secret(
+                    &base64::decode_config(
+                        params.value.trim_end_matches('='),
+                        base64::URL_SAFE_NO_PAD,
+                    )
+                    .map_err(|e| anyhow!("invalid key {:?}: {}", jwk.common.key_id, e))?,
+                ),
+                _ => DecodingKey::from_jwk(jwk)?,
+            };
+            self.keys.push(Key {
+                id: jwk.common.key_id.clone(),
+                key,
+                algorithms,
+            });
+        }
+        Ok(())
+    }
+
+    /// Whether a key with the id of the header of `token` is known, or one without an id.
+    pub fn knows_key(&self, token: &str) -> bool {
+        decode_header(token).is_ok_and(|header| {
+            self.keys
+                .iter()
+                .any(|k| k.id.is_none() || header.kid.is_none() || k.id == header.kid)
+        })
+    }
+
+    /// Verifies `token`, returning the name of the user it was issued for.
+    pub fn verify(&self, token: &str) -> Result<String, Error> {
+        self.claims(token)?
+            .get(&self.user_claim)
+            .and_then(Value::as_str)
+            .map(str::to_owned)
+            .ok_or_else(|| anyhow!("token has no {} claim", self.user_claim))
+    }
+
+    /// Verifies `token`, returning its claims.
+    pub fn claims(&self, token: &str) -> Result<Map<String, Value>, Error> {
+        let header = decode_header(token)?;
+        let mut validation = Validation::new(header.alg);
+        // numbers are opaque to jsonwebtoken with serde_json's `arbitrary_precision`, so the times
+        // are checked by `check_times`
+        validation.validate_exp = false;
+        validation.required_spec_claims.clear();
+        if let Some(audience) = &self.audience {
+            validation.set_audience(&[audience]);
+        }
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        let mut last_error = anyhow!("no key for algorithm {:?}", header.alg);
+        for key in self.keys.iter().filter(|k| {
+            k.algorithms.contains(&header.alg)
+                && (k.id.is_none() || header.kid.is_none() || k.id == header.kid)
+        }) {
+            match decode::<Map<String, Value>>(token, &key.key, &validation) {
+                Ok(data) => return check_times(data.claims),
+                Err(e) => last_error = e.into(),
+            }
+        }
+        Err(last_error)
+    }
+}
+/// Checks that the token with `claims` has expired neither by `exp` nor is used before `nbf`.
+fn check_times(claims: Map<String, Value>) -> Result<Map<String, Value>, Error> {
+    let time = |claim| claims.get(claim).map(|t| t.as_f64().map(|t| t as i64));
+    let now = crate::clock::now().timestamp();
+    match time("exp") {
+        None => return Err(anyhow!("token has no exp claim")),
+        Some(None) => return Err(anyhow!("invalid exp claim")),
+        Some(Some(exp)) if exp + LEEWAY < now => return Err(anyhow!("token expired")),
+        Some(Some(_)) => (),
+    }
+    match time("nbf") {
+        Some(None) => Err(anyhow!("invalid nbf claim")),
+        Some(Some(nbf)) if nbf - LEEWAY > now => Err(anyhow!("token not valid yet")),
+        _ => Ok(claims),
+    }
+}
+
+impl std::fmt::Debug for JwtAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("JwtAuth")
+            .field("keys", &self.keys.len())
+            .field("user_claim", &self.user_claim)
+            .field("audience", &self.audience)
+            .field("issuer", &self.issuer)
+            .finish()
+    }
+}
@@ -0,0 +1,172 @@
+//! Per-user label namespaces in shared wallets.
+//!
+//! Labels of a user with a `label_prefix` are stored in the wallet with the prefix, which the
+//! proxy adds to the labels the user sets and strips from the labels it lists, leaving out the
+//! labels of everyone else.
+
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use futures::future::{BoxFuture, FutureExt};
+use hyper::StatusCode;
+use serde_json::Value;
+
+use crate::categories;
+use crate::client::{
+    GenericRpcMethod, RpcError, RpcRequest, RpcResponse, ACCESS_DENIED_ERROR_CODE,
+};
+use crate::intercept::{InterceptResult, Interceptor, RequestContext};
+use crate::journal;
+use crate::state::State;
+use crate::users::User;
+
+/// Methods taking or returning labels that are confined to the namespace of the user.
+pub const NAMESPACED_METHODS: &[&str] = &[
+    "getaddressesbylabel",
+    "getnewaddress",
+    "listlabels",
+    "listreceivedbylabel",
+    "setlabel",
+];
+
+/// Position of the label among the parameters of `method`.
+fn label_param(method: &str) -> Option<usize> {
+    match method {
+        "getaddressesbylabel" | "getnewaddress" => Some(0),
+        "setlabel" => Some(1),
+        _ => None,
+    }
+}
+
+/// Names of the labels of an address in a `getaddressinfo` result, which older versions of
+/// bitcoind return as objects.
+fn address_labels(info: &Value) -> Vec<&str> {
+    info.get("labels")
+        .and_then(Value::as_array)
+        .map(|labels| {
+            labels
+                .iter()
+                .filter_map(|l| l.as_str().or_else(|| l.get("name")?.as_str()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+async fn forward(
+    state: Arc<State>,
+    req: &RpcRequest<GenericRpcMethod>,
+    ctx: &RequestContext,
+) -> Result<RpcResponse<GenericRpcMethod>, RpcError> {
+    match &state.journal {
+        Some(journal) if categories::is_write(&req.method) => {
+            journal::forward(state.clone(), journal, &ctx.path, req, ctx).await
+        }
+        _ => Ok(state.rpc_client.call_at(&ctx.path, req).await?),
+    }
+}
+
+/// Rejects labelling an address already labelled in the namespace of another user.
+async fn check_address(
+    state: Arc<State>,
+    prefix: &str,
+    address: &Value,
+    ctx: &RequestContext,
+) -> Result<(), RpcError> {
+    let info = state
+        .rpc_client
+        .call_at(
+            &ctx.path,
+            &RpcRequest {
+                id: None,
+                method: GenericRpcMethod("getaddressinfo".to_owned()),
+                params: vec![address.clone()],
+            },
+        )
+        .await?
+        .into_result()?;
+    if address_labels(&info)
+        .iter()
+        .any(|l| !l.is_empty() && !l.starts_with(prefix))
+    {
+        return Err(RpcError {
+            code: ACCESS_DENIED_ERROR_CODE,
+            message: format!(
+                "Address {} is labelled by another user",
+                address.as_str().unwrap_or_default()
+            ),
+            data: None,
+            status: Some(StatusCode::FORBIDDEN),
+        });
+    }
+    Ok(())
+}
+
+/// Confines the labels used in the namespaced methods to the `label_prefix` of the user, if it
+/// has one.
+pub struct LabelNamespace;
+impl Interceptor for LabelNamespace {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            let prefix = match &user.label_prefix {
+                Some(prefix) => prefix,
+                None => return Ok(None),
+            };
+            let mut params = req.params.clone();
+            if let Some(i) = label_param(&req.method) {
+                if req.method.0 == "getnewaddress" && params.len() <= i {
+                    params.resize(i + 1, Value::Null);
+                }
+                match params.get_mut(i) {
+                    // the default label of the user is the bare prefix
+                    Some(label @ Value::Null) => *label = prefix.clone().into(),
+                    Some(Value::String(label)) => label.insert_str(0, prefix),
+                    Some(_) => return Err(anyhow!("label must be a string").into()),
+                    None => (),
+                }
+            }
+            if req.method.0 == "setlabel" {
+                if let Some(address) = params.first() {
+                    check_address(state.clone(), prefix, address, ctx).await?;
+                }
+            }
+            let namespaced = RpcRequest {
+                id: req.id.clone(),
+                method: GenericRpcMethod(req.method.0.clone()),
+                params,
+            };
+            let mut response = forward(state, &namespaced, ctx).await?;
+            response.id = req.id.clone();
+            response.result = match (req.method.0.as_str(), response.result) {
+                ("listlabels", Some(Value::Array(labels))) => Some(
+                    labels
+                        .iter()
+                        .filter_map(|l| l.as_str()?.strip_prefix(prefix.as_str()))
+                        .map(Value::from)
+                        .collect::<Vec<_>>()
+                        .into(),
+                ),
+                ("listreceivedbylabel", Some(Value::Array(entries))) => Some(
+                    entries
+                        .into_iter()
+                        .filter_map(|mut entry| {
+                            let label = entry.get("label")?.as_str()?;
+                            let stripped = label.strip_prefix(prefix.as_str())?.to_owned();
+                            entry["label"] = stripped.into();
+                            Some(entry)
+                        })
+                        .collect::<Vec<_>>()
+                        .into(),
+                ),
+                (_, result) => result,
+            };
+            Ok(Some(response))
+        }
+        .boxed()
+    }
+}
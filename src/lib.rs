@@ -1,3 +1,8 @@
+//! Finer-grained permission management for bitcoind.
+//!
+//! The binary is a thin wrapper around this library: build a [`State`] with [`State::builder`] and pass
+//! it to [`main`]. See the [`prelude`] for the part of the API that is kept stable.
+
 #![cfg_attr(feature = "old_rust", allow(unstable_name_collisions))]
 
 #[macro_use]
@@ -5,42 +10,444 @@ extern crate derive_more;
 #[macro_use]
 extern crate slog;
 
+pub mod acl;
+#[cfg(feature = "peer-fetch")]
+pub mod address_book;
+pub mod allowed_calls;
+pub mod api_keys;
+pub mod approvals;
+pub mod audit;
+pub mod auth;
+pub mod balances;
+#[cfg(feature = "peer-fetch")]
+pub mod block_cache;
+pub mod cache;
+pub mod cache_sync;
+pub mod capabilities;
+pub mod categories;
+#[cfg(feature = "chaos")]
+pub mod chaos;
 pub mod client;
+pub mod clock;
+pub mod compat;
+#[cfg(unix)]
+pub mod control;
+pub mod cookie;
+pub mod data_dir;
+#[cfg(feature = "websocket")]
+pub mod deflate;
+pub mod deprecation;
+pub mod dry_run;
+pub mod etag;
+pub mod events;
+#[cfg(feature = "peer-fetch")]
 pub mod fetch_blocks;
+pub mod filters;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod guardrails;
+#[cfg(unix)]
+pub mod handoff;
+pub mod headers;
+#[cfg(feature = "peer-fetch")]
+pub mod headers_first;
+pub mod http_client;
+pub mod idempotency;
+pub mod in_flight;
+pub mod intercept;
+pub mod journal;
+pub mod jsonrpc2;
+pub mod jwt;
+pub mod labels;
+pub mod lifecycle;
+pub mod listen;
+pub mod lockout;
+pub mod metrics;
+pub mod notify;
+pub mod oidc;
+pub mod onion;
+#[cfg(feature = "peer-fetch")]
+pub mod p2p;
+pub mod param_policy;
+pub mod password;
+pub mod paths;
+pub mod prelude;
+pub mod priority;
 pub mod proxy;
+pub mod quota;
+pub mod rate_limit;
+pub mod rbf;
+pub mod redact;
+pub mod redis;
+pub mod report;
+pub mod resolver;
+pub mod reuse;
+pub mod roles;
 pub mod rpc_methods;
+pub mod sats;
+#[cfg(feature = "peer-fetch")]
+pub mod scheduler;
+pub mod schema;
+pub mod secrets;
+pub mod self_test;
+pub mod signing;
+#[cfg(feature = "peer-fetch")]
+pub mod simulation;
 pub mod state;
+pub mod stats;
+pub mod storage;
+pub mod subscriptions;
+pub mod tenants;
+pub mod timeouts;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod tokens;
+pub mod tunnel;
+pub mod tx_status;
+#[cfg(feature = "cache")]
+pub mod txout_cache;
+pub mod upstream;
 pub mod users;
 pub mod util;
+pub mod warnings;
+#[cfg(feature = "websocket")]
+pub mod websocket;
 
 use std::convert::Infallible;
+use std::net::SocketAddr;
 use std::sync::Arc;
 
 use anyhow::Error;
+use futures::future::{BoxFuture, Future};
 use futures::FutureExt;
 use hyper::{
+    server::conn::AddrStream,
     service::{make_service_fn, service_fn},
-    Server,
+    Body, Request, Response, Server,
 };
+#[cfg(feature = "tls")]
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(feature = "tls")]
+use tokio_rustls::server::TlsStream;
 
 pub use crate::client::{AuthSource, RpcClient};
+#[cfg(feature = "peer-fetch")]
 pub use crate::fetch_blocks::Peers;
+use crate::headers::ResponseHeaders;
+pub use crate::intercept::{Interceptor, Interceptors};
+use crate::listen::{Listen, Listener};
+use crate::notify::Notifier;
 use crate::proxy::proxy_request;
-pub use crate::state::{State, TorState};
+pub use crate::state::State;
+#[cfg(feature = "tor")]
+pub use crate::state::TorState;
+use crate::users::ClientCert;
 pub use crate::users::{User, Users};
 
-pub async fn main(state: Arc<State>) -> Result<(), Error> {
+/// Applies the HTTP settings of the listener.
+fn http_options<I>(builder: hyper::server::Builder<I>, state: &State) -> hyper::server::Builder<I> {
+    builder
+        .http1_only(!state.http2)
+        .http2_max_concurrent_streams(state.http2_max_streams)
+        // large responses such as blocks would be throttled by the default window of 64 KiB
+        .http2_adaptive_window(true)
+}
+
+/// The body of the responses, which carries the trailers of gRPC calls.
+#[cfg(feature = "grpc")]
+type ResponseBody = crate::grpc::TrailersBody;
+#[cfg(not(feature = "grpc"))]
+type ResponseBody = Body;
+
+/// Answers a request on a connection from `remote_addr`, which is unknown on Unix sockets, to a
+/// listener sending `headers` instead of the global ones.
+fn handle(
+    state: Arc<State>,
+    headers: Option<Arc<ResponseHeaders>>,
+    remote_addr: Option<SocketAddr>,
+    client_cert: Option<ClientCert>,
+    mut req: Request<Body>,
+) -> BoxFuture<'static, Result<Response<ResponseBody>, Error>> {
+    if let Some(headers) = headers {
+        req.extensions_mut().insert(headers);
+    }
+    if let Some(remote_addr) = remote_addr {
+        req.extensions_mut().insert(remote_addr);
+    }
+    if let Some(client_cert) = client_cert {
+        req.extensions_mut().insert(client_cert);
+    }
+    #[cfg(feature = "grpc")]
+    return futures::TryFutureExt::map_ok(
+        proxy_request(state, req),
+        crate::grpc::TrailersBody::wrap,
+    )
+    .boxed();
+    #[cfg(not(feature = "grpc"))]
+    return proxy_request(state, req).boxed();
+}
+
+/// Serves the proxy on `listener` until `shutdown` and the requests in flight by then are
+/// answered.
+#[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+fn serve(
+    state: Arc<State>,
+    listen: &Listen,
+    listener: Listener,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<BoxFuture<'static, Result<(), hyper::Error>>, Error> {
     let state_local = state.clone();
-    let make_service = make_service_fn(move |_conn| {
-        let state_local_local = state_local.clone();
+    let headers = listen.headers.clone();
+    let server = match listener {
+        #[cfg(feature = "tls")]
+        Listener::Tcp(listener) if listen.tls.is_some() => {
+            let tls = listen.tls.clone().unwrap();
+            let tls_local = tls.clone();
+            let make_service = make_service_fn(move |conn: &TlsStream<TcpStream>| {
+                let state = state_local.clone();
+                let headers = headers.clone();
+                let remote_addr = conn.get_ref().0.peer_addr().ok();
+                let client_cert = tls_local.client_cert(conn);
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req| {
+                        handle(
+                            state.clone(),
+                            headers.clone(),
+                            remote_addr,
+                            client_cert.clone(),
+                            req,
+                        )
+                    }))
+                }
+            });
+            let incoming = tls.incoming(TcpListener::from_std(listener)?, state.logger.clone());
+            http_options(
+                Server::builder(hyper::server::accept::from_stream(incoming)),
+                &state,
+            )
+            .serve(make_service)
+            .with_graceful_shutdown(shutdown)
+            .boxed()
+        }
+        Listener::Tcp(listener) => {
+            let make_service = make_service_fn(move |conn: &AddrStream| {
+                let state = state_local.clone();
+                let headers = headers.clone();
+                let remote_addr = conn.remote_addr();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req| {
+                        handle(state.clone(), headers.clone(), Some(remote_addr), None, req)
+                    }))
+                }
+            });
+            http_options(Server::from_tcp(listener)?, &state)
+                .serve(make_service)
+                .with_graceful_shutdown(shutdown)
+                .boxed()
+        }
+        #[cfg(unix)]
+        Listener::Unix(listener) => {
+            listener.set_nonblocking(true)?;
+            let listener = tokio::net::UnixListener::from_std(listener)?;
+            let make_service = make_service_fn(move |_: &tokio::net::UnixStream| {
+                let state = state_local.clone();
+                let headers = headers.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req| {
+                        handle(state.clone(), headers.clone(), None, None, req)
+                    }))
+                }
+            });
+            http_options(
+                Server::builder(hyper::server::accept::from_stream(listener)),
+                &state,
+            )
+            .serve(make_service)
+            .with_graceful_shutdown(shutdown)
+            .boxed()
+        }
+    };
+    Ok(server)
+}
+
+/// Serves the proxy on `listener`, which only the onion service leads to, until `shutdown`. Tor
+/// connects from loopback, so the address of clients is left unknown rather than passing for a
+/// local one. Responses carry the global headers.
+fn serve_onion(
+    state: Arc<State>,
+    listener: std::net::TcpListener,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<BoxFuture<'static, Result<(), hyper::Error>>, Error> {
+    let state_local = state.clone();
+    let make_service = make_service_fn(move |_: &AddrStream| {
+        let state = state_local.clone();
         async move {
             Ok::<_, Infallible>(service_fn(move |req| {
-                proxy_request(state_local_local.clone(), req).boxed()
+                handle(state.clone(), None, None, None, req)
             }))
         }
     });
+    Ok(http_options(Server::from_tcp(listener)?, &state)
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown)
+        .boxed())
+}
+
+/// Serves the proxy on the sockets of `state.listen` until a server fails or `state.shutdown` is
+/// triggered, by the embedding application or by a new process taking the sockets over.
+pub async fn main(state: Arc<State>) -> Result<(), Error> {
+    if let Some(cookie) = &state.cookie {
+        cookie.write()?;
+        info!(state.logger, "Wrote cookie file {}", cookie.path.display());
+    }
+    if let Some(signer) = &state.response_signer {
+        info!(
+            state.logger,
+            "Signing responses with key {}",
+            hex::encode(signer.public_key())
+        );
+    }
+
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let state = state.clone();
+        let mut sigusr1 = signal(SignalKind::user_defined1())?;
+        spawn_until_shutdown(&state.clone(), async move {
+            while sigusr1.recv().await.is_some() {
+                state.set_read_only(!state.read_only.load(std::sync::atomic::Ordering::SeqCst));
+            }
+        });
+    }
+
+    if state.approvals.is_some() {
+        let state = state.clone();
+        spawn_until_shutdown(&state.clone(), async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(10));
+            loop {
+                interval.tick().await;
+                if let Some(approvals) = &state.approvals {
+                    for (id, details) in approvals.expire() {
+                        info!(state.logger, "Approval {} expired", id);
+                        Notifier::spawn(state.clone(), "approval_expired", id, details);
+                    }
+                }
+            }
+        });
+    }
 
-    let server = Server::bind(&state.bind).serve(make_service);
+    spawn_until_shutdown(&state, crate::cache_sync::CacheSync::run(state.clone()));
+    spawn_until_shutdown(
+        &state,
+        crate::capabilities::Capabilities::run(state.clone()),
+    );
+    #[cfg(feature = "cache")]
+    spawn_until_shutdown(&state, crate::txout_cache::TxOutCache::run(state.clone()));
+    spawn_until_shutdown(&state, crate::oidc::Oidc::run(state.clone()));
+    spawn_until_shutdown(&state, crate::balances::Balances::run(state.clone()));
+    spawn_until_shutdown(
+        &state,
+        crate::subscriptions::Subscriptions::run(state.clone()),
+    );
+    spawn_until_shutdown(&state, crate::priority::Load::run(state.clone()));
+
+    #[cfg(feature = "peer-fetch")]
+    spawn_until_shutdown(&state, crate::fetch_blocks::keep_peers_warm(state.clone()));
+
+    {
+        let state = state.clone();
+        spawn_until_shutdown(&state.clone(), async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                save_usage(&state);
+            }
+        });
+    }
+
+    #[cfg(unix)]
+    let listeners = {
+        let (listeners, takeover) = crate::handoff::listen(&state)?;
+        if let Some(takeover) = takeover {
+            // connections queue in the backlog of the sockets until the servers are polled
+            tokio::task::spawn_blocking(move || takeover.finish()).await??;
+        }
+        listeners
+    };
+    #[cfg(not(unix))]
+    let listeners = {
+        if state.control_socket.is_some() {
+            return Err(anyhow::anyhow!("control_socket is only supported on unix"));
+        }
+        state
+            .listen
+            .iter()
+            .map(|listen| Listener::bind(&listen.address))
+            .collect::<Result<Vec<_>, _>>()?
+    };
+    let shutdown = state.shutdown.clone();
+    let shutdown = async move { shutdown.triggered().await }.boxed().shared();
+    #[cfg(unix)]
+    {
+        let state = state.clone();
+        let listeners = listeners
+            .iter()
+            .map(Listener::try_clone)
+            .collect::<Result<Vec<_>, _>>()?;
+        spawn_until_shutdown(&state.clone(), async move {
+            if let Err(e) = crate::control::serve(state.clone(), listeners).await {
+                error!(state.logger, "Failed to serve the control socket: {:#}", e);
+            }
+        });
+    }
+
+    let mut servers = Vec::new();
+    for (listen, listener) in state.listen.iter().zip(listeners) {
+        info!(state.logger, "Listening on {}", listen.address);
+        servers.push(serve(state.clone(), listen, listener, shutdown.clone())?);
+    }
+    if state.onion.is_some() {
+        let listener = std::net::TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))?;
+        let target = listen::Address::Tcp(listener.local_addr()?);
+        servers.push(serve_onion(state.clone(), listener, shutdown.clone())?);
+        spawn_until_shutdown(&state, crate::onion::Onion::run(state.clone(), target));
+    }
+    state.hooks.started(&state);
+    let served = futures::future::try_join_all(servers).await;
+    // a failed server stops the others too
+    state.shutdown.trigger();
+    save_usage(&state);
+    if served.is_ok() {
+        info!(state.logger, "Finished in-flight requests, exiting");
+    }
+    state.hooks.shut_down();
+    served?;
+    Ok(())
+}
+
+/// Runs the background task `task` until the proxy shuts down.
+fn spawn_until_shutdown(state: &Arc<State>, task: impl Future<Output = ()> + Send + 'static) {
+    let shutdown = state.shutdown.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = task => (),
+            _ = shutdown.triggered() => (),
+        }
+    });
+}
 
-    Ok(server.await?)
+/// Saves quota usage and what is learnt about peers, which is otherwise done every minute.
+fn save_usage(state: &State) {
+    if let Err(e) = state.quota_usage.save() {
+        warn!(state.logger, "Failed to save quota usage: {:#}", e);
+    }
+    #[cfg(feature = "peer-fetch")]
+    if let Err(e) = state.fetch_usage.save() {
+        warn!(state.logger, "Failed to save fetch quota usage: {:#}", e);
+    }
+    #[cfg(feature = "peer-fetch")]
+    if let Err(e) = state.address_book.save() {
+        warn!(state.logger, "Failed to save the address book: {:#}", e);
+    }
 }
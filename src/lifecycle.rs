@@ -0,0 +1,110 @@
+//! Hooks into the lifecycle of the proxy and a handle to shut it down, for applications embedding
+//! it that coordinate it with their own components.
+//!
+//! Shutting down is graceful: the proxy stops accepting connections and background tasks, answers
+//! the requests in flight and then returns from [`main`](crate::main). A new process taking over
+//! through the control socket shuts the running one down the same way.
+
+use std::sync::Arc;
+
+use bitcoin::BlockHash;
+use tokio::sync::watch;
+
+use crate::state::State;
+
+type StartHook = Box<dyn Fn(&Arc<State>) + Send + Sync>;
+
+/// Functions called by the proxy as it starts, notices new blocks and shuts down. They are called
+/// on the runtime of the proxy, so they should return quickly and spawn anything taking longer.
+#[derive(Default)]
+pub struct Hooks {
+    on_start: Vec<StartHook>,
+    on_tip: Vec<Box<dyn Fn(BlockHash) + Send + Sync>>,
+    on_shutdown: Vec<Box<dyn Fn() + Send + Sync>>,
+}
+impl Hooks {
+    /// Calls `hook` once the proxy listens on all its addresses.
+    pub fn on_start(mut self, hook: impl Fn(&Arc<State>) + Send + Sync + 'static) -> Self {
+        self.on_start.push(Box::new(hook));
+        self
+    }
+
+    /// Calls `hook` with the new best block whenever it changes. The tip is checked every
+    /// `tip_poll_interval`, or learnt from other proxies sharing `cache_sync_redis`.
+    pub fn on_tip(mut self, hook: impl Fn(BlockHash) + Send + Sync + 'static) -> Self {
+        self.on_tip.push(Box::new(hook));
+        self
+    }
+
+    /// Calls `hook` once the proxy has shut down, before `main` returns.
+    pub fn on_shutdown(mut self, hook: impl Fn() + Send + Sync + 'static) -> Self {
+        self.on_shutdown.push(Box::new(hook));
+        self
+    }
+
+    pub(crate) fn wants_tip(&self) -> bool {
+        !self.on_tip.is_empty()
+    }
+
+    pub(crate) fn started(&self, state: &Arc<State>) {
+        for hook in &self.on_start {
+            hook(state);
+        }
+    }
+
+    pub(crate) fn tip(&self, hash: BlockHash) {
+        for hook in &self.on_tip {
+            hook(hash);
+        }
+    }
+
+    pub(crate) fn shut_down(&self) {
+        for hook in &self.on_shutdown {
+            hook();
+        }
+    }
+}
+impl std::fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Hooks")
+            .field("on_start", &self.on_start.len())
+            .field("on_tip", &self.on_tip.len())
+            .field("on_shutdown", &self.on_shutdown.len())
+            .finish()
+    }
+}
+
+/// Shuts the proxy down when triggered. Clones trigger the same shutdown.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+}
+impl Default for Shutdown {
+    fn default() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        Shutdown {
+            sender: Arc::new(sender),
+            receiver,
+        }
+    }
+}
+impl Shutdown {
+    /// Starts shutting the proxy down, returning right away.
+    pub fn trigger(&self) {
+        let _ = self.sender.broadcast(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Resolves once the shutdown is triggered.
+    pub async fn triggered(&self) {
+        let mut receiver = self.receiver.clone();
+        // the sender lives as long as this handle, so `recv` doesn't run dry
+        while !*receiver.borrow() {
+            receiver.recv().await;
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! The sockets the proxy listens on: TCP addresses and, on unix, Unix sockets, each with TLS
+//! settings and response headers of its own. All of them serve the same proxy.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::headers::ResponseHeaders;
+#[cfg(feature = "tls")]
+use crate::tls::Tls;
+
+/// An address to listen on: `host:port` or `unix:/path/to/socket`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+impl std::str::FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            #[cfg(unix)]
+            return match path {
+                "" => Err(format!("{:?} has no path", s)),
+                path => Ok(Address::Unix(path.into())),
+            };
+            #[cfg(not(unix))]
+            return Err(format!(
+                "{:?}: Unix sockets are only supported on unix",
+                path
+            ));
+        }
+        s.parse()
+            .map(Address::Tcp)
+            .map_err(|_| format!("{:?} is neither host:port nor unix:<path>", s))
+    }
+}
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Address::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            Address::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+impl<'de> serde::Deserialize<'de> for Address {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// An entry of `bind`: an address, or a table with the address and the TLS settings and
+/// response headers to serve it with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bind {
+    pub address: Address,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_client_ca: Option<PathBuf>,
+    /// Replaces the global `forward_response_headers` if set
+    pub forward_response_headers: Option<Vec<String>>,
+    /// Added to the global `response_header`, replacing headers of the same name
+    pub response_header: HashMap<String, String>,
+}
+impl From<Address> for Bind {
+    fn from(address: Address) -> Self {
+        Bind {
+            address,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            forward_response_headers: None,
+            response_header: HashMap::new(),
+        }
+    }
+}
+impl Bind {
+    /// Whether the entry sets headers of its own.
+    pub fn has_headers(&self) -> bool {
+        self.forward_response_headers.is_some() || !self.response_header.is_empty()
+    }
+}
+impl<'de> serde::Deserialize<'de> for Bind {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Table {
+            address: Address,
+            tls_cert: Option<PathBuf>,
+            tls_key: Option<PathBuf>,
+            tls_client_ca: Option<PathBuf>,
+            forward_response_headers: Option<Vec<String>>,
+            #[serde(default)]
+            response_header: HashMap<String, String>,
+        }
+
+        struct Visitor;
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = Bind;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("an address or a table with one")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, s: &str) -> Result<Bind, E> {
+                s.parse::<Address>().map(Bind::from).map_err(E::custom)
+            }
+
+            fn visit_map<M: serde::de::MapAccess<'de>>(self, map: M) -> Result<Bind, M::Error> {
+                use serde::Deserialize;
+
+                let table = Table::deserialize(serde::de::value::MapAccessDeserializer::new(map))?;
+                Ok(Bind {
+                    address: table.address,
+                    tls_cert: table.tls_cert,
+                    tls_key: table.tls_key,
+                    tls_client_ca: table.tls_client_ca,
+                    forward_response_headers: table.forward_response_headers,
+                    response_header: table.response_header,
+                })
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+/// A socket the proxy listens on.
+#[derive(Debug)]
+pub struct Listen {
+    pub address: Address,
+    /// Serves the socket over TLS if configured
+    #[cfg(feature = "tls")]
+    pub tls: Option<Arc<Tls>>,
+    /// Headers of the responses sent on the socket, the global ones if `None`
+    pub headers: Option<Arc<ResponseHeaders>>,
+}
+
+/// A bound listening socket.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(std::net::TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener),
+}
+impl Listener {
+    /// Binds `address`. A Unix socket left behind by a process that is gone is replaced.
+    pub fn bind(address: &Address) -> std::io::Result<Self> {
+        match address {
+            Address::Tcp(addr) => Ok(Listener::Tcp(std::net::TcpListener::bind(addr)?)),
+            #[cfg(unix)]
+            Address::Unix(path) => {
+                use std::os::unix::net::{UnixListener, UnixStream};
+
+                if let Err(e) = UnixStream::connect(path) {
+                    if e.kind() == std::io::ErrorKind::ConnectionRefused {
+                        std::fs::remove_file(path)?;
+                    }
+                }
+                Ok(Listener::Unix(UnixListener::bind(path)?))
+            }
+        }
+    }
+
+    /// The address the socket is bound to.
+    pub fn address(&self) -> std::io::Result<Address> {
+        match self {
+            Listener::Tcp(listener) => Ok(Address::Tcp(listener.local_addr()?)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener
+                .local_addr()?
+                .as_pathname()
+                .map(|path| Address::Unix(path.to_owned()))
+                .ok_or_else(|| std::io::Error::other("unnamed Unix socket")),
+        }
+    }
+
+    pub fn try_clone(&self) -> std::io::Result<Self> {
+        match self {
+            Listener::Tcp(listener) => Ok(Listener::Tcp(listener.try_clone()?)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => Ok(Listener::Unix(listener.try_clone()?)),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl std::os::unix::io::AsRawFd for Listener {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+#[cfg(unix)]
+impl std::os::unix::io::FromRawFd for Listener {
+    /// Takes the listening socket `fd`, of either kind.
+    unsafe fn from_raw_fd(fd: std::os::unix::io::RawFd) -> Self {
+        let mut addr: libc::sockaddr_storage = std::mem::zeroed();
+        let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        let unix = libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) == 0
+            && i32::from(addr.ss_family) == libc::AF_UNIX;
+        if unix {
+            Listener::Unix(std::os::unix::net::UnixListener::from_raw_fd(fd))
+        } else {
+            Listener::Tcp(std::net::TcpListener::from_raw_fd(fd))
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! Temporary lockout of addresses and user names after repeated failed authentication attempts,
+//! to slow down guessing passwords.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::StatusCode;
+use serde_json::{json, Value};
+
+use crate::client::{RpcError, ACCESS_DENIED_ERROR_CODE};
+
+/// Counters are only swept once there are this many, so that a few clients don't pay for it.
+const SWEEP_THRESHOLD: usize = 1024;
+
+#[derive(Debug)]
+struct Counter {
+    failures: u32,
+    /// Start of the window the failures were counted in
+    since: Instant,
+    locked_until: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Counters<K>(Mutex<HashMap<K, Counter>>);
+impl<K: Hash + Eq> Counters<K> {
+    fn new() -> Self {
+        Counters(Mutex::new(HashMap::new()))
+    }
+
+    fn locked(&self, key: &K, now: Instant) -> Option<Duration> {
+        let counters = self.0.lock().unwrap();
+        let until = counters.get(key)?.locked_until?;
+        until.checked_duration_since(now)
+    }
+
+    /// Counts a failure, returning whether it locked `key` out.
+    fn fail(&self, key: K, lockout: &Lockout, now: Instant) -> bool {
+        let mut counters = self.0.lock().unwrap();
+        if counters.len() >= SWEEP_THRESHOLD {
+            counters.retain(|_, c| {
+                now.duration_since(c.since) < lockout.window
+                    || c.locked_until.is_some_and(|until| until > now)
+            });
+        }
+        let counter = counters.entry(key).or_insert(Counter {
+            failures: 0,
+            since: now,
+            locked_until: None,
+        });
+        if now.duration_since(counter.since) >= lockout.window {
+            counter.failures = 0;
+            counter.since = now;
+        }
+        counter.failures += 1;
+        if counter.failures >= lockout.max_failures {
+            counter.failures = 0;
+            counter.since = now;
+            counter.locked_until = Some(now + lockout.duration);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn reset(&self, key: &K) {
+        self.0.lock().unwrap().remove(key);
+    }
+}
+impl<K: Hash + Eq + std::fmt::Display> Counters<K> {
+    /// Keys with failures counted at `now` or locked out, for state dumps.
+    fn dump(&self, lockout: &Lockout, now: Instant) -> Value {
+        let counters = self.0.lock().unwrap();
+        let mut keys: Vec<_> = counters
+            .iter()
+            .filter_map(|(key, c)| {
+                let locked_for = c.locked_until.and_then(|u| u.checked_duration_since(now));
+                let failures = if now.duration_since(c.since) < lockout.window {
+                    c.failures
+                } else {
+                    0
+                };
+                if failures == 0 && locked_for.is_none() {
+                    return None;
+                }
+                Some(json!({
+                    "key": key.to_string(),
+                    "failures": failures,
+                    "locked_for": locked_for.map(|d| d.as_secs_f64()),
+                }))
+            })
+            .collect();
+        keys.sort_by(|a, b| a["key"].as_str().cmp(&b["key"].as_str()));
+        keys.into()
+    }
+}
+
+#[derive(Debug)]
+pub struct Lockout {
+    /// Failed attempts within `window` locking out an address or user name
+    pub max_failures: u32,
+    pub window: Duration,
+    /// How long a lockout lasts
+    pub duration: Duration,
+    by_ip: Counters<IpAddr>,
+    by_user: Counters<String>,
+}
+impl Lockout {
+    pub fn new(max_failures: u32, window: Duration, duration: Duration) -> Self {
+        Lockout {
+            max_failures,
+            window,
+            duration,
+            by_ip: Counters::new(),
+            by_user: Counters::new(),
+        }
+    }
+
+    /// Rejects attempts from a locked out address or for a locked out user name.
+    pub fn check(&self, ip: Option<IpAddr>, user: Option<&str>) -> Result<(), RpcError> {
+        let now = Instant::now();
+        let wait = ip
+            .and_then(|ip| self.by_ip.locked(&ip, now))
+            .into_iter()
+            .chain(user.and_then(|user| self.by_user.locked(&user.to_owned(), now)))
+            .max();
+        match wait {
+            None => Ok(()),
+            Some(wait) => Err(RpcError {
+                code: ACCESS_DENIED_ERROR_CODE,
+                message: format!(
+                    "Too many failed authentication attempts, try again in {} seconds",
+                    wait.as_secs_f64().ceil()
+                ),
+                data: Some(json!({ "retry_after": wait.as_secs_f64() })),
+                status: Some(StatusCode::TOO_MANY_REQUESTS),
+            }),
+        }
+    }
+
+    /// Counts a failed attempt, returning whether it locked out the address or user name.
+    pub fn fail(&self, ip: Option<IpAddr>, user: Option<&str>) -> bool {
+        let now = Instant::now();
+        let ip_locked = ip.is_some_and(|ip| self.by_ip.fail(ip, self, now));
+        let user_locked = user.is_some_and(|user| self.by_user.fail(user.to_owned(), self, now));
+        ip_locked || user_locked
+    }
+
+    /// The addresses and user names with recent failures or locked out.
+    pub fn dump(&self) -> Value {
+        let now = Instant::now();
+        json!({
+            "addresses": self.by_ip.dump(self, now),
+            "users": self.by_user.dump(self, now),
+        })
+    }
+
+    /// Forgets the failures of a user name after it authenticated successfully.
+    pub fn succeed(&self, user: Option<&str>) {
+        if let Some(user) = user {
+            self.by_user.reset(&user.to_owned());
+        }
+    }
+}
@@ -4,13 +4,113 @@ extern crate configure_me;
 #[macro_use]
 extern crate serde;
 
-use anyhow::Error;
+use anyhow::{anyhow, Error};
 use btc_rpc_proxy;
 
 mod create_state;
 
+/// Reads a password from stdin and prints its hash to put into the config.
+fn hash_password(args: impl Iterator<Item = std::ffi::OsString>) -> Result<(), Error> {
+    use btc_rpc_proxy::password::{hash, Scheme};
+
+    let mut scheme = Scheme::Argon2;
+    for arg in args {
+        match arg.to_str() {
+            Some("--bcrypt") => scheme = Scheme::Bcrypt,
+            Some("--argon2") => scheme = Scheme::Argon2,
+            _ => {
+                return Err(anyhow!(
+                    "usage: btc_rpc_proxy hash-password [--argon2|--bcrypt]"
+                ))
+            }
+        }
+    }
+    eprintln!("Password (read from stdin):");
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    let password = password.trim_end_matches(&['\r', '\n'][..]);
+    if password.is_empty() {
+        return Err(anyhow!("empty password"));
+    }
+    println!("{}", hash(password, scheme)?);
+    Ok(())
+}
+
+/// Checks an `X-Proxy-Signature` header against the request and response it was sent for.
+fn verify_response(args: impl Iterator<Item = std::ffi::OsString>) -> Result<(), Error> {
+    use btc_rpc_proxy::signing::Signature;
+
+    const USAGE: &str = "usage: btc_rpc_proxy verify-response --key <hex public key> \
+                         --signature <header value> [--path <path>] [--user <caller>] \
+                         <request file> <response file>";
+    let mut args = args.map(|a| a.into_string().map_err(|_| anyhow!(USAGE)));
+    let (mut key, mut signature, mut files) = (None, None, Vec::new());
+    let (mut path, mut user) = (None, None);
+    while let Some(arg) = args.next() {
+        match arg?.as_str() {
+            "--key" => key = Some(args.next().ok_or_else(|| anyhow!(USAGE))??),
+            "--signature" => signature = Some(args.next().ok_or_else(|| anyhow!(USAGE))??),
+            "--path" => path = Some(args.next().ok_or_else(|| anyhow!(USAGE))??),
+            "--user" => user = Some(args.next().ok_or_else(|| anyhow!(USAGE))??),
+            file => files.push(file.to_owned()),
+        }
+    }
+    let (key, signature, request, response) = match (key, signature, &files[..]) {
+        (Some(key), Some(signature), [request, response]) => (key, signature, request, response),
+        _ => return Err(anyhow!(USAGE)),
+    };
+    let key = hex::decode(key.trim()).map_err(|_| anyhow!("the key isn't hex"))?;
+    let signature: Signature = signature.parse()?;
+    let read =
+        |path: &str| std::fs::read(path).map_err(|e| anyhow!("failed to read {}: {}", path, e));
+    signature.verify(&key, &read(request)?, &read(response)?)?;
+    if let Some(path) = path {
+        let path = btc_rpc_proxy::paths::normalize(&path)
+            .ok_or_else(|| anyhow!("{} isn't a JSON-RPC path", path))?;
+        if path != signature.path {
+            return Err(anyhow!("the request was sent to {}", signature.path));
+        }
+    }
+    if user.is_some_and(|user| user != signature.caller) {
+        return Err(anyhow!("the request was sent by {}", signature.caller));
+    }
+    let time = chrono::TimeZone::timestamp_opt(&chrono::Utc, signature.time, 0)
+        .single()
+        .ok_or_else(|| anyhow!("invalid time of the signature"))?;
+    println!(
+        "Valid signature of a response to {} {} by {} sent at {}",
+        signature.method,
+        signature.path,
+        signature.caller,
+        time.to_rfc3339()
+    );
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    let state = create_state::create_state()?.arc();
-    btc_rpc_proxy::main(state).await
+    // don't need a configuration
+    let mut args = std::env::args_os().skip(1);
+    match args.next().as_ref().and_then(|a| a.to_str()) {
+        Some("hash-password") => return hash_password(args),
+        Some("verify-response") => return verify_response(args),
+        _ => (),
+    }
+    let (state, args) = create_state::create_state()?;
+    let state = state.arc();
+    match args.first().and_then(|a| a.to_str()) {
+        None => btc_rpc_proxy::main(state).await,
+        Some("self-test") => {
+            let checks = btc_rpc_proxy::self_test::self_test(state).await;
+            for check in &checks {
+                println!("{}", check);
+            }
+            if checks.iter().all(|c| c.result.is_ok()) {
+                Ok(())
+            } else {
+                std::process::exit(1)
+            }
+        }
+        Some(command) => Err(anyhow!("unknown command {}", command)),
+    }
 }
@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+#[cfg(feature = "metrics")]
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+
+#[cfg(feature = "metrics")]
+use crate::cache::CacheInfo;
+#[cfg(feature = "metrics")]
+use crate::state::State;
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ErrorSummary {
+    pub count: u64,
+    pub last_seen: DateTime<Utc>,
+}
+
+/// Counters exported in the Prometheus text format on `/metrics`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub forwarded: AtomicU64,
+    pub intercepted: AtomicU64,
+    pub errors: AtomicU64,
+    pub schema_divergences: AtomicU64,
+    /// Failed calls by method and error code
+    pub error_summaries: Mutex<HashMap<(String, i64), ErrorSummary>>,
+}
+impl Metrics {
+    /// How many distinct kinds of errors are summarized.
+    const MAX_ERROR_SUMMARIES: usize = 100;
+
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_error(&self, method: &str, code: i64) {
+        let mut summaries = self.error_summaries.lock().unwrap();
+        let key = (method.to_owned(), code);
+        if summaries.len() >= Self::MAX_ERROR_SUMMARIES && !summaries.contains_key(&key) {
+            return;
+        }
+        let summary = summaries.entry(key).or_insert(ErrorSummary {
+            count: 0,
+            last_seen: Utc::now(),
+        });
+        summary.count += 1;
+        summary.last_seen = Utc::now();
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn render(&self, state: &State) -> String {
+        let mut out = String::new();
+        let mut counter = |name: &str, help: &str, value: u64| {
+            writeln!(out, "# HELP btc_rpc_proxy_{} {}", name, help).unwrap();
+            writeln!(out, "# TYPE btc_rpc_proxy_{} counter", name).unwrap();
+            writeln!(out, "btc_rpc_proxy_{} {}", name, value).unwrap();
+        };
+        counter(
+            "forwarded_total",
+            "Calls forwarded to bitcoind",
+            self.forwarded.load(Ordering::Relaxed),
+        );
+        counter(
+            "intercepted_total",
+            "Calls answered by the proxy",
+            self.intercepted.load(Ordering::Relaxed),
+        );
+        counter(
+            "errors_total",
+            "Calls that failed or were denied",
+            self.errors.load(Ordering::Relaxed),
+        );
+        counter(
+            "cookie_reloads_total",
+            "Calls retried with a changed cookie after bitcoind rejected the old one",
+            state.rpc_client.cookie_reloads.load(Ordering::Relaxed),
+        );
+        counter(
+            "schema_divergences_total",
+            "Responses not matching the schema expected for their method",
+            self.schema_divergences.load(Ordering::Relaxed),
+        );
+        if let Some(subscriptions) = &state.subscriptions {
+            counter(
+                "subscription_events_dropped_total",
+                "Events dropped or coalesced for subscribers reading too slowly",
+                subscriptions.dropped.load(Ordering::Relaxed),
+            );
+            counter(
+                "subscription_overflows_total",
+                "Subscribers disconnected for reading too slowly",
+                subscriptions.overflowed.load(Ordering::Relaxed),
+            );
+        }
+        writeln!(
+            out,
+            "# HELP btc_rpc_proxy_deprecated_calls_total Calls of deprecated methods by user"
+        )
+        .unwrap();
+        writeln!(out, "# TYPE btc_rpc_proxy_deprecated_calls_total counter").unwrap();
+        for ((user, method), count) in state.deprecations.calls() {
+            writeln!(
+                out,
+                "btc_rpc_proxy_deprecated_calls_total{{user=\"{}\",method=\"{}\"}} {}",
+                user.escape_default(),
+                method.escape_default(),
+                count
+            )
+            .unwrap();
+        }
+        let mut gauge = |name: &str, help: &str, value: u64| {
+            writeln!(out, "# HELP btc_rpc_proxy_{} {}", name, help).unwrap();
+            writeln!(out, "# TYPE btc_rpc_proxy_{} gauge", name).unwrap();
+            writeln!(out, "btc_rpc_proxy_{} {}", name, value).unwrap();
+        };
+        gauge(
+            "read_only",
+            "Whether the emergency read-only mode is active",
+            state.read_only.load(Ordering::SeqCst) as u64,
+        );
+        gauge(
+            "degraded",
+            "Whether bitcoind is degraded and batch users are slowed down",
+            state.load.as_ref().is_some_and(|l| l.is_degraded()) as u64,
+        );
+        gauge(
+            "in_flight_requests",
+            "Requests being handled",
+            state.in_flight.len() as u64,
+        );
+        if let Some(subscriptions) = &state.subscriptions {
+            gauge(
+                "subscribers",
+                "Clients connected to /ws",
+                subscriptions.subscribers() as u64,
+            );
+        }
+        gauge(
+            "cache_budget_bytes",
+            "Memory all caches together may use",
+            state.memory_budget.limit as u64,
+        );
+        gauge(
+            "cache_used_bytes",
+            "Memory used by all caches together",
+            state.memory_budget.used() as u64,
+        );
+        let caches = state.caches();
+        let mut labelled =
+            |name: &str, kind: &str, help: &str, value: &dyn Fn(&dyn CacheInfo) -> u64| {
+                writeln!(out, "# HELP btc_rpc_proxy_cache_{} {}", name, help).unwrap();
+                writeln!(out, "# TYPE btc_rpc_proxy_cache_{} {}", name, kind).unwrap();
+                for cache in &caches {
+                    writeln!(
+                        out,
+                        "btc_rpc_proxy_cache_{}{{cache=\"{}\"}} {}",
+                        name,
+                        cache.name(),
+                        value(*cache)
+                    )
+                    .unwrap();
+                }
+            };
+        labelled("bytes", "gauge", "Bytes held by the cache", &|c| {
+            c.bytes() as u64
+        });
+        labelled("entries", "gauge", "Entries in the cache", &|c| {
+            c.entries() as u64
+        });
+        labelled(
+            "hits_total",
+            "counter",
+            "Lookups answered by the cache",
+            &|c| c.stats().hits.load(Ordering::Relaxed),
+        );
+        labelled(
+            "misses_total",
+            "counter",
+            "Lookups not answered by the cache",
+            &|c| c.stats().misses.load(Ordering::Relaxed),
+        );
+        labelled(
+            "evictions_total",
+            "counter",
+            "Entries evicted to stay within the budget",
+            &|c| c.stats().evictions.load(Ordering::Relaxed),
+        );
+        out
+    }
+}
@@ -0,0 +1,170 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Error};
+use bitcoin::hashes::{hmac, sha256, Hash, HashEngine};
+use hyper::{header::CONTENT_TYPE, Body, Client, Method, Request, Uri};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use crate::state::State;
+
+#[derive(Debug)]
+pub struct SmtpConfig {
+    pub server: SocketAddr,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+/// Tells operators about events that need their attention, such as sends waiting for approval.
+#[derive(Debug, Default)]
+pub struct Notifier {
+    pub webhook: Option<Uri>,
+    pub smtp: Option<SmtpConfig>,
+    /// Key the event signatures are made with, so receivers can verify they come from the proxy
+    pub secret: Vec<u8>,
+    /// Link included in notifications, `{id}` and `{signature}` are substituted
+    pub link_template: Option<String>,
+}
+impl Notifier {
+    pub fn is_enabled(&self) -> bool {
+        self.webhook.is_some() || self.smtp.is_some()
+    }
+
+    pub fn sign(&self, event: &str, id: &str) -> String {
+        let mut engine = hmac::HmacEngine::<sha256::Hash>::new(&self.secret);
+        engine.input(event.as_bytes());
+        engine.input(b":");
+        engine.input(id.as_bytes());
+        hex::encode(&hmac::Hmac::<sha256::Hash>::from_engine(engine)[..])
+    }
+
+    async fn send_webhook(&self, uri: &Uri, payload: &Value) -> Result<(), Error> {
+        let response = Client::new()
+            .request(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri(uri)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(serde_json::to_vec(payload)?))?,
+            )
+            .await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("webhook responded with {}", response.status()));
+        }
+        Ok(())
+    }
+
+    async fn send_mail(&self, smtp: &SmtpConfig, subject: &str, text: &str) -> Result<(), Error> {
+        async fn expect(conn: &mut BufReader<TcpStream>, code: &str) -> Result<(), Error> {
+            loop {
+                let mut line = String::new();
+                conn.read_line(&mut line).await?;
+                if !line.starts_with(code) {
+                    return Err(anyhow!("unexpected SMTP response: {}", line.trim_end()));
+                }
+                // the last line of a multi-line response has a space after the code
+                if line.as_bytes().get(3) != Some(&b'-') {
+                    return Ok(());
+                }
+            }
+        }
+        let mut conn = BufReader::new(TcpStream::connect(smtp.server).await?);
+        expect(&mut conn, "220").await?;
+        conn.write_all(b"HELO btc-rpc-proxy\r\n").await?;
+        expect(&mut conn, "250").await?;
+        conn.write_all(format!("MAIL FROM:<{}>\r\n", smtp.from).as_bytes())
+            .await?;
+        expect(&mut conn, "250").await?;
+        for to in &smtp.to {
+            conn.write_all(format!("RCPT TO:<{}>\r\n", to).as_bytes())
+                .await?;
+            expect(&mut conn, "250").await?;
+        }
+        conn.write_all(b"DATA\r\n").await?;
+        expect(&mut conn, "354").await?;
+        let body = text
+            .lines()
+            .map(|l| {
+                if l.starts_with('.') {
+                    format!(".{}", l)
+                } else {
+                    l.to_owned()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\r\n");
+        conn.write_all(
+            format!(
+                "From: <{}>\r\nTo: {}\r\nSubject: {}\r\n\r\n{}\r\n.\r\n",
+                smtp.from,
+                smtp.to
+                    .iter()
+                    .map(|t| format!("<{}>", t))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                subject,
+                body
+            )
+            .as_bytes(),
+        )
+        .await?;
+        expect(&mut conn, "250").await?;
+        conn.write_all(b"QUIT\r\n").await?;
+        Ok(())
+    }
+
+    /// Sends `details` about the object `id` to all configured channels.
+    pub async fn notify(&self, event: &str, id: &str, details: Value) -> Result<(), Error> {
+        let signature = self.sign(event, id);
+        let link = self
+            .link_template
+            .as_ref()
+            .map(|t| t.replace("{id}", id).replace("{signature}", &signature));
+        let payload = json!({
+            "event": event,
+            "id": id,
+            "signature": signature,
+            "link": link,
+            "details": details,
+        });
+        let webhook = async {
+            match &self.webhook {
+                Some(uri) => self.send_webhook(uri, &payload).await,
+                None => Ok(()),
+            }
+        };
+        let mail = async {
+            match &self.smtp {
+                Some(smtp) => {
+                    self.send_mail(
+                        smtp,
+                        &format!("[btc-rpc-proxy] {} {}", event, id),
+                        &serde_json::to_string_pretty(&payload)?,
+                    )
+                    .await
+                }
+                None => Ok(()),
+            }
+        };
+        let (webhook, mail) = futures::join!(webhook, mail);
+        webhook.and(mail)
+    }
+
+    /// Like `notify` but in the background, logging failures.
+    pub fn spawn(state: Arc<State>, event: &'static str, id: String, details: Value) {
+        if !state.notifier.is_enabled() {
+            return;
+        }
+        tokio::spawn(async move {
+            if let Err(e) = state.notifier.notify(event, &id, details).await {
+                error!(
+                    state.logger,
+                    "{}",
+                    e.context(format!("notifying {} {}", event, id))
+                );
+            }
+        });
+    }
+}
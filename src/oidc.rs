@@ -0,0 +1,207 @@
+//! Authentication with access tokens of an OpenID Connect provider.
+//!
+//! The keys of the provider are found through its discovery document and cached, they are
+//! fetched again periodically and, at most once a minute, when a token names a key that isn't
+//! cached, so that key rotations are picked up. Tokens are mapped to configured users either by
+//! a claim naming the user or by their scopes (or the values of another claim, e.g. `groups`).
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Error};
+use hyper::Uri;
+use jsonwebtoken::decode_header;
+use jsonwebtoken::jwk::JwkSet;
+use serde_json::{Map, Value};
+
+use crate::http_client;
+use crate::jwt::JwtAuth;
+use crate::state::State;
+
+/// Keys aren't fetched more often than this for tokens naming unknown keys.
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+/// Failed fetches of the keys are retried after this.
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub struct Oidc {
+    /// `iss` of accepted tokens, the discovery document is found relative to it
+    pub issuer: String,
+    audience: String,
+    /// Claim naming the user whose permissions apply
+    user_claim: Option<String>,
+    /// Claim containing the scopes (space separated) or other values mapped with `scopes`
+    scope_claim: String,
+    /// The users whose permissions apply by scope
+    scopes: HashMap<String, String>,
+    ca_file: Option<PathBuf>,
+    pub refresh_interval: Duration,
+    keys: RwLock<Option<Arc<JwtAuth>>>,
+    /// Held while fetching the keys, so that concurrent requests don't fetch them again
+    fetching: tokio::sync::Mutex<()>,
+    /// When the keys were last fetched, successfully or not
+    fetched: Mutex<Option<Instant>>,
+}
+impl Oidc {
+    pub fn new(
+        issuer: String,
+        audience: String,
+        user_claim: Option<String>,
+        scope_claim: String,
+        scopes: HashMap<String, String>,
+        ca_file: Option<PathBuf>,
+        refresh_interval: Duration,
+    ) -> Result<Self, Error> {
+        if user_claim.is_none() && scopes.is_empty() {
+            return Err(anyhow!(
+                "oidc_issuer requires oidc_user_claim or oidc_scope"
+            ));
+        }
+        Ok(Oidc {
+            issuer,
+            audience,
+            user_claim,
+            scope_claim,
+            scopes,
+            ca_file,
+            refresh_interval,
+            keys: RwLock::new(None),
+            fetching: tokio::sync::Mutex::new(()),
+            fetched: Mutex::new(None),
+        })
+    }
+
+    async fn get_json<T: serde::de::DeserializeOwned>(&self, uri: &str) -> Result<T, Error> {
+        let uri: Uri = uri.parse()?;
+        let body = http_client::get(&uri, self.ca_file.as_deref()).await?;
+        serde_json::from_slice(&body).map_err(|e| anyhow!("invalid response from {}: {}", uri, e))
+    }
+
+    /// Fetches the keys of the provider, replacing the cached ones.
+    pub async fn refresh(&self) -> Result<usize, Error> {
+        *self.fetched.lock().unwrap() = Some(Instant::now());
+        let discovery: Map<String, Value> = self
+            .get_json(&format!(
+                "{}/.well-known/openid-configuration",
+                self.issuer.trim_end_matches('/')
+            ))
+            .await?;
+        if discovery.get("issuer").and_then(Value::as_str) != Some(&self.issuer) {
+            return Err(anyhow!(
+                "the discovery document names a different issuer than {}",
+                self.issuer
+            ));
+        }
+        let jwks_uri = discovery
+            .get("jwks_uri")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("the discovery document has no jwks_uri"))?;
+        let jwks: JwkSet = self.get_json(jwks_uri).await?;
+        let mut keys = JwtAuth::new(
+            "sub".to_owned(),
+            Some(self.audience.clone()),
+            Some(self.issuer.clone()),
+        );
+        keys.add_jwk_set(&jwks)?;
+        let count = jwks.keys.len();
+        *self.keys.write().unwrap() = Some(Arc::new(keys));
+        Ok(count)
+    }
+
+    /// Fetches the keys periodically.
+    pub async fn run(state: Arc<State>) {
+        let oidc = match &state.oidc {
+            Some(oidc) => oidc,
+            None => return,
+        };
+        loop {
+            let result = {
+                let _fetching = oidc.fetching.lock().await;
+                oidc.refresh().await
+            };
+            let wait = match result {
+                Ok(count) => {
+                    debug!(state.logger, "Fetched {} keys of {}", count, oidc.issuer);
+                    oidc.refresh_interval
+                }
+                Err(e) => {
+                    warn!(
+                        state.logger,
+                        "Failed to fetch the keys of {}: {:#}", oidc.issuer, e
+                    );
+                    RETRY_INTERVAL.min(oidc.refresh_interval)
+                }
+            };
+            tokio::time::delay_for(wait).await;
+        }
+    }
+
+    fn cached(&self) -> Option<Arc<JwtAuth>> {
+        self.keys.read().unwrap().clone()
+    }
+
+    /// Verifies `token`, fetching the keys again if it names an unknown one.
+    async fn claims(&self, token: &str) -> Result<Map<String, Value>, Error> {
+        // malformed tokens are rejected without fetching the keys
+        decode_header(token)?;
+        if let Some(keys) = self.cached().filter(|keys| keys.knows_key(token)) {
+            return keys.claims(token);
+        }
+        let _fetching = self.fetching.lock().await;
+        // another request may have fetched them while waiting for the lock
+        let known = self.cached().is_some_and(|keys| keys.knows_key(token));
+        let recently = self
+            .fetched
+            .lock()
+            .unwrap()
+            .is_some_and(|at| at.elapsed() < MIN_REFRESH_INTERVAL);
+        if !known && !recently {
+            self.refresh().await?;
+        }
+        self.cached()
+            .ok_or_else(|| anyhow!("the keys of {} couldn't be fetched", self.issuer))?
+            .claims(token)
+    }
+
+    /// The scopes or other values of the claim mapped to users.
+    fn scopes(&self, claims: &Map<String, Value>) -> Vec<String> {
+        match claims.get(&self.scope_claim) {
+            Some(Value::String(scopes)) => scopes.split_whitespace().map(str::to_owned).collect(),
+            Some(Value::Array(scopes)) => scopes
+                .iter()
+                .filter_map(Value::as_str)
+                .map(str::to_owned)
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Verifies `token`, returning its subject and the name of the configured user whose
+    /// permissions apply: the one named by the user claim if configured and present, the one
+    /// the first mapped scope of the token maps to otherwise.
+    pub async fn authenticate(&self, token: &str) -> Result<(String, String), Error> {
+        let claims = self.claims(token).await?;
+        let subject = claims
+            .get("sub")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("token has no sub claim"))?
+            .to_owned();
+        if let Some(user) = self
+            .user_claim
+            .as_ref()
+            .and_then(|claim| claims.get(claim))
+            .and_then(Value::as_str)
+        {
+            return Ok((subject, user.to_owned()));
+        }
+        let user = self
+            .scopes(&claims)
+            .into_iter()
+            .find_map(|scope| self.scopes.get(&scope))
+            .ok_or_else(|| anyhow!("no scope of the token of {} is mapped to a user", subject))?
+            .clone();
+        Ok((subject, user))
+    }
+}
@@ -0,0 +1,336 @@
+//! Publishing the proxy as a Tor onion service through Tor's control port, so that wallets can
+//! reach a home node from anywhere without forwarding ports.
+//!
+//! The service is added with `ADD_ONION` and lives as long as the control connection, which is
+//! kept open and opened again if it fails. It leads to a listener of its own on an ephemeral
+//! loopback port, so that its clients aren't mistaken for local ones: their address is unknown,
+//! as on Unix sockets, so they never pass checks requiring loopback or an allowed address and
+//! don't share the lockout of the loopback address. Its key is kept in a file if configured, so that the
+//! onion address stays the same across restarts, and only in memory otherwise.
+//!
+//! See the [control protocol](https://spec.torproject.org/control-spec/).
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+use bitcoin::hashes::{hmac, sha256, Hash, HashEngine};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+
+use crate::listen::Address;
+use crate::state::State;
+
+/// How long to wait before connecting to Tor again.
+const RETRY: Duration = Duration::from_secs(10);
+
+const SERVER_HASH_KEY: &[u8] = b"Tor safe cookie authentication server-to-controller hash";
+const CLIENT_HASH_KEY: &[u8] = b"Tor safe cookie authentication controller-to-server hash";
+
+/// The onion service of the proxy.
+#[derive(Debug)]
+pub struct Onion {
+    /// Tor's control port
+    pub control: Address,
+    /// For `HASHEDPASSWORD` authentication, cookie authentication is used without it
+    pub password: Option<String>,
+    /// The port of the service, as seen by clients
+    pub port: u16,
+    /// Where the key of the service is kept, if anywhere
+    pub key_file: Option<PathBuf>,
+    /// The key of the service once known, e.g. `ED25519-V3:<base64>`
+    key: Mutex<Option<String>>,
+}
+impl Onion {
+    pub fn new(
+        control: Address,
+        password: Option<String>,
+        port: u16,
+        key_file: Option<PathBuf>,
+    ) -> Result<Self, Error> {
+        let key = match &key_file {
+            Some(path) => read_key(path)?,
+            None => None,
+        };
+        Ok(Onion {
+            control,
+            password,
+            port,
+            key_file,
+            key: Mutex::new(key),
+        })
+    }
+
+    /// Keeps the onion service leading to `target` published for as long as the proxy runs.
+    pub async fn run(state: Arc<State>, target: Address) {
+        let onion = match &state.onion {
+            Some(onion) => onion,
+            None => return,
+        };
+        loop {
+            let published = match &onion.control {
+                Address::Tcp(addr) => match tokio::net::TcpStream::connect(addr).await {
+                    Ok(stream) => onion.publish(stream, &state, &target).await,
+                    Err(e) => Err(e.into()),
+                },
+                #[cfg(unix)]
+                Address::Unix(path) => match tokio::net::UnixStream::connect(path).await {
+                    Ok(stream) => onion.publish(stream, &state, &target).await,
+                    Err(e) => Err(e.into()),
+                },
+            };
+            match published {
+                Ok(()) => warn!(
+                    state.logger,
+                    "Tor closed the control connection, publishing the onion service again"
+                ),
+                Err(e) => warn!(
+                    state.logger,
+                    "Failed to publish the onion service through {}: {:#}", onion.control, e
+                ),
+            }
+            tokio::time::delay_for(RETRY).await;
+        }
+    }
+
+    /// Publishes the service through the control connection `stream`, returning once Tor closes
+    /// it.
+    async fn publish<S>(&self, stream: S, state: &State, target: &Address) -> Result<(), Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut control = Control::new(stream);
+        control.authenticate(self.password.as_deref()).await?;
+        let key = self.key.lock().unwrap().clone();
+        let (service_id, new_key) = control
+            .add_onion(key.as_deref(), self.port, &target.to_string())
+            .await?;
+        if let Some(new_key) = new_key {
+            if let Some(path) = &self.key_file {
+                write_key(path, &new_key)?;
+            }
+            *self.key.lock().unwrap() = Some(new_key);
+        }
+        info!(
+            state.logger,
+            "Published the onion service {}.onion:{} for {}", service_id, self.port, target
+        );
+        control.closed().await
+    }
+}
+
+fn read_key(path: &Path) -> Result<Option<String>, Error> {
+    match std::fs::read_to_string(path) {
+        Ok(key) if key.trim().starts_with("ED25519-V3:") => Ok(Some(key.trim().to_owned())),
+        Ok(_) => Err(anyhow!("{} holds no onion service key", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(anyhow!("failed to read {}: {}", path.display(), e)),
+    }
+}
+
+/// Writes `key` to `path`, readable by the owner only.
+fn write_key(path: &Path, key: &str) -> Result<(), Error> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", key))
+        .map_err(|e| anyhow!("failed to write {}: {}", path.display(), e))
+}
+
+/// A connection to Tor's control port.
+pub struct Control<S> {
+    stream: BufReader<S>,
+}
+impl<S: AsyncRead + AsyncWrite + Unpin> Control<S> {
+    pub fn new(stream: S) -> Self {
+        Control {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    /// Sends `command`, returning the lines of the reply without their status, or an error if it
+    /// isn't 250.
+    pub async fn command(&mut self, command: &str) -> Result<Vec<String>, Error> {
+        self.stream
+            .get_mut()
+            .write_all(format!("{}\r\n", command).as_bytes())
+            .await?;
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).await? == 0 {
+                return Err(anyhow!("Tor closed the control connection"));
+            }
+            let line = line.trim_end_matches(&['\r', '\n'][..]);
+            if line.len() < 4 || !line.is_char_boundary(4) {
+                return Err(anyhow!("invalid reply of Tor: {:?}", line));
+            }
+            let (status, separator, text) = (&line[..3], &line[3..4], &line[4..]);
+            if status != "250" {
+                return Err(anyhow!(
+                    "Tor refused {}: {} {}",
+                    verb(command),
+                    status,
+                    text
+                ));
+            }
+            // data follows up to a line with a single dot, which isn't needed
+            if separator == "+" {
+                loop {
+                    let mut data = String::new();
+                    if self.stream.read_line(&mut data).await? == 0 || data.trim_end() == "." {
+                        break;
+                    }
+                }
+            }
+            lines.push(text.to_owned());
+            if separator == " " {
+                return Ok(lines);
+            }
+        }
+    }
+
+    /// Authenticates with `password` if given and Tor accepts one, or without credentials or
+    /// with Tor's cookie otherwise.
+    pub async fn authenticate(&mut self, password: Option<&str>) -> Result<(), Error> {
+        let info = self.command("PROTOCOLINFO 1").await?;
+        let auth = info
+            .iter()
+            .find_map(|line| line.strip_prefix("AUTH "))
+            .ok_or_else(|| anyhow!("Tor didn't tell how to authenticate"))?;
+        let methods: Vec<&str> = auth
+            .split(' ')
+            .find_map(|field| field.strip_prefix("METHODS="))
+            .map_or_else(Vec::new, |methods| methods.split(',').collect());
+        let cookie_file = auth
+            .find("COOKIEFILE=")
+            .map(|i| unquote(&auth[i + "COOKIEFILE=".len()..]));
+        if let Some(password) = password {
+            if methods.contains(&"HASHEDPASSWORD") {
+                self.command(&format!("AUTHENTICATE {}", quote(password)))
+                    .await?;
+                return Ok(());
+            }
+        }
+        if methods.contains(&"NULL") {
+            self.command("AUTHENTICATE").await?;
+            return Ok(());
+        }
+        let cookie = match cookie_file {
+            Some(path) if methods.contains(&"SAFECOOKIE") || methods.contains(&"COOKIE") => {
+                std::fs::read(&path).map_err(|e| anyhow!("failed to read {}: {}", path, e))?
+            }
+            _ if methods.contains(&"HASHEDPASSWORD") => {
+                return Err(anyhow!("Tor requires tor_control_password"))
+            }
+            _ => {
+                return Err(anyhow!(
+                    "no supported authentication method among {:?}",
+                    methods
+                ))
+            }
+        };
+        if !methods.contains(&"SAFECOOKIE") {
+            self.command(&format!("AUTHENTICATE {}", hex::encode(&cookie)))
+                .await?;
+            return Ok(());
+        }
+        let client_nonce = rand::random::<[u8; 32]>();
+        let challenge = self
+            .command(&format!(
+                "AUTHCHALLENGE SAFECOOKIE {}",
+                hex::encode(client_nonce)
+            ))
+            .await?;
+        let field = |name: &str| {
+            challenge
+                .first()
+                .and_then(|line| {
+                    line.split(' ')
+                        .find_map(|f| f.strip_prefix(name)?.strip_prefix('='))
+                })
+                .and_then(|value| hex::decode(value).ok())
+                .ok_or_else(|| anyhow!("invalid AUTHCHALLENGE reply of Tor"))
+        };
+        let server_hash = field("SERVERHASH")?;
+        let server_nonce = field("SERVERNONCE")?;
+        let message = [&cookie[..], &client_nonce[..], &server_nonce[..]].concat();
+        if safe_cookie_hash(SERVER_HASH_KEY, &message)[..] != server_hash[..] {
+            return Err(anyhow!("Tor doesn't know the cookie, is it another Tor?"));
+        }
+        let client_hash = safe_cookie_hash(CLIENT_HASH_KEY, &message);
+        self.command(&format!("AUTHENTICATE {}", hex::encode(&client_hash[..])))
+            .await?;
+        Ok(())
+    }
+
+    /// Adds an onion service with `key`, or a new key, forwarding `port` to `target`, returning
+    /// the id of the service (its address without `.onion`) and the key if it is new.
+    pub async fn add_onion(
+        &mut self,
+        key: Option<&str>,
+        port: u16,
+        target: &str,
+    ) -> Result<(String, Option<String>), Error> {
+        let reply = self
+            .command(&format!(
+                "ADD_ONION {} Port={},{}",
+                key.unwrap_or("NEW:ED25519-V3"),
+                port,
+                target
+            ))
+            .await?;
+        let field = |name: &str| {
+            reply
+                .iter()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix('='))
+                .map(str::to_owned)
+        };
+        let service_id =
+            field("ServiceID").ok_or_else(|| anyhow!("Tor didn't tell the onion address"))?;
+        Ok((service_id, field("PrivateKey")))
+    }
+
+    /// Waits for Tor to close the connection.
+    pub async fn closed(mut self) -> Result<(), Error> {
+        let mut line = String::new();
+        while self.stream.read_line(&mut line).await? > 0 {
+            line.clear();
+        }
+        Ok(())
+    }
+}
+
+fn safe_cookie_hash(key: &[u8], message: &[u8]) -> hmac::Hmac<sha256::Hash> {
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(key);
+    engine.input(message);
+    hmac::Hmac::from_engine(engine)
+}
+
+/// The first word of `command`, so that passwords aren't logged.
+fn verb(command: &str) -> &str {
+    command.split(' ').next().unwrap_or_default()
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// The quoted string `s` starts with, unescaped.
+fn unquote(s: &str) -> String {
+    let mut unquoted = String::new();
+    let mut chars = s.strip_prefix('"').unwrap_or(s).chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => break,
+            '\\' => unquoted.extend(chars.next()),
+            c => unquoted.push(c),
+        }
+    }
+    unquoted
+}
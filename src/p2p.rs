@@ -0,0 +1,305 @@
+//! Framing of P2P messages and the handshake with peers.
+//!
+//! The `bitcoin` crate fails to decode messages it doesn't know, such as the `wtxidrelay`,
+//! `sendaddrv2` and `sendcmpct` modern peers send during and right after the handshake, so
+//! messages are framed here and only the known ones are decoded.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime};
+
+use anyhow::{anyhow, Error};
+use bitcoin::consensus::encode::{self, deserialize_partial};
+use bitcoin::hashes::{sha256d, Hash};
+use bitcoin::network::{
+    address::Address,
+    constants::{Network::Bitcoin, ServiceFlags},
+    message::{NetworkMessage, RawNetworkMessage},
+    message_network::VersionMessage,
+};
+use serde_json::json;
+
+/// Protocol version signalled to peers, the first one supporting `wtxidrelay` (BIP 339).
+pub const PROTOCOL_VERSION: u32 = 70016;
+
+/// `feefilter` sent to peers, as bitcoind does in blocks-only mode: no transactions please.
+const MAX_FEE_FILTER: i64 = 21_000_000 * 100_000_000;
+
+const HEADER_LEN: usize = 24;
+
+/// Largest message bitcoind accepts.
+const MAX_PAYLOAD: usize = 4_000_000;
+
+/// A message received from a peer.
+#[derive(Debug)]
+pub enum Message {
+    Known(NetworkMessage),
+    /// A message the `bitcoin` crate can't decode
+    Other {
+        command: String,
+        payload: Vec<u8>,
+    },
+}
+impl Message {
+    pub fn command(&self) -> &str {
+        match self {
+            Message::Known(msg) => msg.cmd(),
+            Message::Other { command, .. } => command,
+        }
+    }
+}
+
+/// A message as sent over the wire, without the header.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub command: String,
+    pub payload: Vec<u8>,
+}
+impl Frame {
+    pub fn new(payload: NetworkMessage) -> Self {
+        let command = payload.cmd().to_owned();
+        let mut data = encode::serialize(&RawNetworkMessage {
+            magic: Bitcoin.magic(),
+            payload,
+        });
+        Frame {
+            command,
+            payload: data.split_off(HEADER_LEN),
+        }
+    }
+
+    /// Reads the next frame of the network with `magic`.
+    pub fn read<R: Read + ?Sized>(stream: &mut R, magic: u32) -> Result<Self, Error> {
+        let mut header = [0; HEADER_LEN];
+        stream.read_exact(&mut header)?;
+        let received = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        if received != magic {
+            return Err(anyhow!("unexpected network magic {:08x}", received));
+        }
+        let command = String::from_utf8_lossy(&header[4..16])
+            .trim_end_matches('\0')
+            .to_owned();
+        let len = u32::from_le_bytes([header[16], header[17], header[18], header[19]]) as usize;
+        if len > MAX_PAYLOAD {
+            return Err(anyhow!("{} message of {} bytes is too large", command, len));
+        }
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload)?;
+        if sha256d::Hash::hash(&payload)[..4] != header[20..24] {
+            return Err(anyhow!("invalid checksum of {} message", command));
+        }
+        Ok(Frame { command, payload })
+    }
+
+    /// The frame with its header, as sent over the wire on the network with `magic`.
+    fn encode(&self, magic: u32) -> Vec<u8> {
+        let mut data = magic.to_le_bytes().to_vec();
+        let mut name = [0; 12];
+        name[..self.command.len()].copy_from_slice(self.command.as_bytes());
+        data.extend_from_slice(&name);
+        data.extend_from_slice(&(self.payload.len() as u32).to_le_bytes());
+        data.extend_from_slice(&sha256d::Hash::hash(&self.payload)[..4]);
+        data.extend_from_slice(&self.payload);
+        data
+    }
+
+    pub fn write<W: Write + ?Sized>(&self, stream: &mut W, magic: u32) -> Result<(), Error> {
+        stream.write_all(&self.encode(magic))?;
+        stream.flush()?;
+        Ok(())
+    }
+
+    /// Decodes the message if the `bitcoin` crate knows it.
+    pub fn decode(self) -> Result<Message, Error> {
+        // decoding ignores the magic
+        let data = self.encode(Bitcoin.magic());
+        match deserialize_partial::<RawNetworkMessage>(&data) {
+            Ok((msg, _)) => Ok(Message::Known(msg.payload)),
+            Err(encode::Error::UnrecognizedNetworkCommand(_)) => Ok(Message::Other {
+                command: self.command,
+                payload: self.payload,
+            }),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// A connection to a peer messages are exchanged over.
+pub trait Connection {
+    fn receive_frame(&mut self) -> Result<Frame, Error>;
+    fn send_frame(&mut self, frame: &Frame) -> Result<(), Error>;
+
+    fn receive(&mut self) -> Result<Message, Error> {
+        self.receive_frame()?.decode()
+    }
+    /// Sends a message the `bitcoin` crate knows.
+    fn send(&mut self, payload: NetworkMessage) -> Result<(), Error> {
+        self.send_frame(&Frame::new(payload))
+    }
+    /// Sends a message the `bitcoin` crate doesn't know.
+    fn send_other(&mut self, command: &str, payload: &[u8]) -> Result<(), Error> {
+        self.send_frame(&Frame {
+            command: command.to_owned(),
+            payload: payload.to_vec(),
+        })
+    }
+}
+
+/// Which way a traced message went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// Records the messages exchanged with peers, to the log and/or to a file of JSON lines.
+#[derive(Debug)]
+pub struct Tracer {
+    pub logger: Option<slog::Logger>,
+    pub file: Option<Mutex<File>>,
+    /// Bytes of every payload recorded
+    pub max_payload: usize,
+}
+impl Tracer {
+    pub fn record(&self, peer: &str, direction: Direction, frame: &Frame) {
+        let payload = &frame.payload[..frame.payload.len().min(self.max_payload)];
+        if let Some(logger) = &self.logger {
+            info!(
+                logger,
+                "P2P {} {} {} ({} bytes): {}{}",
+                if direction == Direction::Sent {
+                    ">"
+                } else {
+                    "<"
+                },
+                peer,
+                frame.command,
+                frame.payload.len(),
+                hex::encode(payload),
+                if payload.len() < frame.payload.len() {
+                    "..."
+                } else {
+                    ""
+                }
+            );
+        }
+        if let Some(file) = &self.file {
+            let mut line = json!({
+                "time": chrono::Utc::now().to_rfc3339(),
+                "peer": peer,
+                "direction": direction,
+                "command": frame.command,
+                "size": frame.payload.len(),
+                "payload": hex::encode(payload),
+            })
+            .to_string();
+            line.push('\n');
+            if let Err(e) = file.lock().unwrap().write_all(line.as_bytes()) {
+                if let Some(logger) = &self.logger {
+                    warn!(logger, "Failed to write P2P trace: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// What a peer told about itself during the handshake.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct Features {
+    pub version: u32,
+    pub user_agent: String,
+    /// Announces transactions by wtxid (BIP 339)
+    pub wtxidrelay: bool,
+    /// Wants addresses in `addrv2` messages (BIP 155)
+    pub addrv2: bool,
+    /// Announces blocks with `headers` (BIP 130)
+    pub sendheaders: bool,
+    /// Highest compact block version the peer offered (BIP 152)
+    pub compact_blocks: Option<u64>,
+    /// Fee rate (sat/kvB) below which the peer doesn't want transactions announced (BIP 133)
+    pub feefilter: Option<i64>,
+    /// Round trip time of a ping right after the handshake
+    pub ping_ms: Option<u64>,
+}
+impl Features {
+    /// Records `msg` if it announces a feature.
+    fn note(&mut self, msg: &Message) {
+        match msg {
+            Message::Known(NetworkMessage::SendHeaders) => self.sendheaders = true,
+            Message::Known(NetworkMessage::FeeFilter(rate)) => self.feefilter = Some(*rate),
+            Message::Other { command, .. } if command == "wtxidrelay" => self.wtxidrelay = true,
+            Message::Other { command, .. } if command == "sendaddrv2" => self.addrv2 = true,
+            Message::Other { command, payload } if command == "sendcmpct" && payload.len() == 9 => {
+                let mut version = [0; 8];
+                version.copy_from_slice(&payload[1..]);
+                let version = u64::from_le_bytes(version);
+                self.compact_blocks = Some(self.compact_blocks.unwrap_or(0).max(version));
+            }
+            _ => (),
+        }
+    }
+}
+
+fn version_message(addr: Address) -> NetworkMessage {
+    let mut version = VersionMessage::new(
+        ServiceFlags::NONE,
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64,
+        addr,
+        Address::new(&([127, 0, 0, 1], 8332).into(), ServiceFlags::NONE),
+        rand::random(),
+        format!("BTC RPC Proxy v{}", env!("CARGO_PKG_VERSION")),
+        0,
+    );
+    version.version = PROTOCOL_VERSION;
+    // only blocks are fetched, so transactions aren't relayed (BIP 37)
+    version.relay = false;
+    NetworkMessage::Version(version)
+}
+
+/// Performs the version handshake and collects the features the peer announces right after it.
+///
+/// `wtxidrelay` is sent between `version` and `verack` as BIP 339 requires. `sendcmpct` is never
+/// sent, so peers don't push compact blocks, and `sendaddrv2` neither, so that they keep sending
+/// `addr` messages.
+pub fn handshake<C: Connection + ?Sized>(conn: &mut C, addr: Address) -> Result<Features, Error> {
+    let mut features = Features::default();
+    conn.send(version_message(addr))?;
+    loop {
+        let msg = conn.receive()?;
+        match msg {
+            Message::Known(NetworkMessage::Version(version)) => {
+                features.version = version.version;
+                features.user_agent = version.user_agent;
+                if version.version >= PROTOCOL_VERSION {
+                    conn.send_other("wtxidrelay", &[])?;
+                }
+                conn.send(NetworkMessage::Verack)?;
+            }
+            Message::Known(NetworkMessage::Verack) => break,
+            Message::Known(NetworkMessage::Ping(nonce)) => {
+                conn.send(NetworkMessage::Pong(nonce))?
+            }
+            msg => features.note(&msg),
+        }
+    }
+    conn.send(NetworkMessage::FeeFilter(MAX_FEE_FILTER))?;
+    // peers announce their features in response to `verack`, before answering a later ping
+    let nonce = rand::random();
+    let start = Instant::now();
+    conn.send(NetworkMessage::Ping(nonce))?;
+    loop {
+        match conn.receive()? {
+            Message::Known(NetworkMessage::Pong(n)) if n == nonce => {
+                features.ping_ms = Some(start.elapsed().as_millis() as u64);
+                return Ok(features);
+            }
+            Message::Known(NetworkMessage::Ping(n)) => conn.send(NetworkMessage::Pong(n))?,
+            msg => features.note(&msg),
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! Restrictions on the parameters of calls, e.g. the amounts a user may send.
+
+use hyper::StatusCode;
+use serde_json::{json, Value};
+
+use crate::client::{GenericRpcMethod, RpcError, RpcRequest, POLICY_VIOLATION_ERROR_CODE};
+
+/// A restriction on one positional parameter of a method.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ParamRule {
+    pub method: String,
+    /// Position of the parameter, starting at 0
+    pub param: usize,
+    /// Smallest allowed value of a numeric parameter (numbers in strings count too)
+    #[serde(default)]
+    pub min: Option<f64>,
+    /// Largest allowed value of a numeric parameter
+    #[serde(default)]
+    pub max: Option<f64>,
+    /// Values the parameter may take, any if not set
+    #[serde(default)]
+    pub allowed: Option<Vec<Value>>,
+    /// Rejects calls omitting the parameter instead of leaving it to bitcoind's default
+    #[serde(default)]
+    pub required: bool,
+}
+impl ParamRule {
+    /// Why `value` violates the rule, if it does.
+    fn violation(&self, value: Option<&Value>) -> Option<String> {
+        let value = match value {
+            None | Some(Value::Null) if self.required => return Some("is required".to_owned()),
+            None | Some(Value::Null) => return None,
+            Some(value) => value,
+        };
+        if let Some(allowed) = &self.allowed {
+            if !allowed.contains(value) {
+                return Some(format!(
+                    "must be one of {}, got {}",
+                    Value::from(allowed.clone()),
+                    value
+                ));
+            }
+        }
+        if self.min.is_none() && self.max.is_none() {
+            return None;
+        }
+        let n = match value {
+            Value::Number(n) => n.as_f64(),
+            Value::String(s) => s.parse().ok(),
+            _ => None,
+        };
+        match n {
+            None => Some(format!("must be a number, got {}", value)),
+            Some(n) if self.min.is_some_and(|min| n < min) => Some(format!(
+                "must be at least {}, got {}",
+                self.min.unwrap(),
+                value
+            )),
+            Some(n) if self.max.is_some_and(|max| n > max) => Some(format!(
+                "must be at most {}, got {}",
+                self.max.unwrap(),
+                value
+            )),
+            Some(_) => None,
+        }
+    }
+
+    /// Checks the parameter of `req` if the rule applies to its method.
+    pub fn check(&self, req: &RpcRequest<GenericRpcMethod>) -> Result<(), RpcError> {
+        if req.method.0 != self.method {
+            return Ok(());
+        }
+        let value = req.params.get(self.param);
+        match self.violation(value) {
+            None => Ok(()),
+            Some(violation) => Err(RpcError {
+                code: POLICY_VIOLATION_ERROR_CODE,
+                message: format!("Parameter {} of {} {}", self.param, self.method, violation),
+                data: Some(json!({
+                    "method": self.method,
+                    "param": self.param,
+                    "value": value,
+                    "min": self.min,
+                    "max": self.max,
+                    "allowed": self.allowed,
+                })),
+                status: Some(StatusCode::FORBIDDEN),
+            }),
+        }
+    }
+}
+
+/// Checks `req` against all `rules`, failing with the first violation.
+pub fn check(rules: &[ParamRule], req: &RpcRequest<GenericRpcMethod>) -> Result<(), RpcError> {
+    rules.iter().try_for_each(|rule| rule.check(req))
+}
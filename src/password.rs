@@ -0,0 +1,144 @@
+//! Passwords of users, stored in plain text or as argon2 or bcrypt hashes.
+
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Error};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use bitcoin::hashes::{sha256, Hash};
+use subtle::ConstantTimeEq;
+
+/// Algorithms `btc_rpc_proxy hash-password` can hash with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    Argon2,
+    Bcrypt,
+}
+
+/// Hashes `password` with a random salt, in the format `Password` accepts.
+pub fn hash(password: &str, scheme: Scheme) -> Result<String, Error> {
+    match scheme {
+        Scheme::Argon2 => {
+            let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>())
+                .map_err(|e| anyhow!("{}", e))?;
+            Ok(Argon2::default()
+                .hash_password(password.as_bytes(), &salt)
+                .map_err(|e| anyhow!("{}", e))?
+                .to_string())
+        }
+        Scheme::Bcrypt => Ok(bcrypt::hash(password, bcrypt::DEFAULT_COST)?),
+    }
+}
+
+/// A password from the config. Strings in the PHC format of argon2 (`$argon2id$...`) or the
+/// format of bcrypt (`$2b$...`) are hashes, anything else is the password itself.
+#[derive(Default, serde::Deserialize)]
+#[serde(from = "String")]
+pub struct Password {
+    stored: String,
+    /// Digest of the last password matching a hash, so that clients don't pay for the slow
+    /// hash function on every request
+    verified: Mutex<Option<sha256::Hash>>,
+    /// Read from a file or the environment, so never written back to the config
+    resolved: bool,
+}
+impl From<String> for Password {
+    fn from(stored: String) -> Self {
+        Password {
+            stored,
+            verified: Mutex::new(None),
+            resolved: false,
+        }
+    }
+}
+impl serde::Serialize for Password {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.stored)
+    }
+}
+impl Password {
+    /// A password read from outside of the config.
+    pub fn resolved(stored: String) -> Self {
+        Password {
+            resolved: true,
+            ..Password::from(stored)
+        }
+    }
+
+    pub fn is_resolved(&self) -> bool {
+        self.resolved
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stored.is_empty()
+    }
+
+    pub fn is_hashed(&self) -> bool {
+        self.stored.starts_with("$argon2") || self.is_bcrypt()
+    }
+
+    fn is_bcrypt(&self) -> bool {
+        ["$2a$", "$2b$", "$2x$", "$2y$"]
+            .iter()
+            .any(|p| self.stored.starts_with(p))
+    }
+
+    /// Checks that a hash can be parsed, so that typos are noticed at startup.
+    pub fn check(&self) -> Result<(), Error> {
+        if self.is_bcrypt() {
+            bcrypt::HashParts::from_str(&self.stored)?;
+        } else if self.is_hashed() {
+            let hash = PasswordHash::new(&self.stored)
+                .map_err(|e| anyhow!("invalid argon2 hash: {}", e))?;
+            if hash.hash.is_none() {
+                return Err(anyhow!("invalid argon2 hash: no output, truncated?"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks a password sent by a client, in constant time for passwords in plain text.
+    pub fn verify(&self, password: &str) -> bool {
+        if !self.is_hashed() {
+            return bool::from(self.stored.as_bytes().ct_eq(password.as_bytes()));
+        }
+        let digest = sha256::Hash::hash(password.as_bytes());
+        if let Some(verified) = *self.verified.lock().unwrap() {
+            if bool::from(verified[..].ct_eq(&digest[..])) {
+                return true;
+            }
+        }
+        let valid = if self.is_bcrypt() {
+            bcrypt::verify(password, &self.stored).unwrap_or(false)
+        } else {
+            PasswordHash::new(&self.stored).is_ok_and(|hash| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok()
+            })
+        };
+        if valid {
+            *self.verified.lock().unwrap() = Some(digest);
+        }
+        valid
+    }
+}
+impl Clone for Password {
+    fn clone(&self) -> Self {
+        Password {
+            stored: self.stored.clone(),
+            verified: Mutex::new(*self.verified.lock().unwrap()),
+            resolved: self.resolved,
+        }
+    }
+}
+impl std::fmt::Debug for Password {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(if self.is_hashed() {
+            "Password(hashed)"
+        } else {
+            "Password(..)"
+        })
+    }
+}
@@ -0,0 +1,66 @@
+//! The paths of JSON-RPC requests: `/` for node calls and `/wallet/<name>` for wallet calls,
+//! checked and normalized before they are matched against permissions or sent to bitcoind.
+
+use crate::users::percent_decode;
+
+/// Encodes a wallet name as a path segment.
+pub fn wallet_path(name: &str) -> String {
+    format!("/wallet/{}", percent_encode(name))
+}
+
+/// Percent-encodes everything in `s` but unreserved characters.
+pub fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+/// Whether `name` is a wallet name that stays in bitcoind's wallet directory. Names may have
+/// directories, but not `..` or an absolute path.
+fn is_relative_name(name: &str) -> bool {
+    !name.starts_with('/')
+        && !name.split(['/', '\\']).any(|part| part == "..")
+        && !name.chars().any(char::is_control)
+}
+
+/// The path to send a request for `path` to, `None` if it isn't a JSON-RPC endpoint or names a
+/// wallet outside of bitcoind's wallet directory. Wallet names are encoded the same way
+/// whichever way the client encoded them, so that they can't be spelled past permissions.
+pub fn normalize(path: &str) -> Option<String> {
+    match path {
+        "" | "/" => Some("/".to_owned()),
+        path => {
+            let name = percent_decode(path.strip_prefix("/wallet/")?)?;
+            Some(wallet_path(&name)).filter(|_| is_relative_name(&name))
+        }
+    }
+}
+
+/// Checks a base path bitcoind is mounted under, e.g. by a reverse proxy, returning it without
+/// a trailing slash (empty for the root).
+pub fn base_path(path: &str) -> Result<String, String> {
+    let trimmed = path.trim_end_matches('/');
+    if !trimmed.is_empty() && !trimmed.starts_with('/') {
+        return Err(format!("{:?} has to start with /", path));
+    }
+    if trimmed
+        .split('/')
+        .skip(1)
+        .any(|segment| segment.is_empty() || segment == "." || segment == "..")
+    {
+        return Err(format!("{:?} has empty, . or .. segments", path));
+    }
+    if trimmed.contains(['?', '#', '%']) || trimmed.chars().any(|c| !c.is_ascii_graphic()) {
+        return Err(format!(
+            "{:?} has characters not allowed in a base path",
+            path
+        ));
+    }
+    Ok(trimmed.to_owned())
+}
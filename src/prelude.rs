@@ -0,0 +1,30 @@
+//! The stable public API of the crate.
+//!
+//! Embedders should import from here rather than from the individual modules: everything
+//! re-exported below follows semver, while the rest of the public modules may change between
+//! minor releases.
+//!
+//! ```no_run
+//! use btc_rpc_proxy::prelude::*;
+//!
+//! async fn run(rpc_client: RpcClient, logger: slog::Logger) -> Result<(), anyhow::Error> {
+//!     let state = State::builder(rpc_client, logger)
+//!         .hooks(Hooks::default().on_shutdown(|| println!("proxy stopped")))
+//!         .build();
+//!     serve(state.arc()).await
+//! }
+//! ```
+
+pub use crate::client::{
+    AuthSource, GenericRpcMethod, RpcClient, RpcError, RpcMethod, RpcRequest, RpcResponse,
+    SingleOrBatchRpcRequest,
+};
+#[cfg(feature = "peer-fetch")]
+pub use crate::fetch_blocks::Peers;
+pub use crate::intercept::{InterceptResult, Interceptor, Interceptors, RequestContext};
+pub use crate::lifecycle::{Hooks, Shutdown};
+pub use crate::main as serve;
+#[cfg(feature = "tor")]
+pub use crate::state::TorState;
+pub use crate::state::{State, StateBuilder};
+pub use crate::users::{User, Users};
@@ -0,0 +1,193 @@
+//! Prioritization of interactive clients over batch clients (indexers, explorers) while bitcoind
+//! is syncing or overloaded.
+//!
+//! bitcoind is checked periodically: it counts as degraded while in initial block download, when
+//! its tip is older than `max_tip_age` or when answering `getblockchaininfo` takes longer than
+//! `max_latency`, which indicates a full RPC work queue. Calls of batch users are then delayed to
+//! a trickle, the calls of other users aren't affected.
+
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, TimeZone, Utc};
+use serde_json::{json, Value};
+
+use crate::client::{GenericRpcMethod, RpcError, RpcRequest};
+use crate::intercept::{RequestContext, LOCAL_METHOD_PREFIX};
+use crate::rate_limit::{rate_limited, Algorithm, Decision, RateLimit};
+use crate::state::State;
+
+/// Index of the trickle among the limits of a user in the rate limiter, apart from its own.
+const TRICKLE_INDEX: usize = usize::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    #[default]
+    Interactive,
+    /// Slowed down to a trickle while bitcoind is degraded
+    Batch,
+}
+
+#[derive(Debug, Default)]
+struct Status {
+    checked: Option<DateTime<Utc>>,
+    initial_block_download: Option<bool>,
+    tip_age: Option<Duration>,
+    latency: Option<Duration>,
+    /// Why bitcoind is degraded, empty if it isn't
+    reasons: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct Load {
+    pub interval: Duration,
+    pub max_tip_age: Duration,
+    pub max_latency: Duration,
+    /// The limit on the calls of batch users while degraded
+    pub trickle: RateLimit,
+    status: RwLock<Status>,
+}
+impl Load {
+    pub fn new(
+        interval: Duration,
+        max_tip_age: Duration,
+        max_latency: Duration,
+        trickle: f64,
+    ) -> Self {
+        Load {
+            interval,
+            max_tip_age,
+            max_latency,
+            trickle: RateLimit {
+                algorithm: Algorithm::LeakyBucket,
+                ..RateLimit::per_second(trickle, Some(10))
+            },
+            status: RwLock::new(Status::default()),
+        }
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        !self.status.read().unwrap().reasons.is_empty()
+    }
+
+    async fn check(&self, state: &State) -> Result<(), RpcError> {
+        let started = Instant::now();
+        let info = state
+            .rpc_client
+            .call(&RpcRequest {
+                id: None,
+                method: GenericRpcMethod("getblockchaininfo".to_owned()),
+                params: Vec::new(),
+            })
+            .await?
+            .into_result()?;
+        let latency = started.elapsed();
+        let now = Utc::now();
+        let initial_block_download = info["initialblockdownload"].as_bool();
+        // `time` was only added in 23.0
+        let tip_age = info["time"]
+            .as_i64()
+            .or_else(|| info["mediantime"].as_i64())
+            .and_then(|time| Utc.timestamp_opt(time, 0).single())
+            .and_then(|time| (now - time).to_std().ok());
+        let mut reasons = Vec::new();
+        if initial_block_download == Some(true) {
+            reasons.push("initial block download".to_owned());
+        }
+        if let Some(age) = tip_age.filter(|age| *age > self.max_tip_age) {
+            reasons.push(format!("tip is {} s old", age.as_secs()));
+        }
+        if latency > self.max_latency {
+            reasons.push(format!("getblockchaininfo took {} ms", latency.as_millis()));
+        }
+        let mut status = self.status.write().unwrap();
+        match (status.reasons.is_empty(), reasons.is_empty()) {
+            (true, false) => warn!(
+                state.logger,
+                "bitcoind is degraded ({}), slowing down batch users",
+                reasons.join(", ")
+            ),
+            (false, true) => info!(
+                state.logger,
+                "bitcoind recovered, no longer slowing down batch users"
+            ),
+            _ => (),
+        }
+        *status = Status {
+            checked: Some(now),
+            initial_block_download,
+            tip_age,
+            latency: Some(latency),
+            reasons,
+        };
+        Ok(())
+    }
+
+    /// Checks bitcoind periodically.
+    pub async fn run(state: Arc<State>) {
+        let load = match &state.load {
+            Some(load) => load,
+            None => return,
+        };
+        let mut interval = tokio::time::interval(load.interval);
+        loop {
+            interval.tick().await;
+            if let Err(e) = load.check(&state).await {
+                debug!(
+                    state.logger,
+                    "Failed to check the load of bitcoind: {}", e.message
+                );
+            }
+        }
+    }
+
+    /// Delays the calls of batch users to the trickle while bitcoind is degraded.
+    pub async fn throttle(
+        &self,
+        state: &State,
+        priority: Priority,
+        method: &str,
+        ctx: &RequestContext,
+    ) -> Result<(), RpcError> {
+        if priority != Priority::Batch
+            || method.starts_with(LOCAL_METHOD_PREFIX)
+            || !self.is_degraded()
+        {
+            return Ok(());
+        }
+        match state
+            .rate_limiter
+            .acquire(&ctx.user_name, TRICKLE_INDEX, &self.trickle)
+            .await
+        {
+            Ok(Decision::Allow) => Ok(()),
+            Ok(Decision::Delay(wait)) => {
+                tokio::time::delay_for(wait).await;
+                Ok(())
+            }
+            Ok(Decision::Reject(retry_after)) => Err(rate_limited(&self.trickle, retry_after)),
+            Err(e) => {
+                warn!(
+                    state.logger,
+                    "Failed to check the trickle of {}, allowing the call: {:#}", ctx.user_name, e
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// The active policy and what it is based on, for `proxy_status`.
+    pub fn status(&self) -> Value {
+        let status = self.status.read().unwrap();
+        json!({
+            "policy": if status.reasons.is_empty() { "normal" } else { "prioritize_interactive" },
+            "reasons": status.reasons,
+            "checked": status.checked,
+            "initial_block_download": status.initial_block_download,
+            "tip_age": status.tip_age.map(|a| a.as_secs()),
+            "latency": status.latency.map(|l| l.as_secs_f64()),
+            "batch_trickle": self.trickle.to_string(),
+        })
+    }
+}
@@ -1,85 +1,284 @@
+use std::net::SocketAddr;
 use std::sync::Arc;
 
 use anyhow::Error;
-use hyper::{
-    body::Bytes,
-    header::{AUTHORIZATION, WWW_AUTHENTICATE},
-    Body, Method, Request, Response, StatusCode,
-};
+use hyper::{body::Bytes, header::IF_NONE_MATCH, Body, Request, Response, StatusCode};
 use tokio::stream::StreamExt;
 
-use crate::client::{RpcError, RpcResponse};
+use crate::audit::{self, Event, Kind};
+use crate::auth::Authenticated;
+use crate::client::{
+    RpcError, RpcResponse, SingleOrBatchRpcRequest, ACCESS_DENIED_ERROR_CODE,
+    METHOD_NOT_ALLOWED_ERROR_CODE,
+};
+use crate::etag;
+use crate::headers::ResponseHeaders;
+use crate::intercept::RequestContext;
+use crate::metrics::Metrics;
+use crate::quota::QuotaUsage;
+use crate::schema;
 use crate::state::State;
+use crate::users::IMPERSONATE_HEADER;
+use crate::warnings;
 
 pub async fn proxy_request(
     state: Arc<State>,
     request: Request<Body>,
+) -> Result<Response<Body>, Error> {
+    #[cfg(feature = "chaos")]
+    if let Some(chaos) = &state.chaos {
+        chaos.delay().await;
+        if chaos.drop_connection() {
+            return Err(anyhow::anyhow!("dropping connection (chaos)"));
+        }
+    }
+    let headers = request.extensions().get::<Arc<ResponseHeaders>>().cloned();
+    let mut response = handle_request(state.clone(), request).await?;
+    #[cfg(feature = "chaos")]
+    if let Some(chaos) = &state.chaos {
+        response = chaos.mangle(response).await?;
+    }
+    headers
+        .as_deref()
+        .unwrap_or(&state.response_headers)
+        .inject(&mut response);
+    Ok(response)
+}
+
+pub(crate) async fn handle_request(
+    state: Arc<State>,
+    request: Request<Body>,
 ) -> Result<Response<Body>, Error> {
     let (parts, body) = request.into_parts();
-    if parts.uri.path() == "/" || parts.uri.path() == "" || parts.uri.path().starts_with("/wallet/")
-    {
-        if parts.method == Method::POST {
+    #[cfg(feature = "metrics")]
+    if state.serve_metrics && parts.uri.path() == "/metrics" {
+        return Ok(Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(state.metrics.render(&state).into())?);
+    }
+    if parts.uri.path() == "/stats" || parts.uri.path() == "/stats.json" {
+        return crate::stats::serve(&state, &parts).await;
+    }
+    #[cfg(feature = "grpc")]
+    if parts.uri.path().starts_with(crate::grpc::SERVICE_PATH) {
+        return crate::grpc::serve(state, parts, body).await;
+    }
+    if parts.uri.path() == "/events" {
+        return crate::events::serve(state, parts).await;
+    }
+    #[cfg(feature = "websocket")]
+    if parts.uri.path() == "/ws" {
+        return crate::subscriptions::serve(state, parts, body).await;
+    }
+    if let Some(path) = crate::paths::normalize(parts.uri.path()) {
+        if state.compat.accepts_method(&parts, &body) {
+            if let Some(status) = state.compat.check(&parts) {
+                return Ok(Response::builder().status(status).body(Body::empty())?);
+            }
             let state_local = state.clone();
-            if let Some((name, user)) = parts
+            let remote_addr = parts.extensions.get::<SocketAddr>().copied();
+            let ip = remote_addr.map(|a| a.ip());
+            let Authenticated {
+                name,
+                profile,
+                user,
+                api_key,
+            } = match crate::auth::authenticate_request(&state, &parts).await {
+                Ok(auth) => auth,
+                Err(response) => return Ok(response),
+            };
+            let mut impersonator = None;
+            let (name, profile, user) = match parts
                 .headers
-                .get(AUTHORIZATION)
-                .and_then(|auth| state_local.users.get(auth))
+                .get(IMPERSONATE_HEADER)
+                .map(|v| v.to_str().map(str::trim))
             {
-                let body_data = body.collect::<Result<Bytes, _>>().await?;
-                match serde_json::from_slice(body_data.as_ref()) {
-                    Ok(req) => {
-                        let state_local = state.clone();
-                        let name_local = Arc::new(name);
-                        let response = state
-                            .rpc_client
-                            .send(parts.uri.path(), &req, move |_path, req| {
-                                use futures::TryFutureExt;
-                                let name_local_ok = name_local.clone();
-                                let name_local_err = name_local.clone();
-                                let state_local_ok = state_local.clone();
-                                let state_local_err = state_local.clone();
-                                user.intercept(state_local.clone(), req)
-                                    .map_ok(move |res| {
-                                        if res.is_some() {
-                                            debug!(
-                                                state_local_ok.logger,
-                                                "{} called {}: INTERCEPTED",
-                                                name_local_ok,
-                                                req.method.0
-                                            )
-                                        } else {
-                                            debug!(
-                                                state_local_ok.logger,
-                                                "{} called {}: FORWARDED",
-                                                name_local_ok,
-                                                req.method.0
-                                            )
-                                        }
-                                        res
-                                    })
-                                    .map_err(move |err| {
-                                        warn!(
-                                            state_local_err.logger,
-                                            "{} called {}: ERROR {} {}",
-                                            name_local_err,
-                                            req.method.0,
-                                            err.code,
-                                            err.message
+                Some(target) => {
+                    let target = target.unwrap_or_default();
+                    match state_local.users.impersonate(&user, target) {
+                        Ok(target) => {
+                            info!(state.logger, "{} is impersonating {}", name, target.0);
+                            impersonator = Some(name);
+                            let (name, user) = target;
+                            (name.clone(), name, user)
+                        }
+                        Err(e) => {
+                            warn!(
+                                state.logger,
+                                "{} denied impersonating {:?}: {}", name, target, e.message
+                            );
+                            let reason = format!("impersonating {:?}: {}", target, e.message);
+                            audit::record(
+                                &state,
+                                Event {
+                                    user: Some(&name),
+                                    ip,
+                                    ..Event::new(Kind::ImpersonationDenied, &reason)
+                                },
+                            );
+                            return RpcResponse::from(e).into_response();
+                        }
+                    }
+                }
+                None => (name, profile, user),
+            };
+            if impersonator.is_some() {
+                // the impersonated user's restrictions apply as well, to reproduce its view
+                if let Err(e) = user
+                    .check_access(crate::clock::now())
+                    .and_then(|_| user.check_address(remote_addr.map(|a| a.ip())))
+                {
+                    warn!(state.logger, "{} denied: {}", name, e.message);
+                    audit::record(
+                        &state,
+                        Event {
+                            user: Some(&name),
+                            impersonator: impersonator.as_deref(),
+                            ip,
+                            ..Event::new(Kind::AccessDenied, &e.message)
+                        },
+                    );
+                    return RpcResponse::from(e).into_response();
+                }
+            }
+            let user = &*user;
+            let body_data = body.collect::<Result<Bytes, _>>().await?;
+            let quota = Some((name.clone(), user.quota.clone())).filter(|q| !q.1.is_empty());
+            if let Some((name, quota)) = &quota {
+                state.quota_usage.add_bytes(name, quota, body_data.len());
+            }
+            let counted = |response| match quota.clone() {
+                Some((name, quota)) => {
+                    QuotaUsage::count_response(state.clone(), name, quota, response)
+                }
+                None => response,
+            };
+            // as received, for the signature of the response
+            let request_body = body_data.clone();
+            let (body_data, plan) = if state.jsonrpc2_strict {
+                match crate::jsonrpc2::plan(&body_data) {
+                    Ok((body_data, plan)) => (body_data, Some(plan)),
+                    Err(answer) => return Ok(counted(crate::jsonrpc2::json_response(&answer)?)),
+                }
+            } else {
+                (body_data, None)
+            };
+            // of the listener the request came in on
+            let headers = parts
+                .extensions
+                .get::<Arc<ResponseHeaders>>()
+                .map_or(&state.response_headers, |headers| &**headers);
+            let ctx = &RequestContext {
+                remote_addr,
+                impersonator,
+                api_key,
+                profile,
+                path: path.clone(),
+                ..RequestContext::new(name, &parts.headers)
+            };
+            let response = match serde_json::from_slice(body_data.as_ref()) {
+                Ok(req) => {
+                    let state_local = state.clone();
+                    let name_local = Arc::new(ctx.caller());
+                    let _in_flight = state.in_flight.start(&name_local, &path, &req);
+                    let response = state
+                        .rpc_client
+                        .send(&path, &req, headers, move |path, req| {
+                            use futures::TryFutureExt;
+                            let name_local_ok = name_local.clone();
+                            let name_local_err = name_local.clone();
+                            let state_local_ok = state_local.clone();
+                            let state_local_err = state_local.clone();
+                            user.intercept(state_local.clone(), path, req, ctx)
+                                .map_ok(move |res| {
+                                    if res.is_some() {
+                                        Metrics::inc(&state_local_ok.metrics.intercepted);
+                                        debug!(
+                                            state_local_ok.logger,
+                                            "{} called {}: INTERCEPTED",
+                                            name_local_ok,
+                                            req.method.0
+                                        )
+                                    } else {
+                                        Metrics::inc(&state_local_ok.metrics.forwarded);
+                                        debug!(
+                                            state_local_ok.logger,
+                                            "{} called {}: FORWARDED", name_local_ok, req.method.0
+                                        )
+                                    }
+                                    res
+                                })
+                                .map_err(move |err| {
+                                    Metrics::inc(&state_local_err.metrics.errors);
+                                    state_local_err.metrics.record_error(&req.method, err.code);
+                                    warn!(
+                                        state_local_err.logger,
+                                        "{} called {}: ERROR {} {}",
+                                        name_local_err,
+                                        req.method.0,
+                                        err.code,
+                                        err.message
+                                    );
+                                    if err.code == ACCESS_DENIED_ERROR_CODE
+                                        || err.code == METHOD_NOT_ALLOWED_ERROR_CODE
+                                    {
+                                        audit::record(
+                                            &state_local_err,
+                                            Event {
+                                                user: Some(&ctx.user_name),
+                                                impersonator: ctx.impersonator.as_deref(),
+                                                ip,
+                                                method: Some(&req.method.0),
+                                                ..Event::new(Kind::CallDenied, &err.message)
+                                            },
                                         );
-                                        err
-                                    })
+                                    }
+                                    err
+                                })
+                        })
+                        .await;
+                    let response = match response {
+                        Ok(response) => response,
+                        Err(e) => {
+                            warn!(state.logger, "Failed to reach bitcoind: {:#}", e);
+                            RpcResponse::from(RpcError {
+                                status: Some(StatusCode::BAD_GATEWAY),
+                                ..RpcError::from(e)
                             })
-                            .await?;
-                        Ok(response)
+                            .into_response()?
+                        }
+                    };
+                    let response = schema::validate(&state, &req, response).await?;
+                    state.deprecations.warn(&state.logger, ctx, &req);
+                    let response = warnings::add(&req, ctx.take_warnings(), response).await?;
+                    match req {
+                        SingleOrBatchRpcRequest::Single(req) if etag::is_cacheable(&req.method) => {
+                            etag::tag(response, parts.headers.get(IF_NONE_MATCH)).await?
+                        }
+                        _ => response,
                     }
-                    Err(e) => Ok(RpcResponse::from(RpcError::from(e)).into_response()?),
                 }
-            } else {
-                Ok(Response::builder()
-                    .status(StatusCode::UNAUTHORIZED)
-                    .header(WWW_AUTHENTICATE, "Basic realm=\"jsonrpc\"")
-                    .body(Body::empty())?)
-            }
+                Err(e) => RpcResponse::from(RpcError::from(e)).into_response()?,
+            };
+            let response = match &plan {
+                Some(plan) => crate::jsonrpc2::translate(plan, response).await?,
+                None => response,
+            };
+            let response = match &state.response_signer {
+                Some(signer) => {
+                    signer
+                        .sign(
+                            parts.method.as_str(),
+                            &path,
+                            &ctx.caller(),
+                            &request_body,
+                            response,
+                        )
+                        .await?
+                }
+                None => response,
+            };
+            Ok(counted(response))
         } else {
             Ok(Response::builder()
                 .status(StatusCode::METHOD_NOT_ALLOWED)
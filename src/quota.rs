@@ -0,0 +1,374 @@
+//! Cumulative quotas on the calls and traffic of users per day or month.
+//!
+//! Usage is kept in memory and saved to the storage periodically, so that it survives restarts if
+//! the storage does.
+//!
+//! Periods are calendar time, so they follow the wall clock. If it is stepped back, usage counted
+//! while it was ahead is counted in the current period, so that correcting a clock doesn't give
+//! users their quota again.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, Datelike, Duration, TimeZone, Timelike, Utc};
+use futures::future::{BoxFuture, FutureExt};
+use futures::TryStreamExt;
+use hyper::{Body, Response, StatusCode};
+use serde_json::{json, Value};
+
+use crate::auth::is_anonymous;
+use crate::client::{
+    GenericRpcMethod, RpcError, RpcRequest, RpcResponse, QUOTA_EXCEEDED_ERROR_CODE,
+};
+use crate::clock;
+use crate::intercept::{InterceptResult, Interceptor, RequestContext};
+use crate::state::State;
+use crate::storage::Storage;
+use crate::users::User;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Day,
+    Month,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Quota {
+    pub period: Period,
+    /// Counts the last 24 hours or 30 days instead of the current calendar day or month (UTC)
+    #[serde(default)]
+    pub rolling: bool,
+    /// Calls allowed per period
+    #[serde(default)]
+    pub requests: Option<u64>,
+    /// Bytes of requests and responses allowed per period
+    #[serde(default)]
+    pub bytes: Option<u64>,
+}
+impl Quota {
+    /// Start of the bucket usage at `now` is counted in: hours of rolling days, days of rolling
+    /// months and the whole period of calendar ones.
+    fn bucket(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let day = now.date().and_hms(0, 0, 0);
+        match (self.period, self.rolling) {
+            (Period::Day, false) | (Period::Month, true) => day,
+            (Period::Month, false) => Utc.ymd(now.year(), now.month(), 1).and_hms(0, 0, 0),
+            (Period::Day, true) => day + Duration::hours(i64::from(now.hour())),
+        }
+    }
+
+    /// Start of the oldest bucket still counted at `now`.
+    fn window_start(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let bucket = self.bucket(now);
+        match (self.period, self.rolling) {
+            (_, false) => bucket,
+            (Period::Day, true) => bucket - Duration::hours(23),
+            (Period::Month, true) => bucket - Duration::days(29),
+        }
+    }
+
+    /// When the usage counted in the bucket starting at `bucket` stops counting.
+    fn expiry(&self, bucket: DateTime<Utc>) -> DateTime<Utc> {
+        match (self.period, self.rolling) {
+            (Period::Day, false) => bucket + Duration::days(1),
+            (Period::Month, false) if bucket.month() == 12 => {
+                Utc.ymd(bucket.year() + 1, 1, 1).and_hms(0, 0, 0)
+            }
+            (Period::Month, false) => Utc
+                .ymd(bucket.year(), bucket.month() + 1, 1)
+                .and_hms(0, 0, 0),
+            (Period::Day, true) => bucket + Duration::hours(24),
+            (Period::Month, true) => bucket + Duration::days(30),
+        }
+    }
+
+    fn exceeded(&self, usage: Usage) -> bool {
+        self.requests.is_some_and(|r| usage.requests >= r)
+            || self.bytes.is_some_and(|b| usage.bytes >= b)
+    }
+}
+impl std::fmt::Display for Quota {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let limits: Vec<String> = self
+            .requests
+            .map(|r| format!("{} requests", r))
+            .into_iter()
+            .chain(self.bytes.map(|b| format!("{} bytes", b)))
+            .collect();
+        let period = match (self.period, self.rolling) {
+            (Period::Day, false) => "per day",
+            (Period::Month, false) => "per month",
+            (Period::Day, true) => "per 24 hours",
+            (Period::Month, true) => "per 30 days",
+        };
+        write!(f, "{} {}", limits.join(" and "), period)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct Usage {
+    pub requests: u64,
+    pub bytes: u64,
+}
+impl std::ops::AddAssign for Usage {
+    fn add_assign(&mut self, other: Usage) {
+        self.requests += other.requests;
+        self.bytes += other.bytes;
+    }
+}
+
+type Buckets = VecDeque<(DateTime<Utc>, Usage)>;
+
+/// Buckets starting this long ago no longer count towards any quota: calendar months start at
+/// most 31 days ago, rolling periods are shorter.
+const LONGEST_PERIOD_DAYS: i64 = 31;
+
+/// The buckets of the `quota`th quota of `user`, as stored.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Stored {
+    user: String,
+    quota: usize,
+    buckets: Buckets,
+}
+
+/// Usage of every quota of every user, kept in the document `name` of the storage.
+#[derive(Debug)]
+pub struct QuotaUsage {
+    usage: Mutex<HashMap<(String, usize), Buckets>>,
+    storage: Arc<dyn Storage>,
+    name: &'static str,
+    dirty: AtomicBool,
+}
+impl QuotaUsage {
+    pub fn open(storage: Arc<dyn Storage>, name: &'static str) -> Result<Self, Error> {
+        let usage = match storage.load(name)? {
+            Some(usage) => serde_json::from_value::<Vec<Stored>>(usage)
+                .map_err(|e| anyhow!("invalid {} in storage: {}", name, e))?
+                .into_iter()
+                .map(|s| ((s.user, s.quota), s.buckets))
+                .collect(),
+            None => HashMap::new(),
+        };
+        Ok(QuotaUsage {
+            usage: Mutex::new(usage),
+            storage,
+            name,
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    /// Drops the usage of users that no longer counts towards any quota, so that clients
+    /// calling once (e.g. anonymous ones from many addresses) don't accumulate.
+    fn evict(&self) {
+        let oldest = clock::now() - Duration::days(LONGEST_PERIOD_DAYS);
+        self.usage
+            .lock()
+            .unwrap()
+            .retain(|_, buckets| buckets.back().is_some_and(|(bucket, _)| *bucket > oldest));
+    }
+
+    /// Stores the usage if it changed since the last call. Usage of anonymous clients is only
+    /// kept in memory, as their names are addresses that mostly won't come back.
+    pub fn save(&self) -> Result<(), Error> {
+        self.evict();
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let stored: Vec<Stored> = self
+            .usage
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((user, _), buckets)| !buckets.is_empty() && !is_anonymous(user))
+            .map(|((user, quota), buckets)| Stored {
+                user: user.clone(),
+                quota: *quota,
+                buckets: buckets.clone(),
+            })
+            .collect();
+        self.storage
+            .store(self.name, &serde_json::to_value(stored)?)
+    }
+
+    /// Runs `f` on the buckets of the `i`th quota of `user` still counted at `now`.
+    fn with<R>(
+        &self,
+        user: &str,
+        i: usize,
+        quota: &Quota,
+        now: DateTime<Utc>,
+        f: impl FnOnce(&mut Buckets) -> R,
+    ) -> R {
+        let mut usage = self.usage.lock().unwrap();
+        let buckets = usage.entry((user.to_owned(), i)).or_default();
+        let current = quota.bucket(now);
+        if buckets.back().is_some_and(|(bucket, _)| *bucket > current) {
+            let mut ahead = Usage::default();
+            while buckets.back().is_some_and(|(bucket, _)| *bucket > current) {
+                ahead += buckets.pop_back().unwrap().1;
+            }
+            match buckets.back_mut() {
+                Some((last, total)) if *last == current => *total += ahead,
+                _ => buckets.push_back((current, ahead)),
+            }
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        let start = quota.window_start(now);
+        while buckets.front().is_some_and(|(bucket, _)| *bucket < start) {
+            buckets.pop_front();
+        }
+        f(buckets)
+    }
+
+    fn total(buckets: &Buckets) -> Usage {
+        let mut total = Usage::default();
+        for (_, usage) in buckets {
+            total += *usage;
+        }
+        total
+    }
+
+    /// Counts `usage` against the quotas of `user`.
+    pub fn add(&self, user: &str, quotas: &[Quota], usage: Usage) {
+        let now = clock::now();
+        for (i, quota) in quotas.iter().enumerate() {
+            let bucket = quota.bucket(now);
+            self.with(user, i, quota, now, |buckets| match buckets.back_mut() {
+                Some((last, total)) if *last == bucket => *total += usage,
+                _ => buckets.push_back((bucket, usage)),
+            });
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Fails if any of the quotas of `user` is used up.
+    pub fn check(&self, user: &str, quotas: &[Quota]) -> Result<(), RpcError> {
+        let now = clock::now();
+        for (i, quota) in quotas.iter().enumerate() {
+            let exhausted = self.with(user, i, quota, now, |buckets| {
+                if quota.exceeded(Self::total(buckets)) {
+                    buckets.front().map(|(bucket, _)| quota.expiry(*bucket))
+                } else {
+                    None
+                }
+            });
+            if let Some(resets_at) = exhausted {
+                let retry_after = (resets_at - now).num_seconds().max(0);
+                return Err(RpcError {
+                    code: QUOTA_EXCEEDED_ERROR_CODE,
+                    message: format!("Quota of {} exceeded", quota),
+                    data: Some(json!({
+                        "retry_after": retry_after,
+                        "resets_at": resets_at.to_rfc3339(),
+                    })),
+                    status: Some(StatusCode::TOO_MANY_REQUESTS),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts a call of `user`, failing if any of its quotas is used up.
+    pub fn acquire(&self, user: &str, quotas: &[Quota]) -> Result<(), RpcError> {
+        self.check(user, quotas)?;
+        self.add(
+            user,
+            quotas,
+            Usage {
+                requests: 1,
+                bytes: 0,
+            },
+        );
+        Ok(())
+    }
+
+    /// Counts `bytes` of traffic of `user`.
+    pub fn add_bytes(&self, user: &str, quotas: &[Quota], bytes: usize) {
+        self.add(
+            user,
+            quotas,
+            Usage {
+                requests: 0,
+                bytes: bytes as u64,
+            },
+        );
+    }
+
+    /// Counts the bytes of `response` against the quotas of `user` as they are sent.
+    pub fn count_response(
+        state: Arc<State>,
+        user: String,
+        quotas: Vec<Quota>,
+        response: Response<Body>,
+    ) -> Response<Body> {
+        let (parts, body) = response.into_parts();
+        let body = body.map_ok(move |chunk| {
+            state.quota_usage.add_bytes(&user, &quotas, chunk.len());
+            chunk
+        });
+        Response::from_parts(parts, Body::wrap_stream(body))
+    }
+
+    /// Current usage and limits of the quotas of `user`.
+    pub fn report(&self, user: &str, quotas: &[Quota]) -> Value {
+        let now = clock::now();
+        quotas
+            .iter()
+            .enumerate()
+            .map(|(i, quota)| {
+                let (usage, oldest) = self.with(user, i, quota, now, |buckets| {
+                    (Self::total(buckets), buckets.front().map(|b| b.0))
+                });
+                json!({
+                    "period": quota.period,
+                    "rolling": quota.rolling,
+                    "requests": { "used": usage.requests, "limit": quota.requests },
+                    "bytes": { "used": usage.bytes, "limit": quota.bytes },
+                    "exceeded": quota.exceeded(usage),
+                    "resets_at": quota
+                        .expiry(oldest.unwrap_or_else(|| quota.bucket(now)))
+                        .to_rfc3339(),
+                })
+            })
+            .collect::<Vec<_>>()
+            .into()
+    }
+}
+
+/// `proxy_getquota [user]`: the usage of the quotas of the caller, or of another user for users
+/// with `manage_users`.
+pub struct GetQuota;
+impl Interceptor for GetQuota {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            let result = match req.params.first().and_then(Value::as_str) {
+                Some(name) if name != ctx.user_name => {
+                    if !user.manage_users {
+                        return Err(anyhow!("quotas of other users require manage_users").into());
+                    }
+                    let other = state
+                        .users
+                        .by_name(name)
+                        .ok_or_else(|| anyhow!("unknown user {}", name))?;
+                    state.quota_usage.report(name, &other.quota)
+                }
+                _ => state.quota_usage.report(&ctx.user_name, &user.quota),
+            };
+            Ok(Some(RpcResponse {
+                id: req.id.clone(),
+                result: Some(result),
+                error: None,
+            }))
+        }
+        .boxed()
+    }
+}
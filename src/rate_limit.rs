@@ -0,0 +1,372 @@
+//! Limits on how often users may call methods.
+//!
+//! State is kept in memory or, to share it between several proxies in front of the same node,
+//! in Redis. All algorithms are implemented twice: in Rust for the former and as Lua scripts
+//! (using the clock of the Redis server) for the latter.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Error};
+use hyper::StatusCode;
+use serde_json::{json, Value};
+
+use crate::client::{RpcError, MISC_ERROR_CODE};
+use crate::redis::{Redis, Reply};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Algorithm {
+    /// Allows bursts of up to `burst` calls, refilled at the average rate
+    #[default]
+    TokenBucket,
+    /// Allows at most `requests` calls within any `period`
+    SlidingWindow,
+    /// Delays calls so that they reach the node at the average rate, queueing up to `burst`
+    LeakyBucket,
+}
+
+fn default_period() -> f64 {
+    1.0
+}
+
+/// `requests` calls per `period` seconds, on average.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RateLimit {
+    #[serde(default)]
+    pub algorithm: Algorithm,
+    pub requests: u32,
+    #[serde(default = "default_period")]
+    pub period: f64,
+    /// Size of the bucket, `requests` if not set
+    #[serde(default)]
+    pub burst: Option<u32>,
+    /// Methods the limit applies to, all of them if empty
+    #[serde(default)]
+    pub methods: HashSet<String>,
+}
+impl RateLimit {
+    /// A token bucket of `rate` calls per second on average, allowing bursts of `burst` calls
+    /// (the rate rounded up if not set).
+    pub fn per_second(rate: f64, burst: Option<u32>) -> Self {
+        let requests = rate.ceil().max(1.0);
+        RateLimit {
+            algorithm: Algorithm::TokenBucket,
+            requests: requests as u32,
+            period: requests / rate,
+            burst,
+            methods: HashSet::new(),
+        }
+    }
+    pub fn applies_to(&self, method: &str) -> bool {
+        self.methods.is_empty() || self.methods.contains(method)
+    }
+    fn burst(&self) -> f64 {
+        f64::from(self.burst.unwrap_or(self.requests).max(1))
+    }
+    /// Seconds between calls at the average rate
+    fn interval(&self) -> f64 {
+        self.period / f64::from(self.requests.max(1))
+    }
+    /// How long unused state of the limit is kept
+    fn ttl(&self) -> u64 {
+        (self.period + self.interval() * self.burst()).ceil() as u64 + 1
+    }
+}
+impl std::fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let algorithm = match self.algorithm {
+            Algorithm::TokenBucket => "token_bucket",
+            Algorithm::SlidingWindow => "sliding_window",
+            Algorithm::LeakyBucket => "leaky_bucket",
+        };
+        write!(
+            f,
+            "{} calls per {}s ({}",
+            self.requests, self.period, algorithm
+        )?;
+        if self.algorithm != Algorithm::SlidingWindow {
+            write!(f, ", burst {}", self.burst())?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// What to do with a call.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    Allow,
+    /// Make the call after waiting
+    Delay(Duration),
+    /// Reject the call, it may be retried after the duration
+    Reject(Duration),
+}
+
+#[derive(Debug)]
+enum Local {
+    TokenBucket { tokens: f64, updated: Instant },
+    SlidingWindow(VecDeque<Instant>),
+    LeakyBucket { next: Instant },
+}
+
+fn secs(s: f64) -> Duration {
+    Duration::from_secs_f64(s.max(0.0))
+}
+
+impl Local {
+    fn new(limit: &RateLimit, now: Instant) -> Self {
+        match limit.algorithm {
+            Algorithm::TokenBucket => Local::TokenBucket {
+                tokens: limit.burst(),
+                updated: now,
+            },
+            Algorithm::SlidingWindow => Local::SlidingWindow(VecDeque::new()),
+            Algorithm::LeakyBucket => Local::LeakyBucket { next: now },
+        }
+    }
+
+    fn acquire(&mut self, limit: &RateLimit, now: Instant) -> Decision {
+        match self {
+            Local::TokenBucket { tokens, updated } => {
+                let elapsed = now.saturating_duration_since(*updated).as_secs_f64();
+                *tokens = (*tokens + elapsed / limit.interval()).min(limit.burst());
+                *updated = now;
+                if *tokens >= 1.0 {
+                    *tokens -= 1.0;
+                    Decision::Allow
+                } else {
+                    Decision::Reject(secs((1.0 - *tokens) * limit.interval()))
+                }
+            }
+            Local::SlidingWindow(calls) => {
+                let period = secs(limit.period);
+                while calls
+                    .front()
+                    .is_some_and(|t| now.saturating_duration_since(*t) >= period)
+                {
+                    calls.pop_front();
+                }
+                if calls.len() < limit.requests as usize {
+                    calls.push_back(now);
+                    Decision::Allow
+                } else {
+                    Decision::Reject((calls[0] + period).saturating_duration_since(now))
+                }
+            }
+            Local::LeakyBucket { next } => {
+                let start = (*next).max(now);
+                let wait = start - now;
+                if wait.as_secs_f64() >= limit.interval() * limit.burst() {
+                    Decision::Reject(wait - secs(limit.interval() * (limit.burst() - 1.0)))
+                } else {
+                    *next = start + secs(limit.interval());
+                    if wait == Duration::from_secs(0) {
+                        Decision::Allow
+                    } else {
+                        Decision::Delay(wait)
+                    }
+                }
+            }
+        }
+    }
+
+    /// The state of the bucket or window at `now`, for state dumps.
+    fn dump(&self, now: Instant) -> Value {
+        match self {
+            Local::TokenBucket { tokens, updated } => json!({
+                "tokens": tokens,
+                "since_update": now.saturating_duration_since(*updated).as_secs_f64(),
+            }),
+            Local::SlidingWindow(calls) => json!({ "calls": calls.len() }),
+            Local::LeakyBucket { next } => json!({
+                "queued": next.saturating_duration_since(now).as_secs_f64(),
+            }),
+        }
+    }
+}
+
+/// Uses the clock of the Redis server, so that it doesn't matter which instance checks a limit.
+const LUA_PRELUDE: &str = r#"
+if redis.replicate_commands then redis.replicate_commands() end
+local time = redis.call('TIME')
+local now = tonumber(time[1]) + tonumber(time[2]) / 1000000
+local interval, burst, ttl = tonumber(ARGV[1]), tonumber(ARGV[2]), tonumber(ARGV[3])
+"#;
+
+const LUA_TOKEN_BUCKET: &str = r#"
+local state = redis.call('HMGET', KEYS[1], 'tokens', 'updated')
+local tokens = tonumber(state[1]) or burst
+local updated = tonumber(state[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - updated) / interval)
+local decision, wait = 'allow', 0
+if tokens >= 1 then tokens = tokens - 1 else decision, wait = 'reject', (1 - tokens) * interval end
+redis.call('HMSET', KEYS[1], 'tokens', tostring(tokens), 'updated', tostring(now))
+redis.call('EXPIRE', KEYS[1], ttl)
+return {decision, tostring(wait)}
+"#;
+
+const LUA_SLIDING_WINDOW: &str = r#"
+local period, requests = tonumber(ARGV[4]), tonumber(ARGV[5])
+redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', now - period)
+if redis.call('ZCARD', KEYS[1]) < requests then
+  redis.call('ZADD', KEYS[1], now, tostring(now) .. ':' .. ARGV[6])
+  redis.call('EXPIRE', KEYS[1], ttl)
+  return {'allow', '0'}
+end
+local oldest = redis.call('ZRANGE', KEYS[1], 0, 0, 'WITHSCORES')
+return {'reject', tostring(tonumber(oldest[2]) + period - now)}
+"#;
+
+const LUA_LEAKY_BUCKET: &str = r#"
+local start = math.max(now, tonumber(redis.call('GET', KEYS[1])) or now)
+local wait = start - now
+if wait >= interval * burst then return {'reject', tostring(wait - interval * (burst - 1))} end
+redis.call('SET', KEYS[1], tostring(start + interval), 'EX', ttl)
+if wait > 0 then return {'delay', tostring(wait)} end
+return {'allow', '0'}
+"#;
+
+/// Buckets are evicted once this many are kept, and then whenever their number doubled.
+const MIN_SWEEP: usize = 1024;
+
+/// The state of the limits of every user, with when it is no longer needed.
+#[derive(Debug, Default)]
+struct Memory {
+    buckets: HashMap<(String, usize), (Local, Instant)>,
+    /// Number of buckets at which unused ones are evicted next
+    sweep_at: usize,
+}
+impl Memory {
+    /// Drops the state of limits that wasn't used for longer than it matters, so that clients
+    /// calling once (e.g. anonymous ones from many addresses) don't accumulate.
+    fn evict(&mut self, now: Instant) {
+        if self.buckets.len() < self.sweep_at {
+            return;
+        }
+        self.buckets.retain(|_, (_, expires)| *expires > now);
+        self.sweep_at = (self.buckets.len() * 2).max(MIN_SWEEP);
+    }
+}
+
+#[derive(Debug)]
+enum Backend {
+    Memory(Mutex<Memory>),
+    Redis(Redis),
+}
+
+/// Keeps track of the calls of all users against their limits.
+#[derive(Debug)]
+pub struct RateLimiter {
+    backend: Backend,
+}
+impl RateLimiter {
+    pub fn memory() -> Self {
+        RateLimiter {
+            backend: Backend::Memory(Mutex::new(Memory {
+                sweep_at: MIN_SWEEP,
+                ..Memory::default()
+            })),
+        }
+    }
+
+    /// Shares the state with other proxies using the Redis server at `url`.
+    pub fn redis(url: &str) -> Result<Self, Error> {
+        Ok(RateLimiter {
+            backend: Backend::Redis(Redis::new(url)?),
+        })
+    }
+
+    pub fn is_shared(&self) -> bool {
+        matches!(self.backend, Backend::Redis(_))
+    }
+
+    /// The buckets and windows kept in memory, by user and index of the limit. The state shared
+    /// in Redis isn't included.
+    pub fn dump(&self) -> Value {
+        match &self.backend {
+            Backend::Memory(state) => {
+                let now = Instant::now();
+                let state = state.lock().unwrap();
+                let mut buckets: Vec<_> = state.buckets.iter().collect();
+                buckets.sort_by(|a, b| a.0.cmp(b.0));
+                buckets
+                    .into_iter()
+                    .map(|((user, index), (local, _))| {
+                        json!({ "user": user, "limit": index, "state": local.dump(now) })
+                    })
+                    .collect()
+            }
+            Backend::Redis(_) => json!({ "shared": true }),
+        }
+    }
+
+    /// Records a call of `user` against its `index`th limit.
+    pub async fn acquire(
+        &self,
+        user: &str,
+        index: usize,
+        limit: &RateLimit,
+    ) -> Result<Decision, Error> {
+        match &self.backend {
+            Backend::Memory(state) => {
+                let now = Instant::now();
+                let mut state = state.lock().unwrap();
+                state.evict(now);
+                let (local, expires) = state
+                    .buckets
+                    .entry((user.to_owned(), index))
+                    .or_insert_with(|| (Local::new(limit, now), now));
+                *expires = now + Duration::from_secs(limit.ttl());
+                Ok(local.acquire(limit, now))
+            }
+            Backend::Redis(redis) => {
+                let script = match limit.algorithm {
+                    Algorithm::TokenBucket => LUA_TOKEN_BUCKET,
+                    Algorithm::SlidingWindow => LUA_SLIDING_WINDOW,
+                    Algorithm::LeakyBucket => LUA_LEAKY_BUCKET,
+                };
+                let key = format!(
+                    "btc-rpc-proxy:rate:{:?}:{}:{}",
+                    limit.algorithm, user, index
+                );
+                let args = [
+                    limit.interval().to_string(),
+                    limit.burst().to_string(),
+                    limit.ttl().to_string(),
+                    limit.period.to_string(),
+                    limit.requests.to_string(),
+                    hex::encode(rand::random::<[u8; 8]>()),
+                ];
+                let reply = redis
+                    .eval(&format!("{}{}", LUA_PRELUDE, script), &[&key], &args)
+                    .await?;
+                let parts = match &reply {
+                    Reply::Array(Some(parts)) if parts.len() == 2 => parts,
+                    _ => return Err(anyhow!("unexpected reply {:?}", reply)),
+                };
+                let wait = secs(
+                    parts[1]
+                        .as_str()
+                        .ok_or_else(|| anyhow!("unexpected reply {:?}", reply))?
+                        .parse()?,
+                );
+                Ok(match parts[0].as_str() {
+                    Some("allow") => Decision::Allow,
+                    Some("delay") => Decision::Delay(wait),
+                    _ => Decision::Reject(wait),
+                })
+            }
+        }
+    }
+}
+
+/// The error returned for calls exceeding a limit.
+pub fn rate_limited(limit: &RateLimit, retry_after: Duration) -> RpcError {
+    RpcError {
+        code: MISC_ERROR_CODE,
+        message: format!("Rate limit exceeded: {}", limit),
+        data: Some(json!({ "retry_after": retry_after.as_secs_f64() })),
+        status: Some(StatusCode::TOO_MANY_REQUESTS),
+    }
+}
@@ -0,0 +1,201 @@
+//! `proxy_bumpstuck`, finding the wallet's unconfirmed transactions paying less than the
+//! mempool currently asks for and bumping their fees.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use futures::future::{BoxFuture, FutureExt};
+use serde_json::{json, Value};
+
+use crate::client::{GenericRpcMethod, RpcError, RpcRequest, RpcResponse};
+use crate::intercept::{InterceptResult, Interceptor, RequestContext};
+use crate::state::State;
+use crate::users::User;
+
+/// Confirmation target used unless the caller asks for another one.
+const DEFAULT_CONF_TARGET: u64 = 6;
+
+/// Sent transactions looked at, the most recent ones first.
+const MAX_TRANSACTIONS: u64 = 1000;
+
+const SAT_PER_BTC: f64 = 100_000_000.0;
+
+async fn call(
+    state: &State,
+    path: &str,
+    method: &str,
+    params: Vec<Value>,
+) -> Result<Value, RpcError> {
+    state
+        .rpc_client
+        .call_at(
+            path,
+            &RpcRequest {
+                id: None,
+                method: GenericRpcMethod(method.to_owned()),
+                params,
+            },
+        )
+        .await?
+        .into_result()
+}
+
+/// BTC/kvB in sat/vB.
+fn sat_per_vb(btc_per_kvb: Option<f64>) -> f64 {
+    btc_per_kvb.unwrap_or(0.0) * SAT_PER_BTC / 1000.0
+}
+
+/// Fee rate the mempool currently asks for to confirm within `conf_target` blocks, in sat/vB.
+async fn target_fee_rate(state: &State, conf_target: u64) -> Result<(f64, f64), RpcError> {
+    let estimate = call(state, "/", "estimatesmartfee", vec![conf_target.into()]).await?;
+    let mempool = call(state, "/", "getmempoolinfo", Vec::new()).await?;
+    let target =
+        sat_per_vb(estimate["feerate"].as_f64()).max(sat_per_vb(mempool["mempoolminfee"].as_f64()));
+    Ok((target, sat_per_vb(mempool["incrementalrelayfee"].as_f64())))
+}
+
+/// `proxy_bumpstuck [conf_target] [execute]`: unconfirmed replaceable transactions of the
+/// wallet paying less than needed to confirm within `conf_target` blocks (6 by default), with
+/// the fee rate they would be bumped to. With `execute`, they are bumped with `bumpfee`, which
+/// has to be allowed to the caller and goes through the same checks as if called directly.
+/// Bumps exceeding `max_fee_rate` or `bumpstuck_max_fee` are skipped.
+pub struct BumpStuck;
+impl Interceptor for BumpStuck {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            let conf_target = match req.params.first() {
+                None | Some(Value::Null) => DEFAULT_CONF_TARGET,
+                Some(target) => target
+                    .as_u64()
+                    .filter(|t| *t > 0)
+                    .ok_or_else(|| anyhow!("expected a positive confirmation target"))?,
+            };
+            let execute = req.params.get(1).and_then(Value::as_bool).unwrap_or(false);
+            if execute && user.allowed_by("bumpfee").is_none() {
+                return Err(anyhow!("executing bumps requires bumpfee to be allowed").into());
+            }
+            let (target, increment) = target_fee_rate(&state, conf_target).await?;
+            let guardrails = &state.guardrails;
+
+            // listed by the proxy itself, so the wallet restrictions of the caller are checked here
+            user.check_wallet(&ctx.path, "listtransactions")?;
+            let transactions = call(
+                &state,
+                &ctx.path,
+                "listtransactions",
+                vec!["*".into(), MAX_TRANSACTIONS.into()],
+            )
+            .await?;
+            let mut seen = HashSet::new();
+            let mut stuck = Vec::new();
+            for tx in transactions.as_array().into_iter().flatten().rev() {
+                let txid = match tx["txid"].as_str() {
+                    Some(txid) => txid,
+                    None => continue,
+                };
+                if tx["category"] != "send"
+                    || tx["confirmations"] != 0
+                    || tx["bip125-replaceable"] != "yes"
+                    || !seen.insert(txid.to_owned())
+                {
+                    continue;
+                }
+                let entry = match call(&state, "/", "getmempoolentry", vec![txid.into()]).await {
+                    Ok(entry) => entry,
+                    // confirmed or evicted since
+                    Err(_) => continue,
+                };
+                let vsize = entry["vsize"].as_f64().unwrap_or(0.0);
+                let fee = entry["fees"]["base"].as_f64().unwrap_or(0.0) * SAT_PER_BTC;
+                if vsize <= 0.0 {
+                    continue;
+                }
+                let fee_rate = fee / vsize;
+                if fee_rate >= target {
+                    continue;
+                }
+                let bump_rate = target.max(fee_rate + increment).ceil();
+                let added_fee = (bump_rate - fee_rate) * vsize / SAT_PER_BTC;
+                let mut status = json!({
+                    "txid": txid,
+                    "vsize": vsize,
+                    "fee_rate": fee_rate,
+                    "bump_fee_rate": bump_rate,
+                    "added_fee": added_fee,
+                });
+                let limit = if guardrails.max_fee_rate.is_some_and(|max| bump_rate > max) {
+                    Some("the bumped fee rate exceeds max_fee_rate")
+                } else if guardrails.bump_max_fee.is_some_and(|max| added_fee > max) {
+                    Some("the added fee exceeds bumpstuck_max_fee")
+                } else {
+                    None
+                };
+                if let Some(reason) = limit {
+                    status["action"] = "skipped".into();
+                    status["reason"] = reason.into();
+                } else if execute {
+                    let bump = RpcRequest {
+                        id: None,
+                        method: GenericRpcMethod("bumpfee".to_owned()),
+                        params: vec![txid.into(), json!({ "fee_rate": bump_rate })],
+                    };
+                    // each bump is a call of its own, retried as such if the caller retries
+                    let bump_ctx = RequestContext {
+                        idempotency_key: ctx
+                            .idempotency_key
+                            .as_ref()
+                            .map(|key| format!("{}:bumpfee:{}", key, txid)),
+                        ..ctx.clone()
+                    };
+                    let result = match user
+                        .intercept(state.clone(), &ctx.path, &bump, &bump_ctx)
+                        .boxed()
+                        .await
+                    {
+                        Ok(Some(response)) => response.into_result(),
+                        // not rewritten, rewritten calls are forwarded by `intercept` itself
+                        Ok(None) => call(&state, &ctx.path, "bumpfee", bump.params).await,
+                        Err(e) => Err(e),
+                    };
+                    match result {
+                        Ok(result) => {
+                            info!(
+                                state.logger,
+                                "{} bumped {} to {} sat/vB",
+                                ctx.caller(),
+                                txid,
+                                bump_rate
+                            );
+                            status["action"] = "bumped".into();
+                            status["result"] = result;
+                        }
+                        Err(e) => {
+                            status["action"] = "failed".into();
+                            status["reason"] = e.message.into();
+                        }
+                    }
+                } else {
+                    status["action"] = "none".into();
+                }
+                stuck.push(status);
+            }
+            Ok(Some(RpcResponse {
+                id: req.id.clone(),
+                result: Some(json!({
+                    "target_fee_rate": target,
+                    "conf_target": conf_target,
+                    "stuck": stuck,
+                })),
+                error: None,
+            }))
+        }
+        .boxed()
+    }
+}
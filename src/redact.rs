@@ -0,0 +1,102 @@
+//! Removal of sensitive fields from the results of calls before they reach a user, e.g. the seed
+//! id of `getwalletinfo` or the addresses of peers in `getpeerinfo`.
+
+use std::convert::TryFrom;
+
+use anyhow::{anyhow, Error};
+use serde_json::Value;
+
+use crate::client::{GenericRpcMethod, RpcResponse};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Key(String),
+    /// Every element of an array
+    Each,
+}
+
+/// Path of a field in a result: keys separated by dots, `[]` standing for every element of an
+/// array, e.g. `descriptors[].desc` or `[].addr`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct FieldPath {
+    path: String,
+    segments: Vec<Segment>,
+}
+impl TryFrom<String> for FieldPath {
+    type Error = Error;
+    fn try_from(path: String) -> Result<Self, Error> {
+        let mut segments = Vec::new();
+        for part in path.split('.') {
+            let mut key = part;
+            let mut each = 0;
+            while let Some(rest) = key.strip_suffix("[]") {
+                key = rest;
+                each += 1;
+            }
+            if key.contains(['[', ']']) || (key.is_empty() && each == 0) {
+                return Err(anyhow!("invalid field path {:?}", path));
+            }
+            if !key.is_empty() {
+                segments.push(Segment::Key(key.to_owned()));
+            }
+            segments.extend(std::iter::repeat_n(Segment::Each, each));
+        }
+        if !matches!(segments.last(), Some(Segment::Key(_))) {
+            return Err(anyhow!("field path {:?} has to end with a key", path));
+        }
+        Ok(FieldPath { path, segments })
+    }
+}
+impl From<FieldPath> for String {
+    fn from(path: FieldPath) -> Self {
+        path.path
+    }
+}
+
+/// Removes the field at `segments` from `value`, wherever it exists.
+fn remove(value: &mut Value, segments: &[Segment]) {
+    match (segments, value) {
+        ([Segment::Key(key)], Value::Object(fields)) => {
+            fields.remove(key);
+        }
+        ([Segment::Key(key), rest @ ..], Value::Object(fields)) => {
+            if let Some(value) = fields.get_mut(key) {
+                remove(value, rest);
+            }
+        }
+        ([Segment::Each, rest @ ..], Value::Array(items)) => {
+            for item in items {
+                remove(item, rest);
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Fields removed from the results of `method`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Redaction {
+    pub method: String,
+    pub fields: Vec<FieldPath>,
+}
+
+/// Whether any of `redactions` applies to `method`.
+pub fn applies(redactions: &[Redaction], method: &str) -> bool {
+    redactions.iter().any(|r| r.method == method)
+}
+
+/// Removes the fields of `redactions` applying to `method` from the result of `response`.
+pub fn apply(redactions: &[Redaction], method: &str, response: &mut RpcResponse<GenericRpcMethod>) {
+    let result = match &mut response.result {
+        Some(result) => result,
+        None => return,
+    };
+    for field in redactions
+        .iter()
+        .filter(|r| r.method == method)
+        .flat_map(|r| &r.fields)
+    {
+        remove(result, &field.segments);
+    }
+}
@@ -0,0 +1,200 @@
+//! A minimal Redis client, enough to share state between proxy instances.
+
+use anyhow::{anyhow, Error};
+use futures::future::{BoxFuture, FutureExt};
+use hyper::Uri;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+/// A reply of the Redis server.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Status(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+impl Reply {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Reply::Status(s) => Some(s),
+            Reply::Bulk(Some(b)) => std::str::from_utf8(b).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// An error reply of the Redis server, after which the connection is still usable.
+#[derive(Debug)]
+pub struct RedisError(pub String);
+impl std::fmt::Display for RedisError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Redis error: {}", self.0)
+    }
+}
+impl std::error::Error for RedisError {}
+
+fn read_reply<'a>(conn: &'a mut BufReader<TcpStream>) -> BoxFuture<'a, Result<Reply, Error>> {
+    async move {
+        let mut line = String::new();
+        if conn.read_line(&mut line).await? == 0 {
+            return Err(anyhow!("Redis closed the connection"));
+        }
+        let line = line.trim_end_matches("\r\n");
+        let (kind, rest) = line.split_at(line.len().min(1));
+        Ok(match kind {
+            "+" => Reply::Status(rest.to_owned()),
+            "-" => return Err(RedisError(rest.to_owned()).into()),
+            ":" => Reply::Integer(rest.parse()?),
+            "$" => match rest.parse::<i64>()? {
+                len if len < 0 => Reply::Bulk(None),
+                len => {
+                    let mut data = vec![0; len as usize + 2];
+                    conn.read_exact(&mut data).await?;
+                    data.truncate(len as usize);
+                    Reply::Bulk(Some(data))
+                }
+            },
+            "*" => match rest.parse::<i64>()? {
+                len if len < 0 => Reply::Array(None),
+                len => {
+                    let mut items = Vec::with_capacity(len as usize);
+                    for _ in 0..len {
+                        items.push(read_reply(conn).await?);
+                    }
+                    Reply::Array(Some(items))
+                }
+            },
+            _ => return Err(anyhow!("invalid Redis reply {:?}", line)),
+        })
+    }
+    .boxed()
+}
+
+fn encode(args: &[&[u8]]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg);
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+/// A single connection to a Redis server, reestablished after errors.
+#[derive(Debug)]
+pub struct Redis {
+    addr: String,
+    password: Option<String>,
+    db: Option<String>,
+    conn: Mutex<Option<BufReader<TcpStream>>>,
+}
+impl Redis {
+    /// Parses a `redis://[:password@]host[:port][/db]` URL without connecting.
+    pub fn new(url: &str) -> Result<Self, Error> {
+        let uri: Uri = url.parse()?;
+        if uri.scheme_str() != Some("redis") {
+            return Err(anyhow!("expected a redis:// URL, got {}", url));
+        }
+        let authority = uri
+            .authority()
+            .ok_or_else(|| anyhow!("missing host in {}", url))?;
+        let password = authority
+            .as_str()
+            .rsplit_once('@')
+            .map(|(user_info, _)| user_info.split_once(':').map_or(user_info, |(_, p)| p))
+            .map(str::to_owned);
+        let db = Some(uri.path().trim_start_matches('/'))
+            .filter(|db| !db.is_empty())
+            .map(str::to_owned);
+        Ok(Redis {
+            addr: format!(
+                "{}:{}",
+                authority.host(),
+                authority.port_u16().unwrap_or(6379)
+            ),
+            password,
+            db,
+            conn: Mutex::new(None),
+        })
+    }
+
+    async fn connect(&self) -> Result<BufReader<TcpStream>, Error> {
+        let mut conn = BufReader::new(TcpStream::connect(&self.addr).await?);
+        if let Some(password) = &self.password {
+            conn.get_mut()
+                .write_all(&encode(&[b"AUTH", password.as_bytes()]))
+                .await?;
+            read_reply(&mut conn).await?;
+        }
+        if let Some(db) = &self.db {
+            conn.get_mut()
+                .write_all(&encode(&[b"SELECT", db.as_bytes()]))
+                .await?;
+            read_reply(&mut conn).await?;
+        }
+        Ok(conn)
+    }
+
+    /// Runs a single command.
+    pub async fn query(&self, args: &[&[u8]]) -> Result<Reply, Error> {
+        let mut conn = self.conn.lock().await;
+        if conn.is_none() {
+            *conn = Some(self.connect().await?);
+        }
+        let stream = conn.as_mut().unwrap();
+        let res = match stream.get_mut().write_all(&encode(args)).await {
+            Ok(()) => read_reply(stream).await,
+            Err(e) => Err(e.into()),
+        };
+        // the connection may be left in the middle of a reply after other errors
+        if res.as_ref().is_err_and(|e| !e.is::<RedisError>()) {
+            *conn = None;
+        }
+        res
+    }
+
+    /// Sends `message` to the subscribers of `channel`.
+    pub async fn publish(&self, channel: &str, message: &[u8]) -> Result<(), Error> {
+        self.query(&[b"PUBLISH", channel.as_bytes(), message])
+            .await
+            .map(drop)
+    }
+
+    /// Opens a separate connection receiving the messages sent to `channel`.
+    pub async fn subscribe(&self, channel: &str) -> Result<Subscription, Error> {
+        let mut conn = self.connect().await?;
+        conn.get_mut()
+            .write_all(&encode(&[b"SUBSCRIBE", channel.as_bytes()]))
+            .await?;
+        read_reply(&mut conn).await?;
+        Ok(Subscription(conn))
+    }
+
+    /// Runs a Lua script atomically.
+    pub async fn eval(&self, script: &str, keys: &[&str], args: &[String]) -> Result<Reply, Error> {
+        let num_keys = keys.len().to_string();
+        let mut cmd: Vec<&[u8]> = vec![b"EVAL", script.as_bytes(), num_keys.as_bytes()];
+        cmd.extend(keys.iter().map(|k| k.as_bytes()));
+        cmd.extend(args.iter().map(|a| a.as_bytes()));
+        self.query(&cmd).await
+    }
+}
+
+/// A connection in subscriber mode.
+#[derive(Debug)]
+pub struct Subscription(BufReader<TcpStream>);
+impl Subscription {
+    pub async fn next_message(&mut self) -> Result<Vec<u8>, Error> {
+        loop {
+            if let Reply::Array(Some(mut parts)) = read_reply(&mut self.0).await? {
+                if parts.len() == 3 && parts[0].as_str() == Some("message") {
+                    if let Reply::Bulk(Some(message)) = parts.pop().unwrap() {
+                        return Ok(message);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use futures::future::{BoxFuture, FutureExt};
+use hyper::StatusCode;
+use serde_json::{json, Value};
+
+use crate::client::{
+    GenericRpcMethod, RpcError, RpcRequest, RpcResponse, ACCESS_DENIED_ERROR_CODE,
+};
+use crate::intercept::{InterceptResult, Interceptor, RequestContext};
+use crate::listen::Address;
+use crate::state::State;
+use crate::users::User;
+
+fn features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(feature = "peer-fetch") {
+        features.push("peer-fetch");
+    }
+    if cfg!(feature = "tor") {
+        features.push("tor");
+    }
+    if cfg!(feature = "chaos") {
+        features.push("chaos");
+    }
+    features
+}
+
+/// The configuration without secrets, user names, addresses or anything else identifying.
+fn config(state: &State) -> Value {
+    let mut users: Vec<_> = state.users.list().into_iter().map(|(_, u)| u).collect();
+    users.sort_by_key(|u| u.allowed_calls.len());
+    let tcp: Vec<_> = state
+        .listen
+        .iter()
+        .filter_map(|l| match l.address {
+            Address::Tcp(addr) => Some(addr),
+            #[cfg(unix)]
+            Address::Unix(_) => None,
+        })
+        .collect();
+    #[allow(unused_mut)]
+    let mut config = json!({
+        "bind_port": tcp.first().map(|addr| addr.port()),
+        "bind_loopback": tcp.iter().all(|addr| addr.ip().is_loopback()),
+        "listeners": state.listen.len(),
+        "users": users
+            .into_iter()
+            .map(|u| json!({
+                "allowed_calls": u.allowed_calls.len(),
+                "role": u.role,
+                "fetch_blocks": u.fetch_blocks,
+                "expires": u.expires.is_some(),
+                "allowed_hours": u.allowed_hours.len(),
+            }))
+            .collect::<Vec<_>>(),
+        "compat": {
+            "text_plain": state.compat.text_plain,
+            "missing_content_type": state.compat.missing_content_type,
+            "get_with_body": state.compat.get_with_body,
+            "expect_continue": state.compat.expect_continue,
+        },
+        "read_only": state.read_only.load(Ordering::SeqCst),
+        "validate_responses": format!("{:?}", state.validate_responses),
+        "approvals": state.approvals.as_ref().map(|a| json!({
+            "threshold": a.threshold.as_btc(),
+            "timeout": a.timeout.as_secs(),
+        })),
+        "notify_webhook": state.notifier.webhook.is_some(),
+        "notify_email": state.notifier.smtp.is_some(),
+        "journal": state.journal.is_some(),
+        "data_dir": state.data_dir.is_some(),
+        "idempotency_window": state.idempotency.window.as_secs(),
+        "cache_memory_budget": state.memory_budget.limit,
+        "rate_limit_redis": state.rate_limiter.is_shared(),
+        "cache_sync_redis": state.cache_sync.is_shared(),
+        "load_check_interval": state.load.as_ref().map(|l| l.interval.as_secs()),
+        "balance_wallets": state.balances.as_ref().map(|b| b.wallets.len()),
+        "deprecated_methods": state.deprecations.methods(),
+        "guardrails": {
+            "unconfirmed_inputs": state.guardrails.unconfirmed_inputs,
+            "subtract_fee": state.guardrails.subtract_fee,
+            "fee_rate": state.guardrails.fee_rate,
+            "min_fee_rate": state.guardrails.min_fee_rate,
+            "max_fee_rate": state.guardrails.max_fee_rate,
+            "replaceable": state.guardrails.replaceable,
+            "bumpstuck_max_fee": state.guardrails.bump_max_fee,
+        },
+        "address_reuse": state.address_reuse.as_ref().map(|r| r.mode),
+        "lockout": state.lockout.as_ref().map(|l| json!({
+            "failures": l.max_failures,
+            "window": l.window.as_secs(),
+            "duration": l.duration.as_secs(),
+        })),
+    });
+    #[cfg(feature = "metrics")]
+    {
+        config["serve_metrics"] = state.serve_metrics.into();
+    }
+    #[cfg(feature = "cache")]
+    {
+        config["txout_cache"] = state.txout_cache.is_some().into();
+    }
+    #[cfg(feature = "tls")]
+    {
+        config["tls"] = state.listen.iter().any(|l| l.tls.is_some()).into();
+    }
+    #[cfg(feature = "peer-fetch")]
+    {
+        use crate::block_cache::BlockCache;
+        use crate::fetch_blocks::FetchPolicy;
+
+        config["peer_timeout"] = state.peer_timeout.as_secs().into();
+        config["peer_timeouts"] = json!(state.peer_timeouts.all());
+        config["max_peer_age"] = state.max_peer_age.as_secs().into();
+        config["warm_peers"] = state.warm_peers.into();
+        config["peer_keepalive_interval"] = state.peer_keepalive_interval.as_secs().into();
+        config["p2p_trace"] = (state.p2p_tracer.is_some()).into();
+        config["max_peer_concurrency"] = json!(state.max_peer_concurrency);
+        config["fetch_budget"] = json!(state.fetch_budget.map(|b| b.as_secs()));
+        config["fetch_order"] = FetchPolicy::METHODS
+            .iter()
+            .map(|m| {
+                let steps: Vec<_> = state
+                    .fetch_policy
+                    .steps(m)
+                    .into_iter()
+                    .map(|s| match s.timeout {
+                        Some(t) => format!("{}:{}", s.stage, t.as_secs()),
+                        None => s.stage.to_string(),
+                    })
+                    .collect();
+                (m.to_string(), steps.into())
+            })
+            .collect::<serde_json::Map<_, _>>()
+            .into();
+        config["block_cache"] = match state.block_cache {
+            BlockCache::Memory(_) => "memory",
+            BlockCache::Disk(_) => "disk",
+        }
+        .into();
+    }
+    #[cfg(feature = "tor")]
+    {
+        config["tor"] = json!(state.tor.as_ref().map(|t| json!({ "only": t.only })));
+    }
+    config
+}
+
+/// Error counts by method and code. Messages are left out as they may contain addresses.
+fn errors(state: &State) -> Value {
+    let mut errors: Vec<_> = state
+        .metrics
+        .error_summaries
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|((method, code), summary)| {
+            json!({
+                "method": method,
+                "code": code,
+                "count": summary.count,
+                "last_seen": summary.last_seen,
+            })
+        })
+        .collect();
+    errors.sort_by_key(|e| std::cmp::Reverse(e["count"].as_u64()));
+    errors.into()
+}
+
+/// `proxy_generatereport`: an anonymized diagnostic report to attach to bug reports. Only
+/// available to clients connecting from the same machine.
+pub struct GenerateReport;
+impl Interceptor for GenerateReport {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        _user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            if !ctx.remote_addr.is_some_and(|a| a.ip().is_loopback()) {
+                return Err(RpcError {
+                    code: ACCESS_DENIED_ERROR_CODE,
+                    message: "Reports can only be generated from localhost".to_owned(),
+                    data: None,
+                    status: Some(StatusCode::FORBIDDEN),
+                });
+            }
+            let metrics = &state.metrics;
+            #[allow(unused_mut)]
+            let mut report = json!({
+                "generated": chrono::Utc::now(),
+                "version": env!("CARGO_PKG_VERSION"),
+                "features": features(),
+                "config": config(&state),
+                "metrics": {
+                    "forwarded": metrics.forwarded.load(Ordering::Relaxed),
+                    "intercepted": metrics.intercepted.load(Ordering::Relaxed),
+                    "errors": metrics.errors.load(Ordering::Relaxed),
+                    "schema_divergences": metrics.schema_divergences.load(Ordering::Relaxed),
+                    "cache_used_bytes": state.memory_budget.used(),
+                },
+                "errors": errors(&state),
+            });
+            #[cfg(feature = "peer-fetch")]
+            {
+                let peers = state.peers.read().await.clone();
+                report["peers"] = json!({
+                    "count": peers.len(),
+                    "age": peers.age().map(|a| a.as_secs()),
+                });
+            }
+            Ok(Some(RpcResponse {
+                id: req.id.clone(),
+                result: Some(report),
+                error: None,
+            }))
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,283 @@
+//! Looking up host names without asking the resolver of the system, whose queries leave the
+//! host in the clear even if all other traffic goes through Tor: through Tor itself, with its
+//! `RESOLVE` extension of SOCKS5, or with DNS-over-HTTPS ([RFC 8484]).
+//!
+//! [RFC 8484]: https://www.rfc-editor.org/rfc/rfc8484
+
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Error};
+#[cfg(feature = "tls")]
+use hyper::Uri;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// The `RESOLVE` command of Tor's SOCKS5 extensions.
+const TOR_RESOLVE: u8 = 0xF0;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+
+/// The longest DNS response that is read.
+#[cfg(feature = "tls")]
+const MAX_DNS_RESPONSE: usize = 64 * 1024;
+
+#[derive(Debug, Clone)]
+pub enum Resolver {
+    /// `getaddrinfo`
+    System,
+    /// Through the Tor SOCKS5 proxy at this address
+    Tor(SocketAddr),
+    /// With DNS-over-HTTPS
+    #[cfg(feature = "tls")]
+    Doh(Doh),
+}
+/// A DNS-over-HTTPS server.
+#[cfg(feature = "tls")]
+#[derive(Debug, Clone)]
+pub struct Doh {
+    /// The URL queries are posted to, whose host the certificate is verified against
+    pub uri: Uri,
+    /// The address to connect to, so that the server itself isn't looked up. The host of `uri`
+    /// is looked up with the system resolver if `None`.
+    pub addr: Option<IpAddr>,
+    pub ca_file: Option<PathBuf>,
+}
+
+impl Resolver {
+    /// Parses the `resolver` option: `system`, `tor` for the Tor proxy at `tor_proxy` or the
+    /// URL of a DNS-over-HTTPS server, connected to at `doh_address` if given.
+    pub fn from_config(
+        resolver: &str,
+        tor_proxy: Option<SocketAddr>,
+        doh_address: Option<IpAddr>,
+        ca_file: Option<PathBuf>,
+    ) -> Result<Self, Error> {
+        match resolver {
+            "system" => Ok(Resolver::System),
+            "tor" => tor_proxy
+                .map(Resolver::Tor)
+                .ok_or_else(|| anyhow!("resolving with tor requires tor_proxy")),
+            #[cfg(feature = "tls")]
+            url if url.starts_with("https://") => Ok(Resolver::Doh(Doh {
+                uri: url.parse()?,
+                addr: doh_address,
+                ca_file,
+            })),
+            #[cfg(not(feature = "tls"))]
+            url if url.starts_with("https://") => {
+                let _ = (doh_address, ca_file);
+                Err(anyhow!(
+                    "DNS-over-HTTPS requires the proxy to be built with the `tls` feature"
+                ))
+            }
+            other => Err(anyhow!(
+                "unknown resolver {:?}, expected system, tor or an https:// URL",
+                other
+            )),
+        }
+    }
+
+    /// The addresses of `host`, an IP address being returned as it is.
+    pub async fn lookup(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, Error> {
+        if let Ok(ip) = host
+            .trim_matches(|c| c == '[' || c == ']')
+            .parse::<IpAddr>()
+        {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        let ips = match self {
+            Resolver::System => {
+                return Ok(tokio::net::lookup_host((host, port)).await?.collect());
+            }
+            Resolver::Tor(proxy) => {
+                let mut stream = TcpStream::connect(proxy).await?;
+                vec![tor_resolve(&mut stream, host).await?]
+            }
+            #[cfg(feature = "tls")]
+            Resolver::Doh(doh) => {
+                let (v4, v6) = futures::join!(doh.query(host, TYPE_A), doh.query(host, TYPE_AAAA));
+                match (v4, v6) {
+                    (Err(e), Err(_)) => return Err(e),
+                    (v4, v6) => v4
+                        .unwrap_or_default()
+                        .into_iter()
+                        .chain(v6.unwrap_or_default())
+                        .collect(),
+                }
+            }
+        };
+        if ips.is_empty() {
+            return Err(anyhow!("{} has no addresses", host));
+        }
+        Ok(ips
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect())
+    }
+}
+
+/// Looks `host` up through the Tor SOCKS5 proxy at the other end of `stream`.
+pub async fn tor_resolve<S>(stream: &mut S, host: &str) -> Result<IpAddr, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if host.len() > 255 {
+        return Err(anyhow!("{:?} is too long to be looked up", host));
+    }
+    stream.write_all(&[5, 1, 0]).await?;
+    let mut choice = [0; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice != [5, 0] {
+        return Err(anyhow!("Tor refused the authentication method"));
+    }
+    let mut request = vec![5, TOR_RESOLVE, 0, 3, host.len() as u8];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&[0, 0]);
+    stream.write_all(&request).await?;
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(anyhow!(
+            "Tor failed to look up {}: reply {}",
+            host,
+            reply[1]
+        ));
+    }
+    let ip = match reply[3] {
+        1 => {
+            let mut ip = [0; 4];
+            stream.read_exact(&mut ip).await?;
+            IpAddr::from(ip)
+        }
+        4 => {
+            let mut ip = [0; 16];
+            stream.read_exact(&mut ip).await?;
+            IpAddr::from(ip)
+        }
+        _ => {
+            return Err(anyhow!(
+                "Tor answered the lookup of {} without an address",
+                host
+            ))
+        }
+    };
+    Ok(ip)
+}
+
+/// A DNS query for records of `qtype` of `host`, with recursion desired.
+pub fn encode_query(host: &str, qtype: u16) -> Result<Vec<u8>, Error> {
+    // the ID is 0 over HTTPS, so that responses can be cached
+    let mut query = vec![0, 0, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(anyhow!("{:?} is not a valid host name", host));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&1u16.to_be_bytes());
+    Ok(query)
+}
+
+/// Skips the possibly compressed name at `pos` of `message`, returning where it ends.
+fn skip_name(message: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(pos)?;
+        match len {
+            0 => return Some(pos + 1),
+            // a pointer ends the name
+            len if len & 0xC0 == 0xC0 => return Some(pos + 2),
+            len => pos += 1 + len as usize,
+        }
+    }
+}
+
+/// The addresses in the answers of the DNS response `message`, an error if it is one.
+pub fn parse_answers(message: &[u8]) -> Result<Vec<IpAddr>, Error> {
+    let invalid = || anyhow!("invalid DNS response");
+    let u16_at = |pos: usize| -> Result<u16, Error> {
+        let bytes = message.get(pos..pos + 2).ok_or_else(invalid)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+    let flags = u16_at(2)?;
+    match flags & 0xF {
+        0 => (),
+        3 => return Ok(Vec::new()),
+        rcode => return Err(anyhow!("DNS lookup failed with RCODE {}", rcode)),
+    }
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(message, pos).ok_or_else(invalid)? + 4;
+    }
+    let mut ips = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(message, pos).ok_or_else(invalid)?;
+        let rtype = u16_at(pos)?;
+        let len = u16_at(pos + 8)? as usize;
+        let data = message.get(pos + 10..pos + 10 + len).ok_or_else(invalid)?;
+        match (rtype, len) {
+            (TYPE_A, 4) => ips.push(IpAddr::from([data[0], data[1], data[2], data[3]])),
+            (TYPE_AAAA, 16) => {
+                let mut ip = [0; 16];
+                ip.copy_from_slice(data);
+                ips.push(IpAddr::from(ip));
+            }
+            // CNAMEs are followed by the server, their targets' records come along
+            _ => (),
+        }
+        pos += 10 + len;
+    }
+    Ok(ips)
+}
+
+#[cfg(feature = "tls")]
+impl Doh {
+    async fn query(&self, host: &str, qtype: u16) -> Result<Vec<IpAddr>, Error> {
+        use hyper::header::{ACCEPT, CONTENT_TYPE, HOST};
+        use hyper::{Body, Method, Request, StatusCode};
+
+        let name = self
+            .uri
+            .host()
+            .ok_or_else(|| anyhow!("{} has no host", self.uri))?;
+        let port = self.uri.port_u16().unwrap_or(443);
+        let dns_name = webpki::DNSNameRef::try_from_ascii_str(name)
+            .map_err(|_| anyhow!("{} is not a valid DNS name", name))?;
+        let stream = match self.addr {
+            Some(ip) => TcpStream::connect((ip, port)).await?,
+            None => TcpStream::connect((name, port)).await?,
+        };
+        let connector = tokio_rustls::TlsConnector::from(std::sync::Arc::new(
+            crate::http_client::tls_config(self.ca_file.as_deref())?,
+        ));
+        let stream = connector.connect(dns_name, stream).await?;
+        let (mut sender, connection) = hyper::client::conn::handshake(stream).await?;
+        tokio::spawn(connection);
+        let path = self.uri.path_and_query().map_or("/", |p| p.as_str());
+        let response = sender
+            .send_request(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri(path)
+                    .header(HOST, name)
+                    .header(CONTENT_TYPE, "application/dns-message")
+                    .header(ACCEPT, "application/dns-message")
+                    .body(Body::from(encode_query(host, qtype)?))?,
+            )
+            .await?;
+        if response.status() != StatusCode::OK {
+            return Err(anyhow!("{} responded with {}", self.uri, response.status()));
+        }
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        if body.len() > MAX_DNS_RESPONSE {
+            return Err(anyhow!("DNS response too long"));
+        }
+        parse_answers(&body)
+    }
+}
@@ -0,0 +1,280 @@
+//! Address reuse detection: addresses users generated or saw receiving funds are remembered, and
+//! sends to addresses that already received funds are warned about or blocked.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use hyper::StatusCode;
+use serde_json::{json, Value};
+
+use crate::client::{
+    GenericRpcMethod, RpcError, RpcRequest, RpcResponse, POLICY_VIOLATION_ERROR_CODE,
+};
+use crate::intercept::RequestContext;
+use crate::state::State;
+use crate::tenants::TenantStore;
+
+/// What to do with sends to addresses that already received funds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    Warn,
+    Block,
+}
+impl std::str::FromStr for Mode {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "warn" => Ok(Mode::Warn),
+            "block" => Ok(Mode::Block),
+            _ => Err(anyhow::anyhow!(
+                "unknown address reuse mode {}, expected warn or block",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Seen {
+    /// Path of the wallet the address was seen at
+    path: String,
+    /// Whether the address is known to have received funds
+    funded: bool,
+}
+
+/// Addresses seen by the proxy, by the user whose calls revealed them.
+#[derive(Debug)]
+pub struct AddressReuse {
+    pub mode: Mode,
+    /// How many addresses are remembered per user, later ones aren't tracked
+    pub quota: usize,
+    addresses: Mutex<HashMap<String, HashMap<String, Seen>>>,
+}
+
+/// Addresses paid by a send, for the methods taking them positionally.
+fn destinations(req: &RpcRequest<GenericRpcMethod>) -> Vec<String> {
+    fn keys(outputs: &Value) -> Vec<String> {
+        match outputs {
+            Value::Object(outputs) => outputs
+                .keys()
+                .filter(|address| *address != "data")
+                .cloned()
+                .collect(),
+            Value::Array(outputs) => outputs
+                .iter()
+                .flat_map(|output| match output {
+                    Value::String(address) => vec![address.clone()],
+                    output => keys(output),
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+    match &*req.method.0 {
+        "sendtoaddress" => req
+            .params
+            .first()
+            .and_then(Value::as_str)
+            .map(str::to_owned)
+            .into_iter()
+            .collect(),
+        "sendmany" => req.params.get(1).map(keys).unwrap_or_default(),
+        "send" | "sendall" => req.params.first().map(keys).unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+/// Addresses a response shows receiving funds.
+fn funded(method: &str, result: &Value) -> Vec<String> {
+    let entries = match method {
+        "listsinceblock" => &result["transactions"],
+        _ => result,
+    };
+    entries
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|entry| match method {
+            "listtransactions" | "listsinceblock" => entry["category"] == "receive",
+            "listreceivedbyaddress" => entry["amount"].as_f64().is_some_and(|a| a > 0.0),
+            _ => true,
+        })
+        .filter_map(|entry| entry["address"].as_str().map(str::to_owned))
+        .collect()
+}
+
+impl AddressReuse {
+    pub fn new(mode: Mode, quota: usize) -> Self {
+        AddressReuse {
+            mode,
+            quota,
+            addresses: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether the responses to `method` reveal addresses.
+    pub fn observes(&self, method: &str) -> bool {
+        matches!(
+            method,
+            "getnewaddress"
+                | "listreceivedbyaddress"
+                | "listsinceblock"
+                | "listtransactions"
+                | "listunspent"
+                | "send"
+                | "sendall"
+                | "sendmany"
+                | "sendtoaddress"
+        )
+    }
+
+    fn remember(&self, user: &str, path: &str, address: String, funded: bool) {
+        let mut addresses = self.addresses.lock().unwrap();
+        let addresses = addresses.entry(user.to_owned()).or_default();
+        if let Some(seen) = addresses.get_mut(&address) {
+            seen.funded |= funded;
+        } else if addresses.len() < self.quota {
+            addresses.insert(
+                address,
+                Seen {
+                    path: path.to_owned(),
+                    funded,
+                },
+            );
+        }
+    }
+
+    /// Remembers the addresses revealed by a successful call of `user`.
+    pub fn observe(
+        &self,
+        user: &str,
+        path: &str,
+        req: &RpcRequest<GenericRpcMethod>,
+        response: &RpcResponse<GenericRpcMethod>,
+    ) {
+        let result = match (&response.result, &response.error) {
+            (Some(result), None) => result,
+            _ => return,
+        };
+        match &*req.method.0 {
+            "getnewaddress" => {
+                if let Some(address) = result.as_str() {
+                    self.remember(user, path, address.to_owned(), false);
+                }
+            }
+            method @ ("listreceivedbyaddress"
+            | "listsinceblock"
+            | "listtransactions"
+            | "listunspent") => {
+                for address in funded(method, result) {
+                    self.remember(user, path, address, true);
+                }
+            }
+            _ => {
+                // the outputs of the send received funds now
+                for address in destinations(req) {
+                    self.remember(user, path, address, true);
+                }
+            }
+        }
+    }
+
+    /// Whether `address` is known to have received funds, asking the wallet it was generated
+    /// by if it isn't yet.
+    async fn reused(&self, state: &State, address: &str) -> bool {
+        let unfunded: Vec<String> = {
+            let addresses = self.addresses.lock().unwrap();
+            let seen = addresses.values().filter_map(|a| a.get(address));
+            if seen.clone().any(|seen| seen.funded) {
+                return true;
+            }
+            seen.map(|seen| seen.path.clone()).collect()
+        };
+        for path in unfunded {
+            let received = state
+                .rpc_client
+                .call_at(
+                    &path,
+                    &RpcRequest {
+                        id: None,
+                        method: GenericRpcMethod("getreceivedbyaddress".to_owned()),
+                        params: vec![address.into(), 0.into()],
+                    },
+                )
+                .await
+                .ok()
+                .and_then(|response| response.result)
+                .and_then(|received| received.as_f64());
+            if received.is_some_and(|r| r > 0.0) {
+                for addresses in self.addresses.lock().unwrap().values_mut() {
+                    if let Some(seen) = addresses.get_mut(address) {
+                        seen.funded = true;
+                    }
+                }
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Warns about or blocks `req` if it sends to an address that already received funds.
+    pub async fn check(
+        &self,
+        state: &State,
+        req: &RpcRequest<GenericRpcMethod>,
+        ctx: &RequestContext,
+    ) -> Result<(), RpcError> {
+        for address in destinations(req) {
+            if !self.reused(state, &address).await {
+                continue;
+            }
+            let message = format!("address {} already received funds", address);
+            if self.mode == Mode::Block {
+                return Err(RpcError {
+                    code: POLICY_VIOLATION_ERROR_CODE,
+                    message: format!("Sending to a reused address is denied: {}", message),
+                    data: Some(json!({ "method": req.method.0, "address": address })),
+                    status: Some(StatusCode::FORBIDDEN),
+                });
+            }
+            warn!(
+                state.logger,
+                "{} called {} reusing {}",
+                ctx.caller(),
+                req.method.0,
+                address
+            );
+            ctx.warn(req, format!("{}, reusing addresses harms privacy", message));
+        }
+        Ok(())
+    }
+}
+impl TenantStore for AddressReuse {
+    fn name(&self) -> &'static str {
+        "address_reuse"
+    }
+    fn inspect(&self, user: &str) -> Value {
+        let addresses = self.addresses.lock().unwrap();
+        let entries: Vec<_> = addresses
+            .get(user)
+            .into_iter()
+            .flatten()
+            .map(|(address, seen)| {
+                json!({
+                    "address": address,
+                    "path": seen.path,
+                    "funded": seen.funded,
+                })
+            })
+            .collect();
+        json!({ "quota": self.quota, "entries": entries })
+    }
+    fn purge(&self, user: &str) -> usize {
+        self.addresses
+            .lock()
+            .unwrap()
+            .remove(user)
+            .map_or(0, |addresses| addresses.len())
+    }
+}
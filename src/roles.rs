@@ -0,0 +1,64 @@
+//! Presets of permissions users can be given with `role` instead of listing methods in
+//! `allowed_calls`.
+
+use crate::categories;
+
+/// Methods outside the wallet that don't change state but shouldn't be available to read-only
+/// users: they write files on the node, change its logging or make it download data.
+const READ_ONLY_EXCLUDED: &[&str] = &[
+    "dumptxoutset",
+    "getblockfrompeer",
+    "importmempool",
+    "loadtxoutset",
+    "logging",
+];
+
+/// Wallet methods that don't change the wallet but reveal keys or sign with them.
+const WATCH_ONLY_EXCLUDED: &[&str] = &[
+    "addmultisigaddress",
+    "createwalletdescriptor",
+    "dumpprivkey",
+    "dumpwallet",
+    "gethdkeys",
+    "listdescriptors",
+    "signmessage",
+    "signrawtransactionwithwallet",
+    "walletdisplayaddress",
+    "walletprocesspsbt",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Methods of bitcoind outside the wallet that don't change the state of the node
+    ReadOnly,
+    /// `readonly` and the wallet methods showing balances, addresses and transactions without
+    /// revealing keys or signing
+    WatchOnly,
+    /// All methods, including those served by the proxy
+    Admin,
+}
+impl Role {
+    pub fn allows(&self, method: &str) -> bool {
+        let category = categories::category(method);
+        let read = || !categories::is_write(method) && !READ_ONLY_EXCLUDED.contains(&method);
+        match self {
+            Role::ReadOnly => category.is_some_and(|c| c != "wallet" && c != "proxy") && read(),
+            Role::WatchOnly => {
+                category.is_some_and(|c| c != "proxy")
+                    && read()
+                    && !WATCH_ONLY_EXCLUDED.contains(&method)
+            }
+            Role::Admin => true,
+        }
+    }
+}
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Role::ReadOnly => "readonly",
+            Role::WatchOnly => "watchonly",
+            Role::Admin => "admin",
+        })
+    }
+}
@@ -7,6 +7,7 @@ use bitcoin::{
 };
 use linear_map::{set::LinearSet, LinearMap};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
 
 use crate::client::RpcMethod;
 use crate::util::{Either, HexBytes};
@@ -88,7 +89,8 @@ impl<'de> Deserialize<'de> for GetBlockHeader {
 #[serde(rename_all = "camelCase")]
 pub struct GetBlockHeaderResult {
     pub hash: bitcoin::BlockHash,
-    pub confirmations: u32,
+    /// -1 if the block is not in the best chain
+    pub confirmations: i32,
     pub height: usize,
     pub version: i32,
     pub version_hex: Option<HexBytes>,
@@ -104,7 +106,7 @@ pub struct GetBlockHeaderResult {
     pub nextblockhash: Option<bitcoin::BlockHash>,
 }
 
-#[derive(Debug, serde::Deserialize, serde::Serialize)]
+#[derive(Debug, serde::Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct GetBlockResult {
     #[serde(flatten)]
@@ -114,6 +116,29 @@ pub struct GetBlockResult {
     pub weight: usize,
     pub tx: Vec<bitcoin::Txid>,
 }
+// Not derived: flattened fields are buffered in a way that loses the numbers of serde_json's
+// arbitrary precision, so the header is read from a `Value` instead.
+impl<'de> Deserialize<'de> for GetBlockResult {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        struct Body {
+            size: usize,
+            strippedsize: Option<usize>,
+            weight: usize,
+            tx: Vec<bitcoin::Txid>,
+        }
+        let value = Value::deserialize(deserializer)?;
+        let header = GetBlockHeaderResult::deserialize(&value).map_err(serde::de::Error::custom)?;
+        let body = Body::deserialize(&value).map_err(serde::de::Error::custom)?;
+        Ok(GetBlockResult {
+            header,
+            size: body.size,
+            strippedsize: body.strippedsize,
+            weight: body.weight,
+            tx: body.tx,
+        })
+    }
+}
 
 #[derive(Debug)]
 pub struct GetPeerInfo;
@@ -0,0 +1,271 @@
+//! Amounts in satoshis for clients that don't want to parse bitcoind's decimal BTC amounts.
+//!
+//! Requests carrying [`SATS_HEADER`] get the BTC amounts in results of the methods below
+//! annotated with their value in satoshis, in a field of the same name suffixed with `_sats`
+//! (`"amount": 0.0001, "amount_sats": 10000`). Amounts in their params may be given as
+//! `{"sat": 10000}`, which is converted to the exact decimal amount before the call is
+//! forwarded.
+
+use serde_json::{Map, Value};
+
+use crate::client::{GenericRpcMethod, RpcError, RpcRequest, RpcResponse, MISC_ERROR_CODE};
+
+/// Requests carrying this header with a value of `1` or `true` are served amounts in satoshis.
+pub const SATS_HEADER: &str = "x-sats";
+
+const SATS_PER_BTC: i64 = 100_000_000;
+
+/// Fields holding BTC amounts in the results of each method: keys separated by dots, `[]`
+/// standing for every element of an array or every value of an object. Fields of other units,
+/// like the amounts in satoshis of `getblockstats` and fee rates, aren't listed.
+const AMOUNTS: &[(&str, &[&str])] = &[
+    ("analyzepsbt", &["fee"]),
+    ("bumpfee", &["origfee", "fee"]),
+    (
+        "decodepsbt",
+        &["fee", "tx.vout[].value", "inputs[].witness_utxo.amount"],
+    ),
+    ("decoderawtransaction", &["vout[].value"]),
+    ("fundrawtransaction", &["fee"]),
+    (
+        "getbalances",
+        &[
+            "mine.trusted",
+            "mine.untrusted_pending",
+            "mine.immature",
+            "mine.used",
+            "watchonly.trusted",
+            "watchonly.untrusted_pending",
+            "watchonly.immature",
+        ],
+    ),
+    (
+        "getblock",
+        &["tx[].fee", "tx[].vin[].prevout.value", "tx[].vout[].value"],
+    ),
+    (
+        "getmempoolancestors",
+        &[
+            "[].fees.base",
+            "[].fees.modified",
+            "[].fees.ancestor",
+            "[].fees.descendant",
+        ],
+    ),
+    (
+        "getmempooldescendants",
+        &[
+            "[].fees.base",
+            "[].fees.modified",
+            "[].fees.ancestor",
+            "[].fees.descendant",
+        ],
+    ),
+    (
+        "getmempoolentry",
+        &[
+            "fees.base",
+            "fees.modified",
+            "fees.ancestor",
+            "fees.descendant",
+        ],
+    ),
+    ("getmempoolinfo", &["total_fee"]),
+    (
+        "getrawmempool",
+        &[
+            "[].fees.base",
+            "[].fees.modified",
+            "[].fees.ancestor",
+            "[].fees.descendant",
+        ],
+    ),
+    (
+        "getrawtransaction",
+        &["fee", "vin[].prevout.value", "vout[].value"],
+    ),
+    (
+        "gettransaction",
+        &["amount", "fee", "details[].amount", "details[].fee"],
+    ),
+    ("gettxout", &["value"]),
+    (
+        "gettxoutsetinfo",
+        &["total_amount", "total_unspendable_amount"],
+    ),
+    (
+        "getwalletinfo",
+        &["balance", "unconfirmed_balance", "immature_balance"],
+    ),
+    ("listreceivedbyaddress", &["[].amount"]),
+    ("listreceivedbylabel", &["[].amount"]),
+    (
+        "listsinceblock",
+        &[
+            "transactions[].amount",
+            "transactions[].fee",
+            "removed[].amount",
+            "removed[].fee",
+        ],
+    ),
+    ("listtransactions", &["[].amount", "[].fee"]),
+    ("listunspent", &["[].amount"]),
+    ("psbtbumpfee", &["origfee", "fee"]),
+    ("scantxoutset", &["total_amount", "unspents[].amount"]),
+    ("testmempoolaccept", &["[].fees.base"]),
+    ("walletcreatefundedpsbt", &["fee"]),
+];
+
+/// `btc` in satoshis, if it is a valid amount, read from its decimal digits so that it is exact.
+pub(crate) fn to_sats(btc: &Value) -> Option<i64> {
+    let btc = match btc {
+        Value::Number(btc) => btc.to_string(),
+        _ => return None,
+    };
+    let (negative, btc) = match btc.strip_prefix('-') {
+        Some(btc) => (true, btc),
+        None => (false, btc.as_str()),
+    };
+    // numbers made from floats may have an exponent, bitcoind doesn't write any
+    let (decimal, exponent) = match btc.split_once(['e', 'E']) {
+        Some((decimal, exponent)) => (decimal, exponent.parse::<i32>().ok()?),
+        None => (btc, 0),
+    };
+    let (whole, fraction) = decimal.split_once('.').unwrap_or((decimal, ""));
+    let digits = format!("{}{}", whole, fraction);
+    if whole.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    // the digits are an integer of this many satoshis times 10^shift
+    let shift = exponent
+        .checked_sub(fraction.len() as i32)?
+        .checked_add(8)?;
+    let digits = digits.trim_start_matches('0');
+    let sats = if shift >= 0 {
+        // more digits than an i64 has
+        if shift > 18 {
+            return None;
+        }
+        format!("{}{}", digits, "0".repeat(shift as usize))
+    } else {
+        let cut = digits.len().saturating_sub(shift.unsigned_abs() as usize);
+        let (sats, rest) = digits.split_at(cut);
+        // fractions of a satoshi
+        if rest.bytes().any(|b| b != b'0') {
+            return None;
+        }
+        sats.to_owned()
+    };
+    let sats = if sats.is_empty() {
+        0
+    } else {
+        sats.parse::<i64>().ok()?
+    };
+    Some(if negative { -sats } else { sats })
+}
+
+/// `sats` as a decimal amount in BTC, given as a string since bitcoind accepts those too.
+fn to_btc(sats: i64) -> String {
+    let sign = if sats < 0 { "-" } else { "" };
+    let sats = sats.unsigned_abs();
+    format!(
+        "{}{}.{:08}",
+        sign,
+        sats / SATS_PER_BTC as u64,
+        sats % SATS_PER_BTC as u64
+    )
+}
+
+/// Adds `<key>_sats` next to the amount at `path` in `value`, wherever it exists.
+fn annotate_path(value: &mut Value, path: &[&str]) {
+    let (segment, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    let (key, each) = match segment.strip_suffix("[]") {
+        Some(key) => (key, true),
+        None => (*segment, false),
+    };
+    let value = if key.is_empty() {
+        value
+    } else if !each && rest.is_empty() {
+        if let Value::Object(fields) = value {
+            annotate_field(fields, key);
+        }
+        return;
+    } else {
+        match value.get_mut(key) {
+            Some(value) => value,
+            None => return,
+        }
+    };
+    match (each, value) {
+        (true, Value::Array(elements)) => elements
+            .iter_mut()
+            .for_each(|element| annotate_path(element, rest)),
+        (true, Value::Object(fields)) => fields
+            .values_mut()
+            .for_each(|field| annotate_path(field, rest)),
+        (true, _) => (),
+        (false, value) => annotate_path(value, rest),
+    }
+}
+
+fn annotate_field(fields: &mut Map<String, Value>, key: &str) {
+    if let Some(sats) = fields.get(key).and_then(to_sats) {
+        fields.insert(format!("{}_sats", key), sats.into());
+    }
+}
+
+/// Annotates the BTC amounts in the result of a call of `method` with their value in satoshis.
+pub fn annotate(method: &str, response: &mut RpcResponse<GenericRpcMethod>) {
+    let paths = match AMOUNTS.iter().find(|(m, _)| *m == method) {
+        Some((_, paths)) => paths,
+        None => return,
+    };
+    if let Some(result) = &mut response.result {
+        for path in paths.iter() {
+            annotate_path(result, &path.split('.').collect::<Vec<_>>());
+        }
+    }
+}
+
+/// Replaces amounts given as `{"sat": n}` anywhere in `value`, returning whether any were.
+fn convert(value: &mut Value) -> Result<bool, RpcError> {
+    match value {
+        Value::Object(fields) if fields.len() == 1 && fields.contains_key("sat") => {
+            let sats = fields["sat"].as_i64().ok_or_else(|| RpcError {
+                code: MISC_ERROR_CODE,
+                message: "Amounts in satoshis have to be integers".to_owned(),
+                data: None,
+                status: None,
+            })?;
+            *value = to_btc(sats).into();
+            Ok(true)
+        }
+        Value::Object(fields) => fields
+            .values_mut()
+            .try_fold(false, |any, field| Ok(convert(field)? || any)),
+        Value::Array(elements) => elements
+            .iter_mut()
+            .try_fold(false, |any, element| Ok(convert(element)? || any)),
+        _ => Ok(false),
+    }
+}
+
+/// The call with amounts given in satoshis converted to BTC, `None` if it has none.
+pub fn convert_request(
+    req: &RpcRequest<GenericRpcMethod>,
+) -> Result<Option<RpcRequest<GenericRpcMethod>>, RpcError> {
+    let mut params = req.params.clone();
+    let mut converted = false;
+    for param in params.iter_mut() {
+        converted |= convert(param)?;
+    }
+    Ok(Some(RpcRequest {
+        id: req.id.clone(),
+        method: GenericRpcMethod(req.method.0.clone()),
+        params,
+    })
+    .filter(|_| converted))
+}
@@ -0,0 +1,251 @@
+//! Decides where to look for a pruned block, in which order and for how long: the stages of
+//! `fetch_order`, the peers raced for the `p2p` stage, their scores, the fetch budget and the
+//! fetch quotas of users.
+//!
+//! Where blocks actually come from is up to [`Sources`], which is implemented for the state of
+//! the proxy and by the scripted network of a [`Simulation`](crate::simulation::Simulation).
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Error;
+use bitcoin::{hash_types::BlockHash, network::address::Address, Block};
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::StreamExt;
+use slog::Logger;
+use tokio::time::Instant;
+
+use crate::address_book::AddressBook;
+use crate::client::RpcError;
+use crate::fetch_blocks::{peer_name, FetchPolicy, FetchStage, Fetcher};
+use crate::quota::{QuotaUsage, Usage};
+
+/// The places blocks are fetched from.
+pub trait Sources: Send + Sync {
+    /// A peer blocks can be fetched from directly.
+    type Peer: Send;
+
+    /// Where `peer` is, which its score is kept by.
+    fn peer_addr(peer: &Self::Peer) -> Address;
+    /// Fails unless bitcoind knows the header of the block and, unless `allow_forks`, the block
+    /// is part of its best chain.
+    fn check_best_chain(
+        &self,
+        hash: BlockHash,
+        allow_forks: bool,
+    ) -> BoxFuture<'_, Result<(), RpcError>>;
+    /// Looks for the block at `stage`, `None` if it isn't there. Peers failing to deliver it are
+    /// added to `failures`. `FetchStage::P2p` is left to the scheduler.
+    fn fetch<'a>(
+        &'a self,
+        stage: FetchStage,
+        hash: BlockHash,
+        failures: &'a Mutex<Vec<PeerFailure>>,
+    ) -> BoxFuture<'a, Result<Option<Block>, RpcError>>;
+    /// The peers of the `p2p` stage, in no particular order.
+    fn peers(&self) -> BoxFuture<'_, Result<Vec<Self::Peer>, Error>>;
+    /// Fetches the block from `peer`, checked against `hash`.
+    fn fetch_from_peer(
+        &self,
+        peer: Self::Peer,
+        hash: BlockHash,
+    ) -> BoxFuture<'_, Result<Block, Error>>;
+    /// Keeps a block fetched from elsewhere than bitcoind and the cache.
+    fn cache(&self, block: &Block);
+}
+
+/// A place a block was looked for in vain.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Attempt {
+    pub stage: String,
+    /// `unavailable`, `timeout`, the error or why the stage was skipped
+    pub result: String,
+    pub elapsed_ms: u64,
+}
+
+/// A peer that failed to deliver a block.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct PeerFailure {
+    pub peer: String,
+    pub reason: String,
+}
+
+/// Why a block wasn't found anywhere.
+#[derive(Debug, Clone)]
+pub struct Missing {
+    pub attempts: Vec<Attempt>,
+    pub peers: Vec<PeerFailure>,
+    /// Whether the scheduler gave up because the fetch budget ran out
+    pub budget_exhausted: bool,
+}
+
+#[derive(Debug)]
+pub enum Outcome {
+    Fetched(Block),
+    Missing(Missing),
+}
+
+pub struct Scheduler<'a, S> {
+    pub sources: &'a S,
+    pub policy: &'a FetchPolicy,
+    /// Peers are asked best scoring first and scored by the outcome
+    pub address_book: &'a AddressBook,
+    /// Counts blocks fetched from elsewhere than bitcoind and the cache against `fetch_quota`
+    pub fetch_usage: &'a QuotaUsage,
+    /// Time spent looking for a block at most
+    pub budget: Option<Duration>,
+    /// Peers asked for a block at once, all of them if `None`
+    pub max_peer_concurrency: Option<usize>,
+    pub logger: &'a Logger,
+}
+impl<'a, S: Sources> Scheduler<'a, S> {
+    /// Looks for the block in the places configured for `method`, in order. Checks the block
+    /// against the best chain before looking elsewhere than bitcoind, unless `best_chain_check`
+    /// is `None`. Gives up once the budget or the fetch quota of the user is spent.
+    pub async fn fetch(
+        &self,
+        method: &str,
+        hash: BlockHash,
+        best_chain_check: Option<bool>,
+        fetcher: Fetcher<'_>,
+    ) -> Result<Outcome, RpcError> {
+        let mut checked = best_chain_check.is_none();
+        let deadline = self.budget.map(|budget| Instant::now() + budget);
+        let mut attempts = Vec::new();
+        let failures = Mutex::new(Vec::new());
+        let mut budget_exhausted = false;
+        for step in self.policy.steps(method) {
+            let remote = step.stage != FetchStage::Backend && step.stage != FetchStage::Cache;
+            if remote {
+                if let Err(e) = self.fetch_usage.check(fetcher.user_name, fetcher.quota) {
+                    debug!(
+                        self.logger,
+                        "Not fetching block {} for {}: {}", hash, fetcher.user_name, e.message
+                    );
+                    attempts.push(Attempt {
+                        stage: step.stage.to_string(),
+                        result: e.message,
+                        elapsed_ms: 0,
+                    });
+                    break;
+                }
+            }
+            if step.stage != FetchStage::Backend && !checked {
+                self.sources
+                    .check_best_chain(hash, best_chain_check.unwrap_or_default())
+                    .await?;
+                checked = true;
+            }
+            let remaining = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(remaining) if remaining > Duration::from_secs(0) => Some(remaining),
+                    _ => {
+                        budget_exhausted = true;
+                        break;
+                    }
+                },
+                None => None,
+            };
+            let timeout = match (step.timeout, remaining) {
+                (Some(timeout), Some(remaining)) => Some(timeout.min(remaining)),
+                (timeout, remaining) => timeout.or(remaining),
+            };
+            let start = Instant::now();
+            let attempt = |result: String| Attempt {
+                stage: step.stage.to_string(),
+                result,
+                elapsed_ms: start.elapsed().as_millis() as u64,
+            };
+            let fetch = match step.stage {
+                FetchStage::P2p => self.fetch_from_peers(hash, &failures).boxed(),
+                stage => self.sources.fetch(stage, hash, &failures),
+            };
+            let res = match timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, fetch).await {
+                    Ok(res) => res,
+                    Err(_) => {
+                        warn!(
+                            self.logger,
+                            "Timed out fetching block {} from {}", hash, step.stage
+                        );
+                        attempts.push(attempt("timeout".to_owned()));
+                        continue;
+                    }
+                },
+                None => fetch.await,
+            };
+            match res {
+                Ok(Some(block)) => {
+                    if remote {
+                        self.sources.cache(&block);
+                        self.fetch_usage.add(
+                            fetcher.user_name,
+                            fetcher.quota,
+                            Usage {
+                                requests: 1,
+                                bytes: block.get_size() as u64,
+                            },
+                        );
+                    }
+                    return Ok(Outcome::Fetched(block));
+                }
+                Ok(None) => {
+                    debug!(
+                        self.logger,
+                        "Block {} not available from {}", hash, step.stage
+                    );
+                    attempts.push(attempt("unavailable".to_owned()));
+                }
+                // e.g. the block doesn't exist at all, no point in asking anyone else
+                Err(e) if step.stage == FetchStage::Backend => return Err(e),
+                Err(e) => {
+                    warn!(
+                        self.logger,
+                        "Error fetching block {} from {}: {}", hash, step.stage, e.message
+                    );
+                    attempts.push(attempt(e.message));
+                }
+            }
+        }
+        error!(self.logger, "Could not fetch block {}.", hash);
+        Ok(Outcome::Missing(Missing {
+            attempts,
+            peers: failures.into_inner().unwrap(),
+            budget_exhausted,
+        }))
+    }
+
+    /// Races the peers, best scoring first and `max_peer_concurrency` at a time, for the block.
+    async fn fetch_from_peers(
+        &self,
+        hash: BlockHash,
+        failures: &Mutex<Vec<PeerFailure>>,
+    ) -> Result<Option<Block>, RpcError> {
+        let mut peers = self.sources.peers().await?;
+        self.address_book
+            .sort_by_score(&mut peers, |peer| S::peer_addr(peer));
+        let concurrency = self.max_peer_concurrency.unwrap_or(peers.len()).max(1);
+        // yields a block before asking the next peer
+        let mut results = futures::stream::iter(peers)
+            .map(|peer| async move {
+                let addr = S::peer_addr(&peer);
+                let res = self.sources.fetch_from_peer(peer, hash).await;
+                self.address_book.record(&addr, res.is_ok());
+                (addr, res)
+            })
+            .buffer_unordered(concurrency);
+        while let Some((addr, res)) = results.next().await {
+            match res {
+                Ok(block) => return Ok(Some(block)),
+                Err(e) => {
+                    warn!(self.logger, "Error fetching block from peer: {}", e);
+                    failures.lock().unwrap().push(PeerFailure {
+                        peer: peer_name(&addr),
+                        reason: format!("{:#}", e),
+                    });
+                }
+            }
+        }
+        Ok(None)
+    }
+}
@@ -0,0 +1,292 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error};
+use hyper::{body::Bytes, header::CONTENT_LENGTH, Body, Response, StatusCode};
+use serde_json::value::{to_raw_value, RawValue};
+use serde_json::{json, Value};
+use tokio::stream::StreamExt;
+
+use crate::client::{GenericRpcMethod, RpcRequest, SingleOrBatchRpcRequest, MISC_ERROR_CODE};
+use crate::metrics::Metrics;
+use crate::state::State;
+
+/// What to do with responses that don't match the schema expected for their method.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ValidationMode {
+    /// Responses are not validated at all
+    #[default]
+    Off,
+    /// Divergences are logged and counted, the response is passed on
+    Log,
+    /// Divergent responses are replaced with an error
+    Reject,
+}
+impl FromStr for ValidationMode {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(ValidationMode::Off),
+            "log" => Ok(ValidationMode::Log),
+            "reject" => Ok(ValidationMode::Reject),
+            _ => Err(anyhow!(
+                "invalid validation mode {:?}, expected off, log or reject",
+                s
+            )),
+        }
+    }
+}
+
+/// Expected shape of a JSON value. Objects may contain more fields than listed.
+#[derive(Debug)]
+pub enum Schema {
+    Any,
+    Null,
+    Bool,
+    Integer,
+    Number,
+    String,
+    Hex,
+    /// 32 bytes encoded as hex, e.g. a block hash or txid
+    Hash,
+    Array(Box<Schema>),
+    Object(Vec<(&'static str, Schema)>),
+    OneOf(Vec<Schema>),
+}
+impl Schema {
+    fn name(&self) -> &'static str {
+        match self {
+            Schema::Any => "anything",
+            Schema::Null => "null",
+            Schema::Bool => "bool",
+            Schema::Integer => "integer",
+            Schema::Number => "number",
+            Schema::String => "string",
+            Schema::Hex => "hex string",
+            Schema::Hash => "32 byte hex string",
+            Schema::Array(_) => "array",
+            Schema::Object(_) => "object",
+            Schema::OneOf(_) => "one of several types",
+        }
+    }
+
+    fn matches_type(&self, value: &Value) -> bool {
+        let is_hex =
+            |s: &str| s.len().is_multiple_of(2) && s.bytes().all(|b| b.is_ascii_hexdigit());
+        match (self, value) {
+            (Schema::Any, _) => true,
+            (Schema::Null, Value::Null) => true,
+            (Schema::Bool, Value::Bool(_)) => true,
+            (Schema::Integer, Value::Number(n)) => n.is_i64() || n.is_u64(),
+            (Schema::Number, Value::Number(_)) => true,
+            (Schema::String, Value::String(_)) => true,
+            (Schema::Hex, Value::String(s)) => is_hex(s),
+            (Schema::Hash, Value::String(s)) => s.len() == 64 && is_hex(s),
+            (Schema::Array(_), Value::Array(_)) => true,
+            (Schema::Object(_), Value::Object(_)) => true,
+            (Schema::OneOf(schemas), _) => schemas.iter().any(|s| s.check(value, "").is_empty()),
+            _ => false,
+        }
+    }
+
+    /// Describes every place `value` diverges from the schema, `path` naming the value itself.
+    pub fn check(&self, value: &Value, path: &str) -> Vec<String> {
+        if !self.matches_type(value) {
+            return vec![format!("{}: expected {}, got {}", path, self.name(), value)];
+        }
+        match (self, value) {
+            (Schema::Array(item), Value::Array(items)) => items
+                .iter()
+                .enumerate()
+                .flat_map(|(i, v)| item.check(v, &format!("{}[{}]", path, i)))
+                .collect(),
+            (Schema::Object(fields), Value::Object(obj)) => fields
+                .iter()
+                .flat_map(|(name, schema)| match obj.get(*name) {
+                    Some(v) => schema.check(v, &format!("{}.{}", path, name)),
+                    None => vec![format!("{}.{}: missing", path, name)],
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+fn block_header_fields() -> Vec<(&'static str, Schema)> {
+    vec![
+        ("hash", Schema::Hash),
+        ("confirmations", Schema::Integer),
+        ("height", Schema::Integer),
+        ("version", Schema::Integer),
+        ("merkleroot", Schema::Hash),
+        ("time", Schema::Integer),
+        ("nonce", Schema::Integer),
+        ("bits", Schema::Hex),
+    ]
+}
+
+fn transaction() -> Schema {
+    Schema::Object(vec![
+        ("txid", Schema::Hash),
+        ("hash", Schema::Hash),
+        ("vin", Schema::Array(Box::new(Schema::Any))),
+        ("vout", Schema::Array(Box::new(Schema::Any))),
+    ])
+}
+
+/// The schema of the result of `req`, if the method is known.
+pub fn expected(req: &RpcRequest<GenericRpcMethod>) -> Option<Schema> {
+    let param = |i: usize| req.params.get(i).filter(|p| !p.is_null());
+    // bitcoind accepts both booleans and numbers for verbosity flags
+    let verbosity = |i: usize, default: u64| match param(i) {
+        Some(Value::Bool(b)) => *b as u64,
+        Some(Value::Number(n)) => n.as_u64().unwrap_or(default),
+        _ => default,
+    };
+    Some(match &*req.method.0 {
+        "getblockcount" | "getconnectioncount" => Schema::Integer,
+        "getbestblockhash" | "getblockhash" | "sendrawtransaction" | "sendtoaddress" => {
+            Schema::Hash
+        }
+        "getdifficulty" | "getbalance" => Schema::Number,
+        "getnewaddress" | "getrawchangeaddress" => Schema::String,
+        "getblockchaininfo" => Schema::Object(vec![
+            ("chain", Schema::String),
+            ("blocks", Schema::Integer),
+            ("headers", Schema::Integer),
+            ("bestblockhash", Schema::Hash),
+            ("difficulty", Schema::Number),
+            ("mediantime", Schema::Integer),
+            ("verificationprogress", Schema::Number),
+            ("pruned", Schema::Bool),
+        ]),
+        "getnetworkinfo" => Schema::Object(vec![
+            ("version", Schema::Integer),
+            ("subversion", Schema::String),
+            ("protocolversion", Schema::Integer),
+            ("connections", Schema::Integer),
+        ]),
+        "getmempoolinfo" => {
+            Schema::Object(vec![("size", Schema::Integer), ("bytes", Schema::Integer)])
+        }
+        "estimatesmartfee" => Schema::Object(vec![("blocks", Schema::Integer)]),
+        "validateaddress" => Schema::Object(vec![("isvalid", Schema::Bool)]),
+        "getblockheader" if verbosity(1, 1) == 0 => Schema::Hex,
+        "getblockheader" => Schema::Object(block_header_fields()),
+        "getblock" => match verbosity(1, 1) {
+            0 => Schema::Hex,
+            1 => {
+                let mut fields = block_header_fields();
+                fields.push(("tx", Schema::Array(Box::new(Schema::Hash))));
+                Schema::Object(fields)
+            }
+            _ => {
+                let mut fields = block_header_fields();
+                fields.push(("tx", Schema::Array(Box::new(transaction()))));
+                Schema::Object(fields)
+            }
+        },
+        "getrawtransaction" if verbosity(1, 0) == 0 => Schema::Hex,
+        "getrawtransaction" => {
+            let mut tx = transaction();
+            if let Schema::Object(fields) = &mut tx {
+                fields.push(("hex", Schema::Hex));
+            }
+            tx
+        }
+        "getrawmempool" if verbosity(0, 0) == 0 => Schema::Array(Box::new(Schema::Hash)),
+        "gettxout" => Schema::OneOf(vec![
+            Schema::Null,
+            Schema::Object(vec![
+                ("bestblock", Schema::Hash),
+                ("confirmations", Schema::Integer),
+                ("value", Schema::Number),
+            ]),
+        ]),
+        _ => return None,
+    })
+}
+
+/// Checks the results in `response` against the schemas expected for the methods of `req`,
+/// logging divergences and, in reject mode, replacing the divergent results with errors.
+///
+/// Only the responses to methods with a schema are parsed, others are passed through as is.
+pub async fn validate(
+    state: &State,
+    req: &SingleOrBatchRpcRequest,
+    response: Response<Body>,
+) -> Result<Response<Body>, Error> {
+    if state.validate_responses == ValidationMode::Off || !response.status().is_success() {
+        return Ok(response);
+    }
+    let reqs: Vec<_> = match req {
+        SingleOrBatchRpcRequest::Single(req) => vec![req],
+        SingleOrBatchRpcRequest::Batch(reqs) => reqs.iter().collect(),
+    };
+    let schemas: Vec<_> = reqs.iter().map(|req| expected(req)).collect();
+    if schemas.iter().all(Option::is_none) {
+        return Ok(response);
+    }
+    let (mut parts, body) = response.into_parts();
+    let body = body.collect::<Result<Bytes, _>>().await?;
+    let raw: Box<RawValue> = match serde_json::from_slice(&body) {
+        Ok(raw) => raw,
+        Err(e) => {
+            warn!(state.logger, "Response is not valid JSON: {}", e);
+            return Ok(Response::from_parts(parts, body.into()));
+        }
+    };
+    let mut responses = match req {
+        SingleOrBatchRpcRequest::Single(_) => vec![raw],
+        SingleOrBatchRpcRequest::Batch(_) => serde_json::from_str(raw.get()).unwrap_or_default(),
+    };
+    let mut rejected = false;
+    for ((req, schema), raw) in reqs.into_iter().zip(schemas).zip(responses.iter_mut()) {
+        let schema = match schema {
+            Some(schema) => schema,
+            None => continue,
+        };
+        let res: Value = match serde_json::from_str(raw.get()) {
+            Ok(res) => res,
+            Err(_) => continue,
+        };
+        if res.get("error").is_some_and(|e| !e.is_null()) {
+            continue;
+        }
+        let divergences = match res.get("result") {
+            Some(result) => schema.check(result, "result"),
+            None => continue,
+        };
+        if divergences.is_empty() {
+            continue;
+        }
+        Metrics::inc(&state.metrics.schema_divergences);
+        let divergences = divergences.join("; ");
+        warn!(
+            state.logger,
+            "{} returned an unexpected response: {}", req.method.0, divergences
+        );
+        if state.validate_responses == ValidationMode::Reject {
+            *raw = to_raw_value(&json!({
+                "id": res.get("id").cloned().unwrap_or(Value::Null),
+                "result": null,
+                "error": {
+                    "code": MISC_ERROR_CODE,
+                    "message": format!("Invalid response from bitcoind: {}", divergences),
+                },
+            }))?;
+            rejected = true;
+        }
+    }
+    if !rejected {
+        return Ok(Response::from_parts(parts, body.into()));
+    }
+    let body = match req {
+        SingleOrBatchRpcRequest::Single(_) => {
+            parts.status = StatusCode::BAD_GATEWAY;
+            responses.swap_remove(0).get().as_bytes().to_vec()
+        }
+        SingleOrBatchRpcRequest::Batch(_) => serde_json::to_vec(&responses)?,
+    };
+    parts.headers.insert(CONTENT_LENGTH, body.len().into());
+    Ok(Response::from_parts(parts, body.into()))
+}
@@ -0,0 +1,31 @@
+//! Secrets kept outside of the config file, in files (e.g. Docker or systemd credentials) or
+//! environment variables.
+
+use std::path::Path;
+
+use anyhow::{anyhow, Error};
+
+/// Reads a secret from a file, without the trailing newline editors like to add.
+pub fn read_file(path: &Path) -> Result<String, Error> {
+    let secret = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("failed to read secret from {}: {}", path.display(), e))?;
+    Ok(secret.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+/// Reads a secret from an environment variable.
+pub fn read_env(name: &str) -> Result<String, Error> {
+    std::env::var(name).map_err(|e| anyhow!("failed to read secret from ${}: {}", name, e))
+}
+
+/// The secret of the setting `name`, given either in the config or in a file.
+pub fn resolve(
+    name: &str,
+    value: Option<String>,
+    file: Option<&Path>,
+) -> Result<Option<String>, Error> {
+    match (value, file) {
+        (Some(_), Some(_)) => Err(anyhow!("{} and {}_file can't be set together", name, name)),
+        (value, None) => Ok(value),
+        (None, Some(path)) => read_file(path).map(Some),
+    }
+}
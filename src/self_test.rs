@@ -0,0 +1,115 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Error};
+use serde_json::Value;
+
+use crate::client::{GenericRpcMethod, RpcRequest};
+use crate::listen::Address;
+use crate::state::State;
+
+/// Outcome of a single check of [`self_test`].
+#[derive(Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub result: Result<String, Error>,
+}
+impl std::fmt::Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.result {
+            Ok(details) => write!(f, "PASS {}: {}", self.name, details),
+            Err(e) => write!(f, "FAIL {}: {:#}", self.name, e),
+        }
+    }
+}
+
+async fn check_bitcoind(state: &State) -> Result<String, Error> {
+    let info = state
+        .rpc_client
+        .call(&RpcRequest {
+            id: None,
+            method: GenericRpcMethod("getblockchaininfo".to_owned()),
+            params: Vec::new(),
+        })
+        .await?
+        .into_result()?;
+    Ok(format!(
+        "chain {}, {} blocks",
+        info.get("chain").and_then(Value::as_str).unwrap_or("?"),
+        info.get("blocks").unwrap_or(&Value::Null)
+    ))
+}
+
+#[cfg(feature = "tor")]
+async fn check_tor(proxy: std::net::SocketAddr) -> Result<String, Error> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut stream = tokio::net::TcpStream::connect(proxy).await?;
+    // SOCKS5 greeting offering no authentication
+    stream.write_all(&[5, 1, 0]).await?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply != [5, 0] {
+        return Err(anyhow::anyhow!("unexpected SOCKS5 reply {:?}", reply));
+    }
+    Ok(format!("SOCKS5 proxy at {} is reachable", proxy))
+}
+
+#[cfg(feature = "peer-fetch")]
+async fn check_p2p(state: Arc<State>) -> Result<String, Error> {
+    let peers = crate::fetch_blocks::Peers::updated(&state.rpc_client).await?;
+    let mut last_error = anyhow::anyhow!("bitcoind has no outbound full node peers");
+    for mut peer in peers.handles::<Vec<_>>().into_iter().take(3) {
+        let addr = peer
+            .addr()
+            .socket_addr()
+            .map_or_else(|_| "an onion peer".to_owned(), |a| a.to_string());
+        match peer.connect(state.clone()).await {
+            Ok(_) => return Ok(format!("handshake with {} succeeded", addr)),
+            Err(e) => last_error = e.context(format!("handshake with {}", addr)),
+        }
+    }
+    Err(last_error)
+}
+
+fn check_listener(state: &State) -> Result<String, Error> {
+    let mut available = Vec::new();
+    for listen in &state.listen {
+        match &listen.address {
+            Address::Tcp(addr) => drop(std::net::TcpListener::bind(addr)?),
+            // binding would replace a socket nobody listens on
+            #[cfg(unix)]
+            Address::Unix(path) => {
+                if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                    return Err(anyhow!("{} is in use", listen.address));
+                }
+            }
+        }
+        available.push(listen.address.to_string());
+    }
+    Ok(format!("{} available", available.join(", ")))
+}
+
+/// Exercises everything the proxy depends on, for packagers and support scripts.
+pub async fn self_test(state: Arc<State>) -> Vec<Check> {
+    let mut checks = vec![Check {
+        name: "bitcoind",
+        result: check_bitcoind(&state).await,
+    }];
+    #[cfg(feature = "tor")]
+    if let Some(tor) = &state.tor {
+        checks.push(Check {
+            name: "tor",
+            result: check_tor(tor.proxy).await,
+        });
+    }
+    #[cfg(feature = "peer-fetch")]
+    checks.push(Check {
+        name: "p2p",
+        result: check_p2p(state.clone()).await,
+    });
+    checks.push(Check {
+        name: "listener",
+        result: check_listener(&state),
+    });
+    checks
+}
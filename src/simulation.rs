@@ -0,0 +1,363 @@
+//! A scripted network for the fetch scheduler: peers and other sources with fixed latencies and
+//! behaviors, and bitcoind with a fixed set of blocks. Nothing is sent over the network.
+//!
+//! Run on a runtime with its clock paused (`tokio::time::pause`, with tokio's `test-util`
+//! feature), a simulation takes no real time and plays out the same way every time, so that
+//! properties of the scheduler can be checked against many generated scripts. Run it in a spawned
+//! task: the paused clock may advance before the future the runtime blocks on is polled again.
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+use bitcoin::{
+    blockdata::constants::genesis_block,
+    hash_types::BlockHash,
+    network::{address::Address, constants::ServiceFlags},
+    Block, Network,
+};
+use futures::future::{BoxFuture, FutureExt};
+use tokio::time::Instant;
+
+use crate::client::{RpcError, MISC_ERROR_CODE};
+use crate::fetch_blocks::{peer_name, FetchStage};
+use crate::scheduler::{PeerFailure, Sources};
+
+/// A distinct block for every `n`, to script with.
+pub fn block(n: u32) -> Block {
+    let mut block = genesis_block(Network::Regtest);
+    block.header.nonce = n;
+    block
+}
+
+/// How a simulated peer answers requests for blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Behavior {
+    /// Delivers every block of the chain
+    Honest,
+    /// Says it doesn't have the block
+    Missing,
+    /// Never answers, until the request times out
+    Stalling,
+    /// Fails the first this many requests, then delivers
+    Flaky(usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct SimPeer {
+    pub addr: Address,
+    /// How long the peer takes to answer
+    pub latency: Duration,
+    pub behavior: Behavior,
+}
+impl SimPeer {
+    /// The `n`th peer of a simulation.
+    pub fn new(n: u16, latency: Duration, behavior: Behavior) -> Self {
+        let addr = SocketAddr::from(([10, 0, (n >> 8) as u8, n as u8], 8333));
+        SimPeer {
+            addr: Address::new(&addr, ServiceFlags::NETWORK),
+            latency,
+            behavior,
+        }
+    }
+}
+
+/// A source other than peers: the blocks it has and how long it takes to answer.
+#[derive(Debug, Clone, Default)]
+struct SimStage {
+    latency: Duration,
+    blocks: HashSet<BlockHash>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EventKind {
+    Requested,
+    Delivered,
+    Failed(String),
+}
+
+/// Something that happened to a request, `at` a time since the simulation started.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub at: Duration,
+    /// The peer (as `host:port`) or stage asked
+    pub source: String,
+    pub kind: EventKind,
+}
+
+/// Implements `Sources` with scripted behavior, recording every request as an `Event`.
+#[derive(Debug)]
+pub struct Simulation {
+    chain: HashMap<BlockHash, Block>,
+    forks: HashSet<BlockHash>,
+    peers: Vec<SimPeer>,
+    stages: Mutex<HashMap<FetchStage, SimStage>>,
+    /// How long the proxy waits for a peer
+    peer_timeout: Duration,
+    started: Instant,
+    events: Mutex<Vec<Event>>,
+    requests: Mutex<HashMap<String, usize>>,
+}
+impl Simulation {
+    /// A simulation of the blocks of `chain`, which no source has yet.
+    pub fn new(chain: impl IntoIterator<Item = Block>) -> Self {
+        Simulation {
+            chain: chain.into_iter().map(|b| (b.block_hash(), b)).collect(),
+            forks: HashSet::new(),
+            peers: Vec::new(),
+            stages: Mutex::new(HashMap::new()),
+            peer_timeout: Duration::from_secs(30),
+            started: Instant::now(),
+            events: Mutex::new(Vec::new()),
+            requests: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_peer(mut self, peer: SimPeer) -> Self {
+        self.peers.push(peer);
+        self
+    }
+
+    /// Makes `stage` answer after `latency`, with `blocks` if asked for them. Blocks fetched
+    /// elsewhere are added to the cache stage.
+    pub fn with_stage(
+        self,
+        stage: FetchStage,
+        latency: Duration,
+        blocks: impl IntoIterator<Item = BlockHash>,
+    ) -> Self {
+        self.stages.lock().unwrap().insert(
+            stage,
+            SimStage {
+                latency,
+                blocks: blocks.into_iter().collect(),
+            },
+        );
+        self
+    }
+
+    /// Takes the block `hash` out of the best chain of bitcoind.
+    pub fn with_fork(mut self, hash: BlockHash) -> Self {
+        self.forks.insert(hash);
+        self
+    }
+
+    pub fn with_peer_timeout(mut self, timeout: Duration) -> Self {
+        self.peer_timeout = timeout;
+        self
+    }
+
+    /// Addresses of the peers, for the address book to observe.
+    pub fn addrs(&self) -> Vec<Address> {
+        self.peers.iter().map(|p| p.addr.clone()).collect()
+    }
+
+    /// Everything that happened so far, in order.
+    pub fn events(&self) -> Vec<Event> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// How many times `source` was asked for a block.
+    pub fn requests(&self, source: &str) -> usize {
+        self.requests
+            .lock()
+            .unwrap()
+            .get(source)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Most peers waited for at the same time.
+    pub fn max_concurrent_peers(&self) -> usize {
+        let peers: HashSet<String> = self.peers.iter().map(|p| peer_name(&p.addr)).collect();
+        let mut current = 0usize;
+        let mut max = 0;
+        for event in self.events.lock().unwrap().iter() {
+            if !peers.contains(&event.source) {
+                continue;
+            }
+            match event.kind {
+                EventKind::Requested => {
+                    current += 1;
+                    max = max.max(current);
+                }
+                _ => current = current.saturating_sub(1),
+            }
+        }
+        max
+    }
+
+    fn record(&self, source: &str, kind: EventKind) {
+        if kind == EventKind::Requested {
+            *self
+                .requests
+                .lock()
+                .unwrap()
+                .entry(source.to_owned())
+                .or_default() += 1;
+        }
+        self.events.lock().unwrap().push(Event {
+            at: self.started.elapsed(),
+            source: source.to_owned(),
+            kind,
+        });
+    }
+
+    fn chain_block(&self, hash: BlockHash) -> Result<&Block, RpcError> {
+        self.chain.get(&hash).ok_or_else(|| RpcError {
+            code: -5,
+            message: "Block not found".to_owned(),
+            data: None,
+            status: None,
+        })
+    }
+}
+
+/// Ends the request of a peer that was dropped before it answered, e.g. because another peer
+/// delivered first.
+struct Pending<'a> {
+    sim: &'a Simulation,
+    source: String,
+    done: bool,
+}
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.sim
+                .record(&self.source, EventKind::Failed("cancelled".to_owned()));
+        }
+    }
+}
+
+impl Sources for Simulation {
+    type Peer = SimPeer;
+
+    fn peer_addr(peer: &SimPeer) -> Address {
+        peer.addr.clone()
+    }
+
+    fn check_best_chain(
+        &self,
+        hash: BlockHash,
+        allow_forks: bool,
+    ) -> BoxFuture<'_, Result<(), RpcError>> {
+        async move {
+            self.chain_block(hash)?;
+            if self.forks.contains(&hash) && !allow_forks {
+                return Err(RpcError {
+                    code: MISC_ERROR_CODE,
+                    message: format!("Block {} is not in the best chain", hash),
+                    data: None,
+                    status: None,
+                });
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn fetch<'a>(
+        &'a self,
+        stage: FetchStage,
+        hash: BlockHash,
+        _failures: &'a Mutex<Vec<PeerFailure>>,
+    ) -> BoxFuture<'a, Result<Option<Block>, RpcError>> {
+        async move {
+            let block = self.chain_block(hash)?;
+            let source = stage.to_string();
+            self.record(&source, EventKind::Requested);
+            let mut pending = Pending {
+                sim: self,
+                source,
+                done: false,
+            };
+            let scripted = self.stages.lock().unwrap().get(&stage).cloned();
+            let res = match scripted {
+                Some(scripted) => {
+                    tokio::time::delay_for(scripted.latency).await;
+                    if scripted.blocks.contains(&hash) {
+                        Some(block.clone())
+                    } else {
+                        None
+                    }
+                }
+                None => None,
+            };
+            pending.done = true;
+            self.record(
+                &pending.source,
+                match res {
+                    Some(_) => EventKind::Delivered,
+                    None => EventKind::Failed("unavailable".to_owned()),
+                },
+            );
+            Ok(res)
+        }
+        .boxed()
+    }
+
+    fn peers(&self) -> BoxFuture<'_, Result<Vec<SimPeer>, Error>> {
+        futures::future::ok(self.peers.clone()).boxed()
+    }
+
+    fn fetch_from_peer(
+        &self,
+        peer: SimPeer,
+        hash: BlockHash,
+    ) -> BoxFuture<'_, Result<Block, Error>> {
+        async move {
+            let source = peer_name(&peer.addr);
+            let nth = self.requests(&source);
+            self.record(&source, EventKind::Requested);
+            let mut pending = Pending {
+                sim: self,
+                source,
+                done: false,
+            };
+            let answer = async {
+                let block = self.chain.get(&hash);
+                match peer.behavior {
+                    Behavior::Stalling => futures::future::pending::<Result<Block, Error>>().await,
+                    Behavior::Flaky(failures) if nth < failures => {
+                        tokio::time::delay_for(peer.latency).await;
+                        Err(anyhow!("connection reset"))
+                    }
+                    Behavior::Missing => {
+                        tokio::time::delay_for(peer.latency).await;
+                        Err(anyhow!("notfound"))
+                    }
+                    Behavior::Honest | Behavior::Flaky(_) => {
+                        tokio::time::delay_for(peer.latency).await;
+                        block.cloned().ok_or_else(|| anyhow!("notfound"))
+                    }
+                }
+            };
+            let res = match tokio::time::timeout(self.peer_timeout, answer).await {
+                Ok(res) => res,
+                Err(_) => Err(anyhow!("timed out")),
+            };
+            pending.done = true;
+            self.record(
+                &pending.source,
+                match &res {
+                    Ok(_) => EventKind::Delivered,
+                    Err(e) => EventKind::Failed(e.to_string()),
+                },
+            );
+            res
+        }
+        .boxed()
+    }
+
+    fn cache(&self, block: &Block) {
+        self.stages
+            .lock()
+            .unwrap()
+            .entry(FetchStage::Cache)
+            .or_default()
+            .blocks
+            .insert(block.block_hash());
+    }
+}
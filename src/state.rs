@@ -1,50 +1,570 @@
+use std::collections::HashMap;
+#[cfg(feature = "tor")]
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
+#[cfg(feature = "peer-fetch")]
 use anyhow::Error;
+#[cfg(feature = "peer-fetch")]
+use bitcoin::Network;
 use slog::Logger;
+#[cfg(feature = "peer-fetch")]
 use tokio::sync::RwLock;
 
+#[cfg(feature = "peer-fetch")]
+use crate::address_book::AddressBook;
+use crate::approvals::Approvals;
+use crate::audit::Audit;
+use crate::auth::{self, AuthBackend};
+use crate::balances::Balances;
+#[cfg(feature = "peer-fetch")]
+use crate::block_cache::BlockCache;
+#[cfg(feature = "peer-fetch")]
+use crate::cache::SizedCache;
+use crate::cache::{CacheInfo, MemoryBudget};
+use crate::cache_sync::CacheSync;
+use crate::capabilities::Capabilities;
+#[cfg(feature = "chaos")]
+use crate::chaos::Chaos;
 use crate::client::RpcClient;
-use crate::fetch_blocks::{PeerHandle, Peers};
+use crate::compat::Compat;
+use crate::cookie::Cookie;
+use crate::data_dir::DataDir;
+use crate::deprecation::Deprecations;
+#[cfg(feature = "peer-fetch")]
+use crate::fetch_blocks::{FetchPolicy, PeerHandle, Peers};
+use crate::guardrails::Guardrails;
+use crate::headers::ResponseHeaders;
+use crate::idempotency::Idempotency;
+use crate::in_flight::InFlight;
+use crate::intercept::Interceptors;
+use crate::journal::Journal;
+use crate::jwt::JwtAuth;
+use crate::lifecycle::{Hooks, Shutdown};
+use crate::listen::{Address, Listen};
+use crate::lockout::Lockout;
+use crate::metrics::Metrics;
+use crate::notify::Notifier;
+use crate::oidc::Oidc;
+use crate::onion::Onion;
+#[cfg(feature = "peer-fetch")]
+use crate::p2p::Tracer;
+use crate::priority::Load;
+use crate::quota::QuotaUsage;
+use crate::rate_limit::{RateLimit, RateLimiter};
+use crate::reuse::AddressReuse;
+use crate::schema::ValidationMode;
+use crate::signing::Signer;
+use crate::stats::Stats;
+use crate::storage::{self, Memory, Storage};
+use crate::subscriptions::Subscriptions;
+use crate::tenants::TenantStore;
+#[cfg(feature = "peer-fetch")]
+use crate::timeouts::PeerTimeouts;
+use crate::tokens::Tokens;
+use crate::tx_status::TxStatusCache;
+#[cfg(feature = "cache")]
+use crate::txout_cache::TxOutCache;
 use crate::users::Users;
 
+#[cfg(feature = "tor")]
 #[derive(Debug)]
 pub struct TorState {
     pub proxy: SocketAddr,
     pub only: bool,
 }
 
+/// Everything the proxy needs to serve requests, shared between all connections. Built with
+/// [`State::builder`].
 #[derive(Debug)]
 pub struct State {
-    pub bind: SocketAddr,
-    pub rpc_client: RpcClient,
-    pub tor: Option<TorState>,
-    pub users: Users,
-    pub logger: Logger,
-    pub peer_timeout: Duration,
-    pub peers: RwLock<Arc<Peers>>,
-    pub max_peer_age: Duration,
-    pub max_peer_concurrency: Option<usize>,
+    /// Sockets the proxy listens on
+    pub(crate) listen: Vec<Listen>,
+    /// Socket a new process takes over the listening sockets through
+    pub(crate) control_socket: Option<PathBuf>,
+    /// Called as the proxy starts, notices new blocks and shuts down
+    pub(crate) hooks: Hooks,
+    /// Shuts the proxy down gracefully when triggered
+    pub(crate) shutdown: Shutdown,
+    /// Accept HTTP/2 connections on the listener
+    pub(crate) http2: bool,
+    /// Calls in flight on one HTTP/2 connection at most
+    pub(crate) http2_max_streams: u32,
+    /// Headers forwarded to and injected into responses on the listener
+    pub(crate) response_headers: ResponseHeaders,
+    /// Methods answered with a warning that they are deprecated
+    pub(crate) deprecations: Deprecations,
+    /// Coin selection and fee policies of funding calls
+    pub(crate) guardrails: Guardrails,
+    /// Quirks of client libraries the listener tolerates
+    pub(crate) compat: Compat,
+    /// Client for the real bitcoind
+    pub(crate) rpc_client: RpcClient,
+    /// Proxy used for peer connections, if any
+    #[cfg(feature = "tor")]
+    pub(crate) tor: Option<TorState>,
+    /// The onion service published through Tor's control port, if any
+    pub(crate) onion: Option<Onion>,
+    /// Users allowed to connect to the proxy
+    pub(crate) users: Users,
+    /// The user whose permissions apply to clients sending no credentials, if any
+    pub(crate) anonymous_user: Option<String>,
+    /// Limits the calls of all anonymous clients together
+    pub(crate) anonymous_rate_limit: RateLimit,
+    /// Cookie file written for applications authenticating like with bitcoind's `.cookie`
+    pub(crate) cookie: Option<Cookie>,
+    /// Validate credentials not matching a configured user, in order
+    pub(crate) auth_backends: Vec<Box<dyn AuthBackend>>,
+    /// Verifies bearer tokens if configured
+    pub(crate) jwt: Option<JwtAuth>,
+    /// Verifies access tokens of an OpenID Connect provider if configured
+    pub(crate) oidc: Option<Oidc>,
+    /// Tracks calls against the rate limits of users
+    pub(crate) rate_limiter: RateLimiter,
+    /// Methods served (fully or partially) by the proxy itself
+    pub(crate) interceptors: Interceptors,
+    pub(crate) logger: Logger,
+    /// Serve Prometheus metrics on `/metrics`
+    #[cfg(feature = "metrics")]
+    pub(crate) serve_metrics: bool,
+    /// Require JSON-RPC 2.0 framing from clients
+    pub(crate) jsonrpc2_strict: bool,
+    pub(crate) metrics: Metrics,
+    /// Requests being handled
+    pub(crate) in_flight: InFlight,
+    /// Usage of the daily and monthly quotas of users
+    pub(crate) quota_usage: QuotaUsage,
+    /// Usage of the fetch quotas of all users
+    #[cfg(feature = "peer-fetch")]
+    pub(crate) fetch_usage: QuotaUsage,
+    /// Deny all state-changing methods for every user
+    pub(crate) read_only: AtomicBool,
+    /// Checks whether bitcoind is degraded to slow down batch users, if enabled
+    pub(crate) load: Option<Load>,
+    /// Checking of responses against the schemas expected for their methods
+    pub(crate) validate_responses: ValidationMode,
+    /// Sends waiting for a second user to approve them
+    pub(crate) approvals: Option<Approvals>,
+    /// Channels operators are notified through
+    pub(crate) notifier: Notifier,
+    /// Record of forwarded state-changing calls
+    pub(crate) journal: Option<Journal>,
+    /// Signs responses if configured, to prove later what they were
+    pub(crate) response_signer: Option<Signer>,
+    /// Directory keeping the files of the proxy
+    pub(crate) data_dir: Option<DataDir>,
+    /// Results of state-changing calls made with an idempotency key
+    pub(crate) idempotency: Idempotency,
+    /// Addresses seen receiving funds, to detect their reuse
+    pub(crate) address_reuse: Option<AddressReuse>,
+    /// Faults injected into requests
+    #[cfg(feature = "chaos")]
+    pub(crate) chaos: Option<Chaos>,
+    /// Network of bitcoind and thus of the peers blocks are fetched from
+    #[cfg(feature = "peer-fetch")]
+    pub(crate) network: Network,
+    /// How long to wait for a response from a peer
+    #[cfg(feature = "peer-fetch")]
+    pub(crate) peer_timeout: Duration,
+    /// How long to wait for peers and how often to retry connecting, by how they are reached
+    #[cfg(feature = "peer-fetch")]
+    pub(crate) peer_timeouts: PeerTimeouts,
+    /// Cached list of peers to fetch pruned blocks from
+    #[cfg(feature = "peer-fetch")]
+    pub(crate) peers: RwLock<Arc<Peers>>,
+    /// How long the cached peer list stays valid
+    #[cfg(feature = "peer-fetch")]
+    pub(crate) max_peer_age: Duration,
+    /// How many connections to the best scoring peers to keep open between fetches
+    #[cfg(feature = "peer-fetch")]
+    pub(crate) warm_peers: usize,
+    /// How often idle peer connections are pinged
+    #[cfg(feature = "peer-fetch")]
+    pub(crate) peer_keepalive_interval: Duration,
+    /// Records P2P messages if tracing is enabled
+    #[cfg(feature = "peer-fetch")]
+    pub(crate) p2p_tracer: Option<Arc<Tracer>>,
+    /// How many peers to ask for a block at once
+    #[cfg(feature = "peer-fetch")]
+    pub(crate) max_peer_concurrency: Option<usize>,
+    /// How long a block is looked for in all places before giving up
+    #[cfg(feature = "peer-fetch")]
+    pub(crate) fetch_budget: Option<Duration>,
+    /// Where pruned blocks are looked for
+    #[cfg(feature = "peer-fetch")]
+    pub(crate) fetch_policy: FetchPolicy,
+    /// Memory all caches together may use
+    pub(crate) memory_budget: Arc<MemoryBudget>,
+    /// Blocks fetched from elsewhere than bitcoind
+    #[cfg(feature = "peer-fetch")]
+    pub(crate) block_cache: BlockCache,
+    /// Peers seen and how well they served blocks
+    #[cfg(feature = "peer-fetch")]
+    pub(crate) address_book: AddressBook,
+    /// Statuses of confirmed transactions served by `proxy_gettxstatuses`
+    pub(crate) tx_statuses: TxStatusCache,
+    /// Responses to `gettxout`, if enabled
+    #[cfg(feature = "cache")]
+    pub(crate) txout_cache: Option<TxOutCache>,
+    /// Balance snapshots of wallets polled in the background if configured
+    pub(crate) balances: Option<Balances>,
+    /// Subscribers to new blocks and transactions on `/ws`, if enabled
+    pub(crate) subscriptions: Option<Subscriptions>,
+    /// The public stats page, if enabled
+    pub(crate) stats: Option<Stats>,
+    /// Whether the gRPC service is served
+    #[cfg(feature = "grpc")]
+    pub(crate) grpc: bool,
+    /// Invalidates caches on new tips and shares invalidations with other proxies
+    pub(crate) cache_sync: CacheSync,
+    /// What bitcoind supports, once detected
+    pub(crate) capabilities: Capabilities,
+    /// Counts failed authentication attempts, if enabled
+    pub(crate) lockout: Option<Lockout>,
+    /// Where audit events of denied and failed authentication go, if anywhere
+    pub(crate) audit: Option<Audit>,
+    /// Bearer tokens issued with `proxy_issuetoken`
+    pub(crate) tokens: Tokens,
 }
 impl State {
+    /// A proxy in front of `rpc_client` listening on `127.0.0.1:8331`, with the defaults of the
+    /// config and optional components disabled. Everything is kept in memory.
+    pub fn builder(rpc_client: RpcClient, logger: Logger) -> StateBuilder {
+        let storage: Arc<dyn Storage> = Arc::new(Memory::default());
+        let memory_budget = MemoryBudget::new(64 << 20);
+        StateBuilder {
+            state: State {
+                listen: vec![Listen {
+                    address: Address::Tcp(([127, 0, 0, 1], 8331).into()),
+                    #[cfg(feature = "tls")]
+                    tls: None,
+                    headers: None,
+                }],
+                control_socket: None,
+                hooks: Hooks::default(),
+                shutdown: Shutdown::default(),
+                http2: true,
+                http2_max_streams: 100,
+                response_headers: ResponseHeaders::default(),
+                deprecations: Deprecations::default(),
+                guardrails: Guardrails::default(),
+                compat: Compat::default(),
+                rpc_client,
+                #[cfg(feature = "tor")]
+                tor: None,
+                onion: None,
+                // in memory, there is nothing to read that could fail
+                users: Users::open(HashMap::new(), HashMap::new(), storage.clone())
+                    .expect("users in memory"),
+                anonymous_user: None,
+                anonymous_rate_limit: RateLimit::per_second(auth::DEFAULT_ANONYMOUS_RATE, None),
+                cookie: None,
+                auth_backends: Vec::new(),
+                jwt: None,
+                oidc: None,
+                rate_limiter: RateLimiter::memory(),
+                interceptors: Interceptors::default(),
+                logger,
+                #[cfg(feature = "metrics")]
+                serve_metrics: false,
+                jsonrpc2_strict: false,
+                metrics: Metrics::default(),
+                in_flight: InFlight::default(),
+                quota_usage: QuotaUsage::open(storage.clone(), storage::QUOTA_USAGE)
+                    .expect("quota usage in memory"),
+                #[cfg(feature = "peer-fetch")]
+                fetch_usage: QuotaUsage::open(storage.clone(), storage::FETCH_USAGE)
+                    .expect("quota usage in memory"),
+                read_only: AtomicBool::new(false),
+                load: None,
+                validate_responses: ValidationMode::default(),
+                approvals: None,
+                notifier: Notifier::default(),
+                journal: None,
+                response_signer: None,
+                data_dir: None,
+                idempotency: Idempotency::new(Duration::from_secs(86400), 1000),
+                address_reuse: None,
+                #[cfg(feature = "chaos")]
+                chaos: None,
+                #[cfg(feature = "peer-fetch")]
+                network: Network::Bitcoin,
+                #[cfg(feature = "peer-fetch")]
+                peer_timeout: Duration::from_secs(30),
+                #[cfg(feature = "peer-fetch")]
+                peer_timeouts: PeerTimeouts::new(30, HashMap::new()).expect("default timeouts"),
+                #[cfg(feature = "peer-fetch")]
+                peers: RwLock::new(Arc::new(Peers::new())),
+                #[cfg(feature = "peer-fetch")]
+                max_peer_age: Duration::from_secs(300),
+                #[cfg(feature = "peer-fetch")]
+                warm_peers: 3,
+                #[cfg(feature = "peer-fetch")]
+                peer_keepalive_interval: Duration::from_secs(60),
+                #[cfg(feature = "peer-fetch")]
+                p2p_tracer: None,
+                #[cfg(feature = "peer-fetch")]
+                max_peer_concurrency: None,
+                #[cfg(feature = "peer-fetch")]
+                fetch_budget: None,
+                #[cfg(feature = "peer-fetch")]
+                fetch_policy: FetchPolicy::default(),
+                #[cfg(feature = "peer-fetch")]
+                block_cache: BlockCache::Memory(SizedCache::new("blocks", memory_budget.clone())),
+                #[cfg(feature = "peer-fetch")]
+                address_book: AddressBook::open(storage).expect("address book in memory"),
+                tx_statuses: TxStatusCache::new(memory_budget.clone()),
+                memory_budget,
+                #[cfg(feature = "cache")]
+                txout_cache: None,
+                balances: None,
+                subscriptions: None,
+                stats: None,
+                #[cfg(feature = "grpc")]
+                grpc: false,
+                cache_sync: CacheSync::new(None, Duration::from_secs(5)),
+                capabilities: Capabilities::default(),
+                lockout: None,
+                audit: None,
+                tokens: Tokens::default(),
+            },
+        }
+    }
+
+    /// The handle shutting the proxy down, to keep a clone of before the proxy starts.
+    pub fn shutdown(&self) -> &Shutdown {
+        &self.shutdown
+    }
+
     pub fn leak(self) -> &'static Self {
         Box::leak(Box::new(self))
     }
     pub fn arc(self) -> Arc<Self> {
         Arc::new(self)
     }
-    pub async fn get_peers(self: Arc<Self>) -> Result<Vec<PeerHandle>, Error> {
+    /// Caches of the proxy, for metrics.
+    #[allow(clippy::vec_init_then_push)]
+    pub fn caches(&self) -> Vec<&dyn CacheInfo> {
+        #[allow(unused_mut)]
+        let mut caches: Vec<&dyn CacheInfo> = Vec::new();
+        #[cfg(feature = "peer-fetch")]
+        caches.push(self.block_cache.info());
+        caches.push(&self.tx_statuses);
+        #[cfg(feature = "cache")]
+        if let Some(txout_cache) = &self.txout_cache {
+            caches.push(txout_cache);
+        }
+        caches
+    }
+    /// Stores of state kept on behalf of individual users.
+    pub fn tenant_stores(&self) -> Vec<&dyn TenantStore> {
+        let mut stores: Vec<&dyn TenantStore> = vec![&self.idempotency];
+        if let Some(approvals) = &self.approvals {
+            stores.push(approvals);
+        }
+        if let Some(reuse) = &self.address_reuse {
+            stores.push(reuse);
+        }
+        stores
+    }
+    pub fn set_read_only(&self, read_only: bool) {
+        if self.read_only.swap(read_only, Ordering::SeqCst) != read_only {
+            warn!(
+                self.logger,
+                "Read-only mode {}",
+                if read_only { "ENABLED" } else { "DISABLED" }
+            );
+        }
+    }
+    #[cfg(feature = "peer-fetch")]
+    pub(crate) async fn get_peers(self: Arc<Self>) -> Result<Vec<PeerHandle>, Error> {
         let peers = self.peers.read().await.clone();
         if peers.stale(self.max_peer_age) {
+            let state = self.clone();
             tokio::task::spawn(async move {
-                match Peers::updated(&self.rpc_client).await {
-                    Ok(peers) => *self.peers.write().await = Arc::new(peers),
-                    Err(e) => error!(self.logger, "{}", e.context("updating peer list")),
+                match Peers::updated(&state.rpc_client).await {
+                    Ok(mut peers) => {
+                        state.address_book.observe(peers.addrs());
+                        let mut current = state.peers.write().await;
+                        peers.keep_connections(&current);
+                        *current = Arc::new(peers)
+                    }
+                    Err(e) => error!(state.logger, "{}", e.context("updating peer list")),
                 }
             });
         }
-        Ok(peers.handles())
+        let mut handles: Vec<PeerHandle> = peers.handles();
+        self.address_book
+            .sort_by_score(&mut handles, |handle| handle.addr().clone());
+        Ok(handles)
     }
 }
+
+/// Sets up the components of a [`State`]. Components added in later releases are disabled unless
+/// set, so code building a state keeps working.
+#[derive(Debug)]
+pub struct StateBuilder {
+    state: State,
+}
+impl StateBuilder {
+    pub fn build(self) -> State {
+        self.state
+    }
+
+    /// Denies all state-changing methods for every user from the start
+    pub fn read_only(self, read_only: bool) -> Self {
+        self.state.read_only.store(read_only, Ordering::SeqCst);
+        self
+    }
+}
+
+/// Setters of [`StateBuilder`] replacing a component.
+macro_rules! setters {
+    ($($(#[$attr:meta])* $field:ident: $ty:ty,)*) => {
+        impl StateBuilder {
+            $(
+                $(#[$attr])*
+                pub fn $field(mut self, $field: $ty) -> Self {
+                    self.state.$field = $field;
+                    self
+                }
+            )*
+        }
+    };
+}
+
+setters! {
+    /// Sockets the proxy listens on
+    listen: Vec<Listen>,
+    /// Socket a new process takes over the listening sockets through
+    control_socket: Option<PathBuf>,
+    /// Called as the proxy starts, notices new blocks and shuts down
+    hooks: Hooks,
+    /// Accept HTTP/2 connections
+    http2: bool,
+    /// Calls in flight on one HTTP/2 connection at most
+    http2_max_streams: u32,
+    /// Headers forwarded from bitcoind and injected into responses
+    response_headers: ResponseHeaders,
+    /// Methods answered with a warning that they are deprecated
+    deprecations: Deprecations,
+    /// Coin selection and fee policies of funding calls
+    guardrails: Guardrails,
+    /// Quirks of client libraries tolerated
+    compat: Compat,
+    /// Proxy used for peer connections
+    #[cfg(feature = "tor")]
+    tor: Option<TorState>,
+    /// The onion service published through Tor's control port
+    onion: Option<Onion>,
+    /// Users allowed to connect to the proxy
+    users: Users,
+    /// The user whose permissions apply to clients sending no credentials
+    anonymous_user: Option<String>,
+    /// Limits the calls of all anonymous clients together
+    anonymous_rate_limit: RateLimit,
+    /// Cookie file written for applications authenticating like with bitcoind's `.cookie`
+    cookie: Option<Cookie>,
+    /// Validate credentials not matching a configured user, in order
+    auth_backends: Vec<Box<dyn AuthBackend>>,
+    /// Verifies bearer tokens
+    jwt: Option<JwtAuth>,
+    /// Verifies access tokens of an OpenID Connect provider
+    oidc: Option<Oidc>,
+    /// Tracks calls against the rate limits of users
+    rate_limiter: RateLimiter,
+    /// Methods served (fully or partially) by the proxy itself
+    interceptors: Interceptors,
+    /// Serve Prometheus metrics on `/metrics`
+    #[cfg(feature = "metrics")]
+    serve_metrics: bool,
+    /// Require JSON-RPC 2.0 framing from clients
+    jsonrpc2_strict: bool,
+    /// Usage of the daily and monthly quotas of users
+    quota_usage: QuotaUsage,
+    /// Usage of the fetch quotas of all users
+    #[cfg(feature = "peer-fetch")]
+    fetch_usage: QuotaUsage,
+    /// Slows down batch users while bitcoind is degraded
+    load: Option<Load>,
+    /// Checking of responses against the schemas expected for their methods
+    validate_responses: ValidationMode,
+    /// Parks sends until a second user approves them
+    approvals: Option<Approvals>,
+    /// Channels operators are notified through
+    notifier: Notifier,
+    /// Record of forwarded state-changing calls
+    journal: Option<Journal>,
+    /// Signs responses
+    response_signer: Option<Signer>,
+    /// Directory keeping the files of the proxy
+    data_dir: Option<DataDir>,
+    /// Results of state-changing calls made with an idempotency key
+    idempotency: Idempotency,
+    /// Detects addresses receiving funds more than once
+    address_reuse: Option<AddressReuse>,
+    /// Faults injected into requests
+    #[cfg(feature = "chaos")]
+    chaos: Option<Chaos>,
+    /// Network of bitcoind and thus of the peers blocks are fetched from
+    #[cfg(feature = "peer-fetch")]
+    network: Network,
+    /// How long to wait for a response from a peer
+    #[cfg(feature = "peer-fetch")]
+    peer_timeout: Duration,
+    /// How long to wait for peers and how often to retry connecting, by how they are reached
+    #[cfg(feature = "peer-fetch")]
+    peer_timeouts: PeerTimeouts,
+    /// How long the cached peer list stays valid
+    #[cfg(feature = "peer-fetch")]
+    max_peer_age: Duration,
+    /// How many connections to the best scoring peers to keep open between fetches
+    #[cfg(feature = "peer-fetch")]
+    warm_peers: usize,
+    /// How often idle peer connections are pinged
+    #[cfg(feature = "peer-fetch")]
+    peer_keepalive_interval: Duration,
+    /// Records P2P messages
+    #[cfg(feature = "peer-fetch")]
+    p2p_tracer: Option<Arc<Tracer>>,
+    /// How many peers to ask for a block at once
+    #[cfg(feature = "peer-fetch")]
+    max_peer_concurrency: Option<usize>,
+    /// How long a block is looked for in all places before giving up
+    #[cfg(feature = "peer-fetch")]
+    fetch_budget: Option<Duration>,
+    /// Where pruned blocks are looked for
+    #[cfg(feature = "peer-fetch")]
+    fetch_policy: FetchPolicy,
+    /// Memory all caches together may use, which the caches set have to share
+    memory_budget: Arc<MemoryBudget>,
+    /// Blocks fetched from elsewhere than bitcoind
+    #[cfg(feature = "peer-fetch")]
+    block_cache: BlockCache,
+    /// Peers seen and how well they served blocks
+    #[cfg(feature = "peer-fetch")]
+    address_book: AddressBook,
+    /// Statuses of confirmed transactions served by `proxy_gettxstatuses`
+    tx_statuses: TxStatusCache,
+    /// Responses to `gettxout`
+    #[cfg(feature = "cache")]
+    txout_cache: Option<TxOutCache>,
+    /// Balance snapshots of wallets polled in the background
+    balances: Option<Balances>,
+    /// Subscribers to new blocks and transactions on `/ws`
+    subscriptions: Option<Subscriptions>,
+    /// The public stats page
+    stats: Option<Stats>,
+    /// Serve the gRPC service
+    #[cfg(feature = "grpc")]
+    grpc: bool,
+    /// Invalidates caches on new tips and shares invalidations with other proxies
+    cache_sync: CacheSync,
+    /// Counts failed authentication attempts
+    lockout: Option<Lockout>,
+    /// Where audit events of denied and failed authentication go
+    audit: Option<Audit>,
+}
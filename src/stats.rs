@@ -0,0 +1,178 @@
+//! A public status page of the node on `/stats` (HTML) and `/stats.json`, served without
+//! authentication.
+//!
+//! Only data that reveals nothing about the node's wallets, peers or addresses is shown. It is
+//! fetched from bitcoind at most once per `stats_cache` seconds however many clients ask, and
+//! every client address is rate limited on its own, so the page can't be used to load the node.
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use anyhow::Error;
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE, RETRY_AFTER};
+use hyper::{http::request::Parts, Body, Response, StatusCode};
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+
+use crate::rate_limit::{Decision, RateLimit};
+use crate::state::State;
+use crate::subscriptions::call;
+
+#[derive(Debug)]
+struct Snapshot {
+    fetched: Instant,
+    stats: Value,
+}
+
+#[derive(Debug)]
+pub struct Stats {
+    /// Requests per client address
+    pub limit: RateLimit,
+    /// How long fetched stats are served for
+    pub ttl: Duration,
+    started: Instant,
+    snapshot: Mutex<Option<Snapshot>>,
+}
+impl Stats {
+    pub fn new(limit: RateLimit, ttl: Duration) -> Self {
+        Stats {
+            limit,
+            ttl,
+            started: Instant::now(),
+            snapshot: Mutex::new(None),
+        }
+    }
+
+    /// Asks bitcoind for the stats.
+    async fn fetch(&self, state: &State) -> Result<Value, Error> {
+        let (chain, network, uptime) = futures::future::try_join3(
+            call(state, "getblockchaininfo", Vec::new()),
+            call(state, "getnetworkinfo", Vec::new()),
+            call(state, "uptime", Vec::new()),
+        )
+        .await?;
+        Ok(json!({
+            "chain": chain["chain"],
+            "height": chain["blocks"],
+            "best_block": chain["bestblockhash"],
+            "initial_block_download": chain["initialblockdownload"],
+            "version": network["version"],
+            "subversion": network["subversion"],
+            "uptime": uptime,
+            "proxy_version": env!("CARGO_PKG_VERSION"),
+            "proxy_uptime": self.started.elapsed().as_secs(),
+        }))
+    }
+
+    /// The stats, fetched again if older than `ttl`. Old stats are served while bitcoind can't
+    /// be reached.
+    pub async fn get(&self, state: &State) -> Result<Value, Error> {
+        let mut snapshot = self.snapshot.lock().await;
+        if let Some(snapshot) = &*snapshot {
+            if snapshot.fetched.elapsed() < self.ttl {
+                return Ok(snapshot.stats.clone());
+            }
+        }
+        match self.fetch(state).await {
+            Ok(stats) => {
+                *snapshot = Some(Snapshot {
+                    fetched: Instant::now(),
+                    stats: stats.clone(),
+                });
+                Ok(stats)
+            }
+            Err(e) => match &*snapshot {
+                Some(snapshot) => {
+                    debug!(state.logger, "Serving old stats: {:#}", e);
+                    Ok(snapshot.stats.clone())
+                }
+                None => Err(e),
+            },
+        }
+    }
+}
+
+/// The stats as a minimal HTML page.
+pub fn render_html(stats: &Value) -> String {
+    let rows: String = stats
+        .as_object()
+        .into_iter()
+        .flatten()
+        .map(|(name, value)| {
+            let value = match value {
+                Value::String(s) => s.clone(),
+                value => value.to_string(),
+            };
+            format!(
+                "<tr><th>{}</th><td>{}</td></tr>\n",
+                escape(&name.replace('_', " ")),
+                escape(&value)
+            )
+        })
+        .collect();
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Node stats</title></head>\n<body><table>\n{}</table></body></html>\n",
+        rows
+    )
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Answers a request for `/stats` or `/stats.json`.
+pub async fn serve(state: &State, parts: &Parts) -> Result<Response<Body>, Error> {
+    let stats = match &state.stats {
+        Some(stats) => stats,
+        None => {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())?)
+        }
+    };
+    let ip = parts.extensions.get::<SocketAddr>().map(|a| a.ip());
+    let client = format!("stats:{}", ip.map_or_else(String::new, |ip| ip.to_string()));
+    match state.rate_limiter.acquire(&client, 0, &stats.limit).await {
+        Ok(Decision::Allow) | Ok(Decision::Delay(_)) => (),
+        Ok(Decision::Reject(retry_after)) => {
+            return Ok(Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .header(RETRY_AFTER, retry_after.as_secs().max(1))
+                .body(Body::empty())?)
+        }
+        Err(e) => {
+            // unlike calls, stats aren't worth loading the node without a limit
+            warn!(
+                state.logger,
+                "Failed to check rate limit of {}: {:#}", client, e
+            );
+            return Ok(Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(Body::empty())?);
+        }
+    }
+    let values = match stats.get(state).await {
+        Ok(values) => values,
+        Err(e) => {
+            warn!(state.logger, "Failed to fetch stats: {:#}", e);
+            return Ok(Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(Body::empty())?);
+        }
+    };
+    let (content_type, body) = if parts.uri.path() == "/stats.json" {
+        ("application/json", values.to_string())
+    } else {
+        ("text/html; charset=utf-8", render_html(&values))
+    };
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, content_type)
+        .header(
+            CACHE_CONTROL,
+            format!("public, max-age={}", stats.ttl.as_secs()),
+        )
+        .body(body.into())?)
+}
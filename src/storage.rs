@@ -0,0 +1,180 @@
+//! Where the proxy keeps what it needs across restarts: the users changed at runtime, the
+//! journal, the address book and quota usage.
+//!
+//! Everything is stored as JSON, either as documents, which are replaced as a whole, or as logs,
+//! which are only appended to. Embedders may supply their own backend by implementing `Storage`.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Error};
+use serde_json::Value;
+
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "sqlite")]
+pub use sqlite::Sqlite;
+
+/// Users added, changed (the user) or removed (`null`) at runtime, by name
+pub const USERS: &str = "users";
+/// Log of state-changing calls
+pub const JOURNAL: &str = "journal";
+/// Peers of the address book
+pub const PEERS: &str = "peers";
+/// Usage of the quotas of every user
+pub const QUOTA_USAGE: &str = "quota_usage";
+/// Usage of the fetch quotas of every user
+pub const FETCH_USAGE: &str = "fetch_usage";
+
+pub trait Storage: Send + Sync + std::fmt::Debug {
+    /// The document `name`, `None` if it was never stored.
+    fn load(&self, name: &str) -> Result<Option<Value>, Error>;
+    /// Replaces the document `name`, either entirely or not at all.
+    fn store(&self, name: &str, document: &Value) -> Result<(), Error>;
+    /// Appends `record` to the log `name`, returning once it is durable.
+    fn append(&self, name: &str, record: &Value) -> Result<(), Error>;
+    /// The records of the log `name`, oldest first.
+    fn records(&self, name: &str) -> Result<Vec<Value>, Error>;
+}
+
+/// Keeps everything in memory, so that nothing survives a restart.
+#[derive(Debug, Default)]
+pub struct Memory {
+    documents: Mutex<HashMap<String, Value>>,
+    logs: Mutex<HashMap<String, Vec<Value>>>,
+}
+impl Storage for Memory {
+    fn load(&self, name: &str) -> Result<Option<Value>, Error> {
+        Ok(self.documents.lock().unwrap().get(name).cloned())
+    }
+
+    fn store(&self, name: &str, document: &Value) -> Result<(), Error> {
+        self.documents
+            .lock()
+            .unwrap()
+            .insert(name.to_owned(), document.clone());
+        Ok(())
+    }
+
+    fn append(&self, name: &str, record: &Value) -> Result<(), Error> {
+        self.logs
+            .lock()
+            .unwrap()
+            .entry(name.to_owned())
+            .or_default()
+            .push(record.clone());
+        Ok(())
+    }
+
+    fn records(&self, name: &str) -> Result<Vec<Value>, Error> {
+        Ok(self
+            .logs
+            .lock()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .unwrap_or_default())
+    }
+}
+
+/// Keeps each document and log in a file of its own: documents as JSON, logs with a record per
+/// line. Those without a file are kept in memory.
+#[derive(Debug, Default)]
+pub struct Files {
+    paths: HashMap<String, PathBuf>,
+    /// Serializes appends, so that records aren't interleaved
+    append: Mutex<()>,
+    memory: Memory,
+}
+impl Files {
+    /// Keeps the document or log `name` in the file at `path`, if set.
+    pub fn with(mut self, name: &str, path: Option<PathBuf>) -> Self {
+        if let Some(path) = path {
+            self.paths.insert(name.to_owned(), path);
+        }
+        self
+    }
+}
+impl Storage for Files {
+    fn load(&self, name: &str) -> Result<Option<Value>, Error> {
+        let path = match self.paths.get(name) {
+            Some(path) => path,
+            None => return self.memory.load(name),
+        };
+        match std::fs::read(path) {
+            Ok(data) => {
+                Ok(Some(serde_json::from_slice(&data).map_err(|e| {
+                    anyhow!("{} is corrupt: {}", path.display(), e)
+                })?))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(anyhow!("failed to read {}: {}", path.display(), e)),
+        }
+    }
+
+    fn store(&self, name: &str, document: &Value) -> Result<(), Error> {
+        let path = match self.paths.get(name) {
+            Some(path) => path,
+            None => return self.memory.store(name, document),
+        };
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(document)?)
+            .and_then(|()| std::fs::rename(&tmp, path))
+            .map_err(|e| anyhow!("failed to write {}: {}", path.display(), e))
+    }
+
+    fn append(&self, name: &str, record: &Value) -> Result<(), Error> {
+        let path = match self.paths.get(name) {
+            Some(path) => path,
+            None => return self.memory.append(name, record),
+        };
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let _guard = self.append.lock().unwrap();
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| {
+                file.write_all(&line)?;
+                file.sync_data()
+            })
+            .map_err(|e| anyhow!("failed to append to {}: {}", path.display(), e))
+    }
+
+    fn records(&self, name: &str) -> Result<Vec<Value>, Error> {
+        let path = match self.paths.get(name) {
+            Some(path) => path,
+            None => return self.memory.records(name),
+        };
+        let data = match std::fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(anyhow!("failed to read {}: {}", path.display(), e)),
+        };
+        data.lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(|l| Ok(serde_json::from_str(l)?))
+            .collect()
+    }
+}
+
+/// Opens the backend described by `spec`: `memory` or `sqlite:<path>`.
+pub fn open(spec: &str) -> Result<Arc<dyn Storage>, Error> {
+    match spec.strip_prefix("sqlite:") {
+        #[cfg(feature = "sqlite")]
+        Some(path) => Ok(Arc::new(Sqlite::open(path)?)),
+        #[cfg(not(feature = "sqlite"))]
+        Some(_) => Err(anyhow!(
+            "storage {} requires the sqlite feature, which this proxy was built without",
+            spec
+        )),
+        None if spec == "memory" => Ok(Arc::new(Memory::default())),
+        None => Err(anyhow!(
+            "invalid storage {}, expected memory or sqlite:<path>",
+            spec
+        )),
+    }
+}
@@ -0,0 +1,220 @@
+//! A SQLite database as storage, through the few functions of the system library it needs.
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_uchar};
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Error};
+use serde_json::Value;
+
+use super::Storage;
+
+#[allow(non_camel_case_types)]
+enum sqlite3 {}
+#[allow(non_camel_case_types)]
+enum sqlite3_stmt {}
+
+const SQLITE_OK: c_int = 0;
+const SQLITE_ROW: c_int = 100;
+const SQLITE_DONE: c_int = 101;
+const SQLITE_OPEN_READWRITE: c_int = 0x2;
+const SQLITE_OPEN_CREATE: c_int = 0x4;
+const SQLITE_OPEN_FULLMUTEX: c_int = 0x10000;
+/// Makes SQLite copy bound values
+const SQLITE_TRANSIENT: isize = -1;
+
+#[link(name = "sqlite3")]
+extern "C" {
+    fn sqlite3_open_v2(
+        filename: *const c_char,
+        db: *mut *mut sqlite3,
+        flags: c_int,
+        vfs: *const c_char,
+    ) -> c_int;
+    fn sqlite3_close(db: *mut sqlite3) -> c_int;
+    fn sqlite3_errmsg(db: *mut sqlite3) -> *const c_char;
+    fn sqlite3_busy_timeout(db: *mut sqlite3, ms: c_int) -> c_int;
+    fn sqlite3_prepare_v2(
+        db: *mut sqlite3,
+        sql: *const c_char,
+        len: c_int,
+        stmt: *mut *mut sqlite3_stmt,
+        tail: *mut *const c_char,
+    ) -> c_int;
+    fn sqlite3_bind_text(
+        stmt: *mut sqlite3_stmt,
+        index: c_int,
+        text: *const c_char,
+        len: c_int,
+        destructor: isize,
+    ) -> c_int;
+    fn sqlite3_step(stmt: *mut sqlite3_stmt) -> c_int;
+    fn sqlite3_column_text(stmt: *mut sqlite3_stmt, column: c_int) -> *const c_uchar;
+    fn sqlite3_column_bytes(stmt: *mut sqlite3_stmt, column: c_int) -> c_int;
+    fn sqlite3_finalize(stmt: *mut sqlite3_stmt) -> c_int;
+}
+
+const SCHEMA: &[&str] = &[
+    "PRAGMA journal_mode = WAL",
+    "PRAGMA synchronous = FULL",
+    "CREATE TABLE IF NOT EXISTS documents (name TEXT PRIMARY KEY, document TEXT NOT NULL)",
+    "CREATE TABLE IF NOT EXISTS logs (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT NOT NULL, record TEXT NOT NULL)",
+    "CREATE INDEX IF NOT EXISTS logs_by_name ON logs (name, id)",
+];
+
+struct Connection(*mut sqlite3);
+// opened with SQLITE_OPEN_FULLMUTEX and only used behind a mutex
+unsafe impl Send for Connection {}
+impl Connection {
+    fn error(&self) -> Error {
+        let message = unsafe { CStr::from_ptr(sqlite3_errmsg(self.0)) };
+        anyhow!("SQLite: {}", message.to_string_lossy())
+    }
+
+    /// Runs `sql` with the text parameters `params`, returning the first column of every row.
+    fn query(&self, sql: &str, params: &[&str]) -> Result<Vec<String>, Error> {
+        let sql = CString::new(sql)?;
+        let mut stmt = std::ptr::null_mut();
+        if unsafe { sqlite3_prepare_v2(self.0, sql.as_ptr(), -1, &mut stmt, std::ptr::null_mut()) }
+            != SQLITE_OK
+        {
+            return Err(self.error());
+        }
+        let stmt = Statement(stmt);
+        for (i, param) in params.iter().enumerate() {
+            let rc = unsafe {
+                sqlite3_bind_text(
+                    stmt.0,
+                    i as c_int + 1,
+                    param.as_ptr() as *const c_char,
+                    param.len() as c_int,
+                    SQLITE_TRANSIENT,
+                )
+            };
+            if rc != SQLITE_OK {
+                return Err(self.error());
+            }
+        }
+        let mut rows = Vec::new();
+        loop {
+            match unsafe { sqlite3_step(stmt.0) } {
+                SQLITE_ROW => unsafe {
+                    let text = sqlite3_column_text(stmt.0, 0);
+                    let len = sqlite3_column_bytes(stmt.0, 0) as usize;
+                    let text = if text.is_null() {
+                        &[][..]
+                    } else {
+                        std::slice::from_raw_parts(text, len)
+                    };
+                    rows.push(String::from_utf8_lossy(text).into_owned());
+                },
+                SQLITE_DONE => return Ok(rows),
+                _ => return Err(self.error()),
+            }
+        }
+    }
+}
+impl Drop for Connection {
+    fn drop(&mut self) {
+        unsafe { sqlite3_close(self.0) };
+    }
+}
+
+struct Statement(*mut sqlite3_stmt);
+impl Drop for Statement {
+    fn drop(&mut self) {
+        unsafe { sqlite3_finalize(self.0) };
+    }
+}
+
+/// Keeps documents and logs in a table each of a SQLite database.
+pub struct Sqlite {
+    path: String,
+    conn: Mutex<Connection>,
+}
+impl std::fmt::Debug for Sqlite {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Sqlite").field("path", &self.path).finish()
+    }
+}
+impl Sqlite {
+    /// Opens the database at `path`, creating it if needed.
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let filename = CString::new(path)?;
+        let mut db = std::ptr::null_mut();
+        let rc = unsafe {
+            sqlite3_open_v2(
+                filename.as_ptr(),
+                &mut db,
+                SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE | SQLITE_OPEN_FULLMUTEX,
+                std::ptr::null(),
+            )
+        };
+        let conn = Connection(db);
+        if rc != SQLITE_OK {
+            return Err(anyhow!(
+                "failed to open {}: {:#}",
+                path,
+                if db.is_null() {
+                    anyhow!("out of memory")
+                } else {
+                    conn.error()
+                }
+            ));
+        }
+        // another process taking over may still be writing
+        unsafe { sqlite3_busy_timeout(conn.0, 5000) };
+        for sql in SCHEMA {
+            conn.query(sql, &[])
+                .map_err(|e| anyhow!("failed to set up {}: {:#}", path, e))?;
+        }
+        Ok(Sqlite {
+            path: path.to_owned(),
+            conn: Mutex::new(conn),
+        })
+    }
+}
+impl Storage for Sqlite {
+    fn load(&self, name: &str) -> Result<Option<Value>, Error> {
+        let rows = self
+            .conn
+            .lock()
+            .unwrap()
+            .query("SELECT document FROM documents WHERE name = ?1", &[name])?;
+        rows.first()
+            .map(|document| {
+                serde_json::from_str(document)
+                    .map_err(|e| anyhow!("document {} in {} is corrupt: {}", name, self.path, e))
+            })
+            .transpose()
+    }
+
+    fn store(&self, name: &str, document: &Value) -> Result<(), Error> {
+        self.conn.lock().unwrap().query(
+            "INSERT OR REPLACE INTO documents (name, document) VALUES (?1, ?2)",
+            &[name, &document.to_string()],
+        )?;
+        Ok(())
+    }
+
+    fn append(&self, name: &str, record: &Value) -> Result<(), Error> {
+        self.conn.lock().unwrap().query(
+            "INSERT INTO logs (name, record) VALUES (?1, ?2)",
+            &[name, &record.to_string()],
+        )?;
+        Ok(())
+    }
+
+    fn records(&self, name: &str) -> Result<Vec<Value>, Error> {
+        let rows = self.conn.lock().unwrap().query(
+            "SELECT record FROM logs WHERE name = ?1 ORDER BY id",
+            &[name],
+        )?;
+        rows.iter()
+            .map(|record| {
+                serde_json::from_str(record)
+                    .map_err(|e| anyhow!("log {} in {} is corrupt: {}", name, self.path, e))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,512 @@
+//! Notifications of new blocks and mempool transactions, sent to clients subscribing over a
+//! WebSocket on `/ws` (with the `websocket` feature) or server-sent events on `/events`.
+//!
+//! Events are queued per connection, at most `subscription_queue` of them. A subscriber reading
+//! slower than events arrive is handled by the configured [`Overflow`] policy, so that neither
+//! its queue grows without bounds nor publishing waits for it.
+
+use std::collections::{HashSet, VecDeque};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+use bitcoin::{hash_types::BlockHash, Txid};
+use futures::stream::StreamExt;
+use serde_json::{json, Value};
+use tokio::sync::Notify;
+
+use crate::client::{GenericRpcMethod, RpcRequest};
+use crate::filters::{Filter, TxInfo};
+use crate::metrics::Metrics;
+use crate::state::State;
+
+#[cfg(feature = "websocket")]
+mod ws;
+#[cfg(feature = "websocket")]
+pub use ws::serve;
+
+/// What to do with an event for a subscriber whose queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Drops the oldest queued event, telling the subscriber how many it missed
+    DropOldest,
+    /// Closes the connection, the subscriber has to catch up with RPC calls
+    Close,
+    /// Replaces queued block events with the new one, as only the latest tip matters to most
+    /// subscribers. Drops the oldest event if no block event is queued.
+    CoalesceBlocks,
+}
+impl FromStr for Overflow {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "drop_oldest" => Ok(Overflow::DropOldest),
+            "close" => Ok(Overflow::Close),
+            "coalesce_blocks" => Ok(Overflow::CoalesceBlocks),
+            s => Err(anyhow!(
+                "unknown overflow policy {:?}, expected drop_oldest, close or coalesce_blocks",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Topic {
+    Block,
+    Tx,
+}
+impl Topic {
+    /// The call users have to be allowed to subscribe to the topic, which they could poll
+    /// instead.
+    pub fn method(self) -> &'static str {
+        match self {
+            Topic::Block => "getbestblockhash",
+            Topic::Tx => "getrawmempool",
+        }
+    }
+}
+impl FromStr for Topic {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "block" => Ok(Topic::Block),
+            "tx" => Ok(Topic::Tx),
+            s => Err(anyhow!("unknown topic {:?}, expected block or tx", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A new best block
+    Block { hash: BlockHash, height: u64 },
+    /// A transaction entered the mempool
+    Tx { txid: Txid },
+    /// Transactions that entered the mempool since the last digest
+    TxDigest { txids: Vec<Txid> },
+}
+impl Event {
+    pub fn topic(&self) -> Topic {
+        match self {
+            Event::Block { .. } => Topic::Block,
+            Event::Tx { .. } | Event::TxDigest { .. } => Topic::Tx,
+        }
+    }
+
+    /// The notification sent for the event.
+    pub fn to_json(&self) -> Value {
+        match self {
+            Event::Block { hash, height } => json!({
+                "method": "block",
+                "params": { "hash": hash, "height": height },
+            }),
+            Event::Tx { txid } => json!({
+                "method": "tx",
+                "params": { "txid": txid },
+            }),
+            Event::TxDigest { txids } => json!({
+                "method": "txs",
+                "params": { "txids": txids },
+            }),
+        }
+    }
+}
+
+/// What a subscriber is sent next.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Delivery {
+    Event(Event),
+    /// This many events were dropped since the last delivery
+    Dropped(u64),
+    /// The queue overflowed with `Overflow::Close`, the connection has to be closed
+    Overflowed,
+}
+
+/// Transactions collected for one digest at most, any more are dropped.
+pub const MAX_DIGEST_TXIDS: usize = 10_000;
+/// Seconds between digests at most.
+pub const MAX_DIGEST_INTERVAL: u64 = 600;
+/// Transactions whose details are fetched at once for filters.
+const MAX_CONCURRENT_FETCHES: usize = 8;
+
+#[derive(Debug, Default)]
+struct Queue {
+    events: VecDeque<Event>,
+    topics: HashSet<Topic>,
+    dropped: u64,
+    overflowed: bool,
+    /// Transactions are collected into a digest sent this often instead of one by one
+    digest: Option<Duration>,
+    digest_txids: Vec<Txid>,
+    /// What transactions have to do to be queued
+    filter: Filter,
+}
+
+/// The events waiting to be sent to one subscriber.
+#[derive(Debug)]
+pub struct Outbox {
+    capacity: usize,
+    overflow: Overflow,
+    queue: Mutex<Queue>,
+    ready: Notify,
+}
+impl Outbox {
+    pub fn new(capacity: usize, overflow: Overflow) -> Self {
+        Outbox {
+            capacity: capacity.max(1),
+            overflow,
+            queue: Mutex::default(),
+            ready: Notify::new(),
+        }
+    }
+
+    /// Starts or stops queueing events of `topic`.
+    pub fn set_subscribed(&self, topic: Topic, subscribed: bool) {
+        let mut queue = self.queue.lock().unwrap();
+        if subscribed {
+            queue.topics.insert(topic);
+        } else {
+            queue.topics.remove(&topic);
+            queue.events.retain(|e| e.topic() != topic);
+            if topic == Topic::Tx {
+                queue.digest_txids.clear();
+                queue.filter = Filter::default();
+            }
+        }
+    }
+
+    pub fn is_subscribed(&self, topic: Topic) -> bool {
+        self.queue.lock().unwrap().topics.contains(&topic)
+    }
+
+    /// Collects transactions into a digest sent every `interval`, or sends them one by one again.
+    /// What was collected is left for the next `flush_digest`.
+    pub fn set_digest(&self, interval: Option<Duration>) {
+        self.queue.lock().unwrap().digest = interval;
+        self.ready.notify();
+    }
+
+    pub fn digest(&self) -> Option<Duration> {
+        self.queue.lock().unwrap().digest
+    }
+
+    /// Only queues transactions passing `filter` from now on.
+    pub fn set_filter(&self, filter: Filter) {
+        self.queue.lock().unwrap().filter = filter;
+    }
+
+    /// Whether transactions are filtered, which needs their details.
+    pub fn is_filtered(&self) -> bool {
+        !self.queue.lock().unwrap().filter.is_empty()
+    }
+
+    /// Queues the transactions collected since the last digest, if any, like `push`.
+    pub fn flush_digest(&self) -> Option<u64> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.digest_txids.is_empty() {
+            return Some(0);
+        }
+        let txids = std::mem::take(&mut queue.digest_txids);
+        self.enqueue(queue, Event::TxDigest { txids })
+    }
+
+    /// Queues `event` if its topic is subscribed, without waiting. Returns how many events were
+    /// dropped to make room, and `None` if the queue overflowed and the subscriber is closed.
+    pub fn push(&self, event: Event) -> Option<u64> {
+        self.push_matching(event, None)
+    }
+
+    /// Like `push`, but a transaction is only queued if it passes the filter given its `info`.
+    pub fn push_matching(&self, event: Event, info: Option<&TxInfo>) -> Option<u64> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.overflowed {
+            return None;
+        }
+        if !queue.topics.contains(&event.topic()) {
+            return Some(0);
+        }
+        if let Event::Tx { .. } = event {
+            if !queue.filter.matches(info) {
+                return Some(0);
+            }
+        }
+        if let (Some(_), Event::Tx { txid }) = (queue.digest, &event) {
+            if queue.digest_txids.len() >= MAX_DIGEST_TXIDS {
+                queue.dropped += 1;
+                return Some(1);
+            }
+            queue.digest_txids.push(*txid);
+            return Some(0);
+        }
+        self.enqueue(queue, event)
+    }
+
+    fn enqueue(&self, mut queue: MutexGuard<Queue>, event: Event) -> Option<u64> {
+        if queue.overflowed {
+            return None;
+        }
+        let mut dropped = 0;
+        if queue.events.len() >= self.capacity {
+            match self.overflow {
+                Overflow::Close => {
+                    queue.overflowed = true;
+                    queue.events.clear();
+                    queue.digest_txids.clear();
+                    drop(queue);
+                    self.ready.notify();
+                    return None;
+                }
+                Overflow::CoalesceBlocks if event.topic() == Topic::Block => {
+                    let len = queue.events.len();
+                    queue.events.retain(|e| e.topic() != Topic::Block);
+                    dropped = (len - queue.events.len()) as u64;
+                }
+                Overflow::CoalesceBlocks | Overflow::DropOldest => (),
+            }
+            if dropped == 0 {
+                queue.events.pop_front();
+                dropped = 1;
+            }
+            queue.dropped += dropped;
+        }
+        queue.events.push_back(event);
+        drop(queue);
+        self.ready.notify();
+        Some(dropped)
+    }
+
+    /// The next delivery if any, a notice of dropped events before the events after them.
+    pub fn pop(&self) -> Option<Delivery> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.overflowed {
+            return Some(Delivery::Overflowed);
+        }
+        if queue.dropped > 0 {
+            return Some(Delivery::Dropped(std::mem::take(&mut queue.dropped)));
+        }
+        queue.events.pop_front().map(Delivery::Event)
+    }
+
+    /// Waits for the next delivery.
+    pub async fn next(&self) -> Delivery {
+        loop {
+            if let Some(delivery) = self.pop() {
+                return delivery;
+            }
+            // a push in between leaves a permit, so this returns right away
+            self.ready.notified().await;
+        }
+    }
+
+    /// Events waiting to be sent.
+    pub fn len(&self) -> usize {
+        self.queue.lock().unwrap().events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The subscribers of this proxy and the new blocks and transactions they are notified of.
+#[derive(Debug)]
+pub struct Subscriptions {
+    /// Events queued per subscriber at most
+    pub queue_size: usize,
+    pub overflow: Overflow,
+    /// How often bitcoind is polled for new blocks and transactions
+    pub poll_interval: Duration,
+    /// Whether messages may be compressed for clients that support it
+    pub compression: bool,
+    outboxes: Mutex<Vec<Weak<Outbox>>>,
+    /// Events dropped for slow subscribers, for metrics
+    pub dropped: AtomicU64,
+    /// Subscribers disconnected for overflowing their queue, for metrics
+    pub overflowed: AtomicU64,
+}
+impl Subscriptions {
+    pub fn new(queue_size: usize, overflow: Overflow, poll_interval: Duration) -> Self {
+        Subscriptions {
+            queue_size,
+            overflow,
+            poll_interval,
+            compression: true,
+            outboxes: Mutex::new(Vec::new()),
+            dropped: AtomicU64::new(0),
+            overflowed: AtomicU64::new(0),
+        }
+    }
+
+    /// Registers a subscriber, until the returned outbox is dropped.
+    pub fn subscribe(&self) -> Arc<Outbox> {
+        let outbox = Arc::new(Outbox::new(self.queue_size, self.overflow));
+        self.outboxes.lock().unwrap().push(Arc::downgrade(&outbox));
+        outbox
+    }
+
+    /// Connected subscribers.
+    pub fn subscribers(&self) -> usize {
+        self.outboxes
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|o| o.strong_count() > 0)
+            .count()
+    }
+
+    /// Whether anyone is subscribed to `topic`, which is only polled if so.
+    pub fn wants(&self, topic: Topic) -> bool {
+        self.outboxes
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .any(|o| o.is_subscribed(topic))
+    }
+
+    /// Whether anyone filters transactions, which are only fetched if so.
+    pub fn wants_details(&self) -> bool {
+        self.outboxes
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .any(|o| o.is_subscribed(Topic::Tx) && o.is_filtered())
+    }
+
+    /// Queues `event` for its subscribers. Never waits for them.
+    pub fn publish(&self, event: Event) {
+        self.publish_matching(event, None)
+    }
+
+    /// Queues `event` for its subscribers whose filters it passes given `info`.
+    pub fn publish_matching(&self, event: Event, info: Option<&TxInfo>) {
+        self.outboxes
+            .lock()
+            .unwrap()
+            .retain(|outbox| match outbox.upgrade() {
+                Some(outbox) => self.count(outbox.push_matching(event.clone(), info)),
+                None => false,
+            });
+    }
+
+    /// Counts the outcome of queueing an event for metrics, false if the subscriber overflowed.
+    fn count(&self, pushed: Option<u64>) -> bool {
+        match pushed {
+            Some(dropped) => {
+                self.dropped.fetch_add(dropped, Ordering::Relaxed);
+                true
+            }
+            None => {
+                Metrics::inc(&self.overflowed);
+                false
+            }
+        }
+    }
+
+    /// Queues the digest of `outbox` and counts it like `publish`.
+    #[cfg(feature = "websocket")]
+    fn flush_digest(&self, outbox: &Outbox) {
+        let mut outboxes = self.outboxes.lock().unwrap();
+        if !self.count(outbox.flush_digest()) {
+            outboxes.retain(|o| !std::ptr::eq(o.as_ptr(), outbox));
+        }
+    }
+
+    async fn poll_tip(state: &State, tip: &mut Option<BlockHash>) -> Result<(), Error> {
+        let hash: BlockHash =
+            serde_json::from_value(call(state, "getbestblockhash", vec![]).await?)?;
+        if *tip == Some(hash) {
+            return Ok(());
+        }
+        // the tip at the first poll is not news
+        if tip.replace(hash).is_some() {
+            let header = call(state, "getblockheader", vec![json!(hash)]).await?;
+            let height = header["height"]
+                .as_u64()
+                .ok_or_else(|| anyhow!("expected the height of block {}", hash))?;
+            state
+                .subscriptions
+                .as_ref()
+                .unwrap()
+                .publish(Event::Block { hash, height });
+        }
+        Ok(())
+    }
+
+    async fn poll_mempool(state: &State, seen: &mut Option<HashSet<Txid>>) -> Result<(), Error> {
+        let txids: HashSet<Txid> =
+            serde_json::from_value(call(state, "getrawmempool", vec![]).await?)?;
+        // the mempool at the first poll is not news
+        if let Some(seen) = seen {
+            let subscriptions = state.subscriptions.as_ref().unwrap();
+            let new: Vec<Txid> = txids
+                .iter()
+                .filter(|txid| !seen.contains(*txid))
+                .copied()
+                .collect();
+            if subscriptions.wants_details() {
+                let mut details = futures::stream::iter(new)
+                    .map(|txid| async move {
+                        // transactions gone from the mempool already only pass empty filters
+                        (txid, TxInfo::fetch(state, txid).await.ok())
+                    })
+                    .buffered(MAX_CONCURRENT_FETCHES);
+                while let Some((txid, info)) = details.next().await {
+                    subscriptions.publish_matching(Event::Tx { txid }, info.as_ref());
+                }
+            } else {
+                for txid in new {
+                    subscriptions.publish(Event::Tx { txid });
+                }
+            }
+        }
+        *seen = Some(txids);
+        Ok(())
+    }
+
+    /// Polls bitcoind for the topics subscribed to, for as long as the proxy runs.
+    pub async fn run(state: Arc<State>) {
+        let subscriptions = match &state.subscriptions {
+            Some(subscriptions) => subscriptions,
+            None => return,
+        };
+        let mut interval = tokio::time::interval(subscriptions.poll_interval);
+        let mut tip = None;
+        let mut mempool = None;
+        loop {
+            interval.tick().await;
+            if subscriptions.wants(Topic::Block) {
+                if let Err(e) = Self::poll_tip(&state, &mut tip).await {
+                    debug!(state.logger, "Failed to poll the tip: {:#}", e);
+                }
+            } else {
+                tip = None;
+            }
+            if subscriptions.wants(Topic::Tx) {
+                if let Err(e) = Self::poll_mempool(&state, &mut mempool).await {
+                    debug!(state.logger, "Failed to poll the mempool: {:#}", e);
+                }
+            } else {
+                mempool = None;
+            }
+        }
+    }
+}
+
+pub(crate) async fn call(state: &State, method: &str, params: Vec<Value>) -> Result<Value, Error> {
+    Ok(state
+        .rpc_client
+        .call(&RpcRequest {
+            id: None,
+            method: GenericRpcMethod(method.to_owned()),
+            params,
+        })
+        .await?
+        .into_result()?)
+}
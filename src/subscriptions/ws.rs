@@ -0,0 +1,332 @@
+//! The WebSocket on `/ws` subscribers connect to.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+use futures::future::{Either, FutureExt};
+use hyper::{http::request::Parts, upgrade::Upgraded, Body, Response, StatusCode};
+use serde_json::{json, Value};
+use tokio::io::{AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::mpsc;
+use tokio::time::{delay_until, Instant};
+
+use super::{Delivery, Outbox, Subscriptions, Topic, MAX_DIGEST_INTERVAL};
+use crate::auth::Authenticated;
+use crate::client::{
+    GenericRpcMethod, RpcError, RpcRequest, RpcResponse, METHOD_NOT_ALLOWED_ERROR_CODE,
+    METHOD_NOT_ALLOWED_ERROR_MESSAGE, METHOD_NOT_FOUND_ERROR_CODE,
+};
+use crate::filters::Filter;
+use crate::state::State;
+use crate::users::User;
+use crate::websocket::{
+    self, Deflate, Message, POLICY_VIOLATION, PROTOCOL_ERROR, UNSUPPORTED_DATA,
+};
+
+/// Answers a `digest` call of the client, `["tx", seconds]` with 0 seconds to stop collecting
+/// transactions into digests, with the seconds.
+fn handle_digest(outbox: &Outbox, params: &[Value]) -> Result<Value, Error> {
+    match params {
+        [topic, seconds] if topic.as_str() == Some("tx") => {
+            let seconds = seconds
+                .as_u64()
+                .filter(|s| *s <= MAX_DIGEST_INTERVAL)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "expected an interval of at most {} seconds",
+                        MAX_DIGEST_INTERVAL
+                    )
+                })?;
+            outbox.set_digest(Some(Duration::from_secs(seconds)).filter(|_| seconds > 0));
+            Ok(json!(seconds))
+        }
+        _ => Err(anyhow!("expected [\"tx\", seconds]")),
+    }
+}
+
+/// Answers a `filter` call of the client, `["tx", filter]` with a null filter to remove it, with
+/// the filter set.
+async fn handle_filter(
+    state: &State,
+    user: &User,
+    outbox: &Outbox,
+    params: &[Value],
+) -> Result<Value, RpcError> {
+    match params {
+        [topic, filter] if topic.as_str() == Some("tx") => {
+            if user.allowed_by(Topic::Tx.method()).is_none() {
+                return Err(RpcError {
+                    code: METHOD_NOT_ALLOWED_ERROR_CODE,
+                    message: METHOD_NOT_ALLOWED_ERROR_MESSAGE.to_owned(),
+                    data: None,
+                    status: None,
+                });
+            }
+            let filter = match filter {
+                Value::Null => Filter::default(),
+                filter => Filter::parse(state, filter).await?,
+            };
+            let json = Some(filter.to_json()).filter(|_| !filter.is_empty());
+            outbox.set_filter(filter);
+            Ok(json.unwrap_or_default())
+        }
+        _ => Err(anyhow!("expected [\"tx\", filter], only transactions can be filtered").into()),
+    }
+}
+
+/// Answers a `subscribe` or `unsubscribe` call of the client with the topics subscribed to, or
+/// a `digest` or `filter` call.
+async fn handle_call(
+    state: &State,
+    user: &User,
+    outbox: &Outbox,
+    text: &str,
+) -> RpcResponse<GenericRpcMethod> {
+    let req: RpcRequest<GenericRpcMethod> = match serde_json::from_str(text) {
+        Ok(req) => req,
+        Err(e) => return RpcResponse::from(RpcError::from(Error::from(e))),
+    };
+    let subscribe = match req.method.0.as_str() {
+        "subscribe" => true,
+        "unsubscribe" => false,
+        "digest" => {
+            let (result, error) = match handle_digest(outbox, &req.params) {
+                Ok(result) => (Some(result), None),
+                Err(e) => (None, Some(e.into())),
+            };
+            return RpcResponse {
+                id: req.id,
+                result,
+                error,
+            };
+        }
+        "filter" => {
+            let (result, error) = match handle_filter(state, user, outbox, &req.params).await {
+                Ok(result) => (Some(result), None),
+                Err(e) => (None, Some(e)),
+            };
+            return RpcResponse {
+                id: req.id,
+                result,
+                error,
+            };
+        }
+        method => {
+            return RpcResponse {
+                id: req.id,
+                result: None,
+                error: Some(RpcError {
+                    code: METHOD_NOT_FOUND_ERROR_CODE,
+                    message: format!(
+                        "unknown method {}, expected subscribe, unsubscribe, digest or filter",
+                        method
+                    ),
+                    data: None,
+                    status: None,
+                }),
+            }
+        }
+    };
+    let topics = req
+        .params
+        .iter()
+        .map(|topic| {
+            topic
+                .as_str()
+                .ok_or_else(|| anyhow!("expected a topic"))?
+                .parse::<Topic>()
+        })
+        .collect::<Result<Vec<_>, _>>();
+    let error = match topics {
+        Ok(topics) => match topics
+            .iter()
+            .find(|t| user.allowed_by(t.method()).is_none())
+        {
+            Some(_) if subscribe => Some(RpcError {
+                code: METHOD_NOT_ALLOWED_ERROR_CODE,
+                message: METHOD_NOT_ALLOWED_ERROR_MESSAGE.to_owned(),
+                data: None,
+                status: None,
+            }),
+            _ => {
+                for topic in topics {
+                    outbox.set_subscribed(topic, subscribe);
+                }
+                None
+            }
+        },
+        Err(e) => Some(e.into()),
+    };
+    let subscribed: Vec<_> = [Topic::Block, Topic::Tx]
+        .iter()
+        .filter(|t| outbox.is_subscribed(**t))
+        .map(|t| match t {
+            Topic::Block => "block",
+            Topic::Tx => "tx",
+        })
+        .collect();
+    RpcResponse {
+        id: req.id,
+        result: Some(json!(subscribed)).filter(|_| error.is_none()),
+        error,
+    }
+}
+
+/// Reads the calls of the client, queueing answers for the writer.
+async fn read_calls(
+    state: &State,
+    user: &User,
+    outbox: &Outbox,
+    mut reader: ReadHalf<Upgraded>,
+    deflate: Option<Deflate>,
+    mut replies: mpsc::Sender<Message>,
+) -> Result<(), Error> {
+    loop {
+        let message = match Message::read(&mut reader, deflate).await {
+            Ok(message) => message,
+            Err(e) => {
+                // control frames carry reasons of 123 bytes at most
+                let reason = e.to_string().chars().take(100).collect();
+                replies
+                    .send(Message::Close(Some((PROTOCOL_ERROR, reason))))
+                    .await?;
+                return Err(e);
+            }
+        };
+        let reply = match message {
+            Message::Text(text) => {
+                let reply = handle_call(state, user, outbox, &text).await;
+                Message::Text(serde_json::to_string(&reply)?)
+            }
+            Message::Ping(data) => Message::Pong(data),
+            Message::Pong(_) => continue,
+            Message::Binary(_) => {
+                Message::Close(Some((UNSUPPORTED_DATA, "expected text".to_owned())))
+            }
+            Message::Close(_) => Message::Close(None),
+        };
+        let closing = matches!(reply, Message::Close(_));
+        replies.send(reply).await?;
+        if closing {
+            return Ok(());
+        }
+    }
+}
+
+/// Sends answers and queued events until the connection is closed, and the digests of the
+/// client when they are due.
+async fn write_deliveries(
+    subscriptions: &Subscriptions,
+    outbox: &Outbox,
+    mut writer: WriteHalf<Upgraded>,
+    deflate: Option<Deflate>,
+    mut replies: mpsc::Receiver<Message>,
+) -> Result<(), Error> {
+    let mut next_digest = None;
+    loop {
+        next_digest = match (outbox.digest(), next_digest) {
+            (Some(interval), None) => Some(Instant::now() + interval),
+            (Some(_), due) => due,
+            (None, _) => {
+                // what was collected before digests were turned off
+                subscriptions.flush_digest(outbox);
+                None
+            }
+        };
+        // the queue only grows while this waits for a slow client
+        while let Some(delivery) = outbox.pop() {
+            let message = match delivery {
+                Delivery::Event(event) => Message::Text(event.to_json().to_string()),
+                Delivery::Dropped(count) => Message::Text(
+                    json!({ "method": "dropped", "params": { "count": count } }).to_string(),
+                ),
+                Delivery::Overflowed => {
+                    let close =
+                        Message::Close(Some((POLICY_VIOLATION, "subscriber too slow".to_owned())));
+                    writer.write_all(&close.encode(None, deflate)).await?;
+                    return Ok(());
+                }
+            };
+            writer.write_all(&message.encode(None, deflate)).await?;
+        }
+        let digest_due = async move {
+            match next_digest {
+                Some(due) => delay_until(due).await,
+                None => futures::future::pending().await,
+            }
+        };
+        tokio::select! {
+            reply = replies.recv() => match reply {
+                Some(reply) => {
+                    writer.write_all(&reply.encode(None, deflate)).await?;
+                    if let Message::Close(_) = reply {
+                        return Ok(());
+                    }
+                }
+                None => return Ok(()),
+            },
+            // a push since the queue was drained left a permit
+            _ = outbox.ready.notified() => (),
+            _ = digest_due => {
+                subscriptions.flush_digest(outbox);
+                next_digest = None;
+            }
+        }
+    }
+}
+
+/// Upgrades the request to a WebSocket delivering the events the client subscribes to.
+pub async fn serve(state: Arc<State>, parts: Parts, body: Body) -> Result<Response<Body>, Error> {
+    let subscriptions = match &state.subscriptions {
+        Some(subscriptions) => subscriptions,
+        None => {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())?)
+        }
+    };
+    let Authenticated { name, user, .. } =
+        match crate::auth::authenticate_request(&state, &parts).await {
+            Ok(auth) => auth,
+            Err(response) => return Ok(response),
+        };
+    let (response, deflate) = match websocket::handshake(&parts, subscriptions.compression) {
+        Some(handshake) => handshake,
+        None => {
+            return Ok(Response::builder()
+                .status(StatusCode::UPGRADE_REQUIRED)
+                .header(hyper::header::UPGRADE, "websocket")
+                .body("expected a WebSocket handshake".into())?)
+        }
+    };
+    let outbox = subscriptions.subscribe();
+    tokio::spawn(async move {
+        let upgraded = match body.on_upgrade().await {
+            Ok(upgraded) => upgraded,
+            Err(e) => {
+                warn!(
+                    state.logger,
+                    "Failed to upgrade connection of {}: {}", name, e
+                );
+                return;
+            }
+        };
+        debug!(state.logger, "{} connected to /ws", name);
+        let (reader, writer) = tokio::io::split(upgraded);
+        let (replies_tx, replies_rx) = mpsc::channel(16);
+        let reading = read_calls(&state, &user, &outbox, reader, deflate, replies_tx).boxed();
+        let subscriptions = state.subscriptions.as_ref().unwrap();
+        let writing = write_deliveries(subscriptions, &outbox, writer, deflate, replies_rx).boxed();
+        // once the client stops sending, the writer finishes sending the last answers
+        let res = match futures::future::select(reading, writing).await {
+            Either::Left((read, writing)) => read.and(writing.await),
+            Either::Right((written, _)) => written,
+        };
+        match res {
+            Ok(()) => debug!(state.logger, "{} disconnected from /ws", name),
+            Err(e) => debug!(state.logger, "{} disconnected from /ws: {:#}", name, e),
+        }
+    });
+    Ok(response)
+}
@@ -0,0 +1,108 @@
+//! Proxy-side state kept on behalf of individual users.
+//!
+//! Every store keeps the entries of each user in a separate namespace and limits how many of
+//! them a single user may occupy, so that one client can neither see nor crowd out the state of
+//! another one.
+
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use futures::future::{BoxFuture, FutureExt};
+use hyper::StatusCode;
+use serde_json::{Map, Value};
+
+use crate::client::{GenericRpcMethod, RpcError, RpcRequest, RpcResponse, MISC_ERROR_CODE};
+use crate::intercept::{InterceptResult, Interceptor, RequestContext};
+use crate::state::State;
+use crate::users::User;
+
+/// A store of proxy-side state with per-user namespaces.
+pub trait TenantStore: Send + Sync {
+    /// Name of the store in reports
+    fn name(&self) -> &'static str;
+    /// What is stored on behalf of `user`
+    fn inspect(&self, user: &str) -> Value;
+    /// Forgets everything stored on behalf of `user`, returning how many entries were removed.
+    fn purge(&self, user: &str) -> usize;
+}
+
+/// The error returned when a user has used up the quota of a store.
+pub fn quota_exceeded(store: &str, quota: usize) -> RpcError {
+    RpcError {
+        code: MISC_ERROR_CODE,
+        message: format!("Quota of {} {} entries per user exceeded", quota, store),
+        data: None,
+        status: Some(StatusCode::TOO_MANY_REQUESTS),
+    }
+}
+
+fn user_param(req: &RpcRequest<GenericRpcMethod>) -> Result<&str, RpcError> {
+    Ok(req
+        .params
+        .first()
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow!("expected a user name"))?)
+}
+
+fn respond(req: &RpcRequest<GenericRpcMethod>, result: Map<String, Value>) -> InterceptResult {
+    Ok(Some(RpcResponse {
+        id: req.id.clone(),
+        result: Some(Value::Object(result)),
+        error: None,
+    }))
+}
+
+/// `proxy_gettenant <user>`: the proxy-side state kept on behalf of a user, per store.
+pub struct GetTenant;
+impl Interceptor for GetTenant {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        _user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        _ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            let user = user_param(req)?;
+            respond(
+                req,
+                state
+                    .tenant_stores()
+                    .into_iter()
+                    .map(|s| (s.name().to_owned(), s.inspect(user)))
+                    .collect(),
+            )
+        }
+        .boxed()
+    }
+}
+
+/// `proxy_purgetenant <user>`: forgets the proxy-side state of a user, returning the number of
+/// removed entries per store.
+pub struct PurgeTenant;
+impl Interceptor for PurgeTenant {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        _user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            let user = user_param(req)?;
+            info!(
+                state.logger,
+                "{} purged the state of {}", ctx.user_name, user
+            );
+            respond(
+                req,
+                state
+                    .tenant_stores()
+                    .into_iter()
+                    .map(|s| (s.name().to_owned(), s.purge(user).into()))
+                    .collect(),
+            )
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,106 @@
+//! How long to wait for peers, depending on how they are reached: a peer on the local network
+//! answers in milliseconds, one reached over Tor may take most of a minute to build a circuit.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+
+/// How a peer is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    /// Private, loopback and link-local addresses
+    Lan,
+    Clearnet,
+    /// Onion addresses, and all peers with `tor_only`
+    Tor,
+}
+impl std::str::FromStr for Transport {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lan" => Ok(Transport::Lan),
+            "clearnet" => Ok(Transport::Clearnet),
+            "tor" => Ok(Transport::Tor),
+            _ => Err(anyhow!(
+                "unknown transport {}, expected lan, clearnet or tor",
+                s
+            )),
+        }
+    }
+}
+impl Transport {
+    /// How `ip` is reached without Tor.
+    pub fn of_ip(ip: IpAddr) -> Self {
+        let local = match ip {
+            IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+            // unique local (fc00::/7) and link-local (fe80::/10) addresses
+            IpAddr::V6(ip) => {
+                ip.is_loopback()
+                    || (ip.segments()[0] & 0xfe00) == 0xfc00
+                    || (ip.segments()[0] & 0xffc0) == 0xfe80
+            }
+        };
+        if local {
+            Transport::Lan
+        } else {
+            Transport::Clearnet
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct TimeoutProfile {
+    /// Seconds to wait for a response
+    pub timeout: u64,
+    /// How often connecting is retried after a failure
+    #[serde(default)]
+    pub retries: u32,
+}
+impl TimeoutProfile {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+/// Timeout profiles of every transport.
+#[derive(Debug)]
+pub struct PeerTimeouts(HashMap<Transport, TimeoutProfile>);
+impl PeerTimeouts {
+    /// Profiles waiting `peer_timeout` seconds for peers on the local network and the clearnet
+    /// and three times as long with one retry over Tor, unless overridden.
+    pub fn new(
+        peer_timeout: u64,
+        overrides: HashMap<String, TimeoutProfile>,
+    ) -> Result<Self, Error> {
+        let clearnet = TimeoutProfile {
+            timeout: peer_timeout,
+            retries: 0,
+        };
+        let mut profiles = HashMap::new();
+        profiles.insert(Transport::Lan, clearnet);
+        profiles.insert(Transport::Clearnet, clearnet);
+        profiles.insert(
+            Transport::Tor,
+            TimeoutProfile {
+                timeout: peer_timeout * 3,
+                retries: 1,
+            },
+        );
+        for (transport, profile) in overrides {
+            profiles.insert(transport.parse()?, profile);
+        }
+        Ok(PeerTimeouts(profiles))
+    }
+
+    pub fn get(&self, transport: Transport) -> TimeoutProfile {
+        self.0[&transport]
+    }
+
+    /// All profiles, for reports.
+    pub fn all(&self) -> &HashMap<Transport, TimeoutProfile> {
+        &self.0
+    }
+}
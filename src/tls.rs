@@ -0,0 +1,161 @@
+//! TLS termination with optional client certificates.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Error};
+use bitcoin::hashes::{sha256, Hash};
+use futures::stream::{Stream, StreamExt};
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{
+    AllowAnyAuthenticatedClient, Certificate, ClientCertVerified, ClientCertVerifier,
+    DistinguishedNames, RootCertStore, ServerConfig, Session, TLSError,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use crate::users::ClientCert;
+
+/// Requests a certificate from every client without verifying it during the handshake, so that
+/// both self-signed certificates (identified by fingerprint) and ones issued by the configured CA
+/// are accepted. Clients without a certificate may still use basic auth.
+struct AnyClientCert;
+impl ClientCertVerifier for AnyClientCert {
+    fn client_auth_mandatory(&self, _sni: Option<&webpki::DNSName>) -> Option<bool> {
+        Some(false)
+    }
+    fn client_auth_root_subjects(
+        &self,
+        _sni: Option<&webpki::DNSName>,
+    ) -> Option<DistinguishedNames> {
+        Some(Vec::new())
+    }
+    fn verify_client_cert(
+        &self,
+        _presented_certs: &[Certificate],
+        _sni: Option<&webpki::DNSName>,
+    ) -> Result<ClientCertVerified, TLSError> {
+        Ok(ClientCertVerified::assertion())
+    }
+}
+
+/// TLS settings of the listener.
+pub struct Tls {
+    acceptor: TlsAcceptor,
+    /// Verifies certificates of clients identified by subject
+    client_ca: Option<Arc<dyn ClientCertVerifier>>,
+}
+impl Tls {
+    /// Serves the certificate chain in `cert` with `key`, offering HTTP/2 in ALPN if `http2`.
+    pub fn new(
+        cert: &Path,
+        key: &Path,
+        client_ca: Option<&Path>,
+        http2: bool,
+    ) -> Result<Self, Error> {
+        let chain = certs(&mut BufReader::new(File::open(cert)?))
+            .map_err(|_| anyhow!("invalid certificate in {}", cert.display()))?;
+        let mut keys = pkcs8_private_keys(&mut BufReader::new(File::open(key)?))
+            .map_err(|_| anyhow!("invalid key in {}", key.display()))?;
+        if keys.is_empty() {
+            keys = rsa_private_keys(&mut BufReader::new(File::open(key)?))
+                .map_err(|_| anyhow!("invalid key in {}", key.display()))?;
+        }
+        let key = keys
+            .pop()
+            .ok_or_else(|| anyhow!("no private key in {}", key.display()))?;
+        let mut config = ServerConfig::new(Arc::new(AnyClientCert));
+        config.set_single_cert(chain, key)?;
+        if http2 {
+            config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+        } else {
+            config.set_protocols(&[b"http/1.1".to_vec()]);
+        }
+        let client_ca = match client_ca {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                let (added, _) = roots
+                    .add_pem_file(&mut BufReader::new(File::open(path)?))
+                    .map_err(|_| anyhow!("invalid certificate in {}", path.display()))?;
+                if added == 0 {
+                    return Err(anyhow!("no CA certificate in {}", path.display()));
+                }
+                Some(AllowAnyAuthenticatedClient::new(roots))
+            }
+            None => None,
+        };
+        Ok(Tls {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            client_ca,
+        })
+    }
+
+    /// The identity of the client of a connection, if it presented a certificate.
+    pub fn client_cert(&self, stream: &TlsStream<TcpStream>) -> Option<ClientCert> {
+        let chain = stream.get_ref().1.get_peer_certificates()?;
+        let leaf = chain.first()?;
+        let subject = self
+            .client_ca
+            .as_ref()
+            .filter(|ca| ca.verify_client_cert(&chain, None).is_ok())
+            .and_then(|_| x509_parser::parse_x509_certificate(&leaf.0).ok())
+            .map(|(_, cert)| cert.subject().to_string());
+        Some(ClientCert {
+            fingerprint: sha256::Hash::hash(&leaf.0).to_string(),
+            subject,
+        })
+    }
+
+    /// Accepts connections, performing handshakes concurrently so that a slow client doesn't
+    /// hold up others. Failed handshakes are dropped.
+    pub fn incoming(
+        self: Arc<Self>,
+        mut listener: TcpListener,
+        logger: slog::Logger,
+    ) -> impl Stream<Item = Result<TlsStream<TcpStream>, std::io::Error>> {
+        let (send, recv) = futures::channel::mpsc::channel(16);
+        // stops accepting once the server drops the stream, e.g. after a takeover
+        let (mut stop, stopped) = futures::channel::oneshot::channel::<()>();
+        tokio::spawn(async move {
+            loop {
+                let accepted = tokio::select! {
+                    accepted = listener.accept() => accepted,
+                    _ = stop.cancellation() => break,
+                };
+                let (stream, addr): (TcpStream, SocketAddr) = match accepted {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        warn!(logger, "Failed to accept connection: {}", e);
+                        continue;
+                    }
+                };
+                let acceptor = self.acceptor.clone();
+                let mut send = send.clone();
+                let logger = logger.clone();
+                tokio::spawn(async move {
+                    match acceptor.accept(stream).await {
+                        Ok(stream) => {
+                            let _ = futures::SinkExt::send(&mut send, Ok(stream)).await;
+                        }
+                        Err(e) => debug!(logger, "TLS handshake with {} failed: {}", addr, e),
+                    }
+                });
+            }
+        });
+        recv.map(move |stream| {
+            let _ = &stopped;
+            stream
+        })
+    }
+}
+impl std::fmt::Debug for Tls {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Tls")
+            .field("client_ca", &self.client_ca.is_some())
+            .finish()
+    }
+}
@@ -0,0 +1,238 @@
+//! Short-lived bearer tokens issued by the proxy, e.g. for a script that should only work for
+//! the next hour. They are kept in memory, so restarting the proxy revokes them.
+//!
+//! Their lifetime is measured with the monotonic clock, so stepping the system clock neither
+//! expires them early nor keeps them alive longer. The expiry announced to clients is only the
+//! wall clock time it corresponds to at issuance.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration as StdDuration;
+
+use anyhow::{anyhow, Error};
+use bitcoin::hashes::{sha256, Hash};
+use chrono::{DateTime, Duration, Utc};
+use futures::future::{BoxFuture, FutureExt};
+use serde_json::{json, Value};
+use tokio::time::Instant;
+
+use crate::auth::Authenticated;
+use crate::client::{GenericRpcMethod, RpcRequest, RpcResponse};
+use crate::clock;
+use crate::intercept::{InterceptResult, Interceptor, RequestContext};
+use crate::state::State;
+use crate::users::{User, Users, CREDENTIAL_FIELDS};
+
+/// Lifetime of tokens if `proxy_issuetoken` isn't given one.
+const DEFAULT_TTL: i64 = 3600;
+
+/// Longer lifetimes are cut to this, which is forever for tokens kept in memory.
+const MAX_TTL: StdDuration = StdDuration::from_secs(100 * 365 * 24 * 3600);
+
+/// How long expired tokens are remembered, so that clients using them get a clear error.
+const KEEP_EXPIRED: StdDuration = StdDuration::from_secs(24 * 3600);
+
+#[derive(Debug)]
+struct Token {
+    name: String,
+    /// The configured user whose permissions the token carries
+    profile: String,
+    /// The expiry announced at issuance
+    expires: DateTime<Utc>,
+    /// When the token expires, on the monotonic clock
+    deadline: Instant,
+    /// The expiry of the credentials it was issued with, a date on the wall clock
+    not_after: Option<DateTime<Utc>>,
+    /// The user the token was derived from and the user without its credentials
+    derived: Option<(Arc<User>, Arc<User>)>,
+    /// The user and id of the API key the token was issued with, which revoking revokes it
+    api_key: Option<(String, String)>,
+}
+
+#[derive(Debug, Default)]
+pub struct Tokens(Mutex<HashMap<sha256::Hash, Token>>);
+impl Tokens {
+    /// Issues a token for the client `name` with the permissions of the configured user
+    /// `profile`, valid for `ttl` but not after `not_after` nor once the API key `api_key` (its
+    /// user and id) it was issued with is revoked, returning it and the wall clock time it expires
+    /// at.
+    pub fn issue(
+        &self,
+        name: &str,
+        profile: &str,
+        ttl: StdDuration,
+        not_after: Option<DateTime<Utc>>,
+        api_key: Option<(String, String)>,
+    ) -> (String, DateTime<Utc>) {
+        let token = hex::encode(rand::random::<[u8; 32]>());
+        let ttl = ttl.min(MAX_TTL);
+        let now = Instant::now();
+        let expires = clock::now() + Duration::from_std(ttl).unwrap();
+        let expires = not_after.map_or(expires, |e| e.min(expires));
+        let mut tokens = self.0.lock().unwrap();
+        tokens.retain(|_, t| now.saturating_duration_since(t.deadline) < KEEP_EXPIRED);
+        tokens.insert(
+            sha256::Hash::hash(token.as_bytes()),
+            Token {
+                name: name.to_owned(),
+                profile: profile.to_owned(),
+                expires,
+                deadline: now + ttl,
+                not_after,
+                derived: None,
+                api_key,
+            },
+        );
+        (token, expires)
+    }
+
+    /// The client a token was issued for, with the permissions of its profile without
+    /// credentials and expired if the token is, `None` if the token is unknown, its profile was
+    /// removed or the API key it was issued with revoked.
+    pub fn get(&self, users: &Users, token: &str) -> Option<Authenticated> {
+        let key = sha256::Hash::hash(token.as_bytes());
+        let (name, profile, expires, deadline, not_after, derived, api_key) = {
+            let tokens = self.0.lock().unwrap();
+            let token = tokens.get(&key)?;
+            (
+                token.name.clone(),
+                token.profile.clone(),
+                token.expires,
+                token.deadline,
+                token.not_after,
+                token.derived.clone(),
+                token.api_key.clone(),
+            )
+        };
+        let user = users.by_name(&profile)?;
+        let api_key = match api_key {
+            Some((owner, id)) => {
+                let owner = users.by_name(&owner)?;
+                owner.api_key.iter().find(|k| k.id == id && !k.revoked)?;
+                Some(id)
+            }
+            None => None,
+        };
+        let authenticated = |user| Authenticated {
+            api_key: api_key.clone(),
+            ..Authenticated::mapped(name.clone(), profile.clone(), user)
+        };
+        if Instant::now() >= deadline {
+            // expired as of now even if the wall clock was stepped back since
+            let expired = expires.min(clock::now());
+            let user = Arc::new(derive(&user, Some(expired)).ok()?);
+            return Some(authenticated(user));
+        }
+        let derived = match derived {
+            Some((from, derived)) if Arc::ptr_eq(&from, &user) => derived,
+            _ => {
+                let derived = Arc::new(derive(&user, not_after).ok()?);
+                if let Some(token) = self.0.lock().unwrap().get_mut(&key) {
+                    token.derived = Some((user, derived.clone()));
+                }
+                derived
+            }
+        };
+        Some(authenticated(derived))
+    }
+}
+
+/// `user` without credentials, expiring at `expires` if given and before its own expiry.
+fn derive(user: &User, expires: Option<DateTime<Utc>>) -> Result<User, Error> {
+    let mut value = serde_json::to_value(user)?;
+    let fields = value.as_object_mut().unwrap();
+    for field in CREDENTIAL_FIELDS {
+        fields.remove(*field);
+    }
+    if let Some(expires) = expires {
+        let expires = user.expires.map_or(expires, |e| e.min(expires));
+        fields.insert("expires".to_owned(), expires.to_rfc3339().into());
+    }
+    User::from_value(value)
+}
+
+/// `proxy_issuetoken [seconds] [user]`: issues a bearer token for the caller, or with
+/// `manage_users` for another user, valid for an hour by default. Tokens never outlive the
+/// credentials they were issued with.
+pub struct IssueToken;
+impl Interceptor for IssueToken {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            let ttl = match req.params.first() {
+                None | Some(Value::Null) => DEFAULT_TTL,
+                Some(ttl) => ttl
+                    .as_i64()
+                    .filter(|ttl| *ttl > 0)
+                    .ok_or_else(|| anyhow!("expected a positive number of seconds"))?,
+            };
+            // clients of other backends, OpenID Connect and anonymous clients are known by
+            // names that aren't configured users, or even are another configured user
+            if ctx.user_name != ctx.profile {
+                return Err(anyhow!(
+                    "tokens can only be issued to configured users, not to {} (user {})",
+                    ctx.user_name,
+                    ctx.profile
+                )
+                .into());
+            }
+            let name = match req.params.get(1).and_then(Value::as_str) {
+                Some(name) if name != ctx.user_name => {
+                    if !user.manage_users {
+                        return Err(anyhow!(
+                            "issuing tokens for other users requires manage_users"
+                        )
+                        .into());
+                    }
+                    if state.users.by_name(name).is_none() {
+                        return Err(anyhow!("unknown user {}", name).into());
+                    }
+                    name
+                }
+                _ => &*ctx.user_name,
+            };
+            // the API key belongs to whoever authenticated, the admin if impersonating
+            let api_key = ctx
+                .api_key
+                .as_ref()
+                .map(|id| (ctx.principal().to_owned(), id.clone()));
+            let key_expires = api_key.as_ref().and_then(|(owner, id)| {
+                let owner = state.users.by_name(owner)?;
+                owner.api_key.iter().find(|k| k.id == *id)?.expires
+            });
+            let not_after = match (user.expires, key_expires) {
+                (Some(user), Some(key)) => Some(user.min(key)),
+                (user, key) => user.or(key),
+            };
+            let (token, expires) = state.tokens.issue(
+                name,
+                name,
+                StdDuration::from_secs(ttl as u64),
+                not_after,
+                api_key,
+            );
+            info!(
+                state.logger,
+                "{} issued a token for {} expiring at {}",
+                ctx.caller(),
+                name,
+                expires.to_rfc3339()
+            );
+            Ok(Some(RpcResponse {
+                id: req.id.clone(),
+                result: Some(json!({
+                    "token": token,
+                    "user": name,
+                    "expires": expires.to_rfc3339(),
+                })),
+                error: None,
+            }))
+        }
+        .boxed()
+    }
+}
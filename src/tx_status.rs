@@ -0,0 +1,184 @@
+//! `proxy_gettxstatuses`, the confirmation status of many transactions in one call, for
+//! watchtowers and similar services checking their transactions on every block.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use bitcoin::{BlockHash, Txid};
+use futures::future::{BoxFuture, FutureExt};
+use serde_json::{json, Value};
+
+use crate::cache::{CacheInfo, CacheStats, MemoryBudget, SizedCache};
+use crate::client::{GenericRpcMethod, RpcRequest, RpcResponse};
+use crate::intercept::{InterceptResult, Interceptor, RequestContext};
+use crate::state::State;
+use crate::users::User;
+
+/// Most transactions a single call may ask about.
+pub const MAX_TXIDS: usize = 1000;
+
+/// bitcoind's error code for unknown transactions.
+const INVALID_ADDRESS_OR_KEY_ERROR_CODE: i64 = -5;
+
+/// Statuses of confirmed transactions with the tip they were looked up at, since their number of
+/// confirmations changes with every block.
+#[derive(Debug)]
+pub struct TxStatusCache(SizedCache<Txid, (BlockHash, Value)>);
+impl TxStatusCache {
+    pub fn new(budget: Arc<MemoryBudget>) -> Self {
+        TxStatusCache(SizedCache::new("txstatus", budget))
+    }
+
+    fn get(&self, txid: &Txid, tip: &BlockHash) -> Option<Value> {
+        self.0
+            .get(txid)
+            .filter(|entry| entry.0 == *tip)
+            .map(|entry| entry.1.clone())
+    }
+}
+impl CacheInfo for TxStatusCache {
+    fn name(&self) -> &'static str {
+        self.0.name()
+    }
+    fn stats(&self) -> &CacheStats {
+        self.0.stats()
+    }
+    fn bytes(&self) -> usize {
+        self.0.bytes()
+    }
+    fn entries(&self) -> usize {
+        self.0.entries()
+    }
+    fn clear(&self) -> usize {
+        self.0.clear()
+    }
+    fn depends_on_tip(&self) -> bool {
+        true
+    }
+}
+
+/// A transaction to look up, with the block containing it if the client knows it.
+fn parse_query(value: &Value) -> Option<(Txid, Option<BlockHash>)> {
+    match value {
+        Value::String(txid) => Some((txid.parse().ok()?, None)),
+        Value::Object(query) => Some((
+            query.get("txid")?.as_str()?.parse().ok()?,
+            match query.get("blockhash") {
+                None | Some(Value::Null) => None,
+                Some(hash) => Some(hash.as_str()?.parse().ok()?),
+            },
+        )),
+        _ => None,
+    }
+}
+
+/// The status of `txid` from the response of `getrawtransaction` with `verbose`.
+fn status(state: &State, txid: &Txid, response: RpcResponse<GenericRpcMethod>) -> Value {
+    match response.into_result() {
+        Ok(tx) => match tx.get("blockhash") {
+            Some(hash) => json!({
+                "txid": txid,
+                "status": "confirmed",
+                "blockhash": hash,
+                "confirmations": tx.get("confirmations"),
+                "blocktime": tx.get("blocktime"),
+            }),
+            None => json!({ "txid": txid, "status": "mempool" }),
+        },
+        Err(e) if e.code == INVALID_ADDRESS_OR_KEY_ERROR_CODE => {
+            let txindex = state.capabilities.get().and_then(|f| f.txindex());
+            json!({
+                "txid": txid,
+                "status": "unknown",
+                "reason": if txindex == Some(false) {
+                    "not in the mempool, and bitcoind has no txindex to look it up: pass the \
+                     hash of the block containing it"
+                } else {
+                    "not in the mempool or the blockchain"
+                },
+            })
+        }
+        Err(e) => json!({ "txid": txid, "status": "error", "reason": e.message }),
+    }
+}
+
+/// `proxy_gettxstatuses <txids>`: whether each transaction is confirmed (with its block and
+/// number of confirmations), in the mempool or unknown. Transactions may be given as
+/// `{"txid": ..., "blockhash": ...}` to find them without txindex. Missing statuses are looked
+/// up in a single batch sent to bitcoind.
+pub struct GetTxStatuses;
+impl Interceptor for GetTxStatuses {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        _user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        _ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            let queries = req
+                .params
+                .first()
+                .and_then(Value::as_array)
+                .ok_or_else(|| anyhow!("expected an array of txids"))?;
+            if queries.len() > MAX_TXIDS {
+                return Err(anyhow!("at most {} txids may be given", MAX_TXIDS).into());
+            }
+            let queries = queries
+                .iter()
+                .map(|q| parse_query(q).ok_or_else(|| anyhow!("invalid txid {}", q)))
+                .collect::<Result<Vec<_>, _>>()?;
+            let tip = state.cache_sync.tip();
+            let mut statuses: HashMap<Txid, Value> = HashMap::new();
+            let mut missing = Vec::new();
+            let mut seen = HashSet::new();
+            for (txid, block_hash) in &queries {
+                if !seen.insert(txid) {
+                    continue;
+                }
+                match tip.and_then(|tip| state.tx_statuses.get(txid, &tip)) {
+                    Some(status) => {
+                        statuses.insert(*txid, status);
+                    }
+                    None => missing.push((*txid, *block_hash)),
+                }
+            }
+            let batch: Vec<_> = missing
+                .iter()
+                .enumerate()
+                .map(|(id, (txid, block_hash))| RpcRequest {
+                    id: Some(id.into()),
+                    method: GenericRpcMethod("getrawtransaction".to_owned()),
+                    params: match block_hash {
+                        Some(hash) => vec![json!(txid), true.into(), json!(hash)],
+                        None => vec![json!(txid), true.into()],
+                    },
+                })
+                .collect();
+            let responses = state.rpc_client.call_batch(&batch).await?;
+            let unchanged = tip.filter(|tip| state.cache_sync.tip() == Some(*tip));
+            for ((txid, _), response) in missing.iter().zip(responses) {
+                let status = status(&state, txid, response);
+                if let Some(tip) = unchanged.filter(|_| status["status"] == "confirmed") {
+                    let size = status.to_string().len();
+                    state
+                        .tx_statuses
+                        .0
+                        .insert(*txid, (tip, status.clone()), size);
+                }
+                statuses.insert(*txid, status);
+            }
+            let result: Vec<Value> = queries
+                .iter()
+                .map(|(txid, _)| statuses[txid].clone())
+                .collect();
+            Ok(Some(RpcResponse {
+                id: req.id.clone(),
+                result: Some(result.into()),
+                error: None,
+            }))
+        }
+        .boxed()
+    }
+}
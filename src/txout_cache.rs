@@ -0,0 +1,339 @@
+//! Cache of `gettxout` responses, which Lightning nodes call at high rates to check that their
+//! channels are still open.
+//!
+//! Entries are tagged with the tip they were valid at and only served while it's still the best
+//! block. Since `gettxout` also looks into the mempool by default, a mirror of the mempool drops
+//! the entries of outputs spent or created by transactions entering or leaving it.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Error};
+use bitcoin::{BlockHash, OutPoint, Transaction, Txid};
+use futures::future::{BoxFuture, FutureExt};
+use serde_json::Value;
+
+use crate::cache::{CacheInfo, CacheStats, MemoryBudget};
+use crate::client::{GenericRpcMethod, RpcRequest, RpcResponse};
+use crate::intercept::{InterceptResult, Interceptor, RequestContext};
+use crate::state::State;
+use crate::users::User;
+
+/// Output and whether the mempool is included, as in the parameters of `gettxout`.
+type Key = (Txid, u32, bool);
+
+#[derive(Debug)]
+struct Entry {
+    tip: BlockHash,
+    value: Value,
+    size: usize,
+    seq: u64,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    map: HashMap<Key, Entry>,
+    /// Keys in insertion order, oldest first, with the sequence number they were inserted with
+    order: VecDeque<(Key, u64)>,
+    seq: u64,
+    bytes: usize,
+}
+
+/// What the mirror knows about a transaction in the mempool.
+#[derive(Debug)]
+struct MempoolTx {
+    inputs: Vec<OutPoint>,
+    outputs: u32,
+}
+
+#[derive(Debug)]
+pub struct TxOutCache {
+    budget: Arc<MemoryBudget>,
+    entries: Mutex<Entries>,
+    stats: CacheStats,
+    /// Transactions in the mempool when it was last polled, `None` for those that couldn't be
+    /// fetched. `None` until the first poll.
+    mempool: Mutex<Option<HashMap<Txid, Option<MempoolTx>>>>,
+}
+impl TxOutCache {
+    pub fn new(budget: Arc<MemoryBudget>) -> Self {
+        TxOutCache {
+            budget,
+            entries: Mutex::new(Entries::default()),
+            stats: CacheStats::default(),
+            mempool: Mutex::new(None),
+        }
+    }
+
+    fn get(&self, key: &Key, tip: &BlockHash) -> Option<Value> {
+        let entries = self.entries.lock().unwrap();
+        match entries.map.get(key).filter(|e| e.tip == *tip) {
+            Some(entry) => {
+                self.stats.hits.fetch_add(1, Ordering::Relaxed);
+                Some(entry.value.clone())
+            }
+            None => {
+                self.stats.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Caches `value`, evicting the oldest entries to make room for it.
+    fn insert(&self, key: Key, tip: BlockHash, value: Value) {
+        let size = std::mem::size_of::<(Key, Entry)>() + value.to_string().len();
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(old) = entries.map.remove(&key) {
+            entries.bytes -= old.size;
+            self.budget.release(old.size);
+        }
+        while !self.budget.try_reserve(size) {
+            let (oldest, seq) = match entries.order.pop_front() {
+                Some(oldest) => oldest,
+                None => return,
+            };
+            if entries.map.get(&oldest).is_some_and(|e| e.seq == seq) {
+                let evicted = entries.map.remove(&oldest).unwrap();
+                entries.bytes -= evicted.size;
+                self.budget.release(evicted.size);
+                self.stats.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        entries.seq += 1;
+        let seq = entries.seq;
+        entries.order.push_back((key, seq));
+        entries.bytes += size;
+        entries.map.insert(
+            key,
+            Entry {
+                tip,
+                value,
+                size,
+                seq,
+            },
+        );
+    }
+
+    /// Drops the entries of outputs whose state in the mempool may have changed, returning how
+    /// many there were.
+    fn invalidate(&self, outputs: impl IntoIterator<Item = OutPoint>) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let mut cleared = 0;
+        for output in outputs {
+            if let Some(entry) = entries.map.remove(&(output.txid, output.vout, true)) {
+                entries.bytes -= entry.size;
+                self.budget.release(entry.size);
+                cleared += 1;
+            }
+        }
+        if entries.order.len() > 2 * entries.map.len() + 1024 {
+            let Entries { map, order, .. } = &mut *entries;
+            order.retain(|(key, seq)| map.get(key).is_some_and(|e| e.seq == *seq));
+        }
+        cleared
+    }
+
+    /// Drops all entries including the mempool.
+    fn invalidate_mempool(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let keys: Vec<_> = entries.map.keys().filter(|k| k.2).copied().collect();
+        for key in &keys {
+            let entry = entries.map.remove(key).unwrap();
+            entries.bytes -= entry.size;
+            self.budget.release(entry.size);
+        }
+        keys.len()
+    }
+
+    async fn fetch_tx(state: &State, txid: &Txid) -> Result<MempoolTx, Error> {
+        let hex = state
+            .rpc_client
+            .call(&RpcRequest {
+                id: None,
+                method: GenericRpcMethod("getrawtransaction".to_owned()),
+                params: vec![txid.to_string().into()],
+            })
+            .await?
+            .into_result()?;
+        let hex = hex
+            .as_str()
+            .ok_or_else(|| anyhow!("expected a transaction in hex"))?;
+        let tx: Transaction = bitcoin::consensus::deserialize(&hex::decode(hex)?)?;
+        Ok(MempoolTx {
+            inputs: tx.input.iter().map(|i| i.previous_output).collect(),
+            outputs: tx.output.len() as u32,
+        })
+    }
+
+    /// Compares the mempool with the one seen last time, dropping the entries of outputs spent
+    /// or created by transactions that entered or left it.
+    async fn poll_mempool(&self, state: &State) -> Result<(), Error> {
+        let txids = state
+            .rpc_client
+            .call(&RpcRequest {
+                id: None,
+                method: GenericRpcMethod("getrawmempool".to_owned()),
+                params: Vec::new(),
+            })
+            .await?
+            .into_result()?;
+        let txids: HashSet<Txid> = serde_json::from_value(txids)?;
+        let (first, added): (bool, Vec<Txid>) = match &*self.mempool.lock().unwrap() {
+            None => (true, txids.iter().copied().collect()),
+            Some(mempool) => (
+                false,
+                txids
+                    .iter()
+                    .filter(|txid| !mempool.contains_key(*txid))
+                    .copied()
+                    .collect(),
+            ),
+        };
+        let mut fetched = Vec::with_capacity(added.len());
+        for txid in added {
+            // Entries cached before the first poll are dropped below, no need to look at the
+            // whole mempool.
+            let tx = if first {
+                None
+            } else {
+                Self::fetch_tx(state, &txid).await.ok()
+            };
+            fetched.push((txid, tx));
+        }
+        let mut mempool = self.mempool.lock().unwrap();
+        let mempool = mempool.get_or_insert_with(HashMap::new);
+        let removed: Vec<Txid> = mempool
+            .keys()
+            .filter(|txid| !txids.contains(*txid))
+            .copied()
+            .collect();
+        let removed: Vec<_> = removed
+            .into_iter()
+            .map(|txid| (txid, mempool.remove(&txid).unwrap()))
+            .collect();
+        // Without the details of a transaction, any output may be affected
+        let mut unknown = first;
+        let mut outputs = Vec::new();
+        for (txid, tx) in removed.iter().chain(&fetched) {
+            match tx {
+                Some(tx) => {
+                    outputs.extend(tx.inputs.iter().copied());
+                    outputs.extend((0..tx.outputs).map(|vout| OutPoint { txid: *txid, vout }));
+                }
+                None => unknown = true,
+            }
+        }
+        mempool.extend(fetched);
+        let cleared = if unknown {
+            self.invalidate_mempool()
+        } else {
+            self.invalidate(outputs)
+        };
+        if cleared > 0 {
+            debug!(
+                state.logger,
+                "Mempool changes invalidated {} cached outputs", cleared
+            );
+        }
+        Ok(())
+    }
+
+    /// Mirrors the mempool every `tip_poll_interval`, for as long as the proxy runs.
+    pub async fn run(state: Arc<State>) {
+        let cache = match &state.txout_cache {
+            Some(cache) => cache,
+            None => return,
+        };
+        if state.cache_sync.tip_interval.as_secs() == 0 {
+            return;
+        }
+        let mut interval = tokio::time::interval(state.cache_sync.tip_interval);
+        loop {
+            interval.tick().await;
+            if let Err(e) = cache.poll_mempool(&state).await {
+                debug!(state.logger, "Failed to mirror the mempool: {:#}", e);
+            }
+        }
+    }
+}
+impl CacheInfo for TxOutCache {
+    fn name(&self) -> &'static str {
+        "txout"
+    }
+    fn stats(&self) -> &CacheStats {
+        &self.stats
+    }
+    fn bytes(&self) -> usize {
+        self.entries.lock().unwrap().bytes
+    }
+    fn entries(&self) -> usize {
+        self.entries.lock().unwrap().map.len()
+    }
+    fn clear(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        self.budget.release(entries.bytes);
+        let cleared = entries.map.len();
+        *entries = Entries::default();
+        cleared
+    }
+    fn depends_on_tip(&self) -> bool {
+        true
+    }
+}
+
+fn key(req: &RpcRequest<GenericRpcMethod>) -> Option<Key> {
+    let txid = req.params.first()?.as_str()?.parse().ok()?;
+    let vout = req.params.get(1)?.as_u64()?;
+    let include_mempool = match req.params.get(2) {
+        None | Some(Value::Null) => true,
+        Some(v) => v.as_bool()?,
+    };
+    Some((txid, vout as u32, include_mempool))
+}
+
+/// Serves `gettxout` from the cache while the tip it was answered at is still the best block.
+pub struct GetTxOut;
+impl Interceptor for GetTxOut {
+    fn intercept<'a>(
+        &'a self,
+        state: Arc<State>,
+        _user: &'a User,
+        req: &'a RpcRequest<GenericRpcMethod>,
+        _ctx: &'a RequestContext,
+    ) -> BoxFuture<'a, InterceptResult> {
+        async move {
+            let cache = match &state.txout_cache {
+                Some(cache) => cache,
+                None => return Ok(None),
+            };
+            let (key, tip) = match (key(req), state.cache_sync.tip()) {
+                (Some(key), Some(tip)) => (key, tip),
+                _ => return Ok(None),
+            };
+            if let Some(value) = cache.get(&key, &tip) {
+                return Ok(Some(RpcResponse {
+                    id: req.id.clone(),
+                    result: Some(value),
+                    error: None,
+                }));
+            }
+            let response = state.rpc_client.call(req).await?;
+            if let (Some(result), None) = (&response.result, &response.error) {
+                // Spent and unknown outputs have no `bestblock` to tell when they were looked up
+                let valid_at = match result {
+                    Value::Null => Some(tip),
+                    result => result
+                        .get("bestblock")
+                        .and_then(Value::as_str)
+                        .and_then(|hash| hash.parse().ok()),
+                };
+                if valid_at == Some(tip) && state.cache_sync.tip() == Some(tip) {
+                    cache.insert(key, tip, result.clone());
+                }
+            }
+            Ok(Some(response))
+        }
+        .boxed()
+    }
+}
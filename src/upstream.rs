@@ -0,0 +1,290 @@
+//! Connections to bitcoind: plain TCP, a Unix socket for a node that doesn't listen on the
+//! network or, with the `tls` feature, TLS for a bitcoind behind stunnel or nginx on another host.
+//! The Esplora server of `esplora_url` is reached the same way.
+//!
+//! Connections always go to the configured address. The host of the request URI is only the name
+//! the certificate of bitcoind is verified against (and sent in SNI and `Host`), so that it can
+//! differ from what the address resolves to.
+//!
+//! TCP connections can go through an egress proxy, see [`tunnel`](crate::tunnel). bitcoind may
+//! be given by a host name instead, looked up for every new connection with a
+//! [`Resolver`](crate::resolver::Resolver) that need not be the one of the system.
+
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::future::{BoxFuture, FutureExt};
+use hyper::client::connect::{Connected, Connection};
+use hyper::service::Service;
+use hyper::Uri;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+use crate::resolver::Resolver;
+use crate::tunnel::{Destination, Proxy};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+pub enum Stream {
+    Plain(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<tokio_rustls::client::TlsStream<TcpStream>>),
+}
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(unix)]
+            Stream::Unix(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            Stream::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(unix)]
+            Stream::Unix(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(feature = "tls")]
+            Stream::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+impl Connection for Stream {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+/// Where bitcoind listens.
+#[derive(Debug, Clone)]
+enum Target {
+    Tcp(SocketAddr),
+    /// A host name and port, looked up with the resolver
+    Host(String, u16, Arc<Resolver>),
+    /// A path, or `@name` for a socket in the abstract namespace of Linux
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// Connects a Unix socket, `@name` being an abstract one on Linux.
+#[cfg(unix)]
+async fn connect_unix(path: &Path) -> std::io::Result<UnixStream> {
+    #[cfg(target_os = "linux")]
+    if let Some(name) = path.to_str().and_then(|p| p.strip_prefix('@')) {
+        use std::os::linux::net::SocketAddrExt;
+
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        // connecting a local socket doesn't block for long
+        let stream = std::os::unix::net::UnixStream::connect_addr(&addr)?;
+        stream.set_nonblocking(true)?;
+        return UnixStream::from_std(stream);
+    }
+    UnixStream::connect(path).await
+}
+
+/// Connects to bitcoind for the HTTP client of `RpcClient`.
+#[derive(Clone)]
+pub struct Connector {
+    target: Target,
+    proxy: Option<Arc<Proxy>>,
+    keepalive: Option<Duration>,
+    #[cfg(feature = "tls")]
+    tls: Option<tokio_rustls::TlsConnector>,
+}
+impl Connector {
+    /// Connects to `addr` over plain TCP.
+    pub fn plain(addr: SocketAddr) -> Self {
+        Connector {
+            target: Target::Tcp(addr),
+            proxy: None,
+            keepalive: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Connects to the Unix socket at `path`, or to the abstract socket `name` if `path` is
+    /// `@name`.
+    #[cfg(unix)]
+    pub fn unix(path: PathBuf) -> Self {
+        Connector {
+            target: Target::Unix(path),
+            proxy: None,
+            keepalive: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Connects to `host` over plain TCP, looked up with `resolver` for every new connection.
+    pub fn host(host: String, port: u16, resolver: Resolver) -> Self {
+        Connector {
+            target: Target::Host(host, port, Arc::new(resolver)),
+            proxy: None,
+            keepalive: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Connects to `addr` over TLS, verifying the certificate with `config`.
+    #[cfg(feature = "tls")]
+    pub fn tls(addr: SocketAddr, config: rustls::ClientConfig) -> Self {
+        Connector {
+            target: Target::Tcp(addr),
+            proxy: None,
+            keepalive: None,
+            tls: Some(Arc::new(config).into()),
+        }
+    }
+
+    /// Connects to `host` as looked up with `resolver` instead of the address the connector was
+    /// made with, trying every address it has. Through a proxy, the proxy looks `host` up.
+    pub fn resolving(self, host: String, port: u16, resolver: Resolver) -> Self {
+        Connector {
+            target: Target::Host(host, port, Arc::new(resolver)),
+            ..self
+        }
+    }
+
+    /// Connects through `proxy`, which has no effect on Unix sockets.
+    pub fn via(self, proxy: Proxy) -> Self {
+        Connector {
+            proxy: Some(Arc::new(proxy)),
+            ..self
+        }
+    }
+
+    /// Sends TCP keep-alive probes after `interval` of idleness, so that connections kept in the
+    /// pool aren't dropped by firewalls and dead ones are noticed.
+    pub fn keepalive(self, interval: Duration) -> Self {
+        Connector {
+            keepalive: Some(interval),
+            ..self
+        }
+    }
+
+    /// The scheme of URIs of bitcoind.
+    pub fn scheme(&self) -> &'static str {
+        #[cfg(feature = "tls")]
+        if self.tls.is_some() {
+            return "https";
+        }
+        "http"
+    }
+}
+impl std::fmt::Debug for Connector {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Connector")
+            .field("target", &self.target)
+            .field("proxy", &self.proxy)
+            .field("keepalive", &self.keepalive)
+            .field("scheme", &self.scheme())
+            .finish()
+    }
+}
+impl Service<Uri> for Connector {
+    type Response = Stream;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Stream, BoxError>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context) -> Poll<Result<(), BoxError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let this = self.clone();
+        async move {
+            let destinations: Vec<Destination> = match &this.target {
+                Target::Tcp(addr) => vec![(*addr).into()],
+                // a proxy looks names up itself, which is the only way to reach onion services
+                Target::Host(host, port, _) if this.proxy.is_some() => {
+                    vec![Destination::Host(host.clone(), *port)]
+                }
+                Target::Host(host, port, resolver) => resolver
+                    .lookup(host, *port)
+                    .await?
+                    .into_iter()
+                    .map(Destination::from)
+                    .collect(),
+                #[cfg(unix)]
+                Target::Unix(path) => return Ok(Stream::Unix(connect_unix(path).await?)),
+            };
+            let mut last_error = None;
+            let mut connected = None;
+            for destination in destinations {
+                let stream = match (&this.proxy, destination) {
+                    (Some(proxy), destination) => proxy.connect(destination).await,
+                    (None, Destination::Addr(addr)) => TcpStream::connect(addr).await,
+                    (None, Destination::Host(host, port)) => {
+                        TcpStream::connect((host.as_str(), port)).await
+                    }
+                };
+                match stream {
+                    Ok(stream) => {
+                        connected = Some(stream);
+                        break;
+                    }
+                    Err(e) => last_error = Some(e),
+                }
+            }
+            let stream = match (connected, last_error) {
+                (Some(stream), _) => stream,
+                (None, Some(e)) => return Err(e.into()),
+                (None, None) => return Err("no addresses to connect to".into()),
+            };
+            stream.set_nodelay(true)?;
+            if this.keepalive.is_some() {
+                stream.set_keepalive(this.keepalive)?;
+            }
+            #[cfg(feature = "tls")]
+            if let Some(tls) = &this.tls {
+                let host = uri.host().unwrap_or_default();
+                let name = webpki::DNSNameRef::try_from_ascii_str(host)
+                    .map_err(|_| format!("{} is not a valid DNS name", host))?;
+                return Ok(Stream::Tls(Box::new(tls.connect(name, stream).await?)));
+            }
+            let _ = uri;
+            Ok(Stream::Plain(stream))
+        }
+        .boxed()
+    }
+}
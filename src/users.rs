@@ -1,173 +1,923 @@
-use std::collections::{HashMap, HashSet};
-use std::sync::Arc;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, RwLock};
 
-use anyhow::Error;
-use bitcoin::consensus::Encodable;
-use hyper::{header::HeaderValue, StatusCode};
-use serde_json::Value;
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, NaiveTime, Utc};
+use hyper::StatusCode;
+use serde_json::{Map, Value};
 
+use crate::allowed_calls::AllowedCalls;
+use crate::api_keys::ApiKey;
+use crate::categories;
 use crate::client::{
-    GenericRpcMethod, RpcError, RpcMethod, RpcRequest, RpcResponse, METHOD_NOT_ALLOWED_ERROR_CODE,
-    METHOD_NOT_ALLOWED_ERROR_MESSAGE, MISC_ERROR_CODE, PRUNE_ERROR_MESSAGE,
-};
-use crate::fetch_blocks::fetch_block;
-use crate::rpc_methods::{
-    GetBlock, GetBlockHeader, GetBlockHeaderParams, GetBlockResult, GetBlockchainInfo,
+    GenericRpcMethod, RpcError, RpcRequest, RpcResponse, ACCESS_DENIED_ERROR_CODE,
+    APPROVAL_REQUIRED_ERROR_CODE, METHOD_NOT_ALLOWED_ERROR_CODE, METHOD_NOT_ALLOWED_ERROR_MESSAGE,
+    METHOD_NOT_FOUND_ERROR_CODE, METHOD_NOT_FOUND_ERROR_MESSAGE, READ_ONLY_ERROR_MESSAGE,
 };
+use crate::dry_run;
+use crate::idempotency::Claim;
+use crate::intercept::{RequestContext, LOCAL_METHOD_PREFIX};
+use crate::journal;
+use crate::notify::Notifier;
+use crate::param_policy::{self, ParamRule};
+use crate::password::Password;
+use crate::priority::Priority;
+use crate::quota::Quota;
+use crate::rate_limit::{rate_limited, Decision, RateLimit};
+use crate::redact::{self, Redaction};
+use crate::roles::Role;
+use crate::sats;
+use crate::secrets;
 use crate::state::State;
+use crate::storage::{Storage, USERS};
 
 #[cfg(feature = "old_rust")]
 use crate::util::old_rust::StrCompat;
 
-#[derive(Debug, serde::Deserialize)]
-pub struct Users(pub HashMap<String, User>);
+/// Header naming the user on whose behalf a user allowed to impersonate others makes the request.
+pub const IMPERSONATE_HEADER: &str = "x-impersonate";
+
+/// The name the calls of all anonymous clients together are limited under.
+const ANONYMOUS_TIER: &str = "anonymous:*";
+
+/// Identity of a client that presented a certificate to the TLS listener.
+#[derive(Debug, Clone)]
+pub struct ClientCert {
+    /// Hex SHA-256 of the DER encoded certificate
+    pub fingerprint: String,
+    /// Subject of the certificate, only set if it was issued by `tls_client_ca`
+    pub subject: Option<String>,
+}
+
+/// Normalizes a fingerprint, ignoring case and `:` separators.
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| *c != ':')
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Fields of a user `proxy_setpermissions` can't change.
+pub(crate) const CREDENTIAL_FIELDS: &[&str] = &[
+    "password",
+    "password_file",
+    "password_env",
+    "api_key",
+    "client_cert",
+    "client_cert_subject",
+];
+
+/// Fields of a user reading secrets from the host of the proxy, which only the config may set.
+pub(crate) const HOST_FIELDS: &[&str] = &["password_file", "password_env"];
+
+/// A user as configured and with the permissions of its groups, which apply to its requests.
+#[derive(Debug)]
+struct Member {
+    own: Arc<User>,
+    effective: Arc<User>,
+}
+
+/// The users of the proxy, those of the config overridden by the ones changed at runtime.
+#[derive(Debug)]
+pub struct Users {
+    users: RwLock<HashMap<String, Member>>,
+    /// Permissions users inherit by naming them in `groups`
+    groups: HashMap<String, User>,
+    /// Users added or changed at runtime, `null` if removed
+    changes: Mutex<BTreeMap<String, Value>>,
+    /// Where the changes are kept across restarts
+    storage: Arc<dyn Storage>,
+}
 impl Users {
-    pub fn get(&self, auth: &HeaderValue) -> Option<(String, &User)> {
-        let header_str = auth.to_str().ok()?;
-        let auth = header_str.strip_prefix("Basic ")?;
-        let auth_decoded = base64::decode(auth).ok()?;
-        let auth_decoded_str = std::str::from_utf8(&auth_decoded).ok()?;
-        let mut auth_split = auth_decoded_str.split(":");
-        let name = auth_split.next()?;
-        let pass = auth_split.next()?;
-        self.0
+    /// The users of the config, with the changes kept in `storage` applied.
+    pub fn open(
+        mut users: HashMap<String, User>,
+        groups: HashMap<String, User>,
+        storage: Arc<dyn Storage>,
+    ) -> Result<Self, Error> {
+        for (name, group) in &groups {
+            group
+                .check_group()
+                .map_err(|e| anyhow!("group {}: {}", name, e))?;
+        }
+        let changes: BTreeMap<String, Value> = match storage.load(USERS)? {
+            Some(changes) => serde_json::from_value(changes)
+                .map_err(|e| anyhow!("invalid users in storage: {}", e))?,
+            None => BTreeMap::new(),
+        };
+        for (name, user) in &changes {
+            if user.is_null() {
+                users.remove(name);
+            } else {
+                let user = User::from_value(user.clone())
+                    .map_err(|e| anyhow!("user {} in storage: {}", name, e))?;
+                users.insert(name.clone(), user);
+            }
+        }
+        let mut members = HashMap::new();
+        for (name, user) in users {
+            let member =
+                Self::member(&groups, user).map_err(|e| anyhow!("user {}: {}", name, e))?;
+            members.insert(name, member);
+        }
+        Ok(Users {
+            users: RwLock::new(members),
+            groups,
+            changes: Mutex::new(changes),
+            storage,
+        })
+    }
+
+    /// `user` along with its permissions including those of its groups.
+    fn member(groups: &HashMap<String, User>, user: User) -> Result<Member, Error> {
+        let mut effective = user.clone();
+        for name in &user.groups {
+            let group = groups
+                .get(name)
+                .ok_or_else(|| anyhow!("unknown group {}", name))?;
+            effective.inherit(group);
+        }
+        Ok(Member {
+            own: Arc::new(user),
+            effective: Arc::new(effective),
+        })
+    }
+
+    /// Authenticates the user a TLS client certificate is configured for.
+    pub fn by_client_cert(&self, cert: &ClientCert) -> Option<(String, Arc<User>)> {
+        self.users
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, m)| (name, &m.effective))
+            .find(|(_, u)| {
+                u.client_cert
+                    .as_ref()
+                    .is_some_and(|f| normalize_fingerprint(f) == cert.fingerprint)
+                    || u.client_cert_subject
+                        .as_ref()
+                        .is_some_and(|s| cert.subject.as_ref() == Some(s))
+            })
+            .map(|(name, u)| (name.clone(), u.clone()))
+    }
+
+    /// Looks up the user `admin` wants to act as, checking that `admin` may do so.
+    pub fn impersonate(&self, admin: &User, target: &str) -> Result<(String, Arc<User>), RpcError> {
+        let denied = |message: String| RpcError {
+            code: ACCESS_DENIED_ERROR_CODE,
+            message,
+            data: None,
+            status: Some(StatusCode::FORBIDDEN),
+        };
+        if !admin.impersonate {
+            return Err(denied("Not allowed to impersonate other users".to_owned()));
+        }
+        self.by_name(target)
+            .map(|u| (target.to_owned(), u))
+            .ok_or_else(|| denied(format!("Unknown user {}", target)))
+    }
+
+    /// The user `name` with the permissions of its groups.
+    pub fn by_name(&self, name: &str) -> Option<Arc<User>> {
+        self.users
+            .read()
+            .unwrap()
             .get(name)
-            .filter(|u| u.password == pass)
-            .map(|u| (name.to_owned(), u))
+            .map(|m| m.effective.clone())
+    }
+
+    /// The user `name` as configured, without the permissions of its groups.
+    pub fn definition(&self, name: &str) -> Option<Arc<User>> {
+        self.users.read().unwrap().get(name).map(|m| m.own.clone())
+    }
+
+    /// All users, sorted by name.
+    pub fn list(&self) -> Vec<(String, Arc<User>)> {
+        let mut users: Vec<_> = self
+            .users
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(n, m)| (n.clone(), m.effective.clone()))
+            .collect();
+        users.sort_by(|a, b| a.0.cmp(&b.0));
+        users
+    }
+
+    /// Adds `name` if `user` is set, removes it otherwise. The change takes effect with the next
+    /// request and is kept in the storage.
+    pub fn set(&self, name: &str, user: Option<User>) -> Result<(), Error> {
+        let member = user
+            .map(|user| Self::member(&self.groups, user))
+            .transpose()?;
+        let mut changes = self.changes.lock().unwrap();
+        changes.insert(
+            name.to_owned(),
+            member
+                .as_ref()
+                .map(|m| serde_json::to_value(&*m.own))
+                .transpose()?
+                .unwrap_or(Value::Null),
+        );
+        self.storage
+            .store(USERS, &serde_json::to_value(&*changes)?)?;
+        let mut users = self.users.write().unwrap();
+        match member {
+            Some(member) => users.insert(name.to_owned(), member),
+            None => users.remove(name),
+        };
+        Ok(())
+    }
+
+    /// `name` with the permissions in `fields` changed, which have the format of the config.
+    pub fn with_permissions(&self, name: &str, fields: Map<String, Value>) -> Result<User, Error> {
+        if let Some(field) = CREDENTIAL_FIELDS.iter().find(|f| fields.contains_key(**f)) {
+            return Err(anyhow!("{} can't be changed with permissions", field));
+        }
+        let user = self
+            .definition(name)
+            .ok_or_else(|| anyhow!("unknown user {}", name))?;
+        let mut value = serde_json::to_value(&*user)?;
+        value.as_object_mut().unwrap().extend(fields);
+        User::from_value(value)
     }
 }
 
-#[derive(Debug, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct User {
-    pub password: String,
-    pub allowed_calls: HashSet<String>,
+    /// May be omitted for users identified by a client certificate
+    #[serde(default, skip_serializing_if = "Password::is_resolved")]
+    pub password: Password,
+    /// File the password is read from instead
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_file: Option<PathBuf>,
+    /// Environment variable the password is read from instead
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_env: Option<String>,
+    /// Keys accepted as the password besides it
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub api_key: Vec<ApiKey>,
+    /// Groups whose permissions the user inherits, see `User::inherit`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+    #[serde(default)]
+    pub allowed_calls: AllowedCalls,
+    /// Preset of methods allowed in addition to `allowed_calls`
+    #[serde(default)]
+    pub role: Option<Role>,
     #[serde(default)]
     pub fetch_blocks: bool,
+    /// The credentials are rejected after this time
+    #[serde(default, alias = "valid_until")]
+    pub expires: Option<DateTime<Utc>>,
+    /// Times of day (UTC) the credentials may be used at, any time if empty
+    #[serde(default)]
+    pub allowed_hours: Vec<TimeWindow>,
+    /// Addresses the credentials may be used from, any if empty
+    #[serde(default)]
+    pub allowed_ips: Vec<IpRange>,
+    /// May make requests as any other user using the `X-Impersonate` header
+    #[serde(default)]
+    pub impersonate: bool,
+    /// Limits on how often methods may be called
+    #[serde(default)]
+    pub rate_limit: Vec<RateLimit>,
+    /// Limits on the calls and traffic per day or month
+    #[serde(default)]
+    pub quota: Vec<Quota>,
+    /// Limits on the blocks fetched from elsewhere than bitcoind (`requests`) and their size
+    #[serde(default)]
+    pub fetch_quota: Vec<Quota>,
+    /// Wallets that may be used through `/wallet/<name>`, any if not set
+    #[serde(default)]
+    pub allowed_wallets: Option<HashSet<String>>,
+    /// Prefix of the wallet labels the user may see and set, e.g. `alice/`
+    #[serde(default)]
+    pub label_prefix: Option<String>,
+    /// Shorthand for a token bucket limit on all calls
+    #[serde(default)]
+    pub max_requests_per_second: Option<f64>,
+    /// Calls allowed in a burst with `max_requests_per_second`
+    #[serde(default)]
+    pub burst: Option<u32>,
+    /// SHA-256 fingerprint of a TLS client certificate identifying the user
+    #[serde(default)]
+    pub client_cert: Option<String>,
+    /// Subject of a TLS client certificate issued by `tls_client_ca` identifying the user
+    #[serde(default)]
+    pub client_cert_subject: Option<String>,
+    /// Restrictions on the parameters of allowed calls
+    #[serde(default)]
+    pub param_policy: Vec<ParamRule>,
+    /// Batch users are slowed down while bitcoind is degraded, interactive if not set
+    #[serde(default)]
+    pub priority: Option<Priority>,
+    /// Fields removed from the results of calls
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub redact: Vec<Redaction>,
+    /// May add, remove and change users with `proxy_adduser`, `proxy_removeuser` and
+    /// `proxy_setpermissions`
+    #[serde(default)]
+    pub manage_users: bool,
+    /// May approve and reject sends parked for approval with `proxy_approve` and `proxy_reject`
+    #[serde(default)]
+    pub approve: bool,
 }
 impl User {
-    pub async fn intercept<'a>(
+    /// Parses a user in the format of the config, checking its password hash.
+    pub fn from_value(value: Value) -> Result<Self, Error> {
+        let mut user: User = serde_json::from_value(value)?;
+        user.resolve_password()?;
+        user.check_credentials()?;
+        Ok(user)
+    }
+
+    /// Checks that the hashes of the password and API keys can be parsed and that the ids of the
+    /// keys are unique.
+    pub fn check_credentials(&self) -> Result<(), Error> {
+        self.password.check()?;
+        let mut ids = HashSet::new();
+        for key in &self.api_key {
+            if key.id.is_empty() || !ids.insert(&key.id) {
+                return Err(anyhow!("API keys need unique ids, got {:?}", key.id));
+            }
+            key.key
+                .check()
+                .map_err(|e| anyhow!("API key {}: {}", key.id, e))?;
+        }
+        Ok(())
+    }
+
+    /// Checks that a group doesn't have credentials or groups itself.
+    pub fn check_group(&self) -> Result<(), Error> {
+        let credentials = !self.password.is_empty()
+            || self.password_file.is_some()
+            || self.password_env.is_some()
+            || !self.api_key.is_empty()
+            || self.has_client_cert();
+        if credentials {
+            return Err(anyhow!("groups can't have credentials"));
+        }
+        if !self.groups.is_empty() {
+            return Err(anyhow!("groups can't be in groups"));
+        }
+        Ok(())
+    }
+
+    /// Checks that the permissions are safe to give to clients without credentials: only listed
+    /// methods outside the wallet that don't change state, no grants, and a rate limit.
+    pub fn check_anonymous(&self) -> Result<(), Error> {
+        if self.allowed_calls.has_patterns() {
+            return Err(anyhow!("allowed_calls may only list methods, not patterns"));
+        }
+        if let Some(method) = self
+            .allowed_calls
+            .names()
+            .find(|m| !Role::ReadOnly.allows(m))
+        {
+            return Err(anyhow!("{} isn't a read-only method", method));
+        }
+        if self.role.is_some_and(|role| role != Role::ReadOnly) {
+            return Err(anyhow!("only the readonly role is allowed"));
+        }
+        if self.fetch_blocks || self.impersonate || self.manage_users || self.approve {
+            return Err(anyhow!(
+                "fetch_blocks, impersonate, manage_users and approve aren't allowed"
+            ));
+        }
+        if self.max_requests_per_second.is_none() && self.rate_limit.is_empty() {
+            return Err(anyhow!("a rate limit is required"));
+        }
+        Ok(())
+    }
+
+    /// Adds the permissions of `group`: the allowed calls and grants of both and all their
+    /// limits apply, while settings of the user (e.g. `role`, `expires` or `allowed_ips`)
+    /// take precedence over those of the group.
+    pub fn inherit(&mut self, group: &User) {
+        fn or<T: Clone>(own: &mut Option<T>, inherited: &Option<T>) {
+            if own.is_none() {
+                own.clone_from(inherited);
+            }
+        }
+        fn or_list<T: Clone>(own: &mut Vec<T>, inherited: &[T]) {
+            if own.is_empty() {
+                own.extend_from_slice(inherited);
+            }
+        }
+        self.allowed_calls.extend(&group.allowed_calls);
+        or(&mut self.role, &group.role);
+        self.fetch_blocks |= group.fetch_blocks;
+        self.impersonate |= group.impersonate;
+        self.manage_users |= group.manage_users;
+        self.approve |= group.approve;
+        or(&mut self.expires, &group.expires);
+        or_list(&mut self.allowed_hours, &group.allowed_hours);
+        or_list(&mut self.allowed_ips, &group.allowed_ips);
+        self.rate_limit.extend_from_slice(&group.rate_limit);
+        self.quota.extend_from_slice(&group.quota);
+        self.fetch_quota.extend_from_slice(&group.fetch_quota);
+        or(&mut self.allowed_wallets, &group.allowed_wallets);
+        or(&mut self.label_prefix, &group.label_prefix);
+        or(
+            &mut self.max_requests_per_second,
+            &group.max_requests_per_second,
+        );
+        or(&mut self.burst, &group.burst);
+        self.param_policy.extend_from_slice(&group.param_policy);
+        or(&mut self.priority, &group.priority);
+        self.redact.extend_from_slice(&group.redact);
+    }
+
+    /// Why `method` is allowed: the entry of `allowed_calls` or the role allowing it.
+    pub fn allowed_by(&self, method: &str) -> Option<String> {
+        if let Some(entry) = self.allowed_calls.matching_entry(method) {
+            return Some(entry.to_owned());
+        }
+        self.role
+            .filter(|role| role.allows(method))
+            .map(|role| format!("role {}", role))
+    }
+
+    pub fn has_client_cert(&self) -> bool {
+        self.client_cert.is_some() || self.client_cert_subject.is_some()
+    }
+
+    /// Checks constraints on when the credentials may be used.
+    pub fn check_access(&self, now: DateTime<Utc>) -> Result<(), RpcError> {
+        if let Some(expires) = self.expires {
+            if now >= expires {
+                return Err(RpcError {
+                    code: ACCESS_DENIED_ERROR_CODE,
+                    message: format!("Credentials expired at {}", expires.to_rfc3339()),
+                    data: None,
+                    status: Some(StatusCode::FORBIDDEN),
+                });
+            }
+        }
+        let time = now.time();
+        if !self.allowed_hours.is_empty() && !self.allowed_hours.iter().any(|w| w.contains(time)) {
+            return Err(RpcError {
+                code: ACCESS_DENIED_ERROR_CODE,
+                message: format!(
+                    "Credentials may only be used between {} (UTC)",
+                    self.allowed_hours
+                        .iter()
+                        .map(|w| w.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+                data: None,
+                status: Some(StatusCode::FORBIDDEN),
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks that the credentials may be used from `addr`, the address of the client if known.
+    pub fn check_address(&self, addr: Option<IpAddr>) -> Result<(), RpcError> {
+        if self.allowed_ips.is_empty()
+            || addr.is_some_and(|a| self.allowed_ips.iter().any(|r| r.contains(a)))
+        {
+            return Ok(());
+        }
+        Err(RpcError {
+            code: ACCESS_DENIED_ERROR_CODE,
+            message: match addr {
+                Some(addr) => format!("Credentials may not be used from {}", addr),
+                None => "Credentials may not be used from an unknown address".to_owned(),
+            },
+            data: None,
+            status: Some(StatusCode::FORBIDDEN),
+        })
+    }
+
+    /// Reads the password from `password_file` or `password_env`, if set.
+    pub fn resolve_password(&mut self) -> Result<(), Error> {
+        let password = match (&self.password_file, &self.password_env) {
+            (None, None) => return Ok(()),
+            (Some(path), None) => secrets::read_file(path)?,
+            (None, Some(var)) => secrets::read_env(var)?,
+            (Some(_), Some(_)) => {
+                return Err(anyhow!(
+                    "password_file and password_env can't be set together"
+                ))
+            }
+        };
+        if !self.password.is_empty() {
+            return Err(anyhow!(
+                "password can't be set together with password_file or password_env"
+            ));
+        }
+        self.password = Password::resolved(password);
+        Ok(())
+    }
+
+    /// Whether the wallet `name` may be used.
+    pub fn may_use_wallet(&self, name: &str) -> bool {
+        self.allowed_wallets
+            .as_ref()
+            .is_none_or(|allowed| allowed.contains(name))
+    }
+
+    /// Checks that the wallet a request is sent to (if any) may be used. Wallet methods sent to
+    /// `/` would use bitcoind's default wallet, or take the name of a wallet as a parameter, so
+    /// they are denied to users restricted to some wallets.
+    pub fn check_wallet(&self, path: &str, method: &str) -> Result<(), RpcError> {
+        if self.allowed_wallets.is_none() {
+            return Ok(());
+        }
+        let name = match path.strip_prefix("/wallet/") {
+            Some(name) => percent_decode(name),
+            None if categories::category(method) == Some("wallet") => {
+                return Err(RpcError {
+                    code: ACCESS_DENIED_ERROR_CODE,
+                    message: format!("{} has to be sent to /wallet/<name>", method),
+                    data: None,
+                    status: Some(StatusCode::FORBIDDEN),
+                })
+            }
+            None => return Ok(()),
+        };
+        match name {
+            Some(name) if self.may_use_wallet(&name) => Ok(()),
+            name => Err(RpcError {
+                code: ACCESS_DENIED_ERROR_CODE,
+                message: format!(
+                    "Wallet {} is not allowed",
+                    name.as_deref().unwrap_or("(invalid name)")
+                ),
+                data: None,
+                status: Some(StatusCode::FORBIDDEN),
+            }),
+        }
+    }
+
+    /// All limits of the user, including the one of `max_requests_per_second`.
+    pub fn rate_limits(&self) -> Vec<Cow<'_, RateLimit>> {
+        self.max_requests_per_second
+            .filter(|rate| *rate > 0.0)
+            .map(|rate| Cow::Owned(RateLimit::per_second(rate, self.burst)))
+            .into_iter()
+            .chain(self.rate_limit.iter().map(Cow::Borrowed))
+            .collect()
+    }
+
+    pub async fn intercept(
         &self,
         state: Arc<State>,
-        req: &'a RpcRequest<GenericRpcMethod>,
+        path: &str,
+        req: &RpcRequest<GenericRpcMethod>,
+        ctx: &RequestContext,
     ) -> Result<Option<RpcResponse<GenericRpcMethod>>, RpcError> {
-        if self.allowed_calls.contains(&*req.method) {
-            if self.fetch_blocks && &*req.method == GetBlock.as_str()
-            // only non-verbose for now
+        #[cfg(feature = "chaos")]
+        if let Some(e) = state.chaos.as_ref().and_then(|c| c.fail_call()) {
+            return Err(e);
+        }
+        if self.allowed_by(&req.method).is_some() {
+            self.check_wallet(path, &req.method)?;
+            // amounts in satoshis are converted before any policy looks at them
+            let converted = if ctx.sats {
+                sats::convert_request(req)?
+            } else {
+                None
+            };
+            let req = converted.as_ref().unwrap_or(req);
+            param_policy::check(&self.param_policy, req)?;
+            let rewritten = state.guardrails.apply(&state, req).await?;
+            if rewritten.is_some() {
+                info!(
+                    state.logger,
+                    "{} called {}: REWRITTEN by coin selection guardrails",
+                    ctx.user_name,
+                    req.method.0
+                );
+            }
+            let req = rewritten.as_ref().unwrap_or(req);
+            // users out of quota may still see when it resets
+            if req.method.0 != "proxy_getquota" {
+                state.quota_usage.acquire(&ctx.user_name, &self.quota)?;
+            }
+            self.check_rate_limits(&state, &req.method, ctx).await?;
+            if let Some(load) = &state.load {
+                load.throttle(&state, self.priority.unwrap_or_default(), &req.method, ctx)
+                    .await?;
+            }
+            if let Some(reuse) = &state.address_reuse {
+                reuse.check(&state, req, ctx).await?;
+            }
+            if ctx.dry_run && categories::is_write(&req.method) {
+                return dry_run::simulate(state, path, req).await;
+            }
+            if categories::is_write(&req.method) && state.read_only.load(Ordering::SeqCst) {
+                return Err(RpcError {
+                    code: ACCESS_DENIED_ERROR_CODE,
+                    message: READ_ONLY_ERROR_MESSAGE.to_owned(),
+                    data: None,
+                    status: Some(StatusCode::SERVICE_UNAVAILABLE),
+                });
+            }
+            let key = ctx
+                .idempotency_key
+                .as_ref()
+                .filter(|_| categories::is_write(&req.method));
+            let reuse = state
+                .address_reuse
+                .as_ref()
+                .filter(|reuse| reuse.observes(&req.method));
+            let redacted = redact::applies(&self.redact, &req.method);
+            // the caller forwards the request it has, so a rewritten one is forwarded here
+            if key.is_none() && reuse.is_none() && !redacted && !ctx.sats && rewritten.is_none() {
+                return self.dispatch(state, path, req, ctx, &mut false).await;
+            }
+            if let Some(key) = key {
+                if let Claim::Replay(response) =
+                    state.idempotency.claim(&ctx.user_name, key, req)?
+                {
+                    info!(
+                        state.logger,
+                        "{} called {}: REPLAYED result for idempotency key {}",
+                        ctx.user_name,
+                        req.method.0,
+                        key
+                    );
+                    return Ok(Some(response));
+                }
+            }
+            let mut sent = false;
+            let mut res = match self
+                .dispatch(state.clone(), path, req, ctx, &mut sent)
+                .await
             {
-                match req.params.get(1).unwrap_or(&1_u64.into()) {
-                    Value::Number(ref n) if n.as_u64() == Some(0) => {
-                        match fetch_block(
-                            state.clone(),
-                            state.get_peers().await?,
-                            serde_json::from_value(req.params[0].clone()).map_err(Error::from)?,
-                        )
+                Ok(None) => {
+                    sent = true;
+                    state
+                        .rpc_client
+                        .call_at(path, req)
                         .await
-                        {
-                            Ok(Some(block)) => {
-                                let mut block_data = Vec::new();
-                                block
-                                    .consensus_encode(&mut block_data)
-                                    .map_err(Error::from)?;
-                                let block_data = hex::encode(&block_data);
-                                Ok(Some(RpcResponse {
-                                    id: req.id.clone(),
-                                    result: Some(Value::String(block_data)),
-                                    error: None,
-                                }))
-                            }
-                            Ok(None) => Ok(Some(RpcResponse {
-                                id: req.id.clone(),
-                                result: None,
-                                error: Some(RpcError {
-                                    code: MISC_ERROR_CODE,
-                                    message: PRUNE_ERROR_MESSAGE.to_owned(),
-                                    status: None,
-                                }),
-                            })),
-                            Err(e) => Ok(Some(e.into())),
-                        }
-                    }
-                    Value::Number(ref n) if n.as_u64() == Some(1) => {
-                        let hash =
-                            serde_json::from_value(req.params[0].clone()).map_err(Error::from)?;
-                        let fetch_header_req = RpcRequest {
-                            id: None,
-                            method: GetBlockHeader,
-                            params: GetBlockHeaderParams(hash, Some(true)),
+                        .map(Some)
+                        .map_err(RpcError::from)
+                }
+                res => res,
+            };
+            if let (Some(reuse), Ok(Some(response))) = (reuse, &res) {
+                reuse.observe(&ctx.user_name, path, req, response);
+            }
+            // before storing the result, so that replays are redacted as well
+            if let Ok(Some(response)) = &mut res {
+                redact::apply(&self.redact, &req.method, response);
+                if ctx.sats {
+                    sats::annotate(&req.method, response);
+                }
+            }
+            if let Some(key) = key {
+                match &res {
+                    Ok(Some(response)) => state.idempotency.complete(&ctx.user_name, key, response),
+                    // retries learn about the same approval instead of parking the send again
+                    Err(e) if e.code == APPROVAL_REQUIRED_ERROR_CODE => {
+                        let pending = RpcResponse {
+                            id: req.id.clone(),
+                            result: None,
+                            error: Some(e.clone()),
                         };
-                        match futures::try_join!(
-                            async {
-                                state
-                                    .rpc_client
-                                    .call(&fetch_header_req)
-                                    .await?
-                                    .into_result()
-                            },
-                            async {
-                                fetch_block(state.clone(), state.clone().get_peers().await?, hash)
-                                    .await
-                            }
-                        ) {
-                            Ok((header, Some(block))) => Ok(Some(RpcResponse {
-                                id: req.id.clone(),
-                                result: {
-                                    let size = block.get_size();
-                                    let witness = block
-                                        .txdata
-                                        .iter()
-                                        .flat_map(|tx| tx.input.iter())
-                                        .flat_map(|input| input.witness.iter())
-                                        .map(|witness| witness.len())
-                                        .fold(0, |acc, x| acc + x);
-                                    Some(serde_json::to_value(GetBlockResult {
-                                        header: header.into_right().ok_or_else(|| {
-                                            anyhow::anyhow!(
-                                                "unexpected response for getblockheader"
-                                            )
-                                        })?,
-                                        size,
-                                        strippedsize: if witness > 0 {
-                                            Some(size - witness)
-                                        } else {
-                                            None
-                                        },
-                                        weight: block.get_weight(),
-                                        tx: block.txdata.into_iter().map(|tx| tx.txid()).collect(),
-                                    })?)
-                                },
-                                error: None,
-                            })),
-                            Ok((_, None)) => Ok(Some(RpcResponse {
-                                id: req.id.clone(),
-                                result: None,
-                                error: Some(RpcError {
-                                    code: MISC_ERROR_CODE,
-                                    message: PRUNE_ERROR_MESSAGE.to_owned(),
-                                    status: None,
-                                }),
-                            })),
-                            Err(e) => Ok(Some(e.into())),
-                        }
+                        state.idempotency.complete(&ctx.user_name, key, &pending)
                     }
-                    _ => Ok(None), // TODO
+                    Err(_) if sent => state.idempotency.abandon(&ctx.user_name, key),
+                    _ => state.idempotency.release(&ctx.user_name, key),
                 }
-            } else if self.fetch_blocks && &*req.method == GetBlockchainInfo.as_str() {
-                let mut res = state.rpc_client.call(req).await?;
-                res.result.as_mut().map(|r| match r {
-                    Value::Object(o) => o.get_mut("pruned").map(|p| *p = Value::Bool(false)),
-                    _ => None,
-                });
-
-                Ok(Some(res))
-            } else {
-                Ok(None)
             }
+            res
         } else {
             Err(RpcError {
                 code: METHOD_NOT_ALLOWED_ERROR_CODE,
                 message: METHOD_NOT_ALLOWED_ERROR_MESSAGE.to_owned(),
+                data: None,
                 status: Some(StatusCode::FORBIDDEN),
             })
         }
     }
+
+    /// Counts the call against the limits applying to it, waiting if a limit asks for it.
+    ///
+    /// Calls are allowed if the state of a limit can't be checked (e.g. Redis is down), so that
+    /// the node stays available.
+    async fn check_rate_limits(
+        &self,
+        state: &State,
+        method: &str,
+        ctx: &RequestContext,
+    ) -> Result<(), RpcError> {
+        for (i, limit) in self.rate_limits().iter().enumerate() {
+            if !limit.applies_to(method) {
+                continue;
+            }
+            match state.rate_limiter.acquire(&ctx.user_name, i, limit).await {
+                Ok(Decision::Allow) => (),
+                Ok(Decision::Delay(wait)) => tokio::time::delay_for(wait).await,
+                Ok(Decision::Reject(retry_after)) => return Err(rate_limited(limit, retry_after)),
+                Err(e) => warn!(
+                    state.logger,
+                    "Failed to check rate limit of {}, allowing the call: {:#}", ctx.user_name, e
+                ),
+            }
+        }
+        if crate::auth::is_anonymous(&ctx.user_name) {
+            let limit = &state.anonymous_rate_limit;
+            match state.rate_limiter.acquire(ANONYMOUS_TIER, 0, limit).await {
+                Ok(Decision::Allow) => (),
+                Ok(Decision::Delay(wait)) => tokio::time::delay_for(wait).await,
+                Ok(Decision::Reject(retry_after)) => return Err(rate_limited(limit, retry_after)),
+                Err(e) => warn!(
+                    state.logger,
+                    "Failed to check rate limit of anonymous clients, allowing the call: {:#}", e
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    /// Parks or serves an authorized call, `Ok(None)` meaning it should be forwarded as is.
+    /// `sent` is set once the call is forwarded to bitcoind, so that a failure from then on can
+    /// be told apart from a refusal.
+    async fn dispatch(
+        &self,
+        state: Arc<State>,
+        path: &str,
+        req: &RpcRequest<GenericRpcMethod>,
+        ctx: &RequestContext,
+        sent: &mut bool,
+    ) -> Result<Option<RpcResponse<GenericRpcMethod>>, RpcError> {
+        if let Some(approvals) = &state.approvals {
+            if let Some((id, pending)) = approvals.park(path, req, ctx)? {
+                info!(
+                    state.logger,
+                    "{} called {}: PARKED for approval", ctx.user_name, req.method.0
+                );
+                if let Some(details) = approvals.get(&id) {
+                    Notifier::spawn(state.clone(), "approval_pending", id, details);
+                }
+                return Err(pending);
+            }
+        }
+        state.capabilities.check(req)?;
+        match state.interceptors.get(&req.method) {
+            Some(interceptor) => interceptor.intercept(state.clone(), self, req, ctx).await,
+            None if categories::is_write(&req.method) && state.journal.is_some() => {
+                let journal = state.journal.as_ref().unwrap();
+                *sent = true;
+                journal::forward(state.clone(), journal, path, req, ctx)
+                    .await
+                    .map(Some)
+            }
+            None if req.method.starts_with(LOCAL_METHOD_PREFIX) => Err(RpcError {
+                code: METHOD_NOT_FOUND_ERROR_CODE,
+                message: METHOD_NOT_FOUND_ERROR_MESSAGE.to_owned(),
+                data: None,
+                status: Some(StatusCode::NOT_FOUND),
+            }),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Decodes a path segment the way bitcoind does with wallet names.
+pub fn percent_decode(s: &str) -> Option<String> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            out.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            out.push(b);
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+/// A range of the day, possibly wrapping over midnight, e.g. `22:00-06:00`.
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct TimeWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+impl TimeWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+impl FromStr for TimeWindow {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut split = s.split('-');
+        let mut next = || -> Result<NaiveTime, Error> {
+            let t = split
+                .next()
+                .ok_or_else(|| anyhow!("invalid time window {:?}, expected HH:MM-HH:MM", s))?;
+            Ok(NaiveTime::parse_from_str(t.trim(), "%H:%M")?)
+        };
+        let start = next()?;
+        let end = next()?;
+        Ok(TimeWindow { start, end })
+    }
+}
+impl std::convert::TryFrom<String> for TimeWindow {
+    type Error = Error;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+impl serde::Serialize for TimeWindow {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+impl std::fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+/// An address or a range of addresses in CIDR notation, e.g. `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct IpRange {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+impl IpRange {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        // clients connecting over IPv6 to a dual-stack socket show up as IPv4-mapped addresses
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+            addr => addr,
+        };
+        match (self.addr, addr) {
+            (IpAddr::V4(range), IpAddr::V4(addr)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(range) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(range), IpAddr::V6(addr)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(range) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+impl FromStr for IpRange {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow!("invalid address range {:?}, expected e.g. 10.0.0.0/8", s);
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.trim().parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+        Ok(IpRange { addr, prefix })
+    }
+}
+impl std::convert::TryFrom<String> for IpRange {
+    type Error = Error;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+impl serde::Serialize for IpRange {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+impl std::fmt::Display for IpRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
 }
@@ -1,8 +1,9 @@
 use hyper::body::Bytes;
 use serde::{
-    de::{Deserialize, Deserializer, Unexpected},
+    de::{Deserialize, DeserializeOwned, Deserializer, Unexpected},
     ser::{Serialize, Serializer},
 };
+use serde_json::Value;
 
 #[derive(Debug, Deref, DerefMut, From, Into)]
 pub struct HexBytes(Bytes);
@@ -73,12 +74,27 @@ pub mod old_rust {
     }
 }
 
-#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[derive(Clone, Debug, serde::Serialize)]
 #[serde(untagged)]
 pub enum Either<Left, Right> {
     Left(Left),
     Right(Right),
 }
+// Not derived: untagged enums buffer their input in a way that loses the numbers of
+// serde_json's arbitrary precision, a `Value` keeps them.
+impl<'de, Left: DeserializeOwned, Right: DeserializeOwned> Deserialize<'de>
+    for Either<Left, Right>
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        Left::deserialize(&value)
+            .map(Either::Left)
+            .or_else(|_| Right::deserialize(&value).map(Either::Right))
+            .map_err(|_| {
+                serde::de::Error::custom("data did not match any variant of untagged enum Either")
+            })
+    }
+}
 impl<Left, Right> Either<Left, Right> {
     pub fn as_left(&self) -> Option<&Left> {
         match self {
@@ -0,0 +1,89 @@
+//! Warnings about successful calls (deprecated methods, reused addresses, ...), sent to clients
+//! in `Warning` headers and a `warning` field of the responses concerned.
+
+use std::collections::BTreeMap;
+
+use anyhow::Error;
+use hyper::{
+    body::Bytes,
+    header::{HeaderValue, CONTENT_LENGTH, WARNING},
+    Body, Response,
+};
+use serde_json::value::{to_raw_value, RawValue};
+use serde_json::Value;
+use tokio::stream::StreamExt;
+
+use crate::client::SingleOrBatchRpcRequest;
+
+/// Warnings about the calls of a request, with the ids of the calls they are about.
+pub type Warnings = Vec<(Option<Value>, String)>;
+
+/// The fields of a response, with their values as they were written.
+type Fields = BTreeMap<String, Box<RawValue>>;
+
+/// The warnings of the call with the given id, joined.
+fn joined(warnings: &Warnings, id: Option<&Value>) -> Option<String> {
+    let matching: Vec<&str> = warnings
+        .iter()
+        .filter(|(call, _)| id.is_none() || call.as_ref() == id)
+        .map(|(_, warning)| warning.as_str())
+        .collect();
+    if matching.is_empty() {
+        None
+    } else {
+        Some(matching.join("; "))
+    }
+}
+
+/// Adds `warnings` to the response to `req`.
+pub async fn add(
+    req: &SingleOrBatchRpcRequest,
+    warnings: Warnings,
+    response: Response<Body>,
+) -> Result<Response<Body>, Error> {
+    if warnings.is_empty() {
+        return Ok(response);
+    }
+    let (mut parts, body) = response.into_parts();
+    for (_, warning) in &warnings {
+        let value = format!("299 btc_rpc_proxy {:?}", warning);
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            parts.headers.append(WARNING, value);
+        }
+    }
+    let body: Bytes = body.collect::<Result<Bytes, _>>().await?;
+    // only the fields of the responses are parsed, results are copied as they are
+    let body = match req {
+        SingleOrBatchRpcRequest::Single(_) => match serde_json::from_slice::<Fields>(&body) {
+            Ok(mut response) => {
+                if let Some(warning) = joined(&warnings, None) {
+                    response.insert("warning".to_owned(), to_raw_value(&warning)?);
+                }
+                Bytes::from(serde_json::to_vec(&response)?)
+            }
+            // not a response to the call, the header has to do
+            Err(_) => body,
+        },
+        SingleOrBatchRpcRequest::Batch(_) => match serde_json::from_slice::<Vec<Fields>>(&body) {
+            Ok(mut responses) => {
+                for response in responses.iter_mut() {
+                    let id = response
+                        .get("id")
+                        .and_then(|id| serde_json::from_str::<Value>(id.get()).ok())
+                        .filter(|id| !id.is_null());
+                    let warning = match id {
+                        Some(id) => joined(&warnings, Some(&id)),
+                        None => None,
+                    };
+                    if let Some(warning) = warning {
+                        response.insert("warning".to_owned(), to_raw_value(&warning)?);
+                    }
+                }
+                Bytes::from(serde_json::to_vec(&responses)?)
+            }
+            Err(_) => body,
+        },
+    };
+    parts.headers.insert(CONTENT_LENGTH, body.len().into());
+    Ok(Response::from_parts(parts, body.into()))
+}
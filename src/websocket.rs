@@ -0,0 +1,264 @@
+//! The parts of the WebSocket protocol (RFC 6455) subscriptions need: the opening handshake,
+//! unfragmented messages and their compression with permessage-deflate (RFC 7692).
+
+use anyhow::{anyhow, Error};
+use bitcoin::hashes::{sha1, Hash, HashEngine};
+use hyper::{
+    header::{
+        HeaderValue, CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_EXTENSIONS, SEC_WEBSOCKET_KEY,
+        SEC_WEBSOCKET_VERSION, UPGRADE,
+    },
+    http::request::Parts,
+    Body, Method, Response, StatusCode,
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::deflate;
+
+/// Appended to the key of the client to prove the server speaks WebSocket.
+const GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Messages from clients larger than this close the connection.
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Status code closing a connection because of a message not following the protocol.
+pub const PROTOCOL_ERROR: u16 = 1002;
+/// Status code closing a connection because of a message the server can't handle.
+pub const UNSUPPORTED_DATA: u16 = 1003;
+/// Status code closing a connection violating a policy of the server.
+pub const POLICY_VIOLATION: u16 = 1008;
+
+/// Messages shorter than this are sent uncompressed, as compression wouldn't save anything.
+const MIN_COMPRESSED_SIZE: usize = 64;
+
+/// Parameters of permessage-deflate agreed on in the handshake. Every message is compressed on
+/// its own, so there is no context to keep between messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deflate {
+    /// Matches in messages sent refer at most `2^window_bits` bytes back, as the client asked
+    pub window_bits: u8,
+    /// Whether the client asked for `server_no_context_takeover`
+    server_no_context_takeover: bool,
+}
+impl Deflate {
+    /// The first offer in a `Sec-WebSocket-Extensions` header that can be accepted, if any.
+    pub fn negotiate(header: &str) -> Option<Self> {
+        header.split(',').find_map(|offer| {
+            let mut params = offer.split(';').map(str::trim);
+            if params.next() != Some("permessage-deflate") {
+                return None;
+            }
+            let mut deflate = Deflate {
+                window_bits: 15,
+                server_no_context_takeover: false,
+            };
+            for param in params {
+                let mut kv = param.splitn(2, '=').map(str::trim);
+                let name = kv.next()?;
+                let value = kv.next().map(|v| v.trim_matches('"'));
+                match (name, value) {
+                    ("server_no_context_takeover", None) => {
+                        deflate.server_no_context_takeover = true
+                    }
+                    ("client_no_context_takeover", None) | ("client_max_window_bits", _) => (),
+                    ("server_max_window_bits", Some(bits)) => {
+                        deflate.window_bits = bits.parse().ok().filter(|b| (8..=15).contains(b))?
+                    }
+                    _ => return None,
+                }
+            }
+            Some(deflate)
+        })
+    }
+
+    /// The `Sec-WebSocket-Extensions` header accepting the offer. Clients are asked not to keep
+    /// a context either.
+    pub fn response(&self) -> String {
+        let mut response = "permessage-deflate; client_no_context_takeover".to_owned();
+        if self.server_no_context_takeover {
+            response.push_str("; server_no_context_takeover");
+        }
+        if self.window_bits < 15 {
+            response.push_str(&format!("; server_max_window_bits={}", self.window_bits));
+        }
+        response
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// The status code and reason, if any
+    Close(Option<(u16, String)>),
+}
+impl Message {
+    fn opcode(&self) -> u8 {
+        match self {
+            Message::Text(_) => 0x1,
+            Message::Binary(_) => 0x2,
+            Message::Close(_) => 0x8,
+            Message::Ping(_) => 0x9,
+            Message::Pong(_) => 0xa,
+        }
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        match self {
+            Message::Text(text) => text.as_bytes().to_vec(),
+            Message::Binary(data) | Message::Ping(data) | Message::Pong(data) => data.clone(),
+            Message::Close(None) => Vec::new(),
+            Message::Close(Some((code, reason))) => {
+                let mut payload = code.to_be_bytes().to_vec();
+                payload.extend_from_slice(reason.as_bytes());
+                payload
+            }
+        }
+    }
+
+    /// The message as a single frame, masked with `mask` as clients have to, and compressed if
+    /// `deflate` was agreed on and it is worth it.
+    pub fn encode(&self, mask: Option<[u8; 4]>, deflate: Option<Deflate>) -> Vec<u8> {
+        let mut payload = self.payload();
+        let mut frame = vec![0x80 | self.opcode()];
+        let data = matches!(self, Message::Text(_) | Message::Binary(_));
+        if let Some(deflate) = deflate.filter(|_| data && payload.len() >= MIN_COMPRESSED_SIZE) {
+            let compressed = deflate::compress(&payload, deflate.window_bits);
+            if compressed.len() < payload.len() {
+                payload = compressed;
+                frame[0] |= 0x40;
+            }
+        }
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+        match payload.len() {
+            len if len < 126 => frame.push(mask_bit | len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(mask_bit | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(mask_bit | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        if let Some(mask) = mask {
+            frame.extend_from_slice(&mask);
+            apply_mask(&mut payload, mask);
+        }
+        frame.extend_from_slice(&payload);
+        frame
+    }
+
+    /// Reads the next message from a client, whose frames have to be masked, decompressing it
+    /// if `deflate` was agreed on.
+    pub async fn read<R: AsyncRead + Unpin>(
+        reader: &mut R,
+        deflate: Option<Deflate>,
+    ) -> Result<Self, Error> {
+        let mut header = [0u8; 2];
+        reader.read_exact(&mut header).await?;
+        let opcode = header[0] & 0x0f;
+        let compressed = header[0] & 0x40 != 0;
+        if header[0] & 0x30 != 0 || (compressed && (deflate.is_none() || opcode >= 0x8)) {
+            return Err(anyhow!("unexpected reserved bits in frame"));
+        }
+        if header[0] & 0x80 == 0 || opcode == 0 {
+            return Err(anyhow!("fragmented messages are not supported"));
+        }
+        if header[1] & 0x80 == 0 {
+            return Err(anyhow!("unmasked frame from client"));
+        }
+        let len = match header[1] & 0x7f {
+            126 => {
+                let mut len = [0u8; 2];
+                reader.read_exact(&mut len).await?;
+                u16::from_be_bytes(len) as u64
+            }
+            127 => {
+                let mut len = [0u8; 8];
+                reader.read_exact(&mut len).await?;
+                u64::from_be_bytes(len)
+            }
+            len => len as u64,
+        };
+        if len > MAX_MESSAGE_SIZE as u64 || (opcode >= 0x8 && len > 125) {
+            return Err(anyhow!("frame of {} bytes is too large", len));
+        }
+        let mut mask = [0u8; 4];
+        reader.read_exact(&mut mask).await?;
+        let mut payload = vec![0u8; len as usize];
+        reader.read_exact(&mut payload).await?;
+        apply_mask(&mut payload, mask);
+        if compressed {
+            payload = deflate::decompress(&payload, MAX_MESSAGE_SIZE)?;
+        }
+        Ok(match opcode {
+            0x1 => Message::Text(
+                String::from_utf8(payload).map_err(|_| anyhow!("text message is not UTF-8"))?,
+            ),
+            0x2 => Message::Binary(payload),
+            0x8 if payload.len() >= 2 => Message::Close(Some((
+                u16::from_be_bytes([payload[0], payload[1]]),
+                String::from_utf8_lossy(&payload[2..]).into_owned(),
+            ))),
+            0x8 => Message::Close(None),
+            0x9 => Message::Ping(payload),
+            0xa => Message::Pong(payload),
+            opcode => return Err(anyhow!("unknown opcode {:#x}", opcode)),
+        })
+    }
+}
+
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+}
+
+/// `Sec-WebSocket-Accept` answering the `Sec-WebSocket-Key` of a client.
+pub fn accept_key(key: &[u8]) -> String {
+    let mut engine = sha1::Hash::engine();
+    engine.input(key);
+    engine.input(GUID);
+    base64::encode(&sha1::Hash::from_engine(engine)[..])
+}
+
+fn has_token(value: Option<&HeaderValue>, token: &str) -> bool {
+    value
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+}
+
+/// The response switching the connection of `parts` to WebSocket, `None` unless it asks for that,
+/// and the compression agreed on if `compression` is allowed.
+pub fn handshake(parts: &Parts, compression: bool) -> Option<(Response<Body>, Option<Deflate>)> {
+    let headers = &parts.headers;
+    if parts.method != Method::GET
+        || !has_token(headers.get(UPGRADE), "websocket")
+        || !has_token(headers.get(CONNECTION), "upgrade")
+        || headers
+            .get(SEC_WEBSOCKET_VERSION)
+            .map(HeaderValue::as_bytes)
+            != Some(b"13")
+    {
+        return None;
+    }
+    let key = headers.get(SEC_WEBSOCKET_KEY)?;
+    let deflate = headers
+        .get_all(SEC_WEBSOCKET_EXTENSIONS)
+        .iter()
+        .filter(|_| compression)
+        .filter_map(|v| v.to_str().ok())
+        .find_map(Deflate::negotiate);
+    let mut response = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(UPGRADE, "websocket")
+        .header(CONNECTION, "Upgrade")
+        .header(SEC_WEBSOCKET_ACCEPT, accept_key(key.as_bytes()));
+    if let Some(deflate) = deflate {
+        response = response.header(SEC_WEBSOCKET_EXTENSIONS, deflate.response());
+    }
+    Some((response.body(Body::empty()).ok()?, deflate))
+}
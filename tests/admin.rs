@@ -0,0 +1,41 @@
+//! Managing users at runtime with `manage_users`.
+#![cfg(unix)]
+
+mod common;
+
+use std::sync::Arc;
+
+use common::local::{bitcoind, call, proxy};
+use hyper::StatusCode;
+use serde_json::json;
+
+const ADMIN: Option<(&str, &str)> = Some(("admin", "secret"));
+
+#[tokio::test]
+async fn users_added_at_runtime_dont_read_the_host() {
+    let (connector, _) = bitcoind("admin", |_, _| json!(800000));
+    let users = json!({
+        "admin": { "password": "secret", "manage_users": true, "allowed_calls": ["*"] },
+    });
+    let state = Arc::new(proxy(connector, users).build());
+
+    for (field, value) in &[
+        ("password_file", "/etc/shadow"),
+        ("password_env", "BITCOIND_PASSWORD"),
+    ] {
+        let fields = json!({ *field: value, "allowed_calls": ["getblockcount"] });
+        let (_, response) = call(&state, ADMIN, "proxy_adduser", json!(["bob", fields])).await;
+        let message = response["error"]["message"].as_str().unwrap();
+        assert!(message.contains(field), "{}", message);
+    }
+    let (status, _) = call(&state, Some(("bob", "")), "getblockcount", json!([])).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let fields = json!({ "password": "hunter2", "allowed_calls": ["getblockcount"] });
+    let (_, response) = call(&state, ADMIN, "proxy_adduser", json!(["bob", fields])).await;
+    assert!(response["error"].is_null(), "{}", response);
+    let (status, response) =
+        call(&state, Some(("bob", "hunter2")), "getblockcount", json!([])).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(response["result"], 800000);
+}
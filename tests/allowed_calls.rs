@@ -0,0 +1,79 @@
+//! Method names, glob patterns and categories of `allowed_calls`.
+#![cfg(unix)]
+
+mod common;
+
+use btc_rpc_proxy::allowed_calls::AllowedCalls;
+use hyper::StatusCode;
+use serde_json::json;
+
+use common::local::{bitcoind, call, proxy};
+
+fn parse(entries: &[&str]) -> AllowedCalls {
+    serde_json::from_value(json!(entries)).unwrap()
+}
+
+#[test]
+fn globs() {
+    let allowed = parse(&["getblockcount", "list*", "getblock?ash"]);
+    assert_eq!(
+        allowed.matching_entry("getblockcount"),
+        Some("getblockcount")
+    );
+    assert_eq!(allowed.matching_entry("listunspent"), Some("list*"));
+    assert_eq!(allowed.matching_entry("getblockhash"), Some("getblock?ash"));
+    // `?` is exactly one character, and patterns match the whole name
+    assert!(!allowed.allows("getblockash"));
+    assert!(!allowed.allows("getblockhashes"));
+    assert!(!allowed.allows("unlistunspent"));
+    assert!(!allowed.allows("getblock"));
+}
+
+#[test]
+fn categories() {
+    let allowed = parse(&["wallet/list*", "network/*"]);
+    assert_eq!(allowed.matching_entry("listunspent"), Some("wallet/list*"));
+    assert_eq!(allowed.matching_entry("getpeerinfo"), Some("network/*"));
+    assert!(!allowed.allows("getbalance"));
+    // named like a wallet method, but a network one
+    assert_eq!(allowed.matching_entry("listbanned"), Some("network/*"));
+    assert!(!parse(&["wallet/list*"]).allows("listbanned"));
+    assert!(!allowed.allows("getblockcount"));
+    // not a method of bitcoind
+    assert!(!allowed.allows("listeverything"));
+
+    let proxy_methods = parse(&["proxy/*"]);
+    assert!(proxy_methods.allows("proxy_status"));
+    assert!(!proxy_methods.allows("getblockcount"));
+}
+
+#[test]
+fn entries_are_kept_as_written() {
+    let allowed = parse(&["getblockcount", "wallet/*", "get*"]);
+    assert_eq!(
+        serde_json::to_value(&allowed).unwrap(),
+        json!(["getblockcount", "get*", "wallet/*"])
+    );
+}
+
+#[tokio::test]
+async fn patterns_allow_calls() {
+    let (connector, received) = bitcoind("allowed-calls", |_, _| json!(null));
+    let state = proxy(
+        connector,
+        json!({ "alice": { "password": "secret", "allowed_calls": ["getblock*", "wallet/list*"] } }),
+    )
+    .build()
+    .arc();
+    let alice = Some(("alice", "secret"));
+
+    for method in &["getblockcount", "getblockhash", "listunspent"] {
+        let (status, _) = call(&state, alice, method, json!([])).await;
+        assert_eq!(status, StatusCode::OK, "{}", method);
+    }
+    for method in &["sendtoaddress", "stop", "getbestblockhash"] {
+        let (status, response) = call(&state, alice, method, json!([])).await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "{}: {}", method, response);
+    }
+    assert_eq!(received.lock().unwrap().len(), 3);
+}
@@ -0,0 +1,96 @@
+//! Credentials restricted to the addresses of `allowed_ips`.
+#![cfg(unix)]
+
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use btc_rpc_proxy::users::{IpRange, User};
+use btc_rpc_proxy::State;
+use hyper::{Request, StatusCode};
+use serde_json::json;
+
+use common::local::{bitcoind, proxy, send};
+
+fn range(s: &str) -> IpRange {
+    s.parse().unwrap()
+}
+
+#[test]
+fn ranges() {
+    let lan = range("10.1.0.0/16");
+    assert!(lan.contains("10.1.2.3".parse().unwrap()));
+    assert!(!lan.contains("10.2.0.1".parse().unwrap()));
+    // over IPv6 to a dual-stack socket
+    assert!(lan.contains("::ffff:10.1.2.3".parse().unwrap()));
+    assert!(!lan.contains("2001:db8::1".parse().unwrap()));
+
+    let single = range("192.0.2.1");
+    assert!(single.contains("192.0.2.1".parse().unwrap()));
+    assert!(!single.contains("192.0.2.2".parse().unwrap()));
+    let v6 = range("2001:db8::/32");
+    assert!(v6.contains("2001:db8:ffff::1".parse().unwrap()));
+    assert!(!v6.contains("2001:db9::1".parse().unwrap()));
+    assert!(range("0.0.0.0/0").contains("203.0.113.9".parse().unwrap()));
+
+    for invalid in &[
+        "10.0.0.0/33",
+        "2001:db8::/129",
+        "10.0.0/8",
+        "localhost",
+        "10.0.0.0/x",
+    ] {
+        assert!(invalid.parse::<IpRange>().is_err(), "{}", invalid);
+    }
+    assert!(User::from_value(json!({ "allowed_ips": ["10.0.0.0/33"] })).is_err());
+}
+
+async fn call_from(state: &Arc<State>, addr: Option<&str>) -> StatusCode {
+    let request = Request::post("/");
+    let request = match addr {
+        Some(addr) => request.extension(addr.parse::<SocketAddr>().unwrap()),
+        None => request,
+    };
+    let (status, _) = send(
+        state,
+        Some(("alice", "secret")),
+        request,
+        &json!({ "id": 1, "method": "getblockcount", "params": [] }),
+    )
+    .await;
+    status
+}
+
+#[tokio::test]
+async fn credentials_only_work_from_allowed_addresses() {
+    let (connector, received) = bitcoind("allowed-ips", |_, _| json!(800000));
+    let state = proxy(
+        connector,
+        json!({
+            "alice": {
+                "password": "secret",
+                "allowed_calls": ["getblockcount"],
+                "allowed_ips": ["10.1.0.0/16", "2001:db8::/32"],
+            }
+        }),
+    )
+    .build()
+    .arc();
+
+    assert_eq!(
+        call_from(&state, Some("10.1.2.3:5000")).await,
+        StatusCode::OK
+    );
+    assert_eq!(
+        call_from(&state, Some("[2001:db8::1]:5000")).await,
+        StatusCode::OK
+    );
+    assert_eq!(
+        call_from(&state, Some("10.2.0.1:5000")).await,
+        StatusCode::FORBIDDEN
+    );
+    // e.g. over a Unix socket
+    assert_eq!(call_from(&state, None).await, StatusCode::FORBIDDEN);
+    assert_eq!(received.lock().unwrap().len(), 2);
+}
@@ -0,0 +1,147 @@
+//! Clients without credentials, served with the permissions of `anonymous_user`.
+#![cfg(unix)]
+
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use btc_rpc_proxy::quota::{Period, Quota, QuotaUsage, Usage};
+use btc_rpc_proxy::rate_limit::{Algorithm, Decision, RateLimit, RateLimiter};
+use btc_rpc_proxy::storage::{self, Memory, Storage};
+use common::local::{bitcoind, proxy, send};
+use hyper::{Request, StatusCode};
+use serde_json::{json, Value};
+
+/// One call per minute for each client.
+fn once_a_minute() -> RateLimit {
+    RateLimit {
+        algorithm: Algorithm::SlidingWindow,
+        requests: 1,
+        period: 60.0,
+        burst: None,
+        methods: Default::default(),
+    }
+}
+
+fn users() -> Value {
+    json!({
+        "anonymous": {
+            "allowed_calls": ["getblockcount"],
+            "rate_limit": [once_a_minute()],
+        }
+    })
+}
+
+async fn call_from(
+    state: &Arc<btc_rpc_proxy::State>,
+    addr: &str,
+    method: &str,
+) -> (StatusCode, Value) {
+    let addr: SocketAddr = addr.parse().unwrap();
+    send(
+        state,
+        None,
+        Request::post("/").extension(addr),
+        &json!({ "id": 1, "method": method, "params": [] }),
+    )
+    .await
+}
+
+#[tokio::test]
+async fn clients_are_limited_per_network() {
+    let (connector, _) = bitcoind("anonymous-network", |_, _| json!(800000));
+    let state = Arc::new(
+        proxy(connector, users())
+            .anonymous_user(Some("anonymous".to_owned()))
+            .build(),
+    );
+
+    let (status, _) = call_from(&state, "[2001:db8::1]:1000", "getblockcount").await;
+    assert_eq!(status, StatusCode::OK);
+    // another address of the same /64
+    let (status, _) = call_from(&state, "[2001:db8::2]:1000", "getblockcount").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    let (status, _) = call_from(&state, "[2001:db8:0:1::1]:1000", "getblockcount").await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = call_from(&state, "192.0.2.1:1000", "getblockcount").await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = call_from(&state, "[::ffff:192.0.2.1]:1000", "getblockcount").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+    // only the listed methods
+    let (status, response) = call_from(&state, "192.0.2.2:1000", "getbalance").await;
+    assert_eq!(status, StatusCode::FORBIDDEN, "{}", response);
+}
+
+#[tokio::test]
+async fn all_clients_share_a_limit() {
+    let (connector, _) = bitcoind("anonymous-tier", |_, _| json!(800000));
+    let state = Arc::new(
+        proxy(connector, users())
+            .anonymous_user(Some("anonymous".to_owned()))
+            .anonymous_rate_limit(RateLimit {
+                requests: 2,
+                ..once_a_minute()
+            })
+            .build(),
+    );
+
+    for client in &["192.0.2.1:1000", "192.0.2.2:1000"] {
+        let (status, _) = call_from(&state, client, "getblockcount").await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    let (status, response) = call_from(&state, "192.0.2.3:1000", "getblockcount").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert!(response["error"]["data"]["retry_after"].as_f64().unwrap() > 0.0);
+}
+
+#[test]
+fn usage_of_anonymous_clients_isnt_stored() {
+    let storage: Arc<dyn Storage> = Arc::new(Memory::default());
+    let usage = QuotaUsage::open(storage.clone(), storage::QUOTA_USAGE).unwrap();
+    let quotas = [Quota {
+        period: Period::Day,
+        rolling: false,
+        requests: Some(10),
+        bytes: None,
+    }];
+    let call = Usage {
+        requests: 1,
+        bytes: 0,
+    };
+    usage.add("alice", &quotas, call);
+    usage.add("anonymous:192.0.2.1", &quotas, call);
+    usage.save().unwrap();
+
+    let stored = storage.load(storage::QUOTA_USAGE).unwrap().unwrap();
+    let users: Vec<_> = stored
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|s| s["user"].as_str().unwrap())
+        .collect();
+    assert_eq!(users, ["alice"]);
+}
+
+#[tokio::test]
+async fn unused_buckets_are_evicted() {
+    let limiter = RateLimiter::memory();
+    let limit = RateLimit {
+        period: 0.001,
+        ..once_a_minute()
+    };
+    for i in 0..1024 {
+        let client = format!("anonymous:192.0.2.{}", i);
+        assert_eq!(
+            limiter.acquire(&client, 0, &limit).await.unwrap(),
+            Decision::Allow
+        );
+    }
+    assert_eq!(limiter.dump().as_array().unwrap().len(), 1024);
+    // kept for a second longer than the state matters
+    tokio::time::delay_for(Duration::from_millis(2100)).await;
+    limiter.acquire("alice", 0, &limit).await.unwrap();
+    assert_eq!(limiter.dump().as_array().unwrap().len(), 1);
+}
@@ -0,0 +1,152 @@
+//! API keys of users: accepted besides the password until they expire or are revoked.
+#![cfg(unix)]
+
+mod common;
+
+use chrono::{DateTime, Duration, Utc};
+use hyper::StatusCode;
+use serde_json::{json, Value};
+
+use common::local::{bitcoind, call, proxy};
+
+fn users() -> Value {
+    json!({
+        "admin": {
+            "password": "secret",
+            "manage_users": true,
+            "allowed_calls": ["proxy_addapikey", "proxy_revokeapikey"],
+        },
+        "alice": {
+            "password": "secret",
+            "allowed_calls": ["getblockcount", "proxy_issuetoken"],
+            "api_key": [
+                { "id": "current", "key": "current-key" },
+                { "id": "old", "key": "old-key", "revoked": true },
+                {
+                    "id": "expired",
+                    "key": "expired-key",
+                    "expires": (Utc::now() - Duration::seconds(1)).to_rfc3339(),
+                },
+            ],
+        },
+    })
+}
+
+#[tokio::test]
+async fn revoked_and_expired_keys_are_rejected() {
+    let (connector, _) = bitcoind("api-keys", |_, _| json!(800000));
+    let state = proxy(connector, users()).build().arc();
+
+    for (password, expected) in &[
+        ("secret", StatusCode::OK),
+        ("current-key", StatusCode::OK),
+        ("old-key", StatusCode::UNAUTHORIZED),
+        ("expired-key", StatusCode::UNAUTHORIZED),
+        // keys belong to their user
+        ("current-key-", StatusCode::UNAUTHORIZED),
+    ] {
+        let (status, _) = call(
+            &state,
+            Some(("alice", password)),
+            "getblockcount",
+            json!([]),
+        )
+        .await;
+        assert_eq!(status, *expected, "{}", password);
+    }
+    let (status, _) = call(
+        &state,
+        Some(("admin", "current-key")),
+        "getblockcount",
+        json!([]),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn keys_are_managed_at_runtime() {
+    let (connector, _) = bitcoind("api-keys-runtime", |_, _| json!(800000));
+    let state = proxy(connector, users()).build().arc();
+    let admin = Some(("admin", "secret"));
+
+    let (_, added) = call(&state, admin, "proxy_addapikey", json!(["alice", "script"])).await;
+    let key = added["result"]["key"].as_str().unwrap().to_owned();
+    let (status, _) = call(&state, Some(("alice", &key)), "getblockcount", json!([])).await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, duplicate) = call(&state, admin, "proxy_addapikey", json!(["alice", "script"])).await;
+    assert!(duplicate["error"].is_object(), "{}", duplicate);
+
+    let (_, revoked) = call(
+        &state,
+        admin,
+        "proxy_revokeapikey",
+        json!(["alice", "script"]),
+    )
+    .await;
+    assert_eq!(revoked["result"], true, "{}", revoked);
+    let (status, _) = call(&state, Some(("alice", &key)), "getblockcount", json!([])).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    // the other credentials are unaffected
+    let (status, _) = call(
+        &state,
+        Some(("alice", "current-key")),
+        "getblockcount",
+        json!([]),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // only for users with manage_users
+    let (_, denied) = call(
+        &state,
+        Some(("alice", "secret")),
+        "proxy_addapikey",
+        json!(["alice", "mine"]),
+    )
+    .await;
+    assert!(denied["error"].is_object(), "{}", denied);
+}
+
+#[tokio::test]
+async fn tokens_go_with_the_key_they_were_issued_with() {
+    let (connector, _) = bitcoind("api-keys-tokens", |_, _| json!(800000));
+    let state = proxy(connector, users()).build().arc();
+    let admin = Some(("admin", "secret"));
+    let expires = Utc::now() + Duration::minutes(5);
+    let (_, added) = call(
+        &state,
+        admin,
+        "proxy_addapikey",
+        json!(["alice", "script", expires.to_rfc3339()]),
+    )
+    .await;
+    let key = added["result"]["key"].as_str().unwrap().to_owned();
+
+    let (_, issued) = call(
+        &state,
+        Some(("alice", &key)),
+        "proxy_issuetoken",
+        json!([3600]),
+    )
+    .await;
+    let token = issued["result"]["token"].as_str().unwrap().to_owned();
+    let token_expires: DateTime<Utc> = issued["result"]["expires"]
+        .as_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(token_expires <= expires, "{}", issued);
+    let (status, _) = call(&state, Some(("Bearer", &token)), "getblockcount", json!([])).await;
+    assert_eq!(status, StatusCode::OK);
+
+    call(
+        &state,
+        admin,
+        "proxy_revokeapikey",
+        json!(["alice", "script"]),
+    )
+    .await;
+    let (status, _) = call(&state, Some(("Bearer", &token)), "getblockcount", json!([])).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
@@ -0,0 +1,168 @@
+//! Sends parked for approval are only decided by approvers, and not forwarded in read-only mode.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use bitcoin::consensus::serialize;
+use bitcoin::{Amount, Transaction, TxIn, TxOut};
+use btc_rpc_proxy::approvals::{sent_amount, Approvals, Approve, GetApproval, Reject};
+use btc_rpc_proxy::client::{GenericRpcMethod, RpcRequest};
+use btc_rpc_proxy::intercept::{Interceptor, RequestContext};
+use btc_rpc_proxy::upstream::Connector;
+use btc_rpc_proxy::{AuthSource, RpcClient, State, User};
+use hyper::HeaderMap;
+use serde_json::{json, Value};
+
+fn request(method: &str, params: Value) -> RpcRequest<GenericRpcMethod> {
+    serde_json::from_value(json!({ "id": 1, "method": method, "params": params })).unwrap()
+}
+
+fn ctx(user: &str) -> RequestContext {
+    RequestContext::new(user.to_owned(), &HeaderMap::new())
+}
+
+fn impersonated(user: &str, admin: &str) -> RequestContext {
+    RequestContext {
+        impersonator: Some(admin.to_owned()),
+        ..ctx(user)
+    }
+}
+
+/// A proxy parking sends of more than 1 BTC, with one send of bob waiting.
+fn state() -> (Arc<State>, String) {
+    state_with(&ctx("bob"))
+}
+
+/// A proxy parking sends of more than 1 BTC, with one send made in `ctx` waiting.
+fn state_with(ctx: &RequestContext) -> (Arc<State>, String) {
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let rpc_client = RpcClient::new(
+        AuthSource::from_config(Some("user".to_owned()), Some("pass".to_owned()), Vec::new())
+            .unwrap(),
+        "http://localhost/".parse().unwrap(),
+        Connector::unix(std::env::temp_dir().join("btc-rpc-proxy-approvals-unused.sock")),
+        logger.clone(),
+    );
+    let approvals = Approvals::new(Amount::ONE_BTC, Duration::from_secs(600), 10);
+    let send = request("sendtoaddress", json!(["bc1qaddress", 2]));
+    let (id, _) = approvals.park("/", &send, ctx).unwrap().unwrap();
+    let state = State::builder(rpc_client, logger)
+        .approvals(Some(approvals))
+        .build()
+        .arc();
+    (state, id)
+}
+
+async fn status(state: &Arc<State>, id: &str) -> Value {
+    let user = User::from_value(json!({})).unwrap();
+    let response = GetApproval
+        .intercept(
+            state.clone(),
+            &user,
+            &request("proxy_getapproval", json!([id])),
+            &ctx("bob"),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+    response.result.unwrap()["status"].clone()
+}
+
+#[test]
+fn sweeps_exceed_any_threshold() {
+    let amount = |params| {
+        sent_amount(&request("sendall", params))
+            .unwrap()
+            .unwrap()
+            .as_btc()
+    };
+    assert_eq!(
+        amount(json!([[{ "bc1qa": 0.5 }, { "bc1qb": "0.25" }]])),
+        0.75
+    );
+    assert_eq!(amount(json!([[{ "bc1qa": 0.5 }, "bc1qb"]])), 21_000_000.0);
+}
+
+#[test]
+fn packages_are_counted() {
+    let tx = |value: u64| {
+        hex::encode(serialize(&Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value,
+                script_pubkey: Default::default(),
+            }],
+        }))
+    };
+    let package = request("submitpackage", json!([[tx(150_000_000), tx(50_000_000)]]));
+    assert_eq!(sent_amount(&package).unwrap().unwrap().as_btc(), 2.0);
+    let approvals = Approvals::new(Amount::ONE_BTC, Duration::from_secs(600), 10);
+    assert!(approvals
+        .park("/", &package, &ctx("bob"))
+        .unwrap()
+        .is_some());
+}
+
+#[tokio::test]
+async fn approving_requires_approve() {
+    let (state, id) = state();
+    let carol = User::from_value(json!({ "allowed_calls": ["proxy_approve"] })).unwrap();
+    let approve = request("proxy_approve", json!([id]));
+    let e = Approve
+        .intercept(state.clone(), &carol, &approve, &ctx("carol"))
+        .await
+        .unwrap_err();
+    assert!(e.message.contains("requires approve"), "{}", e.message);
+    assert_eq!(status(&state, &id).await, "pending");
+}
+
+#[tokio::test]
+async fn approving_is_refused_when_read_only() {
+    let (state, id) = state();
+    state.set_read_only(true);
+    let carol = User::from_value(json!({ "approve": true })).unwrap();
+    let approve = request("proxy_approve", json!([id]));
+    let e = Approve
+        .intercept(state.clone(), &carol, &approve, &ctx("carol"))
+        .await
+        .unwrap_err();
+    assert!(e.message.contains("read-only"), "{}", e.message);
+    assert_eq!(status(&state, &id).await, "pending");
+}
+
+#[tokio::test]
+async fn requests_made_impersonating_are_not_approved_by_the_admin() {
+    let (state, id) = state_with(&impersonated("bob", "admin"));
+    let admin = User::from_value(json!({ "approve": true })).unwrap();
+    let reject = request("proxy_reject", json!([id]));
+    let e = Reject
+        .intercept(state.clone(), &admin, &reject, &ctx("admin"))
+        .await
+        .unwrap_err();
+    assert!(e.message.contains("who made them"), "{}", e.message);
+    assert_eq!(status(&state, &id).await, "pending");
+
+    // bob didn't make it
+    Reject
+        .intercept(state.clone(), &admin, &reject, &ctx("bob"))
+        .await
+        .unwrap();
+    assert_eq!(status(&state, &id).await, "rejected");
+}
+
+#[tokio::test]
+async fn approvals_are_not_decided_impersonating() {
+    let (state, id) = state();
+    let carol = User::from_value(json!({ "approve": true })).unwrap();
+    let reject = request("proxy_reject", json!([id]));
+    for ctx in &[impersonated("carol", "bob"), impersonated("carol", "admin")] {
+        let e = Reject
+            .intercept(state.clone(), &carol, &reject, ctx)
+            .await
+            .unwrap_err();
+        assert!(e.message.contains("impersonating"), "{}", e.message);
+    }
+    assert_eq!(status(&state, &id).await, "pending");
+}
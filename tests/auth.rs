@@ -0,0 +1,49 @@
+//! Basic auth against the users of the config, and what they may use once authenticated.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use btc_rpc_proxy::auth::AuthBackend;
+use btc_rpc_proxy::storage::Memory;
+use btc_rpc_proxy::users::{User, Users};
+use serde_json::json;
+
+#[tokio::test]
+async fn passwords() {
+    let mut users = HashMap::new();
+    users.insert(
+        "alice".to_owned(),
+        User::from_value(json!({ "password": "secret" })).unwrap(),
+    );
+    // e.g. the anonymous user, or one authenticated by tokens only
+    users.insert(
+        "nobody".to_owned(),
+        User::from_value(json!({ "allowed_calls": ["getblockcount"] })).unwrap(),
+    );
+    let users = Users::open(users, HashMap::new(), Arc::new(Memory::default())).unwrap();
+    let login = |user: &'static str, password: &'static str| {
+        let users = &users;
+        async move { users.authenticate(user, password).await.unwrap() }
+    };
+
+    assert_eq!(login("alice", "secret").await.as_deref(), Some("alice"));
+    assert_eq!(login("alice", "").await, None);
+    assert_eq!(login("alice", "wrong").await, None);
+    assert_eq!(login("nobody", "").await, None);
+    assert_eq!(login("nobody", "secret").await, None);
+    assert_eq!(login("unknown", "").await, None);
+}
+
+#[test]
+fn wallets() {
+    let user = User::from_value(json!({ "allowed_wallets": ["hot"] })).unwrap();
+    assert!(user.check_wallet("/wallet/hot", "getbalance").is_ok());
+    assert!(user.check_wallet("/wallet/cold", "getbalance").is_err());
+    // the default wallet, or any wallet named in the parameters
+    assert!(user.check_wallet("/", "getbalance").is_err());
+    assert!(user.check_wallet("/", "loadwallet").is_err());
+    assert!(user.check_wallet("/", "getblockcount").is_ok());
+
+    let unrestricted = User::from_value(json!({})).unwrap();
+    assert!(unrestricted.check_wallet("/", "getbalance").is_ok());
+}
@@ -0,0 +1,73 @@
+//! Time-based logic while the wall clock is stepped, as NTP does on a device that booted with a
+//! wrong clock. Stepping is global, so everything is checked by a single test.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use btc_rpc_proxy::clock;
+use btc_rpc_proxy::quota::{Quota, QuotaUsage};
+use btc_rpc_proxy::storage::Memory;
+use btc_rpc_proxy::tokens::Tokens;
+use btc_rpc_proxy::users::{User, Users};
+use serde_json::json;
+
+fn users() -> Users {
+    let mut users = HashMap::new();
+    users.insert(
+        "alice".to_owned(),
+        User::from_value(json!({ "password": "secret" })).unwrap(),
+    );
+    Users::open(users, HashMap::new(), Arc::new(Memory::default())).unwrap()
+}
+
+async fn tokens() {
+    let users = users();
+    let tokens = Tokens::default();
+    let (token, _) = tokens.issue("alice", "alice", Duration::from_secs(3600), None, None);
+    let valid = |token: &str| {
+        let user = tokens.get(&users, token).unwrap().user;
+        user.check_access(clock::now()).is_ok()
+    };
+
+    // a step forward past the announced expiry doesn't expire the token
+    clock::step(chrono::Duration::days(2));
+    assert!(valid(&token));
+    clock::step(chrono::Duration::days(-2));
+
+    // a step back doesn't keep it alive past its lifetime
+    tokio::time::advance(Duration::from_secs(3599)).await;
+    assert!(valid(&token));
+    clock::step(chrono::Duration::days(-2));
+    tokio::time::advance(Duration::from_secs(1)).await;
+    assert!(!valid(&token));
+    clock::step(chrono::Duration::days(2));
+}
+
+fn quotas() {
+    let usage = QuotaUsage::open(Arc::new(Memory::default()), "quota_usage").unwrap();
+    let quota: Quota = serde_json::from_value(json!({ "period": "day", "requests": 2 })).unwrap();
+    let quotas = [quota];
+
+    // calls counted while the clock was a day ahead still count once it is corrected
+    clock::step(chrono::Duration::days(1));
+    usage.acquire("alice", &quotas).unwrap();
+    clock::step(chrono::Duration::days(-1));
+    usage.acquire("alice", &quotas).unwrap();
+    let exceeded = usage.acquire("alice", &quotas).unwrap_err();
+    // and reset with the current day
+    let tomorrow = (clock::now() + chrono::Duration::days(1))
+        .date()
+        .and_hms(0, 0, 0);
+    assert_eq!(
+        exceeded.data.unwrap()["resets_at"],
+        json!(tomorrow.to_rfc3339())
+    );
+}
+
+#[tokio::test]
+async fn ntp_steps() {
+    tokio::time::pause();
+    tokens().await;
+    quotas();
+}
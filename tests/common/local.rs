@@ -0,0 +1,124 @@
+//! The proxy running in the test process in front of a fake bitcoind on a Unix socket, for
+//! tests of what the proxy decides by itself, which need no node.
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+
+use btc_rpc_proxy::proxy::proxy_request;
+use btc_rpc_proxy::state::StateBuilder;
+use btc_rpc_proxy::storage::Memory;
+use btc_rpc_proxy::upstream::Connector;
+use btc_rpc_proxy::users::{User, Users};
+use btc_rpc_proxy::{AuthSource, RpcClient, State};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use serde_json::{json, Value};
+use tokio::net::UnixListener;
+
+/// The calls a fake bitcoind received.
+pub type Received = Arc<Mutex<Vec<Value>>>;
+
+/// A bitcoind answering each call, also in batches, with the result of `result` for its method
+/// and params.
+pub fn bitcoind(name: &str, result: fn(&str, &Value) -> Value) -> (Connector, Received) {
+    let path = std::env::temp_dir().join(format!(
+        "btc-rpc-proxy-{}-{}.sock",
+        name,
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let received = Received::default();
+    let calls = received.clone();
+    let make_service = make_service_fn(move |_| {
+        let calls = calls.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let calls = calls.clone();
+                async move {
+                    let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                    let answer = |call: Value| {
+                        let response = json!({
+                            "id": call["id"],
+                            "result": result(call["method"].as_str().unwrap(), &call["params"]),
+                            "error": null,
+                        });
+                        calls.lock().unwrap().push(call);
+                        response
+                    };
+                    let response = match serde_json::from_slice(&body).unwrap() {
+                        Value::Array(batch) => batch.into_iter().map(answer).collect(),
+                        call => answer(call),
+                    };
+                    Ok::<_, Infallible>(Response::new(Body::from(response.to_string())))
+                }
+            }))
+        }
+    });
+    let incoming = hyper::server::accept::from_stream(UnixListener::bind(&path).unwrap());
+    tokio::spawn(Server::builder(incoming).serve(make_service));
+    (Connector::unix(path), received)
+}
+
+/// A proxy in front of `bitcoind` with the users of `users`, an object of their definitions.
+pub fn proxy(bitcoind: Connector, users: Value) -> StateBuilder {
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let rpc_client = RpcClient::new(
+        AuthSource::from_config(Some("user".to_owned()), Some("pass".to_owned()), Vec::new())
+            .unwrap(),
+        "http://localhost/".parse().unwrap(),
+        bitcoind,
+        logger.clone(),
+    );
+    let users = serde_json::from_value::<HashMap<String, Value>>(users)
+        .unwrap()
+        .into_iter()
+        .map(|(name, user)| (name, User::from_value(user).unwrap()))
+        .collect();
+    let users = Users::open(users, HashMap::new(), Arc::new(Memory::default())).unwrap();
+    State::builder(rpc_client, logger).users(users)
+}
+
+/// Sends `request` with the credentials `auth`, a basic auth pair or a bearer token, returning
+/// the HTTP status and the response.
+pub async fn send(
+    state: &Arc<State>,
+    auth: Option<(&str, &str)>,
+    request: hyper::http::request::Builder,
+    body: &Value,
+) -> (StatusCode, Value) {
+    let request = match auth {
+        Some(("Bearer", token)) => request.header("Authorization", format!("Bearer {}", token)),
+        Some((user, password)) => request.header(
+            "Authorization",
+            format!("Basic {}", base64::encode(format!("{}:{}", user, password))),
+        ),
+        None => request,
+    };
+    let request = request.body(Body::from(body.to_string())).unwrap();
+    let response = proxy_request(state.clone(), request).await.unwrap();
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let value = if body.is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_slice(&body).unwrap()
+    };
+    (status, value)
+}
+
+/// Calls `method` on `/` as `auth`, returning the status and the response.
+pub async fn call(
+    state: &Arc<State>,
+    auth: Option<(&str, &str)>,
+    method: &str,
+    params: Value,
+) -> (StatusCode, Value) {
+    send(
+        state,
+        auth,
+        Request::post("/"),
+        &json!({ "id": 1, "method": method, "params": params }),
+    )
+    .await
+}
@@ -0,0 +1,325 @@
+//! Harness running the proxy in front of regtest nodes, started from the bitcoind binary in
+//! `BITCOIND_EXE`. Tests using it are skipped if it isn't set. `local` runs the proxy in the test
+//! process instead, in front of a fake bitcoind.
+
+#![allow(dead_code)]
+
+#[cfg(unix)]
+pub mod local;
+
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Error};
+use hyper::{Body, Client, Request, StatusCode};
+use serde_json::{json, Value};
+
+pub const RPC_USER: &str = "test";
+pub const RPC_PASSWORD: &str = "test";
+
+/// How long nodes and the proxy get to start and nodes to sync.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The bitcoind binary to test against, `None` if the test should be skipped.
+pub fn bitcoind_exe() -> Option<PathBuf> {
+    match std::env::var_os("BITCOIND_EXE") {
+        Some(exe) => Some(exe.into()),
+        None => {
+            eprintln!("BITCOIND_EXE is not set, skipping");
+            None
+        }
+    }
+}
+
+/// A port nothing listens on at the moment.
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|l| l.local_addr())
+        .expect("no free port")
+        .port()
+}
+
+/// A directory removed once dropped.
+pub struct TempDir(pub PathBuf);
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "btc-rpc-proxy-{}-{}-{}",
+            name,
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&path).expect("failed to create temporary directory");
+        TempDir(path)
+    }
+}
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Makes the JSON-RPC call `body` to `addr`, returning the HTTP status and the response.
+pub async fn post(
+    addr: SocketAddr,
+    user: &str,
+    password: &str,
+    body: &Value,
+) -> Result<(StatusCode, Value), Error> {
+    let request = Request::post(format!("http://{}/", addr))
+        .header(
+            "Authorization",
+            format!("Basic {}", base64::encode(format!("{}:{}", user, password))),
+        )
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))?;
+    let response = Client::new().request(request).await?;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    let value = if body.is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_slice(&body)
+            .map_err(|e| anyhow!("{}: {}", e, String::from_utf8_lossy(&body)))?
+    };
+    Ok((status, value))
+}
+
+/// Calls `method` at `addr`, failing on errors.
+pub async fn call(
+    addr: SocketAddr,
+    user: &str,
+    password: &str,
+    method: &str,
+    params: Value,
+) -> Result<Value, Error> {
+    let (_, response) = post(
+        addr,
+        user,
+        password,
+        &json!({ "jsonrpc": "1.0", "id": "test", "method": method, "params": params }),
+    )
+    .await?;
+    match &response["error"] {
+        Value::Null => Ok(response["result"].clone()),
+        error => Err(anyhow!("{} failed: {}", method, error)),
+    }
+}
+
+/// Retries `f` until it succeeds or `TIMEOUT` passes.
+pub async fn wait_for<T, F, Fut>(what: &str, mut f: F) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, Error>>,
+{
+    let started = Instant::now();
+    loop {
+        match f().await {
+            Ok(value) => return Ok(value),
+            Err(e) if started.elapsed() > TIMEOUT => {
+                return Err(anyhow!("timed out waiting for {}: {:#}", what, e))
+            }
+            Err(_) => tokio::time::delay_for(Duration::from_millis(200)).await,
+        }
+    }
+}
+
+fn wait_for_port(addr: SocketAddr) -> Result<(), Error> {
+    let started = Instant::now();
+    while TcpStream::connect(addr).is_err() {
+        if started.elapsed() > TIMEOUT {
+            return Err(anyhow!("nothing listens on {}", addr));
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    Ok(())
+}
+
+/// A regtest node.
+pub struct Bitcoind {
+    pub rpc: SocketAddr,
+    pub p2p: SocketAddr,
+    exe: PathBuf,
+    args: Vec<String>,
+    process: Option<Child>,
+    pub dir: TempDir,
+}
+impl Bitcoind {
+    /// Starts a node with the additional arguments `args`, e.g. `-prune=1`.
+    pub async fn start(exe: &Path, args: &[&str]) -> Result<Self, Error> {
+        let dir = TempDir::new("bitcoind");
+        let rpc = SocketAddr::from(([127, 0, 0, 1], free_port()));
+        let p2p = SocketAddr::from(([127, 0, 0, 1], free_port()));
+        let mut all = vec![
+            "-regtest".to_owned(),
+            "-server".to_owned(),
+            "-listen".to_owned(),
+            "-disablewallet".to_owned(),
+            "-printtoconsole=0".to_owned(),
+            format!("-datadir={}", dir.0.display()),
+            format!("-bind={}", p2p),
+            format!("-rpcport={}", rpc.port()),
+            format!("-rpcuser={}", RPC_USER),
+            format!("-rpcpassword={}", RPC_PASSWORD),
+        ];
+        all.extend(args.iter().map(|a| (*a).to_owned()));
+        let mut node = Bitcoind {
+            rpc,
+            p2p,
+            exe: exe.to_owned(),
+            args: all,
+            process: None,
+            dir,
+        };
+        node.restart().await?;
+        Ok(node)
+    }
+
+    /// Starts the node again after `stop`, with the same data and ports.
+    pub async fn restart(&mut self) -> Result<(), Error> {
+        self.process = Some(
+            Command::new(&self.exe)
+                .args(&self.args)
+                .stdout(Stdio::null())
+                .spawn()
+                .map_err(|e| anyhow!("failed to start {}: {}", self.exe.display(), e))?,
+        );
+        wait_for("bitcoind to start", || {
+            self.call("getblockchaininfo", json!([]))
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Stops the node, waiting for it to exit.
+    pub async fn stop(&mut self) -> Result<(), Error> {
+        self.call("stop", json!([])).await?;
+        if let Some(mut process) = self.process.take() {
+            process.wait()?;
+        }
+        Ok(())
+    }
+
+    pub async fn call(&self, method: &str, params: Value) -> Result<Value, Error> {
+        call(self.rpc, RPC_USER, RPC_PASSWORD, method, params).await
+    }
+
+    /// Mines `count` blocks to an anyone-can-spend output.
+    pub async fn mine(&self, count: u64) -> Result<Vec<Value>, Error> {
+        let hashes = self
+            .call("generatetodescriptor", json!([count, "raw(51)"]))
+            .await?;
+        Ok(hashes.as_array().cloned().unwrap_or_default())
+    }
+
+    /// Connects to `other`, which then shows up in `getpeerinfo` as an outbound peer.
+    pub async fn connect(&self, other: &Bitcoind) -> Result<(), Error> {
+        self.call("addnode", json!([other.p2p.to_string(), "onetry"]))
+            .await?;
+        wait_for("nodes to connect", || async {
+            let peers = self.call("getpeerinfo", json!([])).await?;
+            match peers.as_array() {
+                Some(peers) if peers.iter().any(|p| p["addr"] == other.p2p.to_string()) => Ok(()),
+                _ => Err(anyhow!("not connected")),
+            }
+        })
+        .await
+    }
+
+    /// Waits for the node to have the tip of `other`.
+    pub async fn sync_with(&self, other: &Bitcoind) -> Result<(), Error> {
+        let tip = other.call("getbestblockhash", json!([])).await?;
+        wait_for("nodes to sync", || async {
+            match self.call("getbestblockhash", json!([])).await? {
+                hash if hash == tip => Ok(()),
+                _ => Err(anyhow!("not synced")),
+            }
+        })
+        .await
+    }
+}
+impl Drop for Bitcoind {
+    fn drop(&mut self) {
+        if let Some(mut process) = self.process.take() {
+            let _ = process.kill();
+            let _ = process.wait();
+        }
+    }
+}
+
+/// The proxy binary of the crate, in front of a node.
+pub struct Proxy {
+    pub addr: SocketAddr,
+    process: Child,
+    pub dir: TempDir,
+}
+impl Proxy {
+    /// Starts the proxy with `config` appended to the settings reaching `bitcoind`.
+    pub fn start(bitcoind: &Bitcoind, config: &str) -> Result<Self, Error> {
+        let dir = TempDir::new("proxy");
+        let addr = SocketAddr::from(([127, 0, 0, 1], free_port()));
+        let conf = dir.0.join("btc_rpc_proxy.toml");
+        std::fs::write(
+            &conf,
+            format!(
+                "bind_address = \"127.0.0.1\"\n\
+                 bind_port = {}\n\
+                 bitcoind_address = \"127.0.0.1\"\n\
+                 bitcoind_port = {}\n\
+                 bitcoind_user = \"{}\"\n\
+                 bitcoind_password = \"{}\"\n\
+                 network = \"regtest\"\n\
+                 {}",
+                addr.port(),
+                bitcoind.rpc.port(),
+                RPC_USER,
+                RPC_PASSWORD,
+                config
+            ),
+        )?;
+        let log = std::fs::File::create(dir.0.join("proxy.log"))?;
+        let process = Command::new(env!("CARGO_BIN_EXE_btc_rpc_proxy"))
+            .arg("--conf")
+            .arg(&conf)
+            .stdout(Stdio::null())
+            .stderr(log)
+            .spawn()?;
+        let mut proxy = Proxy { addr, process, dir };
+        if let Err(e) = wait_for_port(addr) {
+            return Err(anyhow!("{}, log:\n{}", e, proxy.log()));
+        }
+        if let Ok(Some(status)) = proxy.process.try_wait() {
+            return Err(anyhow!(
+                "the proxy exited with {}:\n{}",
+                status,
+                proxy.log()
+            ));
+        }
+        Ok(proxy)
+    }
+
+    /// What the proxy logged so far, to explain failures.
+    pub fn log(&self) -> String {
+        std::fs::read_to_string(self.dir.0.join("proxy.log")).unwrap_or_default()
+    }
+
+    pub async fn call(
+        &self,
+        user: &str,
+        password: &str,
+        method: &str,
+        params: Value,
+    ) -> Result<Value, Error> {
+        call(self.addr, user, password, method, params).await
+    }
+}
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
@@ -0,0 +1,76 @@
+//! Requests of client libraries that don't quite follow the spec, accepted or rejected depending
+//! on the compatibility toggles.
+
+use btc_rpc_proxy::compat::Compat;
+use hyper::http::request::Parts;
+use hyper::{Body, Request, StatusCode};
+
+fn request(method: &str, headers: &[(&str, &str)], body: &'static str) -> (Parts, Body) {
+    let mut builder = Request::builder().method(method).uri("/");
+    for (name, value) in headers {
+        builder = builder.header(*name, *value);
+    }
+    builder.body(Body::from(body)).unwrap().into_parts()
+}
+
+fn strict() -> Compat {
+    Compat {
+        text_plain: false,
+        missing_content_type: false,
+        get_with_body: false,
+        expect_continue: false,
+    }
+}
+
+#[test]
+fn text_plain_bodies() {
+    let (parts, _) = request(
+        "POST",
+        &[("Content-Type", "Text/Plain; charset=utf-8")],
+        "{}",
+    );
+    assert_eq!(Compat::default().check(&parts), None);
+    assert_eq!(
+        strict().check(&parts),
+        Some(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+    );
+    let (parts, _) = request("POST", &[("Content-Type", "application/json")], "{}");
+    assert_eq!(strict().check(&parts), None);
+}
+
+#[test]
+fn missing_content_type() {
+    let (parts, _) = request("POST", &[], "{}");
+    assert_eq!(Compat::default().check(&parts), None);
+    assert_eq!(
+        strict().check(&parts),
+        Some(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+    );
+}
+
+#[test]
+fn get_with_body() {
+    let compat = Compat {
+        get_with_body: true,
+        ..Compat::default()
+    };
+    let (parts, body) = request("GET", &[], "{}");
+    assert!(compat.accepts_method(&parts, &body));
+    assert!(!Compat::default().accepts_method(&parts, &body));
+    let (parts, body) = request("GET", &[], "");
+    assert!(!compat.accepts_method(&parts, &body));
+    let (parts, body) = request("POST", &[], "{}");
+    assert!(strict().accepts_method(&parts, &body));
+}
+
+#[test]
+fn expect_continue() {
+    let (parts, _) = request("POST", &[("Expect", "100-Continue")], "{}");
+    assert_eq!(Compat::default().check(&parts), None);
+    assert_eq!(strict().check(&parts), Some(StatusCode::EXPECTATION_FAILED));
+    let (parts, _) = request("POST", &[("Expect", "something-else")], "{}");
+    assert_eq!(
+        Compat::default().check(&parts),
+        Some(StatusCode::EXPECTATION_FAILED)
+    );
+}
@@ -0,0 +1,73 @@
+//! Compression of WebSocket messages, checked against streams made by zlib.
+
+#![cfg(feature = "websocket")]
+
+use btc_rpc_proxy::deflate::{compress, decompress};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// A digest of `n` txids, as zlib compressed it below.
+fn digest(n: u64) -> Vec<u8> {
+    let txids: Vec<_> = (0..n).map(|i| format!("\"{:064x}\"", i * 7919)).collect();
+    format!(
+        "{{\"method\":\"txs\",\"params\":{{\"txids\":[{}]}}}}",
+        txids.join(",")
+    )
+    .into_bytes()
+}
+
+/// `digest(40)` compressed by zlib (level 9, dynamic codes) with a sync flush, without the final
+/// `00 00 ff ff`.
+const ZLIB_DYNAMIC: &str = "a4d5316e83210c86e1bb3077f8b10dc6b94ad5c1c6fc6a87a85593a15294bb879ee14342828177413c3cca75dd3fbfb35ccafdef56deca8ffffaf5562e8fbdffcabd782f07387615395ed73ac104672e30d1e64c30a111134c987b80893073309163189838550796a8b5770513dc5a07134da4810965163061440c26a2560213b92798d86f1df4828e4cd00ba239412f4822402fa8bb835ed03003bd201f03f482a62ae805adde412ff8680df4824904f4828519f4823b11e8058f5a412fd8f7ad80895827e8c5ffc70e7ac1e79ca0175223402f84dd412fa499815e888e017a21a60a7a21d1bb958fe7f305";
+
+#[test]
+fn decompresses_zlib_output() {
+    let compressed = hex::decode(ZLIB_DYNAMIC).unwrap();
+    assert_eq!(decompress(&compressed, 1 << 16).unwrap(), digest(40));
+    // zlib without compression, in stored blocks
+    let mut stored = vec![0x00, 0x0b, 0x00, 0xf4, 0xff];
+    stored.extend_from_slice(b"hello world");
+    stored.push(0x00);
+    assert_eq!(decompress(&stored, 1 << 16).unwrap(), b"hello world");
+}
+
+#[test]
+fn round_trips() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let random: Vec<u8> = (0..5000).map(|_| rng.gen()).collect();
+    let runs: Vec<u8> = (0..100_000u32).map(|i| (i / 1000) as u8).collect();
+    let inputs = vec![
+        Vec::new(),
+        b"a".to_vec(),
+        b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa".to_vec(),
+        digest(1),
+        digest(500),
+        random,
+        runs,
+    ];
+    for input in inputs {
+        for window_bits in [8, 12, 15].iter() {
+            let compressed = compress(&input, *window_bits);
+            assert_eq!(
+                decompress(&compressed, input.len()).unwrap(),
+                input,
+                "{} bytes, window of {} bits",
+                input.len(),
+                window_bits
+            );
+        }
+    }
+}
+
+#[test]
+fn txid_digests_compress_well() {
+    let digest = digest(500);
+    assert!(compress(&digest, 15).len() * 3 < digest.len());
+}
+
+#[test]
+fn decompression_is_bounded() {
+    let bomb = compress(&vec![0u8; 1 << 20], 15);
+    assert!(bomb.len() < 10_000);
+    assert!(decompress(&bomb, 1 << 16).is_err());
+    assert!(decompress(&[0xff, 0xff], 1 << 16).is_err());
+}
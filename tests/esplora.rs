@@ -0,0 +1,162 @@
+//! Transactions bitcoind can't find are looked up on Esplora.
+
+#![cfg(all(unix, feature = "peer-fetch"))]
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use bitcoin::consensus::serialize;
+use bitcoin::{OutPoint, Transaction, TxIn, TxOut};
+use btc_rpc_proxy::client::{GenericRpcMethod, RpcRequest};
+use btc_rpc_proxy::fetch_blocks::{Esplora, FetchPolicy};
+use btc_rpc_proxy::intercept::getrawtransaction::EsploraFallback;
+use btc_rpc_proxy::intercept::{Interceptor, RequestContext};
+use btc_rpc_proxy::upstream::Connector;
+use btc_rpc_proxy::{AuthSource, RpcClient, State, User};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, HeaderMap, Request, Response, Server, StatusCode};
+use serde_json::{json, Value};
+use tokio::net::UnixListener;
+
+fn transaction() -> Transaction {
+    Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: OutPoint::default(),
+            ..Default::default()
+        }],
+        output: vec![TxOut {
+            value: 50_000,
+            script_pubkey: Default::default(),
+        }],
+    }
+}
+
+fn socket(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "btc-rpc-proxy-{}-{}.sock",
+        name,
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/// The paths bitcoind received calls on.
+type Paths = Arc<Mutex<Vec<String>>>;
+
+/// A bitcoind without txindex, which finds no transaction.
+fn bitcoind() -> (Connector, Paths) {
+    let path = socket("esplora-bitcoind");
+    let paths = Paths::default();
+    let received = paths.clone();
+    let make_service = make_service_fn(move |_| {
+        let received = received.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let received = received.clone();
+                async move {
+                    received.lock().unwrap().push(req.uri().path().to_owned());
+                    let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                    let call: Value = serde_json::from_slice(&body).unwrap();
+                    let response = json!({
+                        "id": call["id"],
+                        "result": null,
+                        "error": { "code": -5, "message": "No such mempool transaction" },
+                    });
+                    Ok::<_, Infallible>(Response::new(Body::from(response.to_string())))
+                }
+            }))
+        }
+    });
+    let incoming = hyper::server::accept::from_stream(UnixListener::bind(&path).unwrap());
+    tokio::spawn(Server::builder(incoming).serve(make_service));
+    (Connector::unix(path), paths)
+}
+
+/// An Esplora server knowing only `transaction()`.
+fn esplora() -> Connector {
+    let tx = transaction();
+    let path = format!("/api/tx/{}/raw", tx.txid());
+    let raw = serialize(&tx);
+    let make_service = make_service_fn(move |_| {
+        let (path, raw) = (path.clone(), raw.clone());
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let response = if req.uri().path() == path {
+                    Response::new(Body::from(raw.clone()))
+                } else {
+                    let mut response = Response::new(Body::empty());
+                    *response.status_mut() = StatusCode::NOT_FOUND;
+                    response
+                };
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+    let path = socket("esplora");
+    let incoming = hyper::server::accept::from_stream(UnixListener::bind(&path).unwrap());
+    tokio::spawn(Server::builder(incoming).serve(make_service));
+    Connector::unix(path)
+}
+
+fn state(bitcoind: Connector) -> Arc<State> {
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let rpc_client = RpcClient::new(
+        AuthSource::from_config(Some("user".to_owned()), Some("pass".to_owned()), Vec::new())
+            .unwrap(),
+        "http://localhost/".parse().unwrap(),
+        bitcoind,
+        logger.clone(),
+    );
+    let esplora = Esplora::new(&"http://esplora.example/api".parse().unwrap(), esplora());
+    let policy = FetchPolicy::new(HashMap::new(), Some(esplora)).unwrap();
+    State::builder(rpc_client, logger)
+        .fetch_policy(policy)
+        .build()
+        .arc()
+}
+
+async fn getrawtransaction(state: &Arc<State>, user: Value, params: Value) -> Value {
+    let user = User::from_value(user).unwrap();
+    let req: RpcRequest<GenericRpcMethod> =
+        serde_json::from_value(json!({ "id": 1, "method": "getrawtransaction", "params": params }))
+            .unwrap();
+    let ctx = RequestContext {
+        path: "/wallet/hot".to_owned(),
+        ..RequestContext::new("alice".to_owned(), &HeaderMap::new())
+    };
+    let response = EsploraFallback
+        .intercept(state.clone(), &user, &req, &ctx)
+        .await
+        .unwrap();
+    match response {
+        Some(response) => serde_json::to_value(response).unwrap(),
+        None => Value::Null,
+    }
+}
+
+#[tokio::test]
+async fn missing_transactions_are_looked_up() {
+    let (bitcoind, paths) = bitcoind();
+    let state = state(bitcoind);
+    let tx = transaction();
+    let fetching = json!({ "fetch_blocks": true });
+    let found = getrawtransaction(&state, fetching.clone(), json!([tx.txid()])).await;
+    assert_eq!(found["result"], hex::encode(serialize(&tx)));
+    let unknown = json!(["abababababababababababababababababababababababababababababababab"]);
+    let missing = getrawtransaction(&state, fetching.clone(), unknown).await;
+    assert_eq!(missing["error"]["code"], -5);
+    // only non-verbose for now
+    let verbose = getrawtransaction(&state, fetching, json!([tx.txid(), true])).await;
+    assert_eq!(verbose["error"]["code"], -5);
+    // Esplora learns which transactions are asked for, so this is up to fetch_blocks
+    let plain = getrawtransaction(&state, json!({}), json!([tx.txid()])).await;
+    assert_eq!(plain, Value::Null);
+    // bitcoind was asked for the wallet of the request first
+    assert!(paths.lock().unwrap().iter().all(|p| p == "/wallet/hot"));
+    assert_eq!(paths.lock().unwrap().len(), 3);
+}
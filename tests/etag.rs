@@ -0,0 +1,37 @@
+//! Content hashes of responses to calls whose result only depends on their parameters.
+
+use btc_rpc_proxy::etag::tag;
+use hyper::header::{HeaderValue, ETAG};
+use hyper::{Body, Response};
+use serde_json::json;
+
+async fn etag(id: u64, if_none_match: Option<&str>) -> Response<Body> {
+    let body = json!({ "id": id, "result": "00000000deadbeef", "error": null });
+    tag(
+        Response::new(body.to_string().into()),
+        if_none_match
+            .map(HeaderValue::from_str)
+            .transpose()
+            .unwrap()
+            .as_ref(),
+    )
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn ids_dont_change_the_tag() {
+    let first = etag(1, None).await;
+    let tag = first.headers()[ETAG].to_str().unwrap().to_owned();
+    assert_eq!(etag(2, None).await.headers()[ETAG], tag.as_str());
+    assert_eq!(etag(3, Some(&tag)).await.status(), 304);
+    assert_eq!(etag(4, Some(&format!("W/{}", tag))).await.status(), 304);
+}
+
+#[tokio::test]
+async fn any_tag_is_not_a_match() {
+    let response = etag(1, Some("*")).await;
+    assert_eq!(response.status(), 200);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert!(!body.is_empty());
+}
@@ -0,0 +1,60 @@
+//! Topics and encoding of the Server-Sent Events on `/events`.
+
+use btc_rpc_proxy::events::{encode, topics};
+use btc_rpc_proxy::subscriptions::{Delivery, Event, Topic};
+use btc_rpc_proxy::User;
+use hyper::StatusCode;
+use serde_json::json;
+
+fn user(allowed_calls: &[&str]) -> User {
+    User::from_value(json!({ "password": "secret", "allowed_calls": allowed_calls })).unwrap()
+}
+
+#[test]
+fn topics_are_checked() {
+    let both = user(&["getbestblockhash", "getrawmempool"]);
+    let blocks = user(&["getbestblockhash"]);
+    assert_eq!(topics(None, &both).unwrap(), vec![Topic::Block, Topic::Tx]);
+    assert_eq!(topics(None, &blocks).unwrap(), vec![Topic::Block]);
+    assert_eq!(
+        topics(Some("x=1&topics=tx%2Cblock"), &both).unwrap(),
+        vec![Topic::Tx, Topic::Block]
+    );
+    assert_eq!(
+        topics(Some("topics=tx"), &blocks).unwrap_err().0,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        topics(Some("topics=blocks"), &both).unwrap_err().0,
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+        topics(Some("topics="), &both).unwrap_err().0,
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+        topics(None, &user(&["getblockcount"])).unwrap_err().0,
+        StatusCode::FORBIDDEN
+    );
+}
+
+#[test]
+fn events_are_encoded() {
+    let hash = "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
+    let event = Event::Block {
+        hash: hash.parse().unwrap(),
+        height: 0,
+    };
+    assert_eq!(
+        encode(&Delivery::Event(event)).unwrap(),
+        format!(
+            "event: block\ndata: {{\"hash\":\"{}\",\"height\":0}}\n\n",
+            hash
+        )
+    );
+    assert_eq!(
+        encode(&Delivery::Dropped(3)).unwrap(),
+        "event: dropped\ndata: {\"count\":3}\n\n"
+    );
+    assert_eq!(encode(&Delivery::Overflowed), None);
+}
@@ -0,0 +1,105 @@
+//! Credentials which may only be used until they expire, and at certain times of day.
+#![cfg(unix)]
+
+mod common;
+
+use chrono::{Duration, Utc};
+use hyper::StatusCode;
+use serde_json::json;
+
+use common::local::{bitcoind, call, proxy};
+
+#[tokio::test]
+async fn expired_credentials_are_rejected() {
+    let (connector, received) = bitcoind("expiry", |_, _| json!(800000));
+    let state = proxy(
+        connector,
+        json!({
+            "alice": {
+                "password": "secret",
+                "allowed_calls": ["getblockcount"],
+                "expires": (Utc::now() + Duration::days(1)).to_rfc3339(),
+            },
+            "bob": {
+                "password": "secret",
+                "allowed_calls": ["getblockcount"],
+                "valid_until": (Utc::now() - Duration::seconds(1)).to_rfc3339(),
+            },
+        }),
+    )
+    .build()
+    .arc();
+
+    let (status, response) = call(
+        &state,
+        Some(("alice", "secret")),
+        "getblockcount",
+        json!([]),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(response["result"], 800000);
+    let (status, response) =
+        call(&state, Some(("bob", "secret")), "getblockcount", json!([])).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert!(
+        response["error"]["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("Credentials expired at"),
+        "{}",
+        response
+    );
+    assert_eq!(received.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn credentials_are_rejected_outside_their_hours() {
+    let (connector, received) = bitcoind("expiry-hours", |_, _| json!(800000));
+    let now = Utc::now();
+    let window = |from: Duration, to: Duration| {
+        format!(
+            "{}-{}",
+            (now + from).format("%H:%M"),
+            (now + to).format("%H:%M")
+        )
+    };
+    let state = proxy(
+        connector,
+        json!({
+            "alice": {
+                "password": "secret",
+                "allowed_calls": ["getblockcount"],
+                "allowed_hours": [window(Duration::hours(-1), Duration::hours(1))],
+            },
+            "bob": {
+                "password": "secret",
+                "allowed_calls": ["getblockcount"],
+                "allowed_hours": [window(Duration::hours(2), Duration::hours(3))],
+            },
+        }),
+    )
+    .build()
+    .arc();
+
+    let (status, _) = call(
+        &state,
+        Some(("alice", "secret")),
+        "getblockcount",
+        json!([]),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, response) =
+        call(&state, Some(("bob", "secret")), "getblockcount", json!([])).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert!(
+        response["error"]["message"]
+            .as_str()
+            .unwrap()
+            .contains("may only be used between"),
+        "{}",
+        response
+    );
+    assert_eq!(received.lock().unwrap().len(), 1);
+}
@@ -0,0 +1,185 @@
+//! Groups of permissions users inherit by listing them in `groups`.
+#![cfg(unix)]
+
+mod common;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use btc_rpc_proxy::storage::Memory;
+use btc_rpc_proxy::users::{User, Users};
+use btc_rpc_proxy::State;
+use hyper::StatusCode;
+use serde_json::{json, Value};
+
+use common::local::{bitcoind, call, proxy};
+
+fn definitions(users: Value) -> HashMap<String, User> {
+    serde_json::from_value::<HashMap<String, Value>>(users)
+        .unwrap()
+        .into_iter()
+        .map(|(name, user)| (name, User::from_value(user).unwrap()))
+        .collect()
+}
+
+fn open(users: Value, groups: Value) -> Result<Users, anyhow::Error> {
+    Users::open(
+        definitions(users),
+        definitions(groups),
+        Arc::new(Memory::default()),
+    )
+}
+
+fn groups() -> Value {
+    json!({
+        "apps": {
+            "role": "readonly",
+            "allowed_calls": ["savemempool"],
+            "allowed_wallets": ["apps"],
+            "redact": [{ "method": "getnetworkinfo", "fields": ["localaddresses"] }],
+            "rate_limit": [{ "requests": 2, "period": 60, "methods": ["getblockcount"] }],
+        },
+        "ops": {
+            "allowed_calls": ["getnetworkinfo"],
+            "allowed_wallets": ["ops"],
+            "manage_users": true,
+        },
+    })
+}
+
+fn state(name: &str) -> Arc<State> {
+    let (connector, _) = bitcoind(name, |method, _| match method {
+        "getnetworkinfo" => json!({ "version": 270000, "localaddresses": ["192.0.2.1"] }),
+        _ => json!(800000),
+    });
+    let users = json!({
+        "alice": {
+            "password": "secret",
+            "groups": ["apps"],
+            "allowed_calls": ["getnetworkinfo"],
+        },
+        "bob": {
+            "password": "secret",
+            "groups": ["ops", "apps"],
+            "allowed_calls": ["proxy_adduser", "proxy_setpermissions"],
+        },
+    });
+    proxy(connector, json!({}))
+        .users(open(users, groups()).unwrap())
+        .build()
+        .arc()
+}
+
+#[test]
+fn groups_are_checked() {
+    let user = json!({ "alice": { "password": "secret", "groups": ["nope"] } });
+    assert!(open(user, groups()).is_err());
+    for group in &[
+        json!({ "password": "secret" }),
+        json!({ "api_key": [{ "id": "a", "key": "k" }] }),
+        json!({ "groups": ["apps"] }),
+    ] {
+        assert!(
+            open(json!({}), json!({ "bad": group })).is_err(),
+            "{}",
+            group
+        );
+    }
+}
+
+#[test]
+fn settings_of_users_and_earlier_groups_win() {
+    let users = open(
+        json!({
+            "alice": { "groups": ["apps"], "allowed_wallets": ["alice"], "role": "watchonly" },
+            "bob": { "groups": ["ops", "apps"] },
+        }),
+        groups(),
+    )
+    .unwrap();
+    let alice = users.by_name("alice").unwrap();
+    assert!(alice.may_use_wallet("alice") && !alice.may_use_wallet("apps"));
+    assert!(alice.allowed_by("listunspent").is_some());
+    let bob = users.by_name("bob").unwrap();
+    assert!(bob.may_use_wallet("ops") && !bob.may_use_wallet("apps"));
+    assert!(bob.manage_users);
+    assert!(bob.allowed_by("getblockcount").is_some());
+    assert!(bob.allowed_by("getnetworkinfo").is_some());
+    // the definition is kept apart from what's inherited
+    let definition = users.definition("bob").unwrap();
+    assert!(!definition.manage_users);
+    assert!(definition.allowed_by("getnetworkinfo").is_none());
+}
+
+#[tokio::test]
+async fn members_get_the_permissions_of_their_groups() {
+    let state = state("groups");
+    let alice = Some(("alice", "secret"));
+
+    for (method, expected) in &[
+        // from the role of the group
+        ("getblockcount", StatusCode::OK),
+        // from the allowed calls of the group
+        ("savemempool", StatusCode::OK),
+        // from the user's own
+        ("getnetworkinfo", StatusCode::OK),
+        ("sendtoaddress", StatusCode::FORBIDDEN),
+    ] {
+        let (status, response) = call(&state, alice, method, json!([])).await;
+        assert_eq!(status, *expected, "{}: {}", method, response);
+    }
+    // limits of the group apply: its rate limit of two calls
+    let (status, response) = call(&state, alice, "getblockcount", json!([])).await;
+    assert_eq!(status, StatusCode::OK, "{}", response);
+    let (status, response) = call(&state, alice, "getblockcount", json!([])).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS, "{}", response);
+    // and its redactions, for all members
+    for user in &["alice", "bob"] {
+        let (_, info) = call(&state, Some((user, "secret")), "getnetworkinfo", json!([])).await;
+        assert_eq!(info["result"], json!({ "version": 270000 }), "{}", user);
+    }
+}
+
+#[tokio::test]
+async fn users_changed_at_runtime_stay_in_their_groups() {
+    let state = state("groups-runtime");
+    let bob = Some(("bob", "secret"));
+
+    let (_, added) = call(
+        &state,
+        bob,
+        "proxy_adduser",
+        json!(["carol", { "password": "secret", "groups": ["apps"] }]),
+    )
+    .await;
+    assert!(added["error"].is_null(), "{}", added);
+    let (status, _) = call(&state, Some(("carol", "secret")), "savemempool", json!([])).await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, unknown) = call(
+        &state,
+        bob,
+        "proxy_adduser",
+        json!(["dave", { "password": "secret", "groups": ["nope"] }]),
+    )
+    .await;
+    assert!(unknown["error"].is_object(), "{}", unknown);
+
+    // changed permissions don't absorb those of the groups
+    for (fields, expected) in &[
+        (json!({ "allowed_calls": ["uptime"] }), StatusCode::OK),
+        (json!({ "groups": [] }), StatusCode::FORBIDDEN),
+    ] {
+        let (_, changed) = call(
+            &state,
+            bob,
+            "proxy_setpermissions",
+            json!(["carol", fields]),
+        )
+        .await;
+        assert!(changed["error"].is_null(), "{}", changed);
+        let (status, _) = call(&state, Some(("carol", "secret")), "savemempool", json!([])).await;
+        assert_eq!(status, *expected, "{}", fields);
+    }
+    let (status, _) = call(&state, Some(("carol", "secret")), "uptime", json!([])).await;
+    assert_eq!(status, StatusCode::OK);
+}
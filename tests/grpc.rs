@@ -0,0 +1,132 @@
+//! Protobuf messages and the mapping of gRPC calls to JSON-RPC calls.
+
+#![cfg(feature = "grpc")]
+
+use btc_rpc_proxy::client::RpcError;
+use btc_rpc_proxy::grpc::{frame, unframe, Call, Encoder, Field, Message, Status};
+use serde_json::json;
+
+#[test]
+fn messages_roundtrip() {
+    let encoded = Encoder::default()
+        .uint64(1, 300)
+        .string(2, "getblock")
+        .int64(3, -1)
+        .double(4, 1.5)
+        .uint64(5, 0)
+        .string(6, "")
+        .0;
+    assert_eq!(
+        Message::decode(&encoded).unwrap(),
+        Message(vec![
+            (1, Field::Varint(300)),
+            (2, Field::Bytes(b"getblock".to_vec())),
+            (3, Field::Varint(u64::MAX)),
+            (4, Field::Fixed64(1.5f64.to_bits())),
+        ])
+    );
+    assert!(Message::decode(&[0x0a, 0x05, b'a']).is_err());
+    assert!(Message::decode(&[0x0b]).is_err());
+
+    let framed = frame(&encoded);
+    assert_eq!(&framed[..5], &[0, 0, 0, 0, encoded.len() as u8]);
+    assert_eq!(unframe(&framed).unwrap(), &encoded[..]);
+    assert_eq!(
+        unframe(&[1, 0, 0, 0, 0]).unwrap_err().code,
+        Status::UNIMPLEMENTED
+    );
+    assert_eq!(
+        unframe(&[0, 0, 0, 0, 2, 8]).unwrap_err().code,
+        Status::INVALID_ARGUMENT
+    );
+}
+
+#[test]
+fn calls_are_mapped() {
+    let message = Message::decode(&Encoder::default().uint64(1, 100).0).unwrap();
+    let call = Call::from_grpc("GetBlockHash", &message).unwrap();
+    assert_eq!(
+        (call.method(), &call.params),
+        ("getblockhash", &json!([100]))
+    );
+
+    let message = Message::decode(
+        &Encoder::default()
+            .string(1, "getblock")
+            .string(2, r#"["00ab", 2]"#)
+            .string(3, "hot")
+            .0,
+    )
+    .unwrap();
+    let call = Call::from_grpc("Call", &message).unwrap();
+    assert_eq!(call.method(), "getblock");
+    assert_eq!(call.params, json!(["00ab", 2]));
+    assert_eq!(call.wallet.as_deref(), Some("hot"));
+    assert_eq!(
+        call.encode_result(&json!({ "hash": "00ab" })).unwrap(),
+        Encoder::default().string(1, r#"{"hash":"00ab"}"#).0
+    );
+
+    let message = Message::decode(&Encoder::default().string(2, "{}").0).unwrap();
+    assert_eq!(
+        Call::from_grpc("Call", &message).unwrap_err().code,
+        Status::INVALID_ARGUMENT
+    );
+    assert_eq!(
+        Call::from_grpc("GetPeerInfo", &Message::default())
+            .unwrap_err()
+            .code,
+        Status::UNIMPLEMENTED
+    );
+}
+
+#[test]
+fn results_are_encoded() {
+    let call = Call::from_grpc("GetBalances", &Message::default()).unwrap();
+    assert_eq!(call.wallet, None);
+    assert_eq!(
+        call.encode_result(&json!({
+            "mine": { "trusted": 1.00000001, "untrusted_pending": 0, "immature": 0.5 }
+        }))
+        .unwrap(),
+        Encoder::default()
+            .int64(1, 100_000_001)
+            .int64(3, 50_000_000)
+            .0
+    );
+
+    let call = Call::from_grpc("EstimateSmartFee", &Message::default()).unwrap();
+    assert_eq!(
+        call.encode_result(&json!({ "feerate": 0.00012, "blocks": 2 }))
+            .unwrap(),
+        Encoder::default().uint64(1, 12_000).uint64(2, 2).0
+    );
+
+    let call = Call::from_grpc("GetRawTransaction", &Message::default()).unwrap();
+    assert_eq!(
+        call.encode_result(&json!("0200ff")).unwrap(),
+        Encoder::default().bytes(1, &[2, 0, 0xff]).0
+    );
+    assert_eq!(
+        call.encode_result(&json!(5)).unwrap_err().code,
+        Status::INTERNAL
+    );
+}
+
+#[test]
+fn errors_are_mapped() {
+    let status = |code| {
+        Status::from(RpcError {
+            code,
+            message: String::new(),
+            data: None,
+            status: None,
+        })
+        .code
+    };
+    assert_eq!(status(-5), Status::NOT_FOUND);
+    assert_eq!(status(-8), Status::INVALID_ARGUMENT);
+    assert_eq!(status(-32604), Status::PERMISSION_DENIED);
+    assert_eq!(status(-32601), Status::UNIMPLEMENTED);
+    assert_eq!(status(-25), Status::UNKNOWN);
+}
@@ -0,0 +1,193 @@
+//! Calls rewritten by the coin selection guardrails and RBF policies reach bitcoind as
+//! rewritten.
+
+#![cfg(unix)]
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+
+use btc_rpc_proxy::guardrails::{Action, Guardrails};
+use btc_rpc_proxy::proxy::proxy_request;
+use btc_rpc_proxy::storage::Memory;
+use btc_rpc_proxy::upstream::Connector;
+use btc_rpc_proxy::users::{User, Users};
+use btc_rpc_proxy::{AuthSource, RpcClient, State};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use serde_json::{json, Value};
+use tokio::net::UnixListener;
+
+const TXID: &str = "abababababababababababababababababababababababababababababababab";
+
+/// The result of bitcoind for `method`.
+fn result(method: &str) -> Value {
+    match method {
+        // 20 sat/vB to confirm soon, 1 sat/vB to relay
+        "estimatesmartfee" => json!({ "feerate": 0.0002, "blocks": 6 }),
+        "getmempoolinfo" => json!({ "mempoolminfee": 0.00001, "incrementalrelayfee": 0.00001 }),
+        "listtransactions" => json!([{
+            "txid": TXID,
+            "category": "send",
+            "confirmations": 0,
+            "bip125-replaceable": "yes",
+        }]),
+        // 1 sat/vB
+        "getmempoolentry" => json!({ "vsize": 200, "fees": { "base": 0.000002 } }),
+        "bumpfee" => json!({ "txid": TXID }),
+        _ => TXID.into(),
+    }
+}
+
+/// A bitcoind recording the calls it receives.
+fn bitcoind(name: &str) -> (Connector, Arc<Mutex<Vec<Value>>>) {
+    let path = std::env::temp_dir().join(format!(
+        "btc-rpc-proxy-{}-{}.sock",
+        name,
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let calls = received.clone();
+    let make_service = make_service_fn(move |_| {
+        let calls = calls.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let calls = calls.clone();
+                async move {
+                    let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                    let call: Value = serde_json::from_slice(&body).unwrap();
+                    let response = json!({
+                        "id": call["id"],
+                        "result": result(call["method"].as_str().unwrap()),
+                        "error": null,
+                    });
+                    calls.lock().unwrap().push(call);
+                    Ok::<_, Infallible>(Response::new(Body::from(response.to_string())))
+                }
+            }))
+        }
+    });
+    let incoming = hyper::server::accept::from_stream(UnixListener::bind(&path).unwrap());
+    tokio::spawn(Server::builder(incoming).serve(make_service));
+    (Connector::unix(path), received)
+}
+
+fn state(connector: Connector, guardrails: Guardrails) -> Arc<State> {
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let rpc_client = RpcClient::new(
+        AuthSource::from_config(Some("user".to_owned()), Some("pass".to_owned()), Vec::new())
+            .unwrap(),
+        "http://localhost/".parse().unwrap(),
+        connector,
+        logger.clone(),
+    );
+    let mut users = HashMap::new();
+    users.insert(
+        "alice".to_owned(),
+        User::from_value(json!({ "password": "secret", "allowed_calls": ["sendtoaddress", "bumpfee", "proxy_bumpstuck"] }))
+            .unwrap(),
+    );
+    let users = Users::open(users, HashMap::new(), Arc::new(Memory::default())).unwrap();
+    State::builder(rpc_client, logger)
+        .users(users)
+        .guardrails(guardrails)
+        .build()
+        .arc()
+}
+
+async fn call(state: Arc<State>, call: Value) -> Value {
+    let request = Request::post("/")
+        .header(
+            "Authorization",
+            format!("Basic {}", base64::encode("alice:secret")),
+        )
+        .body(Body::from(call.to_string()))
+        .unwrap();
+    let response = proxy_request(state, request).await.unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[tokio::test]
+async fn fee_rates_are_clamped() {
+    let (connector, received) = bitcoind("guardrails-fee-rate");
+    let state = state(
+        connector,
+        Guardrails {
+            fee_rate: Action::Rewrite,
+            min_fee_rate: Some(2.0),
+            max_fee_rate: Some(50.0),
+            ..Guardrails::default()
+        },
+    );
+    let params = json!([
+        "bc1qaddress",
+        0.1,
+        "",
+        "",
+        false,
+        true,
+        null,
+        "unset",
+        null,
+        500
+    ]);
+    let response = call(
+        state,
+        json!({ "id": 1, "method": "sendtoaddress", "params": params }),
+    )
+    .await;
+    assert_eq!(response["result"], TXID);
+    let received = received.lock().unwrap();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0]["method"], "sendtoaddress");
+    assert_eq!(received[0]["params"][9], 50.0);
+}
+
+#[tokio::test]
+async fn replaceability_is_required() {
+    let (connector, received) = bitcoind("guardrails-replaceable");
+    let state = state(
+        connector,
+        Guardrails {
+            replaceable: Action::Rewrite,
+            ..Guardrails::default()
+        },
+    );
+    let params = json!(["bc1qaddress", 0.1, "", "", false, false]);
+    let response = call(
+        state,
+        json!({ "id": 1, "method": "sendtoaddress", "params": params }),
+    )
+    .await;
+    assert_eq!(response["result"], TXID);
+    let received = received.lock().unwrap();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0]["params"][5], true);
+}
+
+#[tokio::test]
+async fn stuck_transactions_are_bumped() {
+    let (connector, received) = bitcoind("guardrails-bumpstuck");
+    let state = state(
+        connector,
+        Guardrails {
+            replaceable: Action::Rewrite,
+            ..Guardrails::default()
+        },
+    );
+    let response = call(
+        state,
+        json!({ "id": 1, "method": "proxy_bumpstuck", "params": [6, true] }),
+    )
+    .await;
+    assert_eq!(response["result"]["stuck"][0]["action"], "bumped");
+    let received = received.lock().unwrap();
+    let bumps: Vec<&Value> = received
+        .iter()
+        .filter(|call| call["method"] == "bumpfee")
+        .collect();
+    assert_eq!(bumps.len(), 1);
+    assert_eq!(bumps[0]["params"], json!([TXID, { "fee_rate": 20.0 }]));
+}
@@ -0,0 +1,98 @@
+//! Headers injected into responses, globally and per listener.
+#![cfg(unix)]
+
+mod common;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use btc_rpc_proxy::headers::ResponseHeaders;
+use btc_rpc_proxy::listen::{Address, Listen};
+use btc_rpc_proxy::upstream::Connector;
+use common::local::{bitcoind, proxy};
+use hyper::{Body, HeaderMap, Request};
+use serde_json::json;
+
+fn socket(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "btc-rpc-proxy-headers-{}-{}.sock",
+        name,
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+fn listen(path: &Path, headers: Option<ResponseHeaders>) -> Listen {
+    Listen {
+        address: Address::Unix(path.to_owned()),
+        #[cfg(feature = "tls")]
+        tls: None,
+        headers: headers.map(Arc::new),
+    }
+}
+
+/// The headers of the response to a call on the socket at `path`.
+async fn headers(path: &Path) -> HeaderMap {
+    for _ in 0..100 {
+        if path.exists() {
+            break;
+        }
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+    let client = hyper::Client::builder().build::<_, Body>(Connector::unix(path.to_owned()));
+    let request = Request::post("http://localhost/")
+        .header(
+            "Authorization",
+            format!("Basic {}", base64::encode("alice:secret")),
+        )
+        .body(Body::from(
+            json!({ "id": 1, "method": "getblockcount", "params": [] }).to_string(),
+        ))
+        .unwrap();
+    let response = client.request(request).await.unwrap();
+    assert!(response.status().is_success(), "{}", response.status());
+    response.headers().clone()
+}
+
+#[tokio::test]
+async fn listeners_send_their_own_headers() {
+    let (connector, _) = bitcoind("headers", |_, _| json!(800000));
+    let users = json!({ "alice": { "password": "secret", "allowed_calls": ["*"] } });
+    let inject = |headers: &[(&str, &str)]| -> HashMap<String, String> {
+        headers
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    };
+    let global = ResponseHeaders::new(None, inject(&[("cache-control", "no-store")])).unwrap();
+    let public = ResponseHeaders::new(
+        None,
+        inject(&[
+            ("cache-control", "no-store"),
+            ("strict-transport-security", "max-age=31536000"),
+        ]),
+    )
+    .unwrap();
+    let (local, exposed) = (socket("local"), socket("public"));
+    let state = Arc::new(
+        proxy(connector, users)
+            .listen(vec![listen(&local, None), listen(&exposed, Some(public))])
+            .response_headers(global)
+            .build(),
+    );
+    tokio::spawn(btc_rpc_proxy::main(state.clone()));
+
+    let headers_local = headers(&local).await;
+    assert_eq!(headers_local["cache-control"], "no-store");
+    assert!(!headers_local.contains_key("strict-transport-security"));
+    let headers_public = headers(&exposed).await;
+    assert_eq!(headers_public["cache-control"], "no-store");
+    assert_eq!(
+        headers_public["strict-transport-security"],
+        "max-age=31536000"
+    );
+    state.shutdown().clone().trigger();
+}
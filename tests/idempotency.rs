@@ -0,0 +1,144 @@
+//! Idempotency keys stay claimed once their call may have executed.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bitcoin::Amount;
+use btc_rpc_proxy::approvals::Approvals;
+use btc_rpc_proxy::client::IDEMPOTENCY_CONFLICT_ERROR_CODE;
+use btc_rpc_proxy::proxy::proxy_request;
+use btc_rpc_proxy::storage::Memory;
+use btc_rpc_proxy::upstream::Connector;
+use btc_rpc_proxy::users::{User, Users};
+use btc_rpc_proxy::{AuthSource, RpcClient, State};
+use hyper::{Body, Request};
+use serde_json::{json, Value};
+
+use common::local::{bitcoind, proxy, send as post};
+
+/// A proxy for a bitcoind that can't be reached.
+fn state(approvals: Option<Approvals>) -> Arc<State> {
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let rpc_client = RpcClient::new(
+        AuthSource::from_config(Some("user".to_owned()), Some("pass".to_owned()), Vec::new())
+            .unwrap(),
+        "http://localhost/".parse().unwrap(),
+        Connector::unix(std::env::temp_dir().join("btc-rpc-proxy-idempotency-gone.sock")),
+        logger.clone(),
+    );
+    let mut users = HashMap::new();
+    users.insert(
+        "alice".to_owned(),
+        User::from_value(json!({ "password": "secret", "allowed_calls": ["sendtoaddress"] }))
+            .unwrap(),
+    );
+    let users = Users::open(users, HashMap::new(), Arc::new(Memory::default())).unwrap();
+    State::builder(rpc_client, logger)
+        .users(users)
+        .approvals(approvals)
+        .build()
+        .arc()
+}
+
+async fn send(state: &Arc<State>) -> Value {
+    let call = json!({ "id": 1, "method": "sendtoaddress", "params": ["bc1qaddress", 2] });
+    let request = Request::post("/")
+        .header(
+            "Authorization",
+            format!("Basic {}", base64::encode("alice:secret")),
+        )
+        .header("Idempotency-Key", "send-1")
+        .body(Body::from(call.to_string()))
+        .unwrap();
+    let response = proxy_request(state.clone(), request).await.unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[tokio::test]
+async fn lost_answers_are_not_retried() {
+    let state = state(None);
+    let first = send(&state).await;
+    assert!(first["error"].is_object(), "{}", first);
+    assert_ne!(first["error"]["code"], IDEMPOTENCY_CONFLICT_ERROR_CODE);
+    let retry = send(&state).await;
+    assert_eq!(retry["error"]["code"], IDEMPOTENCY_CONFLICT_ERROR_CODE);
+    assert!(
+        retry["error"]["message"]
+            .as_str()
+            .unwrap()
+            .contains("unknown"),
+        "{}",
+        retry
+    );
+}
+
+#[tokio::test]
+async fn parked_sends_are_parked_once() {
+    let state = state(Some(Approvals::new(
+        Amount::ONE_BTC,
+        Duration::from_secs(600),
+        10,
+    )));
+    let first = send(&state).await;
+    let id = &first["error"]["data"]["approval_id"];
+    assert!(id.is_string(), "{}", first);
+    let retry = send(&state).await;
+    assert_eq!(&retry["error"]["data"]["approval_id"], id);
+}
+
+#[tokio::test]
+async fn batches_keep_the_results_of_executed_calls() {
+    let (bitcoind, received) = bitcoind("idempotency-batch", |method, _| match method {
+        "sendtoaddress" => "ab".repeat(32).into(),
+        _ => json!(800_000),
+    });
+    let users = json!({
+        "alice": {
+            "password": "secret",
+            "allowed_calls": ["sendtoaddress", "getblockcount"],
+        },
+    });
+    let state = proxy(bitcoind, users)
+        .approvals(Some(Approvals::new(
+            Amount::ONE_BTC,
+            Duration::from_secs(600),
+            10,
+        )))
+        .build()
+        .arc();
+    let batch = json!([
+        { "id": 1, "method": "sendtoaddress", "params": ["bc1qaddress", 0.5] },
+        { "id": 2, "method": "sendtoaddress", "params": ["bc1qaddress", 2] },
+        { "id": 3, "method": "getblockcount", "params": [] },
+    ]);
+    let (_, response) = post(
+        &state,
+        Some(("alice", "secret")),
+        Request::post("/"),
+        &batch,
+    )
+    .await;
+    assert_eq!(response[0]["id"], 1);
+    assert_eq!(response[0]["result"], "ab".repeat(32), "{}", response);
+    assert_eq!(response[1]["id"], 2);
+    assert!(
+        response[1]["error"]["data"]["approval_id"].is_string(),
+        "{}",
+        response
+    );
+    assert_eq!(response[2]["id"], 3);
+    assert_eq!(response[2]["result"], 800_000);
+    let sends = received
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|call| call.to_string().contains("sendtoaddress"))
+        .count();
+    assert_eq!(sends, 1);
+}
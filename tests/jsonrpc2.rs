@@ -0,0 +1,138 @@
+//! Strict JSON-RPC 2.0 framing of requests and responses.
+
+use btc_rpc_proxy::jsonrpc2::{error_code, plan, translate, Plan, Slot};
+use hyper::{Body, Response, StatusCode};
+use serde_json::{json, Value};
+use tokio::stream::StreamExt;
+
+async fn body(response: Response<Body>) -> Value {
+    let body = response
+        .into_body()
+        .collect::<Result<hyper::body::Bytes, _>>()
+        .await
+        .unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+fn planned(request: Value) -> (Value, Plan) {
+    let (forwarded, plan) = plan(request.to_string().as_bytes()).unwrap();
+    (serde_json::from_slice(&forwarded).unwrap(), plan)
+}
+
+#[test]
+fn requests_are_checked() {
+    assert_eq!(
+        planned(json!({ "jsonrpc": "2.0", "id": 1, "method": "getblockcount" })),
+        (
+            json!({ "id": 1, "method": "getblockcount", "params": [] }),
+            Plan {
+                batch: false,
+                slots: vec![Slot::Call {
+                    id: json!(1),
+                    notification: false
+                }],
+            }
+        )
+    );
+    let (forwarded, batch) = planned(json!([
+        { "jsonrpc": "2.0", "method": "ping", "params": [] },
+        { "jsonrpc": "1.0", "id": 2, "method": "getblockcount" },
+        { "jsonrpc": "2.0", "id": 3, "method": "getblockhash", "params": [1] },
+    ]));
+    assert_eq!(
+        forwarded,
+        json!([
+            { "method": "ping", "params": [] },
+            { "id": 3, "method": "getblockhash", "params": [1] },
+        ])
+    );
+    assert_eq!(
+        batch.slots,
+        vec![
+            Slot::Call {
+                id: Value::Null,
+                notification: true
+            },
+            Slot::Invalid { id: json!(2) },
+            Slot::Call {
+                id: json!(3),
+                notification: false
+            },
+        ]
+    );
+
+    for (request, code) in [
+        ("{", -32700),
+        ("[]", -32600),
+        (r#"{"id":1,"method":"getblockcount"}"#, -32600),
+        (
+            r#"{"jsonrpc":"2.0","id":[],"method":"getblockcount"}"#,
+            -32600,
+        ),
+        (
+            r#"{"jsonrpc":"2.0","id":1,"method":"getblock","params":{}}"#,
+            -32600,
+        ),
+    ] {
+        let response = plan(request.as_bytes()).unwrap_err();
+        assert_eq!(response["error"]["code"], json!(code), "{}", request);
+        assert_eq!(response["jsonrpc"], json!("2.0"));
+    }
+    assert_eq!(
+        plan(b"[1, 2]").unwrap_err(),
+        json!([
+            { "jsonrpc": "2.0", "id": null, "error": { "code": -32600, "message": "Invalid Request" } },
+            { "jsonrpc": "2.0", "id": null, "error": { "code": -32600, "message": "Invalid Request" } },
+        ])
+    );
+}
+
+#[tokio::test]
+async fn responses_are_translated() {
+    let (_, single) = planned(json!({ "jsonrpc": "2.0", "id": 1, "method": "getblockcount" }));
+    let response = Response::builder()
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+        .body(Body::from(
+            r#"{"id":1,"result":null,"error":{"code":-32604,"message":"Method not allowed"}}"#,
+        ))
+        .unwrap();
+    let response = translate(&single, response).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        body(response).await,
+        json!({ "jsonrpc": "2.0", "id": 1, "error": { "code": -32004, "message": "Method not allowed" } })
+    );
+
+    let (_, notification) = planned(json!({ "jsonrpc": "2.0", "method": "getblockcount" }));
+    let response = Response::new(Body::from(r#"{"id":null,"result":1,"error":null}"#));
+    let response = translate(&notification, response).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let (_, batch) = planned(json!([
+        { "jsonrpc": "2.0", "method": "ping" },
+        { "jsonrpc": "2.0", "id": "a", "method": "getblockcount" },
+        {},
+    ]));
+    let response = Response::new(Body::from(
+        r#"[{"id":null,"result":null,"error":null},{"id":"a","result":100,"error":null}]"#,
+    ));
+    assert_eq!(
+        body(translate(&batch, response).await.unwrap()).await,
+        json!([
+            { "jsonrpc": "2.0", "id": "a", "result": 100 },
+            { "jsonrpc": "2.0", "id": null, "error": { "code": -32600, "message": "Invalid Request" } },
+        ])
+    );
+
+    // not JSON-RPC
+    let response = Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .body(Body::empty())
+        .unwrap();
+    let response = translate(&batch, response).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    assert_eq!(error_code(-32609), -32009);
+    assert_eq!(error_code(-32601), -32601);
+    assert_eq!(error_code(-5), -5);
+}
@@ -0,0 +1,143 @@
+//! JWTs signed by a configured key, granting the permissions of the user named in a claim.
+#![cfg(unix)]
+
+mod common;
+
+use btc_rpc_proxy::jwt::JwtAuth;
+use chrono::Utc;
+use hyper::StatusCode;
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use serde_json::{json, Value};
+
+use common::local::{bitcoind, call, proxy};
+
+const SECRET: &[u8] = b"a secret shared with the issuer";
+
+fn token(alg: Algorithm, kid: Option<&str>, secret: &[u8], claims: Value) -> String {
+    let header = Header {
+        kid: kid.map(str::to_owned),
+        ..Header::new(alg)
+    };
+    encode(&header, &claims, &EncodingKey::from_secret(secret)).unwrap()
+}
+
+fn claims(user: &str) -> Value {
+    json!({ "sub": user, "exp": Utc::now().timestamp() + 60 })
+}
+
+/// Only accepts tokens signed with `SECRET` using HS256, under the key id `proxy`.
+fn jwks() -> JwtAuth {
+    let mut jwt = JwtAuth::new("sub".to_owned(), None, None);
+    let jwks = json!({ "keys": [{
+        "kty": "oct",
+        "kid": "proxy",
+        "alg": "HS256",
+        "k": base64::encode_config(SECRET, base64::URL_SAFE_NO_PAD),
+    }]});
+    jwt.add_jwk_set(&serde_json::from_value(jwks).unwrap())
+        .unwrap();
+    jwt
+}
+
+#[test]
+fn tokens_must_match_a_key() {
+    let jwt = jwks();
+    let valid = token(Algorithm::HS256, Some("proxy"), SECRET, claims("alice"));
+    assert_eq!(jwt.verify(&valid).unwrap(), "alice");
+    // a token without a key id may be signed by any key
+    let anonymous_key = token(Algorithm::HS256, None, SECRET, claims("alice"));
+    assert_eq!(jwt.verify(&anonymous_key).unwrap(), "alice");
+
+    let wrong_alg = token(Algorithm::HS512, Some("proxy"), SECRET, claims("alice"));
+    assert!(jwt.verify(&wrong_alg).is_err());
+    let wrong_kid = token(Algorithm::HS256, Some("other"), SECRET, claims("alice"));
+    assert!(jwt.verify(&wrong_kid).is_err());
+    assert!(!jwt.knows_key(&wrong_kid));
+    let wrong_secret = token(Algorithm::HS256, Some("proxy"), b"guessed", claims("alice"));
+    assert!(jwt.verify(&wrong_secret).is_err());
+    // unsigned
+    let none = format!(
+        "{}.{}.",
+        base64::encode_config(r#"{"alg":"none","typ":"JWT"}"#, base64::URL_SAFE_NO_PAD),
+        base64::encode_config(claims("alice").to_string(), base64::URL_SAFE_NO_PAD),
+    );
+    assert!(jwt.verify(&none).is_err());
+}
+
+#[test]
+fn expired_tokens_and_other_audiences_are_rejected() {
+    let mut jwt = JwtAuth::new(
+        "sub".to_owned(),
+        Some("btc-rpc-proxy".to_owned()),
+        Some("https://issuer.example".to_owned()),
+    );
+    jwt.add_secret(SECRET);
+    let signed = |claims: Value| token(Algorithm::HS256, None, SECRET, claims);
+    let exp = Utc::now().timestamp() + 60;
+
+    let valid = json!({
+        "sub": "alice",
+        "aud": "btc-rpc-proxy",
+        "iss": "https://issuer.example",
+        "exp": exp,
+    });
+    assert_eq!(jwt.verify(&signed(valid.clone())).unwrap(), "alice");
+    let mut expired = valid.clone();
+    // beyond the leeway of a minute
+    expired["exp"] = json!(Utc::now().timestamp() - 120);
+    assert!(jwt.verify(&signed(expired)).is_err());
+    let mut early = valid.clone();
+    early["nbf"] = json!(Utc::now().timestamp() + 120);
+    assert!(jwt.verify(&signed(early)).is_err());
+    let mut no_exp = valid.clone();
+    no_exp.as_object_mut().unwrap().remove("exp");
+    assert!(jwt.verify(&signed(no_exp)).is_err());
+    let mut audience = valid.clone();
+    audience["aud"] = json!("another-service");
+    assert!(jwt.verify(&signed(audience)).is_err());
+    let mut issuer = valid.clone();
+    issuer["iss"] = json!("https://attacker.example");
+    assert!(jwt.verify(&signed(issuer)).is_err());
+    let mut no_user = valid;
+    no_user.as_object_mut().unwrap().remove("sub");
+    assert!(jwt.verify(&signed(no_user)).is_err());
+}
+
+#[tokio::test]
+async fn tokens_carry_the_permissions_of_their_user() {
+    let (connector, _) = bitcoind("jwt", |_, _| json!(800000));
+    let state = proxy(
+        connector,
+        json!({ "alice": { "allowed_calls": ["getblockcount"] } }),
+    )
+    .jwt(Some(jwks()))
+    .build()
+    .arc();
+
+    let valid = token(Algorithm::HS256, Some("proxy"), SECRET, claims("alice"));
+    let (status, response) =
+        call(&state, Some(("Bearer", &valid)), "getblockcount", json!([])).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(response["result"], 800000);
+    let (status, _) = call(&state, Some(("Bearer", &valid)), "stop", json!([])).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let wrong_alg = token(Algorithm::HS384, Some("proxy"), SECRET, claims("alice"));
+    let (status, _) = call(
+        &state,
+        Some(("Bearer", &wrong_alg)),
+        "getblockcount",
+        json!([]),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let unknown_user = token(Algorithm::HS256, Some("proxy"), SECRET, claims("mallory"));
+    let (status, _) = call(
+        &state,
+        Some(("Bearer", &unknown_user)),
+        "getblockcount",
+        json!([]),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
@@ -0,0 +1,27 @@
+//! The handle shutting the proxy down, as used by applications embedding it.
+
+use btc_rpc_proxy::lifecycle::Shutdown;
+
+#[tokio::test]
+async fn shutdown() {
+    let shutdown = Shutdown::default();
+    let waiters: Vec<_> = (0..3)
+        .map(|_| {
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move { shutdown.triggered().await })
+        })
+        .collect();
+    // waiting before it is triggered
+    tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+    assert!(!shutdown.is_triggered());
+
+    // any clone triggers it, for every waiter
+    shutdown.clone().trigger();
+    assert!(shutdown.is_triggered());
+    for waiter in waiters {
+        waiter.await.unwrap();
+    }
+    // and those coming late
+    shutdown.triggered().await;
+    shutdown.clone().triggered().await;
+}
@@ -0,0 +1,87 @@
+//! Entries of `bind` and the sockets bound for them.
+
+use btc_rpc_proxy::listen::{Address, Bind};
+use serde_json::json;
+
+#[test]
+fn entries() {
+    let bind: Vec<Bind> = serde_json::from_value(json!([
+        "127.0.0.1:8331",
+        "[::1]:8331",
+        { "address": "192.168.1.2:8332", "tls_cert": "cert.pem", "tls_key": "key.pem" },
+    ]))
+    .unwrap();
+    assert_eq!(
+        bind[0],
+        Bind::from(Address::Tcp(([127, 0, 0, 1], 8331).into()))
+    );
+    assert_eq!(bind[1].address.to_string(), "[::1]:8331");
+    assert_eq!(bind[2].address.to_string(), "192.168.1.2:8332");
+    assert_eq!(bind[2].tls_cert, Some("cert.pem".into()));
+    assert_eq!(bind[2].tls_key, Some("key.pem".into()));
+    assert_eq!(bind[2].tls_client_ca, None);
+
+    assert!(!bind[2].has_headers());
+
+    let public: Bind = serde_json::from_value(json!({
+        "address": "0.0.0.0:8332",
+        "forward_response_headers": ["content-type"],
+        "response_header": { "Strict-Transport-Security": "max-age=31536000" },
+    }))
+    .unwrap();
+    assert!(public.has_headers());
+    assert_eq!(
+        public.forward_response_headers,
+        Some(vec!["content-type".to_owned()])
+    );
+    assert_eq!(
+        public.response_header["Strict-Transport-Security"],
+        "max-age=31536000"
+    );
+
+    assert!(serde_json::from_value::<Bind>(json!("localhost")).is_err());
+    assert!(
+        serde_json::from_value::<Bind>(json!({ "address": "127.0.0.1:1", "tls": true })).is_err()
+    );
+    assert!(serde_json::from_value::<Bind>(json!({ "tls_cert": "cert.pem" })).is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn unix_sockets() {
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+
+    use btc_rpc_proxy::listen::Listener;
+
+    let bind: Bind = serde_json::from_value(json!("unix:/run/btc-rpc-proxy.sock")).unwrap();
+    assert_eq!(
+        bind.address,
+        Address::Unix("/run/btc-rpc-proxy.sock".into())
+    );
+    assert_eq!(bind.address.to_string(), "unix:/run/btc-rpc-proxy.sock");
+    assert!("unix:".parse::<Address>().is_err());
+
+    let path = std::env::temp_dir().join(format!("btc-rpc-proxy-listen-{}", std::process::id()));
+    let address = Address::Unix(path.clone());
+    let listener = Listener::bind(&address).unwrap();
+    assert_eq!(listener.address().unwrap(), address);
+    // in use
+    assert!(Listener::bind(&address).is_err());
+
+    // handed over as a file descriptor, it is still known for a Unix socket
+    let fd = match listener {
+        Listener::Unix(listener) => listener.into_raw_fd(),
+        Listener::Tcp(_) => unreachable!(),
+    };
+    let listener = unsafe { Listener::from_raw_fd(fd) };
+    assert!(matches!(listener, Listener::Unix(_)));
+    assert_eq!(listener.address().unwrap(), address);
+
+    // left behind by a process that is gone, it is replaced
+    drop(listener);
+    assert!(path.exists());
+    let listener = Listener::bind(&address).unwrap();
+    assert_eq!(listener.address().unwrap(), address);
+    drop(listener);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,83 @@
+//! Lockout of addresses and user names after repeated failed authentication.
+#![cfg(unix)]
+
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use btc_rpc_proxy::lockout::Lockout;
+use btc_rpc_proxy::State;
+use hyper::{Request, StatusCode};
+use serde_json::{json, Value};
+
+use common::local::{bitcoind, proxy, send};
+
+fn state(name: &str) -> Arc<State> {
+    let (connector, _) = bitcoind(name, |_, _| json!(800000));
+    proxy(
+        connector,
+        json!({
+            "alice": { "password": "secret", "allowed_calls": ["getblockcount"] },
+            "bob": { "password": "secret", "allowed_calls": ["getblockcount"] },
+        }),
+    )
+    .lockout(Some(Lockout::new(
+        3,
+        Duration::from_secs(60),
+        Duration::from_secs(1),
+    )))
+    .build()
+    .arc()
+}
+
+async fn login(state: &Arc<State>, from: &str, user: &str, password: &str) -> (StatusCode, Value) {
+    send(
+        state,
+        Some((user, password)),
+        Request::post("/").extension(from.parse::<SocketAddr>().unwrap()),
+        &json!({ "id": 1, "method": "getblockcount", "params": [] }),
+    )
+    .await
+}
+
+#[tokio::test]
+async fn repeated_failures_lock_out_the_address_and_user() {
+    let state = state("lockout");
+    for _ in 0..3 {
+        let (status, _) = login(&state, "192.0.2.1:1000", "alice", "guess").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+    // even with the right password
+    let (status, response) = login(&state, "192.0.2.1:1000", "alice", "secret").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert!(response["error"]["data"]["retry_after"].as_f64().unwrap() <= 1.0);
+    // the user name from elsewhere
+    let (status, _) = login(&state, "192.0.2.2:1000", "alice", "secret").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    // the address for another user
+    let (status, _) = login(&state, "192.0.2.1:1000", "bob", "secret").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    let (status, _) = login(&state, "192.0.2.2:1000", "bob", "secret").await;
+    assert_eq!(status, StatusCode::OK);
+
+    tokio::time::delay_for(Duration::from_millis(1100)).await;
+    let (status, _) = login(&state, "192.0.2.1:1000", "alice", "secret").await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn logging_in_resets_the_failures_of_the_user() {
+    let state = state("lockout-reset");
+    for _ in 0..2 {
+        login(&state, "192.0.2.1:1000", "alice", "guess").await;
+    }
+    let (status, _) = login(&state, "192.0.2.1:1000", "alice", "secret").await;
+    assert_eq!(status, StatusCode::OK);
+    for _ in 0..2 {
+        login(&state, "192.0.2.2:1000", "alice", "guess").await;
+    }
+    let (status, _) = login(&state, "192.0.2.3:1000", "alice", "secret").await;
+    assert_eq!(status, StatusCode::OK);
+}
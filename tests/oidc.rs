@@ -0,0 +1,270 @@
+//! Access tokens of an OpenID Connect provider, mapped to users by a claim or by their scopes.
+#![cfg(unix)]
+
+mod common;
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use btc_rpc_proxy::oidc::Oidc;
+use chrono::Utc;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Response, Server, StatusCode};
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use serde_json::{json, Value};
+
+use common::local::{bitcoind, call, proxy};
+
+const AUDIENCE: &str = "btc-rpc-proxy";
+
+/// Ids and secrets of HS256 keys.
+type Keys = Vec<(&'static str, &'static [u8])>;
+
+/// A provider publishing the HS256 keys of `keys` by id, which can be rotated, and counting how
+/// often they were fetched.
+struct Provider {
+    issuer: String,
+    keys: Arc<Mutex<Keys>>,
+    fetched: Arc<AtomicUsize>,
+}
+impl Provider {
+    /// None where connections to loopback addresses aren't permitted, as in some sandboxes.
+    async fn start(keys: Keys) -> Option<Self> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").ok()?;
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        if let Err(e) = tokio::net::TcpStream::connect(addr).await {
+            eprintln!("skipped, can't connect to {}: {}", addr, e);
+            return None;
+        }
+        let issuer = format!("http://{}", addr);
+        let keys = Arc::new(Mutex::new(keys));
+        let fetched = Arc::new(AtomicUsize::new(0));
+        let (published, count, base) = (keys.clone(), fetched.clone(), issuer.clone());
+        let make_service = make_service_fn(move |_| {
+            let (keys, fetched, issuer) = (published.clone(), count.clone(), base.clone());
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: hyper::Request<Body>| {
+                    let body = match req.uri().path() {
+                        "/.well-known/openid-configuration" => json!({
+                            "issuer": issuer,
+                            "jwks_uri": format!("{}/jwks", issuer),
+                        }),
+                        _ => {
+                            fetched.fetch_add(1, Ordering::SeqCst);
+                            let keys: Vec<_> = keys
+                                .lock()
+                                .unwrap()
+                                .iter()
+                                .map(|(kid, secret)| {
+                                    json!({
+                                        "kty": "oct",
+                                        "kid": kid,
+                                        "alg": "HS256",
+                                        "k": base64::encode_config(secret, base64::URL_SAFE_NO_PAD),
+                                    })
+                                })
+                                .collect();
+                            json!({ "keys": keys })
+                        }
+                    };
+                    async move { Ok::<_, Infallible>(Response::new(Body::from(body.to_string()))) }
+                }))
+            }
+        });
+        tokio::spawn(Server::from_tcp(listener).unwrap().serve(make_service));
+        Some(Provider {
+            issuer,
+            keys,
+            fetched,
+        })
+    }
+
+    fn token(&self, kid: &str, secret: &[u8], claims: Value) -> String {
+        let mut all = json!({
+            "iss": self.issuer,
+            "aud": AUDIENCE,
+            "sub": "subject-1",
+            "exp": Utc::now().timestamp() + 60,
+        });
+        all.as_object_mut()
+            .unwrap()
+            .extend(claims.as_object().unwrap().clone());
+        let header = Header {
+            kid: Some(kid.to_owned()),
+            ..Header::new(Algorithm::HS256)
+        };
+        encode(&header, &all, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    fn oidc(&self) -> Oidc {
+        oidc(&self.issuer)
+    }
+}
+
+fn oidc(issuer: &str) -> Oidc {
+    let scopes: HashMap<_, _> = vec![
+        ("bitcoin:read".to_owned(), "public".to_owned()),
+        ("bitcoin:admin".to_owned(), "admin".to_owned()),
+    ]
+    .into_iter()
+    .collect();
+    Oidc::new(
+        issuer.to_owned(),
+        AUDIENCE.to_owned(),
+        Some("proxy_user".to_owned()),
+        "scope".to_owned(),
+        scopes,
+        None,
+        Duration::from_secs(3600),
+    )
+    .unwrap()
+}
+
+fn users() -> Value {
+    json!({
+        "public": { "allowed_calls": ["getblockcount"] },
+        "admin": { "allowed_calls": ["getblockcount", "getbalance"] },
+    })
+}
+
+#[tokio::test]
+async fn tokens_map_to_users() {
+    let provider = match Provider::start(vec![("one", b"first secret")]).await {
+        Some(provider) => provider,
+        None => return,
+    };
+    let (connector, _) = bitcoind("oidc", |_, _| json!(800000));
+    let state = proxy(connector, users())
+        .oidc(Some(provider.oidc()))
+        .build()
+        .arc();
+    let read = provider.token(
+        "one",
+        b"first secret",
+        json!({ "scope": "openid bitcoin:read" }),
+    );
+    let (status, _) = call(&state, Some(("Bearer", &read)), "getblockcount", json!([])).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = call(&state, Some(("Bearer", &read)), "getbalance", json!([])).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // the user claim wins over scopes
+    let named = provider.token(
+        "one",
+        b"first secret",
+        json!({ "scope": "bitcoin:read", "proxy_user": "admin" }),
+    );
+    let (status, _) = call(&state, Some(("Bearer", &named)), "getbalance", json!([])).await;
+    assert_eq!(status, StatusCode::OK);
+
+    for rejected in &[
+        // no mapped scope
+        provider.token("one", b"first secret", json!({ "scope": "openid" })),
+        provider.token("one", b"guessed", json!({ "scope": "bitcoin:read" })),
+        provider.token(
+            "one",
+            b"first secret",
+            json!({ "scope": "bitcoin:read", "aud": "other" }),
+        ),
+        provider.token(
+            "one",
+            b"first secret",
+            json!({ "scope": "bitcoin:read", "iss": "http://attacker" }),
+        ),
+        provider.token(
+            "one",
+            b"first secret",
+            json!({ "scope": "bitcoin:read", "exp": Utc::now().timestamp() - 120 }),
+        ),
+    ] {
+        let (status, _) = call(
+            &state,
+            Some(("Bearer", rejected)),
+            "getblockcount",
+            json!([]),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
+
+#[tokio::test]
+async fn rotated_keys_are_fetched() {
+    let provider = match Provider::start(vec![("one", b"first secret")]).await {
+        Some(provider) => provider,
+        None => return,
+    };
+    let (connector, _) = bitcoind("oidc-rotation", |_, _| json!(800000));
+    let state = proxy(connector, users())
+        .oidc(Some(provider.oidc()))
+        .build()
+        .arc();
+    let scope = json!({ "scope": "bitcoin:read" });
+
+    let first = provider.token("one", b"first secret", scope.clone());
+    let (status, _) = call(&state, Some(("Bearer", &first)), "getblockcount", json!([])).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(provider.fetched.load(Ordering::SeqCst), 1);
+
+    provider
+        .keys
+        .lock()
+        .unwrap()
+        .push(("two", b"second secret"));
+    let second = provider.token("two", b"second secret", scope.clone());
+    let (status, _) = call(
+        &state,
+        Some(("Bearer", &second)),
+        "getblockcount",
+        json!([]),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(provider.fetched.load(Ordering::SeqCst), 2);
+
+    // unknown keys don't make the proxy fetch the keys on every request
+    let unknown = provider.token("three", b"third secret", scope);
+    for _ in 0..3 {
+        let (status, _) = call(
+            &state,
+            Some(("Bearer", &unknown)),
+            "getblockcount",
+            json!([]),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+    assert_eq!(provider.fetched.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn tokens_are_rejected_while_the_provider_is_unreachable() {
+    // nothing listens on the discard port
+    let issuer = "http://127.0.0.1:9";
+    let (connector, received) = bitcoind("oidc-unreachable", |_, _| json!(800000));
+    let state = proxy(connector, users())
+        .oidc(Some(oidc(issuer)))
+        .build()
+        .arc();
+    let header = Header {
+        kid: Some("one".to_owned()),
+        ..Header::new(Algorithm::HS256)
+    };
+    let claims = json!({
+        "iss": issuer,
+        "aud": AUDIENCE,
+        "sub": "subject-1",
+        "exp": Utc::now().timestamp() + 60,
+        "scope": "bitcoin:read",
+    });
+    let token = encode(&header, &claims, &EncodingKey::from_secret(b"secret")).unwrap();
+    for token in &[token.as_str(), "not a token"] {
+        let (status, _) = call(&state, Some(("Bearer", token)), "getblockcount", json!([])).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+    assert!(received.lock().unwrap().is_empty());
+}
@@ -0,0 +1,102 @@
+//! Publishing an onion service through Tor's control port, played by a stand-in on the other end
+//! of a socket pair.
+
+#![cfg(unix)]
+
+use bitcoin::hashes::{hmac, sha256, Hash, HashEngine};
+use btc_rpc_proxy::onion::Control;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+
+const COOKIE: [u8; 32] = [7; 32];
+const SERVER_NONCE: [u8; 32] = [9; 32];
+const SERVICE_ID: &str = "proxyxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx";
+const SERVER_HASH_KEY: &[u8] = b"Tor safe cookie authentication server-to-controller hash";
+const CLIENT_HASH_KEY: &[u8] = b"Tor safe cookie authentication controller-to-server hash";
+
+fn hash(key: &[u8], message: &[u8]) -> String {
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(key);
+    engine.input(message);
+    hex::encode(&hmac::Hmac::<sha256::Hash>::from_engine(engine)[..])
+}
+
+/// A Tor authenticating controllers with the cookie at `cookie_file`.
+async fn tor(stream: UnixStream, cookie_file: String) {
+    let mut stream = BufReader::new(stream);
+    let mut message = Vec::new();
+    let mut authenticated = false;
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await.unwrap() == 0 {
+            return;
+        }
+        let words: Vec<&str> = line.trim_end().split(' ').collect();
+        let reply = match words[..] {
+            ["PROTOCOLINFO", "1"] => [
+                "250-PROTOCOLINFO 1\r\n",
+                &format!(
+                    "250-AUTH METHODS=COOKIE,SAFECOOKIE COOKIEFILE=\"{}\"\r\n",
+                    cookie_file.replace('\\', "\\\\")
+                ),
+                "250-VERSION Tor=\"0.4.8.9\"\r\n",
+                "250 OK\r\n",
+            ]
+            .concat(),
+            ["AUTHCHALLENGE", "SAFECOOKIE", nonce] => {
+                let nonce = hex::decode(nonce).unwrap();
+                message = [&COOKIE[..], &nonce, &SERVER_NONCE[..]].concat();
+                format!(
+                    "250 AUTHCHALLENGE SERVERHASH={} SERVERNONCE={}\r\n",
+                    hash(SERVER_HASH_KEY, &message),
+                    hex::encode(SERVER_NONCE)
+                )
+            }
+            ["AUTHENTICATE", client_hash] if client_hash == hash(CLIENT_HASH_KEY, &message) => {
+                authenticated = true;
+                "250 OK\r\n".to_owned()
+            }
+            ["AUTHENTICATE", ..] => "515 Authentication failed\r\n".to_owned(),
+            ["ADD_ONION", "NEW:ED25519-V3", "Port=8331,127.0.0.1:8331"] if authenticated => {
+                format!(
+                    "250-ServiceID={}\r\n250-PrivateKey=ED25519-V3:a2V5\r\n250 OK\r\n",
+                    SERVICE_ID
+                )
+            }
+            ["ADD_ONION", "ED25519-V3:a2V5", "Port=80,unix:/run/proxy.sock"] if authenticated => {
+                format!("250-ServiceID={}\r\n250 OK\r\n", SERVICE_ID)
+            }
+            _ => "510 Unrecognized command\r\n".to_owned(),
+        };
+        stream.get_mut().write_all(reply.as_bytes()).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn publish() {
+    let cookie_file = std::env::temp_dir().join(format!(
+        "btc-rpc-proxy-control-{}.authcookie",
+        std::process::id()
+    ));
+    std::fs::write(&cookie_file, COOKIE).unwrap();
+    let (client, server) = UnixStream::pair().unwrap();
+    tokio::spawn(tor(server, cookie_file.to_str().unwrap().to_owned()));
+
+    let mut control = Control::new(client);
+    control.authenticate(None).await.unwrap();
+    let (service_id, key) = control
+        .add_onion(None, 8331, "127.0.0.1:8331")
+        .await
+        .unwrap();
+    assert_eq!(service_id, SERVICE_ID);
+    assert_eq!(key.as_deref(), Some("ED25519-V3:a2V5"));
+    // the same address again
+    let (service_id, key) = control
+        .add_onion(Some("ED25519-V3:a2V5"), 80, "unix:/run/proxy.sock")
+        .await
+        .unwrap();
+    assert_eq!(service_id, SERVICE_ID);
+    assert_eq!(key, None);
+    let refused = control.command("GETINFO version").await.unwrap_err();
+    assert!(refused.to_string().contains("510"));
+    std::fs::remove_file(&cookie_file).unwrap();
+}
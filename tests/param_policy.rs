@@ -0,0 +1,124 @@
+//! Restrictions on the parameters of calls, checked before they reach bitcoind.
+#![cfg(unix)]
+
+mod common;
+
+use hyper::{Request, StatusCode};
+use serde_json::json;
+
+use common::local::{bitcoind, call, proxy, send};
+
+const POLICY_VIOLATION: i64 = -32608;
+
+#[tokio::test]
+async fn calls_violating_a_policy_are_rejected() {
+    let (connector, received) = bitcoind("param-policy", |_, _| json!(null));
+    let state = proxy(
+        connector,
+        json!({
+            "alice": {
+                "password": "secret",
+                "allowed_calls": ["sendtoaddress", "estimatesmartfee", "getblockcount"],
+                "param_policy": [
+                    { "method": "sendtoaddress", "param": 1, "max": 0.01 },
+                    { "method": "estimatesmartfee", "param": 0, "min": 2, "max": 144, "required": true },
+                    { "method": "estimatesmartfee", "param": 1, "allowed": ["economical", "conservative"] },
+                ],
+            }
+        }),
+    )
+    .build()
+    .arc();
+    let alice = Some(("alice", "secret"));
+    let address = "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080";
+
+    let allowed = [
+        ("sendtoaddress", json!([address, 0.01])),
+        // amounts in strings count too
+        ("sendtoaddress", json!([address, "0.005"])),
+        ("estimatesmartfee", json!([6])),
+        ("estimatesmartfee", json!([144, "economical"])),
+        ("getblockcount", json!([])),
+    ];
+    for (method, params) in &allowed {
+        let (status, response) = call(&state, alice, method, params.clone()).await;
+        assert_eq!(
+            status,
+            StatusCode::OK,
+            "{} {}: {}",
+            method,
+            params,
+            response
+        );
+    }
+
+    let denied = [
+        ("sendtoaddress", json!([address, 0.02])),
+        ("sendtoaddress", json!([address, "1"])),
+        ("sendtoaddress", json!([address, "a lot"])),
+        ("estimatesmartfee", json!([1])),
+        ("estimatesmartfee", json!([])),
+        ("estimatesmartfee", json!([null])),
+        ("estimatesmartfee", json!([6, "unset"])),
+    ];
+    for (method, params) in &denied {
+        let (status, response) = call(&state, alice, method, params.clone()).await;
+        assert_eq!(
+            status,
+            StatusCode::FORBIDDEN,
+            "{} {}: {}",
+            method,
+            params,
+            response
+        );
+        assert_eq!(response["error"]["code"], POLICY_VIOLATION);
+        assert_eq!(response["error"]["data"]["method"], *method);
+    }
+    assert_eq!(received.lock().unwrap().len(), allowed.len());
+
+    // nor inside a batch
+    let (_, responses) = send(
+        &state,
+        alice,
+        Request::post("/"),
+        &json!([
+            { "id": 1, "method": "getblockcount", "params": [] },
+            { "id": 2, "method": "sendtoaddress", "params": [address, 5] },
+        ]),
+    )
+    .await;
+    assert!(responses[0]["error"].is_null(), "{}", responses);
+    assert_eq!(responses[1]["error"]["code"], POLICY_VIOLATION);
+    assert_eq!(received.lock().unwrap().len(), allowed.len() + 1);
+}
+
+#[tokio::test]
+async fn violations_describe_the_rule() {
+    let (connector, _) = bitcoind("param-policy-error", |_, _| json!(null));
+    let state = proxy(
+        connector,
+        json!({
+            "alice": {
+                "password": "secret",
+                "allowed_calls": ["sendtoaddress"],
+                "param_policy": [{ "method": "sendtoaddress", "param": 1, "max": 0.01 }],
+            }
+        }),
+    )
+    .build()
+    .arc();
+
+    let (_, response) = call(
+        &state,
+        Some(("alice", "secret")),
+        "sendtoaddress",
+        json!(["bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080", 0.5]),
+    )
+    .await;
+    assert_eq!(
+        response["error"]["message"],
+        "Parameter 1 of sendtoaddress must be at most 0.01, got 0.5"
+    );
+    assert_eq!(response["error"]["data"]["param"], 1);
+    assert_eq!(response["error"]["data"]["max"], 0.01);
+}
@@ -0,0 +1,169 @@
+//! Results the proxy doesn't need to inspect are passed through as bitcoind wrote them.
+
+#![cfg(unix)]
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+
+use btc_rpc_proxy::client::{RpcResponse, SingleOrBatchRpcRequest};
+use btc_rpc_proxy::headers::ResponseHeaders;
+use btc_rpc_proxy::proxy::proxy_request;
+use btc_rpc_proxy::upstream::Connector;
+use btc_rpc_proxy::warnings;
+use btc_rpc_proxy::{AuthSource, RpcClient, State};
+use hyper::header::{SERVER, WWW_AUTHENTICATE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use serde_json::json;
+use tokio::net::UnixListener;
+use tokio::stream::StreamExt;
+
+/// Formatted the way serializing a parsed result wouldn't.
+const RESULT: &str = r#"{ "hex" : "00ff",  "fee": 0.00001000 }"#;
+
+async fn text(response: Response<Body>) -> String {
+    let body = response
+        .into_body()
+        .collect::<Result<hyper::body::Bytes, _>>()
+        .await
+        .unwrap();
+    String::from_utf8(body.to_vec()).unwrap()
+}
+
+#[tokio::test]
+async fn batches_are_passed_through() {
+    let path = std::env::temp_dir().join(format!(
+        "btc-rpc-proxy-passthrough-{}.sock",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|_| async {
+            let body = format!(r#"[{{"id":2,"result":{},"error":null}}]"#, RESULT);
+            Ok::<_, Infallible>(Response::new(Body::from(body)))
+        }))
+    });
+    let incoming = hyper::server::accept::from_stream(UnixListener::bind(&path).unwrap());
+    tokio::spawn(Server::builder(incoming).serve(make_service));
+
+    let client = RpcClient::new(
+        AuthSource::from_config(Some("user".to_owned()), Some("pass".to_owned()), Vec::new())
+            .unwrap(),
+        "http://localhost/".parse().unwrap(),
+        Connector::unix(path.clone()),
+        slog::Logger::root(slog::Discard, slog::o!()),
+    );
+    let req: SingleOrBatchRpcRequest = serde_json::from_value(json!([
+        { "id": 1, "method": "getblockcount", "params": [] },
+        { "id": 2, "method": "getrawtransaction", "params": ["ab", true] },
+    ]))
+    .unwrap();
+    let response = client
+        .send(
+            "/",
+            &req,
+            &ResponseHeaders::default(),
+            |_, req| async move {
+                Ok(Some(RpcResponse {
+                    id: req.id.clone(),
+                    error: None,
+                    result: Some(json!(100)),
+                })
+                .filter(|_| req.method.0 == "getblockcount"))
+            },
+        )
+        .await
+        .unwrap();
+    let response = warnings::add(&req, vec![(Some(json!(2)), "reused".to_owned())], response)
+        .await
+        .unwrap();
+    assert_eq!(
+        text(response).await,
+        format!(
+            r#"[{{"error":null,"id":1,"result":100}},{{"error":null,"id":2,"result":{},"warning":"reused"}}]"#,
+            RESULT
+        )
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn warnings_keep_results() {
+    let req: SingleOrBatchRpcRequest =
+        serde_json::from_str(r#"{"id":1,"method":"getrawtransaction","params":["ab",true]}"#)
+            .unwrap();
+    let body = format!(r#"{{"result":{},"error":null,"id":1}}"#, RESULT);
+    let response = warnings::add(
+        &req,
+        vec![(Some(json!(1)), "deprecated".to_owned())],
+        Response::new(Body::from(body)),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        text(response).await,
+        format!(
+            r#"{{"error":null,"id":1,"result":{},"warning":"deprecated"}}"#,
+            RESULT
+        )
+    );
+}
+
+#[tokio::test]
+async fn only_headers_of_bitcoind_are_filtered() {
+    let path = std::env::temp_dir().join(format!(
+        "btc-rpc-proxy-passthrough-headers-{}.sock",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|_| async {
+            let body = format!(r#"{{"id":1,"result":{},"error":null}}"#, RESULT);
+            Response::builder()
+                .header(SERVER, "bitcoind")
+                .header("x-node", "a")
+                .body(Body::from(body))
+        }))
+    });
+    let incoming = hyper::server::accept::from_stream(UnixListener::bind(&path).unwrap());
+    tokio::spawn(Server::builder(incoming).serve(make_service));
+
+    let client = RpcClient::new(
+        AuthSource::from_config(Some("user".to_owned()), Some("pass".to_owned()), Vec::new())
+            .unwrap(),
+        "http://localhost/".parse().unwrap(),
+        Connector::unix(path.clone()),
+        slog::Logger::root(slog::Discard, slog::o!()),
+    );
+    let mut inject = HashMap::new();
+    inject.insert("cache-control".to_owned(), "no-store".to_owned());
+    let headers = ResponseHeaders::new(Some(vec!["x-node".to_owned()]), inject).unwrap();
+
+    let req: SingleOrBatchRpcRequest =
+        serde_json::from_str(r#"{"id":1,"method":"getrawtransaction","params":["ab",true]}"#)
+            .unwrap();
+    let mut forwarded = client
+        .send("/", &req, &headers, |_, _| async { Ok(None) })
+        .await
+        .unwrap();
+    headers.inject(&mut forwarded);
+    assert_eq!(forwarded.headers()["x-node"], "a");
+    assert_eq!(forwarded.headers()["cache-control"], "no-store");
+    assert!(!forwarded.headers().contains_key(SERVER));
+    assert_eq!(
+        text(forwarded).await,
+        format!(r#"{{"id":1,"result":{},"error":null}}"#, RESULT)
+    );
+
+    // answered by the proxy itself
+    let state = State::builder(client, slog::Logger::root(slog::Discard, slog::o!()))
+        .response_headers(headers)
+        .build()
+        .arc();
+    let request = Request::post("/").body(Body::from("{}")).unwrap();
+    let local = proxy_request(state, request).await.unwrap();
+    assert_eq!(local.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(local.headers()[WWW_AUTHENTICATE], "Basic realm=\"jsonrpc\"");
+    assert_eq!(local.headers()["cache-control"], "no-store");
+    std::fs::remove_file(&path).unwrap();
+}
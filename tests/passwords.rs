@@ -0,0 +1,95 @@
+//! Passwords stored as argon2 or bcrypt hashes, and `btc_rpc_proxy hash-password`.
+#![cfg(unix)]
+
+mod common;
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use btc_rpc_proxy::password::{hash, Password, Scheme};
+use hyper::StatusCode;
+use serde_json::json;
+
+use common::local::{bitcoind, call, proxy};
+
+#[test]
+fn hashes_are_verified() {
+    let argon2 = Password::from(hash("secret", Scheme::Argon2).unwrap());
+    // the cost of `hash` is too high for a test in a debug build
+    let bcrypt = Password::from(bcrypt::hash("secret", 4).unwrap());
+    for password in &[argon2, bcrypt] {
+        assert!(password.is_hashed());
+        password.check().unwrap();
+        assert!(password.verify("secret"));
+        // again, from the digest of the last match
+        assert!(password.verify("secret"));
+        assert!(!password.verify("Secret"));
+        assert!(!password.verify(""));
+    }
+
+    let plain = Password::from("$ecret".to_owned());
+    assert!(!plain.is_hashed());
+    assert!(plain.verify("$ecret"));
+    assert!(!plain.verify("secret"));
+
+    let hashed = hash("secret", Scheme::Argon2).unwrap();
+    let truncated = &hashed[..hashed.rfind('$').unwrap()];
+    assert!(Password::from(truncated.to_owned()).check().is_err());
+    assert!(Password::from("$2b$04$short".to_owned()).check().is_err());
+}
+
+#[tokio::test]
+async fn users_log_in_with_hashed_passwords() {
+    let (connector, _) = bitcoind("passwords", |_, _| json!(800000));
+    let state = proxy(
+        connector,
+        json!({
+            "alice": {
+                "password": hash("secret", Scheme::Argon2).unwrap(),
+                "allowed_calls": ["getblockcount"],
+            },
+            "bob": {
+                "password": bcrypt::hash("secret", 4).unwrap(),
+                "allowed_calls": ["getblockcount"],
+            },
+        }),
+    )
+    .build()
+    .arc();
+
+    for user in &["alice", "bob"] {
+        let (status, _) = call(&state, Some((user, "secret")), "getblockcount", json!([])).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = call(&state, Some((user, "wrong")), "getblockcount", json!([])).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+    // the hash isn't the password
+    let stored = hash("secret", Scheme::Argon2).unwrap();
+    let (status, _) = call(&state, Some(("alice", &stored)), "getblockcount", json!([])).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[test]
+fn hash_password_prints_a_hash() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_btc_rpc_proxy"))
+        .arg("hash-password")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"secret\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let printed = String::from_utf8(output.stdout).unwrap();
+    let password = Password::from(printed.trim().to_owned());
+    assert!(printed.starts_with("$argon2id$"), "{}", printed);
+    assert!(password.verify("secret"));
+
+    let usage = Command::new(env!("CARGO_BIN_EXE_btc_rpc_proxy"))
+        .args(["hash-password", "--md5"])
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(!usage.status.success());
+}
@@ -0,0 +1,48 @@
+//! Checking and normalizing the paths of JSON-RPC requests.
+
+use btc_rpc_proxy::paths::{base_path, normalize, wallet_path};
+
+#[test]
+fn paths_are_normalized() {
+    assert_eq!(normalize("").as_deref(), Some("/"));
+    assert_eq!(normalize("/").as_deref(), Some("/"));
+    assert_eq!(normalize("/wallet/hot").as_deref(), Some("/wallet/hot"));
+    assert_eq!(normalize("/wallet/").as_deref(), Some("/wallet/"));
+    // spelled differently, the same wallet
+    assert_eq!(normalize("/wallet/%68ot").as_deref(), Some("/wallet/hot"));
+    assert_eq!(
+        normalize("/wallet/my wallet").as_deref(),
+        Some("/wallet/my%20wallet")
+    );
+    assert_eq!(
+        normalize("/wallet/cold/vault").as_deref(),
+        Some("/wallet/cold%2Fvault")
+    );
+    assert_eq!(wallet_path("cold/vault"), "/wallet/cold%2Fvault");
+
+    for path in [
+        "/wallet",
+        "/wallets/hot",
+        "//wallet/hot",
+        "/metrics",
+        "/wallet/../hot",
+        "/wallet/%2E%2E/hot",
+        "/wallet/a%2F..%2F..%2Fhot",
+        "/wallet/%2Fhome%2Fuser%2Fhot",
+        "/wallet/%00",
+        "/wallet/%zz",
+    ] {
+        assert_eq!(normalize(path), None, "{}", path);
+    }
+}
+
+#[test]
+fn base_paths_are_checked() {
+    assert_eq!(base_path("").unwrap(), "");
+    assert_eq!(base_path("/").unwrap(), "");
+    assert_eq!(base_path("/bitcoin/").unwrap(), "/bitcoin");
+    assert_eq!(base_path("/nodes/main").unwrap(), "/nodes/main");
+    for path in ["bitcoin", "/a//b", "/a/../b", "/a?b", "/a b", "/%2e"] {
+        assert!(base_path(path).is_err(), "{}", path);
+    }
+}
@@ -0,0 +1,33 @@
+//! Numbers pass through the proxy exactly as bitcoind wrote them.
+
+use btc_rpc_proxy::client::{GenericRpcMethod, RpcResponse, SingleOrBatchRpcRequest};
+use btc_rpc_proxy::rpc_methods::{GetBlockHeaderResult, GetBlockResult};
+use btc_rpc_proxy::util::{Either, HexBytes};
+
+#[test]
+fn amounts_are_not_rounded_through_floats() {
+    let body = r#"{"id":1,"error":null,"result":{"amount":0.00000001,"balance":21000000.00000000,"difficulty":53911173001054.59,"fee":-0.00001410}}"#;
+    let response: RpcResponse<GenericRpcMethod> = serde_json::from_str(body).unwrap();
+    assert_eq!(serde_json::to_string(&response).unwrap(), body);
+    let req = r#"{"id":1,"method":"sendtoaddress","params":["bc1q",0.10000000]}"#;
+    let req: SingleOrBatchRpcRequest = serde_json::from_str(req).unwrap();
+    assert!(serde_json::to_string(&req).unwrap().contains("0.10000000"));
+}
+
+#[test]
+fn typed_results_keep_their_numbers() {
+    let header = r#"{"hash":"00000000000000000002a7c4c1e48d76c5a37902165a270156b7a8d72728a054","confirmations":1,"height":800000,"version":536870912,"versionHex":"20000000","merkleroot":"3e4d6d02a7b0f5ff5b0e9de46ba46fca35ee3c4f15ba4feb6cf4b1bfa2d1bfd6","time":1690168629,"mediantime":1690165851,"nonce":106861918,"bits":"17053894","difficulty":53911173001054.59,"chainwork":"00000000000000000000000000000000000000004e7b5d5e42bda0c8a7f2d3b4","nTx":3721"#;
+    let block = format!(
+        r#"{},"size":1634,"strippedsize":1000,"weight":4634,"tx":[]}}"#,
+        header
+    );
+    let block: GetBlockResult = serde_json::from_str(&block).unwrap();
+    assert_eq!(block.header.height, 800000);
+    assert_eq!(block.header.difficulty, 53911173001054.59);
+    assert_eq!(block.weight, 4634);
+    let either: Either<HexBytes, GetBlockHeaderResult> =
+        serde_json::from_str(&format!("{}}}", header)).unwrap();
+    assert_eq!(either.as_right().unwrap().n_tx, 3721);
+    let either: Either<HexBytes, GetBlockHeaderResult> = serde_json::from_str("\"00ff\"").unwrap();
+    assert_eq!(either.as_left().unwrap().as_ref(), &[0, 255]);
+}
@@ -0,0 +1,134 @@
+//! Daily and monthly quotas, which reset with their period. The wall clock is stepped to reach
+//! the next period, and stepping is global, so everything is checked by a single test.
+#![cfg(unix)]
+
+mod common;
+
+use std::sync::Arc;
+
+use btc_rpc_proxy::clock;
+use btc_rpc_proxy::quota::{Quota, QuotaUsage};
+use btc_rpc_proxy::storage::Memory;
+use chrono::{Datelike, Duration, TimeZone, Utc};
+use hyper::StatusCode;
+use serde_json::{json, Value};
+
+use common::local::{bitcoind, call, proxy};
+
+const QUOTA_EXCEEDED: i64 = -32609;
+
+fn quota(quota: Value) -> [Quota; 1] {
+    [serde_json::from_value(quota).unwrap()]
+}
+
+fn usage() -> QuotaUsage {
+    QuotaUsage::open(Arc::new(Memory::default()), "quota_usage").unwrap()
+}
+
+/// Steps the clock to `seconds` past the next midnight (UTC).
+fn next_day(seconds: i64) {
+    let now = clock::now();
+    let tomorrow = (now + Duration::days(1)).date().and_hms(0, 0, 0);
+    clock::step(tomorrow - now + Duration::seconds(seconds));
+}
+
+async fn through_the_proxy() {
+    let (connector, _) = bitcoind("quota", |_, _| json!(800000));
+    let state = proxy(
+        connector,
+        json!({
+            "alice": {
+                "password": "secret",
+                "allowed_calls": ["getblockcount", "proxy_getquota"],
+                "quota": [{ "period": "day", "requests": 2 }],
+            }
+        }),
+    )
+    .build()
+    .arc();
+    let alice = Some(("alice", "secret"));
+
+    for _ in 0..2 {
+        let (status, _) = call(&state, alice, "getblockcount", json!([])).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    let (status, response) = call(&state, alice, "getblockcount", json!([])).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response["error"]["code"], QUOTA_EXCEEDED);
+    // still available once the quota is used up
+    let (_, report) = call(&state, alice, "proxy_getquota", json!([])).await;
+    assert_eq!(report["result"][0]["exceeded"], true, "{}", report);
+    assert_eq!(report["result"][0]["requests"]["used"], 2);
+
+    next_day(1);
+    let (status, _) = call(&state, alice, "getblockcount", json!([])).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+fn calendar_day() {
+    let usage = usage();
+    let quotas = quota(json!({ "period": "day", "requests": 2 }));
+    usage.acquire("alice", &quotas).unwrap();
+    usage.acquire("alice", &quotas).unwrap();
+    let exceeded = usage.acquire("alice", &quotas).unwrap_err();
+    let tomorrow = (clock::now() + Duration::days(1)).date().and_hms(0, 0, 0);
+    assert_eq!(
+        exceeded.data.unwrap()["resets_at"],
+        json!(tomorrow.to_rfc3339())
+    );
+    // others have quotas of their own
+    usage.acquire("bob", &quotas).unwrap();
+
+    next_day(1);
+    usage.acquire("alice", &quotas).unwrap();
+    usage.acquire("alice", &quotas).unwrap();
+    assert!(usage.acquire("alice", &quotas).is_err());
+}
+
+fn rolling_day() {
+    let usage = usage();
+    let quotas = quota(json!({ "period": "day", "rolling": true, "requests": 2 }));
+    // early in a day, so that the next steps stay within it or the next
+    next_day(2 * 3600);
+    usage.acquire("alice", &quotas).unwrap();
+    clock::step(Duration::hours(12));
+    usage.acquire("alice", &quotas).unwrap();
+    // unlike a calendar day, a new day doesn't reset the usage
+    next_day(60);
+    assert!(usage.acquire("alice", &quotas).is_err());
+    // but the first call no longer counts 24 hours later
+    clock::step(Duration::hours(2));
+    usage.acquire("alice", &quotas).unwrap();
+    assert!(usage.acquire("alice", &quotas).is_err());
+}
+
+fn calendar_month() {
+    let usage = usage();
+    let quotas = quota(json!({ "period": "month", "requests": 1, "bytes": 1000 }));
+    usage.acquire("alice", &quotas).unwrap();
+    assert!(usage.acquire("alice", &quotas).is_err());
+    usage.acquire("bob", &quotas).unwrap();
+
+    let now = clock::now();
+    let (year, month) = match now.month() {
+        12 => (now.year() + 1, 1),
+        month => (now.year(), month + 1),
+    };
+    let next_month = Utc.ymd(year, month, 1).and_hms(0, 0, 0);
+    clock::step(next_month - now + Duration::seconds(1));
+    usage.acquire("alice", &quotas).unwrap();
+
+    // traffic counts too
+    let usage = self::usage();
+    usage.add_bytes("alice", &quotas, 1000);
+    let exceeded = usage.check("alice", &quotas).unwrap_err();
+    assert_eq!(exceeded.code, QUOTA_EXCEEDED);
+}
+
+#[tokio::test]
+async fn quotas_reset_with_their_period() {
+    through_the_proxy().await;
+    calendar_day();
+    rolling_day();
+    calendar_month();
+}
@@ -0,0 +1,187 @@
+//! Per-user rate limits and their algorithms.
+#![cfg(unix)]
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use btc_rpc_proxy::rate_limit::{Algorithm, Decision, RateLimit, RateLimiter};
+use hyper::StatusCode;
+use serde_json::json;
+
+use common::local::{bitcoind, call, proxy};
+
+fn limit(algorithm: Algorithm, requests: u32, period: f64, burst: Option<u32>) -> RateLimit {
+    RateLimit {
+        algorithm,
+        requests,
+        period,
+        burst,
+        methods: Default::default(),
+    }
+}
+
+fn rejected(decision: Decision) -> Duration {
+    match decision {
+        Decision::Reject(retry_after) => retry_after,
+        other => panic!("expected a rejection, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn token_bucket_allows_bursts() {
+    let limiter = RateLimiter::memory();
+    let limit = limit(Algorithm::TokenBucket, 1, 0.2, Some(3));
+    for _ in 0..3 {
+        assert_eq!(
+            limiter.acquire("alice", 0, &limit).await.unwrap(),
+            Decision::Allow
+        );
+    }
+    let retry_after = rejected(limiter.acquire("alice", 0, &limit).await.unwrap());
+    assert!(
+        retry_after <= Duration::from_millis(200),
+        "{:?}",
+        retry_after
+    );
+    // others have buckets of their own
+    assert_eq!(
+        limiter.acquire("bob", 0, &limit).await.unwrap(),
+        Decision::Allow
+    );
+
+    // refilled at the average rate, one token
+    tokio::time::delay_for(Duration::from_millis(250)).await;
+    assert_eq!(
+        limiter.acquire("alice", 0, &limit).await.unwrap(),
+        Decision::Allow
+    );
+    rejected(limiter.acquire("alice", 0, &limit).await.unwrap());
+}
+
+#[tokio::test]
+async fn sliding_window_counts_calls_within_the_period() {
+    let limiter = RateLimiter::memory();
+    let limit = limit(Algorithm::SlidingWindow, 2, 0.3, Some(10));
+    let start = Instant::now();
+    for _ in 0..2 {
+        assert_eq!(
+            limiter.acquire("alice", 0, &limit).await.unwrap(),
+            Decision::Allow
+        );
+    }
+    // the burst doesn't apply
+    let retry_after = rejected(limiter.acquire("alice", 0, &limit).await.unwrap());
+    assert!(
+        retry_after <= Duration::from_millis(300),
+        "{:?}",
+        retry_after
+    );
+
+    tokio::time::delay_until((start + Duration::from_millis(350)).into()).await;
+    for _ in 0..2 {
+        assert_eq!(
+            limiter.acquire("alice", 0, &limit).await.unwrap(),
+            Decision::Allow
+        );
+    }
+    rejected(limiter.acquire("alice", 0, &limit).await.unwrap());
+}
+
+#[tokio::test]
+async fn leaky_bucket_delays_calls_up_to_the_burst() {
+    let limiter = RateLimiter::memory();
+    let limit = limit(Algorithm::LeakyBucket, 1, 1.0, Some(3));
+    assert_eq!(
+        limiter.acquire("alice", 0, &limit).await.unwrap(),
+        Decision::Allow
+    );
+    // up to `burst` calls wait for their turn
+    let mut last = Duration::from_secs(0);
+    for _ in 0..3 {
+        match limiter.acquire("alice", 0, &limit).await.unwrap() {
+            Decision::Delay(wait) => {
+                assert!(wait > last && wait <= last + Duration::from_secs(1));
+                last = wait;
+            }
+            other => panic!("expected a delay, got {:?}", other),
+        }
+    }
+    assert!(last > Duration::from_millis(2900), "{:?}", last);
+    rejected(limiter.acquire("alice", 0, &limit).await.unwrap());
+}
+
+#[tokio::test]
+async fn limits_apply_to_their_methods() {
+    let (connector, received) = bitcoind("rate-limit", |_, _| json!(800000));
+    let state = proxy(
+        connector,
+        json!({
+            "alice": {
+                "password": "secret",
+                "allowed_calls": ["getblockcount", "getblockhash"],
+                "rate_limit": [{
+                    "algorithm": "sliding_window",
+                    "requests": 1,
+                    "period": 60,
+                    "methods": ["getblockhash"],
+                }],
+            }
+        }),
+    )
+    .build()
+    .arc();
+    let alice = Some(("alice", "secret"));
+
+    let (status, _) = call(&state, alice, "getblockhash", json!([0])).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, response) = call(&state, alice, "getblockhash", json!([0])).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    let retry_after = response["error"]["data"]["retry_after"].as_f64().unwrap();
+    assert!(retry_after > 59.0 && retry_after <= 60.0, "{}", response);
+    for _ in 0..3 {
+        let (status, _) = call(&state, alice, "getblockcount", json!([])).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    assert_eq!(received.lock().unwrap().len(), 4);
+}
+
+#[tokio::test]
+async fn leaky_bucket_holds_calls_back() {
+    let (connector, _) = bitcoind("rate-limit-leaky", |_, _| json!(800000));
+    let state = proxy(
+        connector,
+        json!({
+            "alice": {
+                "password": "secret",
+                "allowed_calls": ["getblockcount"],
+                "rate_limit": [{
+                    "algorithm": "leaky_bucket",
+                    "requests": 1,
+                    "period": 0.2,
+                    "burst": 5,
+                }],
+            }
+        }),
+    )
+    .build()
+    .arc();
+
+    let start = Instant::now();
+    for _ in 0..3 {
+        let (status, _) = call(
+            &state,
+            Some(("alice", "secret")),
+            "getblockcount",
+            json!([]),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    // the second and third calls waited for their turn
+    assert!(
+        start.elapsed() >= Duration::from_millis(380),
+        "{:?}",
+        start.elapsed()
+    );
+}
@@ -0,0 +1,105 @@
+//! `proxy_bumpstuck` bumps each stuck transaction of the wallets the caller may use.
+
+#![cfg(unix)]
+
+mod common;
+
+use hyper::Request;
+use serde_json::{json, Value};
+
+use common::local::{bitcoind, call, proxy, send};
+
+const TXIDS: [&str; 2] = [
+    "abababababababababababababababababababababababababababababababab",
+    "cdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcd",
+];
+
+/// A wallet with two sends stuck at 1 sat/vB while 20 sat/vB confirm soon.
+fn result(method: &str, params: &Value) -> Value {
+    match method {
+        "estimatesmartfee" => json!({ "feerate": 0.0002, "blocks": 6 }),
+        "getmempoolinfo" => json!({ "mempoolminfee": 0.00001, "incrementalrelayfee": 0.00001 }),
+        "listtransactions" => TXIDS
+            .iter()
+            .map(|txid| {
+                json!({
+                    "txid": txid,
+                    "category": "send",
+                    "confirmations": 0,
+                    "bip125-replaceable": "yes",
+                })
+            })
+            .collect(),
+        "getmempoolentry" => json!({ "vsize": 200, "fees": { "base": 0.000002 } }),
+        "bumpfee" => json!({ "txid": params[0] }),
+        _ => Value::Null,
+    }
+}
+
+fn users() -> Value {
+    json!({
+        "alice": { "password": "secret", "allowed_calls": ["bumpfee", "proxy_bumpstuck"] },
+        "bob": {
+            "password": "secret",
+            "allowed_calls": ["bumpfee", "proxy_bumpstuck"],
+            "allowed_wallets": ["hot"],
+        },
+    })
+}
+
+#[tokio::test]
+async fn each_stuck_transaction_is_bumped_once() {
+    let (bitcoind, received) = bitcoind("rbf-idempotency", result);
+    let state = proxy(bitcoind, users()).build().arc();
+    let bumpstuck = json!({ "id": 1, "method": "proxy_bumpstuck", "params": [6, true] });
+    for _ in 0..2 {
+        let (_, response) = send(
+            &state,
+            Some(("alice", "secret")),
+            Request::post("/").header("Idempotency-Key", "bump-1"),
+            &bumpstuck,
+        )
+        .await;
+        let stuck = response["result"]["stuck"].as_array().unwrap().clone();
+        assert_eq!(stuck.len(), 2, "{}", response);
+        for (status, txid) in stuck.iter().zip(TXIDS.iter().rev()) {
+            assert_eq!(status["action"], "bumped", "{}", response);
+            assert_eq!(status["result"]["txid"], *txid);
+        }
+    }
+    // the retry was answered with the results of the first bumps
+    let received = received.lock().unwrap();
+    let bumps: Vec<&Value> = received
+        .iter()
+        .filter(|call| call["method"] == "bumpfee")
+        .map(|call| &call["params"][0])
+        .collect();
+    assert_eq!(bumps, vec![TXIDS[1], TXIDS[0]]);
+}
+
+#[tokio::test]
+async fn restricted_wallets_are_not_listed() {
+    let (bitcoind, received) = bitcoind("rbf-wallets", result);
+    let state = proxy(bitcoind, users()).build().arc();
+    let (_, response) = call(
+        &state,
+        Some(("bob", "secret")),
+        "proxy_bumpstuck",
+        json!([6, true]),
+    )
+    .await;
+    assert!(response["error"].is_object(), "{}", response);
+    assert!(!received
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|call| call["method"] == "listtransactions" || call["method"] == "bumpfee"));
+    let (_, response) = send(
+        &state,
+        Some(("bob", "secret")),
+        Request::post("/wallet/hot"),
+        &json!({ "id": 1, "method": "proxy_bumpstuck", "params": [6] }),
+    )
+    .await;
+    assert_eq!(response["result"]["stuck"].as_array().unwrap().len(), 2);
+}
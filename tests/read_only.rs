@@ -0,0 +1,70 @@
+//! The emergency read-only mode, switched at runtime with `proxy_setreadonly`.
+#![cfg(unix)]
+
+mod common;
+
+use hyper::StatusCode;
+use serde_json::{json, Value};
+
+use common::local::{bitcoind, call, proxy};
+
+fn users() -> Value {
+    json!({
+        "admin": {
+            "password": "secret",
+            "allowed_calls": ["proxy_setreadonly", "proxy_status"],
+        },
+        "alice": { "password": "secret", "allowed_calls": ["getblockcount", "savemempool"] },
+    })
+}
+
+#[tokio::test]
+async fn read_only_mode_denies_write_methods() {
+    let (connector, received) = bitcoind("read-only", |_, _| json!(null));
+    let state = proxy(connector, users()).build().arc();
+    let admin = Some(("admin", "secret"));
+    let alice = Some(("alice", "secret"));
+
+    let (status, _) = call(&state, alice, "savemempool", json!([])).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (_, response) = call(&state, admin, "proxy_setreadonly", json!([true])).await;
+    assert_eq!(response["result"], true);
+    let (_, status) = call(&state, admin, "proxy_status", json!([])).await;
+    assert_eq!(status["result"]["read_only"], true);
+    let (status, response) = call(&state, alice, "savemempool", json!([])).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response["error"]["message"], "Proxy is in read-only mode");
+    // reading goes on
+    let (status, _) = call(&state, alice, "getblockcount", json!([])).await;
+    assert_eq!(status, StatusCode::OK);
+
+    call(&state, admin, "proxy_setreadonly", json!([false])).await;
+    let (status, _) = call(&state, alice, "savemempool", json!([])).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let methods: Vec<_> = received
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|c| c["method"].as_str().unwrap().to_owned())
+        .collect();
+    assert_eq!(methods, ["savemempool", "getblockcount", "savemempool"]);
+}
+
+#[tokio::test]
+async fn the_switch_takes_a_boolean() {
+    let (connector, _) = bitcoind("read-only-switch", |_, _| json!(null));
+    let state = proxy(connector, users()).read_only(true).build().arc();
+
+    let (_, response) = call(
+        &state,
+        Some(("admin", "secret")),
+        "proxy_setreadonly",
+        json!(["no"]),
+    )
+    .await;
+    assert!(response["error"].is_object(), "{}", response);
+    let (status, _) = call(&state, Some(("alice", "secret")), "savemempool", json!([])).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+}
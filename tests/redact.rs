@@ -0,0 +1,138 @@
+//! Fields removed from the results of calls before they reach a user.
+#![cfg(unix)]
+
+mod common;
+
+use btc_rpc_proxy::redact::FieldPath;
+use hyper::Request;
+use serde_json::{json, Value};
+
+use common::local::{bitcoind, call, proxy, send};
+
+fn result(method: &str, _: &Value) -> Value {
+    match method {
+        "getpeerinfo" => json!([
+            { "id": 0, "addr": "192.0.2.1:8333", "addrlocal": "192.0.2.9:8333", "version": 70016 },
+            { "id": 1, "addr": "192.0.2.2:8333", "version": 70016 },
+        ]),
+        "getwalletinfo" => json!({ "walletname": "hot", "hdseedid": "00ff", "txcount": 3 }),
+        "listdescriptors" => json!({
+            "wallet_name": "hot",
+            "descriptors": [
+                { "desc": "wpkh(xprv1)", "active": true },
+                { "desc": "tr(xprv2)", "active": true },
+            ],
+        }),
+        "send" => json!({ "txid": "aa".repeat(32), "complete": true }),
+        _ => json!(800000),
+    }
+}
+
+fn users() -> Value {
+    json!({
+        "monitoring": {
+            "password": "secret",
+            "allowed_calls": ["getpeerinfo", "getwalletinfo", "listdescriptors", "send"],
+            "redact": [
+                { "method": "getpeerinfo", "fields": ["[].addr", "[].addrlocal"] },
+                { "method": "getwalletinfo", "fields": ["hdseedid", "not.there"] },
+                { "method": "listdescriptors", "fields": ["descriptors[].desc"] },
+                { "method": "send", "fields": ["txid"] },
+            ],
+        },
+        "admin": {
+            "password": "secret",
+            "allowed_calls": ["getpeerinfo", "getwalletinfo"],
+        },
+    })
+}
+
+#[test]
+fn field_paths() {
+    for valid in &[
+        "hdseedid",
+        "[].addr",
+        "descriptors[].desc",
+        "a.b.c",
+        "[][].x",
+    ] {
+        let path: Result<FieldPath, _> = serde_json::from_value(json!(valid));
+        assert!(path.is_ok(), "{}", valid);
+    }
+    for invalid in &["", "a..b", "descriptors[]", "[]", "a[0]", "a]b", "a.[]"] {
+        let path: Result<FieldPath, _> = serde_json::from_value(json!(invalid));
+        assert!(path.is_err(), "{}", invalid);
+    }
+}
+
+#[tokio::test]
+async fn fields_are_removed_from_results() {
+    let (connector, _) = bitcoind("redact", result);
+    let state = proxy(connector, users()).build().arc();
+    let monitoring = Some(("monitoring", "secret"));
+
+    let (_, peers) = call(&state, monitoring, "getpeerinfo", json!([])).await;
+    assert_eq!(
+        peers["result"],
+        json!([{ "id": 0, "version": 70016 }, { "id": 1, "version": 70016 }])
+    );
+    // missing fields are ignored
+    let (_, wallet) = call(&state, monitoring, "getwalletinfo", json!([])).await;
+    assert_eq!(
+        wallet["result"],
+        json!({ "walletname": "hot", "txcount": 3 })
+    );
+    let (_, descriptors) = call(&state, monitoring, "listdescriptors", json!([])).await;
+    assert_eq!(
+        descriptors["result"]["descriptors"],
+        json!([{ "active": true }, { "active": true }])
+    );
+    assert_eq!(descriptors["result"]["wallet_name"], "hot");
+
+    // in batches too
+    let (_, batch) = send(
+        &state,
+        monitoring,
+        Request::post("/"),
+        &json!([
+            { "id": 1, "method": "getwalletinfo", "params": [] },
+            { "id": 2, "method": "getpeerinfo", "params": [] },
+        ]),
+    )
+    .await;
+    assert!(batch[0]["result"].get("hdseedid").is_none(), "{}", batch);
+    assert!(batch[1]["result"][0].get("addr").is_none(), "{}", batch);
+
+    // redactions are per user
+    let (_, wallet) = call(
+        &state,
+        Some(("admin", "secret")),
+        "getwalletinfo",
+        json!([]),
+    )
+    .await;
+    assert_eq!(wallet["result"]["hdseedid"], "00ff");
+}
+
+#[tokio::test]
+async fn replays_are_redacted() {
+    let (connector, received) = bitcoind("redact-replay", result);
+    let state = proxy(connector, users()).build().arc();
+    let body = json!({ "id": 1, "method": "send", "params": [{ "bc1qexample": 0.1 }] });
+    for _ in 0..2 {
+        let (_, response) = send(
+            &state,
+            Some(("monitoring", "secret")),
+            Request::post("/").header("Idempotency-Key", "send-1"),
+            &body,
+        )
+        .await;
+        assert_eq!(
+            response["result"],
+            json!({ "complete": true }),
+            "{}",
+            response
+        );
+    }
+    assert_eq!(received.lock().unwrap().len(), 1);
+}
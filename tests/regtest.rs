@@ -0,0 +1,239 @@
+//! End-to-end tests of the proxy in front of regtest nodes. They need a bitcoind binary
+//! (23.0 or newer) in `BITCOIND_EXE` and are skipped otherwise:
+//!
+//! ```sh
+//! BITCOIND_EXE=/usr/local/bin/bitcoind cargo test --test regtest
+//! ```
+
+mod common;
+
+#[cfg(feature = "peer-fetch")]
+use anyhow::anyhow;
+use anyhow::Error;
+use hyper::StatusCode;
+use serde_json::json;
+#[cfg(feature = "peer-fetch")]
+use serde_json::Value;
+
+use common::{bitcoind_exe, post, Bitcoind, Proxy};
+#[cfg(feature = "peer-fetch")]
+use common::{free_port, wait_for};
+
+const USERS: &str = r#"
+[user.alice]
+password = "alice"
+allowed_calls = ["getblockcount", "getblockhash", "getbestblockhash", "getblock", "getblockheader"]
+fetch_blocks = true
+
+[user.bob]
+password = "bob"
+allowed_calls = ["getblockcount"]
+"#;
+
+/// Error code of calls the user isn't allowed to make
+const METHOD_NOT_ALLOWED: i64 = -32604;
+
+/// A pruned node connected to a full one, with the first blocks pruned.
+#[cfg(feature = "peer-fetch")]
+struct PrunedSetup {
+    pruned: Bitcoind,
+    full: Bitcoind,
+    /// Hashes of the blocks mined, from height 1
+    hashes: Vec<Value>,
+}
+#[cfg(feature = "peer-fetch")]
+impl PrunedSetup {
+    async fn start(exe: &std::path::Path) -> Result<Self, Error> {
+        let full = Bitcoind::start(exe, &[]).await?;
+        let pruned = Bitcoind::start(exe, &["-prune=1", "-fastprune"]).await?;
+        pruned.connect(&full).await?;
+        let hashes = full.mine(1000).await?;
+        pruned.sync_with(&full).await?;
+        let height = pruned.call("pruneblockchain", json!([700])).await?;
+        if height.as_u64().unwrap_or(0) < 1 {
+            return Err(anyhow!("nothing was pruned: {}", height));
+        }
+        Ok(PrunedSetup {
+            pruned,
+            full,
+            hashes,
+        })
+    }
+}
+
+#[tokio::test]
+async fn authentication() -> Result<(), Error> {
+    let exe = match bitcoind_exe() {
+        Some(exe) => exe,
+        None => return Ok(()),
+    };
+    let node = Bitcoind::start(&exe, &[]).await?;
+    node.mine(10).await?;
+    let proxy = Proxy::start(&node, USERS)?;
+    let request = json!({ "id": 1, "method": "getblockcount", "params": [] });
+
+    let (status, _) = post(proxy.addr, "bob", "wrong", &request).await?;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = post(proxy.addr, "mallory", "bob", &request).await?;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let count = proxy.call("bob", "bob", "getblockcount", json!([])).await?;
+    assert_eq!(count, json!(10));
+
+    let (status, response) = post(
+        proxy.addr,
+        "bob",
+        "bob",
+        &json!({ "id": 1, "method": "getblockhash", "params": [1] }),
+    )
+    .await?;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(response["error"]["code"], json!(METHOD_NOT_ALLOWED));
+    Ok(())
+}
+
+#[tokio::test]
+async fn batches() -> Result<(), Error> {
+    let exe = match bitcoind_exe() {
+        Some(exe) => exe,
+        None => return Ok(()),
+    };
+    let node = Bitcoind::start(&exe, &[]).await?;
+    let hashes = node.mine(3).await?;
+    let proxy = Proxy::start(&node, USERS)?;
+
+    let (status, response) = post(
+        proxy.addr,
+        "alice",
+        "alice",
+        &json!([
+            { "id": 1, "method": "getblockhash", "params": [1] },
+            { "id": 2, "method": "getblockhash", "params": [3] },
+            { "id": 3, "method": "getblockcount", "params": [] },
+        ]),
+    )
+    .await?;
+    assert_eq!(status, StatusCode::OK);
+    let responses = response.as_array().expect("a batch response");
+    assert_eq!(responses.len(), 3);
+    for (response, (id, result)) in
+        responses
+            .iter()
+            .zip(vec![(1, &hashes[0]), (2, &hashes[2]), (3, &json!(3))])
+    {
+        assert_eq!(response["id"], json!(id));
+        assert_eq!(&response["result"], result);
+    }
+
+    // calls a user may not make fail on their own
+    let (_, response) = post(
+        proxy.addr,
+        "bob",
+        "bob",
+        &json!([
+            { "id": 1, "method": "getblockcount", "params": [] },
+            { "id": 2, "method": "getblockhash", "params": [1] },
+        ]),
+    )
+    .await?;
+    let responses = response.as_array().expect("a batch response");
+    assert_eq!(responses.len(), 2);
+    assert_eq!(responses[0]["result"], json!(3));
+    assert_eq!(responses[1]["error"]["code"], json!(METHOD_NOT_ALLOWED));
+    Ok(())
+}
+
+#[cfg(feature = "peer-fetch")]
+#[tokio::test]
+async fn pruned_blocks_are_fetched_from_peers() -> Result<(), Error> {
+    let exe = match bitcoind_exe() {
+        Some(exe) => exe,
+        None => return Ok(()),
+    };
+    let setup = PrunedSetup::start(&exe).await?;
+    let hash = &setup.hashes[0];
+    if setup
+        .pruned
+        .call("getblock", json!([hash, 0]))
+        .await
+        .is_ok()
+    {
+        return Err(anyhow!("block 1 wasn't pruned"));
+    }
+    let proxy = Proxy::start(&setup.pruned, USERS)?;
+
+    let expected = setup.full.call("getblock", json!([hash, 0])).await?;
+    // the proxy learns about the peers of the node in the background
+    let block = wait_for("the block to be fetched", || {
+        proxy.call("alice", "alice", "getblock", json!([hash, 0]))
+    })
+    .await
+    .map_err(|e| anyhow!("{:#}, log:\n{}", e, proxy.log()))?;
+    assert_eq!(block, expected);
+
+    let block = proxy
+        .call("alice", "alice", "getblock", json!([hash, 1]))
+        .await?;
+    assert_eq!(&block["hash"], hash);
+    assert_eq!(block["height"], json!(1));
+
+    // users without fetch_blocks get the error of bitcoind
+    let proxy = Proxy::start(
+        &setup.pruned,
+        "[user.carol]\npassword = \"carol\"\nallowed_calls = [\"getblock\"]\n",
+    )?;
+    assert!(proxy
+        .call("carol", "carol", "getblock", json!([hash, 0]))
+        .await
+        .is_err());
+    Ok(())
+}
+
+#[cfg(feature = "peer-fetch")]
+#[tokio::test]
+async fn failover() -> Result<(), Error> {
+    let exe = match bitcoind_exe() {
+        Some(exe) => exe,
+        None => return Ok(()),
+    };
+    let mut setup = PrunedSetup::start(&exe).await?;
+
+    // an Esplora server that is down is skipped in favor of peers
+    let proxy = Proxy::start(
+        &setup.pruned,
+        &format!(
+            "esplora_url = \"http://127.0.0.1:{}\"\n\
+             [fetch_order]\n\
+             getblock = [\"backend\", \"esplora:5\", \"p2p\"]\n\
+             {}",
+            free_port(),
+            USERS
+        ),
+    )?;
+    let hash = &setup.hashes[1];
+    let expected = setup.full.call("getblock", json!([hash, 0])).await?;
+    let block = wait_for("the block to be fetched", || {
+        proxy.call("alice", "alice", "getblock", json!([hash, 0]))
+    })
+    .await
+    .map_err(|e| anyhow!("{:#}, log:\n{}", e, proxy.log()))?;
+    assert_eq!(block, expected);
+
+    // the proxy keeps running while bitcoind is down and serves again once it's back
+    setup.pruned.stop().await?;
+    let (status, response) = post(
+        proxy.addr,
+        "alice",
+        "alice",
+        &json!({ "id": 1, "method": "getblockcount", "params": [] }),
+    )
+    .await?;
+    assert!(status.is_server_error(), "status {}", status);
+    assert!(!response["error"].is_null());
+    setup.pruned.restart().await?;
+    let count = proxy
+        .call("alice", "alice", "getblockcount", json!([]))
+        .await?;
+    assert_eq!(count, json!(1000));
+    Ok(())
+}
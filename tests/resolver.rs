@@ -0,0 +1,113 @@
+//! Lookups of host names with DNS-over-HTTPS messages and through Tor, played by a stand-in on
+//! the other end of a socket pair.
+
+use std::net::{IpAddr, SocketAddr};
+
+use btc_rpc_proxy::resolver::{encode_query, parse_answers, Resolver, TYPE_A, TYPE_AAAA};
+
+#[test]
+fn query() {
+    let query = encode_query("node.example.com.", TYPE_AAAA).unwrap();
+    let mut expected = vec![0, 0, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    expected.extend_from_slice(b"\x04node\x07example\x03com\x00\x00\x1c\x00\x01");
+    assert_eq!(query, expected);
+    assert!(encode_query("node..example.com", TYPE_A).is_err());
+    assert!(encode_query(&"a".repeat(64), TYPE_A).is_err());
+}
+
+/// A response for `node.example.com` with a CNAME to `b.example.com`, which has an A and an
+/// AAAA record, names being compressed.
+fn response(rcode: u8) -> Vec<u8> {
+    let mut message = vec![0, 0, 0x81, 0x80 | rcode, 0, 1, 0, 3, 0, 0, 0, 0];
+    message.extend_from_slice(b"\x04node\x07example\x03com\x00\x00\x01\x00\x01");
+    // CNAME b.example.com, pointing at "example.com" of the question at 17
+    message.extend_from_slice(b"\xc0\x0c\x00\x05\x00\x01\x00\x00\x00\x3c\x00\x04\x01b\xc0\x11");
+    message.extend_from_slice(b"\xc0\x2e\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x04");
+    message.extend_from_slice(&[192, 0, 2, 10]);
+    message.extend_from_slice(b"\xc0\x2e\x00\x1c\x00\x01\x00\x00\x00\x3c\x00\x10");
+    message.extend_from_slice(
+        &"2001:db8::10"
+            .parse::<std::net::Ipv6Addr>()
+            .unwrap()
+            .octets(),
+    );
+    message
+}
+
+#[test]
+fn answers() {
+    assert_eq!(
+        parse_answers(&response(0)).unwrap(),
+        vec![
+            "192.0.2.10".parse::<IpAddr>().unwrap(),
+            "2001:db8::10".parse().unwrap()
+        ]
+    );
+    // NXDOMAIN
+    assert_eq!(parse_answers(&response(3)).unwrap(), Vec::<IpAddr>::new());
+    // SERVFAIL
+    assert!(parse_answers(&response(2)).is_err());
+    let truncated = response(0);
+    assert!(parse_answers(&truncated[..truncated.len() - 4]).is_err());
+}
+
+#[test]
+fn config() {
+    let tor: SocketAddr = "127.0.0.1:9050".parse().unwrap();
+    assert!(matches!(
+        Resolver::from_config("system", None, None, None),
+        Ok(Resolver::System)
+    ));
+    assert!(matches!(
+        Resolver::from_config("tor", Some(tor), None, None),
+        Ok(Resolver::Tor(addr)) if addr == tor
+    ));
+    assert!(Resolver::from_config("tor", None, None, None).is_err());
+    assert!(Resolver::from_config("http://dns.example/dns-query", None, None, None).is_err());
+    #[cfg(feature = "tls")]
+    assert!(matches!(
+        Resolver::from_config(
+            "https://dns.example/dns-query",
+            None,
+            Some([192, 0, 2, 53].into()),
+            None
+        ),
+        Ok(Resolver::Doh(_))
+    ));
+}
+
+#[tokio::test]
+async fn literal() {
+    // not even Tor is asked for addresses
+    let resolver = Resolver::Tor("127.0.0.1:1".parse().unwrap());
+    assert_eq!(
+        resolver.lookup("[2001:db8::1]", 8332).await.unwrap(),
+        vec!["[2001:db8::1]:8332".parse().unwrap()]
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn tor() {
+    use btc_rpc_proxy::resolver::tor_resolve;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (mut client, mut tor) = tokio::net::UnixStream::pair().unwrap();
+    tokio::spawn(async move {
+        let mut greeting = [0; 3];
+        tor.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(greeting, [5, 1, 0]);
+        tor.write_all(&[5, 0]).await.unwrap();
+        let mut request = vec![0; 5 + 16 + 2];
+        tor.read_exact(&mut request).await.unwrap();
+        assert_eq!(request[..5], [5, 0xF0, 0, 3, 16]);
+        assert_eq!(&request[5..21], b"node.example.com");
+        tor.write_all(&[5, 0, 0, 1, 192, 0, 2, 10, 0, 0])
+            .await
+            .unwrap();
+    });
+    assert_eq!(
+        tor_resolve(&mut client, "node.example.com").await.unwrap(),
+        IpAddr::from([192, 0, 2, 10])
+    );
+}
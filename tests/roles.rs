@@ -0,0 +1,118 @@
+//! The `readonly`, `watchonly` and `admin` presets of permissions.
+#![cfg(unix)]
+
+mod common;
+
+use btc_rpc_proxy::roles::Role;
+use hyper::StatusCode;
+use serde_json::json;
+
+use common::local::{bitcoind, call, proxy};
+
+#[test]
+fn presets() {
+    let read = [
+        "getblockcount",
+        "getblock",
+        "getpeerinfo",
+        "getmempoolinfo",
+        "estimatesmartfee",
+    ];
+    let watch = [
+        "getbalance",
+        "listunspent",
+        "listtransactions",
+        "getaddressinfo",
+    ];
+    let keys = [
+        "dumpprivkey",
+        "dumpwallet",
+        "listdescriptors",
+        "signrawtransactionwithwallet",
+    ];
+    let write = [
+        "sendrawtransaction",
+        "sendtoaddress",
+        "stop",
+        "setban",
+        "walletpassphrase",
+    ];
+    let node_files = ["dumptxoutset", "logging", "getblockfrompeer"];
+
+    for method in &read {
+        assert!(Role::ReadOnly.allows(method), "{}", method);
+        assert!(Role::WatchOnly.allows(method), "{}", method);
+    }
+    for method in &watch {
+        assert!(!Role::ReadOnly.allows(method), "{}", method);
+        assert!(Role::WatchOnly.allows(method), "{}", method);
+    }
+    for method in keys.iter().chain(&write).chain(&node_files) {
+        assert!(!Role::ReadOnly.allows(method), "{}", method);
+        assert!(!Role::WatchOnly.allows(method), "{}", method);
+    }
+    for method in &["proxy_status", "proxy_issuetoken", "notamethod"] {
+        assert!(!Role::ReadOnly.allows(method), "{}", method);
+        assert!(!Role::WatchOnly.allows(method), "{}", method);
+    }
+    for method in read.iter().chain(&watch).chain(&keys).chain(&write) {
+        assert!(Role::Admin.allows(method), "{}", method);
+    }
+    assert!(Role::Admin.allows("proxy_status"));
+}
+
+#[tokio::test]
+async fn roles_add_to_allowed_calls() {
+    let (connector, received) = bitcoind("roles", |_, _| json!(null));
+    let state = proxy(
+        connector,
+        json!({
+            "alice": {
+                "password": "secret",
+                "role": "readonly",
+                "allowed_calls": ["sendrawtransaction", "proxy_explainacl"],
+            },
+            "bob": { "password": "secret", "role": "watchonly" },
+        }),
+    )
+    .build()
+    .arc();
+    let alice = Some(("alice", "secret"));
+    let bob = Some(("bob", "secret"));
+
+    for (user, method, allowed) in &[
+        (alice, "getblockcount", true),
+        (alice, "sendrawtransaction", true),
+        (alice, "getbalance", false),
+        (bob, "getbalance", true),
+        (bob, "sendrawtransaction", false),
+        (bob, "dumpprivkey", false),
+    ] {
+        let (status, response) = call(&state, *user, method, json!([])).await;
+        let expected = if *allowed {
+            StatusCode::OK
+        } else {
+            StatusCode::FORBIDDEN
+        };
+        assert_eq!(status, expected, "{}: {}", method, response);
+    }
+    assert_eq!(received.lock().unwrap().len(), 3);
+
+    let (_, explained) = call(
+        &state,
+        alice,
+        "proxy_explainacl",
+        json!(["alice", "getblockcount"]),
+    )
+    .await;
+    assert!(
+        explained.to_string().contains("role readonly"),
+        "{}",
+        explained
+    );
+}
+
+#[test]
+fn unknown_roles_are_rejected() {
+    assert!(btc_rpc_proxy::users::User::from_value(json!({ "role": "root" })).is_err());
+}
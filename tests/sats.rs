@@ -0,0 +1,106 @@
+//! Amounts in satoshis, annotated in results and accepted in params.
+
+use btc_rpc_proxy::client::{GenericRpcMethod, RpcRequest, RpcResponse};
+use btc_rpc_proxy::sats::{annotate, convert_request};
+use serde_json::{json, Value};
+
+fn annotated(method: &str, result: Value) -> Value {
+    let mut response = RpcResponse {
+        id: None,
+        error: None,
+        result: Some(result),
+    };
+    annotate(method, &mut response);
+    response.result.unwrap()
+}
+
+#[test]
+fn amounts_are_annotated() {
+    assert_eq!(
+        annotated(
+            "listunspent",
+            json!([{ "amount": 0.1 }, { "amount": 20999999.9769 }])
+        ),
+        json!([
+            { "amount": 0.1, "amount_sats": 10_000_000 },
+            { "amount": 20999999.9769, "amount_sats": 2_099_999_997_690_000i64 },
+        ])
+    );
+    assert_eq!(
+        annotated(
+            "getrawmempool",
+            json!({ "ab": { "fees": { "base": 0.00000141, "modified": 0.00000141 }, "vsize": 141 } })
+        ),
+        json!({ "ab": {
+            "fees": {
+                "base": 0.00000141,
+                "base_sats": 141,
+                "modified": 0.00000141,
+                "modified_sats": 141,
+            },
+            "vsize": 141,
+        } })
+    );
+    assert_eq!(
+        annotated(
+            "getrawtransaction",
+            json!({ "vout": [{ "value": 0.3 }], "fee": -0.00001 })
+        ),
+        json!({
+            "vout": [{ "value": 0.3, "value_sats": 30_000_000 }],
+            "fee": -0.00001,
+            "fee_sats": -1000,
+        })
+    );
+    // already in satoshis
+    let stats = json!({ "totalfee": 12345, "avgfee": 100 });
+    assert_eq!(annotated("getblockstats", stats.clone()), stats);
+}
+
+#[test]
+fn sats_are_converted_in_params() {
+    let req = |params| RpcRequest {
+        id: None,
+        method: GenericRpcMethod("sendmany".to_owned()),
+        params,
+    };
+    let converted = convert_request(&req(vec![
+        json!(""),
+        json!({ "bc1qa": { "sat": 1 }, "bc1qb": { "sat": 2_100_000_000_000_000i64 }, "bc1qc": 0.5 }),
+    ]))
+    .unwrap()
+    .unwrap();
+    assert_eq!(
+        converted.params[1],
+        json!({ "bc1qa": "0.00000001", "bc1qb": "21000000.00000000", "bc1qc": 0.5 })
+    );
+    assert!(convert_request(&req(vec![json!({ "bc1qa": 0.5 })]))
+        .unwrap()
+        .is_none());
+    assert!(convert_request(&req(vec![json!({ "bc1qa": { "sat": 0.5 } })])).is_err());
+}
+
+#[test]
+fn annotations_are_exact() {
+    let result =
+        |amount: &str| serde_json::from_str(&format!("[{{\"amount\": {}}}]", amount)).unwrap();
+    let cases = vec![
+        ("0.00000001", Some(1)),
+        ("20999999.99999999", Some(2_099_999_999_999_999i64)),
+        ("-0.5", Some(-50_000_000)),
+        ("1.41e-6", Some(141)),
+        ("1E3", Some(100_000_000_000)),
+        ("0", Some(0)),
+        ("0.000000001", None),
+        ("1e300", None),
+    ];
+    for (amount, sats) in cases {
+        let annotated = annotated("listunspent", result(amount));
+        assert_eq!(
+            annotated[0].get("amount_sats"),
+            sats.map(Value::from).as_ref(),
+            "{}",
+            amount
+        );
+    }
+}
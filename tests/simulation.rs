@@ -0,0 +1,306 @@
+//! Properties of the block fetch scheduler, checked against scripted networks on a paused clock.
+
+#![cfg(feature = "peer-fetch")]
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bitcoin::Block;
+use btc_rpc_proxy::address_book::AddressBook;
+use btc_rpc_proxy::fetch_blocks::{FetchPolicy, FetchStage, Fetcher};
+use btc_rpc_proxy::quota::{Period, Quota, QuotaUsage};
+use btc_rpc_proxy::scheduler::{Missing, Outcome, Scheduler};
+use btc_rpc_proxy::simulation::{block, Behavior, Event, EventKind, SimPeer, Simulation};
+use btc_rpc_proxy::storage::{Memory, FETCH_USAGE};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::time::Instant;
+
+const NOBODY: Fetcher = Fetcher {
+    user_name: "nobody",
+    quota: &[],
+};
+
+/// Everything a scheduler needs besides the simulation.
+struct Setup {
+    policy: FetchPolicy,
+    address_book: AddressBook,
+    fetch_usage: QuotaUsage,
+    budget: Option<Duration>,
+    max_peer_concurrency: Option<usize>,
+    logger: slog::Logger,
+}
+impl Setup {
+    fn new(steps: &[&str], sim: &Simulation) -> Self {
+        let mut order = HashMap::new();
+        order.insert(
+            "getblock".to_owned(),
+            steps.iter().map(|s| (*s).to_owned()).collect(),
+        );
+        let address_book = AddressBook::open(Arc::new(Memory::default())).unwrap();
+        address_book.observe(&sim.addrs());
+        Setup {
+            policy: FetchPolicy::new(order, None).unwrap(),
+            address_book,
+            fetch_usage: QuotaUsage::open(Arc::new(Memory::default()), FETCH_USAGE).unwrap(),
+            budget: None,
+            max_peer_concurrency: None,
+            logger: slog::Logger::root(slog::Discard, slog::o!()),
+        }
+    }
+
+    async fn fetch(&self, sim: &Simulation, block: &Block, fetcher: Fetcher<'_>) -> Outcome {
+        Scheduler {
+            sources: sim,
+            policy: &self.policy,
+            address_book: &self.address_book,
+            fetch_usage: &self.fetch_usage,
+            budget: self.budget,
+            max_peer_concurrency: self.max_peer_concurrency,
+            logger: &self.logger,
+        }
+        .fetch("getblock", block.block_hash(), None, fetcher)
+        .await
+        .expect("the block is in the chain")
+    }
+}
+
+fn fetched(outcome: Outcome) -> Block {
+    match outcome {
+        Outcome::Fetched(block) => block,
+        Outcome::Missing(missing) => panic!("block not fetched: {:?}", missing),
+    }
+}
+
+fn missing(outcome: Outcome) -> Missing {
+    match outcome {
+        Outcome::Fetched(block) => panic!("block {} fetched", block.block_hash()),
+        Outcome::Missing(missing) => missing,
+    }
+}
+
+fn secs(secs: u64) -> Duration {
+    Duration::from_secs(secs)
+}
+
+/// Runs `test` on the paused clock. In a task of its own, as the clock may advance before the
+/// future the runtime blocks on is polled again.
+async fn simulate(test: impl Future<Output = ()> + Send + 'static) {
+    tokio::time::pause();
+    tokio::spawn(async {
+        // timers fire at whole milliseconds since the runtime started
+        tokio::time::delay_for(Duration::from_millis(1)).await;
+        test.await
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn fastest_peer_wins() {
+    simulate(async {
+        let sim = Simulation::new(vec![block(1)])
+            .with_peer(SimPeer::new(1, secs(3), Behavior::Honest))
+            .with_peer(SimPeer::new(2, secs(1), Behavior::Honest));
+        let setup = Setup::new(&["p2p"], &sim);
+        let start = Instant::now();
+        assert_eq!(
+            fetched(setup.fetch(&sim, &block(1), NOBODY).await),
+            block(1)
+        );
+        assert_eq!(start.elapsed(), secs(1));
+        let last = sim.events().pop().unwrap();
+        assert_eq!(last.source, "10.0.0.1:8333");
+        assert_eq!(last.kind, EventKind::Failed("cancelled".to_owned()));
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn failed_peers_are_asked_last() {
+    simulate(async {
+        let sim = Simulation::new(vec![block(1), block(2)])
+            .with_peer(SimPeer::new(1, secs(1), Behavior::Missing))
+            .with_peer(SimPeer::new(2, secs(1), Behavior::Honest));
+        let mut setup = Setup::new(&["p2p"], &sim);
+        setup.max_peer_concurrency = Some(1);
+        fetched(setup.fetch(&sim, &block(1), NOBODY).await);
+        fetched(setup.fetch(&sim, &block(2), NOBODY).await);
+        assert_eq!(sim.requests("10.0.0.1:8333"), 1);
+        assert_eq!(sim.requests("10.0.0.2:8333"), 2);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn budget_bounds_the_search() {
+    simulate(async {
+        let sim = Simulation::new(vec![block(1)])
+            .with_peer(SimPeer::new(1, secs(1), Behavior::Stalling))
+            .with_stage(FetchStage::GetBlockFromPeer, secs(1), vec![])
+            .with_peer_timeout(secs(60));
+        let mut setup = Setup::new(&["p2p", "getblockfrompeer"], &sim);
+        setup.budget = Some(secs(10));
+        let start = Instant::now();
+        let missing = missing(setup.fetch(&sim, &block(1), NOBODY).await);
+        assert_eq!(start.elapsed(), secs(10));
+        assert!(missing.budget_exhausted);
+        assert_eq!(missing.attempts.len(), 1);
+        assert_eq!(missing.attempts[0].result, "timeout");
+        assert_eq!(sim.requests("getblockfrompeer"), 0);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn fetch_quota_spares_the_cache() {
+    simulate(async {
+        let sim = Simulation::new(vec![block(1), block(2)]).with_peer(SimPeer::new(
+            1,
+            secs(1),
+            Behavior::Honest,
+        ));
+        let setup = Setup::new(&["cache", "p2p"], &sim);
+        let quota = [Quota {
+            period: Period::Day,
+            rolling: false,
+            requests: Some(1),
+            bytes: None,
+        }];
+        let fetcher = Fetcher {
+            user_name: "alice",
+            quota: &quota,
+        };
+        fetched(setup.fetch(&sim, &block(1), fetcher).await);
+        let missing = missing(setup.fetch(&sim, &block(2), fetcher).await);
+        assert!(missing.attempts[1].result.contains("Quota"));
+        // the block fetched first is cached now
+        fetched(setup.fetch(&sim, &block(1), fetcher).await);
+        assert_eq!(sim.requests("10.0.0.1:8333"), 1);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn forks_are_only_fetched_if_allowed() {
+    simulate(async {
+        let sim = Simulation::new(vec![block(1)])
+            .with_peer(SimPeer::new(1, secs(1), Behavior::Honest))
+            .with_fork(block(1).block_hash());
+        let setup = Setup::new(&["p2p"], &sim);
+        let scheduler = Scheduler {
+            sources: &sim,
+            policy: &setup.policy,
+            address_book: &setup.address_book,
+            fetch_usage: &setup.fetch_usage,
+            budget: None,
+            max_peer_concurrency: None,
+            logger: &setup.logger,
+        };
+        let hash = block(1).block_hash();
+        assert!(scheduler
+            .fetch("getblock", hash, Some(false), NOBODY)
+            .await
+            .is_err());
+        assert_eq!(sim.requests("10.0.0.1:8333"), 0);
+        match scheduler.fetch("getblock", hash, Some(true), NOBODY).await {
+            Ok(Outcome::Fetched(_)) => (),
+            res => panic!("unexpected outcome {:?}", res),
+        }
+    })
+    .await;
+}
+
+/// `events` with those happening at the same time, whose order is up to the runtime, by source.
+fn sorted(mut events: Vec<Event>) -> Vec<Event> {
+    events.sort_by(|a, b| (a.at, &a.source).cmp(&(b.at, &b.source)));
+    events
+}
+
+/// A network of a few peers with random latencies and behaviors, a random concurrency and
+/// budget, and whether any peer delivers blocks right away.
+fn random_case(seed: u64) -> (Simulation, Option<usize>, Option<Duration>, bool) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let timeout = secs(rng.gen_range(1, 60));
+    let mut sim = Simulation::new(vec![block(1)]).with_peer_timeout(timeout);
+    let mut deliverable = false;
+    for n in 0..rng.gen_range(1, 8) {
+        let behavior = match rng.gen_range(0, 4) {
+            0 => Behavior::Honest,
+            1 => Behavior::Missing,
+            2 => Behavior::Stalling,
+            _ => Behavior::Flaky(rng.gen_range(0, 2)),
+        };
+        let latency = Duration::from_millis(rng.gen_range(0, 5000));
+        deliverable |=
+            (behavior == Behavior::Honest || behavior == Behavior::Flaky(0)) && latency < timeout;
+        sim = sim.with_peer(SimPeer::new(n, latency, behavior));
+    }
+    let concurrency = match rng.gen_range(0, 4) {
+        0 => None,
+        n => Some(n),
+    };
+    let budget = if rng.gen() {
+        Some(Duration::from_millis(rng.gen_range(1, 20_000)))
+    } else {
+        None
+    };
+    (sim, concurrency, budget, deliverable)
+}
+
+#[tokio::test]
+async fn random_networks() {
+    simulate(async {
+        for seed in 0..500 {
+            let (sim, concurrency, budget, deliverable) = random_case(seed);
+            let mut setup = Setup::new(&["p2p"], &sim);
+            setup.max_peer_concurrency = concurrency;
+            setup.budget = budget;
+            let start = Instant::now();
+            let outcome = setup.fetch(&sim, &block(1), NOBODY).await;
+            let elapsed = start.elapsed();
+
+            if let Some(limit) = concurrency {
+                assert!(
+                    sim.max_concurrent_peers() <= limit,
+                    "seed {}: {} peers at once",
+                    seed,
+                    sim.max_concurrent_peers()
+                );
+            }
+            match budget {
+                Some(budget) => assert!(elapsed <= budget, "seed {}: took {:?}", seed, elapsed),
+                // peers time out, so a peer delivering is always reached without a budget
+                None => assert_eq!(
+                    matches!(outcome, Outcome::Fetched(_)),
+                    deliverable,
+                    "seed {}",
+                    seed
+                ),
+            }
+            let delivered = sim.events().iter().any(|e| e.kind == EventKind::Delivered);
+            match outcome {
+                Outcome::Fetched(b) => {
+                    assert!(delivered, "seed {}", seed);
+                    assert_eq!(b, block(1));
+                }
+                Outcome::Missing(_) => assert!(!delivered, "seed {}", seed),
+            }
+
+            // the same script plays out the same way again
+            let (again, ..) = random_case(seed);
+            let mut setup = Setup::new(&["p2p"], &again);
+            setup.max_peer_concurrency = concurrency;
+            setup.budget = budget;
+            setup.fetch(&again, &block(1), NOBODY).await;
+            assert_eq!(
+                sorted(sim.events()),
+                sorted(again.events()),
+                "seed {}",
+                seed
+            );
+        }
+    })
+    .await;
+}
@@ -0,0 +1,15 @@
+//! Rendering of the public stats page.
+
+use btc_rpc_proxy::stats::render_html;
+use serde_json::json;
+
+#[test]
+fn html_escapes_values() {
+    let html = render_html(&json!({
+        "height": 800000,
+        "subversion": "/Satoshi:25.0.0(<script>alert(1)</script>)/",
+    }));
+    assert!(html.contains("<tr><th>height</th><td>800000</td></tr>"));
+    assert!(html.contains("/Satoshi:25.0.0(&lt;script&gt;alert(1)&lt;/script&gt;)/"));
+    assert!(!html.contains("<script>"));
+}
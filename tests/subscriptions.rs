@@ -0,0 +1,217 @@
+//! Delivery of subscribed events to subscribers that don't keep up.
+
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use bitcoin::hashes::Hash;
+use bitcoin::{hash_types::BlockHash, Txid};
+use btc_rpc_proxy::filters::{Filter, TxInfo};
+use btc_rpc_proxy::subscriptions::{
+    Delivery, Event, Outbox, Overflow, Subscriptions, Topic, MAX_DIGEST_TXIDS,
+};
+
+fn block(height: u64) -> Event {
+    Event::Block {
+        hash: BlockHash::hash(&height.to_le_bytes()),
+        height,
+    }
+}
+
+fn tx(n: u64) -> Event {
+    Event::Tx {
+        txid: Txid::hash(&n.to_le_bytes()),
+    }
+}
+
+fn outbox(capacity: usize, overflow: Overflow) -> Outbox {
+    let outbox = Outbox::new(capacity, overflow);
+    outbox.set_subscribed(Topic::Block, true);
+    outbox.set_subscribed(Topic::Tx, true);
+    outbox
+}
+
+fn drain(outbox: &Outbox) -> Vec<Delivery> {
+    std::iter::from_fn(|| outbox.pop()).take(100).collect()
+}
+
+#[test]
+fn drop_oldest_keeps_the_latest_events() {
+    let outbox = outbox(2, Overflow::DropOldest);
+    assert_eq!(outbox.push(tx(1)), Some(0));
+    assert_eq!(outbox.push(tx(2)), Some(0));
+    assert_eq!(outbox.push(tx(3)), Some(1));
+    assert_eq!(outbox.push(block(1)), Some(1));
+    assert_eq!(outbox.len(), 2);
+    assert_eq!(
+        drain(&outbox),
+        vec![
+            Delivery::Dropped(2),
+            Delivery::Event(tx(3)),
+            Delivery::Event(block(1)),
+        ]
+    );
+}
+
+#[test]
+fn close_gives_up_on_the_subscriber() {
+    let outbox = outbox(2, Overflow::Close);
+    outbox.push(tx(1));
+    outbox.push(tx(2));
+    assert_eq!(outbox.push(tx(3)), None);
+    assert_eq!(outbox.push(tx(4)), None);
+    assert_eq!(outbox.pop(), Some(Delivery::Overflowed));
+    assert!(outbox.is_empty());
+}
+
+#[test]
+fn coalesce_blocks_keeps_only_the_tip() {
+    let outbox = outbox(3, Overflow::CoalesceBlocks);
+    outbox.push(block(1));
+    outbox.push(tx(1));
+    outbox.push(block(2));
+    assert_eq!(outbox.push(block(3)), Some(2));
+    assert_eq!(outbox.push(tx(2)), Some(0));
+    // without a block event queued, the oldest event makes room
+    assert_eq!(outbox.push(tx(3)), Some(1));
+    assert_eq!(
+        drain(&outbox),
+        vec![
+            Delivery::Dropped(3),
+            Delivery::Event(block(3)),
+            Delivery::Event(tx(2)),
+            Delivery::Event(tx(3)),
+        ]
+    );
+}
+
+#[test]
+fn only_subscribed_topics_are_queued() {
+    let outbox = Outbox::new(10, Overflow::DropOldest);
+    outbox.set_subscribed(Topic::Block, true);
+    outbox.push(tx(1));
+    outbox.push(block(1));
+    assert_eq!(drain(&outbox), vec![Delivery::Event(block(1))]);
+    outbox.push(block(2));
+    outbox.set_subscribed(Topic::Block, false);
+    assert!(outbox.is_empty());
+}
+
+#[test]
+fn slow_subscribers_dont_hold_up_the_others() {
+    let subscriptions = Subscriptions::new(2, Overflow::Close, Duration::from_secs(1));
+    let slow = subscriptions.subscribe();
+    let fast = subscriptions.subscribe();
+    let gone = subscriptions.subscribe();
+    for outbox in [&slow, &fast, &gone].iter() {
+        outbox.set_subscribed(Topic::Tx, true);
+    }
+    drop(gone);
+    assert!(subscriptions.wants(Topic::Tx));
+    assert!(!subscriptions.wants(Topic::Block));
+    for n in 0..100 {
+        subscriptions.publish(tx(n));
+        assert_eq!(fast.pop(), Some(Delivery::Event(tx(n))));
+    }
+    assert_eq!(slow.pop(), Some(Delivery::Overflowed));
+    assert_eq!(subscriptions.subscribers(), 1);
+    assert_eq!(subscriptions.overflowed.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn digests_collect_transactions() {
+    let outbox = outbox(2, Overflow::Close);
+    outbox.set_digest(Some(Duration::from_secs(10)));
+    for n in 0..(MAX_DIGEST_TXIDS as u64 + 5) {
+        outbox.push(tx(n));
+    }
+    outbox.push(block(1));
+    assert_eq!(outbox.len(), 1);
+    assert_eq!(outbox.flush_digest(), Some(0));
+    assert_eq!(outbox.flush_digest(), Some(0));
+    let txids = (0..MAX_DIGEST_TXIDS as u64)
+        .map(|n| Txid::hash(&n.to_le_bytes()))
+        .collect();
+    assert_eq!(
+        drain(&outbox),
+        vec![
+            Delivery::Dropped(5),
+            Delivery::Event(block(1)),
+            Delivery::Event(Event::TxDigest { txids }),
+        ]
+    );
+    outbox.set_digest(None);
+    outbox.push(tx(1));
+    assert_eq!(drain(&outbox), vec![Delivery::Event(tx(1))]);
+}
+
+#[test]
+fn digests_overflow_like_events() {
+    let outbox = outbox(1, Overflow::Close);
+    outbox.set_digest(Some(Duration::from_secs(10)));
+    outbox.push(block(1));
+    outbox.push(tx(1));
+    assert_eq!(outbox.flush_digest(), None);
+    assert_eq!(outbox.pop(), Some(Delivery::Overflowed));
+}
+
+#[test]
+fn filters_pick_transactions() {
+    let outbox = outbox(10, Overflow::DropOldest);
+    outbox.set_filter(Filter {
+        addresses: Some(vec!["bc1qmine".to_owned()].into_iter().collect()),
+        min_fee_rate: Some(5.0),
+    });
+    let info = |fee_rate, address: &str| TxInfo {
+        fee_rate,
+        addresses: vec!["bc1qother".to_owned(), address.to_owned()],
+    };
+    outbox.push_matching(tx(1), Some(&info(10.0, "bc1qmine")));
+    outbox.push_matching(tx(2), Some(&info(10.0, "bc1qother")));
+    outbox.push_matching(tx(3), Some(&info(1.0, "bc1qmine")));
+    // without details, only blocks get through
+    outbox.push(tx(4));
+    outbox.push(block(1));
+    assert_eq!(
+        drain(&outbox),
+        vec![Delivery::Event(tx(1)), Delivery::Event(block(1))]
+    );
+    outbox.set_subscribed(Topic::Tx, false);
+    outbox.set_subscribed(Topic::Tx, true);
+    assert!(!outbox.is_filtered());
+    outbox.push(tx(4));
+    assert_eq!(drain(&outbox), vec![Delivery::Event(tx(4))]);
+}
+
+#[test]
+fn details_are_only_wanted_for_filters() {
+    let subscriptions = Subscriptions::new(10, Overflow::DropOldest, Duration::from_secs(1));
+    let filtered = subscriptions.subscribe();
+    let unfiltered = subscriptions.subscribe();
+    filtered.set_subscribed(Topic::Tx, true);
+    unfiltered.set_subscribed(Topic::Tx, true);
+    assert!(!subscriptions.wants_details());
+    filtered.set_filter(Filter {
+        addresses: None,
+        min_fee_rate: Some(20.0),
+    });
+    assert!(subscriptions.wants_details());
+    let cheap = TxInfo {
+        fee_rate: 2.0,
+        addresses: Vec::new(),
+    };
+    subscriptions.publish_matching(tx(1), Some(&cheap));
+    assert_eq!(filtered.pop(), None);
+    assert_eq!(unfiltered.pop(), Some(Delivery::Event(tx(1))));
+}
+
+#[tokio::test]
+async fn next_waits_for_a_push() {
+    let outbox = std::sync::Arc::new(outbox(1, Overflow::DropOldest));
+    let waiting = {
+        let outbox = outbox.clone();
+        tokio::spawn(async move { outbox.next().await })
+    };
+    tokio::time::delay_for(Duration::from_millis(10)).await;
+    outbox.push(block(1));
+    assert_eq!(waiting.await.unwrap(), Delivery::Event(block(1)));
+}
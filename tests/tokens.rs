@@ -0,0 +1,199 @@
+//! Bearer tokens issued by the proxy carry the permissions of the user they were issued to.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::time::Duration;
+
+use anyhow::Error;
+use btc_rpc_proxy::auth::AuthBackend;
+use chrono::{DateTime, Utc};
+use futures::future::{BoxFuture, FutureExt};
+use hyper::StatusCode;
+use serde_json::{json, Value};
+
+use common::local::{bitcoind, call, proxy};
+
+/// A directory accepting `admin` with its own password, mapped to the `readonly` profile.
+#[derive(Debug)]
+struct Directory;
+impl AuthBackend for Directory {
+    fn name(&self) -> &str {
+        "directory"
+    }
+
+    fn authenticate<'a>(
+        &'a self,
+        user: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>, Error>> {
+        let profile = (user == "admin" && password == "directory").then(|| "readonly".to_owned());
+        async move { Ok(profile) }.boxed()
+    }
+}
+
+fn users() -> Value {
+    json!({
+        "admin": { "password": "secret", "manage_users": true, "allowed_calls": ["*"] },
+        "readonly": { "allowed_calls": ["getblockcount", "proxy_issuetoken"] },
+        "alice": { "password": "secret", "allowed_calls": ["getblockcount", "proxy_issuetoken"] },
+    })
+}
+
+#[tokio::test]
+async fn tokens_carry_the_permissions_of_their_user() {
+    let (bitcoind, _) = bitcoind("tokens-permissions", |_, _| json!(1));
+    let state = proxy(bitcoind, users()).build().arc();
+    let (_, issued) = call(
+        &state,
+        Some(("alice", "secret")),
+        "proxy_issuetoken",
+        json!([]),
+    )
+    .await;
+    let token = issued["result"]["token"].as_str().unwrap();
+    let (_, count) = call(&state, Some(("Bearer", token)), "getblockcount", json!([])).await;
+    assert_eq!(count["result"], 1);
+    let (_, denied) = call(&state, Some(("Bearer", token)), "stop", json!([])).await;
+    assert!(denied["error"].is_object(), "{}", denied);
+}
+
+#[tokio::test]
+async fn mapped_users_get_no_tokens() {
+    let (bitcoind, _) = bitcoind("tokens-mapped", |_, _| json!(1));
+    let state = proxy(bitcoind, users())
+        .auth_backends(vec![Box::new(Directory)])
+        .build()
+        .arc();
+    // known as admin, but with the permissions of readonly
+    let (_, count) = call(
+        &state,
+        Some(("admin", "directory")),
+        "getblockcount",
+        json!([]),
+    )
+    .await;
+    assert_eq!(count["result"], 1);
+    let (_, issued) = call(
+        &state,
+        Some(("admin", "directory")),
+        "proxy_issuetoken",
+        json!([]),
+    )
+    .await;
+    assert!(issued["result"].is_null(), "{}", issued);
+    assert!(
+        issued["error"]["message"]
+            .as_str()
+            .unwrap()
+            .contains("configured users"),
+        "{}",
+        issued
+    );
+}
+
+#[tokio::test]
+async fn tokens_expire() {
+    let (bitcoind, _) = bitcoind("tokens-expire", |_, _| json!(1));
+    let state = proxy(bitcoind, users()).build().arc();
+    tokio::time::pause();
+    let (_, issued) = call(
+        &state,
+        Some(("alice", "secret")),
+        "proxy_issuetoken",
+        json!([60]),
+    )
+    .await;
+    let token = issued["result"]["token"].as_str().unwrap();
+
+    tokio::time::advance(Duration::from_secs(59)).await;
+    let (status, _) = call(&state, Some(("Bearer", token)), "getblockcount", json!([])).await;
+    assert_eq!(status, StatusCode::OK);
+    tokio::time::advance(Duration::from_secs(1)).await;
+    let (status, expired) = call(&state, Some(("Bearer", token)), "getblockcount", json!([])).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert!(
+        expired["error"]["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("Credentials expired at"),
+        "{}",
+        expired
+    );
+    let (status, _) = call(&state, Some(("Bearer", "0123")), "getblockcount", json!([])).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn tokens_dont_outlive_their_credentials() {
+    let (bitcoind, _) = bitcoind("tokens-valid-until", |_, _| json!(1));
+    let valid_until = Utc::now() + chrono::Duration::minutes(10);
+    let state = proxy(
+        bitcoind,
+        json!({
+            "alice": {
+                "password": "secret",
+                "allowed_calls": ["getblockcount", "proxy_issuetoken"],
+                "valid_until": valid_until.to_rfc3339(),
+            },
+        }),
+    )
+    .build()
+    .arc();
+    let (_, issued) = call(
+        &state,
+        Some(("alice", "secret")),
+        "proxy_issuetoken",
+        json!([3600]),
+    )
+    .await;
+    let expires: DateTime<Utc> = issued["result"]["expires"]
+        .as_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(expires <= valid_until, "{}", issued);
+    // nor do tokens issued with tokens
+    let token = issued["result"]["token"].as_str().unwrap();
+    let (_, reissued) = call(
+        &state,
+        Some(("Bearer", token)),
+        "proxy_issuetoken",
+        json!([7200]),
+    )
+    .await;
+    let reexpires: DateTime<Utc> = reissued["result"]["expires"]
+        .as_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(reexpires <= expires, "{}", reissued);
+}
+
+#[tokio::test]
+async fn removing_the_user_revokes_its_tokens() {
+    let (bitcoind, _) = bitcoind("tokens-revoked", |_, _| json!(1));
+    let mut users = users();
+    users["admin"]["allowed_calls"] = json!(["proxy_removeuser"]);
+    let state = proxy(bitcoind, users).build().arc();
+    let (_, issued) = call(
+        &state,
+        Some(("alice", "secret")),
+        "proxy_issuetoken",
+        json!([]),
+    )
+    .await;
+    let token = issued["result"]["token"].as_str().unwrap();
+
+    let (_, removed) = call(
+        &state,
+        Some(("admin", "secret")),
+        "proxy_removeuser",
+        json!(["alice"]),
+    )
+    .await;
+    assert!(removed["error"].is_null(), "{}", removed);
+    let (status, _) = call(&state, Some(("Bearer", token)), "getblockcount", json!([])).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
@@ -0,0 +1,140 @@
+//! Calls to a bitcoind reached through a Unix socket, answered by a stand-in serving the socket.
+
+#![cfg(unix)]
+
+use std::convert::Infallible;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use btc_rpc_proxy::client::{GenericRpcMethod, Pool, RpcRequest, SingleOrBatchRpcRequest};
+use btc_rpc_proxy::headers::ResponseHeaders;
+use btc_rpc_proxy::upstream::Connector;
+use btc_rpc_proxy::{AuthSource, RpcClient};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Response, Server};
+use serde_json::json;
+use tokio::net::UnixListener;
+
+/// Answers every call with the path it was sent to.
+fn serve(listener: UnixListener) {
+    serve_counting(listener, Arc::default());
+}
+
+/// Like `serve`, counting the connections accepted in `connections`.
+fn serve_counting(listener: UnixListener, connections: Arc<AtomicUsize>) {
+    use futures::StreamExt;
+
+    let listener = listener.inspect(move |_| {
+        connections.fetch_add(1, Ordering::SeqCst);
+    });
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|req: hyper::Request<Body>| async move {
+            let body = json!({ "id": 1, "result": req.uri().path(), "error": null });
+            Ok::<_, Infallible>(Response::new(Body::from(body.to_string())))
+        }))
+    });
+    let incoming = hyper::server::accept::from_stream(listener);
+    tokio::spawn(Server::builder(incoming).serve(make_service));
+}
+
+async fn call(connector: Connector) -> String {
+    call_under(connector, "http://localhost/").await
+}
+
+async fn call_under(connector: Connector, uri: &str) -> String {
+    let client = RpcClient::new(
+        AuthSource::from_config(Some("user".to_owned()), Some("pass".to_owned()), Vec::new())
+            .unwrap(),
+        uri.parse().unwrap(),
+        connector,
+        slog::Logger::root(slog::Discard, slog::o!()),
+    );
+    let req = RpcRequest {
+        id: Some(json!(1)),
+        method: GenericRpcMethod("getblockcount".to_owned()),
+        params: Vec::new(),
+    };
+    let result = client
+        .call_at("/wallet/hot", &req)
+        .await
+        .unwrap()
+        .into_result()
+        .unwrap();
+    result.as_str().unwrap().to_owned()
+}
+
+#[tokio::test]
+async fn unix_socket() {
+    let path = std::env::temp_dir().join(format!("btc-rpc-proxy-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    serve(UnixListener::bind(&path).unwrap());
+    assert_eq!(call(Connector::unix(path.clone())).await, "/wallet/hot");
+    assert_eq!(
+        call_under(Connector::unix(path.clone()), "http://localhost/bitcoin/").await,
+        "/bitcoin/wallet/hot"
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn abstract_socket() {
+    use std::os::linux::net::SocketAddrExt;
+
+    let name = format!("btc-rpc-proxy-{}", std::process::id());
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(&name).unwrap();
+    let listener = std::os::unix::net::UnixListener::bind_addr(&addr).unwrap();
+    listener.set_nonblocking(true).unwrap();
+    serve(UnixListener::from_std(listener).unwrap());
+    let path = PathBuf::from(format!("@{}", name));
+    assert_eq!(call(Connector::unix(path)).await, "/wallet/hot");
+}
+
+/// Calls made with `call` and forwarded with `send` reuse the same idle connection, unless the
+/// pool is told to keep none.
+#[tokio::test]
+async fn pooled_connections() {
+    let path = std::env::temp_dir().join(format!("btc-rpc-proxy-pool-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let connections = Arc::new(AtomicUsize::new(0));
+    serve_counting(UnixListener::bind(&path).unwrap(), connections.clone());
+    let calls = |pool| {
+        let client = RpcClient::with_pool(
+            AuthSource::from_config(Some("user".to_owned()), Some("pass".to_owned()), Vec::new())
+                .unwrap(),
+            "http://localhost/".parse().unwrap(),
+            Connector::unix(path.clone()),
+            pool,
+            slog::Logger::root(slog::Discard, slog::o!()),
+        );
+        async move {
+            let req = RpcRequest {
+                id: Some(json!(1)),
+                method: GenericRpcMethod("getblockcount".to_owned()),
+                params: Vec::new(),
+            };
+            let batch: SingleOrBatchRpcRequest =
+                serde_json::from_str(r#"[{"id":1,"method":"getblockcount","params":[]}]"#).unwrap();
+            for _ in 0..3 {
+                client.call(&req).await.unwrap();
+                let response = client
+                    .send("/", &batch, &ResponseHeaders::default(), |_, _| {
+                        futures::future::ready(Ok(None))
+                    })
+                    .await
+                    .unwrap();
+                hyper::body::to_bytes(response.into_body()).await.unwrap();
+            }
+        }
+    };
+    calls(Pool::default()).await;
+    assert_eq!(connections.swap(0, Ordering::SeqCst), 1);
+    calls(Pool {
+        max_idle: 0,
+        ..Pool::default()
+    })
+    .await;
+    assert_eq!(connections.load(Ordering::SeqCst), 6);
+    std::fs::remove_file(&path).unwrap();
+}
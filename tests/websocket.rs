@@ -0,0 +1,99 @@
+//! The WebSocket framing and the negotiation of compression.
+
+#![cfg(feature = "websocket")]
+
+use bitcoin::hashes::Hash;
+use bitcoin::Txid;
+use btc_rpc_proxy::subscriptions::Event;
+use btc_rpc_proxy::websocket::{accept_key, handshake, Deflate, Message};
+
+#[test]
+fn accept_key_of_rfc_6455() {
+    assert_eq!(
+        accept_key(b"dGhlIHNhbXBsZSBub25jZQ=="),
+        "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+    );
+}
+
+#[tokio::test]
+async fn masked_frames_are_read_back() {
+    let messages = vec![
+        Message::Text("{\"method\":\"subscribe\",\"params\":[\"block\"]}".to_owned()),
+        Message::Text("x".repeat(1000)),
+        Message::Ping(b"ping".to_vec()),
+        Message::Close(Some((1000, "bye".to_owned()))),
+        Message::Close(None),
+    ];
+    for message in messages {
+        let frame = message.encode(Some([1, 2, 3, 4]), None);
+        assert_eq!(Message::read(&mut &frame[..], None).await.unwrap(), message);
+        // servers don't mask, and clients have to
+        assert!(Message::read(&mut &message.encode(None, None)[..], None)
+            .await
+            .is_err());
+    }
+}
+
+#[tokio::test]
+async fn oversized_frames_are_rejected() {
+    let frame = Message::Binary(vec![0; 100_000]).encode(Some([1, 2, 3, 4]), None);
+    assert!(Message::read(&mut &frame[..], None).await.is_err());
+}
+
+#[test]
+fn permessage_deflate_is_negotiated() {
+    let request = |extensions: &str| {
+        hyper::Request::get("/ws")
+            .header("Upgrade", "websocket")
+            .header("Connection", "Upgrade")
+            .header("Sec-WebSocket-Version", "13")
+            .header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .header("Sec-WebSocket-Extensions", extensions)
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0
+    };
+    let offer = "x-webkit-deflate-frame, permessage-deflate; client_max_window_bits";
+    let (response, deflate) = handshake(&request(offer), true).unwrap();
+    assert_eq!(response.status(), 101);
+    assert_eq!(deflate.unwrap().window_bits, 15);
+    assert_eq!(
+        response.headers()["Sec-WebSocket-Extensions"],
+        "permessage-deflate; client_no_context_takeover"
+    );
+    assert_eq!(handshake(&request(offer), false).unwrap().1, None);
+
+    // the first acceptable offer wins
+    let offer = "permessage-deflate; server_max_window_bits=16, permessage-deflate; server_max_window_bits=10; server_no_context_takeover";
+    let deflate = Deflate::negotiate(offer).unwrap();
+    assert_eq!(deflate.window_bits, 10);
+    assert_eq!(
+        deflate.response(),
+        "permessage-deflate; client_no_context_takeover; server_no_context_takeover; server_max_window_bits=10"
+    );
+    assert_eq!(Deflate::negotiate("permessage-deflate; unknown"), None);
+}
+
+#[tokio::test]
+async fn compressed_frames_are_read_back() {
+    let deflate = Deflate::negotiate("permessage-deflate").unwrap();
+    let digest = Event::TxDigest {
+        txids: (0..100)
+            .map(|n: u64| Txid::hash(&n.to_le_bytes()))
+            .collect(),
+    };
+    let message = Message::Text(digest.to_json().to_string());
+    let frame = message.encode(Some([1, 2, 3, 4]), Some(deflate));
+    assert!(frame.len() < message.encode(Some([1, 2, 3, 4]), None).len());
+    assert_eq!(frame[0] & 0x40, 0x40);
+    assert_eq!(
+        Message::read(&mut &frame[..], Some(deflate)).await.unwrap(),
+        message
+    );
+    // compressed frames are only accepted once negotiated
+    assert!(Message::read(&mut &frame[..], None).await.is_err());
+    // short messages aren't worth it
+    let frame = Message::Text("{}".to_owned()).encode(None, Some(deflate));
+    assert_eq!(frame[0] & 0x40, 0);
+}